@@ -0,0 +1,146 @@
+//! The formats the config can be stored in, picked by the extension of
+//! its file. TOML is easier to edit by hand than JSON.
+//!
+//! Writing the config keeps the settings in the file which this version
+//! doesn't know about, and the comments of TOML files, so editing the
+//! file by hand or with a newer version doesn't lose anything.
+
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use toml_edit::{Document, Item, TableLike};
+
+use super::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// The format of the config file at `path`, which is JSON unless it
+    /// has a `.toml` extension
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn parse(&self, contents: &str) -> Result<Config, Error> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::from_str(contents)?),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|error| Error::new(ErrorKind::InvalidData, error)),
+        }
+    }
+
+    /// Write `config`, keeping anything unknown from the `existing`
+    /// contents of the file it replaces
+    pub fn write(&self, config: &Config, existing: Option<&str>) -> Result<String, Error> {
+        let Value::Object(known) = serde_json::to_value(config)? else {
+            return Err(Error::new(ErrorKind::InvalidData, "the config is not a map"));
+        };
+
+        match self {
+            ConfigFormat::Json => {
+                let mut value = known;
+                if let Some(Ok(Value::Object(existing))) = existing.map(serde_json::from_str) {
+                    keep_unknown_json(&mut value, existing);
+                }
+                Ok(serde_json::to_string_pretty(&value)?)
+            }
+            ConfigFormat::Toml => {
+                let new = toml::to_string(config).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                let new: Document = new.parse().map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                // A file which can't be read is replaced as a whole
+                let Some(Ok(mut document)) = existing.map(str::parse::<Document>) else {
+                    return Ok(new.to_string());
+                };
+                merge_toml(document.as_table_mut(), new.as_table(), &known);
+                Ok(document.to_string())
+            }
+        }
+    }
+}
+
+/// Add the keys of `existing` which `new` doesn't have
+fn keep_unknown_json(new: &mut Map<String, Value>, existing: Map<String, Value>) {
+    for (key, value) in existing {
+        match (new.get_mut(&key), value) {
+            (None, value) => {
+                new.insert(key, value);
+            }
+            (Some(Value::Object(new)), Value::Object(existing)) => keep_unknown_json(new, existing),
+            _ => (),
+        }
+    }
+}
+
+/// Update the `existing` table to the values of `new`, keeping its
+/// comments and the keys which aren't `known` settings
+fn merge_toml(existing: &mut dyn TableLike, new: &dyn TableLike, known: &Map<String, Value>) {
+    // TOML has no null, so settings without a value are left out of the new table
+    let cleared: Vec<String> = existing
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| known.contains_key(key) && !new.contains_key(key))
+        .collect();
+    for key in cleared {
+        existing.remove(&key);
+    }
+
+    for (key, item) in new.iter() {
+        let replace = match (existing.get_mut(key), item) {
+            (Some(old), item) if old.is_table_like() && item.is_table_like() => {
+                let known = known.get(key).and_then(Value::as_object).cloned().unwrap_or_default();
+                merge_toml(old.as_table_like_mut().unwrap(), item.as_table_like().unwrap(), &known);
+                false
+            }
+            (Some(Item::Value(old)), Item::Value(value)) => {
+                // Keep the comment after the value
+                let decor = old.decor().clone();
+                *old = value.clone();
+                *old.decor_mut() = decor;
+                false
+            }
+            _ => true,
+        };
+        if replace {
+            existing.insert(key, item.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigFormat;
+    use crate::config::Config;
+
+    #[test]
+    fn config_formats() {
+        let config = Config {
+            volume: 0.5,
+            volume_cap: Some(0.8),
+            ..Config::new()
+        };
+        for format in [ConfigFormat::Json, ConfigFormat::Toml] {
+            let written = format.write(&config, None).unwrap();
+            let read = format.parse(&written).unwrap();
+            assert_eq!(format.write(&read, None).unwrap(), written);
+        }
+
+        let existing = "# Set by hand\nvolume = 1.0 # loud\nvolume_cap = 0.9\nfrom_the_future = true\n";
+        let toml = ConfigFormat::Toml.write(&Config { volume_cap: None, ..config.clone() }, Some(existing)).unwrap();
+        assert!(toml.starts_with("# Set by hand\nvolume = 0.5 # loud\n"));
+        assert!(toml.contains("from_the_future = true"));
+        assert!(!toml.contains("volume_cap"));
+
+        let json = ConfigFormat::Json.write(&config, Some(r#"{"volume": 1.0, "art": {"new_provider": 1}}"#)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["volume"], 0.5);
+        assert_eq!(value["art"]["new_provider"], 1);
+        assert_eq!(value["art"]["cover_art_archive"], true);
+    }
+}
@@ -0,0 +1,119 @@
+//! Settings which each library in the config has separately
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibrarySettings {
+    /// Whether the watched roots are scanned for new songs every
+    /// `scan_interval`, see [LibraryRoot::watch](super::LibraryRoot::watch)
+    pub watch: bool,
+    /// Glob patterns of files and folders which are left out of scans,
+    /// relative to the root being scanned. `*` matches within a folder
+    /// name, `**` matches any number of folders, and patterns without a
+    /// `/` match a file or folder name anywhere, such as `*.log`.
+    pub exclude: Vec<String>,
+    /// How often watched roots are scanned, `None` to only scan by hand
+    pub scan_interval: Option<Duration>,
+    /// Never write the library file, such as for a library shared from
+    /// another machine
+    pub read_only: bool,
+    /// The edition of each album with several to play when picking songs
+    /// automatically, by the key of its
+    /// [AlbumEditions](crate::music_storage::editions::AlbumEditions)
+    pub preferred_editions: BTreeMap<String, String>,
+    /// Measure the exact duration of songs whose headers are often wrong,
+    /// such as VBR MP3s, after each scheduled scan
+    pub exact_durations: bool,
+    /// Find the silence at the start and end of songs after each scheduled
+    /// scan, and skip it while playing
+    pub trim_silence: bool,
+}
+
+impl Default for LibrarySettings {
+    fn default() -> Self {
+        LibrarySettings {
+            watch: true,
+            exclude: Vec::new(),
+            scan_interval: None,
+            read_only: false,
+            preferred_editions: BTreeMap::new(),
+            exact_durations: false,
+            trim_silence: false,
+        }
+    }
+}
+
+impl LibrarySettings {
+    /// Whether `path`, inside of the folder `root`, is left out of scans
+    pub fn excludes(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        let parts: Vec<String> = relative
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy().to_string())
+            .collect();
+
+        self.exclude.iter().any(|pattern| {
+            let pattern: Vec<char> = pattern.trim_matches('/').chars().collect();
+            match pattern.contains(&'/') {
+                // Anything inside of an excluded folder is excluded too
+                true => (1..=parts.len()).any(|len| {
+                    let path: Vec<char> = parts[..len].join("/").chars().collect();
+                    glob_match(&pattern, &path)
+                }),
+                false => parts.iter().any(|part| glob_match(&pattern, &part.chars().collect::<Vec<_>>())),
+            }
+        })
+    }
+}
+
+/// Match a `/` separated `path` against a glob `pattern`
+fn glob_match(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` can also match no folders at all
+            let skipped = rest.strip_prefix(&['/']).is_some_and(|rest| glob_match(rest, path));
+            skipped || (0..=path.len()).any(|start| glob_match(rest, &path[start..]))
+        }
+        ['*', rest @ ..] => {
+            let folder_end = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=folder_end).any(|start| glob_match(rest, &path[start..]))
+        }
+        ['?', rest @ ..] => path.first().is_some_and(|c| *c != '/') && glob_match(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && glob_match(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::LibrarySettings;
+
+    #[test]
+    fn scan_exclusions() {
+        let settings = LibrarySettings {
+            exclude: vec!["*.log".to_string(), "Podcasts/**/old".to_string(), "Demo?".to_string()],
+            ..Default::default()
+        };
+        let root = Path::new("/music");
+        let excludes = |path: &str| settings.excludes(root, &root.join(path));
+
+        assert!(excludes("Album/rip.log"));
+        assert!(!excludes("Album/rip.log.flac"));
+        assert!(excludes("Podcasts/old/episode.mp3"));
+        assert!(excludes("Podcasts/Show/2019/old/episode.mp3"));
+        assert!(!excludes("Podcasts/Show/episode.mp3"));
+        assert!(excludes("Artist/Demos/track.flac"));
+        assert!(!excludes("Artist/Demo/track.flac"));
+        // Paths outside of the root aren't excluded
+        assert!(!settings.excludes(root, Path::new("/other/rip.log")));
+    }
+}
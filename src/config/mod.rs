@@ -1,22 +1,125 @@
+pub mod format;
+pub mod library_settings;
 pub mod other_settings;
+pub mod paths;
+pub mod secrets;
 
 use std::{
     fs::{self, File, OpenOptions},
-    io::{Error, Read, Write},
-    path::PathBuf,
+    io::{Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
 use serde::{Deserialize, Serialize};
-use serde_json::to_string_pretty;
+use serde_json::{to_string_pretty, Value};
 use thiserror::Error;
 use uuid::Uuid;
 
+use self::format::ConfigFormat;
+use self::library_settings::LibrarySettings;
+use self::paths::DefaultPaths;
+use crate::i18n::DEFAULT_LANGUAGE;
+use crate::music_controller::discord::ConfigDiscord;
+use crate::music_controller::idle::ConfigIdle;
+use crate::music_controller::ignore::ConfigIgnore;
+use crate::music_controller::notifications::ConfigNotifications;
+use crate::music_controller::power::ConfigPower;
+use crate::music_controller::profiles::ConfigProfiles;
+use crate::music_controller::replaygain::ConfigReplayGain;
+use crate::music_controller::skip_silence::ConfigSkipSilence;
+use crate::music_controller::transition::ConfigCrossfade;
+use crate::music_player::player::{AudioOutput, ConfigDeviceRemoval, PauseFade, SeekMode, Visualizer, VolumeCurve};
+use crate::music_storage::art::ConfigArt;
+use crate::music_storage::path_remap::PathRemap;
+use crate::music_storage::jellyfin::{JellyfinClient, JellyfinConfig};
+use crate::music_storage::plex::{PlexClient, PlexConfig};
+use crate::music_storage::remote::RemoteLibrary;
+use crate::music_storage::subsonic::{SubsonicClient, SubsonicConfig};
+
+/// A file which marks a root as available even while it's empty, and
+/// whichever device it is on
+pub const ROOT_MARKER: &str = ".dmp-root";
+
+/// One of the folders which the songs of a library are stored in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibraryRoot {
+    pub path: PathBuf,
+    /// Whether this folder is included when the library is scanned
+    pub scan: bool,
+    /// Whether this folder is watched for changes
+    pub watch: bool,
+    /// Whether this folder is a drive of its own, which is only available
+    /// while something is mounted on it
+    #[serde(default)]
+    pub removable: bool,
+}
+
+impl LibraryRoot {
+    pub fn new(path: PathBuf) -> Self {
+        LibraryRoot {
+            path,
+            scan: true,
+            watch: false,
+            removable: false,
+        }
+    }
+
+    /// Whether the folder can currently be reached, for example
+    /// an external drive which is plugged in.
+    ///
+    /// An unplugged drive usually leaves its mount point behind as an empty
+    /// folder, so empty folders only count if they have a [ROOT_MARKER] in
+    /// them, and removable ones only while they're mounted.
+    pub fn is_available(&self) -> bool {
+        if !self.path.is_dir() {
+            return false;
+        }
+        if self.path.join(ROOT_MARKER).is_file() {
+            return true;
+        }
+        let empty = fs::read_dir(&self.path).map_or(true, |mut entries| entries.next().is_none());
+        !empty && (!self.removable || is_mount_point(&self.path))
+    }
+
+    /// Whether `path` is inside of this folder
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+}
+
+/// Whether another device is mounted on `path`
+#[cfg(unix)]
+fn is_mount_point(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(path), fs::metadata(path.join(".."))) {
+        // The root of the file system is its own parent
+        (Ok(folder), Ok(parent)) => folder.dev() != parent.dev() || folder.ino() == parent.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `path` is the root of a drive
+#[cfg(not(unix))]
+fn is_mount_point(path: &Path) -> bool {
+    path.canonicalize().is_ok_and(|path| path.parent().is_none())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigLibrary {
     pub name: String,
     pub path: PathBuf,
     pub uuid: Uuid,
     pub scan_folders: Option<Vec<PathBuf>>,
+    /// Store song paths relative to the folder containing the library
+    /// file, so the folder can be moved without breaking the library
+    #[serde(default)]
+    pub relative_paths: bool,
+    /// The folders which the songs of this library are stored in
+    #[serde(default)]
+    pub roots: Vec<LibraryRoot>,
+    #[serde(default)]
+    pub settings: LibrarySettings,
 }
 
 impl Default for ConfigLibrary {
@@ -26,6 +129,9 @@ impl Default for ConfigLibrary {
             path: PathBuf::from("library"),
             uuid: Uuid::new_v4(),
             scan_folders: None,
+            relative_paths: false,
+            roots: Vec::new(),
+            settings: LibrarySettings::default(),
         }
     }
 }
@@ -37,9 +143,44 @@ impl ConfigLibrary {
             path,
             uuid: Uuid::new_v4(),
             scan_folders,
+            relative_paths: false,
+            roots: Vec::new(),
+            settings: LibrarySettings::default(),
         }
     }
 
+    /// Add a root folder to the library, returning `false` if
+    /// it was already one of the roots
+    pub fn add_root(&mut self, root: LibraryRoot) -> bool {
+        if self.roots.iter().any(|r| r.path == root.path) {
+            return false;
+        }
+        self.roots.push(root);
+        true
+    }
+
+    /// Remove the root folder at `path`, returning it if it existed
+    pub fn remove_root(&mut self, path: &Path) -> Option<LibraryRoot> {
+        let index = self.roots.iter().position(|r| r.path == path)?;
+        Some(self.roots.remove(index))
+    }
+
+    /// Get the root folder which `path` is inside of
+    pub fn root_of(&self, path: &Path) -> Option<&LibraryRoot> {
+        self.roots.iter().find(|root| root.contains(path))
+    }
+
+    /// Get all of the root folders which can currently be reached
+    pub fn available_roots(&self) -> Vec<&LibraryRoot> {
+        self.roots.iter().filter(|root| root.is_available()).collect()
+    }
+
+    /// Get all of the root folders which should be watched for changes,
+    /// none if the library isn't watched
+    pub fn watched_roots(&self) -> Vec<&LibraryRoot> {
+        self.roots.iter().filter(|root| root.watch && self.settings.watch).collect()
+    }
+
     pub fn open(&self) -> Result<File, Error> {
         match File::open(self.path.as_path()) {
             Ok(ok) => Ok(ok),
@@ -92,6 +233,116 @@ impl ConfigLibraries {
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct ConfigConnections {
     pub listenbrainz_token: Option<String>,
+    /// The application key used to look up songs on AcoustID
+    #[serde(default)]
+    pub acoustid_key: Option<String>,
+    #[serde(default)]
+    pub subsonic: Option<SubsonicConfig>,
+    #[serde(default)]
+    pub jellyfin: Option<JellyfinConfig>,
+    #[serde(default)]
+    pub plex: Option<PlexConfig>,
+    /// Showing the playing track on Discord
+    #[serde(default)]
+    pub discord: ConfigDiscord,
+}
+
+impl ConfigConnections {
+    /// Create a client for each remote library which has been set up
+    pub fn remote_libraries(&self) -> Vec<Box<dyn RemoteLibrary>> {
+        let mut remotes: Vec<Box<dyn RemoteLibrary>> = Vec::new();
+        if let Some(subsonic) = &self.subsonic {
+            remotes.push(Box::new(SubsonicClient::new(subsonic.clone())));
+        }
+        if let Some(jellyfin) = &self.jellyfin {
+            remotes.push(Box::new(JellyfinClient::new(jellyfin.clone())));
+        }
+        if let Some(plex) = &self.plex {
+            remotes.push(Box::new(PlexClient::new(plex.clone())));
+        }
+        remotes
+    }
+}
+
+/// Byte-size budgets for each of the caches, a budget of `0` disables that cache
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigCaches {
+    pub folder: PathBuf,
+    pub art: u64,
+    pub waveform: u64,
+    /// Songs downloaded from remote libraries to play offline
+    pub offline: u64,
+}
+
+impl Default for ConfigCaches {
+    fn default() -> Self {
+        ConfigCaches {
+            folder: PathBuf::from("cache"),
+            art: 256 * 1024 * 1024,
+            waveform: 64 * 1024 * 1024,
+            offline: 4 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Disk usage limits for downloads, syncs, and transcodes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConfigDisk {
+    /// Bytes which must be left free on the disk after writing
+    pub reserve: u64,
+}
+
+impl Default for ConfigDisk {
+    fn default() -> Self {
+        ConfigDisk {
+            reserve: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// The HTTP remote-control API, which is only built with the `remote-api`
+/// feature. Its token is kept in the [Secrets](secrets::Secrets).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConfigRemoteApi {
+    pub enabled: bool,
+    /// Where the API listens, which is only reachable from this machine
+    /// unless it's changed
+    pub bind: String,
+    /// Where the WebSocket of events listens
+    pub events_bind: String,
+}
+
+impl Default for ConfigRemoteApi {
+    fn default() -> Self {
+        ConfigRemoteApi {
+            enabled: false,
+            bind: String::from("127.0.0.1:7667"),
+            events_bind: String::from("127.0.0.1:7668"),
+        }
+    }
+}
+
+/// The MPD protocol server, see [mpd](crate::music_server::mpd). Its
+/// password, if it has one, is kept in the [Secrets](secrets::Secrets).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ConfigMpd {
+    pub enabled: bool,
+    /// Where the server listens, which is only reachable from this
+    /// machine unless it's changed
+    pub bind: String,
+}
+
+impl Default for ConfigMpd {
+    fn default() -> Self {
+        ConfigMpd {
+            enabled: false,
+            bind: String::from("127.0.0.1:6600"),
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -101,7 +352,66 @@ pub struct Config {
     pub backup_folder: Option<PathBuf>,
     pub libraries: ConfigLibraries,
     pub volume: f32,
+    /// Where audio is played, which can be nowhere for running in tests
+    pub output: AudioOutput,
+    /// The highest the volume can be set to, from `0` to `1`
+    pub volume_cap: Option<f64>,
+    /// How the volume is turned into the gain of the output
+    pub volume_curve: VolumeCurve,
+    /// The balance between the left and right speakers, from `-1` to `1`
+    pub balance: f64,
+    /// Whether every channel is mixed down to mono
+    pub mono: bool,
+    /// Custom elements the output is sent through, as a gst-launch
+    /// description, see [Player::set_filter_chain](crate::music_player::player::Player::set_filter_chain)
+    pub filter_chain: Option<String>,
+    /// How the volume is faded when pausing, resuming, and stopping
+    pub pause_fade: PauseFade,
+    /// How seeks land on the position asked for, unless told otherwise
+    pub seek_mode: SeekMode,
+    /// How long the audio output takes to be heard, such as through
+    /// Bluetooth, which the reported position is delayed by
+    pub latency_offset: Duration,
+    /// How the output is measured for visualizers, which is paused while
+    /// saving energy
+    pub visualizer: Visualizer,
+    /// What happens when the audio device being played to is removed
+    pub device_removal: ConfigDeviceRemoval,
     pub connections: ConfigConnections,
+    pub caches: ConfigCaches,
+    pub disk: ConfigDisk,
+    /// Rules applied to paths in libraries and playlists from other machines
+    pub path_remap: PathRemap,
+    /// When to pause playback which nobody seems to be listening to
+    pub idle: ConfigIdle,
+    /// Audio profiles and the times of day they are used
+    pub profiles: ConfigProfiles,
+    pub replay_gain: ConfigReplayGain,
+    /// Which kinds of track have their gaps of silence skipped through
+    pub skip_silence: ConfigSkipSilence,
+    /// Songs which aren't counted in the history or scrobbled
+    pub ignore: ConfigIgnore,
+    /// Where missing album art is fetched from
+    pub art: ConfigArt,
+    /// The language of errors and reports, such as `de`. English is used
+    /// if this isn't set, see [languages](crate::i18n::languages).
+    pub language: Option<String>,
+    /// When to save energy, such as on battery
+    pub power: ConfigPower,
+    /// Where the session, history, and bookmarks are kept, which is
+    /// beside the config if this isn't set
+    pub state_folder: Option<PathBuf>,
+    /// How long before the end of each song it's announced, not counting
+    /// the crossfade, see [transition](crate::music_controller::transition)
+    pub transition_lead: Option<Duration>,
+    /// The shape and lengths of the crossfade between songs
+    pub crossfade: ConfigCrossfade,
+    /// Controlling the player over HTTP, from phones and scripts
+    pub remote_api: ConfigRemoteApi,
+    /// Controlling the player from MPD clients
+    pub mpd: ConfigMpd,
+    /// Desktop notifications when the track changes
+    pub notifications: ConfigNotifications,
 }
 
 impl Config {
@@ -119,7 +429,39 @@ impl Config {
         Config::default()
     }
 
+    /// Where the config and its files are kept by default on this
+    /// platform, or `None` if the home folder can't be found
+    pub fn default_paths() -> Option<DefaultPaths> {
+        DefaultPaths::new()
+    }
+
+    /// A new config using the [default paths](Config::default_paths),
+    /// with a default library. The folders are made if they don't exist.
+    pub fn with_default_paths() -> Result<Self, Error> {
+        let paths = Config::default_paths().ok_or(Error::new(ErrorKind::NotFound, "no home folder"))?;
+        paths.create_folders()?;
+        let mut config = Config::new();
+        config.path = paths.config;
+        config.libraries.libraries[0].path = paths.library;
+        config.caches.folder = paths.cache;
+        config.state_folder = Some(paths.state);
+        Ok(config)
+    }
+
+    /// The path of the state file `name`, such as the session
+    pub fn state_file(&self, name: &str) -> PathBuf {
+        match &self.state_folder {
+            Some(folder) => folder.join(name),
+            None => self.path.with_file_name(name),
+        }
+    }
+
+    /// Write the config to its file, as TOML if the file has a `.toml`
+    /// extension or as JSON otherwise
     pub fn write_file(&self) -> Result<(), Error> {
+        let existing = fs::read_to_string(&self.path).ok();
+        let config = ConfigFormat::from_path(&self.path).write(self, existing.as_deref())?;
+
         let mut writer = self.path.clone();
         writer.set_extension("tmp");
         let mut file = OpenOptions::new()
@@ -128,7 +470,6 @@ impl Config {
             .read(true)
             .write(true)
             .open(&writer)?;
-        let config = to_string_pretty(self)?;
         // dbg!(&config);
 
         file.write_all(config.as_bytes())?;
@@ -159,13 +500,47 @@ impl Config {
     }
 
     pub fn read_file(path: PathBuf) -> Result<Self, Error> {
-        let mut file: File = File::open(path)?;
+        let mut file: File = File::open(&path)?;
         let mut bun: String = String::new();
         _ = file.read_to_string(&mut bun);
-        let config: Config = serde_json::from_str::<Config>(&bun)?;
+        let config: Config = ConfigFormat::from_path(&path).parse(&bun)?;
         Ok(config)
     }
 
+    /// The names of the settings which are different in `other`, such as
+    /// `volume` or `libraries`
+    pub fn changed_fields(&self, other: &Config) -> Vec<String> {
+        let (Ok(Value::Object(old)), Ok(Value::Object(new))) = (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+        new.into_iter()
+            .filter(|(field, value)| old.get(field) != Some(value))
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    /// Read the config file again, returning the names of the settings
+    /// which changed on disk
+    pub fn reload(&mut self) -> Result<Vec<String>, Error> {
+        let mut config = Config::read_file(self.path.clone())?;
+        // The file may have been copied from somewhere else
+        config.path = self.path.clone();
+        let changed = self.changed_fields(&config);
+        *self = config;
+        Ok(changed)
+    }
+
+    /// The language errors and reports should be shown in
+    pub fn language(&self) -> &str {
+        self.language.as_deref().unwrap_or(DEFAULT_LANGUAGE)
+    }
+
+    /// When the config file was last changed
+    pub fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()
+    }
+
     pub fn push_library(&mut self, lib: ConfigLibrary) {
         if self.libraries.libraries.is_empty() {
             self.libraries.default_library = lib.uuid;
@@ -174,6 +549,13 @@ impl Config {
     }
 }
 
+/// A change to the config, sent to [Controller](crate::music_controller::controller::Controller) listeners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigEvent {
+    /// The setting with this name was changed, and has been applied
+    Changed(String),
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("No Library Found for {0}!")]
@@ -189,7 +571,7 @@ pub enum ConfigError {
 
 #[cfg(test)]
 pub mod tests {
-    use super::{Config, ConfigLibrary};
+    use super::{Config, ConfigLibrary, LibraryRoot, ROOT_MARKER};
     use crate::music_storage::library::MusicLibrary;
     use std::{
         path::PathBuf,
@@ -239,6 +621,47 @@ pub mod tests {
         (config, lib)
     }
 
+    #[test]
+    fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            path: dir.path().join("config.json"),
+            volume: 0.5,
+            ..Default::default()
+        };
+        config.write_file().unwrap();
+        assert_eq!(config.reload().unwrap(), Vec::<String>::new());
+
+        let mut changed = config.clone();
+        changed.volume = 0.8;
+        changed.volume_cap = Some(0.9);
+        changed.write_file().unwrap();
+        let mut fields = config.reload().unwrap();
+        fields.sort();
+        assert_eq!(fields, ["volume", "volume_cap"]);
+        assert_eq!(config.volume, 0.8);
+    }
+
+    #[test]
+    fn library_root_availability() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut root = LibraryRoot::new(dir.path().join("mount"));
+        assert!(!root.is_available());
+
+        // The empty mount point of an unplugged drive
+        std::fs::create_dir(&root.path).unwrap();
+        assert!(!root.is_available());
+        std::fs::write(root.path.join("song.flac"), b"").unwrap();
+        assert!(root.is_available());
+
+        // Files left in the mount point don't mean the drive is there
+        root.removable = true;
+        assert!(!root.is_available());
+        std::fs::remove_file(root.path.join("song.flac")).unwrap();
+        std::fs::write(root.path.join(ROOT_MARKER), b"").unwrap();
+        assert!(root.is_available());
+    }
+
     #[test]
     fn test3() {
         let (config, _) = read_config_lib();
@@ -0,0 +1,106 @@
+//! Where the config and the files next to it are kept by default, using
+//! the conventions of each platform: the XDG base directories on Linux,
+//! AppData on Windows, and Application Support on macOS
+
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+
+/// The name of the folders made in each of the platform directories
+const APP_FOLDER: &str = "Dango Music Player";
+/// Linux folders are lowercase without spaces by convention
+const APP_FOLDER_UNIX: &str = "dango-music-player";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultPaths {
+    /// The config file itself
+    pub config: PathBuf,
+    /// The default library file
+    pub library: PathBuf,
+    /// The folder of the caches, such as album art
+    pub cache: PathBuf,
+    /// The folder of the session, history, and bookmarks
+    pub state: PathBuf,
+}
+
+impl DefaultPaths {
+    /// The paths of the platform this is running on, or `None` if the
+    /// home folder can't be found
+    pub fn new() -> Option<Self> {
+        Self::for_platform(env::consts::OS, |name| env::var_os(name).map(PathBuf::from))
+    }
+
+    /// Make every folder, along with their parents
+    pub fn create_folders(&self) -> Result<(), Error> {
+        let folders = [self.config.parent(), self.library.parent(), Some(&self.cache), Some(&self.state)];
+        for folder in folders.into_iter().flatten() {
+            fs::create_dir_all(folder)?;
+        }
+        Ok(())
+    }
+
+    /// The paths of the platform `os`, looking up environment variables with `var`
+    fn for_platform(os: &str, var: impl Fn(&str) -> Option<PathBuf>) -> Option<Self> {
+        // Relative paths aren't valid for any of these variables
+        let var = |name: &str| var(name).filter(|path| path.is_absolute());
+
+        let (config, data, cache, state) = match os {
+            "windows" => {
+                let roaming = var("APPDATA")?.join(APP_FOLDER);
+                let local = var("LOCALAPPDATA").map_or_else(|| roaming.clone(), |local| local.join(APP_FOLDER));
+                (roaming.clone(), roaming, local.join("cache"), local)
+            }
+            "macos" => {
+                let library = var("HOME")?.join("Library");
+                let support = library.join("Application Support").join(APP_FOLDER);
+                (support.clone(), support.clone(), library.join("Caches").join(APP_FOLDER), support)
+            }
+            _ => {
+                let home = var("HOME");
+                let xdg = |name: &str, fallback: &str| var(name).or_else(|| Some(home.as_ref()?.join(fallback)));
+                (
+                    xdg("XDG_CONFIG_HOME", ".config")?.join(APP_FOLDER_UNIX),
+                    xdg("XDG_DATA_HOME", ".local/share")?.join(APP_FOLDER_UNIX),
+                    xdg("XDG_CACHE_HOME", ".cache")?.join(APP_FOLDER_UNIX),
+                    xdg("XDG_STATE_HOME", ".local/state")?.join(APP_FOLDER_UNIX),
+                )
+            }
+        };
+
+        Some(DefaultPaths {
+            config: config.join("config.json"),
+            library: data.join("library.dlib"),
+            cache,
+            state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::DefaultPaths;
+
+    #[test]
+    fn platform_paths() {
+        let vars = |vars: &'static [(&str, &str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| PathBuf::from(value))
+        };
+
+        let linux = DefaultPaths::for_platform("linux", vars(&[("HOME", "/home/dango"), ("XDG_CACHE_HOME", "/tmp/cache")])).unwrap();
+        assert_eq!(linux.config, Path::new("/home/dango/.config/dango-music-player/config.json"));
+        assert_eq!(linux.library, Path::new("/home/dango/.local/share/dango-music-player/library.dlib"));
+        assert_eq!(linux.cache, Path::new("/tmp/cache/dango-music-player"));
+        assert_eq!(linux.state, Path::new("/home/dango/.local/state/dango-music-player"));
+        // Relative XDG paths are ignored
+        let relative = DefaultPaths::for_platform("linux", vars(&[("HOME", "/home/dango"), ("XDG_CONFIG_HOME", "config")]));
+        assert_eq!(relative.unwrap().config, linux.config);
+        assert_eq!(DefaultPaths::for_platform("linux", vars(&[])), None);
+
+        let macos = DefaultPaths::for_platform("macos", vars(&[("HOME", "/Users/dango")])).unwrap();
+        assert_eq!(macos.config, Path::new("/Users/dango/Library/Application Support/Dango Music Player/config.json"));
+        assert_eq!(macos.cache, Path::new("/Users/dango/Library/Caches/Dango Music Player"));
+    }
+}
@@ -0,0 +1,307 @@
+//! Storage for the tokens and passwords of online services, such as
+//! scrobblers and remote libraries, so they aren't kept in the config
+//!
+//! Secrets are kept in the keyring of the OS where there is one which can
+//! be reached: the Secret Service on Linux and the BSDs, the Keychain on
+//! macOS, and the Credential Manager on Windows. Otherwise they're kept in
+//! a file only its owner can read, encrypted with ChaCha20-Poly1305 under a
+//! key made with Argon2 from a passphrase. Without a passphrase of the
+//! user's the machine ID is used, which keeps the file from being read on
+//! another computer or changed unnoticed, but leaves its permissions as
+//! the only thing keeping it from other programs on this one.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use thiserror::Error;
+
+use super::Config;
+
+/// The application name secrets are stored under in keyrings
+const KEYRING_APPLICATION: &str = "dmp-core";
+/// The first bytes of the encrypted file
+const MAGIC: &[u8; 4] = b"DMPS";
+const FILE_VERSION: u8 = 2;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("The keyring failed: {0}")]
+    Keyring(#[from] keyring::Error),
+    /// The file was made on another computer or with another passphrase,
+    /// or has been changed
+    #[error("The secrets file can't be read on this machine")]
+    Undecryptable,
+    #[error("There is no machine ID to encrypt secrets with")]
+    NoMachineSecret,
+    #[error("The key for the secrets file could not be made: {0}")]
+    Kdf(argon2::Error),
+}
+
+/// The secrets of every service, `service -> key -> value`
+type SecretMap = BTreeMap<String, BTreeMap<String, String>>;
+
+pub struct Secrets {
+    /// Where secrets are kept when there's no keyring
+    file: PathBuf,
+    keyring: bool,
+    /// What the file's key is made from instead of the machine ID
+    passphrase: Option<String>,
+}
+
+impl Secrets {
+    /// Secrets kept in the OS keyring, or in the state folder of the
+    /// config when there isn't one
+    pub fn new(config: &Config) -> Self {
+        Secrets {
+            file: config.state_file("secrets.bin"),
+            keyring: true,
+            passphrase: None,
+        }
+    }
+
+    /// Secrets kept only in the encrypted file at `path`
+    pub fn in_file<P: AsRef<Path>>(path: P) -> Self {
+        Secrets {
+            file: path.as_ref().to_path_buf(),
+            keyring: false,
+            passphrase: None,
+        }
+    }
+
+    /// Encrypt the file with a key made from `passphrase` rather than the
+    /// machine ID, which other users of the computer can read
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    pub fn get(&self, service: &str, key: &str) -> Result<Option<String>, SecretError> {
+        if self.keyring {
+            match keyring_entry(service, key).and_then(|entry| entry.get_password()) {
+                Ok(value) => return Ok(Some(value)),
+                Err(keyring::Error::NoEntry) => (),
+                Err(error) => println!("Reading the secret from a file, {}", error),
+            }
+        }
+        // Secrets may have been stored while the keyring couldn't be reached
+        Ok(self.read_file()?.get(service).and_then(|keys| keys.get(key)).cloned())
+    }
+
+    pub fn set(&self, service: &str, key: &str, value: &str) -> Result<(), SecretError> {
+        if self.keyring {
+            match keyring_entry(service, key).and_then(|entry| entry.set_password(value)) {
+                // Don't leave an older copy behind in the file
+                Ok(()) => return self.remove_from_file(service, key),
+                Err(error) => println!("Keeping the secret in a file, {}", error),
+            }
+        }
+        let mut secrets = self.read_file()?;
+        secrets.entry(service.to_string()).or_default().insert(key.to_string(), value.to_string());
+        self.write_file(&secrets)
+    }
+
+    pub fn remove(&self, service: &str, key: &str) -> Result<(), SecretError> {
+        if self.keyring {
+            match keyring_entry(service, key).and_then(|entry| entry.delete_credential()) {
+                Ok(()) | Err(keyring::Error::NoEntry) => (),
+                Err(error) => println!("Could not remove the secret from the keyring, {}", error),
+            }
+        }
+        self.remove_from_file(service, key)
+    }
+
+    fn remove_from_file(&self, service: &str, key: &str) -> Result<(), SecretError> {
+        let mut secrets = self.read_file()?;
+        let Some(keys) = secrets.get_mut(service) else {
+            return Ok(());
+        };
+        if keys.remove(key).is_some() {
+            if keys.is_empty() {
+                secrets.remove(service);
+            }
+            self.write_file(&secrets)?;
+        }
+        Ok(())
+    }
+
+    /// What the file's key is made from
+    fn file_secret(&self) -> Result<String, SecretError> {
+        match &self.passphrase {
+            Some(passphrase) => Ok(passphrase.clone()),
+            None => machine_secret().ok_or(SecretError::NoMachineSecret),
+        }
+    }
+
+    fn read_file(&self) -> Result<SecretMap, SecretError> {
+        let contents = match fs::read(&self.file) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(SecretMap::new()),
+            Err(error) => return Err(error.into()),
+        };
+        if contents.starts_with(MAGIC) && contents.get(MAGIC.len()).is_some_and(|version| *version < FILE_VERSION) {
+            // The older format can't be trusted, so its secrets have to be
+            // entered again
+            println!("Replacing the secrets file, which is in an older format");
+            return Ok(SecretMap::new());
+        }
+        let plain = decrypt(&contents, self.file_secret()?.as_bytes())?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    fn write_file(&self, secrets: &SecretMap) -> Result<(), SecretError> {
+        let plain = serde_json::to_vec(secrets)?;
+        let contents = encrypt(&plain, self.file_secret()?.as_bytes())?;
+
+        let mut writer = self.file.clone();
+        writer.set_extension("tmp");
+        // A leftover file would keep its permissions
+        match fs::remove_file(&writer) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => (),
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&writer)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(writer, &self.file)?;
+        Ok(())
+    }
+}
+
+/// The keyring item of a secret
+fn keyring_entry(service: &str, key: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(&format!("{}:{}", KEYRING_APPLICATION, service), key)
+}
+
+/// Something which is unique to this computer and stays the same
+fn machine_secret() -> Option<String> {
+    let output = |program: &str, args: &[&str]| {
+        let output = Command::new(program).args(args).stderr(Stdio::null()).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).to_string())
+    };
+    let secret = match std::env::consts::OS {
+        "macos" => output("ioreg", &["-rd1", "-c", "IOPlatformExpertDevice"])?
+            .lines()
+            .find(|line| line.contains("IOPlatformUUID"))?
+            .rsplit('"')
+            .nth(1)?
+            .to_string(),
+        "windows" => output("reg", &["query", r"HKLM\SOFTWARE\Microsoft\Cryptography", "/v", "MachineGuid"])?
+            .split_whitespace()
+            .last()?
+            .to_string(),
+        _ => ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())?,
+    };
+    let secret = secret.trim().to_string();
+    (!secret.is_empty()).then_some(secret)
+}
+
+/// Make a 256 bit key from the `secret` and `salt` with Argon2id
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<Key, SecretError> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(SecretError::Kdf)?;
+    Ok(key)
+}
+
+/// Encrypt `plain` with a fresh random salt and nonce. The header is
+/// authenticated along with it, so any change to the file is noticed.
+fn encrypt(plain: &[u8], secret: &[u8]) -> Result<Vec<u8>, SecretError> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut contents = Vec::with_capacity(MAGIC.len() + 1 + SALT_LEN + NONCE_LEN + plain.len() + 16);
+    contents.extend_from_slice(MAGIC);
+    contents.push(FILE_VERSION);
+    contents.extend_from_slice(&salt);
+    contents.extend_from_slice(&nonce);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+    let data = cipher
+        .encrypt(&nonce, chacha20poly1305::aead::Payload { msg: plain, aad: &contents })
+        .map_err(|_| SecretError::Undecryptable)?;
+    contents.extend_from_slice(&data);
+    Ok(contents)
+}
+
+fn decrypt(contents: &[u8], secret: &[u8]) -> Result<Vec<u8>, SecretError> {
+    let header = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+    if contents.len() < header + 16 || &contents[..MAGIC.len()] != MAGIC || contents[MAGIC.len()] != FILE_VERSION {
+        return Err(SecretError::Undecryptable);
+    }
+    let salt = &contents[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = Nonce::from_slice(&contents[header - NONCE_LEN..header]);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(secret, salt)?);
+    cipher
+        .decrypt(nonce, chacha20poly1305::aead::Payload { msg: &contents[header..], aad: &contents[..header] })
+        .map_err(|_| SecretError::Undecryptable)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decrypt, encrypt, SecretError, Secrets};
+
+    #[test]
+    fn secrets_file() {
+        let encrypted = encrypt(b"hunter2", b"machine").unwrap();
+        assert_eq!(decrypt(&encrypted, b"machine").unwrap(), b"hunter2");
+        assert!(matches!(decrypt(&encrypted, b"another machine"), Err(SecretError::Undecryptable)));
+        // Changing any byte, the header included, is noticed
+        for i in [4, 10, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 1;
+            assert!(matches!(decrypt(&tampered, b"machine"), Err(SecretError::Undecryptable)));
+        }
+        // Salts and nonces are never reused
+        assert_ne!(encrypt(b"hunter2", b"machine").unwrap()[5..], encrypted[5..]);
+
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("secrets.bin");
+        let secrets = Secrets::in_file(&path).with_passphrase("correct horse");
+        assert_eq!(secrets.get("listenbrainz", "token").unwrap(), None);
+        secrets.set("listenbrainz", "token", "abc").unwrap();
+        secrets.set("subsonic", "password", "def").unwrap();
+        assert_eq!(secrets.get("listenbrainz", "token").unwrap().as_deref(), Some("abc"));
+        let stored = std::fs::read(&path).unwrap();
+        assert!(!stored.windows(3).any(|window| window == b"abc"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert!(matches!(
+            Secrets::in_file(&path).with_passphrase("wrong").get("subsonic", "password"),
+            Err(SecretError::Undecryptable)
+        ));
+        secrets.remove("listenbrainz", "token").unwrap();
+        assert_eq!(secrets.get("listenbrainz", "token").unwrap(), None);
+        assert_eq!(secrets.get("subsonic", "password").unwrap().as_deref(), Some("def"));
+
+        // Files in the first format are replaced rather than trusted
+        std::fs::write(&path, b"DMPS\x01 old contents").unwrap();
+        assert_eq!(secrets.get("subsonic", "password").unwrap(), None);
+        secrets.set("subsonic", "password", "ghi").unwrap();
+        assert_eq!(secrets.get("subsonic", "password").unwrap().as_deref(), Some("ghi"));
+    }
+}
@@ -0,0 +1,112 @@
+//! One error type for everything the core can fail at, so frontends only
+//! have to match on and show a single type
+//!
+//! Every module keeps its own error, which converts into [DmpError] with
+//! `?`. Library and playlist code returns `Box<dyn Error>`, which is
+//! wrapped with [DmpError::library] and [DmpError::playlist].
+
+use std::fmt::Display;
+
+use kushi::QueueError;
+use thiserror::Error;
+
+use crate::config::secrets::SecretError;
+use crate::config::ConfigError;
+use crate::music_controller::cast::CastError;
+use crate::music_controller::controller::ControllerError;
+use crate::music_controller::queue::QueueViolation;
+use crate::music_player::player::PlayerError;
+use crate::music_storage::corrections::CorrectionError;
+use crate::music_storage::library_format::LibraryFormatError;
+use crate::music_storage::podcast::PodcastError;
+
+pub type DmpResult<T> = Result<T, DmpError>;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DmpError {
+    #[error(transparent)]
+    Player(#[from] PlayerError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("{0:?}")]
+    Queue(#[from] QueueError),
+    #[error("{0:?}")]
+    QueueInvariant(Vec<QueueViolation>),
+    #[error(transparent)]
+    Podcast(#[from] PodcastError),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error(transparent)]
+    Correction(#[from] CorrectionError),
+    #[error(transparent)]
+    LibraryFormat(#[from] LibraryFormatError),
+    #[error(transparent)]
+    Cast(#[from] CastError),
+    /// Reading, scanning, or saving the library failed
+    #[error("library: {0}")]
+    Library(String),
+    /// Reading, importing, or saving a playlist failed
+    #[error("playlist: {0}")]
+    Playlist(String),
+    /// A remote library, such as Jellyfin or Subsonic, failed
+    #[error("remote: {0}")]
+    Remote(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl DmpError {
+    /// Wrap an error from the library, such as from [MusicLibrary::save](crate::music_storage::library::MusicLibrary::save)
+    pub fn library<E: Display>(error: E) -> Self {
+        DmpError::Library(error.to_string())
+    }
+
+    /// Wrap an error from a playlist, such as from [Playlist::from_file](crate::music_storage::playlist::Playlist::from_file)
+    pub fn playlist<E: Display>(error: E) -> Self {
+        DmpError::Playlist(error.to_string())
+    }
+}
+
+impl From<ControllerError> for DmpError {
+    fn from(error: ControllerError) -> Self {
+        match error {
+            ControllerError::QueueError(error) => DmpError::Queue(error),
+            ControllerError::PlayerError(error) => DmpError::Player(error),
+            ControllerError::ConfigError(error) => DmpError::Config(error),
+            ControllerError::IoError(error) => DmpError::Io(error),
+            ControllerError::RemoteError(error) => DmpError::Remote(error),
+            ControllerError::PodcastError(error) => DmpError::Podcast(error),
+            ControllerError::QueueInvariant(violations) => DmpError::QueueInvariant(violations),
+            ControllerError::CastError(error) => DmpError::Cast(error),
+            ControllerError::LibraryError(error) => DmpError::Library(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::DmpError;
+    use crate::config::ConfigError;
+    use crate::i18n::Localize;
+    use crate::music_controller::controller::ControllerError;
+    use crate::music_player::player::PlayerError;
+
+    #[test]
+    fn unified_errors() {
+        let error: DmpError = ControllerError::PlayerError(PlayerError::NotFound).into();
+        assert!(matches!(error, DmpError::Player(PlayerError::NotFound)));
+        assert!(error.localize("en").contains("plugged in"));
+
+        let error: DmpError = ConfigError::NoDefaultLibrary.into();
+        assert_eq!(error.to_string(), "There is no Default Library for this Config");
+        assert_eq!(error.localize("en"), "There is no default library for this config");
+
+        let failed: Result<(), Box<dyn Error>> = Err("disk full".into());
+        let error = failed.map_err(DmpError::library).unwrap_err();
+        assert_eq!(error.to_string(), "library: disk full");
+        assert_eq!(error.localize("en"), "The library could not be read or saved: disk full");
+    }
+}
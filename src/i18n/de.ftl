@@ -0,0 +1,44 @@
+## Fehler
+
+config-no-library = Keine Bibliothek für { $uuid } gefunden
+config-no-default-library = Diese Konfiguration hat keine Standardbibliothek
+config-bad-playlist = Bitte eine gültige m3u8-Playlist angeben
+config-no-backup = Kein Sicherungsordner für die Konfiguration vorhanden
+
+correction-not-found = Kein Korrekturvorschlag mit dieser ID
+correction-song-not-found = Der Titel ist nicht mehr in der Bibliothek
+correction-stale = { $tag } wurde geändert, seit die Korrektur vorgeschlagen wurde
+correction-art = Das Albumcover konnte nicht ersetzt werden: { $error }
+correction-file = Die Korrekturvorschläge konnten nicht gelesen oder gespeichert werden: { $error }
+
+error-player-init = Der Audioplayer konnte nicht starten: { $error }. Prüfe, ob GStreamer und seine Plugins installiert sind
+error-player-not-found = Die Datei des Titels wurde nicht gefunden. Prüfe, ob das Laufwerk angeschlossen ist, oder suche den Titel neu
+error-player-timeout = Der Titel hat zu lange zum Laden gebraucht. Prüfe die Verbindung zum Speicherort und versuche es erneut
+error-player = Die Wiedergabe ist fehlgeschlagen: { $error }
+error-queue = Die Warteschlange konnte nicht geändert werden: { $error }
+error-queue-invariant = Diese Änderung würde die Warteschlange beschädigen: { $error }
+error-podcast = Der Podcast konnte nicht aktualisiert werden: { $error }. Prüfe die Feed-Adresse und deine Verbindung
+error-secret-undecryptable = Gespeicherte Passwörter können auf diesem Gerät nicht gelesen werden, melde dich erneut bei deinen Diensten an
+error-secret = Gespeicherte Passwörter konnten nicht gelesen oder gespeichert werden: { $error }
+error-library-too-new = Die Bibliothek wurde mit einer neueren Version des Players gespeichert, aktualisiere den Player, um sie zu öffnen
+error-library = Die Bibliothek konnte nicht gelesen oder gespeichert werden: { $error }
+error-playlist = Die Playlist konnte nicht gelesen oder gespeichert werden: { $error }
+error-remote = Die entfernte Bibliothek war nicht erreichbar: { $error }. Prüfe deine Verbindung und Anmeldung
+error-cast = Die Wiedergabe konnte nicht auf das Gerät übertragen werden: { $error }
+error-io = Eine Datei konnte nicht gelesen oder geschrieben werden: { $error }
+
+## Korrekturvorschläge
+
+correction-source = Vorgeschlagen von { $source }, zu { $confidence } % sicher
+correction-change = { $tag } von „{ $old }“ zu „{ $new }“ ändern
+correction-add = { $tag } auf „{ $new }“ setzen
+correction-remove = { $tag } („{ $old }“) entfernen
+correction-replace-art = Albumcover ersetzen
+
+## Importe
+
+migration-summary = { $added } Titel hinzugefügt, { $merged } zusammengeführt und { $skipped } übersprungen
+migration-playlists = { $playlists } Playlists importiert, { $unmatched } ihrer Titel wurden nicht gefunden
+migration-art = { $art } Titel haben ein Albumcover erhalten
+playlist-import-summary = { $playlists } Playlists gefunden, { $errors } konnten nicht gelesen werden
+playlist-import-entry = { $title }: { $matched } von { $entries } Titeln gefunden
@@ -0,0 +1,47 @@
+# Messages are written as `id = text`, where `{ $name }` is filled in
+# with the value of the argument called `name`
+
+## Errors
+
+config-no-library = No library found for { $uuid }
+config-no-default-library = There is no default library for this config
+config-bad-playlist = Please provide a better m3u8 playlist
+config-no-backup = No backup config folder present
+
+correction-not-found = No suggested fix with that id
+correction-song-not-found = The song is no longer in the library
+correction-stale = { $tag } has changed since the fix was suggested
+correction-art = Failed to replace the album art: { $error }
+correction-file = Failed to read or write the suggested fixes: { $error }
+
+error-player-init = The audio player couldn't start: { $error }. Check that GStreamer and its plugins are installed
+error-player-not-found = The song's file wasn't found. Check that its drive is plugged in, or relocate the song
+error-player-timeout = The song took too long to load. Check the connection to where it's stored and try again
+error-player = Playback failed: { $error }
+error-queue = The queue couldn't be changed: { $error }
+error-queue-invariant = That change would break the queue: { $error }
+error-podcast = The podcast couldn't be updated: { $error }. Check the feed address and your connection
+error-secret-undecryptable = Saved passwords can't be read on this machine, sign in to your services again
+error-secret = Saved passwords couldn't be read or saved: { $error }
+error-library-too-new = The library was saved by a newer version of the player, update the player to open it
+error-library = The library could not be read or saved: { $error }
+error-playlist = The playlist could not be read or saved: { $error }
+error-remote = The remote library couldn't be reached: { $error }. Check your connection and sign-in
+error-cast = Playback couldn't be cast to the device: { $error }
+error-io = A file couldn't be read or written: { $error }
+
+## Fix suggestions
+
+correction-source = Suggested by { $source }, { $confidence }% sure
+correction-change = Change { $tag } from "{ $old }" to "{ $new }"
+correction-add = Set { $tag } to "{ $new }"
+correction-remove = Remove { $tag } ("{ $old }")
+correction-replace-art = Replace the album art
+
+## Import summaries
+
+migration-summary = Added { $added } songs, merged { $merged } and skipped { $skipped }
+migration-playlists = Imported { $playlists } playlists, { $unmatched } of their songs weren't found
+migration-art = { $art } songs were given album art
+playlist-import-summary = Found { $playlists } playlists, { $errors } couldn't be read
+playlist-import-entry = { $title }: found { $matched } of { $entries } songs
@@ -0,0 +1,277 @@
+//! Translations of the text the core makes for users to read, such as
+//! errors, suggested fixes, and import summaries
+//!
+//! Each piece of text has a message ID, which is looked up in the catalog
+//! of the language set in the [Config](crate::config::Config). Catalogs
+//! are a small part of the [Fluent](https://projectfluent.org) syntax,
+//! `id = text` lines with `{ $name }` arguments, and are built into the
+//! library. Messages missing from a catalog fall back to English.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::config::secrets::SecretError;
+use crate::config::ConfigError;
+use crate::error::DmpError;
+use crate::music_player::player::PlayerError;
+use crate::music_storage::corrections::{Correction, CorrectionError, FieldChange};
+use crate::music_storage::library::Tag;
+use crate::music_storage::library_format::LibraryFormatError;
+use crate::music_storage::migrate::MigrationReport;
+use crate::music_storage::playlist_import::{BulkImport, PlaylistImport};
+
+/// The language messages are in when no other one is set
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// The languages which have a catalog, and the catalogs themselves
+const CATALOGS: &[(&str, &str)] = &[("en", include_str!("en.ftl")), ("de", include_str!("de.ftl"))];
+
+/// The languages messages can be translated to
+pub fn languages() -> Vec<&'static str> {
+    CATALOGS.iter().map(|(language, _)| *language).collect()
+}
+
+fn catalogs() -> &'static HashMap<&'static str, HashMap<&'static str, &'static str>> {
+    static PARSED: OnceLock<HashMap<&str, HashMap<&str, &str>>> = OnceLock::new();
+    PARSED.get_or_init(|| {
+        CATALOGS
+            .iter()
+            .map(|(language, catalog)| (*language, parse_catalog(catalog)))
+            .collect()
+    })
+}
+
+fn parse_catalog(catalog: &str) -> HashMap<&str, &str> {
+    catalog
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(id, text)| (id.trim(), text.trim()))
+        .collect()
+}
+
+/// A piece of text to translate, along with the values filled into it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: &'static str,
+    pub args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(id: &'static str) -> Self {
+        Message { id, args: Vec::new() }
+    }
+
+    pub fn arg<T: ToString>(mut self, name: &'static str, value: T) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    /// The message in `language`, such as `de` or `de-AT`. Regional
+    /// languages use the catalog of their language if they don't have one.
+    pub fn translate(&self, language: &str) -> String {
+        let base = language.split(['-', '_']).next().unwrap_or_default();
+        let text = [language, base, DEFAULT_LANGUAGE]
+            .iter()
+            .find_map(|language| catalogs().get(language)?.get(self.id))
+            .copied()
+            // A missing message is still better shown as something
+            .unwrap_or(self.id);
+        self.fill(text)
+    }
+
+    /// Replace the `{ $name }` arguments in `text` with their values
+    fn fill(&self, text: &str) -> String {
+        let mut filled = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else { break };
+            filled.push_str(&rest[..start]);
+            let name = rest[start + 1..start + end].trim().trim_start_matches('$');
+            match self.args.iter().find(|(arg, _)| *arg == name) {
+                Some((_, value)) => filled.push_str(value),
+                None => filled.push_str(&rest[start..start + end + 1]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        filled.push_str(rest);
+        filled
+    }
+}
+
+/// Something which can be shown to users in their own language
+pub trait Localize {
+    /// The messages which make up the text, one for each line
+    fn messages(&self) -> Vec<Message>;
+
+    /// The text in `language`, see [Message::translate]
+    fn localize(&self, language: &str) -> String {
+        let lines: Vec<String> = self.messages().iter().map(|message| message.translate(language)).collect();
+        lines.join("\n")
+    }
+}
+
+/// The name of a tag shown in messages
+fn tag_name(tag: &Tag) -> String {
+    match tag {
+        Tag::Key(key) => key.clone(),
+        tag => format!("{:?}", tag),
+    }
+}
+
+impl Localize for ConfigError {
+    fn messages(&self) -> Vec<Message> {
+        vec![match self {
+            ConfigError::NoConfigLibrary(uuid) => Message::new("config-no-library").arg("uuid", uuid),
+            ConfigError::NoDefaultLibrary => Message::new("config-no-default-library"),
+            ConfigError::BadPlaylist => Message::new("config-bad-playlist"),
+            ConfigError::NoBackupLibrary => Message::new("config-no-backup"),
+        }]
+    }
+}
+
+impl Localize for CorrectionError {
+    fn messages(&self) -> Vec<Message> {
+        vec![match self {
+            CorrectionError::NotFound => Message::new("correction-not-found"),
+            CorrectionError::SongNotFound => Message::new("correction-song-not-found"),
+            CorrectionError::Stale(tag) => Message::new("correction-stale").arg("tag", tag_name(tag)),
+            CorrectionError::Art(error) => Message::new("correction-art").arg("error", error),
+            CorrectionError::Io(error) => Message::new("correction-file").arg("error", error),
+            CorrectionError::Json(error) => Message::new("correction-file").arg("error", error),
+        }]
+    }
+}
+
+impl Localize for DmpError {
+    fn messages(&self) -> Vec<Message> {
+        let message = match self {
+            DmpError::Config(error) => return error.messages(),
+            DmpError::Correction(error) => return error.messages(),
+            DmpError::Player(PlayerError::Init(error)) => Message::new("error-player-init").arg("error", error),
+            DmpError::Player(PlayerError::NotFound) => Message::new("error-player-not-found"),
+            DmpError::Player(PlayerError::SourceTimeout(_)) => Message::new("error-player-timeout"),
+            DmpError::Player(error) => Message::new("error-player").arg("error", error),
+            DmpError::Queue(error) => Message::new("error-queue").arg("error", format!("{:?}", error)),
+            DmpError::QueueInvariant(violations) => {
+                let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+                Message::new("error-queue-invariant").arg("error", violations.join(", "))
+            }
+            DmpError::Podcast(error) => Message::new("error-podcast").arg("error", error),
+            DmpError::Secret(SecretError::Undecryptable) => Message::new("error-secret-undecryptable"),
+            DmpError::Secret(error) => Message::new("error-secret").arg("error", error),
+            DmpError::LibraryFormat(LibraryFormatError::TooNew(_)) => Message::new("error-library-too-new"),
+            DmpError::LibraryFormat(error) => Message::new("error-library").arg("error", error),
+            DmpError::Library(error) => Message::new("error-library").arg("error", error),
+            DmpError::Playlist(error) => Message::new("error-playlist").arg("error", error),
+            DmpError::Remote(error) => Message::new("error-remote").arg("error", error),
+            DmpError::Cast(error) => Message::new("error-cast").arg("error", error),
+            DmpError::Io(error) => Message::new("error-io").arg("error", error),
+        };
+        vec![message]
+    }
+}
+
+impl Localize for FieldChange {
+    fn messages(&self) -> Vec<Message> {
+        let message = match (&self.old, &self.new) {
+            (Some(old), Some(new)) => Message::new("correction-change").arg("old", old).arg("new", new),
+            (None, Some(new)) => Message::new("correction-add").arg("new", new),
+            (Some(old), None) => Message::new("correction-remove").arg("old", old),
+            (None, None) => return Vec::new(),
+        };
+        vec![message.arg("tag", tag_name(&self.tag))]
+    }
+}
+
+impl Localize for Correction {
+    fn messages(&self) -> Vec<Message> {
+        let confidence = (self.confidence * 100.0).round();
+        let mut messages = vec![Message::new("correction-source")
+            .arg("source", &self.source)
+            .arg("confidence", confidence)];
+        messages.extend(self.changes.iter().flat_map(FieldChange::messages));
+        if self.art.is_some() {
+            messages.push(Message::new("correction-replace-art"));
+        }
+        messages
+    }
+}
+
+impl Localize for MigrationReport {
+    fn messages(&self) -> Vec<Message> {
+        let mut messages = vec![Message::new("migration-summary")
+            .arg("added", self.added)
+            .arg("merged", self.merged)
+            .arg("skipped", self.skipped)];
+        if self.playlists > 0 {
+            messages.push(
+                Message::new("migration-playlists")
+                    .arg("playlists", self.playlists)
+                    .arg("unmatched", self.unmatched),
+            );
+        }
+        if self.art > 0 {
+            messages.push(Message::new("migration-art").arg("art", self.art));
+        }
+        messages
+    }
+}
+
+impl Localize for PlaylistImport {
+    fn messages(&self) -> Vec<Message> {
+        vec![Message::new("playlist-import-entry")
+            .arg("title", self.playlist.title())
+            .arg("matched", self.matched())
+            .arg("entries", self.entries)]
+    }
+}
+
+impl Localize for BulkImport {
+    fn messages(&self) -> Vec<Message> {
+        let mut messages = vec![Message::new("playlist-import-summary")
+            .arg("playlists", self.imports.len())
+            .arg("errors", self.errors.len())];
+        messages.extend(self.imports.iter().flat_map(PlaylistImport::messages));
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{catalogs, languages, Localize, Message, DEFAULT_LANGUAGE};
+    use crate::music_storage::corrections::CorrectionError;
+    use crate::music_storage::library::Tag;
+    use crate::music_storage::migrate::MigrationReport;
+
+    #[test]
+    fn translate_messages() {
+        let error = CorrectionError::Stale(Tag::Title);
+        assert_eq!(error.localize("en"), "Title has changed since the fix was suggested");
+        assert_eq!(error.localize("de-AT"), "Title wurde geändert, seit die Korrektur vorgeschlagen wurde");
+        // Languages without a catalog fall back to English
+        assert_eq!(error.localize("xx"), error.localize("en"));
+        assert_eq!(Message::new("made-up").translate("de"), "made-up");
+
+        let report = MigrationReport { added: 3, art: 1, ..Default::default() };
+        assert_eq!(
+            report.localize("en"),
+            "Added 3 songs, merged 0 and skipped 0\n1 songs were given album art"
+        );
+
+        // Every catalog has every English message, with the same arguments
+        let arguments = |text: &str| -> HashSet<String> {
+            text.split('{').skip(1).filter_map(|arg| Some(arg.split_once('}')?.0.trim().to_string())).collect()
+        };
+        let english = &catalogs()[DEFAULT_LANGUAGE];
+        for language in languages() {
+            let catalog = &catalogs()[language];
+            for (id, text) in english {
+                let translated = catalog.get(id).unwrap_or_else(|| panic!("{} is missing {}", language, id));
+                assert_eq!(arguments(text), arguments(translated), "{} in {}", id, language);
+            }
+        }
+    }
+}
@@ -1,7 +1,45 @@
 pub mod music_storage {
+    pub mod analysis;
+    pub mod art;
+    pub mod cache;
+    pub mod chapters;
+    pub mod corrections;
+    pub mod credits;
+    pub mod cue;
+    pub mod decode;
+    pub mod disk_space;
+    pub mod duration;
+    pub mod duplicates;
+    pub mod editions;
+    pub mod fingerprint;
+    pub mod jellyfin;
     pub mod library;
+    pub mod library_format;
+    pub mod loudness;
+    pub mod lyrics;
+    pub mod memory_store;
+    pub mod migrate;
+    pub mod mirror;
     pub mod music_collection;
+    pub mod musicbrainz;
+    pub mod offline;
+    pub mod path_remap;
     pub mod playlist;
+    pub mod playlist_import;
+    pub mod plex;
+    pub mod podcast;
+    pub mod relocate;
+    pub mod release_type;
+    pub mod smart_playlist;
+    pub mod remote;
+    pub mod rip_log;
+    pub mod silence;
+    #[cfg(feature = "sqlite")]
+    pub mod sqlite;
+    pub mod store;
+    pub mod subsonic;
+    pub mod transfer;
+    pub mod waveform;
     mod utils;
 
     #[allow(dead_code)]
@@ -9,14 +47,48 @@ pub mod music_storage {
 }
 
 pub mod music_controller {
+    pub mod alarm;
+    pub mod bookmarks;
+    pub mod cast;
     pub mod controller;
     pub mod connections;
+    pub mod discord;
+    pub mod events;
+    pub mod exclusions;
+    pub mod history;
+    pub mod idle;
+    pub mod ignore;
+    #[cfg(feature = "media-keys")]
+    pub mod media_keys;
+    pub mod modes;
+    pub mod notifications;
+    pub mod power;
+    pub mod private;
+    pub mod profiles;
+    pub mod quarantine;
     pub mod queue;
+    pub mod replaygain;
+    pub mod session;
+    pub mod skip_silence;
+    pub mod sleep;
+    pub mod snapshot;
+    pub mod transition;
 }
 
 pub mod music_player {
     pub mod gstreamer;
     pub mod player;
+    pub mod probe;
+}
+
+pub mod music_server {
+    pub mod http;
+    pub mod mpd;
+    #[cfg(feature = "remote-api")]
+    pub mod remote_api;
 }
 
 pub mod config;
+pub mod error;
+pub mod i18n;
+pub mod prelude;
@@ -0,0 +1,96 @@
+//! Starting playback at a set time, such as to wake up to music, with the
+//! volume fading in from silence
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use uuid::Uuid;
+
+/// What an [Alarm] plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmSource {
+    Song(Uuid),
+    /// Every song of the playlist, in order
+    Playlist(Uuid),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alarm {
+    pub at: DateTime<Local>,
+    pub source: AlarmSource,
+    /// How long the volume takes to reach the volume in the config
+    pub fade_in: Duration,
+}
+
+impl Alarm {
+    pub fn is_due(&self, now: DateTime<Local>) -> bool {
+        now >= self.at
+    }
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlarmEvent {
+    Started(AlarmSource),
+    /// The alarm went off, but couldn't play anything
+    Failed { source: AlarmSource, error: String },
+    Cancelled,
+}
+
+/// The volume of an alarm which has started playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FadeIn {
+    started: Instant,
+    duration: Duration,
+    /// The volume reached at the end of the fade
+    volume: f64,
+}
+
+impl FadeIn {
+    pub fn new(started: Instant, duration: Duration, volume: f64) -> Self {
+        FadeIn { started, duration, volume }
+    }
+
+    /// The volume to use at `now`, or `None` once the fade is over
+    pub fn volume(&self, now: Instant) -> Option<f64> {
+        let elapsed = now.saturating_duration_since(self.started);
+        match elapsed < self.duration {
+            true => Some(self.volume * elapsed.as_secs_f64() / self.duration.as_secs_f64()),
+            false => None,
+        }
+    }
+
+    pub fn target(&self) -> f64 {
+        self.volume
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use chrono::Local;
+    use uuid::Uuid;
+
+    use super::{Alarm, AlarmSource, FadeIn};
+
+    #[test]
+    fn alarm_fade_in() {
+        let now = Local::now();
+        let alarm = Alarm {
+            at: now + chrono::Duration::minutes(30),
+            source: AlarmSource::Playlist(Uuid::new_v4()),
+            fade_in: Duration::from_secs(60),
+        };
+        assert!(!alarm.is_due(now));
+        assert!(alarm.is_due(now + chrono::Duration::minutes(30)));
+
+        let start = Instant::now();
+        let fade = FadeIn::new(start, alarm.fade_in, 0.8);
+        assert_eq!(fade.volume(start), Some(0.0));
+        assert_eq!(fade.volume(start + Duration::from_secs(30)), Some(0.4));
+        assert_eq!(fade.volume(start + Duration::from_secs(60)), None);
+        // Without a fade the alarm starts at full volume
+        assert_eq!(FadeIn::new(start, Duration::ZERO, 0.8).volume(start), None);
+    }
+}
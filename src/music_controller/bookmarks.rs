@@ -0,0 +1,108 @@
+//! Resume positions of audiobooks, which are saved often while they
+//! play and so are kept in their own file rather than the library
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// How close to the end a bookmark can be before the book counts as finished
+const FINISHED_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bookmarks {
+    pub positions: HashMap<Uuid, Duration>,
+}
+
+impl Bookmarks {
+    /// The location of the bookmarks file, which is stored in the state folder of the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.state_file("bookmarks.json")
+    }
+
+    /// The position to resume the song with the given [Uuid] from
+    pub fn get(&self, uuid: &Uuid) -> Option<Duration> {
+        self.positions.get(uuid).copied()
+    }
+
+    /// Save the position of a song. Positions near the end of the song
+    /// remove the bookmark instead, so it starts over next time.
+    ///
+    /// Returns `true` if the bookmarks changed.
+    pub fn set(&mut self, uuid: Uuid, position: Duration, duration: Option<Duration>) -> bool {
+        let finished = duration.is_some_and(|d| position + FINISHED_MARGIN >= d);
+        if finished || position.is_zero() {
+            return self.positions.remove(&uuid).is_some();
+        }
+        self.positions.insert(uuid, position) != Some(position)
+    }
+
+    pub fn remove(&mut self, uuid: &Uuid) -> Option<Duration> {
+        self.positions.remove(uuid)
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = path.to_path_buf();
+        writer.set_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&writer)?;
+        let bookmarks = serde_json::to_string_pretty(self)?;
+
+        file.write_all(bookmarks.as_bytes())?;
+        fs::rename(writer, path)?;
+        Ok(())
+    }
+
+    /// Read the bookmarks file, returning no bookmarks if it doesn't exist yet
+    pub fn read_file(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Bookmarks::default());
+        }
+
+        let mut file: File = File::open(path)?;
+        let mut bun: String = String::new();
+        file.read_to_string(&mut bun)?;
+        let bookmarks: Bookmarks = serde_json::from_str::<Bookmarks>(&bun)?;
+        Ok(bookmarks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::Bookmarks;
+
+    #[test]
+    fn audiobook_bookmarks() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("bookmarks.json");
+        let uuid = Uuid::new_v4();
+        let length = Some(Duration::from_secs(3600));
+
+        let mut bookmarks = Bookmarks::read_file(&path).unwrap();
+        assert!(bookmarks.set(uuid, Duration::from_secs(600), length));
+        assert!(!bookmarks.set(uuid, Duration::from_secs(600), length));
+        bookmarks.write_file(&path).unwrap();
+
+        let mut bookmarks = Bookmarks::read_file(&path).unwrap();
+        assert_eq!(bookmarks.get(&uuid), Some(Duration::from_secs(600)));
+
+        // Reaching the end starts the book over
+        assert!(bookmarks.set(uuid, Duration::from_secs(3590), length));
+        assert_eq!(bookmarks.get(&uuid), None);
+    }
+}
@@ -0,0 +1,1134 @@
+//! Casting playback to Chromecasts and DLNA/UPnP renderers on the local
+//! network
+//!
+//! Devices are found with [discover]. While casting, songs are served to
+//! the device by a [LibraryServer] so it can stream and seek within local
+//! files, and the [Controller](super::controller::Controller) sends its
+//! transport controls to the device through a [CastSession] instead of
+//! the local player.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, RwLock};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use native_tls::{TlsConnector, TlsStream};
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::music_server::http::{LibraryServer, ServerError};
+use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+
+/// How long [discover] looks for devices by default
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a device has to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const MEDIA_RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+const RENDERING_CONTROL: &str = "urn:schemas-upnp-org:service:RenderingControl:1";
+
+const MDNS_ADDRESS: &str = "224.0.0.251:5353";
+const GOOGLECAST_SERVICE: &str = "_googlecast._tcp.local";
+const CHROMECAST_PORT: u16 = 8009;
+
+/// The app on a Chromecast which plays media from a URL
+const DEFAULT_MEDIA_RECEIVER: &str = "CC1AD845";
+const NS_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const NS_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const NS_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const NS_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+const SENDER_ID: &str = "sender-dmp";
+const RECEIVER_ID: &str = "receiver-0";
+
+/// How often a Chromecast is pinged, it drops connections which go quiet
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the connection to a Chromecast waits for messages before
+/// sending the ones queued up
+const CONNECTION_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum CastError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("invalid response: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("{0}")]
+    Server(#[from] ServerError),
+    #[error("could not connect securely: {0}")]
+    Tls(String),
+    #[error("the device refused: {0}")]
+    Refused(String),
+    #[error("the device didn't answer in time")]
+    Timeout,
+    #[error("the connection to the device was closed")]
+    Disconnected,
+    #[error("nothing is loaded on the device")]
+    NothingLoaded,
+    #[error("can't be cast: {0}")]
+    Unsupported(String),
+}
+
+/// How a cast device is controlled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CastProtocol {
+    Chromecast,
+    /// A UPnP media renderer, with the URLs its services are controlled at
+    Dlna {
+        av_transport: String,
+        rendering_control: Option<String>,
+    },
+}
+
+/// A device on the network which playback can be cast to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CastDevice {
+    pub name: String,
+    /// Where the device is, for Chromecasts this is the port it's
+    /// controlled on
+    pub address: SocketAddr,
+    pub protocol: CastProtocol,
+}
+
+impl CastDevice {
+    /// Connect to the device to control it
+    pub fn connect(&self) -> Result<Box<dyn CastTarget>, CastError> {
+        Ok(match &self.protocol {
+            CastProtocol::Chromecast => Box::new(Chromecast::connect(self.address)?),
+            CastProtocol::Dlna { av_transport, rendering_control } => Box::new(DlnaRenderer {
+                av_transport: av_transport.clone(),
+                rendering_control: rendering_control.clone(),
+            }),
+        })
+    }
+}
+
+/// Something for a cast device to play, and what to show while it does
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CastMedia {
+    pub url: String,
+    pub mime: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub art: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+/// The transport controls of a cast device, which are the same for every
+/// kind of device
+pub trait CastTarget: Send {
+    /// Load `media` on the device and start playing it from `start`
+    fn load(&mut self, media: &CastMedia, start: Duration) -> Result<(), CastError>;
+
+    fn play(&mut self) -> Result<(), CastError>;
+
+    fn pause(&mut self) -> Result<(), CastError>;
+
+    fn stop(&mut self) -> Result<(), CastError>;
+
+    fn seek(&mut self, position: Duration) -> Result<(), CastError>;
+
+    /// Set the volume of the device, from `0` to `1`
+    fn set_volume(&mut self, volume: f64) -> Result<(), CastError>;
+
+    /// How far through the loaded media the device is
+    fn position(&mut self) -> Result<Option<Duration>, CastError>;
+}
+
+/// Look for Chromecasts and DLNA renderers on the local network until
+/// `timeout` has passed. Failures are printed and don't stop the other
+/// kind of device from being found.
+pub fn discover(timeout: Duration) -> Vec<CastDevice> {
+    let chromecasts = spawn(move || discover_chromecasts(timeout));
+    let mut devices = discover_dlna(timeout).unwrap_or_else(|error| {
+        println!("Cast: could not look for DLNA renderers: {}", error);
+        Vec::new()
+    });
+    match chromecasts.join() {
+        Ok(Ok(found)) => devices.extend(found),
+        Ok(Err(error)) => println!("Cast: could not look for Chromecasts: {}", error),
+        Err(_) => println!("Cast: looking for Chromecasts failed"),
+    }
+    devices
+}
+
+/// Pass every packet received on `socket` to `received` until `deadline`
+fn receive_until<F: FnMut(&[u8], SocketAddr)>(
+    socket: &UdpSocket,
+    deadline: Instant,
+    mut received: F,
+) -> io::Result<()> {
+    let mut buf = [0; 9000];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => received(&buf[..len], from),
+            Err(error) if is_timeout(&error) => return Ok(()),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Look for DLNA media renderers with SSDP, and read the description of
+/// each one found
+pub fn discover_dlna(timeout: Duration) -> Result<Vec<CastDevice>, CastError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+        SSDP_ADDRESS,
+        timeout.as_secs().clamp(1, 5),
+        MEDIA_RENDERER
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS)?;
+
+    let mut locations: Vec<String> = Vec::new();
+    receive_until(&socket, Instant::now() + timeout, |packet, _| {
+        if let Some(location) = ssdp_location(&String::from_utf8_lossy(packet)) {
+            if !locations.contains(&location) {
+                locations.push(location);
+            }
+        }
+    })?;
+
+    let mut devices = Vec::new();
+    for location in locations {
+        let description = attohttpc::get(&location)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text());
+        match description.map_err(CastError::from).and_then(|xml| parse_description(&xml, &location)) {
+            Ok(Some(device)) => devices.push(device),
+            Ok(None) => (),
+            Err(error) => println!("Cast: could not read the renderer at {}: {}", location, error),
+        }
+    }
+    Ok(devices)
+}
+
+/// The location of the device description in a response to an SSDP search
+fn ssdp_location(response: &str) -> Option<String> {
+    let mut lines = response.lines();
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Where a URL is, e.g. `http://192.168.1.20:49152` of
+/// `http://192.168.1.20:49152/description.xml`
+fn url_origin(url: &str) -> Option<&str> {
+    let after_scheme = url.find("://")? + 3;
+    let end = url[after_scheme..].find('/').map_or(url.len(), |end| after_scheme + end);
+    Some(&url[..end])
+}
+
+/// Resolve a URL from a device description, which can be relative
+fn resolve_url(base: &str, url: &str) -> String {
+    if url.contains("://") {
+        return url.to_string();
+    }
+    match url.strip_prefix('/') {
+        Some(path) => format!("{}/{}", url_origin(base).unwrap_or(base), path),
+        None => format!("{}/{}", base.rsplit_once('/').map_or(base, |(dir, _)| dir), url),
+    }
+}
+
+/// Read the renderer described by the XML at `location`, `None` if it
+/// can't be sent media
+fn parse_description(xml: &str, location: &str) -> Result<Option<CastDevice>, CastError> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+    let mut name = None;
+    let mut base = None;
+    let mut services = Vec::new();
+    let mut service: (Option<String>, Option<String>) = (None, None);
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => stack.push(String::from_utf8_lossy(e.local_name().as_ref()).to_string()),
+            Event::End(e) => {
+                stack.pop();
+                if e.local_name().as_ref() == b"service" {
+                    if let (Some(kind), Some(url)) = std::mem::take(&mut service) {
+                        services.push((kind, url));
+                    }
+                }
+            }
+            Event::Text(e) => {
+                let text = e.unescape()?.to_string();
+                match stack.last().map(|tag| tag.as_str()) {
+                    Some("friendlyName") if name.is_none() => name = Some(text),
+                    Some("URLBase") => base = Some(text),
+                    Some("serviceType") => service.0 = Some(text),
+                    Some("controlURL") => service.1 = Some(text),
+                    _ => (),
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    let base = base.unwrap_or_else(|| location.to_string());
+    let control = |kind: &str| {
+        services
+            .iter()
+            .find(|(service, _)| service == kind)
+            .map(|(_, url)| resolve_url(&base, url))
+    };
+    let Some(av_transport) = control(AV_TRANSPORT) else {
+        return Ok(None);
+    };
+    let Some(address) = url_origin(location)
+        .and_then(|origin| origin.split_once("://"))
+        .and_then(|(_, host)| host.parse::<SocketAddr>().ok().or_else(|| format!("{}:80", host).parse().ok()))
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(CastDevice {
+        name: name.unwrap_or_else(|| address.to_string()),
+        address,
+        protocol: CastProtocol::Dlna {
+            av_transport,
+            rendering_control: control(RENDERING_CONTROL),
+        },
+    }))
+}
+
+/// Look for Chromecasts with an mDNS query for the Google Cast service
+pub fn discover_chromecasts(timeout: Duration) -> Result<Vec<CastDevice>, CastError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&mdns_query(GOOGLECAST_SERVICE), MDNS_ADDRESS)?;
+
+    let mut devices: Vec<CastDevice> = Vec::new();
+    receive_until(&socket, Instant::now() + timeout, |packet, from| {
+        for device in parse_mdns_response(packet, from.ip()) {
+            if !devices.iter().any(|found| found.address == device.address) {
+                devices.push(device);
+            }
+        }
+    })?;
+    Ok(devices)
+}
+
+fn push_dns_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+/// A DNS query for the PTR records of `service`. Asked from a port other
+/// than 5353, responders answer straight back rather than to the group.
+fn mdns_query(service: &str) -> Vec<u8> {
+    // An ID of 0, no flags, and a single question
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    push_dns_name(&mut packet, service);
+    // PTR, in the IN class with the unicast response bit
+    packet.extend([0, 12, 0x80, 1]);
+    packet
+}
+
+/// Read a possibly compressed name from a DNS packet, returning it and
+/// where the data after it starts
+fn read_dns_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Limits how many pointers are followed, in case they loop
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+        } else if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).to_string());
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+/// The Chromecasts announced in an mDNS response sent from `from`
+fn parse_mdns_response(packet: &[u8], from: IpAddr) -> Vec<CastDevice> {
+    let records = parse_dns_records(packet).unwrap_or_default();
+    let instances = records.iter().filter_map(|(name, kind, data)| {
+        (*kind == 12 && name.eq_ignore_ascii_case(GOOGLECAST_SERVICE))
+            .then(|| read_dns_name(packet, *data).map(|(instance, _)| instance))
+            .flatten()
+    });
+
+    instances
+        .map(|instance| {
+            let record = |wanted: u16| {
+                records
+                    .iter()
+                    .find(|(name, kind, _)| *kind == wanted && name.eq_ignore_ascii_case(&instance))
+                    .map(|(_, _, data)| *data)
+            };
+            // SRV records start with a priority and weight before the port
+            let port = record(33)
+                .and_then(|data| packet.get(data + 4..data + 6))
+                .map_or(CHROMECAST_PORT, |port| u16::from_be_bytes([port[0], port[1]]));
+            let name = record(16)
+                .and_then(|data| txt_value(packet, data, "fn"))
+                .unwrap_or_else(|| instance.split('.').next().unwrap_or_default().to_string());
+            CastDevice {
+                name,
+                address: SocketAddr::new(from, port),
+                protocol: CastProtocol::Chromecast,
+            }
+        })
+        .collect()
+}
+
+/// Every record in the answer, authority and additional sections of a DNS
+/// packet, as its name, type and where its data starts
+fn parse_dns_records(packet: &[u8]) -> Option<Vec<(String, u16, usize)>> {
+    let count = |at: usize| Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]) as usize);
+    let questions = count(4)?;
+    let records = count(6)? + count(8)? + count(10)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_dns_name(packet, pos)?.1 + 4;
+    }
+    let mut found = Vec::new();
+    for _ in 0..records {
+        let (name, after) = read_dns_name(packet, pos)?;
+        let kind = count(after)? as u16;
+        let len = count(after + 8)?;
+        found.push((name, kind, after + 10));
+        pos = after + 10 + len;
+    }
+    Some(found)
+}
+
+/// The value of `key` in a TXT record, whose data is the length of the
+/// record followed by strings like `key=value`
+fn txt_value(packet: &[u8], data: usize, key: &str) -> Option<String> {
+    let len = u16::from_be_bytes([*packet.get(data - 2)?, *packet.get(data - 1)?]) as usize;
+    let mut entries = packet.get(data..data + len)?;
+    while let Some((&entry_len, rest)) = entries.split_first() {
+        let entry = String::from_utf8_lossy(rest.get(..entry_len as usize)?);
+        if let Some((name, value)) = entry.split_once('=') {
+            if name.eq_ignore_ascii_case(key) {
+                return Some(value.to_string());
+            }
+        }
+        entries = &rest[entry_len as usize..];
+    }
+    None
+}
+
+/// Format a position the way UPnP does, e.g. `0:03:25`
+fn upnp_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Parse a UPnP position, which renderers give as `NOT_IMPLEMENTED` if
+/// they don't know it
+fn parse_upnp_time(time: &str) -> Option<Duration> {
+    let mut parts = time.trim().split(':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    Some(Duration::from_secs(hours * 3600 + minutes * 60) + Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+/// The text of the first element called `element` in some XML
+fn xml_value(xml: &str, element: &str) -> Result<Option<String>, CastError> {
+    let mut reader = Reader::from_str(xml);
+    let mut buf = Vec::new();
+    let mut inside = false;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => inside = e.local_name().as_ref() == element.as_bytes(),
+            Event::Text(e) if inside => return Ok(Some(e.unescape()?.to_string())),
+            Event::End(_) => inside = false,
+            Event::Eof => return Ok(None),
+            _ => (),
+        }
+        buf.clear();
+    }
+}
+
+/// DIDL-Lite metadata for a track, which renderers show while playing it
+fn didl_metadata(media: &CastMedia) -> String {
+    let mut item = format!(
+        "<dc:title>{}</dc:title><upnp:class>object.item.audioItem.musicTrack</upnp:class>",
+        escape(media.title.as_deref().unwrap_or_default())
+    );
+    if let Some(artist) = &media.artist {
+        item.push_str(&format!("<upnp:artist>{}</upnp:artist>", escape(artist)));
+    }
+    if let Some(album) = &media.album {
+        item.push_str(&format!("<upnp:album>{}</upnp:album>", escape(album)));
+    }
+    if let Some(art) = &media.art {
+        item.push_str(&format!("<upnp:albumArtURI>{}</upnp:albumArtURI>", escape(art)));
+    }
+    let duration = media
+        .duration
+        .map(|duration| format!(" duration=\"{}\"", upnp_time(duration)))
+        .unwrap_or_default();
+    item.push_str(&format!(
+        "<res protocolInfo=\"http-get:*:{}:*\"{}>{}</res>",
+        escape(&media.mime),
+        duration,
+        escape(&media.url)
+    ));
+
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+        xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+        xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+        <item id=\"0\" parentID=\"-1\" restricted=\"1\">{}</item></DIDL-Lite>",
+        item
+    )
+}
+
+/// A DLNA media renderer, controlled with SOAP actions
+pub struct DlnaRenderer {
+    av_transport: String,
+    rendering_control: Option<String>,
+}
+
+impl DlnaRenderer {
+    fn action(&self, url: &str, service: &str, action: &str, arguments: &[(&str, &str)]) -> Result<String, CastError> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, escape(value)))
+            .collect();
+        let envelope = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+            <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+            action, service, arguments
+        );
+
+        let response = attohttpc::post(url)
+            .timeout(REQUEST_TIMEOUT)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{}\"", service, action))
+            .text(envelope)
+            .send()?;
+        let status = response.status();
+        let body = response.text()?;
+        if !status.is_success() {
+            let reason = xml_value(&body, "errorDescription")?.unwrap_or_else(|| status.to_string());
+            return Err(CastError::Refused(format!("{}: {}", action, reason)));
+        }
+        Ok(body)
+    }
+
+    fn transport(&self, action: &str, arguments: &[(&str, &str)]) -> Result<String, CastError> {
+        let mut with_instance = vec![("InstanceID", "0")];
+        with_instance.extend_from_slice(arguments);
+        self.action(&self.av_transport, AV_TRANSPORT, action, &with_instance)
+    }
+}
+
+impl CastTarget for DlnaRenderer {
+    fn load(&mut self, media: &CastMedia, start: Duration) -> Result<(), CastError> {
+        let metadata = didl_metadata(media);
+        self.transport("SetAVTransportURI", &[("CurrentURI", &media.url), ("CurrentURIMetaData", &metadata)])?;
+        self.play()?;
+        // Most renderers can only seek once they've started playing
+        if !start.is_zero() {
+            self.seek(start)?;
+        }
+        Ok(())
+    }
+
+    fn play(&mut self) -> Result<(), CastError> {
+        self.transport("Play", &[("Speed", "1")]).map(|_| ())
+    }
+
+    fn pause(&mut self) -> Result<(), CastError> {
+        self.transport("Pause", &[]).map(|_| ())
+    }
+
+    fn stop(&mut self) -> Result<(), CastError> {
+        self.transport("Stop", &[]).map(|_| ())
+    }
+
+    fn seek(&mut self, position: Duration) -> Result<(), CastError> {
+        self.transport("Seek", &[("Unit", "REL_TIME"), ("Target", &upnp_time(position))]).map(|_| ())
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), CastError> {
+        let Some(url) = &self.rendering_control else {
+            return Err(CastError::Unsupported(String::from("the renderer has no volume control")));
+        };
+        let volume = (volume.clamp(0.0, 1.0) * 100.0).round().to_string();
+        let arguments = [("InstanceID", "0"), ("Channel", "Master"), ("DesiredVolume", &volume)];
+        self.action(url, RENDERING_CONTROL, "SetVolume", &arguments).map(|_| ())
+    }
+
+    fn position(&mut self) -> Result<Option<Duration>, CastError> {
+        let info = self.transport("GetPositionInfo", &[])?;
+        Ok(xml_value(&info, "RelTime")?.as_deref().and_then(parse_upnp_time))
+    }
+}
+
+/// A message of the Cast V2 protocol, which are all JSON here
+#[derive(Debug, Clone, PartialEq)]
+struct CastMessage {
+    source: String,
+    destination: String,
+    namespace: String,
+    payload: String,
+}
+
+impl CastMessage {
+    fn new(destination: &str, namespace: &str, payload: &Value) -> Self {
+        CastMessage {
+            source: String::from(SENDER_ID),
+            destination: destination.to_string(),
+            namespace: namespace.to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    /// Encode the message as a `CastMessage` protobuf, after its length
+    fn encode(&self) -> Vec<u8> {
+        fn push_string(body: &mut Vec<u8>, field: u64, value: &str) {
+            leb128::write::unsigned(body, field << 3 | 2).unwrap();
+            leb128::write::unsigned(body, value.len() as u64).unwrap();
+            body.extend(value.as_bytes());
+        }
+
+        // Version 0 of the protocol, and a payload type of 0 for text
+        let mut body = vec![0x08, 0];
+        push_string(&mut body, 2, &self.source);
+        push_string(&mut body, 3, &self.destination);
+        push_string(&mut body, 4, &self.namespace);
+        body.extend([0x28, 0]);
+        push_string(&mut body, 6, &self.payload);
+
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    fn decode(mut body: &[u8]) -> Option<Self> {
+        let mut message = CastMessage {
+            source: String::new(),
+            destination: String::new(),
+            namespace: String::new(),
+            payload: String::new(),
+        };
+        while !body.is_empty() {
+            let key = leb128::read::unsigned(&mut body).ok()?;
+            match key & 7 {
+                0 => {
+                    leb128::read::unsigned(&mut body).ok()?;
+                }
+                2 => {
+                    let len = leb128::read::unsigned(&mut body).ok()? as usize;
+                    let value = String::from_utf8_lossy(body.get(..len)?).to_string();
+                    body = &body[len..];
+                    match key >> 3 {
+                        2 => message.source = value,
+                        3 => message.destination = value,
+                        4 => message.namespace = value,
+                        6 => message.payload = value,
+                        _ => (),
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(message)
+    }
+}
+
+/// A message for the connection to send, and where to send the response
+/// to it with a matching request ID
+struct Outgoing {
+    message: CastMessage,
+    reply: Option<(u64, Sender<Value>)>,
+}
+
+/// Send and receive the messages of a Chromecast connection until it's
+/// closed, or the [Chromecast] is dropped
+fn run_connection(mut stream: TlsStream<TcpStream>, outgoing: Receiver<Outgoing>) {
+    let mut pending: HashMap<u64, Sender<Value>> = HashMap::new();
+    let mut received = Vec::new();
+    let mut buf = [0; 4096];
+    let mut last_ping: Option<Instant> = None;
+    loop {
+        let mut frames = Vec::new();
+        loop {
+            match outgoing.try_recv() {
+                Ok(Outgoing { message, reply }) => {
+                    if let Some((id, reply)) = reply {
+                        pending.insert(id, reply);
+                    }
+                    frames.push(message.encode());
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if last_ping.is_none_or(|ping| ping.elapsed() >= HEARTBEAT_INTERVAL) {
+            frames.push(CastMessage::new(RECEIVER_ID, NS_HEARTBEAT, &json!({"type": "PING"})).encode());
+            last_ping = Some(Instant::now());
+        }
+        for frame in frames {
+            if stream.write_all(&frame).is_err() {
+                return;
+            }
+        }
+
+        match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => received.extend_from_slice(&buf[..len]),
+            Err(error) if is_timeout(&error) => continue,
+            Err(_) => return,
+        }
+
+        while received.len() >= 4 {
+            let len = u32::from_be_bytes([received[0], received[1], received[2], received[3]]) as usize;
+            if received.len() < 4 + len {
+                break;
+            }
+            let frame: Vec<u8> = received.drain(..4 + len).skip(4).collect();
+            let Some(message) = CastMessage::decode(&frame) else {
+                continue;
+            };
+            let Ok(payload) = serde_json::from_str::<Value>(&message.payload) else {
+                continue;
+            };
+
+            if message.namespace == NS_HEARTBEAT && payload["type"] == "PING" {
+                let pong = CastMessage::new(&message.source, NS_HEARTBEAT, &json!({"type": "PONG"}));
+                if stream.write_all(&pong.encode()).is_err() {
+                    return;
+                }
+            } else if let Some(reply) = payload["requestId"].as_u64().and_then(|id| pending.remove(&id)) {
+                let _ = reply.send(payload);
+            }
+        }
+    }
+}
+
+/// A Chromecast, playing media with its default media receiver app
+pub struct Chromecast {
+    outgoing: Sender<Outgoing>,
+    /// Where messages for the media receiver are sent, once it's launched
+    transport: String,
+    media_session: Option<u64>,
+    next_request: u64,
+}
+
+impl Chromecast {
+    /// Connect to the Chromecast at `address` and launch the media receiver
+    pub fn connect(address: SocketAddr) -> Result<Self, CastError> {
+        let tcp = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT)?;
+        tcp.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        // Chromecasts have self-signed certificates, so they can't be checked
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .map_err(|error| CastError::Tls(error.to_string()))?;
+        let stream = connector
+            .connect(&address.ip().to_string(), tcp)
+            .map_err(|error| CastError::Tls(error.to_string()))?;
+        stream.get_ref().set_read_timeout(Some(CONNECTION_POLL))?;
+
+        let (outgoing, receiver) = unbounded();
+        spawn(move || run_connection(stream, receiver));
+        let mut cast = Chromecast {
+            outgoing,
+            transport: String::new(),
+            media_session: None,
+            next_request: 0,
+        };
+
+        cast.send(RECEIVER_ID, NS_CONNECTION, json!({"type": "CONNECT"}))?;
+        let status = cast.request(RECEIVER_ID, NS_RECEIVER, json!({"type": "LAUNCH", "appId": DEFAULT_MEDIA_RECEIVER}))?;
+        cast.transport = status["status"]["applications"]
+            .as_array()
+            .and_then(|apps| apps.iter().find(|app| app["appId"] == DEFAULT_MEDIA_RECEIVER))
+            .and_then(|app| app["transportId"].as_str())
+            .ok_or_else(|| CastError::Refused(String::from("the media receiver didn't start")))?
+            .to_string();
+        cast.send(&cast.transport.clone(), NS_CONNECTION, json!({"type": "CONNECT"}))?;
+        Ok(cast)
+    }
+
+    fn send(&self, destination: &str, namespace: &str, payload: Value) -> Result<(), CastError> {
+        let message = CastMessage::new(destination, namespace, &payload);
+        self.outgoing
+            .send(Outgoing { message, reply: None })
+            .map_err(|_| CastError::Disconnected)
+    }
+
+    /// Send a message and wait for the response to it
+    fn request(&mut self, destination: &str, namespace: &str, mut payload: Value) -> Result<Value, CastError> {
+        self.next_request += 1;
+        payload["requestId"] = json!(self.next_request);
+        let (reply, response) = bounded(1);
+        let message = CastMessage::new(destination, namespace, &payload);
+        self.outgoing
+            .send(Outgoing { message, reply: Some((self.next_request, reply)) })
+            .map_err(|_| CastError::Disconnected)?;
+
+        let response = match response.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => return Err(CastError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => return Err(CastError::Disconnected),
+        };
+        match response["type"].as_str() {
+            Some(kind @ ("LOAD_FAILED" | "LOAD_CANCELLED" | "INVALID_REQUEST" | "INVALID_PLAYER_STATE" | "LAUNCH_ERROR")) => {
+                let reason = response["reason"].as_str().unwrap_or_default();
+                Err(CastError::Refused(format!("{} {}", kind, reason).trim().to_string()))
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Send a command to the loaded media, keeping track of its session
+    fn media(&mut self, kind: &str, mut payload: Value) -> Result<Value, CastError> {
+        let session = self.media_session.ok_or(CastError::NothingLoaded)?;
+        payload["type"] = json!(kind);
+        payload["mediaSessionId"] = json!(session);
+        let transport = self.transport.clone();
+        let status = self.request(&transport, NS_MEDIA, payload)?;
+        if let Some(session) = status["status"][0]["mediaSessionId"].as_u64() {
+            self.media_session = Some(session);
+        }
+        Ok(status)
+    }
+}
+
+impl CastTarget for Chromecast {
+    fn load(&mut self, media: &CastMedia, start: Duration) -> Result<(), CastError> {
+        let mut metadata = json!({
+            "metadataType": 3,
+            "title": media.title,
+            "artist": media.artist,
+            "albumName": media.album,
+        });
+        if let Some(art) = &media.art {
+            metadata["images"] = json!([{ "url": art }]);
+        }
+        let mut details = json!({
+            "contentId": media.url,
+            "contentType": media.mime,
+            "streamType": "BUFFERED",
+            "metadata": metadata,
+        });
+        if let Some(duration) = media.duration {
+            details["duration"] = json!(duration.as_secs_f64());
+        }
+
+        let transport = self.transport.clone();
+        let payload = json!({
+            "type": "LOAD",
+            "media": details,
+            "autoplay": true,
+            "currentTime": start.as_secs_f64(),
+        });
+        let status = self.request(&transport, NS_MEDIA, payload)?;
+        self.media_session = status["status"][0]["mediaSessionId"].as_u64();
+        self.media_session.map(|_| ()).ok_or(CastError::NothingLoaded)
+    }
+
+    fn play(&mut self) -> Result<(), CastError> {
+        self.media("PLAY", json!({})).map(|_| ())
+    }
+
+    fn pause(&mut self) -> Result<(), CastError> {
+        self.media("PAUSE", json!({})).map(|_| ())
+    }
+
+    fn stop(&mut self) -> Result<(), CastError> {
+        let stopped = self.media("STOP", json!({})).map(|_| ());
+        self.media_session = None;
+        stopped
+    }
+
+    fn seek(&mut self, position: Duration) -> Result<(), CastError> {
+        self.media("SEEK", json!({ "currentTime": position.as_secs_f64() })).map(|_| ())
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), CastError> {
+        let payload = json!({ "type": "SET_VOLUME", "volume": { "level": volume.clamp(0.0, 1.0) } });
+        self.request(RECEIVER_ID, NS_RECEIVER, payload).map(|_| ())
+    }
+
+    fn position(&mut self) -> Result<Option<Duration>, CastError> {
+        let status = self.media("GET_STATUS", json!({}))?;
+        Ok(status["status"][0]["currentTime"]
+            .as_f64()
+            .and_then(|time| Duration::try_from_secs_f64(time).ok()))
+    }
+}
+
+/// Playback redirected to a cast device, along with the server it streams
+/// songs in the library from
+pub struct CastSession {
+    device: CastDevice,
+    target: Box<dyn CastTarget>,
+    server: LibraryServer,
+    /// The address the device reaches this machine at, which isn't known
+    /// to the server as it listens on every interface
+    host: SocketAddr,
+    /// What's loaded on the device
+    loaded: Option<URI>,
+    /// Where the loaded track starts in the file served, for cue tracks
+    offset: Duration,
+    paused: bool,
+}
+
+impl CastSession {
+    /// Connect to `device`, and start serving `library` to it
+    pub fn start(device: CastDevice, library: Arc<RwLock<MusicLibrary>>) -> Result<Self, CastError> {
+        // Connecting a UDP socket sends nothing, but picks the interface
+        // which the device is reached through
+        let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        probe.connect(device.address)?;
+        let ip = probe.local_addr()?.ip();
+
+        let server = LibraryServer::bind((Ipv4Addr::UNSPECIFIED, 0), library)?;
+        let port = server.address().map_or(0, |address| address.port());
+        let target = device.connect()?;
+        server.start();
+
+        Ok(CastSession {
+            device,
+            target,
+            server,
+            host: SocketAddr::new(ip, port),
+            loaded: None,
+            offset: Duration::ZERO,
+            paused: true,
+        })
+    }
+
+    pub fn device(&self) -> &CastDevice {
+        &self.device
+    }
+
+    /// What's loaded on the device, if anything
+    pub fn loaded(&self) -> Option<&URI> {
+        self.loaded.as_ref()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Load `uri` on the device and play it from `start`. Local files
+    /// are served from the library, so `song` is needed for them.
+    pub fn load(&mut self, uri: &URI, song: Option<&Song>, start: Duration) -> Result<(), CastError> {
+        let served = |song: Option<&Song>| match song {
+            Some(song) => Ok(format!("http://{}/track/{}", self.host, song.uuid)),
+            None => Err(CastError::Unsupported(String::from("only files in the library can be served"))),
+        };
+        let (url, offset) = match uri {
+            URI::Local(_) => (served(song)?, Duration::ZERO),
+            URI::Cue { start, .. } => (served(song)?, *start),
+            URI::Remote(_, url) => (url.clone(), Duration::ZERO),
+        };
+
+        let media = CastMedia {
+            url,
+            mime: song
+                .and_then(|song| song.format)
+                .map_or_else(|| String::from("audio/mpeg"), |format| format.media_type().to_string()),
+            title: song.and_then(|song| song.get_tag(&Tag::Title).cloned()),
+            artist: song.and_then(|song| song.get_tag(&Tag::Artist).cloned()),
+            album: song.and_then(|song| song.get_tag(&Tag::Album).cloned()),
+            art: song
+                .filter(|song| !song.album_art.is_empty())
+                .map(|song| format!("http://{}/art/{}/0", self.host, song.uuid)),
+            duration: song.map(|song| song.duration),
+        };
+        self.target.load(&media, offset + start)?;
+        self.loaded = Some(uri.clone());
+        self.offset = offset;
+        self.paused = false;
+        Ok(())
+    }
+
+    pub fn play(&mut self) -> Result<(), CastError> {
+        self.target.play()?;
+        self.paused = false;
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<(), CastError> {
+        self.target.pause()?;
+        self.paused = true;
+        Ok(())
+    }
+
+    /// Stop playing on the device, which is left connected
+    pub fn stop(&mut self) -> Result<(), CastError> {
+        self.loaded = None;
+        self.paused = true;
+        self.target.stop()
+    }
+
+    pub fn seek(&mut self, position: Duration) -> Result<(), CastError> {
+        self.target.seek(self.offset + position)
+    }
+
+    pub fn set_volume(&mut self, volume: f64) -> Result<(), CastError> {
+        self.target.set_volume(volume)
+    }
+
+    /// How far through the loaded track the device is
+    pub fn position(&mut self) -> Result<Option<Duration>, CastError> {
+        Ok(self.target.position()?.map(|position| position.saturating_sub(self.offset)))
+    }
+}
+
+impl Drop for CastSession {
+    fn drop(&mut self) {
+        self.server.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::{
+        mdns_query, parse_description, parse_mdns_response, parse_upnp_time, push_dns_name, ssdp_location, upnp_time,
+        CastDevice, CastMessage, CastProtocol, NS_MEDIA,
+    };
+
+    #[test]
+    fn dlna_discovery() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\nLocation: http://192.168.1.20:49152/description.xml\r\nST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\r\n";
+        let location = ssdp_location(response).unwrap();
+        assert_eq!(location, "http://192.168.1.20:49152/description.xml");
+        assert_eq!(ssdp_location("NOTIFY * HTTP/1.1\r\nLOCATION: http://a/\r\n"), None);
+
+        let description = r#"<?xml version="1.0"?>
+            <root xmlns="urn:schemas-upnp-org:device-1-0">
+              <device>
+                <friendlyName>Living Room Speaker</friendlyName>
+                <serviceList>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>
+                    <controlURL>/upnp/control/AVTransport1</controlURL>
+                  </service>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>
+                    <controlURL>control/RenderingControl1</controlURL>
+                  </service>
+                </serviceList>
+              </device>
+            </root>"#;
+        let device = parse_description(description, &location).unwrap().unwrap();
+        assert_eq!(
+            device,
+            CastDevice {
+                name: String::from("Living Room Speaker"),
+                address: "192.168.1.20:49152".parse().unwrap(),
+                protocol: CastProtocol::Dlna {
+                    av_transport: String::from("http://192.168.1.20:49152/upnp/control/AVTransport1"),
+                    rendering_control: Some(String::from("http://192.168.1.20:49152/control/RenderingControl1")),
+                },
+            }
+        );
+
+        // Media servers and other devices without a transport are skipped
+        let server = "<root><device><friendlyName>NAS</friendlyName></device></root>";
+        assert_eq!(parse_description(server, &location).unwrap(), None);
+
+        assert_eq!(upnp_time(Duration::from_secs(3725)), "1:02:05");
+        assert_eq!(parse_upnp_time("0:03:25.500"), Some(Duration::from_millis(205_500)));
+        assert_eq!(parse_upnp_time("NOT_IMPLEMENTED"), None);
+    }
+
+    #[test]
+    fn chromecast_discovery() {
+        let query = mdns_query("_googlecast._tcp.local");
+        assert_eq!(&query[4..6], &[0, 1]);
+
+        // A response with the PTR record, then the SRV and TXT records of
+        // the instance in the additional section
+        let instance = "Chromecast-1234._googlecast._tcp.local";
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        push_dns_name(&mut packet, "_googlecast._tcp.local");
+        let mut ptr = Vec::new();
+        push_dns_name(&mut ptr, instance);
+        packet.extend([0, 12, 0, 1, 0, 0, 0, 120, 0, ptr.len() as u8]);
+        packet.extend(ptr);
+
+        push_dns_name(&mut packet, instance);
+        let mut srv = vec![0, 0, 0, 0, 0x1F, 0x49];
+        push_dns_name(&mut srv, "1234.local");
+        packet.extend([0, 33, 0x80, 1, 0, 0, 0, 120, 0, srv.len() as u8]);
+        packet.extend(srv);
+
+        push_dns_name(&mut packet, instance);
+        let mut txt = Vec::new();
+        for entry in ["id=1234", "fn=Kitchen TV", "md=Chromecast"] {
+            txt.push(entry.len() as u8);
+            txt.extend(entry.as_bytes());
+        }
+        packet.extend([0, 16, 0x80, 1, 0, 0, 0, 120, 0, txt.len() as u8]);
+        packet.extend(txt);
+
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 30));
+        assert_eq!(
+            parse_mdns_response(&packet, from),
+            vec![CastDevice {
+                name: String::from("Kitchen TV"),
+                address: SocketAddr::new(from, 8009),
+                protocol: CastProtocol::Chromecast,
+            }]
+        );
+        assert!(parse_mdns_response(&packet[..20], from).is_empty());
+
+        let message = CastMessage::new("web-5", NS_MEDIA, &json!({"type": "PLAY", "requestId": 3}));
+        let frame = message.encode();
+        assert_eq!(u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize, frame.len() - 4);
+        assert_eq!(CastMessage::decode(&frame[4..]), Some(message));
+    }
+}
@@ -2,33 +2,188 @@
 //! player. It manages queues, playback, library access, and
 //! other functions
 
+use chrono::{DateTime, Local, Utc};
 use crossbeam_channel;
 use crossbeam_channel::{Receiver, Sender};
 use kushi::QueueError;
 use kushi::{Queue, QueueItemType};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread::spawn;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{bounded, select, unbounded};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use uuid::Uuid;
 
-use crate::config::ConfigError;
-use crate::music_player::player::{Player, PlayerCommand, PlayerError};
+use crate::config::{ConfigError, ConfigEvent};
+use crate::music_player::player::{Player, PlayerCommand, PlayerError, Visualizer, POSITION_POLL_INTERVAL};
+use crate::music_player::probe::probe;
+use crate::music_storage::cache::Caches;
+use crate::music_storage::disk_space::DiskSpaceError;
+use crate::music_storage::library::{DoNotTrack, Song, URI};
+use crate::music_storage::lyrics::{LyricLine, Lyrics};
+use crate::music_storage::offline::{self, item_songs, DownloadState, OfflineCopies, OfflineEvent, OfflineItem};
+use crate::music_storage::path_remap::RemapRule;
+use crate::music_storage::podcast::{download_to, fetch_feed, Podcast, PodcastError, Podcasts};
+use crate::music_storage::remote::{self, PlaybackReport, RemoteLibrary};
 use crate::{
     config::Config, music_storage::library::MusicLibrary,
 };
 
-use super::queue::{QueueAlbum, QueueSong};
+use super::alarm::{Alarm, AlarmEvent, AlarmSource, FadeIn};
+use super::bookmarks::Bookmarks;
+use super::cast::{CastDevice, CastError, CastSession};
+use super::discord::publish_presence;
+use super::events::{ControllerEvent, EventBus, POSITION_TICK_INTERVAL};
+use super::exclusions::{Exclusion, Exclusions};
+use super::history::{History, HistoryEntry};
+use super::idle::{IdleEvent, IdleTimer};
+use super::ignore::ConfigIgnore;
+use super::modes::{random_seed, shuffled_order, PlaybackModes, RepeatMode};
+use super::notifications::{notify_tracks, Notifier, SystemNotifier};
+use super::power::{on_battery, PowerEvent, PowerMode};
+use super::private::{PrivateSession, PrivateSessionEvent};
+use super::profiles::{AudioProfile, ProfileEvent};
+use super::quarantine::{Quarantine, QuarantinedSong, RetestReport};
+use super::queue::{apply, fair_order, is_order_of, pick_distinct, PlayQueue, QueueAlbum, QueueEvent, QueueInvariants, QueueOp, QueueSong, QueueSource, QueueViolation};
+use super::replaygain::{set_player_gain, AppliedGain, ReplayGain};
+use super::session::Session;
+use super::skip_silence::TrackKind;
+use super::sleep::{SleepAction, SleepEvent, SleepMode, SleepTimer};
+use super::snapshot::{NowPlaying, StateSnapshot};
+use super::transition::{transition_lead, TransitionEvent};
 
+/// How often the library roots are checked for being unplugged or remounted
+const ROOT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the position of a playing podcast episode or audiobook is saved
+const POSITION_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often remote libraries are told how far into a song playback is
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many queue events are kept for listeners before new ones are dropped
+const QUEUE_EVENT_BUFFER: usize = 64;
+
+/// How often unattended playback is checked for
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many idle events are kept for listeners before new ones are dropped
+const IDLE_EVENT_BUFFER: usize = 8;
+
+/// How often the scheduled audio profile is checked for changes
+const PROFILE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many profile events are kept for listeners before new ones are dropped
+const PROFILE_EVENT_BUFFER: usize = 8;
+
+/// How many private session events are kept for listeners before new ones are dropped
+const PRIVATE_EVENT_BUFFER: usize = 8;
+
+/// How often the config file is checked for changes made outside of the controller
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many config events are kept for listeners before new ones are dropped
+const CONFIG_EVENT_BUFFER: usize = 16;
+
+/// How often the computer is checked for running on battery
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many power events are kept for listeners before new ones are dropped
+const POWER_EVENT_BUFFER: usize = 8;
+
+/// How many transition events are kept for listeners before new ones are dropped
+const TRANSITION_EVENT_BUFFER: usize = 8;
+
+/// How often the sleep timer is checked, often enough for its fade to be smooth
+const SLEEP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many sleep timer events are kept for listeners before new ones are dropped
+const SLEEP_EVENT_BUFFER: usize = 8;
+
+/// How often a scheduled alarm is checked for, often enough for its fade to be smooth
+const ALARM_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many alarm events are kept for listeners before new ones are dropped
+const ALARM_EVENT_BUFFER: usize = 8;
+
+/// How many download events are kept for listeners before new ones are dropped
+const OFFLINE_EVENT_BUFFER: usize = 64;
+
+/// How long the session can go without being saved while saving energy
+const SESSION_BATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many of the most recent plays are included in a [StateSnapshot]
+const HISTORY_TAIL: usize = 20;
 
 pub struct Controller<P: Player + Send + Sync> {
-    pub queue: Arc<RwLock<Queue<QueueSong, QueueAlbum>>>,
-    pub config: Arc<RwLock<Config>>,
-    pub library: MusicLibrary,
-    pub player: Arc<Mutex<P>>,
+    pub(crate) queue: Arc<RwLock<Queue<QueueSong, QueueAlbum>>>,
+    pub(crate) config: Arc<RwLock<Config>>,
+    pub(crate) library: Arc<RwLock<MusicLibrary>>,
+    pub(crate) player: Arc<Mutex<P>>,
+    pub(crate) caches: Arc<Caches>,
+    pub(crate) history: History,
+    pub(crate) remotes: Arc<Vec<Box<dyn RemoteLibrary>>>,
+    pub(crate) podcasts: Arc<RwLock<Podcasts>>,
+    pub(crate) bookmarks: Arc<RwLock<Bookmarks>>,
+    pub queue_events: Receiver<QueueEvent>,
+    queue_tx: Sender<QueueEvent>,
+    /// Warnings before unattended playback is paused, see [Controller::still_listening]
+    pub idle_events: Receiver<IdleEvent>,
+    idle: Arc<Mutex<IdleTimer>>,
+    /// Changes between the scheduled audio profiles
+    pub profile_events: Receiver<ProfileEvent>,
+    active_profile: Arc<RwLock<Option<String>>>,
+    /// The ReplayGain applied to the current song
+    gain: Arc<RwLock<Option<AppliedGain>>>,
+    /// Private sessions starting and ending, see [Controller::set_private_session]
+    pub private_events: Receiver<PrivateSessionEvent>,
+    private_tx: Sender<PrivateSessionEvent>,
+    private_session: Arc<PrivateSession>,
+    /// Always locked before the queue when both are, and never while
+    /// holding the queue
+    modes: Arc<RwLock<PlaybackModes>>,
+    /// Settings which changed and were applied while running, see [Controller::reload_config]
+    pub config_events: Receiver<ConfigEvent>,
+    config_tx: Sender<ConfigEvent>,
+    /// Switches between saving energy and not, see [Controller::set_power_mode]
+    pub power_events: Receiver<PowerEvent>,
+    power_tx: Sender<PowerEvent>,
+    power: Arc<RwLock<PowerMode>>,
+    /// Songs about to end, sent once per song, see [transition](super::transition)
+    pub transition_events: Receiver<TransitionEvent>,
+    transition_tx: Sender<TransitionEvent>,
+    /// The power mode picked by hand, rather than from the battery
+    manual_power: Arc<RwLock<Option<PowerMode>>>,
+    /// Checked after every change to the queue, see [Controller::check_queue_invariants]
+    invariants: Option<QueueInvariants>,
+    /// Events for any number of frontends, see [Controller::subscribe]
+    events: Arc<EventBus>,
+    /// The sleep timer fading out and stopping, see [Controller::set_sleep_timer]
+    pub sleep_events: Receiver<SleepEvent>,
+    sleep_tx: Sender<SleepEvent>,
+    sleep_timer: Arc<Mutex<Option<SleepTimer>>>,
+    /// Scheduled playback starting, see [Controller::schedule_play]
+    pub alarm_events: Receiver<AlarmEvent>,
+    alarm_tx: Sender<AlarmEvent>,
+    alarm: Arc<Mutex<Option<Alarm>>>,
+    /// Songs downloaded for offline playback, see [Controller::download_offline]
+    pub offline_events: Receiver<OfflineEvent>,
+    offline_tx: Sender<OfflineEvent>,
+    /// Left out of shuffle and auto-DJ for now, see [Controller::exclude]
+    exclusions: Arc<Mutex<Exclusions>>,
+    /// Songs which failed to play, see [Controller::retest_quarantine]
+    quarantine: Arc<RwLock<Quarantine>>,
+    /// Playback redirected to a cast device, see [Controller::cast_to]
+    cast: Arc<Mutex<Option<CastSession>>>,
+    /// Shows track changes, see [Controller::set_notifier]
+    notifier: Arc<Mutex<Box<dyn Notifier>>>,
+    /// The library URI of the current song, the player only knows where it streams from
+    playing: Arc<RwLock<Option<URI>>>,
 }
 
 #[derive(Error, Debug)]
@@ -39,10 +194,22 @@ pub enum ControllerError {
     PlayerError(#[from] PlayerError),
     #[error("{0:?}")]
     ConfigError(#[from] ConfigError),
+    #[error("{0:?}")]
+    IoError(#[from] std::io::Error),
+    #[error("{0}")]
+    RemoteError(String),
+    #[error("{0:?}")]
+    PodcastError(#[from] PodcastError),
+    #[error("{0:?}")]
+    QueueInvariant(Vec<QueueViolation>),
+    #[error("{0}")]
+    CastError(#[from] CastError),
+    #[error("{0}")]
+    LibraryError(String),
 }
 
 // TODO: move this to a different location to be used elsewhere
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum PlayerLocation {
     Test,
@@ -95,7 +262,24 @@ impl<P: Player + Send + Sync + Sized + 'static> Controller<P> {
         let config = Config::read_file(config_path)?;
         let uuid = config.libraries.get_default()?.uuid;
 
-        let library = MusicLibrary::init(config.libraries.get_default()?.path.clone(), uuid)?;
+        let mut library = MusicLibrary::init(config.libraries.get_default()?.path.clone(), uuid)?;
+        library.apply_remap(&config.path_remap);
+        library.refresh_offline(&config.libraries.get_default()?.roots);
+        let caches = Arc::new(Caches::open(&config.caches)?);
+        caches.set_reserve(config.disk.reserve);
+        let session_path = Session::path(&config);
+        let history = History::new(History::path(&config));
+        // Songs which were downloaded are played from the cache before trying the servers
+        let mut remotes = config.connections.remote_libraries();
+        remotes.insert(0, Box::new(OfflineCopies::new(caches.clone())));
+        let remotes = Arc::new(remotes);
+        let podcasts_path = Podcasts::path(&config);
+        let podcasts = Arc::new(RwLock::new(Podcasts::read_file(&podcasts_path)?));
+        let bookmarks_path = Bookmarks::path(&config);
+        let bookmarks = Arc::new(RwLock::new(Bookmarks::read_file(&bookmarks_path)?));
+        let quarantine_path = Quarantine::path(&config);
+        let quarantine = Arc::new(RwLock::new(Quarantine::read_file(&quarantine_path)?));
+        let output = config.output.clone();
         let config_ = Arc::new(RwLock::from(config));
 
 
@@ -106,41 +290,270 @@ impl<P: Player + Send + Sync + Sized + 'static> Controller<P> {
             shuffle: None
         };
 
+        let (queue_tx, queue_events) = bounded(QUEUE_EVENT_BUFFER);
+        let (idle_tx, idle_events) = bounded(IDLE_EVENT_BUFFER);
+        let (profile_tx, profile_events) = bounded(PROFILE_EVENT_BUFFER);
+        let (private_tx, private_events) = bounded(PRIVATE_EVENT_BUFFER);
+        let (config_tx, config_events) = bounded(CONFIG_EVENT_BUFFER);
+        let (power_tx, power_events) = bounded(POWER_EVENT_BUFFER);
+        let (transition_tx, transition_events) = bounded(TRANSITION_EVENT_BUFFER);
+        let (sleep_tx, sleep_events) = bounded(SLEEP_EVENT_BUFFER);
+        let (alarm_tx, alarm_events) = bounded(ALARM_EVENT_BUFFER);
+        let (offline_tx, offline_events) = bounded(OFFLINE_EVENT_BUFFER);
         let controller = Controller {
             queue: Arc::new(RwLock::from(queue)),
             config: config_.clone(),
-            library,
-            player: Arc::new(Mutex::new(P::new()?)),
+            library: Arc::new(RwLock::new(library)),
+            player: Arc::new(Mutex::new(P::with_output(output)?)),
+            caches,
+            history: history.clone(),
+            remotes: remotes.clone(),
+            podcasts: podcasts.clone(),
+            bookmarks: bookmarks.clone(),
+            queue_events,
+            queue_tx: queue_tx.clone(),
+            idle_events,
+            idle: Arc::new(Mutex::new(IdleTimer::default())),
+            profile_events,
+            active_profile: Arc::new(RwLock::new(None)),
+            gain: Arc::new(RwLock::new(None)),
+            private_events,
+            private_tx,
+            private_session: Arc::new(PrivateSession::default()),
+            modes: Arc::new(RwLock::new(PlaybackModes::default())),
+            config_events,
+            config_tx,
+            power_events,
+            power_tx,
+            power: Arc::new(RwLock::new(PowerMode::Normal)),
+            transition_events,
+            transition_tx,
+            manual_power: Arc::new(RwLock::new(None)),
+            invariants: None,
+            events: Arc::new(EventBus::default()),
+            sleep_events,
+            sleep_tx,
+            sleep_timer: Arc::new(Mutex::new(None)),
+            alarm_events,
+            alarm_tx,
+            alarm: Arc::new(Mutex::new(None)),
+            offline_events,
+            offline_tx,
+            exclusions: Arc::new(Mutex::new(Exclusions::default())),
+            quarantine: quarantine.clone(),
+            cast: Arc::new(Mutex::new(None)),
+            notifier: Arc::new(Mutex::new(Box::new(SystemNotifier))),
+            playing: Arc::new(RwLock::new(None)),
         };
 
 
+        if let Some(cap) = config_.read().unwrap().volume_cap {
+            controller.player.lock().unwrap().set_volume_cap(cap);
+        }
+        controller.player.lock().unwrap().set_volume_curve(config_.read().unwrap().volume_curve);
+        controller.player.lock().unwrap().set_pause_fade(config_.read().unwrap().pause_fade);
+        controller.player.lock().unwrap().set_seek_mode(config_.read().unwrap().seek_mode);
+        controller.player.lock().unwrap().set_latency_offset(config_.read().unwrap().latency_offset);
+        set_channels(&mut *controller.player.lock().unwrap(), &config_.read().unwrap());
+        if config_.read().unwrap().filter_chain.is_some() {
+            set_filter_chain(&mut *controller.player.lock().unwrap(), &config_.read().unwrap());
+        }
+        set_visualizer(&mut *controller.player.lock().unwrap(), &config_.read().unwrap(), PowerMode::Normal);
+        set_transition_lead(&mut *controller.player.lock().unwrap(), &config_.read().unwrap(), &controller.modes.read().unwrap());
+
         let player = controller.player.clone();
         let queue = controller.queue.clone();
+        let library = controller.library.clone();
+        let modes = controller.modes.clone();
+        let config = config_.clone();
+        let gain = controller.gain.clone();
+        let private_session = controller.private_session.clone();
+        let power = controller.power.clone();
+        let transition_tx = controller.transition_tx.clone();
+        let events = controller.events.clone();
+        let playing = controller.playing.clone();
+        let messages = controller.player.lock().unwrap().message_channel().clone();
+        let (loaded_tx, loaded) = unbounded::<LoadedTrack>();
         let controller_thread = spawn(move || {
+            let mut session_saved = Instant::now();
             loop {
-                let signal = { player.lock().unwrap().message_channel().recv().unwrap() };
+                let signal = select! {
+                    recv(messages) -> signal => signal.unwrap(),
+                    recv(loaded) -> track => {
+                        let LoadedTrack { uuid, uri, resume, replay_gain, scrobble, result } = track.unwrap();
+                        // Read before the player is locked, see `Controller::modes`
+                        let current_modes = *modes.read().unwrap();
+                        match result {
+                            Ok(()) => {
+                                *gain.write().unwrap() = set_player_gain(
+                                    &mut *player.lock().unwrap(),
+                                    Some(replay_gain),
+                                    current_modes.normalization,
+                                    &config.read().unwrap().replay_gain,
+                                );
+
+                                // Audiobooks carry on from their bookmark
+                                if let Some(position) = resume.and_then(|r| chrono::Duration::from_std(r).ok()) {
+                                    if let Err(error) = player.lock().unwrap().seek_to(position) {
+                                        println!("Failed to resume the audiobook: {}", error);
+                                    }
+                                }
+                                if scrobble {
+                                    remote::report_playback(&remotes, &uri, PlaybackReport::Started, Duration::ZERO);
+                                }
+                                // Profiles may have changed the crossfade since the last song
+                                set_transition_lead(&mut *player.lock().unwrap(), &config.read().unwrap(), &current_modes);
+                                events.publish(ControllerEvent::TrackChanged { uuid: Some(uuid), uri: uri.clone() });
+                                *playing.write().unwrap() = Some(uri);
+                                update_quarantine(&quarantine, &quarantine_path, uuid, Ok(()));
+                            }
+                            Err((step, error)) => {
+                                println!("Failed to {} the next song: {}", step, error);
+                                events.publish(ControllerEvent::Error(format!("Failed to {} the next song: {}", step, error)));
+                                update_quarantine(&quarantine, &quarantine_path, uuid, Err(error));
+                            }
+                        }
+
+                        // While saving energy the session is saved less often, it's
+                        // still saved when the controller is dropped
+                        if *power.read().unwrap() == PowerMode::Normal || session_saved.elapsed() >= SESSION_BATCH_INTERVAL {
+                            let session = Session::capture(&queue.read().unwrap(), &*player.lock().unwrap(), &current_modes);
+                            if let Err(error) = session.write_file(&session_path) {
+                                println!("Failed to save session: {}", error);
+                            }
+                            // Along with the plays and skips counted since
+                            if let Err(error) = save_library(&library, &config) {
+                                println!("Failed to save the library: {}", error);
+                            }
+                            session_saved = Instant::now();
+                        }
+                        continue;
+                    }
+                };
                 match signal {
                     PlayerCommand::AboutToFinish => {
                         println!("Switching songs!");
 
-                        let mut queue = queue.write().unwrap();
+                        // The current song is about to finish, so count it as listened to
+                        let (source, stream, listened) = {
+                            let player = player.lock().unwrap();
+                            let listened = player.position().and_then(|pos| pos.to_std().ok());
+                            (playing.write().unwrap().take().or_else(|| player.source().clone()), player.source().clone(), listened)
+                        };
 
-                        let uri = queue
-                                .next()
-                                .unwrap()
-                                .clone();
+                        // Podcast episodes aren't in the library, so they only need marking as played
+                        if let Some(uri) = &stream {
+                            let mut podcasts = podcasts.write().unwrap();
+                            if let Some(episode) = podcasts.episode_at_mut(uri) {
+                                episode.set_played(true);
+                                if let Err(error) = podcasts.write_file(&podcasts_path) {
+                                    println!("Failed to save podcasts: {}", error);
+                                }
+                            }
+                        }
+                        if let (Some(uri), Some(listened)) = (&source, listened) {
+                            let ignore = config.read().unwrap().ignore.clone();
+                            let Listen { uuid, scrobble, .. } =
+                                record_listen(&library, &history, &ignore, private_session.is_active(), uri, listened);
+                            if scrobble {
+                                remote::report_playback(
+                                    &remotes,
+                                    uri,
+                                    PlaybackReport::Stopped { finished: true },
+                                    listened,
+                                );
+                            }
+                            // A finished audiobook starts over next time
+                            if let Some(uuid) = uuid {
+                                let mut bookmarks = bookmarks.write().unwrap();
+                                if bookmarks.remove(&uuid).is_some() {
+                                    if let Err(error) = bookmarks.write_file(&bookmarks_path) {
+                                        println!("Failed to save bookmarks: {}", error);
+                                    }
+                                }
+                            }
+                        }
 
-                        player
-                            .lock()
-                            .unwrap()
-                            .enqueue_next(&{
-                                match uri.item {
-                                    QueueItemType::Single(song) => song.song.primary_uri().unwrap().0.clone(),
-                                    _ => unimplemented!()
+                        // The modes can't be locked again while the queue is, see `Controller::modes`
+                        let current_modes = *modes.read().unwrap();
+                        if current_modes.stop_after_current {
+                            modes.write().unwrap().stop_after_current = false;
+                            continue;
+                        }
+
+                        let next = {
+                            let mut queue = queue.write().unwrap();
+                            if current_modes.repeat == RepeatMode::One {
+                                queue.current().unwrap().clone()
+                            } else {
+                                // Skip over songs which are on unavailable drives
+                                let mut skipped = 0;
+                                loop {
+                                    let next = queue.next().unwrap().clone();
+                                    match &next.item {
+                                        QueueItemType::Single(song)
+                                            if skipped < queue.items.len()
+                                                && library.read().unwrap().is_offline(&song.song.uuid) =>
+                                        {
+                                            skipped += 1;
+                                        }
+                                        _ => break next,
+                                    }
                                 }
-                            })
-                            .unwrap();
+                            }
+                        };
+
+                        let (uuid, uri, resume, replay_gain, scrobble, trim, kind) = match next.item {
+                            QueueItemType::Single(song) => {
+                                let advanced = QueueEvent::Advanced {
+                                    uuid: song.song.uuid,
+                                    source: song.source,
+                                };
+                                let _ = queue_tx.try_send(advanced.clone());
+                                events.publish(ControllerEvent::QueueChanged(advanced));
+                                let resume = match song.song.is_audiobook() {
+                                    true => bookmarks.read().unwrap().get(&song.song.uuid),
+                                    false => None,
+                                };
+                                let replay_gain = ReplayGain::from_song(&song.song);
+                                let scrobble = scrobbles(&song.song, &config.read().unwrap().ignore, private_session.is_active());
+                                let trim = silence_trim(&config.read().unwrap(), &song.song);
+                                let kind = TrackKind::of(&song.song);
+                                (song.song.uuid, song.song.primary_uri().unwrap().0.clone(), resume, replay_gain, scrobble, trim, kind)
+                            }
+                            _ => unimplemented!()
+                        };
+
+                        // Resolving can go over the network and loading waits for
+                        // the song to preroll, so both happen on their own thread
+                        // and the rest is done once the song has loaded
+                        let (player, remotes, config, loaded_tx) = (player.clone(), remotes.clone(), config.clone(), loaded_tx.clone());
+                        spawn(move || {
+                            let result = load_next(&player, &remotes, &config, &uri, trim, kind);
+                            let _ = loaded_tx.send(LoadedTrack { uuid, uri, resume, replay_gain, scrobble, result });
+                        });
                     },
+                    PlayerCommand::TransitionAhead { remaining } => {
+                        let uri = playing.read().unwrap().clone().or_else(|| player.lock().unwrap().source().clone());
+                        let uuid = uri.and_then(|uri| Some(library.read().unwrap().query_uri(&uri)?.0.uuid));
+                        let _ = transition_tx.try_send(TransitionEvent::Ahead { uuid, remaining });
+                    }
+                    PlayerCommand::DeviceRemoved(device) => {
+                        let pause = config.read().unwrap().device_removal.pause;
+                        let mut player = player.lock().unwrap();
+                        let playing = player.source().is_some() && !player.is_paused();
+                        let paused = pause && playing && match player.pause() {
+                            Ok(()) => true,
+                            Err(error) => {
+                                println!("Failed to pause for the removed device: {}", error);
+                                false
+                            }
+                        };
+                        events.publish(ControllerEvent::DeviceRemoved { device: device.name, paused });
+                        if paused {
+                            events.publish(ControllerEvent::Paused);
+                        }
+                    }
                     PlayerCommand::EndOfStream => {dbg!()}
                     _ => {}
                 }
@@ -148,13 +561,1750 @@ impl<P: Player + Send + Sync + Sized + 'static> Controller<P> {
 
         });
 
+        // Watch for library roots being unplugged or remounted, and scan
+        // the watched ones as often as the library asks for
+        let config = config_.clone();
+        let library = controller.library.clone();
+        let power = controller.power.clone();
+        let events = controller.events.clone();
+        spawn(move || {
+            let mut scanned = Instant::now();
+            loop {
+                let wait = power.read().unwrap().interval(ROOT_POLL_INTERVAL);
+                sleep(wait);
+                let default = match config.read().unwrap().libraries.get_default() {
+                    Ok(default) => default.clone(),
+                    Err(_) => continue,
+                };
+                let changed = library.write().unwrap().refresh_offline(&default.roots);
+                if changed > 0 {
+                    println!("{} songs changed availability", changed);
+                    events.publish(ControllerEvent::LibraryChanged);
+                }
+
+                let due = default.settings.scan_interval.is_some_and(|interval| scanned.elapsed() >= interval);
+                if due && !power.read().unwrap().defer_background() {
+                    scanned = Instant::now();
+                    match library.write().unwrap().scan_watched(&default) {
+                        Ok(added) if added > 0 => {
+                            println!("{} songs added from watched folders", added);
+                            events.publish(ControllerEvent::LibraryChanged);
+                        }
+                        Ok(_) => (),
+                        Err(error) => {
+                            println!("Failed to scan watched folders: {}", error);
+                            events.publish(ControllerEvent::Error(format!("Failed to scan watched folders: {}", error)));
+                        }
+                    }
+                    if default.settings.trim_silence {
+                        let scan = library.write().unwrap().scan_silence(false);
+                        if !scan.trimmed.is_empty() {
+                            println!("Found silence to skip in {} songs", scan.trimmed.len());
+                            events.publish(ControllerEvent::LibraryChanged);
+                        }
+                    }
+                    if default.settings.exact_durations {
+                        let scan = library.write().unwrap().correct_durations(false);
+                        if !scan.corrected.is_empty() {
+                            println!("Corrected the duration of {} songs", scan.corrected.len());
+                            events.publish(ControllerEvent::LibraryChanged);
+                        }
+                    }
+                }
+            }
+        });
+
+        // Apply changes made to the config file while running
+        let config = config_.clone();
+        let player = controller.player.clone();
+        let library = controller.library.clone();
+        let modes = controller.modes.clone();
+        let gain = controller.gain.clone();
+        let config_tx = controller.config_tx.clone();
+        let power = controller.power.clone();
+        let events = controller.events.clone();
+        let caches = controller.caches.clone();
+        spawn(move || {
+            let mut modified = config.read().unwrap().modified();
+            loop {
+                let wait = power.read().unwrap().interval(CONFIG_POLL_INTERVAL);
+                sleep(wait);
+                let now = config.read().unwrap().modified();
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+
+                // Applied from a copy, as applying locks the player, which is
+                // locked before the config everywhere else
+                let reloaded = {
+                    let mut config = config.write().unwrap();
+                    config.reload().map(|changed| (config.clone(), changed))
+                };
+                match reloaded {
+                    Ok((config, changed)) => {
+                        apply_config(&config, &changed, &player, &library, &modes, &gain, &power, &caches, &events);
+                        for field in changed {
+                            let _ = config_tx.try_send(ConfigEvent::Changed(field));
+                        }
+                    }
+                    // The file may be halfway through being written, so it's read again next time
+                    Err(error) => {
+                        println!("Failed to reload the config: {}", error);
+                        modified = None;
+                    }
+                }
+            }
+        });
+
+        // Save energy while running on battery
+        let config = config_.clone();
+        let player = controller.player.clone();
+        let power = controller.power.clone();
+        let manual_power = controller.manual_power.clone();
+        let power_tx = controller.power_tx.clone();
+        spawn(move || loop {
+            let on_battery = on_battery();
+            let mode = config.read().unwrap().power.mode(*manual_power.read().unwrap(), on_battery);
+            switch_power(mode, on_battery, &power, &player, &config, &power_tx);
+            sleep(POWER_CHECK_INTERVAL);
+        });
+
+        // Pause playback which has gone on for too long without any user commands
+        let config = config_.clone();
+        let player = controller.player.clone();
+        let idle = controller.idle.clone();
+        let remotes_ = controller.remotes.clone();
+        let events = controller.events.clone();
+        spawn(move || loop {
+            sleep(IDLE_CHECK_INTERVAL);
+            let idle_config = config.read().unwrap().idle;
+            let mut player = player.lock().unwrap();
+            let playing = player.source().is_some() && !player.is_paused();
+            let event = match idle.lock().unwrap().check(Instant::now(), &idle_config, playing) {
+                Some(event) => event,
+                None => continue,
+            };
+
+            if event == IdleEvent::Paused {
+                if let Err(error) = player.pause() {
+                    println!("Failed to pause idle playback: {}", error);
+                    continue;
+                }
+                events.publish(ControllerEvent::Paused);
+                if let (Some(uri), Some(position)) = (player.source(), player.position()) {
+                    let position = position.to_std().unwrap_or_default();
+                    remote::report_playback(&remotes_, uri, PlaybackReport::Paused, position);
+                }
+            }
+            let _ = idle_tx.try_send(event);
+        });
+
+        // Switch between audio profiles at the times they are scheduled for
+        let config = config_.clone();
+        let player = controller.player.clone();
+        let modes = controller.modes.clone();
+        let active = controller.active_profile.clone();
+        spawn(move || {
+            // The crossfade from before a profile changed it, put back when it ends
+            let mut saved_crossfade = None;
+            loop {
+                let profile = config.read().unwrap().profiles.scheduled(Local::now().time()).cloned();
+                let to = profile.as_ref().map(|profile| profile.name.clone());
+                let from = active.read().unwrap().clone();
+                if to != from {
+                    let equalizer = profile.as_ref().map(AudioProfile::output_equalizer).unwrap_or_default();
+                    if let Err(error) = player.lock().unwrap().set_equalizer(equalizer) {
+                        println!("Failed to set the equalizer: {}", error);
+                    }
+
+                    {
+                        let mut modes = modes.write().unwrap();
+                        if let Some(crossfade) = saved_crossfade.take() {
+                            modes.crossfade = crossfade;
+                        }
+                        if let Some(crossfade) = profile.as_ref().and_then(|profile| profile.crossfade) {
+                            saved_crossfade = Some(modes.crossfade);
+                            modes.crossfade = Some(crossfade);
+                        }
+                    }
+
+                    *active.write().unwrap() = to.clone();
+                    let _ = profile_tx.try_send(ProfileEvent::Switched { from, to });
+                }
+                sleep(PROFILE_CHECK_INTERVAL);
+            }
+        });
+
+        // Fade out and stop playback when the sleep timer is up
+        let player = controller.player.clone();
+        let modes = controller.modes.clone();
+        let sleep_timer = controller.sleep_timer.clone();
+        let sleep_tx = controller.sleep_tx.clone();
+        let events = controller.events.clone();
+        spawn(move || loop {
+            sleep(SLEEP_CHECK_INTERVAL);
+            let mut timer = sleep_timer.lock().unwrap();
+            let Some(active) = timer.as_mut() else { continue };
+            // Stopping after the track is done once the controller has stopped
+            if active.is_armed() {
+                if !modes.read().unwrap().stop_after_current {
+                    *timer = None;
+                    let _ = sleep_tx.try_send(SleepEvent::Stopped);
+                }
+                continue;
+            }
+
+            let mut player = player.lock().unwrap();
+            let fading = active.is_fading();
+            let now = Instant::now();
+            match active.check(now, player.volume()) {
+                Some(SleepAction::SetVolume(volume)) => {
+                    if !fading {
+                        let _ = sleep_tx.try_send(SleepEvent::Fading { remaining: active.remaining(now) });
+                    }
+                    player.set_volume(volume);
+                }
+                Some(SleepAction::Pause { restore }) => {
+                    if let Err(error) = player.pause() {
+                        println!("Failed to pause for the sleep timer: {}", error);
+                    }
+                    events.publish(ControllerEvent::Paused);
+                    player.set_volume(restore);
+                    *timer = None;
+                    let _ = sleep_tx.try_send(SleepEvent::Stopped);
+                }
+                Some(SleepAction::StopAfterTrack) => {
+                    modes.write().unwrap().stop_after_current = true;
+                    let _ = sleep_tx.try_send(SleepEvent::StoppingAfterTrack);
+                }
+                None => (),
+            }
+        });
+
+        // Start playing when an alarm goes off, fading the volume in
+        let player = controller.player.clone();
+        let library = controller.library.clone();
+        let queue = controller.queue.clone();
+        let config = config_.clone();
+        let modes = controller.modes.clone();
+        let gain = controller.gain.clone();
+        let remotes_ = controller.remotes.clone();
+        let alarm = controller.alarm.clone();
+        let alarm_tx = controller.alarm_tx.clone();
+        let queue_tx = controller.queue_tx.clone();
+        let events = controller.events.clone();
+        spawn(move || {
+            let mut fade: Option<FadeIn> = None;
+            loop {
+                sleep(ALARM_CHECK_INTERVAL);
+                if let Some(fading) = fade {
+                    let volume = fading.volume(Instant::now());
+                    player.lock().unwrap().set_volume(volume.unwrap_or(fading.target()));
+                    if volume.is_none() {
+                        fade = None;
+                    }
+                    continue;
+                }
+
+                let due = {
+                    let mut alarm = alarm.lock().unwrap();
+                    match *alarm {
+                        Some(scheduled) if scheduled.is_due(Local::now()) => alarm.take(),
+                        _ => None,
+                    }
+                };
+                let Some(due) = due else { continue };
+
+                let songs = alarm_songs(&due.source, &library.read().unwrap());
+                let replay_gain = songs.first().map(|song| ReplayGain::from_song(&song.song));
+                let fading = FadeIn::new(Instant::now(), due.fade_in, config.read().unwrap().volume as f64);
+                let volume = fading.volume(Instant::now()).unwrap_or(fading.target());
+                match start_alarm(songs, volume, &player, &remotes_, &queue, &queue_tx, &events) {
+                    Ok(()) => {
+                        *gain.write().unwrap() = set_player_gain(
+                            &mut *player.lock().unwrap(),
+                            replay_gain,
+                            modes.read().unwrap().normalization,
+                            &config.read().unwrap().replay_gain,
+                        );
+                        fade = Some(fading);
+                        let _ = alarm_tx.try_send(AlarmEvent::Started(due.source));
+                    }
+                    Err(error) => {
+                        println!("Failed to start the alarm: {}", error);
+                        events.publish(ControllerEvent::Error(format!("Failed to start the alarm: {}", error)));
+                        let _ = alarm_tx.try_send(AlarmEvent::Failed { source: due.source, error: error.to_string() });
+                    }
+                }
+            }
+        });
+
+        // Show what's playing on Discord, when that's turned on
+        let events = controller.events.subscribe();
+        let config = config_.clone();
+        let library = controller.library.clone();
+        let podcasts = controller.podcasts.clone();
+        let player = controller.player.clone();
+        spawn(move || publish_presence(events, config, library, podcasts, player));
+
+        // Show a notification when the track changes, when that's turned on
+        let events = controller.events.subscribe();
+        let config = config_.clone();
+        let library = controller.library.clone();
+        let podcasts = controller.podcasts.clone();
+        let notifier = controller.notifier.clone();
+        spawn(move || notify_tracks(events, config, library, podcasts, notifier));
+
+        // Tell subscribers and remote libraries where playback is while playing
+        let player = controller.player.clone();
+        let power = controller.power.clone();
+        let events = controller.events.clone();
+        let playing = controller.playing.clone();
+        let library = controller.library.clone();
+        let remotes = controller.remotes.clone();
+        let config = config_.clone();
+        let private_session = controller.private_session.clone();
+        spawn(move || {
+            let mut reported = Instant::now();
+            loop {
+                let wait = power.read().unwrap().interval(POSITION_TICK_INTERVAL);
+                sleep(wait);
+                let report = reported.elapsed() >= PROGRESS_REPORT_INTERVAL;
+                if events.subscribers() == 0 && !report {
+                    continue;
+                }
+                let (position, duration) = {
+                    let player = player.lock().unwrap();
+                    if player.is_paused() {
+                        continue;
+                    }
+                    let duration = player.duration().and_then(|dur| dur.to_std().ok());
+                    (player.position().and_then(|pos| pos.to_std().ok()), duration)
+                };
+                let Some(position) = position else { continue };
+                events.publish(ControllerEvent::PositionTick { position, duration });
+
+                if report {
+                    reported = Instant::now();
+                    let Some(uri) = playing.read().unwrap().clone() else { continue };
+                    let scrobble = library.read().unwrap().query_uri(&uri).is_some_and(|(song, _)| {
+                        scrobbles(song, &config.read().unwrap().ignore, private_session.is_active())
+                    });
+                    if scrobble {
+                        remote::report_playback(&remotes, &uri, PlaybackReport::Progress, position);
+                    }
+                }
+            }
+        });
+
+        // Save the position of podcast episodes and audiobooks while they play
+        let player = controller.player.clone();
+        let library = controller.library.clone();
+        let podcasts = controller.podcasts.clone();
+        let bookmarks = controller.bookmarks.clone();
+        let (podcasts_path, bookmarks_path) = {
+            let config = config_.read().unwrap();
+            (Podcasts::path(&config), Bookmarks::path(&config))
+        };
+        let power = controller.power.clone();
+        spawn(move || loop {
+            let wait = power.read().unwrap().interval(POSITION_SAVE_INTERVAL);
+            sleep(wait);
+            let (source, position, duration) = {
+                let player = player.lock().unwrap();
+                let position = player.position().and_then(|pos| pos.to_std().ok());
+                let duration = player.duration().and_then(|dur| dur.to_std().ok());
+                (player.source().clone(), position, duration)
+            };
+            let (source, position) = match (source, position) {
+                (Some(source), Some(position)) => (source, position),
+                _ => continue,
+            };
+
+            let audiobook = library
+                .read()
+                .unwrap()
+                .query_uri(&source)
+                .filter(|(song, _)| song.is_audiobook())
+                .map(|(song, _)| song.uuid);
+            if let Some(uuid) = audiobook {
+                let mut bookmarks = bookmarks.write().unwrap();
+                if bookmarks.set(uuid, position, duration) {
+                    if let Err(error) = bookmarks.write_file(&bookmarks_path) {
+                        println!("Failed to save bookmarks: {}", error);
+                    }
+                }
+                continue;
+            }
+
+            let mut podcasts = podcasts.write().unwrap();
+            let episode = match podcasts.episode_at_mut(&source) {
+                Some(episode) if episode.position != position => episode,
+                _ => continue,
+            };
+            if episode.set_position(position, duration) {
+                println!("Marked {} as played", episode.title);
+            }
+            if let Err(error) = podcasts.write_file(&podcasts_path) {
+                println!("Failed to save podcasts: {}", error);
+            }
+        });
 
         Ok(controller)
     }
 
+    /// Play a song from the library straight away. Audiobooks are resumed
+    /// from their bookmark rather than starting from the beginning.
+    pub fn play_song(&mut self, uuid: &Uuid) -> Result<(), ControllerError> {
+        self.still_listening();
+        let (uri, audiobook, replay_gain, trim, kind, scrobble) = {
+            let library = self.library.read().unwrap();
+            let (song, _) = library.query_uuid(uuid).ok_or(PlayerError::NotFound)?;
+            let uri = match song.primary_uri() {
+                Ok((uri, _)) => uri.clone(),
+                Err(_) => return Err(PlayerError::NotFound.into()),
+            };
+            let config = self.config.read().unwrap();
+            let scrobble = scrobbles(song, &config.ignore, self.private_session.is_active());
+            let trim = silence_trim(&config, song);
+            (uri, song.is_audiobook(), ReplayGain::from_song(song), trim, TrackKind::of(song), scrobble)
+        };
+        let resolved = remote::resolve_uri(&self.remotes, &uri)
+            .map_err(|e| ControllerError::RemoteError(e.to_string()))?;
+        self.record_outgoing();
+
+        if let Some(cast) = self.cast.lock().unwrap().as_mut() {
+            let position = audiobook.then(|| self.bookmarks.read().unwrap().get(uuid)).flatten();
+            let library = self.library.read().unwrap();
+            let song = library.query_uuid(uuid).map(|(song, _)| song);
+            cast.load(&resolved, song, position.unwrap_or_default())?;
+            self.started(uuid, uri, scrobble, position.unwrap_or_default());
+            return Ok(());
+        }
+
+        let resume = audiobook.then(|| self.bookmarks.read().unwrap().get(uuid)).flatten();
+        {
+            let mut player = self.player.lock().unwrap();
+            player.set_next_trim(trim);
+            set_skip_silence(&mut *player, &self.config.read().unwrap(), kind);
+            player.enqueue_next(&resolved)?;
+            self.set_gain(&mut *player, Some(replay_gain));
+            if let Some(position) = resume {
+                let position = chrono::Duration::from_std(position)
+                    .map_err(|e| PlayerError::Seek(e.to_string()))?;
+                player.seek_to(position)?;
+            }
+            player.play()?;
+        }
+        self.started(uuid, uri, scrobble, resume.unwrap_or_default());
+        Ok(())
+    }
+
+    /// Let everything know the song at `uri` started playing at `position`,
+    /// reporting it to its server if it's `scrobble`d
+    fn started(&self, uuid: &Uuid, uri: URI, scrobble: bool, position: Duration) {
+        if scrobble {
+            remote::report_playback(&self.remotes, &uri, PlaybackReport::Started, position);
+        }
+        *self.playing.write().unwrap() = Some(uri.clone());
+        self.events.publish(ControllerEvent::TrackChanged { uuid: Some(*uuid), uri });
+    }
+
+    /// Count the song which is playing as listened to up to where it is,
+    /// as a play or a skip, before something else is played instead, and
+    /// tell its server it stopped
+    fn record_outgoing(&self) {
+        let Some(uri) = self.playing.write().unwrap().take() else {
+            return;
+        };
+        let Some(listened) = self.position() else {
+            return;
+        };
+        let ignore = self.config.read().unwrap().ignore.clone();
+        let Listen { scrobble, played, .. } =
+            record_listen(&self.library, &self.history, &ignore, self.private_session.is_active(), &uri, listened);
+        if scrobble {
+            remote::report_playback(&self.remotes, &uri, PlaybackReport::Stopped { finished: played }, listened);
+        }
+    }
+
+    /// Save the library, with the plays, ratings, and everything else
+    /// changed while running
+    pub fn save_library(&self) -> Result<(), ControllerError> {
+        save_library(&self.library, &self.config).map_err(|error| ControllerError::LibraryError(error.to_string()))
+    }
+
+    /// Skip to the next song in the queue, or the first song of the next
+    /// album
+    pub fn q_next(&mut self) -> Result<(), ControllerError> {
+        let uuid = {
+            let mut queue = self.queue.write().unwrap();
+            let next = queue.next()?.clone();
+            match next.item {
+                QueueItemType::Single(song) => {
+                    let advanced = QueueEvent::Advanced {
+                        uuid: song.song.uuid,
+                        source: song.source,
+                    };
+                    let _ = self.queue_tx.try_send(advanced.clone());
+                    self.events.publish(ControllerEvent::QueueChanged(advanced));
+                    song.song.uuid
+                }
+                QueueItemType::Multi(album) => album
+                    .album
+                    .discs()
+                    .values()
+                    .flat_map(|tracks| tracks.iter().map(|(_, uuid)| *uuid))
+                    .next()
+                    .ok_or(QueueError::NoNext)?,
+            }
+        };
+        self.play_song(&uuid)
+    }
+
+    /// Go back to the song played before the current one, or the first
+    /// song of the album played before it
+    pub fn q_prev(&mut self) -> Result<(), ControllerError> {
+        let uuid = match self.queue.write().unwrap().prev()?.item.clone() {
+            QueueItemType::Single(song) => song.song.uuid,
+            QueueItemType::Multi(album) => album
+                .album
+                .discs()
+                .values()
+                .flat_map(|tracks| tracks.iter().map(|(_, uuid)| *uuid))
+                .next()
+                .ok_or(QueueError::EmptyPlayed)?,
+        };
+        self.play_song(&uuid)
+    }
+
+    /// Play an episode of a podcast, resuming from where it was left
+    /// off if it has been partly listened to
+    pub fn play_episode(&mut self, podcast: &Uuid, guid: &str) -> Result<(), ControllerError> {
+        self.still_listening();
+        let (uri, resume) = {
+            let podcasts = self.podcasts.read().unwrap();
+            let episode = podcasts
+                .podcast(podcast)
+                .and_then(|podcast| podcast.episode(guid))
+                .ok_or(PodcastError::NotFound)?;
+            (episode.uri(), episode.resume_position())
+        };
+        self.record_outgoing();
+
+        if let Some(cast) = self.cast.lock().unwrap().as_mut() {
+            cast.load(&uri, None, resume.unwrap_or_default())?;
+            self.events.publish(ControllerEvent::TrackChanged { uuid: None, uri });
+            return Ok(());
+        }
+
+        let mut player = self.player.lock().unwrap();
+        set_skip_silence(&mut *player, &self.config.read().unwrap(), TrackKind::Podcast);
+        player.enqueue_next(&uri)?;
+        self.set_gain(&mut *player, None);
+        if let Some(position) = resume {
+            let position = chrono::Duration::from_std(position)
+                .map_err(|e| PlayerError::Seek(e.to_string()))?;
+            player.seek_to(position)?;
+        }
+        player.play()?;
+        self.events.publish(ControllerEvent::TrackChanged { uuid: None, uri });
+        Ok(())
+    }
+
+    /// Subscribe to a podcast feed, returning the [Uuid] of the podcast
+    pub fn subscribe_podcast(&mut self, feed_url: &str) -> Result<Uuid, ControllerError> {
+        let known = self.podcasts.read().unwrap().podcasts.iter().find(|p| p.feed_url == feed_url).map(|p| p.uuid);
+        if let Some(uuid) = known {
+            return Ok(uuid);
+        }
+        // The feed is fetched without holding the podcasts, which playback also uses
+        let podcast = Podcast::from_url(feed_url)?;
+        let path = Podcasts::path(&self.config.read().unwrap());
+        let mut podcasts = self.podcasts.write().unwrap();
+        let uuid = podcasts.add(podcast);
+        podcasts.write_file(&path)?;
+        Ok(uuid)
+    }
+
+    /// Refresh every subscribed podcast, returning the total number of new
+    /// episodes. Podcasts which fail to refresh are skipped.
+    pub fn refresh_podcasts(&mut self) -> Result<usize, ControllerError> {
+        let feeds: Vec<(Uuid, String)> = {
+            let podcasts = self.podcasts.read().unwrap();
+            podcasts.podcasts.iter().map(|podcast| (podcast.uuid, podcast.feed_url.clone())).collect()
+        };
+        let mut added = 0;
+        for (uuid, feed_url) in feeds {
+            let refreshed = fetch_feed(&feed_url).and_then(|contents| {
+                let mut podcasts = self.podcasts.write().unwrap();
+                match podcasts.podcast_mut(&uuid) {
+                    Some(podcast) => podcast.refresh_from(&contents),
+                    // Unsubscribed from while its feed was fetched
+                    None => Ok(0),
+                }
+            });
+            match refreshed {
+                Ok(new) => added += new,
+                Err(error) => println!("Failed to refresh podcast {}: {}", uuid, error),
+            }
+        }
+        let path = Podcasts::path(&self.config.read().unwrap());
+        self.podcasts.read().unwrap().write_file(&path)?;
+        Ok(added)
+    }
+
+    /// Download an episode so it can be played offline, leaving the disk
+    /// space reserved in the config free
+    pub fn download_episode(&mut self, podcast: &Uuid, guid: &str) -> Result<PathBuf, ControllerError> {
+        let (folder, podcasts_path, reserve) = {
+            let config = self.config.read().unwrap();
+            (Podcasts::download_folder(&config).join(podcast.to_string()), Podcasts::path(&config), config.disk.reserve)
+        };
+        let (url, path) = {
+            let podcasts = self.podcasts.read().unwrap();
+            let episode = podcasts
+                .podcast(podcast)
+                .and_then(|podcast| podcast.episode(guid))
+                .ok_or(PodcastError::NotFound)?;
+            (episode.url.clone(), episode.download_path(&folder))
+        };
+
+        // Downloading can take a while, so nothing is held while it does
+        if let Err(error) = download_to(&url, &path, reserve) {
+            publish_disk_space(&self.events, &error);
+            return Err(error.into());
+        }
+
+        let mut podcasts = self.podcasts.write().unwrap();
+        let Some(episode) = podcasts.podcast_mut(podcast).and_then(|podcast| podcast.episode_mut(guid)) else {
+            // Unsubscribed from while it downloaded
+            fs::remove_file(&path)?;
+            return Err(PodcastError::NotFound.into());
+        };
+        episode.download = Some(path.clone());
+        podcasts.write_file(&podcasts_path)?;
+        Ok(path)
+    }
+
     pub fn q_add(&mut self, item: &Uuid, source: PlayerLocation, by_human: bool) {
-        let item = self.library.query_uuid(item).unwrap().0.to_owned();
-        self.queue.write().unwrap().add_item(QueueSong { song: item, location: source }, by_human)
+        let queue_source = match (by_human, source) {
+            (true, _) => QueueSource::User,
+            (false, PlayerLocation::Playlist(uuid)) => QueueSource::Playlist(uuid),
+            (false, _) => QueueSource::AutoDj,
+        };
+        self.q_add_from(item, source, queue_source);
+    }
+
+    /// Add a song to the end of the queue, recording why it was added
+    pub fn q_add_from(&mut self, item: &Uuid, location: PlayerLocation, source: QueueSource) {
+        if source.by_human() {
+            self.still_listening();
+        }
+        let song = self.library.read().unwrap().query_uuid(item).unwrap().0.to_owned();
+        if let Err(error) = self.change_queue(QueueOp::Add(QueueSong { song, location, source })) {
+            println!("Failed to add to the queue: {}", error);
+            return;
+        }
+
+        if let Err(error) = self.save_session() {
+            println!("Failed to save session: {}", error);
+        }
+    }
+
+    /// Add `count` songs picked at random from the library to the queue.
+    /// Only the preferred edition of albums with several is picked from,
+    /// see [LibrarySettings::preferred_editions](crate::config::library_settings::LibrarySettings::preferred_editions).
+    pub fn q_add_auto_dj(&mut self, count: usize) -> Result<(), ControllerError> {
+        let picked: Vec<Uuid> = {
+            let config = self.config.read().unwrap();
+            let library = self.library.read().unwrap();
+            let exclusions = self.exclusions.lock().unwrap();
+            let quarantine = self.quarantine.read().unwrap();
+            let songs: Vec<&Song> = library
+                .preferred_songs(&config.libraries.get_default()?.settings)
+                .into_iter()
+                .filter(|song| !library.is_offline(&song.uuid) && !exclusions.excludes(song, Instant::now()))
+                .filter(|song| !quarantine.contains(&song.uuid))
+                .collect();
+            // The same recording from another album is never picked twice in a session
+            let order = shuffled_order(songs.len(), random_seed());
+            pick_distinct(&self.queue.read().unwrap(), &songs, &order, count)
+        };
+        for uuid in picked {
+            self.q_add_from(&uuid, PlayerLocation::Library, QueueSource::AutoDj);
+        }
+        Ok(())
+    }
+
+    /// Leave `exclusion` out of shuffle and auto-DJ for `duration`, or the
+    /// rest of the session if it's `None`. Nothing in the library changes.
+    /// Excluded songs already in the queue are shuffled to the end.
+    pub fn exclude(&mut self, exclusion: Exclusion, duration: Option<Duration>) {
+        self.exclusions.lock().unwrap().add(exclusion, duration, Instant::now());
+        self.reshuffle();
+    }
+
+    /// What is left out of shuffle and auto-DJ, with how long until each
+    /// exclusion ends if it doesn't last the whole session
+    pub fn exclusions(&self) -> Vec<(Exclusion, Option<Duration>)> {
+        self.exclusions.lock().unwrap().active(Instant::now())
+    }
+
+    /// Stop excluding something, returning whether it was excluded
+    pub fn remove_exclusion(&mut self, exclusion: &Exclusion) -> bool {
+        let removed = self.exclusions.lock().unwrap().remove(exclusion);
+        self.reshuffle();
+        removed
+    }
+
+    pub fn clear_exclusions(&mut self) {
+        self.exclusions.lock().unwrap().clear();
+        self.reshuffle();
+    }
+
+    /// Make the shuffled order again from the same seed, after the exclusions change
+    fn reshuffle(&self) {
+        let modes = self.modes.read().unwrap();
+        let (true, Some(seed)) = (modes.shuffle, modes.shuffle_seed) else {
+            return;
+        };
+        let mut queue = self.queue.write().unwrap();
+        queue.shuffle = Some(self.shuffle_order(&queue, seed));
+    }
+
+    /// The shuffled order of `queue` from `seed`, with the songs which are
+    /// excluded or quarantined played last
+    fn shuffle_order(&self, queue: &PlayQueue, seed: u64) -> Vec<usize> {
+        let exclusions = self.exclusions.lock().unwrap();
+        let quarantine = self.quarantine.read().unwrap();
+        fair_order(queue, seed, |song| exclusions.excludes(song, Instant::now()) || quarantine.contains(&song.uuid))
+    }
+
+    /// The songs which failed to play, which shuffle and auto-DJ leave out
+    /// until they're tested again
+    pub fn quarantined(&self) -> Vec<QuarantinedSong> {
+        self.quarantine.read().unwrap().songs.clone()
+    }
+
+    /// Take a song out of the quarantine without testing it, returning
+    /// whether it was quarantined
+    pub fn release_quarantined(&mut self, uuid: &Uuid) -> Result<bool, ControllerError> {
+        let released = self.quarantine.write().unwrap().release(uuid).is_some();
+        if released {
+            self.quarantine.read().unwrap().write_file(&Quarantine::path(&self.config.read().unwrap()))?;
+            self.reshuffle();
+        }
+        Ok(released)
+    }
+
+    /// Test every quarantined song again, such as after installing a codec
+    /// or fixing files, releasing the ones which can be played now
+    pub fn retest_quarantine(&mut self) -> Result<RetestReport, ControllerError> {
+        // The songs are tested without holding the quarantine, as probing can take a while
+        let mut testing = self.quarantine.read().unwrap().clone();
+        let report = testing.retest(|uuid| self.test_playable(uuid), Utc::now());
+        {
+            let mut quarantine = self.quarantine.write().unwrap();
+            for uuid in &report.released {
+                quarantine.release(uuid);
+            }
+            for (uuid, error) in &report.failed {
+                quarantine.add(*uuid, error.clone(), Utc::now());
+            }
+            quarantine.write_file(&Quarantine::path(&self.config.read().unwrap()))?;
+        }
+        if !report.released.is_empty() {
+            self.reshuffle();
+        }
+        Ok(report)
+    }
+
+    /// Whether the song can be loaded, without playing it
+    fn test_playable(&self, uuid: &Uuid) -> Result<(), String> {
+        let resolved = {
+            let library = self.library.read().unwrap();
+            let (song, _) = library.query_uuid(uuid).ok_or("the song is no longer in the library")?;
+            let (uri, _) = song.primary_uri().map_err(|error| error.to_string())?;
+            remote::resolve_uri(&self.remotes, uri).map_err(|error| error.to_string())?
+        };
+        let result = probe(&resolved.as_uri()).map_err(|error| error.to_string())?;
+        match result.playable {
+            true => Ok(()),
+            false => Err(result.error.unwrap_or_else(|| "the song can't be played".to_string())),
+        }
+    }
+
+    /// Remove the item at `index` from the queue
+    pub fn q_remove(&mut self, index: usize) -> Result<(), ControllerError> {
+        self.still_listening();
+        self.change_queue(QueueOp::Remove(index))?;
+        self.save_session()
+    }
+
+    /// Remove every item from the queue
+    pub fn q_clear(&mut self) -> Result<(), ControllerError> {
+        self.still_listening();
+        self.change_queue(QueueOp::Clear)?;
+        self.save_session()
+    }
+
+    /// Check the queue against `invariants` after every change made through
+    /// the controller, rejecting changes which break them. `None` turns
+    /// checking off.
+    pub fn check_queue_invariants(&mut self, invariants: Option<QueueInvariants>) {
+        self.invariants = invariants;
+    }
+
+    /// Make a change to the queue and tell listeners about it
+    fn change_queue(&self, op: QueueOp) -> Result<(), ControllerError> {
+        change_queue(&self.queue, vec![op], self.invariants.as_ref(), &self.queue_tx, &self.events)
+    }
+
+    /// The songs in the queue which were added for `source`, along with their index
+    pub fn q_from_source(&self, source: &QueueSource) -> Vec<(usize, Uuid)> {
+        self.queue
+            .read()
+            .unwrap()
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| match &item.item {
+                QueueItemType::Single(song) if &song.source == source => Some((i, song.song.uuid)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Restore the queue, volume, and playback modes from the saved session,
+    /// then load the track which was playing, paused at the saved position.
+    ///
+    /// The saved queue replaces the current one, leaving out songs which
+    /// are no longer in the library.
+    pub fn restore_session(&mut self) -> Result<(), ControllerError> {
+        let path = Session::path(&self.config.read().unwrap());
+        if !path.exists() {
+            return Ok(());
+        }
+        let session = Session::read_file(&path)?;
+
+        // Sessions from before the modes were saved only have the queue's flags
+        let mut modes = session.modes;
+        modes.shuffle |= session.shuffle.is_some();
+        if session.loop_ && modes.repeat == RepeatMode::Off {
+            modes.repeat = RepeatMode::All;
+        }
+
+        let songs: Vec<(QueueSong, bool)> = {
+            let library = self.library.read().unwrap();
+            session
+                .queue
+                .iter()
+                .filter_map(|item| {
+                    let (song, _) = library.query_uuid(&item.uuid)?;
+                    Some((QueueSong { song: song.clone(), location: item.location, source: item.source }, item.by_human))
+                })
+                .collect()
+        };
+        if songs.len() < session.queue.len() {
+            println!("{} songs of the saved queue are no longer in the library", session.queue.len() - songs.len());
+        }
+        {
+            // The saved queue replaces whatever was queued before
+            let mut queue = self.queue.write().unwrap();
+            queue.clear();
+            queue.clear_played();
+            for (song, by_human) in songs {
+                queue.add_item(song, by_human);
+            }
+            queue.loop_ = session.loop_;
+
+            // The saved order is only kept if it is still an order of the
+            // queue, otherwise it's made again, from the same seed if there is one
+            let saved = session.shuffle.clone().filter(|order| is_order_of(order, queue.items.len()));
+            queue.shuffle = match (modes.shuffle, saved) {
+                (false, _) => None,
+                (true, Some(order)) => Some(order),
+                (true, None) => {
+                    let seed = *modes.shuffle_seed.get_or_insert_with(random_seed);
+                    Some(self.shuffle_order(&queue, seed))
+                }
+            };
+        }
+        *self.modes.write().unwrap() = modes;
+
+        let library = self.library.read().unwrap();
+        let mut player = self.player.lock().unwrap();
+        player.set_volume(session.volume);
+
+        let current = session.current.and_then(|uuid| library.query_uuid(&uuid));
+        if let Some((song, _)) = current {
+            let uri = match song.primary_uri() {
+                Ok((uri, _)) => uri,
+                Err(_) => return Err(PlayerError::NotFound.into()),
+            };
+            let resolved = remote::resolve_uri(&self.remotes, uri)
+                .map_err(|e| ControllerError::RemoteError(e.to_string()))?;
+            player.enqueue_next(&resolved)?;
+            player.pause()?;
+            *self.playing.write().unwrap() = Some(uri.clone());
+
+            if let Some(position) = session.position {
+                let position = chrono::Duration::from_std(position)
+                    .map_err(|e| PlayerError::Seek(e.to_string()))?;
+                player.seek_to(position)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The current playback modes
+    pub fn modes(&self) -> PlaybackModes {
+        *self.modes.read().unwrap()
+    }
+
+    /// Replace every playback mode at once, see [Controller::update_modes]
+    pub fn set_modes(&mut self, modes: PlaybackModes) -> Result<(), ControllerError> {
+        self.update_modes(|current| *current = modes)
+    }
+
+    /// Change the playback modes, with no other changes able to happen
+    /// in between reading and writing them. The queue is updated to match
+    /// before anything can see the new modes.
+    pub fn update_modes<F: FnOnce(&mut PlaybackModes)>(&mut self, update: F) -> Result<(), ControllerError> {
+        self.still_listening();
+        {
+            let mut modes = self.modes.write().unwrap();
+            let mut queue = self.queue.write().unwrap();
+            let seed = modes.shuffle_seed;
+            update(&mut modes);
+
+            queue.loop_ = modes.repeat == RepeatMode::All;
+            // A new seed gives a new order, even if shuffle was already on
+            if modes.shuffle && (queue.shuffle.is_none() || modes.shuffle_seed != seed) {
+                let seed = *modes.shuffle_seed.get_or_insert_with(random_seed);
+                queue.shuffle = Some(self.shuffle_order(&queue, seed));
+            } else if !modes.shuffle {
+                queue.shuffle = None;
+            }
+        }
+        self.refresh_gain();
+        set_transition_lead(&mut *self.player.lock().unwrap(), &self.config.read().unwrap(), &self.modes.read().unwrap());
+        self.save_session()
+    }
+
+    /// Set the ReplayGain of the player for a song which is being played
+    fn set_gain(&self, player: &mut P, replay_gain: Option<ReplayGain>) {
+        let normalization = self.modes.read().unwrap().normalization;
+        let config = self.config.read().unwrap().replay_gain;
+        *self.gain.write().unwrap() = set_player_gain(player, replay_gain, normalization, &config);
+    }
+
+    /// Apply the ReplayGain of the current song again, after the settings
+    /// it depends on have changed, such as the song's volume adjustment
+    pub fn refresh_gain(&self) {
+        let mut player = self.player.lock().unwrap();
+        let replay_gain = player.source().as_ref().and_then(|source| {
+            let library = self.library.read().unwrap();
+            library.query_uri(source).map(|(song, _)| ReplayGain::from_song(song))
+        });
+        self.set_gain(&mut *player, replay_gain);
+    }
+
+    /// How much listening time has been saved by skipping silence
+    pub fn silence_skipped(&self) -> Duration {
+        self.player.lock().unwrap().silence_skipped()
+    }
+
+    /// The ReplayGain applied to the current song
+    pub fn applied_gain(&self) -> Option<AppliedGain> {
+        *self.gain.read().unwrap()
+    }
+
+    /// Start playing `source` `at` a set time, fading the volume in from
+    /// silence to the volume in the config over `fade_in`. The queue is
+    /// replaced with the songs played. Replaces any alarm already set.
+    pub fn schedule_play(&self, at: DateTime<Local>, source: AlarmSource, fade_in: Duration) {
+        *self.alarm.lock().unwrap() = Some(Alarm { at, source, fade_in });
+    }
+
+    /// The alarm which is set, if there is one
+    pub fn next_alarm(&self) -> Option<Alarm> {
+        *self.alarm.lock().unwrap()
+    }
+
+    pub fn cancel_alarm(&self) {
+        if self.alarm.lock().unwrap().take().is_some() {
+            let _ = self.alarm_tx.try_send(AlarmEvent::Cancelled);
+        }
+    }
+
+    /// Download the songs of `item` from their remote libraries in the
+    /// background, so they're played from the cache even without a
+    /// connection. Returns how many songs are being downloaded.
+    pub fn download_offline(&self, item: &OfflineItem) -> usize {
+        let songs = item_songs(&self.library.read().unwrap(), item);
+        for song in &songs {
+            let _ = self.offline_tx.try_send(OfflineEvent { uuid: song.uuid, state: DownloadState::Queued });
+        }
+
+        let count = songs.len();
+        let caches = self.caches.clone();
+        let remotes = self.remotes.clone();
+        let offline_tx = self.offline_tx.clone();
+        let events = self.events.clone();
+        spawn(move || {
+            for song in songs {
+                let _ = offline_tx.try_send(OfflineEvent { uuid: song.uuid, state: DownloadState::Downloading });
+                let state = match offline::download(&caches.offline, &remotes, &song) {
+                    Ok(()) => DownloadState::Done,
+                    Err(error) => {
+                        publish_disk_space(&events, error.as_ref());
+                        DownloadState::Failed(error.to_string())
+                    }
+                };
+                let _ = offline_tx.try_send(OfflineEvent { uuid: song.uuid, state });
+            }
+        });
+        count
+    }
+
+    /// Remove the downloaded songs of `item`, returning the number of
+    /// bytes reclaimed
+    pub fn remove_offline(&self, item: &OfflineItem) -> Result<u64, std::io::Error> {
+        let mut reclaimed = 0;
+        for song in item_songs(&self.library.read().unwrap(), item) {
+            if let Some(key) = song.location.first().and_then(offline::offline_key) {
+                reclaimed += self.caches.offline.remove(&key)?;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Stop playback `after` a while, either fading it out or letting the
+    /// song playing at the time finish. A zero duration with
+    /// [SleepMode::StopAfterTrack] stops after the current song. Replaces
+    /// any timer already set.
+    pub fn set_sleep_timer(&self, after: Duration, mode: SleepMode) {
+        self.cancel_sleep_timer();
+        *self.sleep_timer.lock().unwrap() = Some(SleepTimer::new(Instant::now(), after, mode));
+    }
+
+    /// How long until the sleep timer is up, which is zero while waiting
+    /// for the last song to finish, or `None` without a timer
+    pub fn sleep_timer_remaining(&self) -> Option<Duration> {
+        let timer = self.sleep_timer.lock().unwrap();
+        timer.as_ref().map(|timer| timer.remaining(Instant::now()))
+    }
+
+    /// Stop the sleep timer, putting the volume back if it was fading
+    pub fn cancel_sleep_timer(&self) {
+        let Some(timer) = self.sleep_timer.lock().unwrap().take() else {
+            return;
+        };
+        if timer.is_armed() {
+            self.modes.write().unwrap().stop_after_current = false;
+        }
+        if let Some(volume) = timer.cancel() {
+            self.player.lock().unwrap().set_volume(volume);
+        }
+        let _ = self.sleep_tx.try_send(SleepEvent::Cancelled);
+    }
+
+    /// Receive every [ControllerEvent] from now on. Any number of
+    /// frontends can subscribe at once, see [EventBus] for what happens
+    /// to ones which fall behind.
+    pub fn subscribe(&self) -> Receiver<ControllerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Show track change notifications with `notifier` instead of the
+    /// platform's notification system
+    pub fn set_notifier(&self, notifier: Box<dyn Notifier>) {
+        *self.notifier.lock().unwrap() = notifier;
+    }
+
+    /// Set the volume of the player, from `0` to `1`
+    pub fn set_volume(&self, volume: f64) {
+        let mut player = self.player.lock().unwrap();
+        player.set_volume(volume);
+        set_cast_volume(&self.cast, player.volume());
+        self.events.publish(ControllerEvent::VolumeChanged(player.volume()));
+    }
+
+    /// Mute or unmute the player, putting back the volume from before it
+    /// was muted
+    pub fn set_muted(&self, muted: bool) {
+        let mut player = self.player.lock().unwrap();
+        match muted {
+            true => player.mute(),
+            false => player.unmute(),
+        }
+        set_cast_volume(&self.cast, player.volume());
+        self.events.publish(ControllerEvent::VolumeChanged(player.volume()));
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.player.lock().unwrap().is_muted()
+    }
+
+    /// The volume the player is using, from `0` to `1`
+    pub fn volume(&self) -> f64 {
+        self.player.lock().unwrap().volume()
+    }
+
+    /// The library, to be queried. Changes go through the controller, and
+    /// playback waits for the library while it's held.
+    pub fn library(&self) -> RwLockReadGuard<'_, MusicLibrary> {
+        self.library.read().unwrap()
+    }
+
+    /// The subscribed podcasts, held like [Controller::library]
+    pub fn podcasts(&self) -> RwLockReadGuard<'_, Podcasts> {
+        self.podcasts.read().unwrap()
+    }
+
+    /// A copy of the config as it is now
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Resume playback, on the cast device while casting
+    pub fn play(&self) -> Result<(), ControllerError> {
+        self.still_listening();
+        match self.cast.lock().unwrap().as_mut() {
+            Some(cast) => cast.play()?,
+            None => self.player.lock().unwrap().play()?,
+        }
+        self.events.publish(ControllerEvent::Resumed);
+        Ok(())
+    }
+
+    /// Pause playback, on the cast device while casting
+    pub fn pause(&self) -> Result<(), ControllerError> {
+        match self.cast.lock().unwrap().as_mut() {
+            Some(cast) => cast.pause()?,
+            None => self.player.lock().unwrap().pause()?,
+        }
+        self.events.publish(ControllerEvent::Paused);
+        Ok(())
+    }
+
+    /// Whether playback is paused, on the cast device while casting
+    pub fn is_paused(&self) -> bool {
+        match self.cast.lock().unwrap().as_ref() {
+            Some(cast) => cast.is_paused(),
+            None => self.player.lock().unwrap().is_paused(),
+        }
+    }
+
+    /// Stop playback, on the cast device while casting
+    pub fn stop(&self) -> Result<(), ControllerError> {
+        match self.cast.lock().unwrap().as_mut() {
+            Some(cast) => cast.stop()?,
+            None => self.player.lock().unwrap().stop()?,
+        }
+        *self.playing.write().unwrap() = None;
+        self.events.publish(ControllerEvent::Stopped);
+        Ok(())
+    }
+
+    /// Seek within the current song, on the cast device while casting
+    pub fn seek_to(&self, position: Duration) -> Result<(), ControllerError> {
+        match self.cast.lock().unwrap().as_mut() {
+            Some(cast) => cast.seek(position)?,
+            None => {
+                let position = chrono::Duration::from_std(position)
+                    .map_err(|e| PlayerError::Seek(e.to_string()))?;
+                self.player.lock().unwrap().seek_to(position)?
+            }
+        }
+        Ok(())
+    }
+
+    /// How far through the current song playback is, on the cast device
+    /// while casting
+    pub fn position(&self) -> Option<Duration> {
+        match self.cast.lock().unwrap().as_mut() {
+            Some(cast) => cast.position().unwrap_or_else(|error| {
+                println!("Cast: could not get the position: {}", error);
+                None
+            }),
+            None => self.player.lock().unwrap().position().and_then(|pos| pos.to_std().ok()),
+        }
+    }
+
+    /// Redirect playback to a cast device found with
+    /// [discover](super::cast::discover). Whatever is playing carries on
+    /// from the same position on the device, and the local player is
+    /// paused. Songs in the library are served to the device over HTTP.
+    ///
+    /// The device doesn't move on through the queue by itself, frontends
+    /// play the next song with [Controller::play_song] as they would after
+    /// it finishes locally.
+    pub fn cast_to(&self, device: CastDevice) -> Result<(), ControllerError> {
+        let mut session = CastSession::start(device, self.library.clone())?;
+
+        let mut player = self.player.lock().unwrap();
+        if let Err(error) = session.set_volume(player.volume()) {
+            println!("Cast: could not set the volume: {}", error);
+        }
+        if let Some(uri) = player.source().clone() {
+            let position = player.position().and_then(|pos| pos.to_std().ok()).unwrap_or_default();
+            let library = self.library.read().unwrap();
+            let song = library.query_uri(&uri).map(|(song, _)| song);
+            session.load(&uri, song, position)?;
+            if player.is_paused() {
+                session.pause()?;
+            }
+            player.pause()?;
+        }
+
+        // Stopping an earlier session first, so two devices don't play
+        if let Some(mut previous) = self.cast.lock().unwrap().replace(session) {
+            if let Err(error) = previous.stop() {
+                println!("Cast: could not stop {}: {}", previous.device().name, error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop casting, and bring playback back to the local player where the
+    /// device left off. The local player is left paused.
+    pub fn stop_casting(&self) -> Result<(), ControllerError> {
+        let Some(mut session) = self.cast.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let position = session.position().unwrap_or_default();
+        let loaded = session.loaded().cloned();
+        session.stop()?;
+
+        let mut player = self.player.lock().unwrap();
+        if let (Some(position), true) = (position, loaded.is_some() && loaded == *player.source()) {
+            let position = chrono::Duration::from_std(position)
+                .map_err(|e| PlayerError::Seek(e.to_string()))?;
+            player.seek_to(position)?;
+        }
+        Ok(())
+    }
+
+    /// The device playback is cast to, if it is
+    pub fn casting(&self) -> Option<CastDevice> {
+        self.cast.lock().unwrap().as_ref().map(|cast| cast.device().clone())
+    }
+
+    /// Set the highest volume the player can be set to, `None` to remove
+    /// it, and save it to the config
+    pub fn set_volume_cap(&mut self, cap: Option<f64>) -> Result<(), ControllerError> {
+        {
+            let mut player = self.player.lock().unwrap();
+            player.set_volume_cap(cap.unwrap_or(1.0));
+            self.events.publish(ControllerEvent::VolumeChanged(player.volume()));
+        }
+        let mut config = self.config.write().unwrap();
+        config.volume_cap = cap;
+        config.write_file()?;
+        Ok(())
+    }
+
+    /// The lyrics of the current song, if it has any
+    pub fn current_lyrics(&self) -> Option<Lyrics> {
+        let player = self.player.lock().unwrap();
+        let library = self.library.read().unwrap();
+        let (song, _) = library.query_uri(player.source().as_ref()?)?;
+        song.lyrics().cloned()
+    }
+
+    /// The line of the current song's synchronized lyrics being sung now,
+    /// as heard after the [latency offset](Config::latency_offset)
+    pub fn playing_lyric_line(&self) -> Option<LyricLine> {
+        let position = self.player.lock().unwrap().position()?.to_std().ok()?;
+        self.current_lyric_line(position)
+    }
+
+    /// The line of the current song's synchronized lyrics being sung at
+    /// `position`, for showing lyrics in time with the song
+    pub fn current_lyric_line(&self, position: Duration) -> Option<LyricLine> {
+        let player = self.player.lock().unwrap();
+        let library = self.library.read().unwrap();
+        let (song, _) = library.query_uri(player.source().as_ref()?)?;
+        song.lyrics()?.line_at(position).map(|(_, line)| line.clone())
+    }
+
+    /// The name of the audio profile which is currently scheduled
+    pub fn active_profile(&self) -> Option<String> {
+        self.active_profile.read().unwrap().clone()
+    }
+
+    /// Start or end a private session, during which plays aren't scrobbled
+    /// or recorded in the history. Private sessions always end when the
+    /// controller is restarted.
+    pub fn set_private_session(&self, private: bool) {
+        if let Some(event) = self.private_session.set(private) {
+            let _ = self.private_tx.try_send(event);
+        }
+    }
+
+    pub fn is_private_session(&self) -> bool {
+        self.private_session.is_active()
+    }
+
+    /// Let the controller know that someone is using the player, which
+    /// stops unattended playback from being paused for a while. Frontends
+    /// should call this for commands which go straight to the player, and
+    /// when an [IdleEvent::Warning] prompt is answered.
+    pub fn still_listening(&self) {
+        self.idle.lock().unwrap().activity(Instant::now());
+    }
+
+    /// Take a snapshot of the queue, modes, what is playing and the most
+    /// recent history. Everything is locked while the snapshot is taken,
+    /// so every part of it describes the same moment.
+    pub fn dump_state(&self) -> Result<StateSnapshot, ControllerError> {
+        let mut history = self.history.entries()?;
+        history.drain(..history.len().saturating_sub(HISTORY_TAIL));
+
+        // Locked in the same order as `update_modes` to avoid deadlocks
+        let modes = self.modes.read().unwrap();
+        let queue = self.queue.read().unwrap();
+        let player = self.player.lock().unwrap();
+        let library = self.library.read().unwrap();
+
+        let now_playing = NowPlaying::capture(&*player, &library, *self.gain.read().unwrap());
+        let private = self.private_session.is_active();
+        Ok(StateSnapshot::capture(&queue, &modes, private, now_playing, history))
+    }
+
+    /// Add a path remapping rule, saving it to the config and applying
+    /// it to the library. Returns the number of songs which were changed.
+    pub fn add_remap_rule(&mut self, from: String, to: String) -> Result<usize, ControllerError> {
+        let remap = {
+            let mut config = self.config.write().unwrap();
+            config.path_remap.add_rule(from, to);
+            config.write_file()?;
+            config.path_remap.clone()
+        };
+
+        Ok(self.library.write().unwrap().apply_remap(&remap))
+    }
+
+    /// Remove the path remapping rule at `index`, saving the config.
+    ///
+    /// Songs which were already remapped keep their new paths.
+    pub fn remove_remap_rule(&mut self, index: usize) -> Result<Option<RemapRule>, ControllerError> {
+        let mut config = self.config.write().unwrap();
+        let removed = config.path_remap.remove_rule(index);
+        config.write_file()?;
+        Ok(removed)
+    }
+
+    /// Clear every cache, returning the number of bytes reclaimed
+    pub fn clear_caches(&self) -> Result<u64, std::io::Error> {
+        self.caches.clear_caches()
+    }
+}
+
+impl<P: Player + Send + Sync> Controller<P> {
+    /// Read the config file again and apply the settings which changed,
+    /// returning their names. Changes to the file are also picked up on
+    /// their own after a few seconds.
+    pub fn reload_config(&self) -> Result<Vec<String>, ControllerError> {
+        // Applied without holding the config, see the file watcher in `Controller::start`
+        let (config, changed) = {
+            let mut config = self.config.write().unwrap();
+            let changed = config.reload()?;
+            (config.clone(), changed)
+        };
+        apply_config(
+            &config,
+            &changed,
+            &self.player,
+            &self.library,
+            &self.modes,
+            &self.gain,
+            &self.power,
+            &self.caches,
+            &self.events,
+        );
+        for field in &changed {
+            let _ = self.config_tx.try_send(ConfigEvent::Changed(field.clone()));
+        }
+        Ok(changed)
+    }
+
+    /// Pick whether to save energy by hand, or go back to picking it from
+    /// the battery with `None`
+    pub fn set_power_mode(&self, manual: Option<PowerMode>) {
+        *self.manual_power.write().unwrap() = manual;
+        let on_battery = on_battery();
+        let mode = self.config.read().unwrap().power.mode(manual, on_battery);
+        switch_power(mode, on_battery, &self.power, &self.player, &self.config, &self.power_tx);
+    }
+
+    /// Whether energy is being saved
+    pub fn power_mode(&self) -> PowerMode {
+        *self.power.read().unwrap()
+    }
+
+    /// Save the current state of playback to the session file
+    pub fn save_session(&self) -> Result<(), ControllerError> {
+        let path = Session::path(&self.config.read().unwrap());
+        let modes = *self.modes.read().unwrap();
+        let session = Session::capture(&self.queue.read().unwrap(), &*self.player.lock().unwrap(), &modes);
+        session.write_file(&path)?;
+        Ok(())
+    }
+}
+
+/// Make changes to the queue, either all of them or none if one fails,
+/// and tell listeners about them
+fn change_queue(
+    queue: &RwLock<PlayQueue>,
+    ops: Vec<QueueOp>,
+    invariants: Option<&QueueInvariants>,
+    queue_tx: &Sender<QueueEvent>,
+    events: &EventBus,
+) -> Result<(), ControllerError> {
+    let changes = {
+        let mut queue = queue.write().unwrap();
+        let mut changed = queue.clone();
+        let mut changes = Vec::new();
+        for op in ops {
+            let (next, events) = apply(&changed, op)?;
+            changed = next;
+            changes.extend(events);
+        }
+        if let Some(invariants) = invariants {
+            let violations = invariants.check(&changed);
+            if !violations.is_empty() {
+                return Err(ControllerError::QueueInvariant(violations));
+            }
+        }
+        *queue = changed;
+        changes
+    };
+    for event in changes {
+        let _ = queue_tx.try_send(event.clone());
+        events.publish(ControllerEvent::QueueChanged(event));
+    }
+    Ok(())
+}
+
+/// Set the volume of the cast device, if playback is being cast
+fn set_cast_volume(cast: &Mutex<Option<CastSession>>, volume: f64) {
+    if let Some(cast) = cast.lock().unwrap().as_mut() {
+        if let Err(error) = cast.set_volume(volume) {
+            println!("Cast: could not set the volume: {}", error);
+        }
+    }
+}
+
+/// The songs an alarm plays, leaving out ones which can't be played
+fn alarm_songs(source: &AlarmSource, library: &MusicLibrary) -> Vec<QueueSong> {
+    let (uuids, location, queue_source) = match source {
+        AlarmSource::Song(uuid) => (vec![*uuid], PlayerLocation::Library, QueueSource::User),
+        AlarmSource::Playlist(uuid) => {
+            let tracks = library.playlists.playlist(uuid).map(|playlist| playlist.tracks()).unwrap_or_default();
+            (tracks, PlayerLocation::Playlist(*uuid), QueueSource::Playlist(*uuid))
+        }
+    };
+    uuids
+        .iter()
+        .filter(|uuid| !library.is_offline(uuid))
+        .filter_map(|uuid| library.query_uuid(uuid))
+        .map(|(song, _)| QueueSong { song: song.clone(), location, source: queue_source })
+        .collect()
+}
+
+/// Replace the queue with the `songs` of an alarm, and start playing the
+/// first of them at `volume`
+fn start_alarm<P: Player>(
+    songs: Vec<QueueSong>,
+    volume: f64,
+    player: &Mutex<P>,
+    remotes: &[Box<dyn RemoteLibrary>],
+    queue: &RwLock<PlayQueue>,
+    queue_tx: &Sender<QueueEvent>,
+    events: &EventBus,
+) -> Result<(), ControllerError> {
+    let first = songs.first().ok_or(PlayerError::NotFound)?;
+    let uuid = first.song.uuid;
+    let uri = match first.song.primary_uri() {
+        Ok((uri, _)) => uri.clone(),
+        Err(_) => return Err(PlayerError::NotFound.into()),
+    };
+    let resolved = remote::resolve_uri(remotes, &uri).map_err(|e| ControllerError::RemoteError(e.to_string()))?;
+
+    let ops = std::iter::once(QueueOp::Clear).chain(songs.into_iter().map(QueueOp::Add)).collect();
+    change_queue(queue, ops, None, queue_tx, events)?;
+
+    let mut player = player.lock().unwrap();
+    player.set_volume(volume);
+    player.enqueue_next(&resolved)?;
+    player.play()?;
+    events.publish(ControllerEvent::TrackChanged { uuid: Some(uuid), uri });
+    Ok(())
+}
+
+/// Switch to the power `mode` if it isn't already being used
+fn switch_power<P: Player>(
+    mode: PowerMode,
+    on_battery: Option<bool>,
+    power: &RwLock<PowerMode>,
+    player: &Mutex<P>,
+    config: &RwLock<Config>,
+    power_tx: &Sender<PowerEvent>,
+) {
+    if *power.read().unwrap() == mode {
+        return;
+    }
+    *power.write().unwrap() = mode;
+    // The player is never locked while holding the config
+    let config = config.read().unwrap().clone();
+    player.lock().unwrap().set_poll_interval(mode.interval(POSITION_POLL_INTERVAL));
+    set_visualizer(&mut *player.lock().unwrap(), &config, mode);
+    let _ = power_tx.try_send(PowerEvent::Switched { mode, on_battery });
+}
+
+/// Set how long before the end of each song it's announced, and how songs
+/// fade, which both depend on the crossfade of the playback modes
+fn set_transition_lead<P: Player>(player: &mut P, config: &Config, modes: &PlaybackModes) {
+    player.set_transition_lead(transition_lead(config.transition_lead, modes.crossfade));
+    player.set_crossfade(config.crossfade.crossfade(modes.crossfade));
+}
+
+/// Whether plays of `song` are reported to its server and scrobbled
+fn scrobbles(song: &Song, ignore: &ConfigIgnore, private: bool) -> bool {
+    !private && ignore.tracks(song, &DoNotTrack::Scrobbling)
+}
+
+/// What [record_listen] made of a listen
+struct Listen {
+    /// The song's [Uuid], if it's in the library
+    uuid: Option<Uuid>,
+    scrobble: bool,
+    /// Whether it counted as a play rather than a skip
+    played: bool,
+}
+
+/// Count `listened` of the song at `uri` as a play or a skip, adding it to
+/// the history if it was played, unless it isn't tracked
+fn record_listen(
+    library: &RwLock<MusicLibrary>,
+    history: &History,
+    ignore: &ConfigIgnore,
+    private: bool,
+    uri: &URI,
+    listened: Duration,
+) -> Listen {
+    let mut library = library.write().unwrap();
+    let song = library.query_uri(uri).map(|(song, _)| song);
+    let uuid = song.map(|song| song.uuid);
+    let tracks = |kind| !private && song.is_none_or(|song| ignore.tracks(song, &kind));
+    let (scrobble, count) = (tracks(DoNotTrack::Scrobbling), tracks(DoNotTrack::History));
+
+    let mut played = false;
+    if let (Some(uuid), true) = (uuid, count) {
+        if library.record_listen(&uuid, listened) == Some(true) {
+            played = true;
+            let (song, _) = library.query_uuid(&uuid).unwrap();
+            if let Err(error) = history.record(&HistoryEntry::new(song, listened)) {
+                println!("Failed to record history: {}", error);
+            }
+        }
+    }
+    Listen { uuid, scrobble, played }
+}
+
+/// Save `library` to the file of the default library in the `config`
+fn save_library(library: &RwLock<MusicLibrary>, config: &RwLock<Config>) -> Result<(), Box<dyn Error>> {
+    let default = config.read().unwrap().libraries.get_default()?.clone();
+    let library = library.read().unwrap();
+    match default.relative_paths {
+        true => library.save_relative(default.path),
+        false => library.save(default.path),
+    }
+}
+
+/// The next song of the queue once it has been loaded, or failed to, on
+/// its own thread by [load_next]
+struct LoadedTrack {
+    uuid: Uuid,
+    uri: URI,
+    resume: Option<Duration>,
+    replay_gain: ReplayGain,
+    scrobble: bool,
+    /// Which step failed, resolving or loading, and why
+    result: Result<(), (&'static str, String)>,
+}
+
+/// Resolve `uri` and load it into the player, waiting for it to preroll
+/// without holding onto the player
+fn load_next<P: Player>(
+    player: &Mutex<P>,
+    remotes: &[Box<dyn RemoteLibrary>],
+    config: &RwLock<Config>,
+    uri: &URI,
+    trim: Option<(Duration, Duration)>,
+    kind: TrackKind,
+) -> Result<(), (&'static str, String)> {
+    let resolved = remote::resolve_uri(remotes, uri).map_err(|error| ("resolve", error.to_string()))?;
+    let loading = {
+        let mut player = player.lock().unwrap();
+        player.set_next_trim(trim);
+        set_skip_silence(&mut *player, &config.read().unwrap(), kind);
+        player.load(&resolved)
+    };
+    loading.and_then(|handle| handle.wait()).map_err(|error| ("load", error.to_string()))
+}
+
+/// Quarantine a song which failed to play with an error, or release one
+/// which played, saving the quarantine if it changed
+fn update_quarantine(quarantine: &RwLock<Quarantine>, path: &Path, uuid: Uuid, result: Result<(), String>) {
+    let mut quarantine = quarantine.write().unwrap();
+    match result {
+        Ok(()) if quarantine.release(&uuid).is_none() => return,
+        Ok(()) => (),
+        Err(error) => quarantine.add(uuid, error, Utc::now()),
+    }
+    if let Err(error) = quarantine.write_file(path) {
+        println!("Failed to save the quarantine: {}", error);
+    }
+}
+
+/// Measure the output for visualizers if the config asks for it, unless
+/// energy is being saved
+fn set_visualizer<P: Player>(player: &mut P, config: &Config, mode: PowerMode) {
+    let visualizer = Visualizer { enabled: config.visualizer.enabled && mode.visuals(), ..config.visualizer };
+    if let Err(error) = player.set_visualizer(visualizer) {
+        println!("Failed to set up the visualizer: {}", error);
+    }
+}
+
+/// The part of `song` to play with the silence at its start and end
+/// skipped, if the library is set to trim it
+fn silence_trim(config: &Config, song: &Song) -> Option<(Duration, Duration)> {
+    let enabled = config.libraries.get_default().is_ok_and(|library| library.settings.trim_silence);
+    enabled.then(|| song.trimmed_range()).flatten()
+}
+
+/// Skip silence in the next track if the config asks for it in tracks of `kind`
+fn set_skip_silence<P: Player>(player: &mut P, config: &Config, kind: TrackKind) {
+    if let Err(error) = player.set_skip_silence(config.skip_silence.minimum_gap(kind)) {
+        println!("Failed to set up skipping silence: {}", error);
+    }
+}
+
+/// Send the output through the custom filter chain of the config, if it has one
+fn set_filter_chain<P: Player>(player: &mut P, config: &Config) {
+    if let Err(error) = player.set_filter_chain(config.filter_chain.as_deref()) {
+        println!("Failed to set the filter chain: {}", error);
+    }
+}
+
+/// Set the balance and mono downmix of the output from the config
+fn set_channels<P: Player>(player: &mut P, config: &Config) {
+    if let Err(error) = player.set_balance(config.balance) {
+        println!("Failed to set the balance: {}", error);
+    }
+    if let Err(error) = player.set_mono(config.mono) {
+        println!("Failed to set the mono downmix: {}", error);
+    }
+}
+
+/// Let frontends know when `error` was caused by running out of disk space
+fn publish_disk_space(events: &EventBus, error: &(dyn std::error::Error + 'static)) {
+    if let Some(&DiskSpaceError::InsufficientSpace { required, available, reserve }) = DiskSpaceError::find(error) {
+        events.publish(ControllerEvent::DiskSpaceLow { required, available, reserve });
+    }
+}
+
+/// Apply the settings named in `changed` to the running player and library.
+/// Settings which are only read when needed apply without doing anything.
+#[allow(clippy::too_many_arguments)]
+fn apply_config<P: Player>(
+    config: &Config,
+    changed: &[String],
+    player: &Mutex<P>,
+    library: &RwLock<MusicLibrary>,
+    modes: &RwLock<PlaybackModes>,
+    gain: &RwLock<Option<AppliedGain>>,
+    power: &RwLock<PowerMode>,
+    caches: &Caches,
+    events: &EventBus,
+) {
+    for field in changed {
+        match field.as_str() {
+            "volume" => {
+                let mut player = player.lock().unwrap();
+                player.set_volume(config.volume as f64);
+                events.publish(ControllerEvent::VolumeChanged(player.volume()));
+            }
+            "volume_cap" => {
+                let mut player = player.lock().unwrap();
+                player.set_volume_cap(config.volume_cap.unwrap_or(1.0));
+                events.publish(ControllerEvent::VolumeChanged(player.volume()));
+            }
+            "volume_curve" => player.lock().unwrap().set_volume_curve(config.volume_curve),
+            "balance" | "mono" => set_channels(&mut *player.lock().unwrap(), config),
+            "filter_chain" => set_filter_chain(&mut *player.lock().unwrap(), config),
+            "pause_fade" => player.lock().unwrap().set_pause_fade(config.pause_fade),
+            "seek_mode" => player.lock().unwrap().set_seek_mode(config.seek_mode),
+            "latency_offset" => player.lock().unwrap().set_latency_offset(config.latency_offset),
+            "visualizer" => set_visualizer(&mut *player.lock().unwrap(), config, *power.read().unwrap()),
+            "transition_lead" | "crossfade" => set_transition_lead(&mut *player.lock().unwrap(), config, &modes.read().unwrap()),
+            "output" => {
+                if let Err(error) = player.lock().unwrap().set_output(config.output.clone()) {
+                    println!("Failed to switch the audio output: {}", error);
+                }
+            }
+            "libraries" => {
+                let Ok(default) = config.libraries.get_default() else {
+                    continue;
+                };
+                let mut library = library.write().unwrap();
+                if library.uuid != default.uuid {
+                    match MusicLibrary::init(default.path.clone(), default.uuid) {
+                        Ok(opened) => {
+                            *library = opened;
+                            library.apply_remap(&config.path_remap);
+                        }
+                        Err(error) => println!("Failed to open the library: {}", error),
+                    }
+                }
+                library.refresh_offline(&default.roots);
+                events.publish(ControllerEvent::LibraryChanged);
+            }
+            "caches" => caches.set_limits(&config.caches),
+            "disk" => caches.set_reserve(config.disk.reserve),
+            "path_remap" => {
+                library.write().unwrap().apply_remap(&config.path_remap);
+                events.publish(ControllerEvent::LibraryChanged);
+            }
+            "replay_gain" => {
+                let mut player = player.lock().unwrap();
+                let replay_gain = player.source().as_ref().and_then(|source| {
+                    let library = library.read().unwrap();
+                    library.query_uri(source).map(|(song, _)| ReplayGain::from_song(song))
+                });
+                let normalization = modes.read().unwrap().normalization;
+                *gain.write().unwrap() = set_player_gain(&mut *player, replay_gain, normalization, &config.replay_gain);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<P: Player + Send + Sync> Drop for Controller<P> {
+    fn drop(&mut self) {
+        if let Err(error) = self.save_session() {
+            println!("Failed to save session: {}", error);
+        }
+        if let Err(error) = save_library(&self.library, &self.config) {
+            println!("Failed to save the library: {}", error);
+        }
     }
 }
 
@@ -162,10 +2312,38 @@ impl<P: Player + Send + Sync + Sized + 'static> Controller<P> {
 mod test_super {
     use std::{thread::sleep, time::Duration};
 
-    use crate::{config::tests::read_config_lib, music_controller::controller::{PlayerLocation, QueueSong}, music_player::{gstreamer::GStreamer, player::Player}};
+    use crate::{config::tests::read_config_lib, music_controller::{controller::{PlayerLocation, QueueSong}, queue::QueueSource}, music_player::{gstreamer::GStreamer, player::Player}};
 
     use super::Controller;
 
+    #[test]
+    fn skips_and_plays() {
+        use std::sync::RwLock;
+        use uuid::Uuid;
+        use crate::music_controller::{history::History, ignore::ConfigIgnore};
+        use crate::music_storage::library::{test::test_song, MusicLibrary};
+
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(dir.path().join("history"));
+        let mut library = MusicLibrary::init(dir.path().join("library.dlib"), Uuid::new_v4()).unwrap();
+        let song = test_song("Song", "Artist", Duration::from_secs(200));
+        let uri = song.location[0].clone();
+        library.library.push(song);
+        let library = RwLock::new(library);
+        let ignore = ConfigIgnore::default();
+
+        // Skipping partway through counts as a skip, and isn't in the history
+        let skip = super::record_listen(&library, &history, &ignore, false, &uri, Duration::from_secs(5));
+        assert!(skip.uuid.is_some() && skip.scrobble && !skip.played);
+        assert!(super::record_listen(&library, &history, &ignore, false, &uri, Duration::from_secs(150)).played);
+        // Nothing is counted during a private session
+        super::record_listen(&library, &history, &ignore, true, &uri, Duration::from_secs(150));
+
+        let song = &library.read().unwrap().library[0];
+        assert_eq!((song.skips, song.plays), (1, 1));
+        assert_eq!(history.entries().unwrap().len(), 1);
+    }
+
     #[test]
     fn construct_controller() {
         println!("starto!");
@@ -177,7 +2355,7 @@ mod test_super {
             {
                 let mut queue = controller.queue.write().unwrap();
                 for x in config.1.library {
-                    queue.add_item(QueueSong { song: x, location: PlayerLocation::Library }, true);
+                    queue.add_item(QueueSong { song: x, location: PlayerLocation::Library, source: QueueSource::User }, true);
                 }
             }
             {
@@ -0,0 +1,282 @@
+//! Showing what's playing on Discord through Rich Presence, updated when
+//! the track changes and cleared while paused or stopped
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::Receiver;
+use discord_rpc_client::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::music_player::player::Player;
+use crate::music_storage::library::{MusicLibrary, Song, Tag, URI};
+use crate::music_storage::podcast::{Episode, Podcast, Podcasts};
+
+use super::events::ControllerEvent;
+
+/// The asset shown when a track has no art of its own, which has to be
+/// uploaded to the Discord application under this key
+pub const FALLBACK_IMAGE: &str = "dmp";
+
+/// How far the position can drift from the shown timestamps before they
+/// are updated, so seeking is picked up
+const SEEK_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Whether the playing track is shown on Discord, stored in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigDiscord {
+    pub enabled: bool,
+    /// The ID of the Discord application the presence is shown as
+    pub application_id: Option<u64>,
+}
+
+/// What is shown on Discord for a track
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub details: String,
+    pub state: Option<String>,
+    /// A URL to the art, or [FALLBACK_IMAGE]
+    pub large_image: String,
+    pub large_text: Option<String>,
+    /// The Unix time the track would have started at to be at its
+    /// current position now, in seconds
+    pub start: u64,
+    /// The Unix time the track finishes at, if its length is known
+    pub end: Option<u64>,
+}
+
+impl Presence {
+    pub fn of_song(song: &Song, position: Duration, now: SystemTime) -> Self {
+        let large_image = song
+            .get_tag(&Tag::MusicBrainzReleaseId)
+            .map(|mbid| format!("https://coverartarchive.org/release/{}/front-250", mbid))
+            .unwrap_or_else(|| FALLBACK_IMAGE.to_string());
+        let title = song
+            .get_tag(&Tag::Title)
+            .cloned()
+            .or_else(|| Some(song.location.first()?.path().file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+
+        let (start, end) = timestamps(position, Some(song.duration), now);
+        Presence {
+            details: title,
+            state: song.get_tag(&Tag::Artist).map(|artist| format!("by {}", artist)),
+            large_image,
+            large_text: song.get_tag(&Tag::Album).cloned(),
+            start,
+            end,
+        }
+    }
+
+    pub fn of_episode(podcast: &Podcast, episode: &Episode, position: Duration, now: SystemTime) -> Self {
+        let (start, end) = timestamps(position, episode.duration, now);
+        Presence {
+            details: episode.title.clone(),
+            state: Some(podcast.title.clone()),
+            large_image: podcast.image.clone().unwrap_or_else(|| FALLBACK_IMAGE.to_string()),
+            large_text: Some(podcast.title.clone()),
+            start,
+            end,
+        }
+    }
+
+    /// Whether playback at `position` has moved away from where these
+    /// timestamps say it should be
+    fn drifted(&self, position: Duration, now: SystemTime) -> bool {
+        let (start, _) = timestamps(position, None, now);
+        start.abs_diff(self.start) > SEEK_TOLERANCE.as_secs()
+    }
+}
+
+/// The start and end of a track as Unix times, from how far into it
+/// playback is at `now`
+fn timestamps(position: Duration, duration: Option<Duration>, now: SystemTime) -> (u64, Option<u64>) {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let start = now.saturating_sub(position).as_secs();
+    (start, duration.map(|duration| start + duration.as_secs()))
+}
+
+/// The connection to the Discord client, made once presence is enabled
+pub struct DiscordPresence {
+    client: Option<(u64, Client)>,
+    shown: Option<Presence>,
+}
+
+impl DiscordPresence {
+    pub fn new() -> Self {
+        DiscordPresence { client: None, shown: None }
+    }
+
+    /// Show `presence` as the application `application_id`
+    pub fn show(&mut self, application_id: u64, presence: Presence) {
+        if self.client.as_ref().is_none_or(|(id, _)| *id != application_id) {
+            self.clear();
+            let mut client = Client::new(application_id);
+            client.start();
+            self.client = Some((application_id, client));
+        }
+        let (_, client) = self.client.as_mut().unwrap();
+
+        let shown = presence.clone();
+        let result = client.set_activity(|activity| {
+            let activity = activity.details(presence.details).timestamps(|time| match presence.end {
+                Some(end) => time.start(presence.start).end(end),
+                None => time.start(presence.start),
+            });
+            let activity = match presence.state {
+                Some(state) => activity.state(state),
+                None => activity,
+            };
+            activity.assets(|assets| {
+                let assets = assets.large_image(presence.large_image);
+                match presence.large_text {
+                    Some(text) => assets.large_text(text),
+                    None => assets,
+                }
+            })
+        });
+        match result {
+            Ok(_) => self.shown = Some(shown),
+            Err(error) => println!("Discord: could not set the presence: {}", error),
+        }
+    }
+
+    /// Stop showing anything
+    pub fn clear(&mut self) {
+        if self.shown.take().is_none() {
+            return;
+        }
+        if let Some((_, client)) = self.client.as_mut() {
+            if let Err(error) = client.clear_activity() {
+                println!("Discord: could not clear the presence: {}", error);
+            }
+        }
+    }
+
+    /// What is currently shown
+    pub fn shown(&self) -> Option<&Presence> {
+        self.shown.as_ref()
+    }
+}
+
+impl Default for DiscordPresence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keep the presence up to date with the controller's `events` until
+/// the controller is gone, changes to the config are picked up from the
+/// next event. While Discord isn't running this waits for it to start.
+pub(super) fn publish_presence<P: Player>(
+    events: Receiver<ControllerEvent>,
+    config: Arc<RwLock<Config>>,
+    library: Arc<RwLock<MusicLibrary>>,
+    podcasts: Arc<RwLock<Podcasts>>,
+    player: Arc<Mutex<P>>,
+) {
+    let mut discord = DiscordPresence::new();
+    // What is playing, kept while paused to be shown again on resuming
+    let mut current: Option<URI> = None;
+
+    for event in events {
+        match &event {
+            ControllerEvent::TrackChanged { uri, .. } => current = Some(uri.clone()),
+            ControllerEvent::Stopped => current = None,
+            _ => (),
+        }
+        let settings = config.read().unwrap().connections.discord.clone();
+        let application_id = match settings.application_id {
+            Some(id) if settings.enabled => id,
+            _ => {
+                discord.clear();
+                discord.client = None;
+                continue;
+            }
+        };
+
+        match event {
+            ControllerEvent::TrackChanged { .. } | ControllerEvent::Resumed => (),
+            // Also shows the track once presence has just been turned on
+            ControllerEvent::PositionTick { position, .. } => match discord.shown() {
+                Some(shown) if !shown.drifted(position, SystemTime::now()) => continue,
+                _ => (),
+            },
+            ControllerEvent::Paused | ControllerEvent::Stopped => {
+                discord.clear();
+                continue;
+            }
+            _ => continue,
+        }
+
+        let Some(uri) = current.as_ref() else { continue };
+        let (position, now) = (track_position(&player, uri), SystemTime::now());
+        let presence = match library.read().unwrap().query_uri(uri) {
+            Some((song, _)) => Some(Presence::of_song(song, position, now)),
+            None => podcasts
+                .read()
+                .unwrap()
+                .episode_at(uri)
+                .map(|(podcast, episode)| Presence::of_episode(podcast, episode, position, now)),
+        };
+        match presence {
+            Some(presence) => discord.show(application_id, presence),
+            None => discord.clear(),
+        }
+    }
+    discord.clear();
+}
+
+/// How far into the track at `uri` the player is, from the start of the
+/// track rather than the file for tracks of a cue sheet
+fn track_position<P: Player>(player: &Mutex<P>, uri: &URI) -> Duration {
+    let position = player
+        .lock()
+        .unwrap()
+        .position()
+        .and_then(|pos| pos.to_std().ok())
+        .unwrap_or_default();
+    match uri {
+        URI::Cue { start, .. } => position.saturating_sub(*start),
+        _ => position,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{Presence, FALLBACK_IMAGE};
+    use crate::music_storage::library::{test::test_song, Tag};
+    use crate::music_storage::podcast::{Episode, Podcast};
+
+    #[test]
+    fn discord_presence() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut song = test_song("Song", "Artist", Duration::from_secs(200));
+        let presence = Presence::of_song(&song, Duration::from_secs(50), now);
+        assert_eq!(presence.details, "Song");
+        assert_eq!(presence.state.as_deref(), Some("by Artist"));
+        assert_eq!(presence.large_image, FALLBACK_IMAGE);
+        assert_eq!((presence.start, presence.end), (999_950, Some(1_000_150)));
+
+        song.set_tag(Tag::MusicBrainzReleaseId, "mbid".to_string());
+        song.set_tag(Tag::Album, "Album".to_string());
+        let presence = Presence::of_song(&song, Duration::ZERO, now);
+        assert_eq!(presence.large_image, "https://coverartarchive.org/release/mbid/front-250");
+        assert_eq!(presence.large_text.as_deref(), Some("Album"));
+
+        // Seeking moves the timestamps once it's past the tolerance
+        assert!(!presence.drifted(Duration::from_secs(1), now));
+        assert!(presence.drifted(Duration::from_secs(30), now));
+
+        let podcast = Podcast { title: "Podcast".to_string(), image: Some("http://art".to_string()), ..Default::default() };
+        let episode = Episode { title: "Episode".to_string(), ..Default::default() };
+        let presence = Presence::of_episode(&podcast, &episode, Duration::from_secs(10), now);
+        assert_eq!(presence.details, "Episode");
+        assert_eq!(presence.large_image, "http://art");
+        assert_eq!(presence.end, None);
+    }
+}
@@ -0,0 +1,138 @@
+//! A bus which any number of frontends can subscribe to, to keep their
+//! state in sync with the [Controller](super::controller::Controller)
+//!
+//! Every subscriber gets its own copy of every event. Subscribers which
+//! fall behind miss position ticks rather than holding up the controller,
+//! but never any other event: a subscriber which falls too far behind is
+//! disconnected instead, so it knows to subscribe again and catch up.
+//! Subscribers are forgotten once their receiver is dropped.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::music_storage::library::URI;
+
+use super::queue::QueueEvent;
+
+/// How many events can be waiting for a subscriber before it is disconnected
+pub const EVENT_BUFFER: usize = 1024;
+
+/// Position ticks are only sent to subscribers with fewer events than this
+/// waiting, as the next tick makes any one of them out of date
+pub const TICK_BUFFER: usize = 4;
+
+/// How often [ControllerEvent::PositionTick] is sent while playing
+pub const POSITION_TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", content = "data")]
+#[non_exhaustive]
+pub enum ControllerEvent {
+    /// A different song started playing, `uuid` is `None` for songs which
+    /// aren't in the library, such as podcast episodes
+    TrackChanged { uuid: Option<Uuid>, uri: URI },
+    /// How far into the current song playback is, sent every
+    /// [POSITION_TICK_INTERVAL] while playing
+    PositionTick { position: Duration, duration: Option<Duration> },
+    QueueChanged(QueueEvent),
+    /// Songs were added to the library, or became available or unavailable
+    LibraryChanged,
+    /// The volume the player is actually using, after the volume cap
+    VolumeChanged(f64),
+    /// Playback was paused or resumed through the controller
+    Paused,
+    Resumed,
+    /// Playback was stopped, leaving nothing loaded
+    Stopped,
+    /// The audio device being played to was removed, such as headphones
+    /// being unplugged, and playback was `paused` if the config says to
+    DeviceRemoved { device: String, paused: bool },
+    /// A download or cache write was refused, as it would have left less
+    /// than the reserved space free on the disk
+    DiskSpaceLow { required: u64, available: u64, reserve: u64 },
+    /// Something went wrong in the background, where there's no caller to
+    /// return an error to
+    Error(String),
+}
+
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<ControllerEvent>>>,
+}
+
+impl EventBus {
+    /// Start receiving every event sent from now on, until the receiver
+    /// falls [EVENT_BUFFER] events behind
+    pub fn subscribe(&self) -> Receiver<ControllerEvent> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// How many subscribers there are, including ones which have gone
+    /// since the last event
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Send an event to every subscriber
+    pub fn publish(&self, event: ControllerEvent) {
+        let tick = matches!(event, ControllerEvent::PositionTick { .. });
+        self.subscribers.lock().unwrap().retain(|tx| {
+            if tick && tx.len() >= TICK_BUFFER {
+                return true;
+            }
+            // Dropping the sender disconnects the subscriber once it has
+            // read what was already sent
+            tx.len() < EVENT_BUFFER && tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crossbeam_channel::TryRecvError;
+
+    use super::{ControllerEvent, EventBus, EVENT_BUFFER, TICK_BUFFER};
+
+    #[test]
+    fn event_bus() {
+        let bus = EventBus::default();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.publish(ControllerEvent::LibraryChanged);
+        assert_eq!(first.try_recv(), Ok(ControllerEvent::LibraryChanged));
+        assert_eq!(second.try_recv(), Ok(ControllerEvent::LibraryChanged));
+
+        // A subscriber which stops reading misses ticks, but nothing else
+        let tick = ControllerEvent::PositionTick { position: Duration::ZERO, duration: None };
+        for _ in 0..TICK_BUFFER * 2 {
+            bus.publish(tick.clone());
+            assert_eq!(second.try_recv(), Ok(tick.clone()));
+        }
+        assert_eq!(first.len(), TICK_BUFFER);
+        bus.publish(ControllerEvent::Paused);
+        assert_eq!(first.len(), TICK_BUFFER + 1);
+        assert_eq!(second.try_recv(), Ok(ControllerEvent::Paused));
+
+        // Until it falls too far behind, when it is disconnected without
+        // holding up the others
+        for _ in 0..EVENT_BUFFER {
+            bus.publish(ControllerEvent::VolumeChanged(0.5));
+            assert_eq!(second.try_recv(), Ok(ControllerEvent::VolumeChanged(0.5)));
+        }
+        assert_eq!(bus.subscribers(), 1);
+        assert_eq!(first.iter().last(), Some(ControllerEvent::VolumeChanged(0.5)));
+        assert_eq!(first.try_recv(), Err(TryRecvError::Disconnected));
+
+        drop(second);
+        bus.publish(ControllerEvent::Error("failed".to_string()));
+        assert_eq!(bus.subscribers(), 0);
+    }
+}
@@ -0,0 +1,102 @@
+//! Songs and artists left out of shuffle and auto-DJ for a while, such as
+//! "not this song right now", without changing their rating or anything
+//! else in the library
+//!
+//! Exclusions only last for the session, they are never saved.
+
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::music_storage::library::{Song, Tag};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exclusion {
+    Song(Uuid),
+    /// Every song by this artist, matched without caring about case
+    Artist(String),
+}
+
+impl Exclusion {
+    pub fn matches(&self, song: &Song) -> bool {
+        match self {
+            Exclusion::Song(uuid) => song.uuid == *uuid,
+            Exclusion::Artist(artist) => song.get_tag(&Tag::Artist).is_some_and(|other| other.eq_ignore_ascii_case(artist)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Exclusions {
+    /// Each exclusion, with when it ends if it doesn't last the whole session
+    excluded: Vec<(Exclusion, Option<Instant>)>,
+}
+
+impl Exclusions {
+    /// Exclude something for `duration`, or the rest of the session if
+    /// it's `None`. Excluding it again replaces how long it lasts.
+    pub fn add(&mut self, exclusion: Exclusion, duration: Option<Duration>, now: Instant) {
+        self.remove(&exclusion);
+        self.excluded.push((exclusion, duration.map(|duration| now + duration)));
+    }
+
+    /// Stop excluding something, returning whether it was excluded
+    pub fn remove(&mut self, exclusion: &Exclusion) -> bool {
+        let before = self.excluded.len();
+        self.excluded.retain(|(other, _)| other != exclusion);
+        self.excluded.len() != before
+    }
+
+    pub fn clear(&mut self) {
+        self.excluded.clear();
+    }
+
+    /// What is excluded at `now`, with how long until each exclusion ends
+    /// if it doesn't last the whole session
+    pub fn active(&self, now: Instant) -> Vec<(Exclusion, Option<Duration>)> {
+        self.excluded
+            .iter()
+            .filter(|(_, ends)| ends.is_none_or(|ends| ends > now))
+            .map(|(exclusion, ends)| (exclusion.clone(), ends.map(|ends| ends - now)))
+            .collect()
+    }
+
+    /// Whether `song` is left out at `now`
+    pub fn excludes(&self, song: &Song, now: Instant) -> bool {
+        self.excluded
+            .iter()
+            .any(|(exclusion, ends)| ends.is_none_or(|ends| ends > now) && exclusion.matches(song))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Exclusion, Exclusions};
+    use crate::music_storage::library::test::test_song;
+
+    #[test]
+    fn session_exclusions() {
+        let now = Instant::now();
+        let song = test_song("Song", "Artist", Duration::from_secs(200));
+        let other = test_song("Other", "Someone", Duration::from_secs(200));
+
+        let mut exclusions = Exclusions::default();
+        exclusions.add(Exclusion::Artist("artist".to_string()), Some(Duration::from_secs(3600)), now);
+        assert!(exclusions.excludes(&song, now));
+        assert!(!exclusions.excludes(&other, now));
+        assert!(!exclusions.excludes(&song, now + Duration::from_secs(3600)));
+
+        exclusions.add(Exclusion::Song(other.uuid), None, now);
+        let active = exclusions.active(now + Duration::from_secs(600));
+        assert_eq!(active[0], (Exclusion::Artist("artist".to_string()), Some(Duration::from_secs(3000))));
+        assert_eq!(active[1], (Exclusion::Song(other.uuid), None));
+        assert_eq!(exclusions.active(now + Duration::from_secs(7200)).len(), 1);
+
+        assert!(exclusions.remove(&Exclusion::Song(other.uuid)));
+        assert!(!exclusions.excludes(&other, now));
+        exclusions.clear();
+        assert!(exclusions.active(now).is_empty());
+    }
+}
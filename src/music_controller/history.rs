@@ -0,0 +1,219 @@
+//! An append-only log of every completed play, which can be queried by
+//! date and exported for other uses, such as backfilling scrobbles
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::music_storage::library::{Song, Tag};
+
+/// A single completed play of a song
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub time: DateTime<Utc>,
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Duration,
+    pub listened: Duration,
+}
+
+impl HistoryEntry {
+    /// Create an entry for a play of `song` which finished now
+    pub fn new(song: &Song, listened: Duration) -> Self {
+        HistoryEntry {
+            time: Utc::now(),
+            uuid: song.uuid,
+            title: song.get_tag(&Tag::Title).cloned(),
+            artist: song.get_tag(&Tag::Artist).cloned(),
+            album: song.get_tag(&Tag::Album).cloned(),
+            duration: song.duration,
+            listened,
+        }
+    }
+}
+
+/// The listening history, stored as one JSON entry per line
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        History {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The location of the history file, which is stored in the state folder of the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.state_file("history.jsonl")
+    }
+
+    /// Append an entry to the end of the history
+    pub fn record(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns every entry between `from` and `to`, inclusive, oldest first.
+    /// A bound of `None` is unbounded on that side.
+    ///
+    /// Lines which can't be read, such as a partial write at the end of
+    /// the file, are skipped.
+    pub fn query(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let entry: HistoryEntry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+
+            if from.is_some_and(|from| entry.time < from) || to.is_some_and(|to| entry.time > to) {
+                continue;
+            }
+            entries.push(entry);
+        }
+
+        entries.sort_by_key(|entry| entry.time);
+        Ok(entries)
+    }
+
+    /// Returns every entry in the history, oldest first
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>, Error> {
+        self.query(None, None)
+    }
+
+    /// Point the entries of songs which were given a new [Uuid] at the
+    /// new one, returning how many entries changed. The file is replaced
+    /// all at once, so it's never left half written.
+    pub fn remap(&self, uuids: &HashMap<Uuid, Uuid>) -> Result<usize, Error> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(error) => return Err(error),
+        };
+
+        let mut changed = 0;
+        let mut remapped = String::with_capacity(contents.len());
+        for line in contents.lines() {
+            match serde_json::from_str::<HistoryEntry>(line) {
+                Ok(mut entry) if uuids.contains_key(&entry.uuid) => {
+                    entry.uuid = uuids[&entry.uuid];
+                    remapped.push_str(&serde_json::to_string(&entry)?);
+                    changed += 1;
+                }
+                // Lines which can't be read are kept as they are
+                _ => remapped.push_str(line),
+            }
+            remapped.push('\n');
+        }
+
+        if changed > 0 {
+            let temporary = self.path.with_extension("jsonl.tmp");
+            fs::write(&temporary, remapped)?;
+            fs::rename(&temporary, &self.path)?;
+        }
+        Ok(changed)
+    }
+}
+
+/// Quote a CSV field if it contains any special characters
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write history entries out as CSV, with a header row
+pub fn export_csv<W: Write>(entries: &[HistoryEntry], writer: &mut W) -> Result<(), Error> {
+    writeln!(writer, "time,uuid,title,artist,album,duration_ms,listened_ms")?;
+    for entry in entries {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{}",
+            entry.time.to_rfc3339(),
+            entry.uuid,
+            csv_field(entry.title.as_deref().unwrap_or_default()),
+            csv_field(entry.artist.as_deref().unwrap_or_default()),
+            csv_field(entry.album.as_deref().unwrap_or_default()),
+            entry.duration.as_millis(),
+            entry.listened.as_millis(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Write history entries out as a JSON array
+pub fn export_json<W: Write>(entries: &[HistoryEntry], writer: &mut W) -> Result<(), Error> {
+    serde_json::to_writer_pretty(writer, entries)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{export_csv, History, HistoryEntry};
+    use crate::music_storage::library::test::test_song;
+
+    #[test]
+    fn history_query_export() {
+        let folder = tempfile::tempdir().unwrap();
+        let history = History::new(folder.path().join("history.jsonl"));
+        assert!(history.entries().unwrap().is_empty());
+
+        let song = test_song("A \"quoted\", title", "Artist", Duration::from_secs(100));
+        for day in [3, 1, 2] {
+            let mut entry = HistoryEntry::new(&song, Duration::from_secs(90));
+            entry.time = Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap();
+            history.record(&entry).unwrap();
+        }
+
+        let entries = history
+            .query(
+                Some(Utc.with_ymd_and_hms(2024, 5, 2, 0, 0, 0).unwrap()),
+                None,
+            )
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].time < entries[1].time);
+
+        let mut csv = Vec::new();
+        export_csv(&entries, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.contains("\"A \"\"quoted\"\", title\",Artist,,100000,90000"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Pausing playback after it has gone on for a long time with nobody
+//! touching the player, so a forgotten player doesn't keep scrobbling
+//! and streaming to an empty room
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// When to pause unattended playback, stored in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigIdle {
+    /// How long playback can go on without any user commands before it
+    /// is paused, `None` to never pause
+    pub pause_after: Option<Duration>,
+    /// How long before pausing the [IdleEvent::Warning] is sent
+    pub warning: Duration,
+}
+
+impl Default for ConfigIdle {
+    fn default() -> Self {
+        ConfigIdle {
+            pause_after: None,
+            warning: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleEvent {
+    /// Playback will be paused in `pause_in` unless there is a user
+    /// command first, such as answering an "are you still listening?" prompt
+    Warning { pause_in: Duration },
+    /// Playback was paused because nobody was listening
+    Paused,
+}
+
+/// Tracks how long playback has gone on since the last user command
+#[derive(Debug, Clone)]
+pub struct IdleTimer {
+    last_activity: Instant,
+    warned: bool,
+}
+
+impl Default for IdleTimer {
+    fn default() -> Self {
+        IdleTimer::new(Instant::now())
+    }
+}
+
+impl IdleTimer {
+    pub fn new(now: Instant) -> Self {
+        IdleTimer {
+            last_activity: now,
+            warned: false,
+        }
+    }
+
+    /// Record a user command, which starts the timer again
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.warned = false;
+    }
+
+    /// Check whether a warning should be sent or playback paused. Only
+    /// continuous playback counts, so the timer starts again whenever
+    /// the player isn't playing.
+    pub fn check(&mut self, now: Instant, config: &ConfigIdle, playing: bool) -> Option<IdleEvent> {
+        let pause_after = match config.pause_after {
+            Some(after) if playing => after,
+            _ => {
+                self.activity(now);
+                return None;
+            }
+        };
+
+        let idle = now.saturating_duration_since(self.last_activity);
+        let warn_at = pause_after.saturating_sub(config.warning);
+        if idle >= pause_after && self.warned {
+            self.activity(now);
+            Some(IdleEvent::Paused)
+        } else if idle >= warn_at && !self.warned {
+            self.warned = true;
+            Some(IdleEvent::Warning {
+                pause_in: pause_after.saturating_sub(idle),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{ConfigIdle, IdleEvent, IdleTimer};
+
+    #[test]
+    fn idle_pause() {
+        let start = Instant::now();
+        let config = ConfigIdle {
+            pause_after: Some(Duration::from_secs(3600)),
+            warning: Duration::from_secs(60),
+        };
+        let mut timer = IdleTimer::new(start);
+
+        assert_eq!(timer.check(start + Duration::from_secs(3000), &config, true), None);
+        assert_eq!(
+            timer.check(start + Duration::from_secs(3550), &config, true),
+            Some(IdleEvent::Warning { pause_in: Duration::from_secs(50) })
+        );
+        assert_eq!(timer.check(start + Duration::from_secs(3560), &config, true), None);
+
+        // Answering the prompt starts the timer over
+        timer.activity(start + Duration::from_secs(3570));
+        assert_eq!(timer.check(start + Duration::from_secs(3610), &config, true), None);
+
+        let later = start + Duration::from_secs(3570 + 3540);
+        assert!(matches!(timer.check(later, &config, true), Some(IdleEvent::Warning { .. })));
+        assert_eq!(timer.check(later + Duration::from_secs(60), &config, true), Some(IdleEvent::Paused));
+
+        // Time spent paused doesn't count
+        assert_eq!(timer.check(later + Duration::from_secs(9000), &config, false), None);
+        assert_eq!(timer.check(later + Duration::from_secs(9001), &config, true), None);
+    }
+}
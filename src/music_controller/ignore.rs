@@ -0,0 +1,117 @@
+//! Songs which aren't tracked, such as white noise or children's songs,
+//! so they don't end up in the play counts, history, or scrobbles
+//!
+//! Songs can be flagged in the library with [DoNotTrack], or matched by
+//! the patterns in the config
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::music_storage::library::{DoNotTrack, Song, Tag, URI};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigIgnore {
+    /// Genres which are never tracked, ignoring case
+    pub genres: Vec<String>,
+    /// Folders whose songs are never tracked
+    pub folders: Vec<PathBuf>,
+    /// Patterns matched against the title and artist of songs, ignoring
+    /// case, where `*` matches anything
+    pub patterns: Vec<String>,
+}
+
+impl ConfigIgnore {
+    /// Whether the config ignores a song
+    pub fn ignores(&self, song: &Song) -> bool {
+        let genre = song.get_tag(&Tag::Genre).map(|genre| genre.to_lowercase());
+        if genre.is_some_and(|genre| self.genres.iter().any(|ignored| ignored.to_lowercase() == genre)) {
+            return true;
+        }
+
+        let in_folder = song.location.iter().any(|location| match location {
+            URI::Remote(_, _) => false,
+            _ => self.folders.iter().any(|folder| location.path().starts_with(folder)),
+        });
+        if in_folder {
+            return true;
+        }
+
+        [Tag::Title, Tag::Artist]
+            .iter()
+            .filter_map(|tag| song.get_tag(tag))
+            .any(|value| self.patterns.iter().any(|pattern| wildcard_match(pattern, value)))
+    }
+
+    /// Whether plays of a song are tracked in the way given by `kind`,
+    /// such as [DoNotTrack::History]
+    pub fn tracks(&self, song: &Song, kind: &DoNotTrack) -> bool {
+        song.is_tracked(kind) && !self.ignores(song)
+    }
+}
+
+/// Match `value` against a pattern where `*` matches any number of
+/// characters, ignoring case
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let mut parts = pattern.split('*');
+
+    // There is always a first part, which has to be at the start
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{wildcard_match, ConfigIgnore};
+    use crate::music_storage::library::{test::test_song, DoNotTrack, Tag};
+
+    #[test]
+    fn ignored_songs() {
+        assert!(wildcard_match("white noise*", "White Noise (10 Hours)"));
+        assert!(wildcard_match("*rain*", "Gentle Rain Sounds"));
+        assert!(!wildcard_match("*rain", "Rainy Day"));
+        assert!(wildcard_match("lullaby", "Lullaby"));
+
+        let ignore = ConfigIgnore {
+            genres: vec!["Children's Music".to_string()],
+            folders: vec![PathBuf::from("/music/sleep")],
+            patterns: vec!["white noise*".to_string()],
+        };
+        let mut song = test_song("Song", "Artist", Duration::from_secs(60));
+        assert!(ignore.tracks(&song, &DoNotTrack::History));
+
+        song.set_tag(Tag::Genre, "children's music".to_string());
+        assert!(ignore.ignores(&song));
+        song.set_tag(Tag::Genre, "Rock".to_string());
+        assert!(!ignore.ignores(&song));
+
+        // Flagged songs aren't tracked in only the ways they are flagged for
+        song.set_tracked(DoNotTrack::Scrobbling, false);
+        assert!(!ignore.tracks(&song, &DoNotTrack::Scrobbling));
+        assert!(ignore.tracks(&song, &DoNotTrack::History));
+        song.set_tracked(DoNotTrack::Scrobbling, true);
+        assert!(ignore.tracks(&song, &DoNotTrack::Scrobbling));
+    }
+}
@@ -0,0 +1,553 @@
+//! System-wide media keys, so the controller still responds to them while
+//! the frontend is minimized or has no window at all
+//!
+//! On Linux and the BSDs the player is offered over
+//! [MPRIS](https://specifications.freedesktop.org/mpris-spec/latest/),
+//! which desktops send their media keys to. On Windows the keys are
+//! registered as hotkeys. On macOS they come from the Now Playing controls
+//! of `MPRemoteCommandCenter`, which are only handled while the main
+//! thread runs its run loop, as it does in any app with a window.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, select, unbounded, Sender};
+use thiserror::Error;
+
+use crate::music_player::player::Player;
+
+use super::controller::{Controller, ControllerError};
+use super::events::ControllerEvent;
+
+/// How often the listening thread checks whether it has been stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKey {
+    PlayPause,
+    Play,
+    Pause,
+    Next,
+    Previous,
+    Stop,
+}
+
+#[derive(Error, Debug)]
+pub enum MediaKeyError {
+    #[error("media keys aren't supported on this platform")]
+    Unsupported,
+    #[error("could not register the media keys: {0}")]
+    Register(String),
+}
+
+/// Whether anything is playing, as shown to the desktop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// The media keys while they are registered, which are given back when
+/// this is stopped or dropped
+pub struct MediaKeys {
+    status: Arc<Mutex<PlaybackStatus>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MediaKeys {
+    /// Register the media keys, sending every press to `keys`
+    pub fn register(keys: Sender<MediaKey>) -> Result<Self, MediaKeyError> {
+        let status = Arc::new(Mutex::new(PlaybackStatus::Stopped));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready) = bounded(1);
+
+        let (status_, stop_) = (status.clone(), stop.clone());
+        let thread = spawn(move || listen(keys, status_, stop_, ready_tx));
+        match ready.recv() {
+            Ok(Ok(())) => Ok(MediaKeys { status, stop, thread: Some(thread) }),
+            Ok(Err(error)) => Err(error),
+            Err(_) => Err(MediaKeyError::Register("the listener stopped".to_string())),
+        }
+    }
+
+    /// Register the media keys and control `controller` with them
+    pub fn start<P: Player + Send + Sync + 'static>(controller: Arc<Mutex<Controller<P>>>) -> Result<Self, MediaKeyError> {
+        let (keys_tx, keys) = unbounded();
+        let media_keys = MediaKeys::register(keys_tx)?;
+        let status = media_keys.status.clone();
+        let events = controller.lock().unwrap().subscribe();
+
+        // Ends once the listener drops its sender, when the keys are stopped
+        spawn(move || loop {
+            select! {
+                recv(keys) -> key => {
+                    let Ok(key) = key else { break };
+                    if let Err(error) = press(&mut controller.lock().unwrap(), key) {
+                        println!("Media keys: {:?} failed: {}", key, error);
+                    }
+                }
+                recv(events) -> event => {
+                    let new = match event {
+                        Ok(ControllerEvent::TrackChanged { .. } | ControllerEvent::Resumed) => PlaybackStatus::Playing,
+                        Ok(ControllerEvent::Paused) => PlaybackStatus::Paused,
+                        Ok(ControllerEvent::Stopped) => PlaybackStatus::Stopped,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    };
+                    *status.lock().unwrap() = new;
+                }
+            }
+        });
+        Ok(media_keys)
+    }
+
+    /// Tell the desktop whether anything is playing, which [MediaKeys::start]
+    /// does by itself
+    pub fn set_status(&self, status: PlaybackStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// Give the media keys back
+    pub fn stop(mut self) {
+        self.unregister();
+    }
+
+    fn unregister(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MediaKeys {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Do what `key` does to `controller`
+pub fn press<P: Player + Send + Sync + 'static>(controller: &mut Controller<P>, key: MediaKey) -> Result<(), ControllerError> {
+    match key {
+        MediaKey::PlayPause if controller.is_paused() => controller.play(),
+        MediaKey::PlayPause | MediaKey::Pause => controller.pause(),
+        MediaKey::Play => controller.play(),
+        MediaKey::Next => controller.q_next(),
+        MediaKey::Previous => controller.q_prev(),
+        MediaKey::Stop => controller.stop(),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod mpris {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+
+    use dbus::arg::{PropMap, RefArg, Variant};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::{PropertiesPropertiesChanged, RequestNameReply};
+    use dbus::blocking::Connection;
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::{MatchRule, SignalArgs};
+    use dbus::strings::{ErrorName, Path};
+    use dbus::Message;
+
+    use super::{MediaKey, MediaKeyError, PlaybackStatus};
+
+    pub const BUS_NAME: &str = "org.mpris.MediaPlayer2.dmp";
+    pub const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+    pub const ROOT: &str = "org.mpris.MediaPlayer2";
+    pub const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+    const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+    const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+
+    const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.mpris.MediaPlayer2">
+    <method name="Raise"/>
+    <method name="Quit"/>
+    <property name="CanQuit" type="b" access="read"/>
+    <property name="CanRaise" type="b" access="read"/>
+    <property name="HasTrackList" type="b" access="read"/>
+    <property name="Identity" type="s" access="read"/>
+    <property name="SupportedUriSchemes" type="as" access="read"/>
+    <property name="SupportedMimeTypes" type="as" access="read"/>
+  </interface>
+  <interface name="org.mpris.MediaPlayer2.Player">
+    <method name="Next"/>
+    <method name="Previous"/>
+    <method name="Pause"/>
+    <method name="PlayPause"/>
+    <method name="Stop"/>
+    <method name="Play"/>
+    <property name="PlaybackStatus" type="s" access="read"/>
+    <property name="Metadata" type="a{sv}" access="read"/>
+    <property name="Rate" type="d" access="read"/>
+    <property name="MinimumRate" type="d" access="read"/>
+    <property name="MaximumRate" type="d" access="read"/>
+    <property name="CanGoNext" type="b" access="read"/>
+    <property name="CanGoPrevious" type="b" access="read"/>
+    <property name="CanPlay" type="b" access="read"/>
+    <property name="CanPause" type="b" access="read"/>
+    <property name="CanSeek" type="b" access="read"/>
+    <property name="CanControl" type="b" access="read"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed" type="a{sv}"/>
+      <arg name="invalidated" type="as"/>
+    </signal>
+  </interface>
+</node>"#;
+
+    /// The key sent by a call to `method` of the player interface
+    pub fn key(method: &str) -> Option<MediaKey> {
+        match method {
+            "PlayPause" => Some(MediaKey::PlayPause),
+            "Play" => Some(MediaKey::Play),
+            "Pause" => Some(MediaKey::Pause),
+            "Next" => Some(MediaKey::Next),
+            "Previous" => Some(MediaKey::Previous),
+            "Stop" => Some(MediaKey::Stop),
+            _ => None,
+        }
+    }
+
+    fn status_name(status: PlaybackStatus) -> &'static str {
+        match status {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+
+    fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+        Variant(Box::new(value))
+    }
+
+    /// The properties of `interface` while playback is `status`
+    pub fn properties(interface: &str, status: PlaybackStatus) -> PropMap {
+        let mut properties: PropMap = HashMap::new();
+        match interface {
+            ROOT => {
+                properties.insert("CanQuit".into(), variant(false));
+                properties.insert("CanRaise".into(), variant(false));
+                properties.insert("HasTrackList".into(), variant(false));
+                properties.insert("Identity".into(), variant("dmp".to_string()));
+                properties.insert("SupportedUriSchemes".into(), variant(Vec::<String>::new()));
+                properties.insert("SupportedMimeTypes".into(), variant(Vec::<String>::new()));
+            }
+            PLAYER => {
+                properties.insert("PlaybackStatus".into(), variant(status_name(status).to_string()));
+                properties.insert("Metadata".into(), variant(PropMap::new()));
+                for rate in ["Rate", "MinimumRate", "MaximumRate"] {
+                    properties.insert(rate.into(), variant(1.0));
+                }
+                for can in ["CanGoNext", "CanGoPrevious", "CanPlay", "CanPause", "CanControl"] {
+                    properties.insert(can.into(), variant(true));
+                }
+                properties.insert("CanSeek".into(), variant(false));
+            }
+            _ => (),
+        }
+        properties
+    }
+
+    /// The reply to a method call, sending the key it presses to `keys`
+    fn reply(msg: &Message, status: PlaybackStatus, keys: &crossbeam_channel::Sender<MediaKey>) -> Message {
+        let unknown = |name: &'static str, text: &str| {
+            msg.error(&ErrorName::from(name), &CString::new(text).unwrap_or_default())
+        };
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+
+        match interface.as_str() {
+            PLAYER => match key(&member) {
+                Some(key) => {
+                    let _ = keys.send(key);
+                    msg.method_return()
+                }
+                None => unknown("org.freedesktop.DBus.Error.UnknownMethod", &member),
+            },
+            ROOT if member == "Raise" || member == "Quit" => msg.method_return(),
+            PROPERTIES => match member.as_str() {
+                "Get" => match msg.read2::<&str, &str>() {
+                    Ok((interface, name)) => match properties(interface, status).remove(name) {
+                        Some(value) => msg.method_return().append1(value),
+                        None => unknown("org.freedesktop.DBus.Error.UnknownProperty", name),
+                    },
+                    Err(error) => unknown("org.freedesktop.DBus.Error.InvalidArgs", &error.to_string()),
+                },
+                "GetAll" => match msg.read1::<&str>() {
+                    Ok(interface) => msg.method_return().append1(properties(interface, status)),
+                    Err(error) => unknown("org.freedesktop.DBus.Error.InvalidArgs", &error.to_string()),
+                },
+                "Set" => unknown("org.freedesktop.DBus.Error.PropertyReadOnly", "properties are read only"),
+                _ => unknown("org.freedesktop.DBus.Error.UnknownMethod", &member),
+            },
+            INTROSPECTABLE if member == "Introspect" => msg.method_return().append1(INTROSPECTION),
+            _ => unknown("org.freedesktop.DBus.Error.UnknownMethod", &member),
+        }
+    }
+
+    /// Connect to the session bus and take the MPRIS name, a second copy
+    /// of the player taking a name of its own
+    pub fn connect(
+        keys: crossbeam_channel::Sender<MediaKey>,
+        status: std::sync::Arc<std::sync::Mutex<PlaybackStatus>>,
+    ) -> Result<Connection, MediaKeyError> {
+        let register = |error: dbus::Error| MediaKeyError::Register(error.to_string());
+        let connection = Connection::new_session().map_err(register)?;
+        if connection.request_name(BUS_NAME, false, false, true).map_err(register)? != RequestNameReply::PrimaryOwner {
+            let instance = format!("{}.instance{}", BUS_NAME, std::process::id());
+            connection.request_name(instance, false, false, true).map_err(register)?;
+        }
+
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, connection| {
+                let status = *status.lock().unwrap();
+                let _ = connection.send(reply(&msg, status, &keys));
+                true
+            }),
+        );
+        Ok(connection)
+    }
+
+    /// Tell the desktop playback is now `status`
+    pub fn announce(connection: &Connection, status: PlaybackStatus) {
+        let mut changed = PropMap::new();
+        changed.insert("PlaybackStatus".into(), variant(status_name(status).to_string()));
+        let signal = PropertiesPropertiesChanged {
+            interface_name: PLAYER.to_string(),
+            changed_properties: changed,
+            invalidated_properties: Vec::new(),
+        };
+        let _ = connection.send(signal.to_emit_message(&Path::from(OBJECT_PATH)));
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn listen(
+    keys: Sender<MediaKey>,
+    status: Arc<Mutex<PlaybackStatus>>,
+    stop: Arc<AtomicBool>,
+    ready: Sender<Result<(), MediaKeyError>>,
+) {
+    let connection = match mpris::connect(keys, status.clone()) {
+        Ok(connection) => connection,
+        Err(error) => {
+            let _ = ready.send(Err(error));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+
+    let mut announced = *status.lock().unwrap();
+    while !stop.load(Ordering::Relaxed) {
+        if let Err(error) = connection.process(STOP_POLL_INTERVAL) {
+            println!("Media keys: lost the session bus: {}", error);
+            break;
+        }
+        let now = *status.lock().unwrap();
+        if now != announced {
+            mpris::announce(&connection, now);
+            announced = now;
+        }
+    }
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct WindowsMessage {
+    hwnd: isize,
+    message: u32,
+    wparam: usize,
+    lparam: isize,
+    time: u32,
+    point: [i32; 2],
+}
+
+#[cfg(windows)]
+#[link(name = "user32")]
+extern "system" {
+    fn RegisterHotKey(hwnd: isize, id: i32, modifiers: u32, key: u32) -> i32;
+    fn UnregisterHotKey(hwnd: isize, id: i32) -> i32;
+    fn PeekMessageW(message: *mut WindowsMessage, hwnd: isize, min: u32, max: u32, remove: u32) -> i32;
+}
+
+/// The virtual key codes of the media keys, which double as hotkey IDs
+#[cfg(windows)]
+const WINDOWS_KEYS: [(u32, MediaKey); 4] = [
+    (0xB0, MediaKey::Next),
+    (0xB1, MediaKey::Previous),
+    (0xB2, MediaKey::Stop),
+    (0xB3, MediaKey::PlayPause),
+];
+
+#[cfg(windows)]
+fn listen(
+    keys: Sender<MediaKey>,
+    _status: Arc<Mutex<PlaybackStatus>>,
+    stop: Arc<AtomicBool>,
+    ready: Sender<Result<(), MediaKeyError>>,
+) {
+    const MOD_NOREPEAT: u32 = 0x4000;
+    const WM_HOTKEY: u32 = 0x0312;
+    const PM_REMOVE: u32 = 0x0001;
+
+    // Hotkeys registered without a window are sent to this thread's queue
+    for (key, _) in WINDOWS_KEYS {
+        if unsafe { RegisterHotKey(0, key as i32, MOD_NOREPEAT, key) } == 0 {
+            for (registered, _) in WINDOWS_KEYS.iter().take_while(|(k, _)| *k != key) {
+                unsafe { UnregisterHotKey(0, *registered as i32) };
+            }
+            let error = std::io::Error::last_os_error();
+            let _ = ready.send(Err(MediaKeyError::Register(error.to_string())));
+            return;
+        }
+    }
+    let _ = ready.send(Ok(()));
+
+    let mut message = WindowsMessage { hwnd: 0, message: 0, wparam: 0, lparam: 0, time: 0, point: [0; 2] };
+    while !stop.load(Ordering::Relaxed) {
+        while unsafe { PeekMessageW(&mut message, 0, 0, 0, PM_REMOVE) } != 0 {
+            if message.message != WM_HOTKEY {
+                continue;
+            }
+            if let Some((_, key)) = WINDOWS_KEYS.iter().find(|(k, _)| *k as usize == message.wparam) {
+                let _ = keys.send(*key);
+            }
+        }
+        std::thread::sleep(STOP_POLL_INTERVAL);
+    }
+    for (key, _) in WINDOWS_KEYS {
+        unsafe { UnregisterHotKey(0, key as i32) };
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod now_playing {
+    use souvlaki::MediaControlEvent;
+
+    use super::MediaKey;
+
+    /// The key pressed by a Now Playing `event`, the others such as
+    /// seeking aren't media keys
+    pub fn key(event: &MediaControlEvent) -> Option<MediaKey> {
+        match event {
+            MediaControlEvent::Toggle => Some(MediaKey::PlayPause),
+            MediaControlEvent::Play => Some(MediaKey::Play),
+            MediaControlEvent::Pause => Some(MediaKey::Pause),
+            MediaControlEvent::Next => Some(MediaKey::Next),
+            MediaControlEvent::Previous => Some(MediaKey::Previous),
+            MediaControlEvent::Stop => Some(MediaKey::Stop),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn listen(
+    keys: Sender<MediaKey>,
+    status: Arc<Mutex<PlaybackStatus>>,
+    stop: Arc<AtomicBool>,
+    ready: Sender<Result<(), MediaKeyError>>,
+) {
+    use souvlaki::{MediaControls, MediaPlayback, PlatformConfig};
+
+    let register = |error: souvlaki::Error| MediaKeyError::Register(format!("{:?}", error));
+    let config = PlatformConfig { display_name: "dmp", dbus_name: "dmp", hwnd: None };
+    let controls = MediaControls::new(config).map_err(register).and_then(|mut controls| {
+        controls
+            .attach(move |event| {
+                if let Some(key) = now_playing::key(&event) {
+                    let _ = keys.send(key);
+                }
+            })
+            .map_err(register)?;
+        Ok(controls)
+    });
+    let mut controls = match controls {
+        Ok(controls) => controls,
+        Err(error) => {
+            let _ = ready.send(Err(error));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+
+    let mut announced = None;
+    while !stop.load(Ordering::Relaxed) {
+        let now = *status.lock().unwrap();
+        if announced != Some(now) {
+            let playback = match now {
+                PlaybackStatus::Playing => MediaPlayback::Playing { progress: None },
+                PlaybackStatus::Paused => MediaPlayback::Paused { progress: None },
+                PlaybackStatus::Stopped => MediaPlayback::Stopped,
+            };
+            if let Err(error) = controls.set_playback(playback) {
+                println!("Media keys: could not show the playback status: {:?}", error);
+            }
+            announced = Some(now);
+        }
+        std::thread::sleep(STOP_POLL_INTERVAL);
+    }
+    let _ = controls.detach();
+}
+
+#[cfg(not(any(windows, unix)))]
+fn listen(
+    _keys: Sender<MediaKey>,
+    _status: Arc<Mutex<PlaybackStatus>>,
+    _stop: Arc<AtomicBool>,
+    ready: Sender<Result<(), MediaKeyError>>,
+) {
+    let _ = ready.send(Err(MediaKeyError::Unsupported));
+}
+
+#[cfg(all(test, target_os = "macos"))]
+mod test {
+    use souvlaki::MediaControlEvent;
+
+    use super::now_playing::key;
+    use super::MediaKey;
+
+    #[test]
+    fn now_playing_keys() {
+        assert_eq!(key(&MediaControlEvent::Toggle), Some(MediaKey::PlayPause));
+        assert_eq!(key(&MediaControlEvent::Next), Some(MediaKey::Next));
+        assert_eq!(key(&MediaControlEvent::Raise), None);
+    }
+}
+
+#[cfg(all(test, unix, not(target_os = "macos")))]
+mod test {
+    use super::mpris::{key, properties, PLAYER};
+    use super::{MediaKey, PlaybackStatus};
+
+    #[test]
+    fn mpris_player() {
+        assert_eq!(key("PlayPause"), Some(MediaKey::PlayPause));
+        assert_eq!(key("Previous"), Some(MediaKey::Previous));
+        assert_eq!(key("OpenUri"), None);
+
+        let properties = properties(PLAYER, PlaybackStatus::Paused);
+        assert_eq!(properties["PlaybackStatus"].0.as_str(), Some("Paused"));
+        assert_eq!(properties["CanGoNext"].0.as_i64(), Some(1));
+    }
+}
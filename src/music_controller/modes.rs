@@ -0,0 +1,94 @@
+//! The playback modes of the [Controller](super::controller::Controller),
+//! kept together so they are always changed and reported as one
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Go back to the start of the queue once it has finished
+    All,
+    /// Play the current song over and over
+    One,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Normalization {
+    #[default]
+    Off,
+    /// Make every track the same loudness
+    Track,
+    /// Keep the loudness differences between the tracks of an album
+    Album,
+}
+
+/// Every playback mode, which is replaced as a whole so that frontends
+/// never see some modes changed and others not
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackModes {
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    /// How long songs overlap for, `None` for gapless playback
+    pub crossfade: Option<Duration>,
+    pub normalization: Normalization,
+    /// Stop once the current song finishes, this is turned off again when it does
+    pub stop_after_current: bool,
+    /// The seed the shuffled order is made from, the same seed always gives
+    /// the same order. A random one is picked when shuffle is turned on
+    /// without one.
+    pub shuffle_seed: Option<u64>,
+}
+
+/// A seed for [shuffled_order] which is different every time
+pub(super) fn random_seed() -> u64 {
+    uuid::Uuid::new_v4().as_u128() as u64
+}
+
+/// An order to play `len` items in, which is always the same for a `seed`
+pub(super) fn shuffled_order(len: usize, seed: u64) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..len).collect();
+
+    // A xorshift generator is plenty for picking an order, it only needs
+    // the state to never be zero. The seed is mixed with splitmix64 first
+    // so that every seed starts from a different state.
+    let mut state = seed.wrapping_add(0x9E3779B97F4A7C15);
+    state = (state ^ (state >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    state = (state ^ (state >> 27)).wrapping_mul(0x94D049BB133111EB);
+    state ^= state >> 31;
+    if state == 0 {
+        state = 0x9E3779B97F4A7C15;
+    }
+    for i in (1..len).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use super::{shuffled_order, PlaybackModes, RepeatMode};
+
+    #[test]
+    fn playback_modes() {
+        let mut order = shuffled_order(50, 7);
+        assert_eq!(order, shuffled_order(50, 7));
+        assert_ne!(order, shuffled_order(50, 8));
+        // Even seeds don't share the order of the odd seed after them
+        assert_ne!(shuffled_order(50, 6), order);
+        assert_ne!(shuffled_order(50, 0), shuffled_order(50, 1));
+        order.sort();
+        assert_eq!(order, (0..50).collect::<Vec<_>>());
+
+        // Modes saved before a field existed keep the default for it
+        let modes: PlaybackModes = serde_json::from_str(r#"{"repeat": "One"}"#).unwrap();
+        assert_eq!(modes.repeat, RepeatMode::One);
+        assert!(!modes.shuffle);
+    }
+}
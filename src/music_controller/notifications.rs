@@ -0,0 +1,247 @@
+//! Desktop notifications when the track changes, through the platform's
+//! notification system or a [Notifier] given by the frontend
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::music_storage::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+use crate::music_storage::podcast::Podcasts;
+
+use super::events::ControllerEvent;
+
+/// Whether notifications are shown, stored in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigNotifications {
+    pub enabled: bool,
+    /// Whether the album art is shown with the notification
+    pub art: bool,
+    /// Tracks which change sooner than this after the last notification
+    /// aren't shown, such as while skipping through the queue
+    pub min_interval: Duration,
+}
+
+impl Default for ConfigNotifications {
+    fn default() -> Self {
+        ConfigNotifications {
+            enabled: false,
+            art: true,
+            min_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    /// The album art, as an image file
+    pub art: Option<PathBuf>,
+}
+
+impl Notification {
+    /// "Now playing: Artist – Title" for a song
+    pub fn now_playing(song: &Song) -> Self {
+        let title = song.get_tag(&Tag::Title).cloned().unwrap_or_else(|| {
+            song.location
+                .first()
+                .and_then(|uri| Some(uri.path().file_stem()?.to_string_lossy().into_owned()))
+                .unwrap_or_default()
+        });
+        Notification {
+            summary: "Now playing".to_string(),
+            body: match song.get_tag(&Tag::Artist) {
+                Some(artist) => format!("{} – {}", artist, title),
+                None => title,
+            },
+            art: None,
+        }
+    }
+}
+
+/// Shows notifications, which frontends can give their own of with
+/// [Controller::set_notifier](super::controller::Controller::set_notifier)
+pub trait Notifier: Send {
+    fn notify(&mut self, notification: &Notification) -> Result<(), Box<dyn Error>>;
+}
+
+/// The platform's own notifications, through `notify-send` on Linux and
+/// the BSDs, `osascript` on macOS, and PowerShell on Windows
+#[derive(Debug, Default)]
+pub struct SystemNotifier;
+
+impl Notifier for SystemNotifier {
+    fn notify(&mut self, notification: &Notification) -> Result<(), Box<dyn Error>> {
+        let mut command = match std::env::consts::OS {
+            "macos" => {
+                let mut command = Command::new("osascript");
+                command.arg("-e").arg(format!(
+                    "display notification {} with title {}",
+                    apple_script_string(&notification.body),
+                    apple_script_string(&notification.summary)
+                ));
+                command
+            }
+            "windows" => {
+                let mut command = Command::new("powershell");
+                command.args(["-NoProfile", "-Command", WINDOWS_TOAST]);
+                command.env("DMP_SUMMARY", &notification.summary).env("DMP_BODY", &notification.body);
+                if let Some(art) = &notification.art {
+                    command.env("DMP_ART", art);
+                }
+                command
+            }
+            _ => {
+                let mut command = Command::new("notify-send");
+                command.args(["--app-name", "dmp"]);
+                if let Some(art) = &notification.art {
+                    command.arg("--icon").arg(art);
+                }
+                command.arg(&notification.summary).arg(&notification.body);
+                command
+            }
+        };
+
+        let status = command.stdout(Stdio::null()).stderr(Stdio::null()).status()?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(format!("the notifier exited with {}", status).into()),
+        }
+    }
+}
+
+/// Shows a toast from the `DMP_SUMMARY`, `DMP_BODY`, and `DMP_ART`
+/// variables, so nothing has to be escaped
+const WINDOWS_TOAST: &str = r#"
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] | Out-Null
+$template = [Windows.UI.Notifications.ToastTemplateType]::ToastImageAndText02
+$xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent($template)
+$text = $xml.GetElementsByTagName('text')
+$text.Item(0).AppendChild($xml.CreateTextNode($env:DMP_SUMMARY)) | Out-Null
+$text.Item(1).AppendChild($xml.CreateTextNode($env:DMP_BODY)) | Out-Null
+if ($env:DMP_ART) { $xml.GetElementsByTagName('image').Item(0).SetAttribute('src', $env:DMP_ART) }
+$toast = [Windows.UI.Notifications.ToastNotification]::new($xml)
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('dmp').Show($toast)
+"#;
+
+fn apple_script_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Lets a notification through at most once every interval
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    last: Option<Instant>,
+}
+
+impl RateLimit {
+    /// Whether a notification can be shown at `now`, counting it as shown
+    /// if it can
+    pub fn allow(&mut self, now: Instant, interval: Duration) -> bool {
+        if self.last.is_some_and(|last| now.saturating_duration_since(last) < interval) {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+/// The album art of `song` as a file, the embedded art being written to
+/// a temporary file which is replaced by the next song's
+fn art_file(song: &Song) -> Option<PathBuf> {
+    match song.album_art.first()? {
+        AlbumArt::External(uri) => Some(uri.path()),
+        AlbumArt::Embedded(_) => {
+            let (data, mime) = song.read_art(0).ok()??;
+            let extension = mime.rsplit('/').next().unwrap_or("jpeg");
+            let path = std::env::temp_dir().join(format!("dmp-now-playing.{}", extension));
+            fs::write(&path, data).ok()?;
+            Some(path)
+        }
+    }
+}
+
+/// Show a notification for every track the controller's `events` say has
+/// started, until the controller is gone
+pub(super) fn notify_tracks(
+    events: Receiver<ControllerEvent>,
+    config: Arc<RwLock<Config>>,
+    library: Arc<RwLock<MusicLibrary>>,
+    podcasts: Arc<RwLock<Podcasts>>,
+    notifier: Arc<Mutex<Box<dyn Notifier>>>,
+) {
+    let mut limit = RateLimit::default();
+    for event in events {
+        let ControllerEvent::TrackChanged { uuid, uri } = event else { continue };
+        let settings = config.read().unwrap().notifications;
+        if !settings.enabled || !limit.allow(Instant::now(), settings.min_interval) {
+            continue;
+        }
+
+        let Some(notification) = track_notification(uuid.is_some(), &uri, &library, &podcasts, settings.art) else {
+            continue;
+        };
+        if let Err(error) = notifier.lock().unwrap().notify(&notification) {
+            println!("Failed to show a notification: {}", error);
+        }
+    }
+}
+
+fn track_notification(
+    in_library: bool,
+    uri: &URI,
+    library: &RwLock<MusicLibrary>,
+    podcasts: &RwLock<Podcasts>,
+    art: bool,
+) -> Option<Notification> {
+    if in_library {
+        let library = library.read().unwrap();
+        let (song, _) = library.query_uri(uri)?;
+        let mut notification = Notification::now_playing(song);
+        if art {
+            notification.art = art_file(song);
+        }
+        return Some(notification);
+    }
+
+    let podcasts = podcasts.read().unwrap();
+    let (podcast, episode) = podcasts.episode_at(uri)?;
+    Some(Notification {
+        summary: "Now playing".to_string(),
+        body: format!("{} – {}", podcast.title, episode.title),
+        art: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Notification, RateLimit};
+    use crate::music_storage::library::{test::test_song, Tag};
+
+    #[test]
+    fn now_playing_notifications() {
+        let song = test_song("Title", "Artist", Duration::from_secs(200));
+        assert_eq!(Notification::now_playing(&song).body, "Artist – Title");
+        let mut song = test_song("Untagged", "Artist", Duration::from_secs(200));
+        song.remove_tag(&Tag::Artist);
+        song.remove_tag(&Tag::Title);
+        assert_eq!(Notification::now_playing(&song).body, "Untagged");
+
+        let mut limit = RateLimit::default();
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        assert!(limit.allow(start, interval));
+        assert!(!limit.allow(start + Duration::from_secs(2), interval));
+        assert!(limit.allow(start + Duration::from_secs(6), interval));
+    }
+}
@@ -0,0 +1,131 @@
+//! Saving energy while running on battery, by polling the player and
+//! checking for changes less often, putting off background work, and
+//! writing to disk in batches
+//!
+//! The mode can be switched by hand with
+//! [Controller::set_power_mode](super::controller::Controller::set_power_mode),
+//! or automatically whenever the battery starts or stops being used
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// How many times longer waits between polls are while saving energy
+const SAVING_FACTOR: u32 = 5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerMode {
+    #[default]
+    Normal,
+    Saving,
+}
+
+impl PowerMode {
+    /// How long to wait between polls which happen every `normal` outside
+    /// of energy saving
+    pub fn interval(&self, normal: Duration) -> Duration {
+        match self {
+            PowerMode::Normal => normal,
+            PowerMode::Saving => normal * SAVING_FACTOR,
+        }
+    }
+
+    /// Whether data which is only for show, such as for visualizers,
+    /// should be made
+    pub fn visuals(&self) -> bool {
+        *self == PowerMode::Normal
+    }
+
+    /// Whether background work, such as analysing songs, should wait
+    /// until there is power again
+    pub fn defer_background(&self) -> bool {
+        *self == PowerMode::Saving
+    }
+}
+
+/// When energy is saved, stored in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigPower {
+    /// Save energy whenever running on battery
+    pub automatic: bool,
+}
+
+impl Default for ConfigPower {
+    fn default() -> Self {
+        ConfigPower { automatic: true }
+    }
+}
+
+impl ConfigPower {
+    /// The mode to use, where `manual` is the mode picked by hand, if any
+    pub fn mode(&self, manual: Option<PowerMode>, on_battery: Option<bool>) -> PowerMode {
+        match (manual, on_battery) {
+            (Some(mode), _) => mode,
+            (None, Some(true)) if self.automatic => PowerMode::Saving,
+            _ => PowerMode::Normal,
+        }
+    }
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    Switched { mode: PowerMode, on_battery: Option<bool> },
+}
+
+/// Whether the computer is running on battery, or `None` if it can't be
+/// told, such as on desktops or platforms other than Linux
+pub fn on_battery() -> Option<bool> {
+    on_battery_in(Path::new("/sys/class/power_supply"))
+}
+
+/// Read the power supplies in a `/sys/class/power_supply` folder
+fn on_battery_in(folder: &Path) -> Option<bool> {
+    let mut has_battery = false;
+    let mut on_mains = false;
+    for supply in fs::read_dir(folder).ok()?.filter_map(|entry| entry.ok()) {
+        let read = |name: &str| fs::read_to_string(supply.path().join(name)).map(|value| value.trim().to_string());
+        match read("type").ok().as_deref() {
+            Some("Mains") | Some("USB") => on_mains |= read("online").is_ok_and(|online| online == "1"),
+            Some("Battery") => has_battery = true,
+            _ => (),
+        }
+    }
+    has_battery.then_some(!on_mains)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::{on_battery_in, ConfigPower, PowerMode};
+
+    #[test]
+    fn power_modes() {
+        let folder = tempfile::tempdir().unwrap();
+        let supply = |name: &str, kind: &str, online: &str| {
+            let path = folder.path().join(name);
+            fs::create_dir(&path).unwrap();
+            fs::write(path.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(path.join("online"), format!("{}\n", online)).unwrap();
+        };
+        // A desktop without a battery can't be on battery
+        supply("AC", "Mains", "0");
+        assert_eq!(on_battery_in(folder.path()), None);
+        supply("BAT0", "Battery", "1");
+        assert_eq!(on_battery_in(folder.path()), Some(true));
+        fs::write(folder.path().join("AC/online"), "1").unwrap();
+        assert_eq!(on_battery_in(folder.path()), Some(false));
+
+        let config = ConfigPower::default();
+        assert_eq!(config.mode(None, Some(true)), PowerMode::Saving);
+        assert_eq!(config.mode(None, None), PowerMode::Normal);
+        assert_eq!(config.mode(Some(PowerMode::Normal), Some(true)), PowerMode::Normal);
+        assert_eq!(ConfigPower { automatic: false }.mode(None, Some(true)), PowerMode::Normal);
+        assert_eq!(PowerMode::Saving.interval(Duration::from_secs(2)), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,50 @@
+//! Private sessions, during which nothing that is played is scrobbled,
+//! counted in the history, or shown as a Discord presence. A private
+//! session isn't saved, so it always ends when the controller restarts.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivateSessionEvent {
+    Started,
+    Ended,
+}
+
+#[derive(Debug, Default)]
+pub struct PrivateSession {
+    active: AtomicBool,
+}
+
+impl PrivateSession {
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Start or end the private session, returning an event if that
+    /// changed whether it is active
+    pub fn set(&self, active: bool) -> Option<PrivateSessionEvent> {
+        if self.active.swap(active, Ordering::Relaxed) == active {
+            return None;
+        }
+        Some(match active {
+            true => PrivateSessionEvent::Started,
+            false => PrivateSessionEvent::Ended,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PrivateSession, PrivateSessionEvent};
+
+    #[test]
+    fn private_session() {
+        let session = PrivateSession::default();
+        assert!(!session.is_active());
+        assert_eq!(session.set(true), Some(PrivateSessionEvent::Started));
+        assert_eq!(session.set(true), None);
+        assert!(session.is_active());
+        assert_eq!(session.set(false), Some(PrivateSessionEvent::Ended));
+    }
+}
@@ -0,0 +1,118 @@
+//! Audio profiles, such as a quieter night mode, which are switched
+//! between automatically at the times of day they are scheduled for
+
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+use crate::music_player::player::Equalizer;
+
+/// A set of audio settings which are applied together
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioProfile {
+    pub name: String,
+    /// Added to every band of the equalizer, in dB
+    #[serde(default)]
+    pub gain: f64,
+    #[serde(default)]
+    pub equalizer: Equalizer,
+    /// The crossfade to use while the profile is active, `None` to keep
+    /// the crossfade of the playback modes
+    #[serde(default)]
+    pub crossfade: Option<Duration>,
+}
+
+impl AudioProfile {
+    /// The equalizer with the gain of the profile applied
+    pub fn output_equalizer(&self) -> Equalizer {
+        self.equalizer.with_gain(self.gain)
+    }
+}
+
+/// The hours a profile is active for each day. A schedule which ends
+/// before it starts lasts over midnight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileSchedule {
+    pub profile: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl ProfileSchedule {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigProfiles {
+    pub profiles: Vec<AudioProfile>,
+    /// When each profile is active, the first matching entry wins
+    pub schedule: Vec<ProfileSchedule>,
+}
+
+impl ConfigProfiles {
+    pub fn profile(&self, name: &str) -> Option<&AudioProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// The profile scheduled for `time`, if there is one
+    pub fn scheduled(&self, time: NaiveTime) -> Option<&AudioProfile> {
+        self.schedule
+            .iter()
+            .filter(|entry| entry.contains(time))
+            .find_map(|entry| self.profile(&entry.profile))
+    }
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileEvent {
+    /// The scheduled profile changed, `None` is no profile
+    Switched {
+        from: Option<String>,
+        to: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::{AudioProfile, ConfigProfiles, ProfileSchedule};
+    use crate::music_player::player::Equalizer;
+
+    #[test]
+    fn scheduled_profiles() {
+        let night = AudioProfile {
+            name: "Night".to_string(),
+            gain: -10.0,
+            equalizer: Equalizer { low: -6.0, mid: 0.0, high: 0.0 },
+            crossfade: Some(Duration::from_secs(8)),
+        };
+        let profiles = ConfigProfiles {
+            profiles: vec![night],
+            schedule: vec![ProfileSchedule {
+                profile: "Night".to_string(),
+                start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            }],
+        };
+
+        let at = |hour| profiles.scheduled(NaiveTime::from_hms_opt(hour, 30, 0).unwrap());
+        assert_eq!(at(23).unwrap().name, "Night");
+        assert_eq!(at(3).unwrap().name, "Night");
+        assert!(at(7).is_none());
+        assert!(at(12).is_none());
+
+        let equalizer = at(23).unwrap().output_equalizer();
+        assert_eq!(equalizer, Equalizer { low: -16.0, mid: -10.0, high: -10.0 });
+    }
+}
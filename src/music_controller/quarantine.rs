@@ -0,0 +1,163 @@
+//! Songs which failed to play, kept so that shuffle and auto-DJ leave them
+//! out until they're tested again, such as after installing a codec or
+//! fixing the file
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedSong {
+    pub uuid: Uuid,
+    /// Why the song last failed to play
+    pub error: String,
+    #[serde(with = "ts_milliseconds")]
+    pub first_failed: DateTime<Utc>,
+    #[serde(with = "ts_milliseconds")]
+    pub last_failed: DateTime<Utc>,
+    /// How many times the song has failed, counting re-tests
+    pub failures: u32,
+}
+
+/// The results of [Quarantine::retest]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RetestReport {
+    /// Songs which play now, and were taken out of the quarantine
+    pub released: Vec<Uuid>,
+    /// Songs which still fail, and why
+    pub failed: Vec<(Uuid, String)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quarantine {
+    pub songs: Vec<QuarantinedSong>,
+}
+
+impl Quarantine {
+    /// The location of the quarantine, which is stored in the state folder of the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.state_file("quarantine.json")
+    }
+
+    /// Record that the song failed to play with `error`
+    pub fn add(&mut self, uuid: Uuid, error: String, now: DateTime<Utc>) {
+        match self.songs.iter_mut().find(|song| song.uuid == uuid) {
+            Some(song) => {
+                song.error = error;
+                song.last_failed = now;
+                song.failures += 1;
+            }
+            None => self.songs.push(QuarantinedSong {
+                uuid,
+                error,
+                first_failed: now,
+                last_failed: now,
+                failures: 1,
+            }),
+        }
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> Option<&QuarantinedSong> {
+        self.songs.iter().find(|song| song.uuid == *uuid)
+    }
+
+    pub fn contains(&self, uuid: &Uuid) -> bool {
+        self.get(uuid).is_some()
+    }
+
+    /// Take a song out of the quarantine, such as once it has played
+    pub fn release(&mut self, uuid: &Uuid) -> Option<QuarantinedSong> {
+        let index = self.songs.iter().position(|song| song.uuid == *uuid)?;
+        Some(self.songs.remove(index))
+    }
+
+    /// Test every song again with `test`, releasing the ones which pass
+    pub fn retest<F>(&mut self, mut test: F, now: DateTime<Utc>) -> RetestReport
+    where
+        F: FnMut(&Uuid) -> Result<(), String>,
+    {
+        let mut report = RetestReport::default();
+        let uuids: Vec<Uuid> = self.songs.iter().map(|song| song.uuid).collect();
+        for uuid in uuids {
+            match test(&uuid) {
+                Ok(()) => {
+                    self.release(&uuid);
+                    report.released.push(uuid);
+                }
+                Err(error) => {
+                    self.add(uuid, error.clone(), now);
+                    report.failed.push((uuid, error));
+                }
+            }
+        }
+        report
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = path.to_path_buf();
+        writer.set_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&writer)?;
+        let quarantine = serde_json::to_string_pretty(self)?;
+
+        file.write_all(quarantine.as_bytes())?;
+        fs::rename(writer, path)?;
+        Ok(())
+    }
+
+    /// Read the quarantine file, returning an empty quarantine if it doesn't exist yet
+    pub fn read_file(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Quarantine::default());
+        }
+
+        let mut file: File = File::open(path)?;
+        let mut contents: String = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(serde_json::from_str::<Quarantine>(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::Quarantine;
+
+    #[test]
+    fn playback_quarantine() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("quarantine.json");
+        let (broken, fixed) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+
+        let mut quarantine = Quarantine::read_file(&path).unwrap();
+        quarantine.add(broken, "no decoder for audio/x-ape".to_string(), now);
+        quarantine.add(fixed, "the file or source is not found".to_string(), now);
+        quarantine.add(fixed, "the file or source is not found".to_string(), now + Duration::seconds(60));
+        quarantine.write_file(&path).unwrap();
+
+        let mut quarantine = Quarantine::read_file(&path).unwrap();
+        let song = quarantine.get(&fixed).unwrap();
+        assert_eq!(song.failures, 2);
+        assert!(song.last_failed > song.first_failed);
+
+        let report = quarantine.retest(|uuid| if *uuid == fixed { Ok(()) } else { Err("still broken".to_string()) }, now);
+        assert_eq!(report.released, vec![fixed]);
+        assert_eq!(report.failed, vec![(broken, "still broken".to_string())]);
+        assert!(!quarantine.contains(&fixed));
+        assert_eq!(quarantine.get(&broken).unwrap().error, "still broken");
+    }
+}
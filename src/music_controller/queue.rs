@@ -1,19 +1,61 @@
+use std::collections::{HashMap, HashSet};
 use std::vec::IntoIter;
 
+use kushi::{Queue, QueueError, QueueItemType, QueueState};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::music_storage::duplicates::duplicate_groups;
 use crate::music_storage::library::{Album, AlbumTrack, Song};
 
 use super::controller::PlayerLocation;
+use super::modes::shuffled_order;
+
+/// Why an item is in the queue, so that UIs can explain and filter it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum QueueSource {
+    /// Added by the user
+    #[default]
+    User,
+    /// Picked automatically to keep playback going
+    AutoDj,
+    /// The rest of an album which the user started
+    AlbumContinuation,
+    /// Queued from the playlist with this [Uuid]
+    Playlist(Uuid),
+}
+
+impl QueueSource {
+    /// Whether the item was added by the user rather than automatically
+    pub fn by_human(&self) -> bool {
+        matches!(self, Self::User)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct QueueSong {
     pub song: Song,
     pub location: PlayerLocation,
+    pub source: QueueSource,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct QueueAlbum {
     pub album: Album,
     pub location: PlayerLocation,
+    pub source: QueueSource,
+}
+
+/// A change to the queue, sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum QueueEvent {
+    Added { uuid: Uuid, index: usize, source: QueueSource },
+    Removed { uuid: Uuid, index: usize, source: QueueSource },
+    /// The queue moved on to the next song
+    Advanced { uuid: Uuid, source: QueueSource },
+    Cleared,
 }
 
 impl IntoIterator for QueueAlbum {
@@ -23,3 +65,343 @@ impl IntoIterator for QueueAlbum {
         self.album.into_iter()
     }
 }
+
+/// The queue of a [Controller](super::controller::Controller)
+pub type PlayQueue = Queue<QueueSong, QueueAlbum>;
+
+/// A change to the queue, which [apply] makes without touching the player
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueOp {
+    /// Add a song to the end of the queue
+    Add(QueueSong),
+    /// Remove the item at this index
+    Remove(usize),
+    Clear,
+    /// Play the queue in the order made from this seed
+    Shuffle(u64),
+    Unshuffle,
+}
+
+/// Make a change to a copy of the queue, returning the changed queue and
+/// the events listeners should be sent about it. The queue is unchanged
+/// if the change fails.
+pub fn apply(queue: &PlayQueue, op: QueueOp) -> Result<(PlayQueue, Vec<QueueEvent>), QueueError> {
+    let mut queue = queue.clone();
+    let mut events = Vec::new();
+    match op {
+        QueueOp::Add(song) => {
+            let (uuid, source) = (song.song.uuid, song.source);
+            queue.add_item(song, source.by_human());
+            let index = queue.items.len() - 1;
+            // New songs are played after everything already shuffled
+            if let Some(order) = &mut queue.shuffle {
+                order.push(index);
+            }
+            events.push(QueueEvent::Added { uuid, index, source });
+        }
+        QueueOp::Remove(index) => {
+            let removed = queue.remove_item(index)?;
+            if let Some(order) = &mut queue.shuffle {
+                order.retain(|&i| i != index);
+                order.iter_mut().filter(|i| **i > index).for_each(|i| *i -= 1);
+            }
+            if let QueueItemType::Single(song) = removed.item {
+                events.push(QueueEvent::Removed { uuid: song.song.uuid, index, source: song.source });
+            }
+        }
+        QueueOp::Clear => {
+            queue.clear();
+            if let Some(order) = &mut queue.shuffle {
+                order.clear();
+            }
+            events.push(QueueEvent::Cleared);
+        }
+        QueueOp::Shuffle(seed) => queue.shuffle = Some(fair_order(&queue, seed, |_| false)),
+        QueueOp::Unshuffle => queue.shuffle = None,
+    }
+    Ok((queue, events))
+}
+
+/// The songs in the queue, along with the index of each
+fn queue_songs(queue: &PlayQueue) -> Vec<(usize, &Song)> {
+    queue
+        .items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| match &item.item {
+            QueueItemType::Single(song) => Some((index, &song.song)),
+            QueueItemType::Multi(_) => None,
+        })
+        .collect()
+}
+
+/// A shuffled order of the queue which is always the same for a `seed`,
+/// where songs which are the same recording as one earlier in the order,
+/// such as from another album, are only played after everything else.
+/// Songs which are `held_back` are played last of all.
+pub(super) fn fair_order<F: Fn(&Song) -> bool>(queue: &PlayQueue, seed: u64, held_back: F) -> Vec<usize> {
+    let songs = queue_songs(queue);
+    let groups = duplicate_groups(&songs.iter().map(|(_, song)| *song).collect::<Vec<_>>());
+    let group_of: HashMap<usize, usize> = songs.iter().zip(groups).map(|((index, _), group)| (*index, group)).collect();
+
+    let held: HashSet<usize> = songs.iter().filter(|(_, song)| held_back(song)).map(|(index, _)| *index).collect();
+    let (held, order): (Vec<usize>, Vec<usize>) =
+        shuffled_order(queue.items.len(), seed).into_iter().partition(|index| held.contains(index));
+    let mut seen = HashSet::new();
+    let (first, repeats): (Vec<usize>, Vec<usize>) = order
+        .into_iter()
+        .partition(|index| group_of.get(index).is_none_or(|group| seen.insert(*group)));
+    first.into_iter().chain(repeats).chain(held).collect()
+}
+
+/// Whether `order` is a shuffled order of a queue of `len` items, with
+/// every index in it once
+pub(super) fn is_order_of(order: &[usize], len: usize) -> bool {
+    let mut seen = vec![false; len];
+    order.len() == len && order.iter().all(|&index| index < len && !std::mem::replace(&mut seen[index], true))
+}
+
+/// Pick up to `count` of the `candidates`, going through them in `order`,
+/// leaving out songs which are the same recording as one already picked,
+/// one in the queue, or one which was played from it
+pub(super) fn pick_distinct(queue: &PlayQueue, candidates: &[&Song], order: &[usize], count: usize) -> Vec<Uuid> {
+    let played = queue.played.iter().filter_map(|item| match &item.item {
+        QueueItemType::Single(song) => Some(&song.song),
+        QueueItemType::Multi(_) => None,
+    });
+    let session: Vec<&Song> = queue_songs(queue).into_iter().map(|(_, song)| song).chain(played).collect();
+    let songs: Vec<&Song> = session.iter().chain(candidates).copied().collect();
+    let groups = duplicate_groups(&songs);
+
+    let mut taken: HashSet<usize> = groups[..session.len()].iter().copied().collect();
+    order
+        .iter()
+        .filter(|&&index| taken.insert(groups[session.len() + index]))
+        .take(count)
+        .map(|&index| candidates[index].uuid)
+        .collect()
+}
+
+/// Something wrong with the state of a queue, found by [QueueInvariants::check]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum QueueViolation {
+    #[error("the song {0} is in the queue more than once")]
+    Duplicate(Uuid),
+    #[error("the shuffled order is not an order of the {len} items in the queue")]
+    InvalidShuffle { len: usize },
+    #[error("more than one item is marked as the current one")]
+    MultipleCurrent,
+    #[error("the item at {0} of the history is marked as the current one")]
+    CurrentInHistory(usize),
+    #[error("the album at {0} has no tracks")]
+    EmptyAlbum(usize),
+    #[error("the item at {0} was queued from a playlist but isn't played from it")]
+    WrongPlaylist(usize),
+}
+
+/// Rules every queue should keep, which can be checked after each change
+/// to catch bugs in queue logic early
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueInvariants {
+    /// Whether the same song can be queued more than once
+    pub allow_duplicates: bool,
+}
+
+impl QueueInvariants {
+    /// Every rule the queue breaks, which is empty for a valid queue
+    pub fn check(&self, queue: &PlayQueue) -> Vec<QueueViolation> {
+        let mut violations = Vec::new();
+
+        if !self.allow_duplicates {
+            let mut seen = HashSet::new();
+            for item in &queue.items {
+                if let QueueItemType::Single(song) = &item.item {
+                    if !seen.insert(song.song.uuid) {
+                        violations.push(QueueViolation::Duplicate(song.song.uuid));
+                    }
+                }
+            }
+        }
+
+        if let Some(order) = &queue.shuffle {
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            if !sorted.into_iter().eq(0..queue.items.len()) {
+                violations.push(QueueViolation::InvalidShuffle { len: queue.items.len() });
+            }
+        }
+
+        let current = queue.items.iter().filter(|item| item.state == QueueState::First);
+        if current.count() > 1 {
+            violations.push(QueueViolation::MultipleCurrent);
+        }
+        for (i, item) in queue.played.iter().enumerate() {
+            if item.state == QueueState::First {
+                violations.push(QueueViolation::CurrentInHistory(i));
+            }
+        }
+
+        for (i, item) in queue.items.iter().enumerate() {
+            match &item.item {
+                QueueItemType::Multi(album) if album.album.len() == 0 => violations.push(QueueViolation::EmptyAlbum(i)),
+                QueueItemType::Single(QueueSong { location, source: QueueSource::Playlist(uuid), .. })
+                    if *location != PlayerLocation::Playlist(*uuid) =>
+                {
+                    violations.push(QueueViolation::WrongPlaylist(i))
+                }
+                _ => (),
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use kushi::Queue;
+    use uuid::Uuid;
+
+    use super::{apply, fair_order, is_order_of, pick_distinct, PlayQueue, QueueEvent, QueueInvariants, QueueOp, QueueSong, QueueSource, QueueViolation};
+    use crate::music_controller::controller::PlayerLocation;
+    use crate::music_controller::session::SessionItem;
+    use crate::music_storage::library::test::test_song;
+
+    /// Random queue changes made from a seed, so failures can be repeated
+    struct OpGenerator {
+        state: u64,
+        songs: Vec<QueueSong>,
+    }
+
+    impl OpGenerator {
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+        }
+
+        fn op(&mut self, queue: &PlayQueue) -> QueueOp {
+            match self.next_u64() % 10 {
+                0..=4 => {
+                    let song = self.next_u64() as usize % self.songs.len();
+                    QueueOp::Add(self.songs[song].clone())
+                }
+                // Sometimes past the end, which must fail without changing anything
+                5..=7 => QueueOp::Remove(self.next_u64() as usize % (queue.items.len() + 2)),
+                8 => QueueOp::Shuffle(self.next_u64()),
+                _ => match self.next_u64() % 3 {
+                    0 => QueueOp::Clear,
+                    _ => QueueOp::Unshuffle,
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn queue_sources() {
+        assert!(QueueSource::User.by_human());
+        assert!(!QueueSource::AlbumContinuation.by_human());
+
+        let playlist = QueueSource::Playlist(Uuid::new_v4());
+        let json = serde_json::to_string(&playlist).unwrap();
+        assert_eq!(serde_json::from_str::<QueueSource>(&json).unwrap(), playlist);
+
+        // Sessions saved before sources existed are treated as added by the user
+        let uuid = Uuid::new_v4();
+        let old = format!(r#"{{"uuid": "{}", "location": "Library", "by_human": true}}"#, uuid);
+        let item: SessionItem = serde_json::from_str(&old).unwrap();
+        assert_eq!(item.source, QueueSource::User);
+    }
+
+    #[test]
+    fn queue_invariants() {
+        let playlist = Uuid::new_v4();
+        let songs: Vec<_> = (0..6)
+            .map(|i| {
+                let (location, source) = match i % 3 {
+                    0 => (PlayerLocation::Playlist(playlist), QueueSource::Playlist(playlist)),
+                    1 => (PlayerLocation::Library, QueueSource::AutoDj),
+                    _ => (PlayerLocation::Library, QueueSource::User),
+                };
+                let song = test_song(&format!("Song {}", i), "Artist", Duration::from_secs(60));
+                QueueSong { song, location, source }
+            })
+            .collect();
+        let invariants = QueueInvariants { allow_duplicates: true };
+
+        for seed in 1..=50 {
+            let mut generator = OpGenerator { state: seed, songs: songs.clone() };
+            let mut queue: PlayQueue = Queue::new();
+            for _ in 0..100 {
+                let op = generator.op(&queue);
+                match apply(&queue, op.clone()) {
+                    Ok((changed, events)) => {
+                        if let QueueOp::Add(song) = &op {
+                            let added = QueueEvent::Added { uuid: song.song.uuid, index: changed.items.len() - 1, source: song.source };
+                            assert_eq!(events, [added]);
+                        }
+                        queue = changed;
+                    }
+                    Err(_) => assert!(matches!(op, QueueOp::Remove(index) if index >= queue.items.len())),
+                }
+                assert_eq!(invariants.check(&queue), [], "seed {} after {:?}", seed, op);
+            }
+        }
+
+        // Broken queues are caught
+        let mut queue: PlayQueue = Queue::new();
+        let song = QueueSong { location: PlayerLocation::Library, ..songs[0].clone() };
+        queue = apply(&queue, QueueOp::Add(song.clone())).unwrap().0;
+        queue = apply(&queue, QueueOp::Add(song.clone())).unwrap().0;
+        queue.shuffle = Some(vec![0, 0]);
+        assert_eq!(
+            QueueInvariants::default().check(&queue),
+            [
+                QueueViolation::Duplicate(song.song.uuid),
+                QueueViolation::InvalidShuffle { len: 2 },
+                QueueViolation::WrongPlaylist(0),
+                QueueViolation::WrongPlaylist(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates_in_session() {
+        let song = |title: &str| {
+            let song = test_song(title, "Artist", Duration::from_secs(200));
+            QueueSong { song, location: PlayerLocation::Library, source: QueueSource::User }
+        };
+        // The same recording on an album and a compilation
+        let songs = [song("One"), song("Two"), song("One"), song("Three"), song("two")];
+        let mut queue: PlayQueue = Queue::new();
+        for song in &songs {
+            queue = apply(&queue, QueueOp::Add(song.clone())).unwrap().0;
+        }
+
+        for seed in 1..=20 {
+            let order = fair_order(&queue, seed, |_| false);
+            assert!(is_order_of(&order, 5));
+            // Every recording is played before any of them is played again
+            let mut first: Vec<usize> = order[..3].iter().map(|i| [0, 1, 0, 2, 1][*i]).collect();
+            first.sort();
+            assert_eq!(first, [0, 1, 2]);
+        }
+        // Held back songs come after everything else
+        let order = fair_order(&queue, 7, |song| song.uuid == songs[3].song.uuid);
+        assert_eq!(order.last(), Some(&3));
+        // Orders saved for a queue which has changed since
+        assert!(!is_order_of(&order, 4));
+        assert!(!is_order_of(&[0, 1, 1, 3, 4], 5));
+        assert!(!is_order_of(&[0, 1, 2, 3, 5], 5));
+
+        let mut queue: PlayQueue = Queue::new();
+        queue = apply(&queue, QueueOp::Add(songs[0].clone())).unwrap().0;
+        let candidates: Vec<_> = songs[1..].iter().map(|song| &song.song).collect();
+        let picked = pick_distinct(&queue, &candidates, &[0, 1, 2, 3], 5);
+        assert_eq!(picked, [songs[1].song.uuid, songs[3].song.uuid]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Working out the ReplayGain to apply to a song, keeping the gain from
+//! pushing the peak of the song above full scale
+
+use serde::{Deserialize, Serialize};
+
+use crate::music_player::player::Player;
+use crate::music_storage::library::{Song, Tag};
+
+use super::modes::Normalization;
+
+/// What to do when the gain would make a song clip
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClippingPolicy {
+    /// Lower the gain until the peak is at full scale
+    #[default]
+    ReduceGain,
+    /// Keep the gain and use a limiter to squash the peaks
+    Limiter,
+    /// Keep the gain and let it clip
+    Allow,
+}
+
+/// How ReplayGain is applied, stored in the config
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigReplayGain {
+    /// Added to the gain of every song with ReplayGain tags, in dB
+    pub preamp: f64,
+    pub clipping: ClippingPolicy,
+}
+
+/// The ReplayGain tags of a song, gains are in dB and peaks are linear
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+    /// The [volume adjustment](Song::volume_adjustment) of the song in
+    /// dB, added on top of the ReplayGain
+    pub offset: f64,
+}
+
+/// The gain which was applied to a song, reported with what is playing
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AppliedGain {
+    /// The gain from the tags plus the pre-amp, in dB
+    pub requested: f64,
+    /// The gain which is actually used, in dB, including the `offset`
+    pub applied: f64,
+    /// The volume adjustment of the song, in dB
+    pub offset: f64,
+    /// The peak of the song after the gain, where `1` is full scale
+    pub peak: f64,
+    /// Whether the limiter is stopping the song from clipping
+    pub limited: bool,
+}
+
+/// The gain in dB of a volume adjustment from `-100`% to `100`%
+pub fn adjustment_gain(adjustment: i8) -> f64 {
+    let scale = 1.0 + adjustment.clamp(-100, 100) as f64 / 100.0;
+    (20.0 * scale.log10()).max(-100.0)
+}
+
+/// Parse a number from a tag such as `-6.54 dB`
+fn parse_tag(song: &Song, key: &str) -> Option<f64> {
+    song.get_tag(&Tag::Key(key.to_string()))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl ReplayGain {
+    pub fn from_song(song: &Song) -> Self {
+        ReplayGain {
+            track_gain: parse_tag(song, "ReplayGainTrackGain"),
+            track_peak: parse_tag(song, "ReplayGainTrackPeak"),
+            album_gain: parse_tag(song, "ReplayGainAlbumGain"),
+            album_peak: parse_tag(song, "ReplayGainAlbumPeak"),
+            offset: adjustment_gain(song.volume_adjustment()),
+        }
+    }
+
+    /// The gain and peak for a normalization mode, using the other
+    /// mode's values if the song doesn't have them
+    fn gain_and_peak(&self, normalization: Normalization) -> Option<(f64, Option<f64>)> {
+        let track = self.track_gain.map(|gain| (gain, self.track_peak));
+        let album = self.album_gain.map(|gain| (gain, self.album_peak));
+        match normalization {
+            Normalization::Off => None,
+            Normalization::Track => track.or(album),
+            Normalization::Album => album.or(track),
+        }
+    }
+
+    /// Work out the gain to apply, `None` if no gain should be applied.
+    ///
+    /// Songs without a peak are treated as already peaking at full scale,
+    /// so with [ClippingPolicy::ReduceGain] they are never made louder.
+    /// The offset is chosen by the listener, so it's added after the gain
+    /// is reduced, and can still make the song clip.
+    pub fn apply(&self, normalization: Normalization, config: &ConfigReplayGain) -> Option<AppliedGain> {
+        let tags = self.gain_and_peak(normalization);
+        if tags.is_none() && self.offset == 0.0 {
+            return None;
+        }
+        let (requested, peak) = tags.map_or((0.0, None), |(gain, peak)| (gain + config.preamp, peak));
+        let peak = peak.unwrap_or(1.0);
+        let scale = |gain: f64| peak * 10f64.powf(gain / 20.0);
+
+        let reduced = match (scale(requested) > 1.0, config.clipping) {
+            (true, ClippingPolicy::ReduceGain) if peak > 0.0 => -20.0 * peak.log10(),
+            _ => requested,
+        };
+        let applied = reduced + self.offset;
+        Some(AppliedGain {
+            requested,
+            applied,
+            offset: self.offset,
+            peak: scale(applied),
+            limited: scale(applied) > 1.0 && config.clipping == ClippingPolicy::Limiter,
+        })
+    }
+}
+
+/// Set the gain of the player for a song with the given ReplayGain, or
+/// remove the gain if there is none, returning the gain which was applied
+pub(super) fn set_player_gain<P: Player>(
+    player: &mut P,
+    replay_gain: Option<ReplayGain>,
+    normalization: Normalization,
+    config: &ConfigReplayGain,
+) -> Option<AppliedGain> {
+    let applied = replay_gain.and_then(|replay_gain| replay_gain.apply(normalization, config));
+    let (gain, limiter) = applied.map_or((0.0, false), |applied| (applied.applied, applied.limited));
+    if let Err(error) = player.set_gain(gain, limiter) {
+        println!("Failed to set the ReplayGain: {}", error);
+    }
+    applied
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{adjustment_gain, ClippingPolicy, ConfigReplayGain, ReplayGain};
+    use crate::music_controller::modes::Normalization;
+    use crate::music_storage::library::{test::test_song, Tag};
+
+    #[test]
+    fn replay_gain_clipping() {
+        let mut song = test_song("Song", "Artist", Duration::from_secs(60));
+        song.set_tag(Tag::Key("ReplayGainTrackGain".into()), "-3.00 dB".into());
+        song.set_tag(Tag::Key("ReplayGainAlbumGain".into()), "+2.00 dB".into());
+        song.set_tag(Tag::Key("ReplayGainAlbumPeak".into()), "0.891251".into());
+        let gain = ReplayGain::from_song(&song);
+        assert_eq!(gain.track_gain, Some(-3.0));
+        assert_eq!(gain.track_peak, None);
+
+        let mut config = ConfigReplayGain { preamp: 0.0, clipping: ClippingPolicy::ReduceGain };
+        assert!(gain.apply(Normalization::Off, &config).is_none());
+
+        // The track has no peak, so cutting it is fine but it can't be boosted
+        let track = gain.apply(Normalization::Track, &config).unwrap();
+        assert_eq!(track.applied, -3.0);
+        config.preamp = 6.0;
+        let track = gain.apply(Normalization::Track, &config).unwrap();
+        assert_eq!((track.requested, track.applied), (3.0, 0.0));
+
+        // The album peak is at -1 dBFS, so it can only be raised by 1 dB
+        let album = gain.apply(Normalization::Album, &config).unwrap();
+        assert_eq!(album.requested, 8.0);
+        assert!((album.applied - 1.0).abs() < 0.001);
+        assert!((album.peak - 1.0).abs() < 0.001);
+
+        config.clipping = ClippingPolicy::Limiter;
+        let album = gain.apply(Normalization::Album, &config).unwrap();
+        assert_eq!(album.applied, 8.0);
+        assert!(album.limited && album.peak > 1.0);
+
+        // A quiet song boosted by the listener, with or without ReplayGain
+        song.set_volume_adjustment(100);
+        let gain = ReplayGain::from_song(&song);
+        assert!((gain.offset - 6.0206).abs() < 0.001);
+        let track = gain.apply(Normalization::Track, &config).unwrap();
+        assert!((track.applied - (track.requested + gain.offset)).abs() < 0.001);
+        let off = gain.apply(Normalization::Off, &config).unwrap();
+        assert_eq!((off.requested, off.applied), (0.0, gain.offset));
+        assert_eq!(adjustment_gain(-100), -100.0);
+    }
+}
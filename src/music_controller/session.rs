@@ -0,0 +1,117 @@
+//! Persistence of the playback session, so that the player can resume
+//! where it left off when it is next opened
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use kushi::{Queue, QueueItemType};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::music_player::player::Player;
+
+use super::controller::PlayerLocation;
+use super::modes::PlaybackModes;
+use super::queue::{QueueAlbum, QueueSong, QueueSource};
+
+/// A single song in the saved queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionItem {
+    pub uuid: Uuid,
+    pub location: PlayerLocation,
+    pub by_human: bool,
+    #[serde(default)]
+    pub source: QueueSource,
+}
+
+/// The state of playback at the time it was saved
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub queue: Vec<SessionItem>,
+    pub current: Option<Uuid>,
+    pub position: Option<Duration>,
+    pub volume: f64,
+    pub loop_: bool,
+    pub shuffle: Option<Vec<usize>>,
+    pub modes: PlaybackModes,
+}
+
+impl Session {
+    /// The location of the session file, which is stored in the state folder of the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.state_file("session.json")
+    }
+
+    /// Capture the current state of the queue and player.
+    ///
+    /// Albums in the queue are not saved, only single songs.
+    pub fn capture<P: Player>(
+        queue: &Queue<QueueSong, QueueAlbum>,
+        player: &P,
+        modes: &PlaybackModes,
+    ) -> Self {
+        let mut current = None;
+        let items = queue
+            .items
+            .iter()
+            .filter_map(|item| match &item.item {
+                QueueItemType::Single(song) => {
+                    let playing = song
+                        .song
+                        .location
+                        .iter()
+                        .any(|uri| Some(uri) == player.source().as_ref());
+                    if current.is_none() && playing {
+                        current = Some(song.song.uuid);
+                    }
+                    Some(SessionItem {
+                        uuid: song.song.uuid,
+                        location: song.location,
+                        by_human: item.by_human,
+                        source: song.source,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        Session {
+            queue: items,
+            current,
+            position: player.position().and_then(|pos| pos.to_std().ok()),
+            volume: player.volume(),
+            loop_: queue.loop_,
+            shuffle: queue.shuffle.clone(),
+            modes: *modes,
+        }
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = path.to_path_buf();
+        writer.set_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&writer)?;
+        let session = to_string_pretty(self)?;
+
+        file.write_all(session.as_bytes())?;
+        fs::rename(writer, path)?;
+        Ok(())
+    }
+
+    pub fn read_file(path: &Path) -> Result<Self, Error> {
+        let mut file: File = File::open(path)?;
+        let mut bun: String = String::new();
+        file.read_to_string(&mut bun)?;
+        let session: Session = serde_json::from_str::<Session>(&bun)?;
+        Ok(session)
+    }
+}
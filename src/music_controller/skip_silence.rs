@@ -0,0 +1,83 @@
+//! Which kinds of track have their gaps of silence skipped through, which
+//! is usually only wanted for spoken content such as podcasts and audiobooks
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::music_storage::library::Song;
+
+/// The kinds of track silence skipping is set for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrackKind {
+    Music,
+    Audiobook,
+    Podcast,
+}
+
+impl TrackKind {
+    /// The kind of a song in the library, which is never a podcast as
+    /// episodes aren't kept there
+    pub fn of(song: &Song) -> Self {
+        match song.is_audiobook() {
+            true => TrackKind::Audiobook,
+            false => TrackKind::Music,
+        }
+    }
+}
+
+/// When silence is skipped, stored in the config and used from the next
+/// track played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigSkipSilence {
+    pub music: bool,
+    pub audiobooks: bool,
+    pub podcasts: bool,
+    /// Gaps of silence shorter than this are played
+    pub minimum_gap: Duration,
+}
+
+impl Default for ConfigSkipSilence {
+    fn default() -> Self {
+        ConfigSkipSilence {
+            music: false,
+            audiobooks: false,
+            podcasts: false,
+            minimum_gap: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ConfigSkipSilence {
+    /// The shortest gap skipped in a track of `kind`, `None` if silence
+    /// isn't skipped in it
+    pub fn minimum_gap(&self, kind: TrackKind) -> Option<Duration> {
+        let skip = match kind {
+            TrackKind::Music => self.music,
+            TrackKind::Audiobook => self.audiobooks,
+            TrackKind::Podcast => self.podcasts,
+        };
+        skip.then_some(self.minimum_gap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ConfigSkipSilence, TrackKind};
+    use crate::music_storage::library::{test::test_song, InternalTag, SongType};
+
+    #[test]
+    fn skip_silence_kinds() {
+        let config = ConfigSkipSilence { podcasts: true, audiobooks: true, ..Default::default() };
+        assert_eq!(config.minimum_gap(TrackKind::Podcast), Some(Duration::from_millis(500)));
+        assert_eq!(config.minimum_gap(TrackKind::Music), None);
+
+        let mut song = test_song("Chapter 1", "Narrator", Duration::from_secs(600));
+        assert_eq!(TrackKind::of(&song), TrackKind::Music);
+        song.internal_tags.push(InternalTag::SongType(SongType::Audiobook));
+        assert_eq!(config.minimum_gap(TrackKind::of(&song)), Some(Duration::from_millis(500)));
+    }
+}
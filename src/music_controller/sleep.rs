@@ -0,0 +1,129 @@
+//! Stopping playback after a while, for falling asleep to music
+
+use std::time::{Duration, Instant};
+
+/// How long [SleepMode::FadeOut] takes to fade the volume out before pausing
+pub const SLEEP_FADE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    /// Fade the volume out so it's silent when the time is up, then pause
+    FadeOut,
+    /// Once the time is up, stop when the current song finishes
+    StopAfterTrack,
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepEvent {
+    /// The volume started fading out, playback pauses in `remaining`
+    Fading { remaining: Duration },
+    /// The time is up, and playback stops when the current song finishes
+    StoppingAfterTrack,
+    /// Playback was stopped by the timer
+    Stopped,
+    Cancelled,
+}
+
+/// What the player should do for a [SleepTimer]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepAction {
+    SetVolume(f64),
+    /// Pause, then put the volume back to what it was before fading
+    Pause { restore: f64 },
+    StopAfterTrack,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepTimer {
+    ends: Instant,
+    mode: SleepMode,
+    /// The volume before the fade started
+    faded_from: Option<f64>,
+    /// Whether the time is up and the current track is the last one
+    armed: bool,
+}
+
+impl SleepTimer {
+    pub fn new(now: Instant, after: Duration, mode: SleepMode) -> Self {
+        SleepTimer {
+            ends: now + after,
+            mode,
+            faded_from: None,
+            armed: false,
+        }
+    }
+
+    pub fn mode(&self) -> SleepMode {
+        self.mode
+    }
+
+    /// How long until the time is up
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.ends.saturating_duration_since(now)
+    }
+
+    /// Whether the time is up and playback stops after the current song
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Whether the volume is being faded
+    pub fn is_fading(&self) -> bool {
+        self.faded_from.is_some()
+    }
+
+    /// What to do at `now`, where `volume` is the current volume of the player
+    pub fn check(&mut self, now: Instant, volume: f64) -> Option<SleepAction> {
+        let remaining = self.remaining(now);
+        match self.mode {
+            SleepMode::FadeOut if remaining > SLEEP_FADE => None,
+            SleepMode::FadeOut => {
+                let from = *self.faded_from.get_or_insert(volume);
+                match remaining.is_zero() {
+                    true => Some(SleepAction::Pause { restore: from }),
+                    false => Some(SleepAction::SetVolume(from * remaining.as_secs_f64() / SLEEP_FADE.as_secs_f64())),
+                }
+            }
+            SleepMode::StopAfterTrack if remaining.is_zero() && !self.armed => {
+                self.armed = true;
+                Some(SleepAction::StopAfterTrack)
+            }
+            SleepMode::StopAfterTrack => None,
+        }
+    }
+
+    /// Stop the timer, returning the volume to put back if it was fading
+    pub fn cancel(self) -> Option<f64> {
+        self.faded_from
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{SleepAction, SleepMode, SleepTimer, SLEEP_FADE};
+
+    #[test]
+    fn sleep_timer() {
+        let start = Instant::now();
+        let mut timer = SleepTimer::new(start, Duration::from_secs(600), SleepMode::FadeOut);
+        assert_eq!(timer.remaining(start + Duration::from_secs(100)), Duration::from_secs(500));
+        assert_eq!(timer.check(start + Duration::from_secs(100), 0.8), None);
+
+        let fading = start + Duration::from_secs(600) - SLEEP_FADE / 2;
+        assert_eq!(timer.check(fading, 0.8), Some(SleepAction::SetVolume(0.4)));
+        // The fade is worked out from the volume before it started
+        assert_eq!(timer.check(fading, 0.4), Some(SleepAction::SetVolume(0.4)));
+        assert_eq!(timer.check(start + Duration::from_secs(601), 0.0), Some(SleepAction::Pause { restore: 0.8 }));
+        assert_eq!(timer.cancel(), Some(0.8));
+
+        let mut timer = SleepTimer::new(start, Duration::from_secs(60), SleepMode::StopAfterTrack);
+        assert_eq!(timer.check(start + Duration::from_secs(59), 1.0), None);
+        assert_eq!(timer.check(start + Duration::from_secs(60), 1.0), Some(SleepAction::StopAfterTrack));
+        assert_eq!(timer.check(start + Duration::from_secs(61), 1.0), None);
+        assert!(timer.is_armed());
+        assert_eq!(timer.cancel(), None);
+    }
+}
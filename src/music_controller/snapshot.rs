@@ -0,0 +1,166 @@
+//! A serializable snapshot of everything the controller is doing, taken
+//! all at once so that its parts agree with each other. Used for
+//! debugging and to check behavior in tests.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use kushi::{Queue, QueueItemType};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::music_player::player::Player;
+use crate::music_storage::library::{MusicLibrary, Tag, URI};
+
+use super::controller::PlayerLocation;
+use super::history::HistoryEntry;
+use super::modes::PlaybackModes;
+use super::queue::{QueueAlbum, QueueSong, QueueSource};
+use super::replaygain::AppliedGain;
+
+/// A single item in the queue
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueItemSnapshot {
+    /// The songs of the item, more than one for albums
+    pub songs: Vec<Uuid>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub location: PlayerLocation,
+    pub source: QueueSource,
+    pub by_human: bool,
+}
+
+/// What the player is doing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NowPlaying {
+    pub uri: URI,
+    /// The song in the library being played, if it is in the library
+    pub uuid: Option<Uuid>,
+    pub position: Option<Duration>,
+    pub duration: Option<Duration>,
+    pub paused: bool,
+    pub volume: f64,
+    /// The ReplayGain applied to the song
+    pub gain: Option<AppliedGain>,
+}
+
+impl NowPlaying {
+    /// Capture the state of the player, `None` if nothing is loaded
+    pub fn capture<P: Player>(player: &P, library: &MusicLibrary, gain: Option<AppliedGain>) -> Option<Self> {
+        let uri = player.source().clone()?;
+        Some(NowPlaying {
+            uuid: library.query_uri(&uri).map(|(song, _)| song.uuid),
+            uri,
+            position: player.position().and_then(|pos| pos.to_std().ok()),
+            duration: player.duration().and_then(|dur| dur.to_std().ok()),
+            paused: player.is_paused(),
+            volume: player.volume(),
+            gain,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StateSnapshot {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub time: DateTime<Utc>,
+    pub queue: Vec<QueueItemSnapshot>,
+    /// The number of items which have already been played
+    pub played: usize,
+    pub modes: PlaybackModes,
+    /// Whether plays are kept out of the history and scrobbles
+    pub private_session: bool,
+    pub now_playing: Option<NowPlaying>,
+    /// The most recent plays, oldest first
+    pub history: Vec<HistoryEntry>,
+}
+
+impl StateSnapshot {
+    pub fn capture(
+        queue: &Queue<QueueSong, QueueAlbum>,
+        modes: &PlaybackModes,
+        private_session: bool,
+        now_playing: Option<NowPlaying>,
+        history: Vec<HistoryEntry>,
+    ) -> Self {
+        let items = queue
+            .items
+            .iter()
+            .map(|item| match &item.item {
+                QueueItemType::Single(song) => QueueItemSnapshot {
+                    songs: vec![song.song.uuid],
+                    title: song.song.get_tag(&Tag::Title).cloned(),
+                    artist: song.song.get_tag(&Tag::Artist).cloned(),
+                    location: song.location,
+                    source: song.source,
+                    by_human: item.by_human,
+                },
+                QueueItemType::Multi(album) => QueueItemSnapshot {
+                    songs: album
+                        .album
+                        .discs()
+                        .values()
+                        .flat_map(|tracks| tracks.iter().map(|(_, uuid)| *uuid))
+                        .collect(),
+                    title: Some(album.album.title().clone()),
+                    artist: album.album.artist().clone(),
+                    location: album.location,
+                    source: album.source,
+                    by_human: item.by_human,
+                },
+            })
+            .collect();
+
+        StateSnapshot {
+            time: Utc::now(),
+            queue: items,
+            played: queue.played.len(),
+            modes: *modes,
+            private_session,
+            now_playing,
+            history,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use kushi::Queue;
+
+    use super::StateSnapshot;
+    use crate::music_controller::controller::PlayerLocation;
+    use crate::music_controller::modes::{PlaybackModes, RepeatMode};
+    use crate::music_controller::queue::{QueueSong, QueueSource};
+    use crate::music_storage::library::test::test_song;
+
+    #[test]
+    fn state_snapshot() {
+        let mut queue = Queue {
+            items: Vec::new(),
+            played: Vec::new(),
+            loop_: true,
+            shuffle: None,
+        };
+        let song = test_song("Song", "Artist", Duration::from_secs(60));
+        let uuid = song.uuid;
+        queue.add_item(
+            QueueSong { song, location: PlayerLocation::Library, source: QueueSource::AutoDj },
+            false,
+        );
+        let modes = PlaybackModes { repeat: RepeatMode::All, ..Default::default() };
+
+        let snapshot = StateSnapshot::capture(&queue, &modes, false, None, Vec::new());
+        assert_eq!(snapshot.queue[0].songs, vec![uuid]);
+        assert_eq!(snapshot.queue[0].source, QueueSource::AutoDj);
+
+        let json = snapshot.to_json().unwrap();
+        assert!(json.contains("\"AutoDj\""));
+        assert!(json.contains("\"repeat\": \"All\""));
+    }
+}
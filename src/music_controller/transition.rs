@@ -0,0 +1,78 @@
+//! Announcing the end of each track ahead of time, so scrobblers,
+//! auto-DJs, and prefetchers know when the next one is coming
+//!
+//! Every track is announced exactly once, the
+//! [lead](crate::config::Config::transition_lead) before it ends. While
+//! crossfading the next track starts before the current one ends, so the
+//! crossfade is added on top.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::music_player::player::{Crossfade, FadeCurve, TRANSITION_LEAD};
+
+/// The shape of the crossfade, stored in the config. How long it is comes
+/// from the [playback modes](super::modes::PlaybackModes::crossfade),
+/// unless the fades are given their own lengths here.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigCrossfade {
+    pub curve: FadeCurve,
+    pub fade_out: Option<Duration>,
+    pub fade_in: Option<Duration>,
+}
+
+impl ConfigCrossfade {
+    /// The crossfade to play with, where `crossfade` is the length from the
+    /// playback modes. There is none while crossfading is off.
+    pub fn crossfade(&self, crossfade: Option<Duration>) -> Option<Crossfade> {
+        let length = crossfade?;
+        Some(Crossfade {
+            curve: self.curve,
+            fade_out: self.fade_out.unwrap_or(length),
+            fade_in: self.fade_in.unwrap_or(length),
+        })
+    }
+}
+
+/// Sent to [Controller](super::controller::Controller) listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionEvent {
+    /// The current song ends in `remaining`, `uuid` is `None` for songs
+    /// which aren't in the library
+    Ahead { uuid: Option<Uuid>, remaining: Duration },
+}
+
+/// How long before the end tracks are announced, where `lead` is the lead
+/// from the config, if one is set
+pub fn transition_lead(lead: Option<Duration>, crossfade: Option<Duration>) -> Duration {
+    lead.unwrap_or(TRANSITION_LEAD) + crossfade.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{transition_lead, ConfigCrossfade};
+    use crate::music_player::player::{FadeCurve, TRANSITION_LEAD};
+
+    #[test]
+    fn lead_with_crossfade() {
+        assert_eq!(transition_lead(None, None), TRANSITION_LEAD);
+        assert_eq!(
+            transition_lead(Some(Duration::from_secs(3)), Some(Duration::from_secs(8))),
+            Duration::from_secs(11)
+        );
+    }
+
+    #[test]
+    fn crossfade_lengths() {
+        let config = ConfigCrossfade { curve: FadeCurve::SCurve, fade_in: Some(Duration::from_secs(2)), ..Default::default() };
+        assert_eq!(config.crossfade(None), None);
+        let crossfade = config.crossfade(Some(Duration::from_secs(8))).unwrap();
+        assert_eq!((crossfade.fade_out, crossfade.fade_in), (Duration::from_secs(8), Duration::from_secs(2)));
+        assert_eq!(crossfade.curve, FadeCurve::SCurve);
+    }
+}
@@ -1,7 +1,9 @@
 // Crate things
 use crate::music_storage::library::URI;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use std::collections::HashSet;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 // GStreamer things
@@ -13,7 +15,7 @@ use gstreamer::prelude::*;
 // Extra things
 use chrono::Duration;
 
-use super::player::{Player, PlayerCommand, PlayerError, PlayerState};
+use super::player::{cap_volume, heard_position, AudioOutput, Crossfade, Equalizer, LoadHandle, PauseFade, Player, PlayerCommand, PlayerError, PlayerState, PlayerTimeouts, PcmFrame, PcmSamples, RemovedDevice, SeekMode, SkippedSilence, StreamInfo, Visualizer, VisualizerData, VolumeCurve, FADE_STEP, POSITION_POLL_INTERVAL, TRANSITION_LEAD, VISUALIZER_FLOOR};
 
 impl From<gst::State> for PlayerState {
     fn from(value: gst::State) -> Self {
@@ -42,6 +44,14 @@ impl TryInto<gst::State> for PlayerState {
     type Error = Box<dyn Error>;
 }
 
+/// How many frames of the PCM tap are kept for the frontend before new
+/// frames are dropped
+const PCM_BUFFER: usize = 64;
+
+/// How much visualizer data is kept for the frontend before new data is
+/// dropped
+const VISUALIZER_BUFFER: usize = 16;
+
 #[derive(Debug, PartialEq, Eq)]
 enum PlaybackInfo {
     Idle,
@@ -51,25 +61,166 @@ enum PlaybackInfo {
         end:   Duration,
     },
 
+    /// Playing a live stream, which has no end
+    Streaming,
+
     /// When this is sent, the thread will die! Use it when the [Player] is
     /// done playing
     Finished
 }
 
+/// The elements which the output of the playbin is sent through
+#[derive(Debug, Default)]
+struct OutputFilters {
+    bin:       Option<Element>,
+    gain:      Option<Element>,
+    /// The volume of the crossfade, separate from the volume of the player
+    fade:      Option<Element>,
+    limiter:   Option<Element>,
+    equalizer: Option<Element>,
+    /// Pans between the left and right speakers
+    balance:   Option<Element>,
+    /// Limits the channels to one while downmixing to mono
+    channels:  Option<Element>,
+    /// Removes gaps of silence while skipping them
+    silence:   Option<Element>,
+    spectrum:  Option<Element>,
+    level:     Option<Element>,
+}
+
+impl OutputFilters {
+    /// Build the filters with a `chain` of custom elements before the
+    /// visualizer, leaving out the limiter, silence skipping, the
+    /// visualizer, and then everything else if their plugins are not installed
+    fn build(chain: Option<&str>) -> Self {
+        let chain = chain.map_or(String::new(), |chain| format!(" ! audioconvert ! {} ! audioconvert", chain));
+        let limiter = " ! rglimiter name=limiter enabled=false";
+        let silence = " ! removesilence name=silence remove=false silent=false ! audioconvert";
+        let visualizer = format!(
+            " ! spectrum name=spectrum post-messages=false threshold={} ! level name=level post-messages=false",
+            VISUALIZER_FLOOR as i32
+        );
+        // Every combination of the optional filters, keeping the visualizer
+        // over silence skipping, and both over the limiter
+        for optional in (0..8).rev() {
+            let pick = |bit: u32, filter: &str| if optional & bit != 0 { filter.to_string() } else { String::new() };
+            let description = format!(
+                "audioconvert ! capsfilter name=channels ! audioconvert{} ! volume name=gain{} ! equalizer-3bands name=equalizer \
+                 ! volume name=fade ! audiopanorama name=balance method=simple{}{} ! audioconvert",
+                pick(2, silence),
+                pick(1, limiter),
+                chain,
+                pick(4, &visualizer)
+            );
+            if let Ok(bin) = gst::parse_bin_from_description(&description, true) {
+                return OutputFilters {
+                    gain: bin.by_name("gain"),
+                    fade: bin.by_name("fade"),
+                    limiter: bin.by_name("limiter"),
+                    equalizer: bin.by_name("equalizer"),
+                    balance: bin.by_name("balance"),
+                    channels: bin.by_name("channels"),
+                    silence: bin.by_name("silence"),
+                    spectrum: bin.by_name("spectrum"),
+                    level: bin.by_name("level"),
+                    bin: Some(bin.upcast()),
+                };
+            }
+        }
+        println!("The gain and equalizer plugins are not installed, they are disabled");
+        OutputFilters::default()
+    }
+
+    /// Copy the settings of each filter to the same filter of `to`
+    fn copy_settings(&self, to: &OutputFilters) {
+        let filters = [
+            (&self.gain, &to.gain),
+            (&self.fade, &to.fade),
+            (&self.limiter, &to.limiter),
+            (&self.equalizer, &to.equalizer),
+            (&self.balance, &to.balance),
+            (&self.channels, &to.channels),
+            (&self.silence, &to.silence),
+            (&self.spectrum, &to.spectrum),
+            (&self.level, &to.level),
+        ];
+        for (from, to) in filters {
+            let (Some(from), Some(to)) = (from, to) else {
+                continue;
+            };
+            for property in from.list_properties().iter() {
+                let flags = property.flags();
+                if flags.contains(glib::ParamFlags::READWRITE)
+                    && !flags.contains(glib::ParamFlags::CONSTRUCT_ONLY)
+                    && !["name", "parent"].contains(&property.name())
+                {
+                    to.set_property_from_value(property.name(), &from.property_value(property.name()));
+                }
+            }
+        }
+    }
+}
+
+/// What the playback monitor does to the current track as it plays
+#[derive(Debug, Default)]
+struct TrackEffects {
+    /// The part of the track which is repeated, from its start
+    loop_region: Option<(Duration, Duration)>,
+    crossfade:   Option<Crossfade>,
+    /// The volume element the crossfade is applied to
+    fade:        Option<Element>,
+}
+
 /// An instance of a music player with a GStreamer backend
 #[derive(Debug)]
 pub struct GStreamer {
     source:     Option<URI>,
 
     message_rx: crossbeam::channel::Receiver<PlayerCommand>,
+    message_tx: crossbeam::channel::Sender<PlayerCommand>,
+    visualizer_rx: crossbeam::channel::Receiver<VisualizerData>,
+    pcm_rx:     crossbeam::channel::Receiver<PcmFrame>,
+    pcm_tx:     crossbeam::channel::Sender<PcmFrame>,
+    /// The probe sending decoded audio to `pcm_tx`, while the tap is on
+    pcm_probe:  Option<gst::PadProbeId>,
     playback_tx: crossbeam::channel::Sender<PlaybackInfo>,
 
     playbin:    Arc<RwLock<Element>>,
     volume:     f64,
-    start:      Option<Duration>,
-    end:        Option<Duration>,
+    volume_cap: f64,
+    /// The volume from before muting, while muted
+    unmuted:    Option<f64>,
+    volume_curve: VolumeCurve,
+    pause_fade: PauseFade,
+    seek_mode:  SeekMode,
+    /// How long the output takes to be heard
+    latency:    std::time::Duration,
+    /// `Some` while scrubbing, with the last position scrubbed to
+    scrub:      Option<Option<Duration>>,
+    /// The elements the output goes through, if their plugins are installed
+    filters:    OutputFilters,
+    /// The part of the next local track to play, see [Player::set_next_trim]
+    trim:       Option<(std::time::Duration, std::time::Duration)>,
+    /// The start and end of the current track within its file
+    bounds:     Arc<RwLock<Option<(Duration, Duration)>>>,
+    effects:    Arc<RwLock<TrackEffects>>,
+    /// The codec and bitrate of the current track, from its tags
+    stream:     Arc<RwLock<StreamInfo>>,
+    /// Whether the source is a stream, the only kind whose tags are sent
+    /// as [PlayerCommand::TagsChanged]
+    streaming:  Arc<AtomicBool>,
+    skipped:    Arc<RwLock<SkippedSilence>>,
+    timeouts:   PlayerTimeouts,
     paused:     Arc<RwLock<bool>>,
     position:   Arc<RwLock<Option<Duration>>>,
+    /// How often the position is checked, in milliseconds
+    poll_interval: Arc<AtomicU64>,
+    /// How long before the end of a track it is announced, in milliseconds
+    transition_lead: Arc<AtomicU64>,
+    /// Where the audio is sent, for telling whether a removed device was it
+    output:     Arc<RwLock<AudioOutput>>,
+    /// Stops watching for removed devices when the player is dropped
+    _device_watch: Option<DeviceWatch>,
 }
 
 impl From<gst::StateChangeError> for PlayerError {
@@ -85,8 +236,13 @@ impl From<glib::BoolError> for PlayerError {
 }
 
 impl GStreamer {
-    /// Set the playback URI
-    fn set_source(&mut self, source: &URI) -> Result<(), PlayerError> {
+    /// Set the playback URI, returning once it has begun loading
+    ///
+    /// The returned [LoadHandle] fails with [PlayerError::SourceTimeout] if the
+    /// source doesn't load, or [PlayerError::SeekFailed] if a CUE track can't be
+    /// seeked to, within the [PlayerTimeouts] of the player
+    fn set_source(&mut self, source: &URI) -> Result<LoadHandle, PlayerError> {
+        let trim = self.trim.take();
         if !source.exists().is_ok_and(|x| x) {
             // If the source doesn't exist, gstreamer will crash!
             return Err(PlayerError::NotFound)
@@ -94,65 +250,161 @@ impl GStreamer {
 
         // Make sure the playback tracker knows the stuff is stopped
         println!("Beginning switch");
-        self.playback_tx.send(PlaybackInfo::Switching).unwrap();
+        self.send_playback(PlaybackInfo::Switching)?;
 
-        let uri = self.playbin.read().unwrap().property_value("current-uri");
         self.source = Some(source.clone());
+        *self.bounds.write().map_err(|_| PlayerError::Poison)? = None;
+        self.effects.write().map_err(|_| PlayerError::Poison)?.loop_region = None;
+        *self.stream.write().map_err(|_| PlayerError::Poison)? = StreamInfo::default();
+        self.streaming.store(source.is_stream(), Ordering::Relaxed);
+        self.playbin_mut()
+            .map_err(|_| PlayerError::Poison)?
+            .set_property("uri", source.as_uri());
+
+        let (result_tx, handle) = LoadHandle::new();
+        let playbin = Arc::clone(&self.playbin);
+        let bounds = Arc::clone(&self.bounds);
+        let playback_tx = self.playback_tx.clone();
+        let timeouts = self.timeouts;
+        let volume = self.volume_curve.gain(self.volume);
+
         match source {
             URI::Cue { start, end, .. } => {
-                self.playbin
-                    .write()
-                    .unwrap()
-                    .set_property("uri", source.as_uri());
+                let start = Duration::from_std(*start)
+                    .map_err(|e| PlayerError::SeekFailed(e.to_string()))?;
+                let end = Duration::from_std(*end)
+                    .map_err(|e| PlayerError::SeekFailed(e.to_string()))?;
 
                 // Set the start and end positions of the CUE file
-                self.start = Some(Duration::from_std(*start).unwrap());
-                self.end = Some(Duration::from_std(*end).unwrap());
+                *self.bounds.write().map_err(|_| PlayerError::Poison)? = Some((start, end));
 
                 // Send the updated position to the tracker
-                self.playback_tx.send(PlaybackInfo::Playing{
-                    start: self.start.unwrap(),
-                    end: self.end.unwrap()
-                }).unwrap();
+                self.send_playback(PlaybackInfo::Playing{ start, end })?;
+                self.play()?;
 
                 // Wait for it to be ready, and then move to the proper position
-                self.play().unwrap();
-                let now = std::time::Instant::now();
-                while now.elapsed() < std::time::Duration::from_millis(20) {
-                    if self.seek_to(Duration::from_std(*start).unwrap()).is_ok() {
-                        return Ok(());
+                let seek_pos = ClockTime::from_useconds(start.num_microseconds().unwrap_or(0) as u64);
+                std::thread::spawn(move || {
+                    let now = std::time::Instant::now();
+                    let mut error = None;
+                    while now.elapsed() < timeouts.seek {
+                        let playbin = match playbin.write() {
+                            Ok(playbin) => playbin,
+                            Err(_) => break,
+                        };
+                        playbin.set_property("volume", 0.0);
+                        let seeked = playbin.seek_simple(gst::SeekFlags::FLUSH, seek_pos);
+                        playbin.set_property("volume", volume);
+                        match seeked {
+                            Ok(_) => {
+                                let _ = result_tx.send(Ok(()));
+                                return
+                            }
+                            Err(e) => error = Some(e.to_string()),
+                        }
+                        drop(playbin);
+                        std::thread::sleep(std::time::Duration::from_millis(1));
                     }
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-                }
-                //panic!("Couldn't seek to beginning of cue track in reasonable time (>20ms)");
-                return Err(PlayerError::StateChange("Could not seek to beginning of CUE track".into()))
+
+                    let _ = result_tx.send(Err(PlayerError::SeekFailed(match error {
+                        Some(error) => error,
+                        None => format!("timed out after {:?}", timeouts.seek),
+                    })));
+                });
             }
-            _ => {
-                self.playbin
-                    .write()
-                    .unwrap()
-                    .set_property("uri", source.as_uri());
+            _ if source.is_stream() => {
+                // Streams have no duration, so wait for playback to begin instead
+                self.play()?;
+
+                std::thread::spawn(move || {
+                    let now = std::time::Instant::now();
+                    loop {
+                        let position = match playbin.read() {
+                            Ok(playbin) => playbin.query_position::<ClockTime>(),
+                            Err(_) => {
+                                let _ = result_tx.send(Err(PlayerError::Poison));
+                                return
+                            }
+                        };
+                        if position.is_some() {
+                            break
+                        }
+                        if now.elapsed() >= timeouts.load {
+                            let _ = result_tx.send(Err(PlayerError::SourceTimeout(timeouts.load)));
+                            return
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    }
 
+                    let result = playback_tx
+                        .send(PlaybackInfo::Streaming)
+                        .map_err(|_| PlayerError::General("the playback monitor has stopped".into()));
+                    let _ = result_tx.send(result);
+                });
+            }
+            _ => {
                 if self.state() != PlayerState::Playing {
-                    self.play().unwrap();
+                    self.play()?;
                 }
 
-                while self.raw_duration().is_none() {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                }
+                std::thread::spawn(move || {
+                    let now = std::time::Instant::now();
+                    let end = loop {
+                        let duration = match playbin.read() {
+                            Ok(playbin) => playbin.query_duration::<ClockTime>(),
+                            Err(_) => {
+                                let _ = result_tx.send(Err(PlayerError::Poison));
+                                return
+                            }
+                        };
+                        if let Some(duration) = duration {
+                            break Duration::nanoseconds(duration.nseconds() as i64)
+                        }
+                        if now.elapsed() >= timeouts.load {
+                            let _ = result_tx.send(Err(PlayerError::SourceTimeout(timeouts.load)));
+                            return
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    };
+
+                    // Skip the audio outside of the trim by seeking past its
+                    // start and ending early
+                    let to_chrono = |time| Duration::from_std(time).unwrap_or_else(|_| Duration::zero());
+                    let end = trim.map_or(end, |(_, trim_end)| end.min(to_chrono(trim_end)));
+                    let skip = trim.map_or(Duration::zero(), |(trim_start, _)| to_chrono(trim_start));
+                    if skip > Duration::zero() {
+                        let seek_pos = ClockTime::from_useconds(skip.num_microseconds().unwrap_or(0) as u64);
+                        if let Ok(playbin) = playbin.write() {
+                            playbin.set_property("volume", 0.0);
+                            if let Err(error) = playbin.seek_simple(gst::SeekFlags::FLUSH, seek_pos) {
+                                println!("Failed to skip the start of the track: {}", error);
+                            }
+                            playbin.set_property("volume", volume);
+                        }
+                    }
 
-                self.start = Some(Duration::seconds(0));
-                self.end = self.raw_duration();
+                    let start = Duration::seconds(0);
+                    if let Ok(mut bounds) = bounds.write() {
+                        *bounds = Some((start, end));
+                    }
 
-                // Send the updated position to the tracker
-                self.playback_tx.send(PlaybackInfo::Playing{
-                    start: self.start.unwrap(),
-                    end: self.end.unwrap()
-                }).unwrap();
+                    // Send the updated position to the tracker
+                    let result = playback_tx
+                        .send(PlaybackInfo::Playing{ start, end })
+                        .map_err(|_| PlayerError::General("the playback monitor has stopped".into()));
+                    let _ = result_tx.send(result);
+                });
             }
         }
 
-        Ok(())
+        Ok(handle)
+    }
+
+    /// Send a message to the playback monitor thread
+    fn send_playback(&self, info: PlaybackInfo) -> Result<(), PlayerError> {
+        self.playback_tx
+            .send(info)
+            .map_err(|_| PlayerError::General("the playback monitor has stopped".into()))
     }
 
     /// Gets a mutable reference to the playbin element
@@ -179,10 +431,42 @@ impl GStreamer {
         Ok(element)
     }
 
-    /// Set volume of the internal playbin player, can be
-    /// used to bypass the main volume control for seeking
+    /// Set volume of the internal playbin player, through the volume
+    /// curve, can be used to bypass the main volume control for seeking
     fn set_gstreamer_volume(&mut self, volume: f64) {
-        self.playbin_mut().unwrap().set_property("volume", volume)
+        let gain = self.volume_curve.gain(volume);
+        self.playbin_mut().unwrap().set_property("volume", gain)
+    }
+
+    /// Ramp the volume of the playbin from `from` to `to`, blocking
+    /// until the [PauseFade] is over
+    fn fade(&mut self, from: f64, to: f64) {
+        for volume in self.pause_fade.steps(from, to) {
+            self.set_gstreamer_volume(volume);
+            std::thread::sleep(FADE_STEP);
+        }
+    }
+
+    /// Seek to `target_pos` within the bounds of the current track
+    fn seek_with(&mut self, target_pos: Duration, mode: SeekMode) -> Result<(), PlayerError> {
+        let (start, end) = match *self.bounds.read().unwrap() {
+            Some(bounds) => bounds,
+            None => return Err(PlayerError::Seek("No START or END time".into())),
+        };
+
+        let adjusted_target = target_pos + start;
+        let clamped_target = adjusted_target.clamp(start, end);
+
+        let seek_pos_clock =
+            ClockTime::from_useconds(clamped_target.num_microseconds().unwrap() as u64);
+        let flags = match mode {
+            SeekMode::Fast => gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+            SeekMode::Accurate => gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+        };
+        self.playbin_mut()
+            .unwrap()
+            .seek_simple(flags, seek_pos_clock)?;
+        Ok(())
     }
 
     fn set_state(&mut self, state: gst::State) -> Result<(), gst::StateChangeError> {
@@ -219,8 +503,121 @@ impl GStreamer {
     }
 }
 
+/// The name of the message which stops [watch_output_devices]
+const DEVICE_WATCH_STOP: &str = "dmp-stop-device-watch";
+
+/// Watching for removed audio devices, until this is dropped
+#[derive(Debug)]
+struct DeviceWatch {
+    bus: gst::Bus,
+}
+
+impl Drop for DeviceWatch {
+    fn drop(&mut self) {
+        let _ = self.bus.post(gst::message::Application::new(gst::Structure::new_empty(DEVICE_WATCH_STOP)));
+    }
+}
+
+/// Watch for audio devices being removed while they are played to, such
+/// as headphones being unplugged, through whichever sound systems GStreamer
+/// can monitor. Nothing is watched if none of them can be.
+fn watch_output_devices(output: Arc<RwLock<AudioOutput>>, message_tx: Sender<PlayerCommand>) -> Option<DeviceWatch> {
+    let monitor = gst::DeviceMonitor::new();
+    monitor.add_filter(Some("Audio/Sink"), None);
+    if let Err(error) = monitor.start() {
+        println!("Not watching for removed audio devices: {}", error);
+        return None;
+    }
+    let watch = DeviceWatch { bus: monitor.bus() };
+
+    std::thread::spawn(move || {
+        // Devices lose their default flag once they're gone, so which ones
+        // were the default is remembered from when they were added
+        let mut defaults: HashSet<String> = monitor
+            .devices()
+            .iter()
+            .filter(|device| device_is_default(device))
+            .map(|device| device.display_name().to_string())
+            .collect();
+        let bus = monitor.bus();
+        let types = [gst::MessageType::DeviceAdded, gst::MessageType::DeviceRemoved, gst::MessageType::Application];
+        while let Some(message) = bus.timed_pop_filtered(ClockTime::NONE, &types) {
+            match message.view() {
+                gst::MessageView::Application(application)
+                    if application.structure().is_some_and(|structure| structure.name() == DEVICE_WATCH_STOP) =>
+                {
+                    break
+                }
+                gst::MessageView::DeviceAdded(added) => {
+                    let device = added.device();
+                    if device_is_default(&device) {
+                        defaults.insert(device.display_name().to_string());
+                    }
+                }
+                gst::MessageView::DeviceRemoved(removed) => {
+                    let device = removed.device();
+                    let name = device.display_name().to_string();
+                    let ids = device
+                        .properties()
+                        .map(|properties| properties.iter().filter_map(|(_, value)| value.get::<String>().ok()).collect())
+                        .unwrap_or_default();
+                    let removed = RemovedDevice { default: defaults.remove(&name) || device_is_default(&device), name, ids };
+                    if removed.was_output(&output.read().unwrap()) {
+                        let _ = message_tx.send(PlayerCommand::DeviceRemoved(removed));
+                    }
+                }
+                _ => (),
+            }
+        }
+        monitor.stop();
+    });
+    Some(watch)
+}
+
+fn device_is_default(device: &gst::Device) -> bool {
+    device
+        .properties()
+        .and_then(|properties| properties.get::<bool>("is-default").ok())
+        .unwrap_or(false)
+}
+
+/// Make the playbin send its audio to `output`, it has to be stopped first
+fn set_output_sink(playbin: &Element, output: &AudioOutput) -> Result<(), PlayerError> {
+    let sink = match output {
+        // The playbin picks the default device for itself
+        AudioOutput::Device => None,
+        AudioOutput::Null => Some(
+            gst::ElementFactory::make("fakesink")
+                .property("sync", true)
+                .build()
+                .map_err(|error| PlayerError::Init(error.to_string()))?,
+        ),
+        AudioOutput::Sink(output_sink) => {
+            let sink = gst::ElementFactory::make(output_sink.class.element())
+                .build()
+                .map_err(|error| PlayerError::Init(error.to_string()))?;
+            for (name, value) in output_sink.sink_properties() {
+                if sink.find_property(&name).is_none() {
+                    return Err(PlayerError::Init(format!("{} has no property {}", output_sink.class.element(), name)));
+                }
+                sink.set_property_from_str(&name, &value);
+            }
+            Some(sink)
+        }
+    };
+    playbin.set_property("audio-sink", sink.as_ref());
+    // Without an audio device to keep time, the system clock is used
+    if let Some(pipeline) = playbin.downcast_ref::<gst::Pipeline>() {
+        match output {
+            AudioOutput::Null => pipeline.use_clock(Some(&gst::SystemClock::obtain())),
+            AudioOutput::Device | AudioOutput::Sink(_) => pipeline.auto_clock(),
+        }
+    }
+    Ok(())
+}
+
 impl Player for GStreamer {
-    fn new() -> Result<Self, PlayerError> {
+    fn with_output(output: AudioOutput) -> Result<Self, PlayerError> {
         // Initialize GStreamer, maybe figure out how to nicely fail here
         if let Err(err) = gst::init() {
             return Err(PlayerError::Init(err.to_string()))
@@ -255,19 +652,50 @@ impl Player for GStreamer {
         playbin.write().unwrap().set_property_from_value("flags", &flags);
         //playbin.write().unwrap().set_property("instant-uri", true);
 
+        if output != AudioOutput::default() {
+            set_output_sink(&playbin.write().unwrap(), &output)?;
+        }
+        let output = Arc::new(RwLock::new(output));
+
+        // Send the output through the gain and equalizer, playing without them if they're missing
+        let filters = OutputFilters::build(None);
+        if let Some(bin) = &filters.bin {
+            playbin.write().unwrap().set_property("audio-filter", bin);
+        }
+
         let position = Arc::new(RwLock::new(None));
 
         // Set up the thread to monitor the position
         let (playback_tx, playback_rx) = unbounded();
         let (status_tx, status_rx) = unbounded::<PlaybackInfo>();
         let position_update = Arc::clone(&position);
-
-        std::thread::spawn(|| playback_monitor(playbin_arc, status_rx, playback_tx, position_update));
+        let tags_tx = playback_tx.clone();
+        let message_tx = playback_tx.clone();
+        let device_watch = watch_output_devices(output.clone(), playback_tx.clone());
+
+        let effects = Arc::new(RwLock::new(TrackEffects { fade: filters.fade.clone(), ..Default::default() }));
+        let monitor_effects = Arc::clone(&effects);
+        let poll_interval = Arc::new(AtomicU64::new(POSITION_POLL_INTERVAL.as_millis() as u64));
+        let monitor_interval = Arc::clone(&poll_interval);
+        let transition_lead = Arc::new(AtomicU64::new(TRANSITION_LEAD.as_millis() as u64));
+        let monitor_lead = Arc::clone(&transition_lead);
+        std::thread::spawn(|| {
+            playback_monitor(playbin_arc, status_rx, playback_tx, position_update, monitor_interval, monitor_lead, monitor_effects)
+        });
 
         // Set up the thread to monitor bus messages
         let playbin_bus_ctrl = Arc::clone(&playbin);
         let paused = Arc::new(RwLock::new(false));
         let bus_paused = Arc::clone(&paused);
+        let (visualizer_tx, visualizer_rx) = bounded(VISUALIZER_BUFFER);
+        let (pcm_tx, pcm_rx) = bounded(PCM_BUFFER);
+        let stream = Arc::new(RwLock::new(StreamInfo::default()));
+        let bus_stream = Arc::clone(&stream);
+        let streaming = Arc::new(AtomicBool::new(false));
+        let bus_streaming = Arc::clone(&streaming);
+        let skipped = Arc::new(RwLock::new(SkippedSilence::default()));
+        let bus_skipped = Arc::clone(&skipped);
+        let mut last_tags = None;
         let bus_watch = playbin
             .read()
             .unwrap()
@@ -278,8 +706,62 @@ impl Player for GStreamer {
                     gst::MessageView::Eos(_) => println!("End of stream"),
                     gst::MessageView::StreamStart(_) => println!("Stream start"),
                     gst::MessageView::Error(err) => {
+                        // Keep watching, so a hiccup in a stream doesn't stop
+                        // the player from handling any further messages
                         println!("Error recieved: {}", err);
-                        return glib::ControlFlow::Break
+                    }
+                    gst::MessageView::Element(element) => {
+                        let Some(structure) = element.structure() else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        if let Some(data) = visualizer_data(structure) {
+                            let _ = visualizer_tx.try_send(data);
+                        }
+                        if structure.name() == "removesilence" {
+                            let at = |field| structure.get::<u64>(field).ok().map(std::time::Duration::from_nanos);
+                            if let Ok(mut skipped) = bus_skipped.write() {
+                                if let Some(at) = at("silence_detected") {
+                                    skipped.gap_started(at);
+                                }
+                                if let Some(at) = at("silence_finished") {
+                                    skipped.gap_finished(at);
+                                }
+                            }
+                        }
+                    }
+                    gst::MessageView::Tag(tag) => {
+                        let tags = tag.tags();
+                        if let Ok(mut stream) = bus_stream.write() {
+                            if let Some(codec) = tags.get::<gst::tags::AudioCodec>() {
+                                stream.lossless = StreamInfo::is_lossless(codec.get());
+                                stream.codec = Some(codec.get().to_string());
+                            }
+                            let bitrate = tags
+                                .get::<gst::tags::Bitrate>()
+                                .or_else(|| tags.get::<gst::tags::NominalBitrate>())
+                                .map(|bitrate| bitrate.get() / 1000);
+                            if bitrate.is_some() {
+                                stream.bitrate = bitrate;
+                            }
+                        }
+                        // The tags of files are already in the library
+                        if !bus_streaming.load(Ordering::Relaxed) {
+                            last_tags = None;
+                            return glib::ControlFlow::Continue;
+                        }
+                        let stream_title = tags.get::<gst::tags::Title>().map(|t| t.get().to_string());
+                        let artist = tags.get::<gst::tags::Artist>().map(|a| a.get().to_string());
+                        let parsed = match (stream_title, artist) {
+                            (None, None) => return glib::ControlFlow::Continue,
+                            (title, Some(artist)) => (title, Some(artist)),
+                            (Some(title), None) => parse_stream_title(&title),
+                        };
+
+                        if last_tags.as_ref() != Some(&parsed) {
+                            last_tags = Some(parsed.clone());
+                            let (title, artist) = parsed;
+                            let _ = tags_tx.try_send(PlayerCommand::TagsChanged { title, artist });
+                        }
                     }
                     gst::MessageView::Buffering(buffering) => {
                         if *bus_paused.read().unwrap() == true {
@@ -288,19 +770,19 @@ impl Player for GStreamer {
 
                         // If the player is not paused, pause it
                         let percent = buffering.percent();
+                        // Failing to change state here is not fatal, the next
+                        // buffering message will try again
                         if percent < 100 {
-                            playbin_bus_ctrl
+                            let _ = playbin_bus_ctrl
                                 .write()
                                 .unwrap()
-                                .set_state(gst::State::Paused)
-                                .unwrap();
+                                .set_state(gst::State::Paused);
                         } else if percent >= 100 {
                             println!("Finished buffering");
-                            playbin_bus_ctrl
+                            let _ = playbin_bus_ctrl
                                 .write()
                                 .unwrap()
-                                .set_state(gst::State::Playing)
-                                .unwrap();
+                                .set_state(gst::State::Playing);
                         }
                     }
                     _ => (),
@@ -320,39 +802,304 @@ impl Player for GStreamer {
             source,
             playbin,
             message_rx: playback_rx,
+            message_tx,
+            visualizer_rx,
+            pcm_rx,
+            pcm_tx,
+            pcm_probe: None,
             playback_tx: status_tx,
             volume: 1.0,
-            start: None,
-            end: None,
+            volume_cap: 1.0,
+            unmuted: None,
+            volume_curve: VolumeCurve::default(),
+            pause_fade: PauseFade::default(),
+            seek_mode: SeekMode::default(),
+            latency: std::time::Duration::ZERO,
+            scrub: None,
+            filters,
+            trim: None,
+            bounds: Arc::new(RwLock::new(None)),
+            effects,
+            stream,
+            streaming,
+            skipped,
+            timeouts: PlayerTimeouts::default(),
             paused,
             position,
+            poll_interval,
+            transition_lead,
+            output,
+            _device_watch: device_watch,
         })
     }
 
+    fn set_output(&mut self, output: AudioOutput) -> Result<(), PlayerError> {
+        let position = self.position();
+        let state = self.playbin().unwrap().current_state();
+
+        // The sink can only be changed while the playbin is stopped
+        self.set_state(gst::State::Ready)?;
+        set_output_sink(&self.playbin().unwrap(), &output)?;
+        *self.output.write().unwrap() = output;
+        if state > gst::State::Ready {
+            self.set_state(state)?;
+            let _ = self.playbin().unwrap().state(ClockTime::from_seconds(self.timeouts.load.as_secs()));
+            if let Some(position) = position {
+                self.seek_to(position)?;
+            }
+        }
+        Ok(())
+    }
+
     fn source(&self) -> &Option<URI> {
         &self.source
     }
 
     fn enqueue_next(&mut self, next_track: &URI) -> Result<(), PlayerError> {
         println!("enqueuing in fn");
+        self.set_source(next_track)?.wait()
+    }
+
+    fn load(&mut self, next_track: &URI) -> Result<LoadHandle, PlayerError> {
         self.set_source(next_track)
     }
 
+    fn set_next_trim(&mut self, trim: Option<(std::time::Duration, std::time::Duration)>) {
+        self.trim = trim;
+    }
+
+    fn set_timeouts(&mut self, timeouts: PlayerTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    fn set_poll_interval(&mut self, interval: std::time::Duration) {
+        self.poll_interval.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn set_transition_lead(&mut self, lead: std::time::Duration) {
+        self.transition_lead.store(lead.as_millis() as u64, Ordering::Relaxed);
+    }
+
     fn set_volume(&mut self, volume: f64) {
-        self.volume = volume.clamp(0.0, 1.0);
-        self.set_gstreamer_volume(self.volume);
+        let (capped, over) = cap_volume(volume, self.volume_cap);
+        if over {
+            println!("Volume {} is above the cap of {}", volume, self.volume_cap);
+            let _ = self.message_tx.try_send(PlayerCommand::VolumeCapped {
+                requested: volume,
+                cap: self.volume_cap,
+            });
+        }
+        self.volume = capped;
+        self.unmuted = None;
+        // Scrubbing stays muted, the volume is put back once it ends
+        if self.scrub.is_none() {
+            self.set_gstreamer_volume(self.volume);
+        }
     }
 
     fn volume(&self) -> f64 {
         self.volume
     }
 
+    fn set_volume_curve(&mut self, curve: VolumeCurve) {
+        self.volume_curve = curve;
+        if self.scrub.is_none() {
+            self.set_gstreamer_volume(self.volume);
+        }
+    }
+
+    fn set_volume_cap(&mut self, cap: f64) {
+        self.volume_cap = cap.clamp(0.0, 1.0);
+        if self.volume > self.volume_cap {
+            self.volume = self.volume_cap;
+            self.set_gstreamer_volume(self.volume);
+        }
+    }
+
+    fn volume_cap(&self) -> f64 {
+        self.volume_cap
+    }
+
+    fn mute(&mut self) {
+        if self.unmuted.is_none() {
+            let volume = self.volume;
+            self.set_volume(0.0);
+            self.unmuted = Some(volume);
+        }
+    }
+
+    fn unmute(&mut self) {
+        if let Some(volume) = self.unmuted.take() {
+            self.set_volume(volume);
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.unmuted.is_some()
+    }
+
+    fn set_latency_offset(&mut self, latency: std::time::Duration) {
+        self.latency = latency;
+    }
+
+    fn set_pause_fade(&mut self, fade: PauseFade) {
+        self.pause_fade = fade;
+    }
+
+    fn set_crossfade(&mut self, crossfade: Option<Crossfade>) {
+        let mut effects = self.effects.write().unwrap();
+        effects.crossfade = crossfade;
+        if let (None, Some(fade)) = (crossfade, &effects.fade) {
+            fade.set_property("volume", 1.0);
+        }
+    }
+
+    fn set_visualizer(&mut self, visualizer: Visualizer) -> Result<(), PlayerError> {
+        let (Some(spectrum), Some(level)) = (&self.filters.spectrum, &self.filters.level) else {
+            return match visualizer.enabled {
+                true => Err(PlayerError::General("the spectrum and level plugins are not installed".into())),
+                false => Ok(()),
+            };
+        };
+        let interval = visualizer.interval.max(std::time::Duration::from_millis(1)).as_nanos() as u64;
+        spectrum.set_property("bands", visualizer.bands.max(1));
+        spectrum.set_property("interval", interval);
+        spectrum.set_property("post-messages", visualizer.enabled);
+        level.set_property("interval", interval);
+        level.set_property("post-messages", visualizer.enabled);
+        Ok(())
+    }
+
+    fn visualizer_rx(&self) -> &crossbeam::channel::Receiver<VisualizerData> {
+        &self.visualizer_rx
+    }
+
+    fn set_pcm_tap(&mut self, enabled: bool) -> Result<(), PlayerError> {
+        // The filters are given the audio straight from the decoder
+        let pad = self
+            .filters
+            .bin
+            .as_ref()
+            .and_then(|bin| bin.static_pad("sink"))
+            .ok_or(PlayerError::General("the audio filters are not installed".into()))?;
+        match (enabled, self.pcm_probe.take()) {
+            (true, Some(probe)) => self.pcm_probe = Some(probe),
+            (true, None) => {
+                let pcm_tx = self.pcm_tx.clone();
+                self.pcm_probe = pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                    if let Some(frame) = info.buffer().and_then(|buffer| pcm_frame(pad, buffer)) {
+                        let _ = pcm_tx.try_send(frame);
+                    }
+                    gst::PadProbeReturn::Ok
+                });
+            }
+            (false, Some(probe)) => pad.remove_probe(probe),
+            (false, None) => (),
+        }
+        Ok(())
+    }
+
+    fn set_filter_chain(&mut self, chain: Option<&str>) -> Result<(), PlayerError> {
+        let chain = chain.map(str::trim).filter(|chain| !chain.is_empty());
+        if let Some(chain) = chain {
+            gst::parse_bin_from_description(chain, true)
+                .map_err(|error| PlayerError::General(format!("the filter chain can't be built: {}", error)))?;
+        }
+        let filters = OutputFilters::build(chain);
+        let bin = filters.bin.as_ref().ok_or(PlayerError::Build)?;
+        self.filters.copy_settings(&filters);
+
+        // Move the PCM tap over to the new filters
+        let tapped = self.pcm_probe.is_some();
+        if tapped {
+            self.set_pcm_tap(false)?;
+        }
+        self.playbin_mut().map_err(|_| PlayerError::Poison)?.set_property("audio-filter", bin);
+        self.effects.write().map_err(|_| PlayerError::Poison)?.fade = filters.fade.clone();
+        self.filters = filters;
+        if tapped {
+            self.set_pcm_tap(true)?;
+        }
+        Ok(())
+    }
+
+    fn pcm_frames(&self) -> &crossbeam::channel::Receiver<PcmFrame> {
+        &self.pcm_rx
+    }
+
+    fn set_equalizer(&mut self, equalizer: Equalizer) -> Result<(), PlayerError> {
+        let element = self.filters.equalizer.as_ref().ok_or(PlayerError::Build)?;
+        // Adding no gain still keeps the bands within range
+        let equalizer = equalizer.with_gain(0.0);
+        element.set_property("band0", equalizer.low);
+        element.set_property("band1", equalizer.mid);
+        element.set_property("band2", equalizer.high);
+        Ok(())
+    }
+
+    fn set_skip_silence(&mut self, minimum_gap: Option<std::time::Duration>) -> Result<(), PlayerError> {
+        let Some(element) = &self.filters.silence else {
+            return match minimum_gap {
+                Some(_) => Err(PlayerError::General("the removesilence plugin is not installed".into())),
+                None => Ok(()),
+            };
+        };
+        if let Some(gap) = minimum_gap {
+            // Older versions of the plugin only skip gaps of a set length
+            if element.find_property("minimum-silence-time").is_some() {
+                element.set_property("minimum-silence-time", gap.as_nanos() as u64);
+            }
+        }
+        element.set_property("remove", minimum_gap.is_some());
+        self.skipped.write().map_err(|_| PlayerError::Poison)?.skipping = minimum_gap.is_some();
+        Ok(())
+    }
+
+    fn silence_skipped(&self) -> std::time::Duration {
+        self.skipped.read().unwrap().total
+    }
+
+    fn set_balance(&mut self, balance: f64) -> Result<(), PlayerError> {
+        let element = self.filters.balance.as_ref().ok_or(PlayerError::Build)?;
+        element.set_property("panorama", balance.clamp(-1.0, 1.0) as f32);
+        Ok(())
+    }
+
+    fn set_mono(&mut self, mono: bool) -> Result<(), PlayerError> {
+        let element = self.filters.channels.as_ref().ok_or(PlayerError::Build)?;
+        let caps = match mono {
+            true => gst::Caps::builder("audio/x-raw").field("channels", 1).build(),
+            false => gst::Caps::new_any(),
+        };
+        element.set_property("caps", caps);
+        Ok(())
+    }
+
+    fn set_gain(&mut self, gain: f64, limiter: bool) -> Result<(), PlayerError> {
+        let element = self.filters.gain.as_ref().ok_or(PlayerError::Build)?;
+        element.set_property("volume", 10f64.powf(gain / 20.0).clamp(0.0, 10.0));
+        match &self.filters.limiter {
+            Some(element) => element.set_property("enabled", limiter),
+            None if limiter => return Err(PlayerError::Build),
+            None => (),
+        }
+        Ok(())
+    }
+
     fn play(&mut self) -> Result<(), PlayerError> {
         if self.state() == PlayerState::Playing {
             return Ok(())
         }
         *self.paused.write().unwrap() = false;
+        // Only fade in when resuming, so the start of a song isn't lost
+        let resuming = self.state() == PlayerState::Paused && self.pause_fade.enabled;
+        if resuming {
+            self.set_gstreamer_volume(0.0);
+        }
         self.set_state(gst::State::Playing)?;
+        if resuming {
+            self.fade(0.0, self.volume);
+        }
         Ok(())
     }
 
@@ -361,7 +1108,12 @@ impl Player for GStreamer {
             return Ok(())
         }
         *self.paused.write().unwrap() = true;
-        self.set_state(gst::State::Paused)?;
+        if self.state() == PlayerState::Playing {
+            self.fade(self.volume, 0.0);
+        }
+        let result = self.set_state(gst::State::Paused);
+        self.set_gstreamer_volume(self.volume);
+        result?;
         Ok(())
     }
 
@@ -370,17 +1122,34 @@ impl Player for GStreamer {
     }
 
     fn position(&self) -> Option<Duration> {
-        *self.position.read().unwrap()
+        self.position.read().unwrap().map(|position| heard_position(position, self.latency))
     }
 
     fn duration(&self) -> Option<Duration> {
-        if self.end.is_some() && self.start.is_some() {
-            Some(self.end.unwrap() - self.start.unwrap())
-        } else {
-            self.raw_duration()
+        match *self.bounds.read().unwrap() {
+            Some((start, end)) => Some(end - start),
+            None => self.raw_duration(),
         }
     }
 
+    fn stream_info(&self) -> Option<StreamInfo> {
+        self.source.as_ref()?;
+        let mut info = self.stream.read().unwrap().clone();
+
+        // The filters see the audio as it comes out of the decoder
+        let caps = self
+            .filters
+            .bin
+            .as_ref()
+            .and_then(|bin| bin.static_pad("sink"))
+            .and_then(|pad| pad.current_caps());
+        let structure = caps.as_ref().and_then(|caps| caps.structure(0))?;
+        info.sample_rate = structure.get::<i32>("rate").ok().map(|rate| rate as u32);
+        info.channels = structure.get::<i32>("channels").ok().map(|channels| channels as u32);
+        info.bit_depth = structure.get::<&str>("format").ok().and_then(format_depth);
+        Some(info)
+    }
+
     fn seek_by(&mut self, seek_amount: Duration) -> Result<(), PlayerError> {
         let time_pos = match *self.position.read().unwrap() {
             Some(pos) => pos,
@@ -393,43 +1162,77 @@ impl Player for GStreamer {
     }
 
     fn seek_to(&mut self, target_pos: Duration) -> Result<(), PlayerError> {
-        let start = if self.start.is_none() {
-            return Err(PlayerError::Seek("No START time".into()));
-        } else {
-            self.start.unwrap()
-        };
+        self.seek_to_with(target_pos, self.seek_mode)
+    }
 
-        let end = if self.end.is_none() {
-            return Err(PlayerError::Seek("No END time".into()));
-        } else {
-            self.end.unwrap()
-        };
+    fn seek_to_with(&mut self, target_pos: Duration, mode: SeekMode) -> Result<(), PlayerError> {
+        self.set_gstreamer_volume(0.0);
+        let result = self.seek_with(target_pos, mode);
+        self.set_gstreamer_volume(self.volume);
+        result
+    }
 
-        let adjusted_target = target_pos + start;
-        let clamped_target = adjusted_target.clamp(start, end);
+    fn set_seek_mode(&mut self, mode: SeekMode) {
+        self.seek_mode = mode;
+    }
 
-        let seek_pos_clock =
-            ClockTime::from_useconds(clamped_target.num_microseconds().unwrap() as u64);
+    fn set_loop_region(&mut self, a: Duration, b: Duration) -> Result<(), PlayerError> {
+        let duration = self.duration().ok_or(PlayerError::Seek("No START or END time".into()))?;
+        if a < Duration::zero() || b <= a || b > duration {
+            return Err(PlayerError::Seek(format!("{} to {} isn't part of the track", a, b)));
+        }
+        self.effects.write().unwrap().loop_region = Some((a, b));
+        Ok(())
+    }
 
+    fn clear_loop_region(&mut self) {
+        self.effects.write().unwrap().loop_region = None;
+    }
+
+    fn loop_region(&self) -> Option<(Duration, Duration)> {
+        self.effects.read().unwrap().loop_region
+    }
+
+    fn begin_scrub(&mut self) -> Result<(), PlayerError> {
+        if self.bounds.read().unwrap().is_none() {
+            return Err(PlayerError::Seek("No START or END time".into()));
+        }
         self.set_gstreamer_volume(0.0);
-        self.playbin_mut()
-            .unwrap()
-            .seek_simple(gst::SeekFlags::FLUSH, seek_pos_clock)?;
-        self.set_gstreamer_volume(self.volume);
+        self.scrub = Some(None);
         Ok(())
     }
 
+    fn scrub_to(&mut self, target_pos: Duration) -> Result<(), PlayerError> {
+        if self.scrub.is_none() {
+            return Err(PlayerError::Seek("Not scrubbing".into()));
+        }
+        self.seek_with(target_pos, SeekMode::Fast)?;
+        self.scrub = Some(Some(target_pos));
+        Ok(())
+    }
+
+    fn end_scrub(&mut self) -> Result<(), PlayerError> {
+        let result = match self.scrub.take() {
+            Some(Some(target_pos)) => self.seek_with(target_pos, SeekMode::Accurate),
+            Some(None) => Ok(()),
+            None => Err(PlayerError::Seek("Not scrubbing".into())),
+        };
+        self.set_gstreamer_volume(self.volume);
+        result
+    }
+
     fn stop(&mut self) -> Result<(), PlayerError> {
         self.pause()?;
         self.ready()?;
 
         // Send the updated position to the tracker
-        self.playback_tx.send(PlaybackInfo::Idle).unwrap();
+        self.send_playback(PlaybackInfo::Idle)?;
 
         // Set all positions to none
         *self.position.write().unwrap() = None;
-        self.start = None;
-        self.end = None;
+        *self.bounds.write().unwrap() = None;
+        self.effects.write().unwrap().loop_region = None;
+        *self.stream.write().unwrap() = StreamInfo::default();
         Ok(())
     }
 
@@ -450,20 +1253,104 @@ impl Drop for GStreamer {
     }
 }
 
+/// Split an ICY `StreamTitle`, usually `Artist - Title`, into its title
+/// and artist. Titles without an artist are returned as-is.
+fn parse_stream_title(stream_title: &str) -> (Option<String>, Option<String>) {
+    let stream_title = stream_title.trim();
+    match stream_title.split_once(" - ") {
+        Some((artist, title)) if !artist.trim().is_empty() && !title.trim().is_empty() => {
+            (Some(title.trim().to_string()), Some(artist.trim().to_string()))
+        }
+        _ if stream_title.is_empty() => (None, None),
+        _ => (Some(stream_title.to_string()), None),
+    }
+}
+
+/// The data in a message from the spectrum or level element
+fn visualizer_data(structure: &gst::StructureRef) -> Option<VisualizerData> {
+    match structure.name().as_str() {
+        "spectrum" => {
+            let magnitudes = structure.get::<gst::List>("magnitude").ok()?;
+            Some(VisualizerData::Spectrum(magnitudes.iter().filter_map(|value| value.get::<f32>().ok()).collect()))
+        }
+        "level" => {
+            let channels = |field: &str| -> Vec<f32> {
+                structure
+                    .get::<glib::ValueArray>(field)
+                    .map(|values| values.iter().filter_map(|value| value.get::<f64>().ok()).map(|db| db as f32).collect())
+                    .unwrap_or_default()
+            };
+            Some(VisualizerData::Level { rms: channels("rms"), peak: channels("peak") })
+        }
+        _ => None,
+    }
+}
+
+/// The bits in each sample of a raw audio `format` such as `S16LE`, or
+/// `S24_32LE` where 24 of the 32 bits are used
+fn format_depth(format: &str) -> Option<u32> {
+    let digits: String = format.chars().skip(1).take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// The samples of a `buffer` going through `pad`, in the format of its caps
+fn pcm_frame(pad: &gst::Pad, buffer: &gst::BufferRef) -> Option<PcmFrame> {
+    let caps = pad.current_caps()?;
+    let structure = caps.structure(0)?;
+    let map = buffer.map_readable().ok()?;
+    Some(PcmFrame {
+        rate: structure.get::<i32>("rate").ok()? as u32,
+        channels: structure.get::<i32>("channels").ok()? as u32,
+        position: buffer.pts().map(|pts| std::time::Duration::from_nanos(pts.nseconds())),
+        samples: PcmSamples::from_bytes(structure.get::<&str>("format").ok()?, &map)?,
+    })
+}
+
+/// The least time the monitor waits for, so it doesn't spin while
+/// paused right before a point
+const MONITOR_MIN_WAIT: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// How long the monitor can wait at `position` before it next has to
+/// check, which is the poll `interval` unless one of the `points` is
+/// sooner
+fn monitor_wait(position: Duration, points: &[Duration], interval: std::time::Duration) -> std::time::Duration {
+    points
+        .iter()
+        .filter(|point| **point > position)
+        .filter_map(|point| (*point - position).to_std().ok())
+        .fold(interval, std::time::Duration::min)
+        .max(MONITOR_MIN_WAIT)
+}
+
+/// Where to seek back to in the file at `position`, if it's reached the
+/// end of the `region` being looped in the track beginning at `start`
+fn loop_target(position: Duration, start: Duration, region: Option<(Duration, Duration)>) -> Option<Duration> {
+    let (a, b) = region?;
+    (position >= start + b).then_some(start + a)
+}
+
 fn playback_monitor(
     playbin: Arc<RwLock<Element>>,
     status_rx: Receiver<PlaybackInfo>,
     playback_tx: Sender<PlayerCommand>,
     position: Arc<RwLock<Option<Duration>>>,
+    poll_interval: Arc<AtomicU64>,
+    transition_lead: Arc<AtomicU64>,
+    effects: Arc<RwLock<TrackEffects>>,
 ) {
     let mut stats = PlaybackInfo::Idle;
     let mut pos_temp;
     let mut sent_atf = false;
+    // Whether the end of the current track has been announced
+    let mut announced = false;
+    let mut wait = POSITION_POLL_INTERVAL;
     loop {
         // Check for new messages to decide how to proceed
-        if let Ok(result) = status_rx.recv_timeout(std::time::Duration::from_millis(50)) {
+        let interval = std::time::Duration::from_millis(poll_interval.load(Ordering::Relaxed));
+        if let Ok(result) = status_rx.recv_timeout(wait.min(interval)) {
             stats = result
         }
+        wait = interval;
 
         pos_temp = playbin
             .read()
@@ -475,7 +1362,26 @@ fn playback_monitor(
             PlaybackInfo::Playing{start, end} if pos_temp.is_some() => {
                 // Check if the current playback position is close to the end
                 let finish_point = end - Duration::milliseconds(2000);
-                if pos_temp.unwrap().num_microseconds() >= end.num_microseconds() {
+                let lead = Duration::milliseconds(transition_lead.load(Ordering::Relaxed) as i64);
+                // Tracks shorter than the lead are announced as soon as they start
+                let announce_point = (end - lead).max(start);
+                let pos = pos_temp.unwrap();
+                let effects = effects.read().unwrap();
+                let region = effects.loop_region;
+                if let (Some(crossfade), Some(fade), None) = (effects.crossfade, &effects.fade, region) {
+                    let gain = crossfade.gain((pos - start).to_std().unwrap_or_default(), (end - start).to_std().unwrap_or_default());
+                    fade.set_property("volume", gain);
+                }
+                if let Some((_, b)) = region {
+                    // The end of the track isn't reached while looping, so nothing is announced
+                    if let Some(target) = loop_target(pos, start, region) {
+                        let target = ClockTime::from_useconds(target.num_microseconds().unwrap() as u64);
+                        if let Err(error) = playbin.read().unwrap().seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, target) {
+                            println!("MONITOR: Failed to loop back: {}", error);
+                        }
+                    }
+                    wait = monitor_wait(pos, &[start + b], interval);
+                } else if pos.num_microseconds() >= end.num_microseconds() {
                     println!("MONITOR: End of stream");
                     let _ = playback_tx.try_send(PlayerCommand::EndOfStream);
                     playbin
@@ -483,26 +1389,43 @@ fn playback_monitor(
                         .unwrap()
                         .set_state(gst::State::Ready)
                         .expect("Unable to set the pipeline state");
-                    sent_atf = false
-                } else if pos_temp.unwrap().num_microseconds() >= finish_point.num_microseconds()
-                    && !sent_atf
-                {
-                    println!("MONITOR: About to finish");
-                    let _ = playback_tx.try_send(PlayerCommand::AboutToFinish);
-                    sent_atf = true;
+                    sent_atf = false;
+                    announced = false;
+                } else {
+                    if pos >= announce_point && !announced {
+                        let remaining = (end - pos).to_std().unwrap_or_default();
+                        let _ = playback_tx.try_send(PlayerCommand::TransitionAhead { remaining });
+                        announced = true;
+                    }
+                    if pos.num_microseconds() >= finish_point.num_microseconds() && !sent_atf {
+                        println!("MONITOR: About to finish");
+                        let _ = playback_tx.try_send(PlayerCommand::AboutToFinish);
+                        sent_atf = true;
+                    }
+                    wait = monitor_wait(pos, &[announce_point, finish_point, end], interval);
+                    // Fades are only smooth if the volume changes often enough
+                    if let Some(crossfade) = effects.crossfade {
+                        let fade_out = end - Duration::from_std(crossfade.fade_out).unwrap_or_default();
+                        let fade_in = start + Duration::from_std(crossfade.fade_in).unwrap_or_default();
+                        wait = match pos < fade_in || pos >= fade_out {
+                            true => wait.min(POSITION_POLL_INTERVAL),
+                            false => monitor_wait(pos, &[fade_out], wait),
+                        };
+                    }
                 }
 
                 // This has to be done AFTER the current time in the file
                 // is calculated, or everything else is wrong
-                pos_temp = Some(pos_temp.unwrap() - start)
+                pos_temp = Some(pos - start)
             },
             PlaybackInfo::Finished => {
                 println!("MONITOR: Shutting down");
                 *position.write().unwrap() = None;
                 break
             },
-            PlaybackInfo::Idle | PlaybackInfo::Switching => {
-                sent_atf = false
+            PlaybackInfo::Idle | PlaybackInfo::Switching | PlaybackInfo::Streaming => {
+                sent_atf = false;
+                announced = false;
             },
             _ => ()
         }
@@ -510,3 +1433,180 @@ fn playback_monitor(
         *position.write().unwrap() = pos_temp;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use gstreamer as gst;
+    use gstreamer::prelude::*;
+
+    use super::{loop_target, monitor_wait, parse_stream_title, GStreamer, MONITOR_MIN_WAIT};
+    use crate::music_player::player::{AudioOutput, Player, PlayerCommand, SeekMode};
+    use crate::music_storage::library::URI;
+
+    /// A WAV file of silence, titled "Silence"
+    fn silent_wav(seconds: u32) -> Vec<u8> {
+        let (rate, channels, bytes_per_sample) = (8000u32, 1u16, 2u16);
+        let size = rate * seconds * (channels * bytes_per_sample) as u32;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + 28 + size).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&channels.to_le_bytes());
+        data.extend_from_slice(&rate.to_le_bytes());
+        data.extend_from_slice(&(rate * (channels * bytes_per_sample) as u32).to_le_bytes());
+        data.extend_from_slice(&(channels * bytes_per_sample).to_le_bytes());
+        data.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+        data.extend_from_slice(b"LIST");
+        data.extend_from_slice(&20u32.to_le_bytes());
+        data.extend_from_slice(b"INFOINAM");
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(b"Silence\0");
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&size.to_le_bytes());
+        data.resize(data.len() + size as usize, 0);
+        data
+    }
+
+    #[test]
+    fn null_output() {
+        // Without the GStreamer plugins installed there is nothing to play with
+        let mut player = match GStreamer::with_output(AudioOutput::Null) {
+            Ok(player) => player,
+            Err(error) => return println!("Skipping, the player can't be made: {}", error),
+        };
+        let file = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
+        std::fs::write(file.path(), silent_wav(3)).unwrap();
+        if let Err(error) = player.enqueue_next(&URI::Local(file.path().to_path_buf())) {
+            return println!("Skipping, the file can't be loaded: {}", error);
+        }
+        player.play().unwrap();
+
+        let messages = player.message_channel().clone();
+        let (mut about_to_finish, mut end, mut position) = (false, false, false);
+        let (mut announced, mut tags_changed) = (0, false);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !end && Instant::now() < deadline {
+            position |= player.position().is_some_and(|position| position.num_milliseconds() > 0);
+            match messages.recv_timeout(Duration::from_millis(100)) {
+                Ok(PlayerCommand::AboutToFinish) => about_to_finish = true,
+                Ok(PlayerCommand::TransitionAhead { .. }) => announced += 1,
+                Ok(PlayerCommand::EndOfStream) => end = true,
+                Ok(PlayerCommand::TagsChanged { .. }) => tags_changed = true,
+                _ => (),
+            }
+        }
+        assert!(position && about_to_finish && end);
+        // Only the tags of streams are sent
+        assert!(!tags_changed);
+        // The track is shorter than the lead, so it's announced once right away
+        assert_eq!(announced, 1);
+    }
+
+    #[test]
+    fn loop_region() {
+        let (start, region) = (chrono::Duration::seconds(60), Some((chrono::Duration::seconds(10), chrono::Duration::seconds(20))));
+        assert_eq!(loop_target(chrono::Duration::seconds(79), start, region), None);
+        assert_eq!(loop_target(chrono::Duration::seconds(80), start, region), Some(chrono::Duration::seconds(70)));
+        assert_eq!(loop_target(chrono::Duration::seconds(80), start, None), None);
+    }
+
+    #[test]
+    fn scrubbing() {
+        let mut player = match GStreamer::with_output(AudioOutput::Null) {
+            Ok(player) => player,
+            Err(error) => return println!("Skipping, the player can't be made: {}", error),
+        };
+        assert!(player.begin_scrub().is_err());
+        let file = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
+        std::fs::write(file.path(), silent_wav(3)).unwrap();
+        if let Err(error) = player.enqueue_next(&URI::Local(file.path().to_path_buf())) {
+            return println!("Skipping, the file can't be loaded: {}", error);
+        }
+        player.set_volume(0.5);
+        assert!(player.scrub_to(chrono::Duration::seconds(1)).is_err());
+
+        player.begin_scrub().unwrap();
+        player.set_volume(0.8);
+        assert_eq!(player.property("volume").get::<f64>().unwrap(), 0.0);
+        player.scrub_to(chrono::Duration::seconds(1)).unwrap();
+        player.scrub_to(chrono::Duration::seconds(2)).unwrap();
+        player.end_scrub().unwrap();
+        assert_eq!(player.property("volume").get::<f64>().unwrap(), 0.8);
+        assert!(player.end_scrub().is_err());
+
+        player.set_seek_mode(SeekMode::Fast);
+        player.seek_to(chrono::Duration::seconds(1)).unwrap();
+        player.seek_to_with(chrono::Duration::seconds(2), SeekMode::Accurate).unwrap();
+    }
+
+    #[test]
+    fn balance_and_mono() {
+        let mut player = match GStreamer::with_output(AudioOutput::Null) {
+            Ok(player) => player,
+            Err(error) => return println!("Skipping, the player can't be made: {}", error),
+        };
+        if player.filters.balance.is_none() {
+            return println!("Skipping, the output filters aren't installed");
+        }
+        player.set_balance(-2.0).unwrap();
+        let balance = player.filters.balance.as_ref().unwrap();
+        assert_eq!(balance.property::<f32>("panorama"), -1.0);
+
+        player.set_mono(true).unwrap();
+        let caps = player.filters.channels.as_ref().unwrap().property::<gst::Caps>("caps");
+        assert_eq!(caps.structure(0).unwrap().get::<i32>("channels").unwrap(), 1);
+        player.set_mono(false).unwrap();
+        assert!(player.filters.channels.as_ref().unwrap().property::<gst::Caps>("caps").is_any());
+    }
+
+    #[test]
+    fn filter_chain() {
+        let mut player = match GStreamer::with_output(AudioOutput::Null) {
+            Ok(player) => player,
+            Err(error) => return println!("Skipping, the player can't be made: {}", error),
+        };
+        if player.filters.balance.is_none() {
+            return println!("Skipping, the output filters aren't installed");
+        }
+        assert!(player.set_filter_chain(Some("not-an-element")).is_err());
+
+        // The settings of the filters are kept when the chain changes
+        player.set_balance(0.5).unwrap();
+        player.set_filter_chain(Some("identity name=custom")).unwrap();
+        let bin = player.filters.bin.clone().unwrap().downcast::<gst::Bin>().unwrap();
+        assert!(bin.by_name("custom").is_some());
+        assert_eq!(player.filters.balance.as_ref().unwrap().property::<f32>("panorama"), 0.5);
+        player.set_filter_chain(None).unwrap();
+        let bin = player.filters.bin.clone().unwrap().downcast::<gst::Bin>().unwrap();
+        assert!(bin.by_name("custom").is_none());
+    }
+
+    #[test]
+    fn monitor_wakes_for_points() {
+        let ms = chrono::Duration::milliseconds;
+        let interval = Duration::from_millis(250);
+        let points = [ms(5000), ms(8000), ms(10000)];
+        assert_eq!(monitor_wait(ms(1000), &points, interval), interval);
+        assert_eq!(monitor_wait(ms(4900), &points, interval), Duration::from_millis(100));
+        assert_eq!(monitor_wait(ms(5000), &points, interval), interval);
+        assert_eq!(monitor_wait(ms(9999), &points, interval), MONITOR_MIN_WAIT);
+        assert_eq!(monitor_wait(ms(12000), &points, interval), interval);
+    }
+
+    #[test]
+    fn icy_stream_title() {
+        assert_eq!(
+            parse_stream_title("Daft Punk - One More Time"),
+            (Some("One More Time".to_string()), Some("Daft Punk".to_string()))
+        );
+        assert_eq!(
+            parse_stream_title("Station Jingle"),
+            (Some("Station Jingle".to_string()), None)
+        );
+        assert_eq!(parse_stream_title("   "), (None, None));
+    }
+}
@@ -1,4 +1,7 @@
+use std::collections::BTreeMap;
+
 use chrono::Duration;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::music_storage::library::URI;
@@ -11,6 +14,10 @@ pub enum PlayerError {
     StateChange(String),
     #[error("seeking failed: {0}")]
     Seek(String),
+    #[error("could not seek to the start of the track: {0}")]
+    SeekFailed(String),
+    #[error("timed out after {0:?} waiting for the source to load")]
+    SourceTimeout(std::time::Duration),
     #[error("the file or source is not found")]
     NotFound,
     #[error("failed to build gstreamer item")]
@@ -21,6 +28,552 @@ pub enum PlayerError {
     General(String),
 }
 
+/// How often players check the playback position, unless they are told otherwise
+pub const POSITION_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// How long before a track ends [`PlayerCommand::TransitionAhead`] is
+/// sent, unless players are told otherwise
+pub const TRANSITION_LEAD: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long the player waits for operations to complete before
+/// giving up and returning an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerTimeouts {
+    /// Time to wait for a new source to load
+    pub load: std::time::Duration,
+    /// Time to wait for the initial seek into a CUE track
+    pub seek: std::time::Duration,
+}
+
+impl Default for PlayerTimeouts {
+    fn default() -> Self {
+        PlayerTimeouts {
+            load: std::time::Duration::from_secs(5),
+            seek: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+/// How long each step of a [PauseFade] is
+pub const FADE_STEP: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// A short ramp of the volume when pausing, resuming, and stopping,
+/// instead of cutting the audio off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PauseFade {
+    pub enabled: bool,
+    pub duration: std::time::Duration,
+}
+
+impl Default for PauseFade {
+    fn default() -> Self {
+        PauseFade {
+            enabled: true,
+            duration: std::time::Duration::from_millis(200),
+        }
+    }
+}
+
+impl PauseFade {
+    /// The volume at each [FADE_STEP] going from `from` to `to`, ending
+    /// on `to`. There are no steps if the fade is disabled.
+    pub fn steps(&self, from: f64, to: f64) -> Vec<f64> {
+        if !self.enabled {
+            return Vec::new();
+        }
+        let count = (self.duration.as_millis() / FADE_STEP.as_millis()).max(1) as usize;
+        (1..=count).map(|step| from + (to - from) * step as f64 / count as f64).collect()
+    }
+}
+
+/// The shape of the volume of a track fading in or out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FadeCurve {
+    Linear,
+    /// Keeps the loudness even through a crossfade, where a linear fade
+    /// dips in the middle
+    #[default]
+    EqualPower,
+    /// Slow at the start and end, and quick in the middle
+    SCurve,
+}
+
+impl FadeCurve {
+    /// The gain of a track `progress` of the way through fading in, from
+    /// `0` to `1`. Fading out is the same curve backwards.
+    pub fn gain(&self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => progress,
+            FadeCurve::EqualPower => (progress * std::f64::consts::FRAC_PI_2).sin(),
+            FadeCurve::SCurve => progress * progress * (3.0 - 2.0 * progress),
+        }
+    }
+}
+
+/// How the end of each track fades out and the start of the next fades in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crossfade {
+    pub curve: FadeCurve,
+    pub fade_out: std::time::Duration,
+    pub fade_in: std::time::Duration,
+}
+
+impl Crossfade {
+    /// The gain at `position` in a track which is `length` long
+    pub fn gain(&self, position: std::time::Duration, length: std::time::Duration) -> f64 {
+        let progress = |time: std::time::Duration, fade: std::time::Duration| match fade.is_zero() {
+            true => 1.0,
+            false => time.as_secs_f64() / fade.as_secs_f64(),
+        };
+        let fading_out = self.curve.gain(progress(length.saturating_sub(position), self.fade_out));
+        let fading_in = self.curve.gain(progress(position, self.fade_in));
+        fading_out.min(fading_in)
+    }
+}
+
+/// Codecs which are lossless, as named in the `audio-codec` tag
+const LOSSLESS_CODECS: [&str; 8] = ["lossless", "flac", "alac", "wavpack", "pcm", "tta", "monkey's audio", "aiff"];
+
+/// The format of the track being played, as the decoder sees it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u32>,
+    /// The average bitrate in kbps
+    pub bitrate: Option<u32>,
+    pub lossless: bool,
+}
+
+impl StreamInfo {
+    /// Whether the `codec` named by the `audio-codec` tag is lossless
+    pub fn is_lossless(codec: &str) -> bool {
+        let codec = codec.to_lowercase();
+        LOSSLESS_CODECS.iter().any(|lossless| codec.contains(lossless))
+    }
+
+    /// The short name of the codec, such as `FLAC` for
+    /// "Free Lossless Audio Codec (FLAC)"
+    pub fn short_codec(&self) -> Option<&str> {
+        let codec = self.codec.as_deref()?;
+        match codec.strip_suffix(')').and_then(|codec| codec.rsplit_once('(')) {
+            Some((_, short)) => Some(short),
+            None => Some(codec),
+        }
+    }
+}
+
+impl std::fmt::Display for StreamInfo {
+    /// A badge such as `FLAC 44.1kHz/16bit`, or `MP3 320kbps` for lossy
+    /// codecs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(codec) = self.short_codec() {
+            parts.push(codec.to_string());
+        }
+        let rate = self.sample_rate.map(|rate| format!("{}kHz", rate as f64 / 1000.0));
+        match (self.lossless, rate, self.bit_depth, self.bitrate) {
+            (true, Some(rate), Some(depth), _) => parts.push(format!("{}/{}bit", rate, depth)),
+            (true, Some(rate), None, _) => parts.push(rate),
+            (_, _, _, Some(bitrate)) => parts.push(format!("{}kbps", bitrate)),
+            (_, Some(rate), _, None) => parts.push(rate),
+            _ => (),
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// The quietest level visualizers are sent, in dB
+pub const VISUALIZER_FLOOR: f32 = -80.0;
+
+/// How the spectrum and levels of the output are measured for
+/// visualizers, such as VU meters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Visualizer {
+    pub enabled: bool,
+    /// How many frequency bands the spectrum is split into
+    pub bands: u32,
+    /// How often the data is sent
+    pub interval: std::time::Duration,
+}
+
+impl Default for Visualizer {
+    fn default() -> Self {
+        Visualizer {
+            enabled: false,
+            bands: 32,
+            interval: std::time::Duration::from_millis(50),
+        }
+    }
+}
+
+/// Sent by [`Player::visualizer_rx`] every [`Visualizer::interval`] while
+/// playing
+#[derive(Debug, Clone, PartialEq)]
+pub enum VisualizerData {
+    /// The magnitude of each band in dB, from low to high frequencies
+    Spectrum(Vec<f32>),
+    /// The loudness of each channel in dB
+    Level { rms: Vec<f32>, peak: Vec<f32> },
+}
+
+/// A level in dB from [`VISUALIZER_FLOOR`] to `0`, as from `0` to `1` for
+/// drawing
+pub fn visualizer_scale(db: f32) -> f32 {
+    ((db - VISUALIZER_FLOOR) / -VISUALIZER_FLOOR).clamp(0.0, 1.0)
+}
+
+/// The samples of a [`PcmFrame`], in the format they were decoded to
+#[derive(Debug, Clone, PartialEq)]
+pub enum PcmSamples {
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+impl PcmSamples {
+    /// Read little-endian samples in a raw audio `format` such as `S16LE`,
+    /// or `None` if the format isn't one of these
+    pub fn from_bytes(format: &str, bytes: &[u8]) -> Option<Self> {
+        Some(match format {
+            "S16LE" => PcmSamples::I16(bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()),
+            "S32LE" => PcmSamples::I32(bytes.chunks_exact(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect()),
+            "F32LE" => PcmSamples::F32(bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect()),
+            "F64LE" => PcmSamples::F64(bytes.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap())).collect()),
+            _ => return None,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            PcmSamples::I16(samples) => samples.len(),
+            PcmSamples::I32(samples) => samples.len(),
+            PcmSamples::F32(samples) => samples.len(),
+            PcmSamples::F64(samples) => samples.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The samples as floats, where `1` is full scale
+    pub fn to_f32(&self) -> Vec<f32> {
+        match self {
+            PcmSamples::I16(samples) => samples.iter().map(|s| *s as f32 / i16::MAX as f32).collect(),
+            PcmSamples::I32(samples) => samples.iter().map(|s| (*s as f64 / i32::MAX as f64) as f32).collect(),
+            PcmSamples::F32(samples) => samples.clone(),
+            PcmSamples::F64(samples) => samples.iter().map(|s| *s as f32).collect(),
+        }
+    }
+}
+
+/// A piece of decoded audio, sent by [`Player::pcm_frames`] while the tap
+/// is on
+#[derive(Debug, Clone, PartialEq)]
+pub struct PcmFrame {
+    pub rate: u32,
+    pub channels: u32,
+    /// Where in the file the samples start
+    pub position: Option<std::time::Duration>,
+    /// The samples of each channel in turn
+    pub samples: PcmSamples,
+}
+
+/// How much time was saved by skipping silence, worked out from where
+/// each gap starts and ends in the file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SkippedSilence {
+    /// Whether silence is being skipped, gaps aren't counted otherwise
+    pub skipping: bool,
+    /// Where the current gap started
+    gap: Option<std::time::Duration>,
+    pub total: std::time::Duration,
+}
+
+impl SkippedSilence {
+    /// A gap of silence started `at` in the file
+    pub fn gap_started(&mut self, at: std::time::Duration) {
+        self.gap = self.skipping.then_some(at);
+    }
+
+    /// The current gap of silence finished `at` in the file
+    pub fn gap_finished(&mut self, at: std::time::Duration) {
+        if let Some(start) = self.gap.take() {
+            self.total += at.saturating_sub(start);
+        }
+    }
+}
+
+/// How exactly a seek lands on the position asked for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeekMode {
+    /// Jump to the closest keyframe, which is quick but can be seconds
+    /// away in some files, such as VBR MP3s
+    Fast,
+    /// Land exactly on the position, decoding from the keyframe before it
+    #[default]
+    Accurate,
+}
+
+/// How the volume of `0` to `1` a listener sets is turned into the gain
+/// of the output, as the ear hears loudness logarithmically
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VolumeCurve {
+    /// The volume is the gain, so most of the useful range is near `0`
+    #[default]
+    Linear,
+    /// The volume is spread over [VolumeCurve::RANGE] dB
+    Logarithmic,
+    /// The gain is the volume to the power of the exponent
+    Exponent(f64),
+}
+
+impl VolumeCurve {
+    /// How many dB below full scale the lowest volume above `0` is, with
+    /// [VolumeCurve::Logarithmic]
+    pub const RANGE: f64 = 60.0;
+
+    /// The gain of the output at `volume`, both from `0` to `1`
+    pub fn gain(&self, volume: f64) -> f64 {
+        let volume = volume.clamp(0.0, 1.0);
+        match self {
+            VolumeCurve::Linear => volume,
+            _ if volume == 0.0 => 0.0,
+            VolumeCurve::Logarithmic => 10f64.powf((volume - 1.0) * Self::RANGE / 20.0),
+            VolumeCurve::Exponent(exponent) => volume.powf(exponent.max(0.01)),
+        }
+    }
+
+    /// The volume which gives the output `gain`, the inverse of
+    /// [VolumeCurve::gain]
+    pub fn volume(&self, gain: f64) -> f64 {
+        let gain = gain.clamp(0.0, 1.0);
+        match self {
+            VolumeCurve::Linear => gain,
+            _ if gain == 0.0 => 0.0,
+            VolumeCurve::Logarithmic => (1.0 + 20.0 * gain.log10() / Self::RANGE).max(0.0),
+            VolumeCurve::Exponent(exponent) => gain.powf(1.0 / exponent.max(0.01)),
+        }
+    }
+
+    /// The volume as a percentage, for showing to the listener
+    pub fn percent(volume: f64) -> u8 {
+        (volume.clamp(0.0, 1.0) * 100.0).round() as u8
+    }
+}
+
+/// Where a player sends its audio
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioOutput {
+    /// The default audio device of the system
+    #[default]
+    Device,
+    /// Nowhere, with playback timed by the system clock instead of an
+    /// audio device, so the player can run without audio hardware such as
+    /// in CI and tests. Songs still play in real time.
+    Null,
+    /// A sound system picked by the listener instead of the default
+    Sink(OutputSink),
+}
+
+/// The sound systems audio can be sent to
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SinkClass {
+    #[default]
+    PulseAudio,
+    PipeWire,
+    Jack,
+    Alsa,
+    Wasapi,
+    CoreAudio,
+    /// Any other GStreamer sink, by the name of its element
+    Custom(String),
+}
+
+impl SinkClass {
+    /// The name of the GStreamer element of the sink
+    pub fn element(&self) -> &str {
+        match self {
+            SinkClass::PulseAudio => "pulsesink",
+            SinkClass::PipeWire => "pipewiresink",
+            SinkClass::Jack => "jackaudiosink",
+            SinkClass::Alsa => "alsasink",
+            SinkClass::Wasapi => "wasapisink",
+            SinkClass::CoreAudio => "osxaudiosink",
+            SinkClass::Custom(element) => element,
+        }
+    }
+
+    /// The property of the sink which picks the device to play to
+    pub fn device_property(&self) -> &str {
+        match self {
+            SinkClass::PipeWire => "target-object",
+            SinkClass::Jack => "port-pattern",
+            _ => "device",
+        }
+    }
+}
+
+/// A sound system and device to play to, stored in the config
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputSink {
+    pub class: SinkClass,
+    /// The device to play to, such as `hw:0,0` for ALSA, or the default
+    /// device of the sound system if it's `None`
+    pub device: Option<String>,
+    /// Play straight to the device, without the mixer of the sound system,
+    /// for bit-perfect output. This is exclusive mode with WASAPI, and a
+    /// `hw:` device with ALSA. The volume, gain, and equalizer still change
+    /// the audio unless they are left alone.
+    pub exclusive: bool,
+    /// Any other properties of the sink element, by name
+    pub properties: BTreeMap<String, String>,
+}
+
+impl OutputSink {
+    /// The properties to set on the sink element, including the device
+    pub fn sink_properties(&self) -> Vec<(String, String)> {
+        let mut properties = Vec::new();
+        let device = match (&self.class, self.exclusive) {
+            // `plughw:` and `default` convert the audio, `hw:` devices don't
+            (SinkClass::Alsa, true) => Some(match self.device.as_deref() {
+                Some(device) if device.starts_with("hw:") => device.to_string(),
+                Some(device) if device.starts_with("plughw:") => device.replacen("plughw:", "hw:", 1),
+                _ => "hw:0".to_string(),
+            }),
+            _ => self.device.clone(),
+        };
+        if let Some(device) = device {
+            properties.push((self.class.device_property().to_string(), device));
+        }
+        if self.class == SinkClass::Wasapi && self.exclusive {
+            properties.push(("exclusive".to_string(), "true".to_string()));
+        }
+        properties.extend(self.properties.iter().map(|(name, value)| (name.clone(), value.clone())));
+        properties
+    }
+}
+
+/// An audio device which went away, such as headphones being unplugged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemovedDevice {
+    pub name: String,
+    /// Whether it was the default device of the sound system
+    pub default: bool,
+    /// The names the sound system knows it by, such as `hw:1,0` with ALSA
+    /// or the node name with PipeWire
+    pub ids: Vec<String>,
+}
+
+impl RemovedDevice {
+    /// Whether `output` was playing to the device
+    pub fn was_output(&self, output: &AudioOutput) -> bool {
+        match output {
+            AudioOutput::Null => false,
+            AudioOutput::Device => self.default,
+            AudioOutput::Sink(sink) => match &sink.device {
+                Some(device) => self.name == *device || self.ids.contains(device),
+                None => self.default,
+            },
+        }
+    }
+}
+
+/// What happens when the audio device being played to is removed, stored
+/// in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigDeviceRemoval {
+    /// Pause, rather than carrying on from whichever device the sound
+    /// system moves playback to, such as the speakers
+    pub pause: bool,
+}
+
+impl Default for ConfigDeviceRemoval {
+    fn default() -> Self {
+        ConfigDeviceRemoval { pause: true }
+    }
+}
+
+/// The gain of the bass, middle, and treble of the output in dB, from
+/// `-24` to `12`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Equalizer {
+    pub low: f64,
+    pub mid: f64,
+    pub high: f64,
+}
+
+impl Equalizer {
+    /// The lowest and highest gain of each band
+    pub const RANGE: (f64, f64) = (-24.0, 12.0);
+
+    /// Add `gain` to every band, keeping them within [Equalizer::RANGE]
+    pub fn with_gain(self, gain: f64) -> Self {
+        let band = |value: f64| (value + gain).clamp(Self::RANGE.0, Self::RANGE.1);
+        Equalizer {
+            low: band(self.low),
+            mid: band(self.mid),
+            high: band(self.high),
+        }
+    }
+}
+
+/// A source which is loading in the background, returned by [`Player::load`]
+#[derive(Debug)]
+pub struct LoadHandle {
+    rx: crossbeam::channel::Receiver<Result<(), PlayerError>>,
+}
+
+impl LoadHandle {
+    /// Create a handle along with the sender which reports
+    /// the result of loading to it
+    pub fn new() -> (crossbeam::channel::Sender<Result<(), PlayerError>>, Self) {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        (tx, LoadHandle { rx })
+    }
+
+    /// Block until the source has finished loading
+    pub fn wait(self) -> Result<(), PlayerError> {
+        match self.rx.recv() {
+            Ok(result) => result,
+            Err(_) => Err(PlayerError::General("loading was abandoned".into())),
+        }
+    }
+
+    /// Block until the source has finished loading, or `timeout` passes,
+    /// returning `None` if it is still loading
+    pub fn wait_timeout(&self, timeout: std::time::Duration) -> Option<Result<(), PlayerError>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(crossbeam::channel::RecvTimeoutError::Timeout) => None,
+            Err(crossbeam::channel::RecvTimeoutError::Disconnected) => {
+                Some(Err(PlayerError::General("loading was abandoned".into())))
+            }
+        }
+    }
+
+    /// Get the result of loading without blocking, returning
+    /// `None` if it is still loading
+    pub fn try_result(&self) -> Option<Result<(), PlayerError>> {
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(crossbeam::channel::TryRecvError::Empty) => None,
+            Err(crossbeam::channel::TryRecvError::Disconnected) => {
+                Some(Err(PlayerError::General("loading was abandoned".into())))
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum PlayerState {
     Playing,
@@ -31,17 +584,64 @@ pub enum PlayerState {
     VoidPending,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 pub enum PlayerCommand {
     Play,
     Pause,
     EndOfStream,
     AboutToFinish,
+    /// The track ends in `remaining`, sent once per track at the time set by
+    /// [`Player::set_transition_lead`], or as soon as it starts if it
+    /// is shorter than that
+    TransitionAhead {
+        remaining: std::time::Duration,
+    },
+    /// The song currently playing on a stream changed, taken
+    /// from its ICY metadata
+    TagsChanged {
+        title: Option<String>,
+        artist: Option<String>,
+    },
+    /// The audio device being played to was removed
+    DeviceRemoved(RemovedDevice),
+    /// A volume above the [`Player::volume_cap`] was asked for, so the
+    /// cap was used instead
+    VolumeCapped {
+        requested: f64,
+        cap: f64,
+    },
+}
+
+/// Limit `volume` to between `0` and `cap`, returning the volume to use
+/// and whether it was above the cap
+pub(crate) fn cap_volume(volume: f64, cap: f64) -> (f64, bool) {
+    let cap = cap.clamp(0.0, 1.0);
+    match volume > cap {
+        true => (cap, true),
+        false => (volume.max(0.0), false),
+    }
+}
+
+/// Where the listener hears a track which the player is at `position` in,
+/// with audio taking `latency` to get from the player to their ears, such
+/// as through Bluetooth headphones
+pub(crate) fn heard_position(position: Duration, latency: std::time::Duration) -> Duration {
+    let latency = Duration::from_std(latency).unwrap_or(Duration::zero());
+    (position - latency).max(Duration::zero())
 }
 
 pub trait Player {
-    /// Create a new player.
-    fn new() -> Result<Self, PlayerError> where Self: Sized;
+    /// Create a new player which plays to the default audio device.
+    fn new() -> Result<Self, PlayerError> where Self: Sized {
+        Self::with_output(AudioOutput::default())
+    }
+
+    /// Create a new player which plays to `output`.
+    fn with_output(output: AudioOutput) -> Result<Self, PlayerError> where Self: Sized;
+
+    /// Switch where the player sends its audio, carrying on from the
+    /// same position.
+    fn set_output(&mut self, output: AudioOutput) -> Result<(), PlayerError>;
 
     /// Get the currently playing [URI] from the player.
     fn source(&self) -> &Option<URI>;
@@ -55,14 +655,124 @@ pub trait Player {
     /// occurs.
     fn enqueue_next(&mut self, next_track: &URI) -> Result<(), PlayerError>;
 
-    /// Set the playback volume, accepts a float from `0` to `1`.
+    /// Begin loading a new [`URI`] without waiting for it to be ready,
+    /// the same as [`Player::enqueue_next`] otherwise.
     ///
-    /// Values outside the range of `0` to `1` will be capped.
+    /// The returned [`LoadHandle`] reports when the source has loaded, or
+    /// the error which stopped it from loading.
+    fn load(&mut self, next_track: &URI) -> Result<LoadHandle, PlayerError>;
+
+    /// Skip the audio before the first and after the second duration of the
+    /// next local track which is loaded, such as silence. Only that track is
+    /// trimmed, and the reported position is still from the start of the file.
+    fn set_next_trim(&mut self, trim: Option<(std::time::Duration, std::time::Duration)>);
+
+    /// Set how long the player waits for a source to load or seek before
+    /// [`Player::enqueue_next`] fails with an error.
+    fn set_timeouts(&mut self, timeouts: PlayerTimeouts);
+
+    /// Set how often the player checks the playback position, which is
+    /// how quickly it notices the end of a track. Checking less often
+    /// uses less energy.
+    fn set_poll_interval(&mut self, interval: std::time::Duration);
+
+    /// Set how long before the end of each track
+    /// [`PlayerCommand::TransitionAhead`] is sent. This doesn't depend on
+    /// the poll interval, the player wakes up in time to send it.
+    fn set_transition_lead(&mut self, lead: std::time::Duration);
+
+    /// Set the playback volume, accepts a float from `0` to `1`, which is
+    /// turned into the gain of the output by the [`VolumeCurve`].
+    ///
+    /// Values outside the range of `0` to the [`Player::volume_cap`] will
+    /// be capped, sending [`PlayerCommand::VolumeCapped`] if it was too high.
     fn set_volume(&mut self, volume: f64);
 
     /// Returns the current volume level, a float from `0` to `1`.
     fn volume(&self) -> f64;
 
+    /// Set how the volume is turned into the gain of the output.
+    fn set_volume_curve(&mut self, curve: VolumeCurve);
+
+    /// Set the highest volume the player can be set to, from `0` to `1`.
+    ///
+    /// The current volume is lowered if it is above the new cap.
+    fn set_volume_cap(&mut self, cap: f64);
+
+    /// Returns the highest volume the player can be set to.
+    fn volume_cap(&self) -> f64;
+
+    /// Silence the output, remembering the volume so that
+    /// [`Player::unmute`] can put it back. Setting the volume unmutes.
+    fn mute(&mut self);
+
+    /// Put back the volume from before [`Player::mute`].
+    fn unmute(&mut self);
+
+    fn is_muted(&self) -> bool;
+
+    /// Set how long the audio output takes to be heard, which is taken
+    /// off of the [`Player::position`] so lyrics and visualizers are in
+    /// time with what the listener hears.
+    fn set_latency_offset(&mut self, latency: std::time::Duration);
+
+    /// Set how the volume is faded when pausing, resuming, and stopping.
+    fn set_pause_fade(&mut self, fade: PauseFade);
+
+    /// Set how tracks fade out and in, or `None` to not fade them.
+    fn set_crossfade(&mut self, crossfade: Option<Crossfade>);
+
+    /// Set how the output is measured for visualizers, see [`Visualizer`].
+    ///
+    /// Fails if it's enabled and the player can't measure it, such as if
+    /// a plugin it needs is not installed.
+    fn set_visualizer(&mut self, visualizer: Visualizer) -> Result<(), PlayerError>;
+
+    /// The channel [`VisualizerData`] is sent on while the [`Visualizer`]
+    /// is enabled. Data the frontend doesn't keep up with is dropped.
+    fn visualizer_rx(&self) -> &crossbeam::channel::Receiver<VisualizerData>;
+
+    /// Start or stop sending the decoded audio to [`Player::pcm_frames`],
+    /// before the gain, equalizer, and volume are applied. Playback carries
+    /// on as normal.
+    fn set_pcm_tap(&mut self, enabled: bool) -> Result<(), PlayerError>;
+
+    /// The channel [`PcmFrame`]s are sent on while the tap is on. Frames the
+    /// frontend doesn't keep up with are dropped.
+    fn pcm_frames(&self) -> &crossbeam::channel::Receiver<PcmFrame>;
+
+    /// Send the output through a chain of custom elements, such as crossfeed
+    /// or LADSPA plugins, written as a gst-launch description like
+    /// `bs2b ! ladspa-delay-so-delay-5s`, or take it out if it's `None`.
+    ///
+    /// The chain is heard from the next track which is loaded, and fails if
+    /// any of its elements aren't installed.
+    fn set_filter_chain(&mut self, chain: Option<&str>) -> Result<(), PlayerError>;
+
+    /// Set the gain of each band of the output, see [`Equalizer`].
+    fn set_equalizer(&mut self, equalizer: Equalizer) -> Result<(), PlayerError>;
+
+    /// Skip through gaps of silence longer than `minimum_gap` as they're
+    /// played, such as the pauses of spoken content, or stop skipping if
+    /// it's `None`. The position counts only the audio which was played.
+    fn set_skip_silence(&mut self, minimum_gap: Option<std::time::Duration>) -> Result<(), PlayerError>;
+
+    /// How much time has been saved by skipping silence since the player
+    /// was made
+    fn silence_skipped(&self) -> std::time::Duration;
+
+    /// Set the balance between the left and right speakers, from `-1`
+    /// for only the left to `1` for only the right.
+    fn set_balance(&mut self, balance: f64) -> Result<(), PlayerError>;
+
+    /// Mix every channel down to mono, such as for a single speaker or
+    /// for listeners who can only hear from one side.
+    fn set_mono(&mut self, mono: bool) -> Result<(), PlayerError>;
+
+    /// Set the gain of the output in dB, separate from the volume, such
+    /// as for ReplayGain. The `limiter` keeps peaks from clipping.
+    fn set_gain(&mut self, gain: f64, limiter: bool) -> Result<(), PlayerError>;
+
     /// If the player is paused or stopped, starts playback.
     fn play(&mut self) -> Result<(), PlayerError>;
 
@@ -75,12 +785,17 @@ pub trait Player {
     /// Convenience function to check if playback is paused.
     fn is_paused(&self) -> bool;
 
-    /// Get the current playback position of the player.
+    /// Get the current playback position of the player, as heard after
+    /// the [`Player::set_latency_offset`].
     fn position(&self) -> Option<Duration>;
 
     /// Get the duration of the currently playing track.
     fn duration(&self) -> Option<Duration>;
 
+    /// The format of the currently playing track, once it has begun
+    /// playing.
+    fn stream_info(&self) -> Option<StreamInfo>;
+
     /// Seek relative to the current position.
     ///
     /// The position is capped at the duration of the song, and zero.
@@ -91,7 +806,227 @@ pub trait Player {
     /// The position is capped at the duration of the song, and zero.
     fn seek_to(&mut self, target_pos: Duration) -> Result<(), PlayerError>;
 
+    /// Seek absolutely within the song using `mode`, instead of the mode
+    /// set by [`Player::set_seek_mode`].
+    fn seek_to_with(&mut self, target_pos: Duration, mode: SeekMode) -> Result<(), PlayerError>;
+
+    /// Set the [`SeekMode`] used by [`Player::seek_to`] and [`Player::seek_by`].
+    fn set_seek_mode(&mut self, mode: SeekMode);
+
+    /// Repeat the part of the current track from `a` to `b` until
+    /// [`Player::clear_loop_region`] is called or the track changes.
+    ///
+    /// Fails if `b` isn't after `a`, or is past the end of the track.
+    fn set_loop_region(&mut self, a: Duration, b: Duration) -> Result<(), PlayerError>;
+
+    /// Stop repeating part of the track, playing on from where it is.
+    fn clear_loop_region(&mut self);
+
+    /// The part of the track being repeated, if any.
+    fn loop_region(&self) -> Option<(Duration, Duration)>;
+
+    /// Start dragging through the song, such as with a seekbar. The audio
+    /// is muted until [`Player::end_scrub`].
+    fn begin_scrub(&mut self) -> Result<(), PlayerError>;
+
+    /// Jump to near `target_pos` while scrubbing, landing on the closest
+    /// keyframe so that it's fast enough to call for every movement.
+    fn scrub_to(&mut self, target_pos: Duration) -> Result<(), PlayerError>;
+
+    /// Stop scrubbing, seeking exactly to the last position scrubbed to
+    /// and unmuting the audio.
+    fn end_scrub(&mut self) -> Result<(), PlayerError>;
+
     /// Return a reference to the player message channel, which can be cloned
     /// in order to monitor messages from the player.
     fn message_channel(&self) -> &crossbeam::channel::Receiver<PlayerCommand>;
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{
+        cap_volume, heard_position, visualizer_scale, Crossfade, FadeCurve, LoadHandle, PauseFade, PcmSamples, PlayerError,
+        AudioOutput, OutputSink, RemovedDevice, SinkClass, SkippedSilence, StreamInfo, VolumeCurve, VISUALIZER_FLOOR,
+    };
+
+    #[test]
+    fn load_handle() {
+        let (tx, handle) = LoadHandle::new();
+        assert!(handle.try_result().is_none());
+        assert!(handle.wait_timeout(Duration::from_millis(1)).is_none());
+
+        std::thread::spawn(move || tx.send(Err(PlayerError::NotFound)).unwrap());
+        assert!(matches!(handle.wait(), Err(PlayerError::NotFound)));
+
+        // Dropping the sender without a result counts as a failure
+        let (tx, handle) = LoadHandle::new();
+        drop(tx);
+        assert!(handle.try_result().is_some_and(|result| result.is_err()));
+    }
+
+    #[test]
+    fn volume_cap() {
+        assert_eq!(cap_volume(0.9, 0.5), (0.5, true));
+        assert_eq!(cap_volume(0.3, 0.5), (0.3, false));
+        assert_eq!(cap_volume(-1.0, 0.5), (0.0, false));
+        assert_eq!(cap_volume(2.0, 7.0), (1.0, true));
+    }
+
+    #[test]
+    fn pcm_samples() {
+        let bytes: Vec<u8> = [i16::MAX, 0, i16::MIN + 1].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let samples = PcmSamples::from_bytes("S16LE", &bytes).unwrap();
+        assert_eq!(samples, PcmSamples::I16(vec![i16::MAX, 0, i16::MIN + 1]));
+        assert_eq!(samples.to_f32(), vec![1.0, 0.0, -1.0]);
+
+        let bytes: Vec<u8> = [0.5f32, -0.25].iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(PcmSamples::from_bytes("F32LE", &bytes).unwrap().to_f32(), vec![0.5, -0.25]);
+        assert_eq!(PcmSamples::from_bytes("S24BE", &bytes), None);
+    }
+
+    #[test]
+    fn visualizer_levels() {
+        assert_eq!(visualizer_scale(0.0), 1.0);
+        assert_eq!(visualizer_scale(VISUALIZER_FLOOR / 2.0), 0.5);
+        // Silence is reported far below the floor
+        assert_eq!(visualizer_scale(-700.0), 0.0);
+        assert_eq!(visualizer_scale(3.0), 1.0);
+    }
+
+    #[test]
+    fn latency_offset() {
+        let position = chrono::Duration::seconds(10);
+        assert_eq!(heard_position(position, Duration::from_millis(250)), chrono::Duration::milliseconds(9750));
+        assert_eq!(heard_position(position, Duration::ZERO), position);
+        // Nothing has been heard yet at the very start
+        assert_eq!(heard_position(chrono::Duration::milliseconds(100), Duration::from_millis(250)), chrono::Duration::zero());
+    }
+
+    #[test]
+    fn skipped_silence() {
+        let mut skipped = SkippedSilence::default();
+        // Gaps while not skipping aren't time saved
+        skipped.gap_started(Duration::from_secs(1));
+        skipped.gap_finished(Duration::from_secs(2));
+        assert_eq!(skipped.total, Duration::ZERO);
+
+        skipped.skipping = true;
+        skipped.gap_started(Duration::from_millis(3000));
+        skipped.gap_finished(Duration::from_millis(4500));
+        skipped.gap_started(Duration::from_millis(9000));
+        skipped.gap_finished(Duration::from_millis(9250));
+        skipped.gap_finished(Duration::from_millis(9900));
+        assert_eq!(skipped.total, Duration::from_millis(1750));
+    }
+
+    #[test]
+    fn output_sinks() {
+        let mut sink = OutputSink { class: SinkClass::Alsa, device: Some("plughw:1,0".into()), ..Default::default() };
+        assert_eq!(sink.sink_properties(), vec![("device".to_string(), "plughw:1,0".to_string())]);
+        // Bit-perfect output goes straight to the hardware
+        sink.exclusive = true;
+        assert_eq!(sink.sink_properties(), vec![("device".to_string(), "hw:1,0".to_string())]);
+
+        let mut sink = OutputSink { class: SinkClass::Wasapi, exclusive: true, ..Default::default() };
+        sink.properties.insert("low-latency".into(), "true".into());
+        assert_eq!(
+            sink.sink_properties(),
+            vec![("exclusive".to_string(), "true".to_string()), ("low-latency".to_string(), "true".to_string())]
+        );
+        assert_eq!(SinkClass::Custom("openalsink".into()).element(), "openalsink");
+    }
+
+    #[test]
+    fn removed_devices() {
+        let headphones = RemovedDevice { name: "Headphones".into(), default: true, ids: vec!["alsa_output.usb".into()] };
+        assert!(headphones.was_output(&AudioOutput::Device));
+        assert!(!headphones.was_output(&AudioOutput::Null));
+
+        // Only the chosen device counts when there is one
+        let mut sink = OutputSink { class: SinkClass::PulseAudio, device: Some("alsa_output.usb".into()), ..Default::default() };
+        assert!(headphones.was_output(&AudioOutput::Sink(sink.clone())));
+        sink.device = Some("alsa_output.hdmi".into());
+        assert!(!headphones.was_output(&AudioOutput::Sink(sink)));
+
+        let speakers = RemovedDevice { name: "Speakers".into(), ..Default::default() };
+        assert!(!speakers.was_output(&AudioOutput::Device));
+    }
+
+    #[test]
+    fn volume_curves() {
+        assert_eq!(VolumeCurve::Linear.gain(0.3), 0.3);
+        // Half volume is 30 dB down, and each step is as many dB
+        let log = VolumeCurve::Logarithmic;
+        assert!((log.gain(0.5) - 10f64.powf(-1.5)).abs() < 1e-9);
+        assert_eq!((log.gain(0.0), log.gain(1.0)), (0.0, 1.0));
+        let cubic = VolumeCurve::Exponent(3.0);
+        assert!((cubic.gain(0.5) - 0.125).abs() < 1e-9);
+
+        // Frontends can show the gain of the output as a volume again
+        for curve in [VolumeCurve::Linear, log, cubic] {
+            assert!((curve.volume(curve.gain(0.42)) - 0.42).abs() < 1e-9);
+        }
+        assert_eq!(log.volume(0.0001), 0.0);
+        assert_eq!(VolumeCurve::percent(0.426), 43);
+    }
+
+    #[test]
+    fn crossfade_curves() {
+        assert_eq!(FadeCurve::Linear.gain(0.5), 0.5);
+        assert_eq!(FadeCurve::SCurve.gain(0.5), 0.5);
+        // An equal-power crossfade is just as loud halfway through
+        let half = FadeCurve::EqualPower.gain(0.5);
+        assert!((half * half * 2.0 - 1.0).abs() < 1e-9);
+        assert_eq!(FadeCurve::EqualPower.gain(2.0), 1.0);
+
+        let crossfade = Crossfade {
+            curve: FadeCurve::Linear,
+            fade_out: Duration::from_secs(10),
+            fade_in: Duration::from_secs(2),
+        };
+        let length = Duration::from_secs(200);
+        assert_eq!(crossfade.gain(Duration::from_secs(1), length), 0.5);
+        assert_eq!(crossfade.gain(Duration::from_secs(100), length), 1.0);
+        assert_eq!(crossfade.gain(Duration::from_secs(195), length), 0.5);
+        assert_eq!(crossfade.gain(length, length), 0.0);
+    }
+
+    #[test]
+    fn pause_fade() {
+        let fade = PauseFade { enabled: true, duration: Duration::from_millis(40) };
+        let steps = fade.steps(0.8, 0.0);
+        assert_eq!(steps.len(), 4);
+        assert_eq!((steps[1], steps[3]), (0.4, 0.0));
+        assert_eq!(fade.steps(0.0, 0.5).last(), Some(&0.5));
+        // Too short a fade still ends on the volume asked for
+        assert_eq!(PauseFade { enabled: true, duration: Duration::ZERO }.steps(1.0, 0.0), vec![0.0]);
+        assert!(PauseFade { enabled: false, ..fade }.steps(1.0, 0.0).is_empty());
+    }
+
+    #[test]
+    fn stream_info_badge() {
+        assert!(StreamInfo::is_lossless("Free Lossless Audio Codec (FLAC)"));
+        assert!(!StreamInfo::is_lossless("MPEG-1 Layer 3 (MP3)"));
+
+        let flac = StreamInfo {
+            codec: Some("Free Lossless Audio Codec (FLAC)".to_string()),
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            channels: Some(2),
+            bitrate: Some(900),
+            lossless: true,
+        };
+        assert_eq!(flac.to_string(), "FLAC 44.1kHz/16bit");
+        let mp3 = StreamInfo {
+            codec: Some("MPEG-1 Layer 3 (MP3)".to_string()),
+            bit_depth: Some(32),
+            bitrate: Some(320),
+            lossless: false,
+            ..flac
+        };
+        assert_eq!(mp3.to_string(), "MP3 320kbps");
+        assert_eq!(StreamInfo { codec: Some("Opus".to_string()), ..Default::default() }.to_string(), "Opus");
+    }
+}
@@ -0,0 +1,198 @@
+//! Checking whether a file or URL can be played before it is added,
+//! such as when it is dragged onto a frontend, along with the technical
+//! details of its audio
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use file_format::FileFormat;
+use gst::{ClockTime, MessageView};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use lofty::{AudioFile, Probe};
+use serde::Serialize;
+use thiserror::Error;
+
+/// How long a source has to get ready to play before it is given up on
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    #[error("the file does not exist")]
+    NotFound,
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("GStreamer failed: {0}")]
+    Gst(String),
+}
+
+impl From<glib::Error> for ProbeError {
+    fn from(value: glib::Error) -> Self {
+        ProbeError::Gst(value.to_string())
+    }
+}
+
+impl From<glib::BoolError> for ProbeError {
+    fn from(value: glib::BoolError) -> Self {
+        ProbeError::Gst(value.to_string())
+    }
+}
+
+/// What was found out about a file or URL
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProbeResult {
+    pub uri: String,
+    /// Whether the source has audio which can be decoded
+    pub playable: bool,
+    /// Why the source can't be played
+    pub error: Option<String>,
+    /// The GStreamer element which decodes the audio, such as `flacdec`
+    pub decoder: Option<String>,
+    /// The MIME type of the file or stream
+    pub mime: Option<String>,
+    pub codec: Option<String>,
+    pub duration: Option<Duration>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub bit_depth: Option<u8>,
+    /// The bitrate of the audio in kbps
+    pub bitrate: Option<u32>,
+    /// Every tag GStreamer found, such as `container-format`
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Find out whether a local file or URL can be played, which decoder
+/// would play it, and the details of its audio.
+///
+/// Sources which can't be played aren't an error, instead
+/// [ProbeResult::playable] is `false` and [ProbeResult::error] says why.
+pub fn probe(path_or_url: &str) -> Result<ProbeResult, ProbeError> {
+    let mut result = match path_or_url.contains("://") {
+        true => ProbeResult {
+            uri: path_or_url.to_string(),
+            ..Default::default()
+        },
+        false => probe_file(Path::new(path_or_url))?,
+    };
+
+    gst::init()?;
+    let pipeline = gst::parse_launch(&format!("uridecodebin name=decode uri=\"{}\" ! fakesink name=sink", result.uri))?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| ProbeError::Gst("the probe is not a pipeline".to_string()))?;
+
+    // The decoders are made inside of uridecodebin, so they are caught being added
+    let decoder = Arc::new(Mutex::new(None));
+    let decoder_ = decoder.clone();
+    pipeline.connect_deep_element_added(move |_, _, element| {
+        let is_decoder = element
+            .factory()
+            .and_then(|factory| factory.metadata("klass").map(|klass| klass.contains("Decoder")))
+            .unwrap_or(false);
+        if is_decoder {
+            *decoder_.lock().unwrap() = element.factory().map(|factory| factory.name().to_string());
+        }
+    });
+
+    pipeline.set_state(gst::State::Paused).map_err(|error| ProbeError::Gst(error.to_string()))?;
+    let bus = pipeline.bus().ok_or(ProbeError::Gst("the pipeline has no bus".to_string()))?;
+    let timeout = ClockTime::from_nseconds(PROBE_TIMEOUT.as_nanos() as u64);
+    let mut ready = false;
+    while let Some(message) = bus.timed_pop(timeout) {
+        match message.view() {
+            MessageView::AsyncDone(_) => {
+                ready = true;
+                break;
+            }
+            MessageView::Error(error) => {
+                result.error = Some(error.error().to_string());
+                break;
+            }
+            MessageView::Tag(tag) => {
+                for (name, value) in tag.tags().iter() {
+                    if let Ok(value) = value.serialize() {
+                        result.tags.insert(name.to_string(), value.to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if !ready && result.error.is_none() {
+        result.error = Some("the source took too long to load".to_string());
+    }
+
+    if ready {
+        let caps = pipeline
+            .by_name("sink")
+            .and_then(|sink| sink.static_pad("sink"))
+            .and_then(|pad| pad.current_caps());
+        let structure = caps.as_ref().and_then(|caps| caps.structure(0));
+        match structure {
+            Some(structure) if structure.name().starts_with("audio/") => {
+                result.playable = true;
+                result.sample_rate = result.sample_rate.or(structure.get::<i32>("rate").ok().map(|r| r as u32));
+                result.channels = result.channels.or(structure.get::<i32>("channels").ok().map(|c| c as u32));
+            }
+            _ => result.error = Some("the source has no audio".to_string()),
+        }
+        if result.duration.is_none() {
+            result.duration = pipeline
+                .query_duration::<ClockTime>()
+                .map(|duration| Duration::from_nanos(duration.nseconds()));
+        }
+    }
+    let _ = pipeline.set_state(gst::State::Null);
+
+    result.decoder = decoder.lock().unwrap().take();
+    result.codec = result.tags.get("audio-codec").cloned();
+    if result.bitrate.is_none() {
+        result.bitrate = result.tags.get("bitrate").and_then(|bitrate| bitrate.parse::<u32>().ok()).map(|b| b / 1000);
+    }
+    Ok(result)
+}
+
+/// Read what can be found out about a local file without GStreamer
+fn probe_file(path: &Path) -> Result<ProbeResult, ProbeError> {
+    if !path.is_file() {
+        return Err(ProbeError::NotFound);
+    }
+    let path = path.canonicalize()?;
+    let uri = glib::filename_to_uri(&path, None)?;
+    let mut result = ProbeResult {
+        uri: uri.to_string(),
+        mime: FileFormat::from_file(&path).ok().map(|format| format.media_type().to_string()),
+        ..Default::default()
+    };
+
+    // Files lofty can't read may still be playable by GStreamer
+    if let Ok(file) = Probe::open(&path).and_then(|probe| probe.read()) {
+        let properties = file.properties();
+        result.duration = Some(properties.duration());
+        result.sample_rate = properties.sample_rate();
+        result.channels = properties.channels().map(u32::from);
+        result.bit_depth = properties.bit_depth();
+        result.bitrate = properties.audio_bitrate();
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{probe, ProbeError};
+
+    #[test]
+    fn probe_missing_file() {
+        assert!(matches!(probe("/does/not/exist.flac"), Err(ProbeError::NotFound)));
+
+        // Without a decoder for it, a text file is never playable
+        let file = tempfile::Builder::new().suffix(".txt").tempfile().unwrap();
+        std::fs::write(file.path(), "not audio").unwrap();
+        if let Ok(result) = probe(file.path().to_str().unwrap()) {
+            assert!(!result.playable);
+            assert!(result.error.is_some());
+            assert_eq!(result.mime.as_deref(), Some("text/plain"));
+        }
+    }
+}
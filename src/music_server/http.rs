@@ -0,0 +1,395 @@
+//! A small HTTP server which exposes the tracks and album art of a
+//! [MusicLibrary], so cast devices and remote frontends can stream
+//! and seek within them without downloading whole files.
+//!
+//! Routes:
+//! - `/track/<uuid>` serves the audio file of a song
+//! - `/art/<uuid>/<index>` serves an entry of the song's album art
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::thread::{spawn, JoinHandle};
+
+use chrono::{DateTime, Utc};
+use file_format::FileFormat;
+use thiserror::Error;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use uuid::Uuid;
+
+use crate::music_storage::library::{MusicLibrary, URI};
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("could not start the server: {0}")]
+    Bind(String),
+}
+
+/// An inclusive range of bytes within a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Returned when a `Range` header cannot be satisfied for the resource
+#[derive(Debug, PartialEq, Eq)]
+pub struct RangeNotSatisfiable;
+
+/// Parses the value of a `Range` header for a resource of `len` bytes.
+///
+/// Returns `Ok(None)` if the header is malformed or requests multiple
+/// ranges, in which case the header should be ignored and the whole
+/// resource served.
+pub fn parse_range(header: &str, len: u64) -> Result<Option<ByteRange>, RangeNotSatisfiable> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        // A suffix range, the last `n` bytes of the resource
+        ("", suffix) => {
+            let suffix = match suffix.parse::<u64>() {
+                Ok(suffix) => suffix,
+                Err(_) => return Ok(None),
+            };
+            if suffix == 0 || len == 0 {
+                return Err(RangeNotSatisfiable);
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }
+        }
+        (start, end) => {
+            let start = match start.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return Ok(None),
+            };
+            let end = match end {
+                "" => len.saturating_sub(1),
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                    _ => return Ok(None),
+                },
+            };
+            if start >= len {
+                return Err(RangeNotSatisfiable);
+            }
+            ByteRange { start, end }
+        }
+    };
+
+    Ok(Some(range))
+}
+
+/// Formats a timestamp as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// The content and validators of a resource being served
+struct Resource {
+    body: Body,
+    len: u64,
+    mime: String,
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+enum Body {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+impl Resource {
+    fn from_path(path: &std::path::Path, mime: Option<String>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let last_modified: Option<DateTime<Utc>> = metadata.modified().ok().map(|m| m.into());
+
+        let mime = match mime {
+            Some(mime) => mime,
+            None => FileFormat::from_file(path)?.media_type().to_string(),
+        };
+
+        Ok(Resource {
+            body: Body::File(file),
+            len: metadata.len(),
+            mime,
+            etag: format!(
+                "\"{:x}-{:x}\"",
+                metadata.len(),
+                last_modified.map(|m| m.timestamp()).unwrap_or_default()
+            ),
+            last_modified,
+        })
+    }
+
+    /// Checks the conditional request headers, returning `true` if the
+    /// client's cached copy is still valid
+    fn not_modified(&self, request: &Request) -> bool {
+        if let Some(if_none_match) = request_header(request, "If-None-Match") {
+            return if_none_match
+                .split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim().trim_start_matches("W/") == self.etag);
+        }
+
+        match (request_header(request, "If-Modified-Since"), self.last_modified) {
+            (Some(since), Some(modified)) => match DateTime::parse_from_rfc2822(since) {
+                Ok(since) => modified.timestamp() <= since.timestamp(),
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Checks the `If-Range` header, returning `true` if a partial response
+    /// should be sent for the requested range
+    fn range_valid(&self, request: &Request) -> bool {
+        let if_range = match request_header(request, "If-Range") {
+            Some(if_range) => if_range.trim(),
+            None => return true,
+        };
+
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+
+        match (DateTime::parse_from_rfc2822(if_range), self.last_modified) {
+            (Ok(date), Some(modified)) => date.timestamp() == modified.timestamp(),
+            _ => false,
+        }
+    }
+
+    fn respond(self, request: Request) -> io::Result<()> {
+        let mut headers = vec![
+            header("Accept-Ranges", "bytes"),
+            header("ETag", &self.etag),
+            header("Content-Type", &self.mime),
+        ];
+        if let Some(modified) = &self.last_modified {
+            headers.push(header("Last-Modified", &http_date(modified)))
+        }
+
+        if self.not_modified(&request) {
+            let response = Response::new(StatusCode(304), headers, io::empty(), Some(0), None);
+            return request.respond(response);
+        }
+
+        let range = match request_header(&request, "Range") {
+            Some(range) if *request.method() == Method::Get && self.range_valid(&request) => {
+                parse_range(range, self.len)
+            }
+            _ => Ok(None),
+        };
+
+        let range = match range {
+            Ok(range) => range,
+            Err(RangeNotSatisfiable) => {
+                headers.push(header("Content-Range", &format!("bytes */{}", self.len)));
+                let response = Response::new(StatusCode(416), headers, io::empty(), Some(0), None);
+                return request.respond(response);
+            }
+        };
+
+        let (status, start, len) = match range {
+            Some(range) => {
+                headers.push(header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", range.start, range.end, self.len),
+                ));
+                (StatusCode(206), range.start, range.len())
+            }
+            None => (StatusCode(200), 0, self.len),
+        };
+
+        let reader: Box<dyn Read + Send> = match self.body {
+            Body::File(mut file) => {
+                file.seek(SeekFrom::Start(start))?;
+                Box::new(file.take(len))
+            }
+            Body::Memory(data) => {
+                let mut cursor = Cursor::new(data);
+                cursor.set_position(start);
+                Box::new(cursor.take(len))
+            }
+        };
+
+        let response = Response::new(status, headers, reader, Some(len as usize), None);
+        request.respond(response)
+    }
+}
+
+fn request_header<'a>(request: &'a Request, field: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.as_str())
+}
+
+/// Serves the songs and album art of a [MusicLibrary] over HTTP
+pub struct LibraryServer {
+    library: Arc<RwLock<MusicLibrary>>,
+    server: Arc<Server>,
+}
+
+impl LibraryServer {
+    /// Bind a new server to `address`. Requests are not handled until
+    /// [LibraryServer::start] is called.
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        library: Arc<RwLock<MusicLibrary>>,
+    ) -> Result<Self, ServerError> {
+        let server = match Server::http(address) {
+            Ok(server) => server,
+            Err(error) => return Err(ServerError::Bind(error.to_string())),
+        };
+
+        Ok(LibraryServer {
+            library,
+            server: Arc::new(server),
+        })
+    }
+
+    /// The address the server is listening on
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Returns the URL a song can be streamed from
+    pub fn track_url(&self, uuid: &Uuid) -> Option<String> {
+        self.address().map(|addr| format!("http://{}/track/{}", addr, uuid))
+    }
+
+    /// Returns the URL an entry of a song's album art can be fetched from
+    pub fn art_url(&self, uuid: &Uuid, index: usize) -> Option<String> {
+        self.address().map(|addr| format!("http://{}/art/{}/{}", addr, uuid, index))
+    }
+
+    /// Start handling requests on a new thread, each request is handled
+    /// on its own thread so that long running streams don't block others
+    pub fn start(&self) -> JoinHandle<()> {
+        let server = Arc::clone(&self.server);
+        let library = Arc::clone(&self.library);
+
+        spawn(move || {
+            for request in server.incoming_requests() {
+                let library = Arc::clone(&library);
+                spawn(move || {
+                    if let Err(error) = handle_request(request, library) {
+                        println!("HTTP: failed to respond: {}", error);
+                    }
+                });
+            }
+        })
+    }
+
+    /// Stop handling requests, causing the thread from
+    /// [LibraryServer::start] to exit
+    pub fn stop(&self) {
+        self.server.unblock();
+    }
+}
+
+fn handle_request(request: Request, library: Arc<RwLock<MusicLibrary>>) -> io::Result<()> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return request.respond(Response::empty(StatusCode(405)));
+    }
+
+    let resource = match find_resource(request.url(), &library) {
+        Some(resource) => resource,
+        None => return request.respond(Response::empty(StatusCode(404))),
+    };
+
+    resource.respond(request)
+}
+
+/// Finds the resource at a request URL within the library
+fn find_resource(url: &str, library: &Arc<RwLock<MusicLibrary>>) -> Option<Resource> {
+    let path = url.split('?').next().unwrap_or_default();
+    let mut segments = path.trim_matches('/').split('/');
+
+    let kind = segments.next()?;
+    let uuid = Uuid::parse_str(segments.next()?).ok()?;
+
+    let library = library.read().unwrap();
+    let (song, _) = library.query_uuid(&uuid)?;
+
+    match (kind, segments.next()) {
+        ("track", None) => {
+            // CUE tracks are served as the whole underlying file, the
+            // client is responsible for seeking to the track's start
+            let path = match song.primary_uri().ok()?.0 {
+                URI::Local(path) => path.clone(),
+                URI::Cue { location, .. } => location.clone(),
+                URI::Remote(_, _) => return None,
+            };
+            let mime = song.format.map(|format| format.media_type().to_string());
+
+            Resource::from_path(&path, mime).ok()
+        }
+        ("art", Some(index)) => {
+            let index = index.parse::<usize>().ok()?;
+            let (data, mime) = song.read_art(index).ok()??;
+
+            Some(Resource {
+                len: data.len() as u64,
+                etag: format!("\"{}-{}-{:x}\"", song.uuid, index, data.len()),
+                last_modified: song.date_modified,
+                body: Body::Memory(data),
+                mime,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_range, ByteRange, RangeNotSatisfiable};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            Ok(Some(ByteRange { start: 0, end: 499 }))
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            Ok(Some(ByteRange { start: 500, end: 999 }))
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            Ok(Some(ByteRange { start: 800, end: 999 }))
+        );
+        assert_eq!(
+            parse_range("bytes=900-5000", 1000),
+            Ok(Some(ByteRange { start: 900, end: 999 }))
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=0-1,5-10", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=50-10", 1000), Ok(None));
+    }
+}
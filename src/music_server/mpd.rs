@@ -0,0 +1,677 @@
+//! A server for a subset of the MPD protocol, so that MPD clients such as
+//! ncmpcpp and MALP can control the [Controller]
+//!
+//! The commands understood are `status`, `currentsong`, `playlistinfo`,
+//! `play`, `playid`, `pause`, `stop`, `next`, `seek`, `seekid`, `seekcur`,
+//! `setvol`, `search`, `find`, `add`, `stats`, `idle` and the handful of
+//! commands clients send when they connect. Command lists work as usual.
+//!
+//! The queue doesn't give its items IDs, so a song's position in the queue
+//! is also its ID. Songs are named by the path of their file.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{select, unbounded, Receiver};
+use kushi::QueueItemType;
+
+use super::http::ServerError;
+use crate::config::ConfigMpd;
+use crate::music_controller::controller::{Controller, PlayerLocation};
+use crate::music_controller::events::ControllerEvent;
+use crate::music_controller::modes::RepeatMode;
+use crate::music_player::player::Player;
+use crate::music_storage::library::{Song, Tag, URI};
+
+/// The version of the protocol announced to clients, which is old enough
+/// that they search with `TYPE VALUE` pairs rather than filter expressions
+pub const PROTOCOL_VERSION: &str = "0.19.0";
+
+/// Tags sent for each song, as MPD names them
+const TAG_TYPES: [(&str, Tag); 8] = [
+    ("Artist", Tag::Artist),
+    ("Album", Tag::Album),
+    ("AlbumArtist", Tag::AlbumArtist),
+    ("Title", Tag::Title),
+    ("Track", Tag::Track),
+    ("Genre", Tag::Genre),
+    ("Date", Tag::Date),
+    ("Disc", Tag::Disk),
+];
+
+const COMMANDS: &[&str] = &[
+    "add", "close", "commands", "currentsong", "find", "idle", "next", "noidle", "notcommands", "outputs",
+    "password", "pause", "ping", "play", "playid", "playlistid", "playlistinfo", "search", "seek", "seekcur",
+    "seekid", "setvol", "stats", "status", "stop", "tagtypes",
+];
+
+/// Commands which can be sent before the password
+const OPEN_COMMANDS: &[&str] = &["close", "commands", "notcommands", "password", "ping"];
+
+/// The error codes of `ACK` responses
+const ACK_ARG: u8 = 2;
+const ACK_PASSWORD: u8 = 3;
+const ACK_PERMISSION: u8 = 4;
+const ACK_UNKNOWN: u8 = 5;
+const ACK_NO_EXIST: u8 = 50;
+
+/// Serves a [Controller] to MPD clients
+pub struct MpdServer<P: Player + Send + Sync + 'static> {
+    controller: Arc<Mutex<Controller<P>>>,
+    listener: Arc<TcpListener>,
+    password: Option<Arc<String>>,
+    running: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + Sync + 'static> MpdServer<P> {
+    /// Bind the server to the address in `config`. Clients need to send
+    /// `password` first if one is given. Connections are not accepted
+    /// until [MpdServer::start] is called.
+    pub fn bind(
+        config: &ConfigMpd,
+        controller: Arc<Mutex<Controller<P>>>,
+        password: Option<String>,
+    ) -> Result<Self, ServerError> {
+        let listener = TcpListener::bind(&config.bind).map_err(|error| ServerError::Bind(error.to_string()))?;
+        Ok(MpdServer {
+            controller,
+            listener: Arc::new(listener),
+            password: password.map(Arc::new),
+            running: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// The address the server is listening on
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    /// Start accepting clients on a new thread, each client is served on
+    /// its own thread
+    pub fn start(&self) -> JoinHandle<()> {
+        let listener = Arc::clone(&self.listener);
+        let controller = Arc::clone(&self.controller);
+        let password = self.password.clone();
+        let running = Arc::clone(&self.running);
+
+        spawn(move || {
+            for stream in listener.incoming() {
+                if !running.load(Ordering::Relaxed) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        println!("MPD: failed to accept a client: {}", error);
+                        continue;
+                    }
+                };
+                let controller = Arc::clone(&controller);
+                let password = password.clone();
+                spawn(move || {
+                    if let Err(error) = serve_client(stream, &controller, password.as_deref().map(|p| p.as_str())) {
+                        println!("MPD: client failed: {}", error);
+                    }
+                });
+            }
+        })
+    }
+
+    /// Stop accepting clients, causing the thread from [MpdServer::start]
+    /// to exit. Clients already connected stay connected.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        // Wakes the thread up from waiting for the next client
+        if let Some(address) = self.address() {
+            let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        }
+    }
+}
+
+/// An `ACK` response, which stops a command list
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ack {
+    code: u8,
+    message: String,
+}
+
+impl Ack {
+    fn new<S: Into<String>>(code: u8, message: S) -> Self {
+        Ack { code, message: message.into() }
+    }
+
+    fn format(&self, index: usize, command: &str) -> String {
+        format!("ACK [{}@{}] {{{}}} {}\n", self.code, index, command, self.message)
+    }
+}
+
+/// Split a command line into the command and its arguments, which can be
+/// quoted with backslash escapes. `None` if a quote isn't closed.
+fn parse_line(line: &str) -> Option<(String, Vec<String>)> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&next) = chars.peek() {
+        if next.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if next == '"' {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '\\' => word.push(chars.next()?),
+                    '"' => break,
+                    c => word.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+    if words.is_empty() {
+        return Some((String::new(), words));
+    }
+    let command = words.remove(0);
+    Some((command, words))
+}
+
+/// What clients know a song by, the path of its file
+fn song_file(song: &Song) -> String {
+    match song.location.first() {
+        Some(URI::Local(path)) => path.display().to_string(),
+        // How MPD names the tracks of cue sheets
+        Some(URI::Cue { location, index, .. }) => format!("{}/track{:04}", location.display(), index + 1),
+        Some(URI::Remote(_, url)) => url.clone(),
+        None => song.uuid.to_string(),
+    }
+}
+
+/// The lines describing a song, with its position in the queue if it's
+/// in it
+fn song_entry(song: &Song, position: Option<usize>) -> String {
+    let mut entry = format!("file: {}\n", song_file(song));
+    for (name, tag) in TAG_TYPES.iter() {
+        if let Some(value) = song.get_tag(tag) {
+            entry.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    entry.push_str(&format!(
+        "Time: {}\nduration: {:.3}\n",
+        song.duration.as_secs(),
+        song.duration.as_secs_f64()
+    ));
+    if let Some(position) = position {
+        entry.push_str(&format!("Pos: {0}\nId: {0}\n", position));
+    }
+    entry
+}
+
+/// Whether a song matches every `(type, value)` filter of `search`, or
+/// `find` when `exact`
+fn song_matches(song: &Song, filters: &[(String, String)], exact: bool) -> bool {
+    let matches = |value: &str, wanted: &str| match exact {
+        true => value == wanted,
+        false => value.to_lowercase().contains(&wanted.to_lowercase()),
+    };
+    filters.iter().all(|(kind, wanted)| {
+        let tag = |name: &str| {
+            TAG_TYPES
+                .iter()
+                .find(|(tag_name, _)| tag_name.eq_ignore_ascii_case(name))
+                .and_then(|(_, tag)| song.get_tag(tag))
+                .is_some_and(|value| matches(value, wanted))
+        };
+        match kind.to_lowercase().as_str() {
+            "file" => matches(&song_file(song), wanted),
+            "any" => matches(&song_file(song), wanted) || TAG_TYPES.iter().any(|(name, _)| tag(name)),
+            name => tag(name),
+        }
+    })
+}
+
+/// A time given to a seek, which is relative when it starts with a sign
+fn parse_seek(time: &str, current: Duration) -> Option<Duration> {
+    let seconds: f64 = time.parse().ok()?;
+    let target = match time.starts_with(['+', '-']) {
+        true => current.as_secs_f64() + seconds,
+        false => seconds,
+    };
+    Duration::try_from_secs_f64(target.max(0.0)).ok()
+}
+
+fn parse_index(arg: Option<&String>) -> Result<usize, Ack> {
+    let arg = arg.ok_or_else(|| Ack::new(ACK_ARG, "wrong number of arguments"))?;
+    arg.parse().map_err(|_| Ack::new(ACK_ARG, format!("integer expected: {}", arg)))
+}
+
+/// The subsystem of `idle` an event changes
+fn subsystem(event: &ControllerEvent) -> Option<&'static str> {
+    match event {
+        ControllerEvent::TrackChanged { .. }
+        | ControllerEvent::Paused
+        | ControllerEvent::Resumed
+        | ControllerEvent::Stopped => Some("player"),
+        ControllerEvent::QueueChanged(_) => Some("playlist"),
+        ControllerEvent::LibraryChanged => Some("database"),
+        ControllerEvent::VolumeChanged(_) => Some("mixer"),
+        _ => None,
+    }
+}
+
+/// The state of a connected client
+struct Client<'a, P: Player + Send + Sync + 'static> {
+    controller: &'a Mutex<Controller<P>>,
+    events: Receiver<ControllerEvent>,
+    /// Subsystems which changed since the client last went idle
+    changed: BTreeSet<&'static str>,
+    /// Goes up every time the queue changes
+    playlist_version: u32,
+    password: Option<&'a str>,
+    authorized: bool,
+}
+
+impl<P: Player + Send + Sync + 'static> Client<'_, P> {
+    fn note(&mut self, event: &ControllerEvent) {
+        if let Some(subsystem) = subsystem(event) {
+            if subsystem == "playlist" {
+                self.playlist_version += 1;
+            }
+            self.changed.insert(subsystem);
+        }
+    }
+
+    fn catch_up(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            self.note(&event);
+        }
+    }
+
+    /// The songs in the queue, with the songs of albums in it laid out
+    fn queue(&self, controller: &Controller<P>) -> Vec<Song> {
+        let queue = controller.queue.read().unwrap();
+        let library = controller.library.read().unwrap();
+        queue
+            .items
+            .iter()
+            .flat_map(|item| match &item.item {
+                QueueItemType::Single(song) => vec![song.song.clone()],
+                QueueItemType::Multi(album) => album
+                    .album
+                    .discs()
+                    .values()
+                    .flat_map(|tracks| tracks.iter())
+                    .filter_map(|(_, uuid)| library.query_uuid(uuid).map(|(song, _)| song.clone()))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Where the playing song is in `queue`
+    fn current(&self, controller: &Controller<P>, queue: &[Song]) -> Option<usize> {
+        let uri = controller.player.lock().unwrap().source().clone()?;
+        let uuid = controller.library.read().unwrap().query_uri(&uri).map(|(song, _)| song.uuid)?;
+        queue.iter().position(|song| song.uuid == uuid)
+    }
+
+    fn play_position(&mut self, controller: &mut Controller<P>, position: usize) -> Result<(), Ack> {
+        let queue = self.queue(controller);
+        let song = queue.get(position).ok_or_else(|| Ack::new(ACK_ARG, "Bad song index"))?;
+        controller.play_song(&song.uuid).map_err(|error| Ack::new(ACK_NO_EXIST, error.to_string()))
+    }
+
+    fn seek(&mut self, controller: &mut Controller<P>, position: Option<usize>, time: Option<&String>) -> Result<(), Ack> {
+        let time = time.ok_or_else(|| Ack::new(ACK_ARG, "wrong number of arguments"))?;
+        if let Some(position) = position {
+            let queue = self.queue(controller);
+            if self.current(controller, &queue) != Some(position) {
+                self.play_position(controller, position)?;
+            }
+        }
+        let current = controller.position().unwrap_or_default();
+        let target = parse_seek(time, current).ok_or_else(|| Ack::new(ACK_ARG, format!("float expected: {}", time)))?;
+        controller.seek_to(target).map_err(|error| Ack::new(ACK_NO_EXIST, error.to_string()))
+    }
+
+    /// Run a command, returning its response without the final `OK`
+    fn execute(&mut self, command: &str, args: &[String]) -> Result<String, Ack> {
+        if !self.authorized && !OPEN_COMMANDS.contains(&command) {
+            return Err(Ack::new(ACK_PERMISSION, format!("you don't have permission for \"{}\"", command)));
+        }
+        let failed = |error: &dyn std::fmt::Display| Ack::new(ACK_NO_EXIST, error.to_string());
+
+        let controller = self.controller;
+        let mut guard = controller.lock().unwrap();
+        let controller = &mut *guard;
+        let mut reply = String::new();
+        match command {
+            "ping" | "noidle" => (),
+            "close" => (),
+            "password" => match (self.password, args.first()) {
+                (Some(password), Some(given)) if password == given => self.authorized = true,
+                (None, Some(_)) => self.authorized = true,
+                _ => return Err(Ack::new(ACK_PASSWORD, "incorrect password")),
+            },
+            "commands" => COMMANDS.iter().for_each(|name| reply.push_str(&format!("command: {}\n", name))),
+            "notcommands" | "urlhandlers" | "decoders" => (),
+            "tagtypes" => TAG_TYPES.iter().for_each(|(name, _)| reply.push_str(&format!("tagtype: {}\n", name))),
+            "outputs" => reply.push_str("outputid: 0\noutputname: dmp-core\noutputenabled: 1\n"),
+            "status" => {
+                let queue = self.queue(controller);
+                let current = self.current(controller, &queue);
+                let modes = controller.modes();
+                let volume = controller.player.lock().unwrap().volume();
+                let state = match (current, controller.is_paused()) {
+                    (None, _) => "stop",
+                    (Some(_), true) => "pause",
+                    (Some(_), false) => "play",
+                };
+                reply.push_str(&format!(
+                    "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: 0\nplaylist: {}\nplaylistlength: {}\nstate: {}\n",
+                    (volume * 100.0).round(),
+                    (modes.repeat != RepeatMode::Off) as u8,
+                    modes.shuffle as u8,
+                    (modes.repeat == RepeatMode::One) as u8,
+                    self.playlist_version,
+                    queue.len(),
+                    state,
+                ));
+                if let Some(current) = current {
+                    let elapsed = controller.position().unwrap_or_default();
+                    let duration = queue[current].duration;
+                    reply.push_str(&format!(
+                        "song: {0}\nsongid: {0}\ntime: {1}:{2}\nelapsed: {3:.3}\nduration: {4:.3}\n",
+                        current,
+                        elapsed.as_secs(),
+                        duration.as_secs(),
+                        elapsed.as_secs_f64(),
+                        duration.as_secs_f64(),
+                    ));
+                }
+            }
+            "currentsong" => {
+                let queue = self.queue(controller);
+                if let Some(current) = self.current(controller, &queue) {
+                    reply.push_str(&song_entry(&queue[current], Some(current)));
+                }
+            }
+            "playlistinfo" | "playlistid" => {
+                let queue = self.queue(controller);
+                let range = match args.first() {
+                    Some(range) => match range.split_once(':') {
+                        Some((start, end)) => {
+                            let start = parse_index(Some(&start.to_string()))?;
+                            let end = if end.is_empty() { queue.len() } else { parse_index(Some(&end.to_string()))? };
+                            start..end.min(queue.len())
+                        }
+                        None => {
+                            let position = parse_index(Some(range))?;
+                            if position >= queue.len() {
+                                return Err(Ack::new(ACK_ARG, "Bad song index"));
+                            }
+                            position..position + 1
+                        }
+                    },
+                    None => 0..queue.len(),
+                };
+                for position in range {
+                    reply.push_str(&song_entry(&queue[position], Some(position)));
+                }
+            }
+            "play" | "playid" => match args.first() {
+                Some(_) => {
+                    let position = parse_index(args.first())?;
+                    self.play_position(controller, position)?;
+                }
+                None => controller.play().map_err(|e| failed(&e))?,
+            },
+            "pause" => {
+                let pause = match args.first().map(|arg| arg.as_str()) {
+                    Some("1") => true,
+                    Some("0") => false,
+                    Some(arg) => return Err(Ack::new(ACK_ARG, format!("boolean (0/1) expected: {}", arg))),
+                    None => !controller.is_paused(),
+                };
+                match pause {
+                    true => controller.pause(),
+                    false => controller.play(),
+                }
+                .map_err(|e| failed(&e))?;
+            }
+            "stop" => controller.stop().map_err(|e| failed(&e))?,
+            "next" => controller.q_next().map_err(|e| failed(&e))?,
+            "seek" | "seekid" => {
+                let position = parse_index(args.first())?;
+                self.seek(controller, Some(position), args.get(1))?;
+                self.changed.insert("player");
+            }
+            "seekcur" => {
+                self.seek(controller, None, args.first())?;
+                self.changed.insert("player");
+            }
+            "setvol" => {
+                let volume = parse_index(args.first())?;
+                controller.set_volume((volume.min(100) as f64) / 100.0);
+            }
+            "search" | "find" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(Ack::new(ACK_ARG, "incorrect arguments"));
+                }
+                let filters: Vec<(String, String)> = args.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+                let library = controller.library.read().unwrap();
+                for song in library.library.iter().filter(|song| song_matches(song, &filters, command == "find")) {
+                    reply.push_str(&song_entry(song, None));
+                }
+            }
+            "add" => {
+                let file = args.first().ok_or_else(|| Ack::new(ACK_ARG, "wrong number of arguments"))?;
+                let uuid = controller
+                    .library
+                    .read()
+                    .unwrap()
+                    .library
+                    .iter()
+                    .find(|song| &song_file(song) == file)
+                    .map(|song| song.uuid)
+                    .ok_or_else(|| Ack::new(ACK_NO_EXIST, "No such song"))?;
+                controller.q_add(&uuid, PlayerLocation::Library, true);
+            }
+            "stats" => {
+                let library = controller.library.read().unwrap();
+                let distinct = |tag: Tag| {
+                    library.library.iter().filter_map(|song| song.get_tag(&tag)).collect::<BTreeSet<_>>().len()
+                };
+                let playtime: Duration = library.library.iter().map(|song| song.duration).sum();
+                reply.push_str(&format!(
+                    "artists: {}\nalbums: {}\nsongs: {}\ndb_playtime: {}\n",
+                    distinct(Tag::Artist),
+                    distinct(Tag::Album),
+                    library.library.len(),
+                    playtime.as_secs()
+                ));
+            }
+            _ => return Err(Ack::new(ACK_UNKNOWN, format!("unknown command \"{}\"", command))),
+        }
+        Ok(reply)
+    }
+
+    /// Changed subsystems the client is waiting on, taken out of those
+    /// noted. Every subsystem is waited on if the client didn't name any.
+    fn take_changed(&mut self, wanted: &[String]) -> Vec<&'static str> {
+        let taken: Vec<&'static str> = self
+            .changed
+            .iter()
+            .copied()
+            .filter(|subsystem| wanted.is_empty() || wanted.iter().any(|wanted| wanted == subsystem))
+            .collect();
+        for subsystem in &taken {
+            self.changed.remove(subsystem);
+        }
+        taken
+    }
+}
+
+fn serve_client<P: Player + Send + Sync + 'static>(
+    stream: TcpStream,
+    controller: &Mutex<Controller<P>>,
+    password: Option<&str>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    // Lines are read on their own thread so that `noidle` can be seen
+    // while waiting for something to change
+    let (line_tx, lines) = unbounded();
+    let reader = BufReader::new(stream);
+    spawn(move || {
+        for line in reader.lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let events = controller.lock().unwrap().subscribe();
+    let mut client = Client {
+        controller,
+        events,
+        changed: BTreeSet::new(),
+        playlist_version: 0,
+        password,
+        authorized: password.is_none(),
+    };
+    writer.write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())?;
+
+    // The commands of a command list, and whether each is followed by `list_OK`
+    let mut list: Option<(bool, Vec<String>)> = None;
+    while let Ok(line) = lines.recv() {
+        client.catch_up();
+        let (command, args) = match parse_line(&line) {
+            Some(parsed) => parsed,
+            None => {
+                writer.write_all(Ack::new(ACK_ARG, "invalid quoting").format(0, "").as_bytes())?;
+                continue;
+            }
+        };
+
+        match (command.as_str(), &mut list) {
+            ("command_list_begin", None) => list = Some((false, Vec::new())),
+            ("command_list_ok_begin", None) => list = Some((true, Vec::new())),
+            ("command_list_end", Some(_)) => {
+                let (list_ok, commands) = list.take().unwrap();
+                let mut reply = String::new();
+                let mut failed = false;
+                for (index, line) in commands.iter().enumerate() {
+                    let (command, args) = parse_line(line).unwrap_or_default();
+                    match client.execute(&command, &args) {
+                        Ok(response) => {
+                            reply.push_str(&response);
+                            if list_ok {
+                                reply.push_str("list_OK\n");
+                            }
+                        }
+                        Err(ack) => {
+                            reply.push_str(&ack.format(index, &command));
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                if !failed {
+                    reply.push_str("OK\n");
+                }
+                writer.write_all(reply.as_bytes())?;
+            }
+            (_, Some((_, commands))) => commands.push(line),
+            ("close", None) => return Ok(()),
+            ("idle", None) => {
+                if !client.authorized {
+                    let ack = Ack::new(ACK_PERMISSION, "you don't have permission for \"idle\"");
+                    writer.write_all(ack.format(0, "idle").as_bytes())?;
+                    continue;
+                }
+                loop {
+                    let changed = client.take_changed(&args);
+                    if !changed.is_empty() {
+                        let mut reply: String = changed.iter().map(|subsystem| format!("changed: {}\n", subsystem)).collect();
+                        reply.push_str("OK\n");
+                        writer.write_all(reply.as_bytes())?;
+                        break;
+                    }
+                    select! {
+                        recv(client.events) -> event => match event {
+                            Ok(event) => client.note(&event),
+                            Err(_) => return Ok(()),
+                        },
+                        recv(lines) -> line => match line {
+                            Ok(line) if line.trim() == "noidle" => {
+                                writer.write_all(b"OK\n")?;
+                                break;
+                            }
+                            // Nothing but `noidle` can be sent while idle
+                            _ => return Ok(()),
+                        },
+                    }
+                }
+            }
+            (_, None) => {
+                let reply = match client.execute(&command, &args) {
+                    Ok(mut response) => {
+                        response.push_str("OK\n");
+                        response
+                    }
+                    Err(ack) => ack.format(0, &command),
+                };
+                writer.write_all(reply.as_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{parse_line, parse_seek, song_entry, song_matches, Ack, ACK_UNKNOWN};
+    use crate::music_storage::library::{test::test_song, Tag};
+
+    #[test]
+    fn mpd_commands() {
+        assert_eq!(
+            parse_line(r#"find artist "The \"Band\"" album Rust"#),
+            Some((
+                String::from("find"),
+                vec![String::from("artist"), String::from("The \"Band\""), String::from("album"), String::from("Rust")]
+            ))
+        );
+        assert_eq!(parse_line("status"), Some((String::from("status"), Vec::new())));
+        assert_eq!(parse_line(r#"search title "unclosed"#), None);
+        assert_eq!(Ack::new(ACK_UNKNOWN, "unknown command \"x\"").format(2, "x"), "ACK [5@2] {x} unknown command \"x\"\n");
+
+        let mut song = test_song("Rust in Peace", "Megadeth", Duration::from_millis(201_500));
+        song.tags.insert(Tag::Album, String::from("Rust in Peace"));
+        assert_eq!(
+            song_entry(&song, Some(3)),
+            "file: /music/Rust in Peace.flac\nArtist: Megadeth\nAlbum: Rust in Peace\nTitle: Rust in Peace\n\
+            Time: 201\nduration: 201.500\nPos: 3\nId: 3\n"
+        );
+
+        let filter = |kind: &str, value: &str| vec![(kind.to_string(), value.to_string())];
+        assert!(song_matches(&song, &filter("artist", "mega"), false));
+        assert!(!song_matches(&song, &filter("artist", "mega"), true));
+        assert!(song_matches(&song, &filter("Artist", "Megadeth"), true));
+        assert!(song_matches(&song, &filter("any", "peace.flac"), false));
+        assert!(!song_matches(&song, &filter("genre", "Thrash"), false));
+
+        assert_eq!(parse_seek("+10", Duration::from_secs(5)), Some(Duration::from_secs(15)));
+        assert_eq!(parse_seek("-10", Duration::from_secs(5)), Some(Duration::ZERO));
+        assert_eq!(parse_seek("42.5", Duration::ZERO), Some(Duration::from_millis(42_500)));
+    }
+}
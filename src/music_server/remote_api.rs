@@ -0,0 +1,533 @@
+//! An HTTP/JSON API for controlling the player from phones and scripts,
+//! built with the `remote-api` feature and turned on in
+//! [ConfigRemoteApi](crate::config::ConfigRemoteApi)
+//!
+//! Every request needs the token from [remote_token], either as an
+//! `Authorization: Bearer <token>` header or as `?token=<token>` so that
+//! artwork can be shown straight from a URL.
+//!
+//! Routes:
+//! - `GET /state` the whole [StateSnapshot](crate::music_controller::snapshot::StateSnapshot)
+//! - `GET /queue` the queue, and how much of it has been played
+//! - `GET /now-playing` what's playing, with the URL of its artwork
+//! - `GET /art/<uuid>` the album art of a song
+//! - `POST /play`, `POST /pause` and `POST /next`
+//! - `POST /seek` to `{"position": <seconds>}`
+//! - `POST /volume` to `{"volume": <0 to 1>}`
+//! - `POST /enqueue` the song `{"uuid": "<uuid>"}`
+//!
+//! Every [ControllerEvent](crate::music_controller::events::ControllerEvent)
+//! is pushed down as JSON to WebSocket clients of `/events` on the
+//! `events_bind` address, which needs the token too.
+
+// The errors and refusals of tungstenite are this large
+#![allow(clippy::result_large_err)]
+
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, TryRecvError};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tungstenite::handshake::{server, HandshakeError};
+use tungstenite::{http, Message, WebSocket};
+use uuid::Uuid;
+
+use super::http::ServerError;
+use crate::config::secrets::{SecretError, Secrets};
+use crate::config::ConfigRemoteApi;
+use crate::music_controller::controller::{Controller, PlayerLocation};
+use crate::music_controller::events::ControllerEvent;
+use crate::music_player::player::Player;
+
+/// Where the token is kept in the [Secrets]
+const TOKEN_SERVICE: &str = "remote-api";
+const TOKEN_KEY: &str = "token";
+
+/// How often WebSocket clients are pinged while they send nothing, to
+/// find the ones which have gone. A client which doesn't answer by the
+/// next ping is dropped.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long reading from a WebSocket client waits before going back to
+/// pushing events
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The token requests to the API need, which is made the first time
+/// it's asked for
+pub fn remote_token(secrets: &Secrets) -> Result<String, SecretError> {
+    if let Some(token) = secrets.get(TOKEN_SERVICE, TOKEN_KEY)? {
+        return Ok(token);
+    }
+    let token = Uuid::new_v4().simple().to_string();
+    secrets.set(TOKEN_SERVICE, TOKEN_KEY, &token)?;
+    Ok(token)
+}
+
+/// Serves the API of a [Controller] over HTTP
+pub struct RemoteApi<P: Player + Send + Sync + 'static> {
+    controller: Arc<Mutex<Controller<P>>>,
+    server: Arc<Server>,
+    events: Arc<TcpListener>,
+    token: Arc<String>,
+    running: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + Sync + 'static> RemoteApi<P> {
+    /// Bind the API and its events to the addresses in `config`. Requests
+    /// are not handled
+    /// until [RemoteApi::start] is called.
+    pub fn bind(
+        config: &ConfigRemoteApi,
+        controller: Arc<Mutex<Controller<P>>>,
+        token: String,
+    ) -> Result<Self, ServerError> {
+        let server = match Server::http(&config.bind) {
+            Ok(server) => server,
+            Err(error) => return Err(ServerError::Bind(error.to_string())),
+        };
+        let events = TcpListener::bind(&config.events_bind).map_err(|error| ServerError::Bind(error.to_string()))?;
+
+        Ok(RemoteApi {
+            controller,
+            server: Arc::new(server),
+            events: Arc::new(events),
+            token: Arc::new(token),
+            running: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// The address the API is listening on
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// The address the events WebSocket is listening on
+    pub fn events_address(&self) -> Option<SocketAddr> {
+        self.events.local_addr().ok()
+    }
+
+    /// Start handling requests on a new thread, each request and events
+    /// client is handled on its own thread so that sending artwork
+    /// doesn't block others
+    pub fn start(&self) -> JoinHandle<()> {
+        let server = Arc::clone(&self.server);
+        let listener = Arc::clone(&self.events);
+        let controller = Arc::clone(&self.controller);
+        let token = Arc::clone(&self.token);
+        let running = Arc::clone(&self.running);
+
+        spawn(move || {
+            let events = {
+                let controller = Arc::clone(&controller);
+                let token = Arc::clone(&token);
+                spawn(move || {
+                    for stream in listener.incoming() {
+                        if !running.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(error) => {
+                                println!("Remote API: failed to accept an events client: {}", error);
+                                continue;
+                            }
+                        };
+                        let controller = Arc::clone(&controller);
+                        let token = Arc::clone(&token);
+                        spawn(move || {
+                            if let Err(error) = serve_events(stream, &controller, &token) {
+                                println!("Remote API: events client failed: {}", error);
+                            }
+                        });
+                    }
+                })
+            };
+
+            for request in server.incoming_requests() {
+                let controller = Arc::clone(&controller);
+                let token = Arc::clone(&token);
+                spawn(move || {
+                    if let Err(error) = handle_request(request, &controller, &token) {
+                        println!("Remote API: failed to respond: {}", error);
+                    }
+                });
+            }
+            let _ = events.join();
+        })
+    }
+
+    /// Stop handling requests, causing the thread from
+    /// [RemoteApi::start] to exit. Events clients already connected stay
+    /// connected.
+    pub fn stop(&self) {
+        self.server.unblock();
+        self.running.store(false, Ordering::Relaxed);
+        // Wakes the events thread up from waiting for the next client
+        if let Some(address) = self.events_address() {
+            let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    State,
+    Queue,
+    NowPlaying,
+    Art(Uuid),
+    Play,
+    Pause,
+    Next,
+    Seek,
+    Volume,
+    Enqueue,
+}
+
+fn route(method: &Method, path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    Some(match (method, segments.as_slice()) {
+        (Method::Get, ["state"]) => Route::State,
+        (Method::Get, ["queue"]) => Route::Queue,
+        (Method::Get, ["now-playing"]) => Route::NowPlaying,
+        (Method::Get, ["art", uuid]) => Route::Art(Uuid::parse_str(uuid).ok()?),
+        (Method::Post, ["play"]) => Route::Play,
+        (Method::Post, ["pause"]) => Route::Pause,
+        (Method::Post, ["next"]) => Route::Next,
+        (Method::Post, ["seek"]) => Route::Seek,
+        (Method::Post, ["volume"]) => Route::Volume,
+        (Method::Post, ["enqueue"]) => Route::Enqueue,
+        _ => return None,
+    })
+}
+
+/// The token a request was sent with, from its `Authorization` header or
+/// its query
+fn presented_token(authorization: Option<&str>, query: Option<&str>) -> Option<String> {
+    if let Some(token) = authorization.and_then(|value| value.trim().strip_prefix("Bearer ")) {
+        return Some(token.trim().to_string());
+    }
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(|token| urlencoding::decode(token).ok())
+        .map(|token| token.into_owned())
+}
+
+/// Compare tokens in the same time wherever they differ, so the token
+/// can't be guessed a character at a time
+fn tokens_match(expected: &str, presented: &str) -> bool {
+    expected.len() == presented.len()
+        && expected
+            .bytes()
+            .zip(presented.bytes())
+            .fold(0, |differs, (a, b)| differs | (a ^ b))
+            == 0
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    /// In seconds
+    position: f64,
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    volume: f64,
+}
+
+#[derive(Deserialize)]
+struct EnqueueBody {
+    uuid: Uuid,
+}
+
+/// Answer the WebSocket handshake of a client, if it asked for the
+/// events with a valid token
+fn accept_events(stream: TcpStream, token: &str) -> Result<WebSocket<TcpStream>, tungstenite::Error> {
+    let check = |request: &server::Request, response: server::Response| {
+        let refuse = |status: u16, error: &str| {
+            let body = json!({ "error": error }).to_string();
+            Err(http::Response::builder().status(status).body(Some(body)).unwrap())
+        };
+        if request.uri().path().trim_end_matches('/') != "/events" {
+            return refuse(404, "there's nothing here");
+        }
+        let authorization = request
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok());
+        let authorized = presented_token(authorization, request.uri().query())
+            .is_some_and(|presented| tokens_match(token, &presented));
+        if !authorized {
+            return refuse(401, "a valid token is needed");
+        }
+        Ok(response)
+    };
+
+    let socket = tungstenite::accept_hdr(stream, check).map_err(|error| match error {
+        HandshakeError::Failure(error) => error,
+        // The stream blocks until the handshake is done
+        HandshakeError::Interrupted(_) => unreachable!("the handshake was interrupted"),
+    })?;
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(socket)
+}
+
+/// Push every event to a WebSocket client until it closes the socket,
+/// which ends with [tungstenite::Error::ConnectionClosed], stops
+/// answering pings, or falls too far behind
+fn push_events(mut socket: WebSocket<TcpStream>, events: Receiver<ControllerEvent>) -> Result<(), tungstenite::Error> {
+    let mut heard = Instant::now();
+    let mut pinged = false;
+    let mut closing = false;
+
+    loop {
+        while !closing {
+            match events.try_recv() {
+                Ok(event) => match serde_json::to_string(&event) {
+                    Ok(json) => socket.write(Message::text(json))?,
+                    Err(error) => println!("Remote API: could not send an event: {}", error),
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    socket.close(None)?;
+                    closing = true;
+                }
+            }
+        }
+
+        if heard.elapsed() >= PING_INTERVAL && !closing {
+            if pinged {
+                println!("Remote API: a WebSocket client stopped answering");
+                return Ok(());
+            }
+            socket.write(Message::Ping(Vec::new()))?;
+            pinged = true;
+            heard = Instant::now();
+        }
+        socket.flush()?;
+
+        // Pings are answered and closes are finished by the socket itself
+        match socket.read() {
+            Ok(_) => {
+                heard = Instant::now();
+                pinged = false;
+            }
+            Err(tungstenite::Error::Io(error))
+                if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Answer a client of the events WebSocket
+fn serve_events<P: Player + Send + Sync + 'static>(
+    stream: TcpStream,
+    controller: &Mutex<Controller<P>>,
+    token: &str,
+) -> Result<(), tungstenite::Error> {
+    let socket = accept_events(stream, token)?;
+    let events = controller.lock().unwrap().subscribe();
+    match push_events(socket, events) {
+        Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+        result => result,
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Browser frontends are served from elsewhere, so requests from any
+/// origin are allowed, they still need the token
+fn respond<R: Read>(request: Request, response: Response<R>) -> io::Result<()> {
+    request.respond(
+        response
+            .with_header(header("Access-Control-Allow-Origin", "*"))
+            .with_header(header("Access-Control-Allow-Headers", "Authorization, Content-Type")),
+    )
+}
+
+fn respond_json(request: Request, status: u16, body: &Value) -> io::Result<()> {
+    let response = Response::from_string(body.to_string())
+        .with_status_code(StatusCode(status))
+        .with_header(header("Content-Type", "application/json"));
+    respond(request, response)
+}
+
+fn handle_request<P: Player + Send + Sync + 'static>(
+    mut request: Request,
+    controller: &Mutex<Controller<P>>,
+    token: &str,
+) -> io::Result<()> {
+    if request.method() == &Method::Options {
+        return respond(request, Response::empty(StatusCode(204)));
+    }
+
+    let (path, query) = match request.url().split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (request.url().to_string(), None),
+    };
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.to_string());
+    let authorized = presented_token(authorization.as_deref(), query.as_deref())
+        .is_some_and(|presented| tokens_match(token, &presented));
+    if !authorized {
+        return respond_json(request, 401, &json!({ "error": "a valid token is needed" }));
+    }
+
+    let Some(route) = route(request.method(), &path) else {
+        return respond_json(request, 404, &json!({ "error": "there's nothing here" }));
+    };
+
+    if let Route::Art(uuid) = route {
+        let art = {
+            let controller = controller.lock().unwrap();
+            let library = controller.library.read().unwrap();
+            library.query_uuid(&uuid).and_then(|(song, _)| song.read_art(0).ok().flatten())
+        };
+        return match art {
+            Some((data, mime)) => {
+                let response = Response::from_data(data).with_header(header("Content-Type", &mime));
+                respond(request, response)
+            }
+            None => respond_json(request, 404, &json!({ "error": "the song has no art" })),
+        };
+    }
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    match handle_route(route, &body, controller) {
+        Ok(reply) => respond_json(request, 200, &reply),
+        Err((status, error)) => respond_json(request, status, &json!({ "error": error })),
+    }
+}
+
+/// What a request to `route` gets back, or the status and reason it failed
+fn handle_route<P: Player + Send + Sync + 'static>(
+    route: Route,
+    body: &str,
+    controller: &Mutex<Controller<P>>,
+) -> Result<Value, (u16, String)> {
+    fn parse<'a, T: Deserialize<'a>>(body: &'a str) -> Result<T, (u16, String)> {
+        serde_json::from_str(body).map_err(|error| (400, error.to_string()))
+    }
+    let failed = |error: &dyn std::fmt::Display| (500, error.to_string());
+
+    let mut controller = controller.lock().unwrap();
+    match route {
+        Route::State | Route::Queue | Route::NowPlaying => {
+            let state = controller.dump_state().map_err(|e| failed(&e))?;
+            Ok(match route {
+                Route::Queue => json!({ "queue": state.queue, "played": state.played }),
+                Route::NowPlaying => {
+                    let library = controller.library.read().unwrap();
+                    let art = state
+                        .now_playing
+                        .as_ref()
+                        .and_then(|playing| playing.uuid)
+                        .filter(|uuid| library.query_uuid(uuid).is_some_and(|(song, _)| !song.album_art.is_empty()))
+                        .map(|uuid| format!("/art/{}", uuid));
+                    json!({ "now_playing": state.now_playing, "art": art })
+                }
+                _ => serde_json::to_value(&state).map_err(|e| failed(&e))?,
+            })
+        }
+        Route::Play => controller.play().map(|_| json!({})).map_err(|e| failed(&e)),
+        Route::Pause => controller.pause().map(|_| json!({})).map_err(|e| failed(&e)),
+        Route::Next => controller.q_next().map(|_| json!({})).map_err(|e| failed(&e)),
+        Route::Seek => {
+            let SeekBody { position } = parse(body)?;
+            let position = Duration::try_from_secs_f64(position).map_err(|error| (400, error.to_string()))?;
+            controller.seek_to(position).map(|_| json!({})).map_err(|e| failed(&e))
+        }
+        Route::Volume => {
+            let VolumeBody { volume } = parse(body)?;
+            controller.set_volume(volume.clamp(0.0, 1.0));
+            Ok(json!({ "volume": controller.player.lock().unwrap().volume() }))
+        }
+        Route::Enqueue => {
+            let EnqueueBody { uuid } = parse(body)?;
+            if controller.library.read().unwrap().query_uuid(&uuid).is_none() {
+                return Err((404, format!("there's no song {}", uuid)));
+            }
+            controller.q_add(&uuid, PlayerLocation::Library, true);
+            Ok(json!({}))
+        }
+        Route::Art(_) => unreachable!("answered before the body is read"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::spawn;
+
+    use tiny_http::Method;
+    use tungstenite::handshake::HandshakeError;
+    use tungstenite::Message;
+    use uuid::Uuid;
+
+    use crate::music_controller::events::ControllerEvent;
+
+    use super::{accept_events, presented_token, push_events, route, tokens_match, Route};
+
+    #[test]
+    fn remote_api_routes() {
+        assert_eq!(route(&Method::Get, "/state"), Some(Route::State));
+        assert_eq!(route(&Method::Get, "/now-playing/"), Some(Route::NowPlaying));
+        assert_eq!(route(&Method::Post, "/seek"), Some(Route::Seek));
+        assert_eq!(route(&Method::Get, "/seek"), None);
+        assert_eq!(route(&Method::Post, "/state"), None);
+        let uuid = Uuid::new_v4();
+        assert_eq!(route(&Method::Get, &format!("/art/{}", uuid)), Some(Route::Art(uuid)));
+        assert_eq!(route(&Method::Get, "/art/not-a-uuid"), None);
+
+        assert_eq!(presented_token(Some("Bearer abc123"), None).as_deref(), Some("abc123"));
+        assert_eq!(presented_token(None, Some("size=300&token=a%2Bb")).as_deref(), Some("a+b"));
+        assert_eq!(presented_token(Some("Basic dXNlcg=="), None), None);
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc"));
+    }
+
+    #[test]
+    fn websocket_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, events) = crossbeam_channel::unbounded();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            assert!(accept_events(stream, "abc123").is_err());
+            let (stream, _) = listener.accept().unwrap();
+            push_events(accept_events(stream, "abc123").unwrap(), events)
+        });
+
+        let refused = tungstenite::client(format!("ws://{}/events", address), TcpStream::connect(address).unwrap());
+        assert!(matches!(refused, Err(HandshakeError::Failure(tungstenite::Error::Http(response))) if response.status() == 401));
+
+        let (mut client, _) = tungstenite::client(
+            format!("ws://{}/events?token=abc123", address),
+            TcpStream::connect(address).unwrap(),
+        )
+        .unwrap();
+        sender.send(ControllerEvent::VolumeChanged(0.5)).unwrap();
+        assert_eq!(
+            client.read().unwrap(),
+            Message::text(r#"{"event":"VolumeChanged","data":0.5}"#)
+        );
+
+        // The server finishes the close, and stops pushing events
+        client.close(None).unwrap();
+        while client.read().is_ok() {}
+        assert!(matches!(server.join().unwrap(), Err(tungstenite::Error::ConnectionClosed)));
+    }
+}
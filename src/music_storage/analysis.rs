@@ -0,0 +1,360 @@
+//! Working out the tempo and musical key of songs from their audio, for
+//! songs whose tags don't have them
+//!
+//! The tempo is found from the autocorrelation of how much the spectrum
+//! changes over time, and the key by comparing the strength of each pitch
+//! class with the Krumhansl-Kessler key profiles
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::decode::{decode_file, DecodeFormat};
+use super::library::{MusicLibrary, Tag, URI};
+
+/// The rate songs are decoded at for analysis, which keeps every
+/// frequency needed to find the key
+const ANALYSIS_RATE: u32 = 11025;
+
+/// The step between the frames used to find the tempo, about 12ms
+const TEMPO_HOP: usize = 128;
+const TEMPO_FRAME: usize = 1024;
+
+/// The frames used to find the key, long enough to tell low notes apart
+const KEY_FRAME: usize = 4096;
+
+/// The slowest and fastest tempo which is looked for
+const TEMPO_RANGE: (f64, f64) = (60.0, 200.0);
+
+/// The tempo which is the most likely, used to choose between a tempo and
+/// double or half of it
+const TYPICAL_TEMPO: f64 = 120.0;
+
+const PITCH_CLASSES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// How well each note fits a major or minor key, starting from its tonic
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// A musical key, written like `F#` or `Am`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MusicalKey {
+    /// The pitch class of the tonic, where `0` is C
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+impl MusicalKey {
+    /// The key in Camelot notation, such as `8A` for A minor, where keys
+    /// next to each other mix well
+    pub fn camelot(&self) -> String {
+        // Minor keys share a number with their relative major, and the
+        // numbers go round in fifths with C major at 8
+        let major = if self.minor { (self.tonic + 3) % 12 } else { self.tonic } as u32;
+        let number = (major * 7 % 12 + 7) % 12 + 1;
+        format!("{}{}", number, if self.minor { 'A' } else { 'B' })
+    }
+}
+
+impl fmt::Display for MusicalKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PITCH_CLASSES[self.tonic as usize % 12], if self.minor { "m" } else { "" })
+    }
+}
+
+impl FromStr for MusicalKey {
+    type Err = String;
+
+    /// Parse keys such as `Am`, `F#`, `Bb minor` or `Db`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut chars = s.chars();
+        let letter = chars.next().ok_or("the key is empty")?.to_ascii_uppercase();
+        let natural = ["C", "D", "E", "F", "G", "A", "B"]
+            .iter()
+            .position(|name| name.starts_with(letter))
+            .ok_or_else(|| format!("{} is not a key", s))?;
+        let mut tonic: i32 = [0, 2, 4, 5, 7, 9, 11][natural];
+
+        let rest = chars.as_str();
+        let rest = match rest.chars().next() {
+            Some('#') | Some('♯') => {
+                tonic += 1;
+                &rest[rest.chars().next().unwrap().len_utf8()..]
+            }
+            Some('b') | Some('♭') => {
+                tonic -= 1;
+                &rest[rest.chars().next().unwrap().len_utf8()..]
+            }
+            _ => rest,
+        };
+        let rest = rest.trim().to_lowercase();
+        let minor = match rest.as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => return Err(format!("{} is not a key", s)),
+        };
+        Ok(MusicalKey {
+            tonic: tonic.rem_euclid(12) as u8,
+            minor,
+        })
+    }
+}
+
+/// An in-place radix 2 FFT, the length of `real` and `imag` must be a
+/// power of two
+fn fft(real: &mut [f64], imag: &mut [f64]) {
+    let n = real.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            real.swap(i, j);
+            imag.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let (re, im) = (real[b] * cos - imag[b] * sin, real[b] * sin + imag[b] * cos);
+                real[b] = real[a] - re;
+                imag[b] = imag[a] - im;
+                real[a] += re;
+                imag[a] += im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// The magnitudes of the spectrum of every frame of `samples`
+fn spectrogram(samples: &[f32], frame: usize, hop: usize) -> Vec<Vec<f64>> {
+    let window: Vec<f64> = (0..frame)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / frame as f64).cos())
+        .collect();
+    let starts: Vec<usize> = (0..samples.len().saturating_sub(frame)).step_by(hop).collect();
+    starts
+        .par_iter()
+        .map(|start| {
+            let mut real: Vec<f64> = samples[*start..start + frame]
+                .iter()
+                .zip(&window)
+                .map(|(sample, window)| *sample as f64 * window)
+                .collect();
+            let mut imag = vec![0.0; frame];
+            fft(&mut real, &mut imag);
+            (0..frame / 2).map(|i| real[i].hypot(imag[i])).collect()
+        })
+        .collect()
+}
+
+/// Estimate the tempo of mono samples in beats per minute, `None` if
+/// there are no beats to find
+pub fn estimate_bpm(samples: &[f32], rate: u32) -> Option<f64> {
+    // How much louder the spectrum gets from each frame to the next
+    let spectrogram = spectrogram(samples, TEMPO_FRAME, TEMPO_HOP);
+    let mut onsets: Vec<f64> = spectrogram
+        .windows(2)
+        .map(|pair| {
+            pair[1]
+                .iter()
+                .zip(&pair[0])
+                .map(|(now, before)| ((1.0 + now).ln() - (1.0 + before).ln()).max(0.0))
+                .sum()
+        })
+        .collect();
+    let mean = onsets.iter().sum::<f64>() / onsets.len().max(1) as f64;
+    onsets.iter_mut().for_each(|onset| *onset -= mean);
+
+    let frames_per_minute = 60.0 * rate as f64 / TEMPO_HOP as f64;
+    let min_lag = (frames_per_minute / TEMPO_RANGE.1).floor() as usize;
+    let max_lag = (frames_per_minute / TEMPO_RANGE.0).ceil() as usize;
+    if onsets.len() <= max_lag * 2 {
+        return None;
+    }
+
+    let correlation: Vec<f64> = (0..=max_lag + 1)
+        .map(|lag| onsets.iter().zip(&onsets[lag..]).map(|(a, b)| a * b).sum::<f64>())
+        .collect();
+    if correlation[0] <= 0.0 {
+        return None;
+    }
+    // Tempos far from the typical tempo are less likely, which stops
+    // double or half the tempo being picked instead
+    let weight = |lag: usize| {
+        let octaves = (frames_per_minute / lag as f64 / TYPICAL_TEMPO).log2();
+        (-0.5 * (octaves / 0.9).powi(2)).exp()
+    };
+    let best = (min_lag..=max_lag)
+        .max_by(|a, b| (correlation[*a] * weight(*a)).total_cmp(&(correlation[*b] * weight(*b))))?;
+    if correlation[best] <= 0.0 {
+        return None;
+    }
+
+    // Fit a parabola through the peak to find the lag between frames
+    let (before, peak, after) = (correlation[best - 1], correlation[best], correlation[best + 1]);
+    let curve = before - 2.0 * peak + after;
+    let offset = match curve.abs() > f64::EPSILON {
+        true => (0.5 * (before - after) / curve).clamp(-0.5, 0.5),
+        false => 0.0,
+    };
+    Some(frames_per_minute / (best as f64 + offset))
+}
+
+/// Estimate the key of mono samples, `None` if there are no notes
+pub fn estimate_key(samples: &[f32], rate: u32) -> Option<MusicalKey> {
+    let mut chroma = [0.0; 12];
+    for frame in spectrogram(samples, KEY_FRAME, KEY_FRAME / 2) {
+        for (bin, magnitude) in frame.iter().enumerate() {
+            let frequency = bin as f64 * rate as f64 / KEY_FRAME as f64;
+            // Only the range of most melodies and chords is counted
+            if !(55.0..=2000.0).contains(&frequency) {
+                continue;
+            }
+            let pitch = (12.0 * (frequency / 440.0).log2()).round() as i32 + 9;
+            chroma[pitch.rem_euclid(12) as usize] += magnitude;
+        }
+    }
+    if chroma.iter().all(|strength| *strength <= 0.0) {
+        return None;
+    }
+
+    let correlate = |profile: &[f64; 12], tonic: usize| {
+        let profile: Vec<f64> = (0..12).map(|i| profile[(i + 12 - tonic) % 12]).collect();
+        let (mean_a, mean_b) = (chroma.iter().sum::<f64>() / 12.0, profile.iter().sum::<f64>() / 12.0);
+        let (mut product, mut square_a, mut square_b) = (0.0, 0.0, 0.0);
+        for (a, b) in chroma.iter().zip(&profile) {
+            product += (a - mean_a) * (b - mean_b);
+            square_a += (a - mean_a).powi(2);
+            square_b += (b - mean_b).powi(2);
+        }
+        product / (square_a * square_b).sqrt()
+    };
+    (0..12)
+        .flat_map(|tonic| [(tonic, false), (tonic, true)])
+        .map(|(tonic, minor)| {
+            let profile = if minor { &MINOR_PROFILE } else { &MAJOR_PROFILE };
+            (correlate(profile, tonic), MusicalKey { tonic: tonic as u8, minor })
+        })
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, key)| key)
+}
+
+/// The results of [MusicLibrary::analyze_songs]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AnalysisScan {
+    pub analyzed: Vec<Uuid>,
+    /// Songs which couldn't be analysed, and why
+    pub failed: Vec<(Uuid, String)>,
+}
+
+impl MusicLibrary {
+    /// Find the tempo and key of local songs which are missing either,
+    /// or of every local song if `rescan` is set, storing them as the
+    /// [Tag::Bpm] and [Tag::InitialKey] of each song
+    pub fn analyze_songs(&mut self, rescan: bool) -> AnalysisScan {
+        let songs: Vec<(usize, std::path::PathBuf)> = self
+            .library
+            .iter()
+            .enumerate()
+            .filter(|(_, song)| {
+                rescan || song.get_tag(&Tag::Bpm).is_none() || song.get_tag(&Tag::InitialKey).is_none()
+            })
+            .filter_map(|(index, song)| match song.location.first() {
+                // The tracks of a cue sheet share a file, so they can't be analysed alone
+                Some(URI::Local(path)) => Some((index, path.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let results: Vec<_> = songs
+            .into_par_iter()
+            .map(|(index, path)| {
+                let samples = Arc::new(Mutex::new(Vec::new()));
+                let samples_ = samples.clone();
+                let format = DecodeFormat {
+                    rate: Some(ANALYSIS_RATE),
+                    channels: Some(1),
+                };
+                let decoded = decode_file(&path, format, move |_, _, decoded| {
+                    samples_.lock().unwrap().extend(decoded.iter().map(|sample| *sample as f32));
+                });
+                let samples = std::mem::take(&mut *samples.lock().unwrap());
+                let result = decoded.map_err(|error| error.to_string()).map(|_| {
+                    (estimate_bpm(&samples, ANALYSIS_RATE), estimate_key(&samples, ANALYSIS_RATE))
+                });
+                (index, result)
+            })
+            .collect();
+
+        let mut scan = AnalysisScan::default();
+        for (index, result) in results {
+            let song = &mut self.library[index];
+            match result {
+                Ok((bpm, key)) => {
+                    if let Some(bpm) = bpm {
+                        song.set_tag(Tag::Bpm, format!("{:.0}", bpm));
+                    }
+                    if let Some(key) = key {
+                        song.set_tag(Tag::InitialKey, key.to_string());
+                    }
+                    scan.analyzed.push(song.uuid);
+                }
+                Err(error) => scan.failed.push((song.uuid, error)),
+            }
+        }
+        scan
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+
+    use super::{estimate_bpm, estimate_key, MusicalKey};
+
+    const RATE: u32 = 11025;
+
+    #[test]
+    fn tempo_and_key() {
+        // A click every half a second is 120 BPM
+        let mut clicks = vec![0.0f32; RATE as usize * 20];
+        for beat in (0..clicks.len()).step_by(RATE as usize / 2) {
+            for (i, sample) in clicks[beat..].iter_mut().take(200).enumerate() {
+                *sample = (i as f32 * 0.9).sin() * (1.0 - i as f32 / 200.0);
+            }
+        }
+        let bpm = estimate_bpm(&clicks, RATE).unwrap();
+        assert!((bpm - 120.0).abs() < 1.5, "{bpm}");
+        assert_eq!(estimate_bpm(&vec![0.0; RATE as usize * 20], RATE), None);
+
+        // The notes of an A minor chord
+        let chord: Vec<f32> = (0..RATE as usize * 5)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                [220.0, 261.63, 329.63].iter().map(|f| (2.0 * PI * f * t).sin()).sum::<f32>() / 3.0
+            })
+            .collect();
+        assert_eq!(estimate_key(&chord, RATE), Some(MusicalKey { tonic: 9, minor: true }));
+
+        let key: MusicalKey = "Bb minor".parse().unwrap();
+        assert_eq!(key.to_string(), "A#m");
+        assert_eq!("Am".parse::<MusicalKey>().unwrap().camelot(), "8A");
+        assert_eq!("C".parse::<MusicalKey>().unwrap().camelot(), "8B");
+        assert_eq!("B".parse::<MusicalKey>().unwrap().camelot(), "1B");
+    }
+}
@@ -0,0 +1,533 @@
+//! Checking the album art of the library for covers which are missing or
+//! of poor quality, and finding better ones from online providers
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use file_format::FileFormat;
+use lofty::PictureInformation;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::cache::Cache;
+use super::corrections::Corrections;
+use super::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+
+/// What is wrong with the album art of a song
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ArtProblem {
+    Missing,
+    /// Smaller than [ArtAudit::min_size] on either side
+    Small { width: u32, height: u32 },
+    /// Saved with so few bits per pixel that it is likely to be blocky
+    Compressed { bits_per_pixel: f64 },
+    /// The art couldn't be read
+    Unreadable(String),
+}
+
+/// The limits used to decide if album art is good enough
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArtAudit {
+    /// The smallest width or height, in pixels
+    pub min_size: u32,
+    /// The fewest bits per pixel a JPEG cover can have
+    pub min_bits_per_pixel: f64,
+}
+
+impl Default for ArtAudit {
+    fn default() -> Self {
+        ArtAudit {
+            min_size: 300,
+            min_bits_per_pixel: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArtIssue {
+    pub song: Uuid,
+    /// The album of the song, if it has one
+    pub query: Option<ArtQuery>,
+    pub problem: ArtProblem,
+}
+
+impl ArtAudit {
+    /// Check the first album art of a song, returning `None` if it is fine
+    pub fn check(&self, song: &Song) -> Option<ArtProblem> {
+        let (data, _) = match song.read_art(0) {
+            Ok(Some(art)) => art,
+            Ok(None) => return Some(ArtProblem::Missing),
+            Err(error) => return Some(ArtProblem::Unreadable(error.to_string())),
+        };
+
+        // Only PNG and JPEG sizes can be read, other formats are assumed fine
+        let (info, jpeg) = match data.get(..3) {
+            Some([0x89, b'P', b'N']) => (PictureInformation::from_png(&data), false),
+            Some([0xFF, 0xD8, 0xFF]) => (PictureInformation::from_jpeg(&data), true),
+            _ => return None,
+        };
+        let info = match info {
+            Ok(info) => info,
+            Err(error) => return Some(ArtProblem::Unreadable(error.to_string())),
+        };
+
+        if info.width < self.min_size || info.height < self.min_size {
+            return Some(ArtProblem::Small {
+                width: info.width,
+                height: info.height,
+            });
+        }
+        let bits_per_pixel = (data.len() * 8) as f64 / (info.width as f64 * info.height as f64);
+        if jpeg && bits_per_pixel < self.min_bits_per_pixel {
+            return Some(ArtProblem::Compressed { bits_per_pixel });
+        }
+        None
+    }
+
+    /// Check the album art of every song in the library
+    pub fn run(&self, library: &MusicLibrary) -> Vec<ArtIssue> {
+        library
+            .library
+            .par_iter()
+            .filter_map(|song| {
+                let problem = self.check(song)?;
+                Some(ArtIssue {
+                    song: song.uuid,
+                    query: ArtQuery::from_song(song),
+                    problem,
+                })
+            })
+            .collect()
+    }
+}
+
+/// What an album is looked up by
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct ArtQuery {
+    pub album: String,
+    pub album_artist: Option<String>,
+    /// The MusicBrainz release of the album
+    pub release_id: Option<String>,
+    /// The MusicBrainz release group of the album, used when the release
+    /// has no art of its own
+    pub release_group_id: Option<String>,
+}
+
+impl ArtQuery {
+    /// The album of a song, or `None` if it has no album to look up
+    pub fn from_song(song: &Song) -> Option<Self> {
+        Some(ArtQuery {
+            album: song.get_tag(&Tag::Album)?.clone(),
+            album_artist: song.get_tag(&Tag::AlbumArtist).or(song.get_tag(&Tag::Artist)).cloned(),
+            release_id: song.get_tag(&Tag::MusicBrainzReleaseId).cloned(),
+            release_group_id: song.get_tag(&Tag::MusicBrainzReleaseGroupId).cloned(),
+        })
+    }
+}
+
+/// A cover found by an [ArtProvider]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArtCandidate {
+    pub url: String,
+    /// A smaller version of the cover for showing while picking one
+    pub thumbnail: Option<String>,
+    /// How sure the provider is that this is the right cover, from `0` to `1`
+    pub confidence: f32,
+}
+
+/// A service which album art can be looked up from
+pub trait ArtProvider {
+    /// The name of the provider, used as the source of suggested fixes
+    fn name(&self) -> &str;
+
+    /// Look up the covers of an album, which can be empty if it has none
+    fn find_covers(&self, query: &ArtQuery) -> Result<Vec<ArtCandidate>, Box<dyn Error>>;
+}
+
+/// Looks up covers from the [Cover Art Archive](https://coverartarchive.org)
+/// by the MusicBrainz IDs of an album
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoverArtArchive;
+
+#[derive(Debug, Deserialize)]
+struct CoverArtImage {
+    image: String,
+    #[serde(default)]
+    thumbnails: HashMap<String, String>,
+    #[serde(default)]
+    front: bool,
+    #[serde(default)]
+    approved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CoverArtListing {
+    images: Vec<CoverArtImage>,
+}
+
+impl CoverArtArchive {
+    const API_URL: &'static str = "https://coverartarchive.org";
+
+    /// The covers listed for a release or release group, where `kind` is
+    /// the part of the URL naming which it is
+    fn listing(kind: &str, id: &str) -> Result<Vec<ArtCandidate>, Box<dyn Error>> {
+        let response = attohttpc::get(format!("{}/{}/{}", Self::API_URL, kind, id)).send()?;
+        // Albums without any art aren't found
+        if response.status() == attohttpc::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let listing: CoverArtListing = response.error_for_status()?.json()?;
+        Ok(Self::candidates(listing))
+    }
+
+    fn candidates(listing: CoverArtListing) -> Vec<ArtCandidate> {
+        listing
+            .images
+            .into_iter()
+            .map(|image| ArtCandidate {
+                thumbnail: ["500", "large", "250", "small"]
+                    .iter()
+                    .find_map(|size| image.thumbnails.get(*size).cloned()),
+                confidence: match (image.front, image.approved) {
+                    (true, true) => 1.0,
+                    (true, false) => 0.9,
+                    // Back covers and booklets are rarely wanted
+                    _ => 0.5,
+                },
+                url: image.image,
+            })
+            .collect()
+    }
+}
+
+impl ArtProvider for CoverArtArchive {
+    fn name(&self) -> &str {
+        "coverartarchive"
+    }
+
+    fn find_covers(&self, query: &ArtQuery) -> Result<Vec<ArtCandidate>, Box<dyn Error>> {
+        if let Some(release) = &query.release_id {
+            let covers = Self::listing("release", release)?;
+            if !covers.is_empty() {
+                return Ok(covers);
+            }
+        }
+        match &query.release_group_id {
+            Some(group) => Self::listing("release-group", group),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A provider which is given by a URL of an image, where `{artist}` and
+/// `{album}` are replaced with the album being looked up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UrlProvider {
+    pub name: String,
+    pub url: String,
+    /// The confidence given to covers from this provider
+    pub confidence: f32,
+}
+
+impl ArtProvider for UrlProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn find_covers(&self, query: &ArtQuery) -> Result<Vec<ArtCandidate>, Box<dyn Error>> {
+        if self.url.contains("{artist}") && query.album_artist.is_none() {
+            return Ok(Vec::new());
+        }
+        let url = self
+            .url
+            .replace("{artist}", &urlencoding::encode(query.album_artist.as_deref().unwrap_or_default()))
+            .replace("{album}", &urlencoding::encode(&query.album));
+
+        // Only URLs which give back an image are a cover
+        let response = attohttpc::head(&url).send()?;
+        let is_image = response
+            .headers()
+            .get(attohttpc::header::CONTENT_TYPE)
+            .and_then(|kind| kind.to_str().ok())
+            .is_some_and(|kind| kind.starts_with("image/"));
+        if !response.is_success() || !is_image {
+            return Ok(Vec::new());
+        }
+        Ok(vec![ArtCandidate {
+            url,
+            thumbnail: None,
+            confidence: self.confidence,
+        }])
+    }
+}
+
+/// Where missing album art is fetched from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigArt {
+    pub cover_art_archive: bool,
+    /// Providers which are looked up after the Cover Art Archive
+    pub providers: Vec<UrlProvider>,
+    /// Covers found with at least this confidence are saved without asking,
+    /// while others are suggested as fixes
+    pub auto_apply: Option<f32>,
+}
+
+impl Default for ConfigArt {
+    fn default() -> Self {
+        ConfigArt {
+            cover_art_archive: true,
+            providers: Vec::new(),
+            auto_apply: None,
+        }
+    }
+}
+
+impl ConfigArt {
+    /// The providers to look up covers from, in order
+    pub fn providers(&self) -> Vec<&dyn ArtProvider> {
+        let mut providers: Vec<&dyn ArtProvider> = Vec::new();
+        if self.cover_art_archive {
+            providers.push(&CoverArtArchive);
+        }
+        providers.extend(self.providers.iter().map(|provider| provider as &dyn ArtProvider));
+        providers
+    }
+}
+
+/// Look up the covers of an album from every provider, along with the name
+/// of the provider each came from, best first
+pub fn find_art(query: &ArtQuery, providers: &[&dyn ArtProvider]) -> Vec<(String, ArtCandidate)> {
+    let mut found = Vec::new();
+    for provider in providers {
+        match provider.find_covers(query) {
+            Ok(covers) => found.extend(covers.into_iter().map(|cover| (provider.name().to_string(), cover))),
+            Err(error) => println!("Failed to look up art from {}: {}", provider.name(), error),
+        }
+    }
+    // The sort is stable, so earlier providers win ties
+    found.sort_by(|(_, a), (_, b)| b.confidence.total_cmp(&a.confidence));
+    found
+}
+
+/// Download a cover, keeping it in `cache` so it is only downloaded once
+pub fn download_art(url: &str, cache: Option<&Cache>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(data) = cache.and_then(|cache| cache.get(url)) {
+        return Ok(data);
+    }
+    let data = attohttpc::get(url).send()?.error_for_status()?.bytes()?;
+    if let Some(cache) = cache {
+        cache.insert(url, &data)?;
+    }
+    Ok(data)
+}
+
+/// Look up a replacement for every issue in an audit, suggesting the best
+/// cover found for each album as a fix. Each album is only looked up once.
+///
+/// Returns the number of fixes which were suggested.
+pub fn suggest_replacements(
+    issues: &[ArtIssue],
+    library: &MusicLibrary,
+    providers: &[&dyn ArtProvider],
+    corrections: &mut Corrections,
+) -> Result<usize, Box<dyn Error>> {
+    let mut found: HashMap<&ArtQuery, Option<(String, ArtCandidate)>> = HashMap::new();
+    let mut suggested = 0;
+
+    for issue in issues {
+        // Songs without an album can't be looked up
+        let query = match &issue.query {
+            Some(query) => query,
+            None => continue,
+        };
+        let cover = found
+            .entry(query)
+            .or_insert_with(|| find_art(query, providers).into_iter().next())
+            .clone();
+
+        if let Some((source, candidate)) = cover {
+            corrections.suggest_art(library, &issue.song, &source, candidate.confidence, candidate.url)?;
+            suggested += 1;
+        }
+    }
+    Ok(suggested)
+}
+
+/// An album with no art, and the covers which were found for it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissingArt {
+    pub query: ArtQuery,
+    pub songs: Vec<Uuid>,
+    /// The covers found, best first, along with the provider of each
+    pub candidates: Vec<(String, ArtCandidate)>,
+    /// Whether the best cover was confident enough to be saved already
+    pub applied: bool,
+}
+
+/// Look up art for every album in the library which has none. The best
+/// cover of each album is downloaded into `cache`, then saved if it is
+/// as confident as [ConfigArt::auto_apply] or otherwise suggested as a fix.
+///
+/// The other candidates are returned so a different one can be picked
+/// with [apply_art].
+pub fn fetch_missing_art(
+    library: &mut MusicLibrary,
+    config: &ConfigArt,
+    cache: Option<&Cache>,
+    corrections: &mut Corrections,
+) -> Result<Vec<MissingArt>, Box<dyn Error>> {
+    let audit = ArtAudit::default();
+    let mut albums: Vec<MissingArt> = Vec::new();
+    for issue in audit.run(library) {
+        let query = match (issue.problem, issue.query) {
+            (ArtProblem::Missing, Some(query)) => query,
+            _ => continue,
+        };
+        match albums.iter_mut().find(|album| album.query == query) {
+            Some(album) => album.songs.push(issue.song),
+            None => albums.push(MissingArt {
+                query,
+                songs: vec![issue.song],
+                candidates: Vec::new(),
+                applied: false,
+            }),
+        }
+    }
+
+    let providers = config.providers();
+    for album in &mut albums {
+        album.candidates = find_art(&album.query, &providers);
+        let Some((source, best)) = album.candidates.first() else {
+            continue;
+        };
+        let data = match download_art(&best.url, cache) {
+            Ok(data) => data,
+            Err(error) => {
+                println!("Failed to download art for {}: {}", album.query.album, error);
+                continue;
+            }
+        };
+
+        if config.auto_apply.is_some_and(|min| best.confidence >= min) {
+            for uuid in &album.songs {
+                if let Some((_, index)) = library.query_uuid(uuid) {
+                    save_cover_data(&mut library.library[index], &data)?;
+                }
+            }
+            album.applied = true;
+        } else {
+            for uuid in &album.songs {
+                corrections.suggest_art(library, uuid, source, best.confidence, best.url.clone())?;
+            }
+        }
+    }
+    Ok(albums)
+}
+
+/// Save the cover picked for an album as the art of each of its songs
+pub fn apply_art(
+    library: &mut MusicLibrary,
+    album: &MissingArt,
+    candidate: &ArtCandidate,
+    cache: Option<&Cache>,
+) -> Result<(), Box<dyn Error>> {
+    let data = download_art(&candidate.url, cache)?;
+    for uuid in &album.songs {
+        if let Some((_, index)) = library.query_uuid(uuid) {
+            save_cover_data(&mut library.library[index], &data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Download a cover and save it beside the song as `cover`, making it the
+/// first album art of the song
+pub(super) fn save_cover(song: &mut Song, url: &str) -> Result<(), Box<dyn Error>> {
+    let data = download_art(url, None)?;
+    save_cover_data(song, &data)
+}
+
+fn save_cover_data(song: &mut Song, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let folder = match song.primary_uri()?.0 {
+        URI::Local(path) => path.parent(),
+        URI::Cue { location, .. } => location.parent(),
+        URI::Remote(_, _) => return Err("art can only be saved for local songs".into()),
+    }
+    .map(PathBuf::from)
+    .ok_or("the song has no folder")?;
+
+    let path = folder.join(format!("cover.{}", FileFormat::from_bytes(data).extension()));
+    fs::write(&path, data)?;
+
+    let art = AlbumArt::External(URI::Local(path));
+    song.album_art.retain(|existing| existing != &art);
+    song.album_art.insert(0, art);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::{ArtAudit, ArtProblem, ArtQuery, CoverArtArchive, CoverArtListing};
+    use crate::music_storage::library::Tag;
+    use crate::music_storage::library::{test::test_song, AlbumArt, URI};
+
+    /// The header of a PNG, which is all that is read to find its size
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13];
+        data.extend_from_slice(b"IHDR");
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[8, 2, 0, 0, 0, 0, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn art_audit() {
+        let folder = tempfile::tempdir().unwrap();
+        let audit = ArtAudit::default();
+
+        let mut song = test_song("Song", "Artist", Duration::from_secs(60));
+        assert_eq!(audit.check(&song), Some(ArtProblem::Missing));
+
+        let small = folder.path().join("small.png");
+        fs::write(&small, png(200, 200)).unwrap();
+        song.album_art = vec![AlbumArt::External(URI::Local(small))];
+        assert_eq!(audit.check(&song), Some(ArtProblem::Small { width: 200, height: 200 }));
+
+        let large = folder.path().join("large.png");
+        fs::write(&large, png(1000, 1000)).unwrap();
+        song.album_art = vec![AlbumArt::External(URI::Local(large))];
+        assert_eq!(audit.check(&song), None);
+    }
+
+    #[test]
+    fn cover_art_archive() {
+        let mut song = test_song("Song", "Artist", Duration::from_secs(60));
+        assert!(ArtQuery::from_song(&song).is_none());
+        song.set_tag(Tag::Album, "Album".to_string());
+        song.set_tag(Tag::MusicBrainzReleaseId, "76df3287".to_string());
+        let query = ArtQuery::from_song(&song).unwrap();
+        assert_eq!(query.album_artist.as_deref(), Some("Artist"));
+        assert_eq!(query.release_id.as_deref(), Some("76df3287"));
+
+        let listing: CoverArtListing = serde_json::from_str(
+            r#"{"images": [
+                {"image": "https://example.com/back.jpg", "front": false, "approved": true, "thumbnails": {}},
+                {"image": "https://example.com/front.jpg", "front": true, "approved": true,
+                 "thumbnails": {"250": "https://example.com/front-250.jpg", "500": "https://example.com/front-500.jpg"}}
+            ]}"#,
+        )
+        .unwrap();
+        let candidates = CoverArtArchive::candidates(listing);
+        assert_eq!(candidates[0].confidence, 0.5);
+        assert_eq!(candidates[1].confidence, 1.0);
+        assert_eq!(candidates[1].thumbnail.as_deref(), Some("https://example.com/front-500.jpg"));
+    }
+}
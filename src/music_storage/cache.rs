@@ -0,0 +1,376 @@
+//! On-disk caches with a byte-size budget and least-recently-used eviction
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+use crate::config::ConfigCaches;
+
+use super::disk_space::{ensure_space, DiskSpaceError};
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("{0}")]
+    DiskSpace(#[from] DiskSpaceError),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, CacheEntry>,
+    size: u64,
+    clock: u64,
+}
+
+/// A folder of cached files, limited to `max_size` bytes in total.
+///
+/// When inserting would put the cache over budget, the least recently
+/// used entries are removed until it fits again. A `max_size` of `0`
+/// disables the cache entirely. Nothing is written which would leave
+/// less than `reserve` bytes free on the disk.
+#[derive(Debug)]
+pub struct Cache {
+    folder: PathBuf,
+    max_size: Mutex<u64>,
+    reserve: Mutex<u64>,
+    inner: Mutex<CacheInner>,
+    /// Counts writes, so that each has its own temporary file
+    writes: AtomicU64,
+}
+
+impl Cache {
+    /// Open a cache in `folder`, creating it if it does not exist. Any
+    /// existing files are loaded, ordered by their modification time.
+    pub fn open<P: AsRef<Path>>(folder: P, max_size: u64) -> Result<Self, io::Error> {
+        let folder = folder.as_ref().to_path_buf();
+        fs::create_dir_all(&folder)?;
+
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&folder)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            // Leftovers from an interrupted write
+            if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+
+            let key = match urlencoding::decode(&entry.file_name().to_string_lossy()) {
+                Ok(key) => key.into_owned(),
+                Err(_) => continue,
+            };
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push((key, metadata.len(), modified));
+        }
+        found.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut inner = CacheInner::default();
+        for (key, size, _) in found {
+            inner.clock += 1;
+            inner.size += size;
+            inner.entries.insert(key, CacheEntry { size, last_used: inner.clock });
+        }
+
+        let cache = Cache {
+            folder,
+            max_size: Mutex::new(max_size),
+            reserve: Mutex::new(0),
+            inner: Mutex::new(inner),
+            writes: AtomicU64::new(0),
+        };
+
+        // The budget may have shrunk since the cache was last used
+        cache.evict(&mut cache.inner.lock().unwrap(), max_size, None);
+
+        Ok(cache)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.folder.join(urlencoding::encode(key).as_ref())
+    }
+
+    /// Get the cached data for `key`, marking it as recently used
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        inner.entries.get_mut(key)?.last_used = clock;
+
+        match fs::read(self.entry_path(key)) {
+            Ok(data) => {
+                // Keep the on-disk order in sync for the next time the cache is opened
+                if let Ok(file) = File::options().write(true).open(self.entry_path(key)) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(data)
+            }
+            Err(_) => {
+                // The file was removed from under us
+                if let Some(entry) = inner.entries.remove(key) {
+                    inner.size -= entry.size;
+                }
+                None
+            }
+        }
+    }
+
+    /// Where the data for `key` is kept, marking it as recently used, for
+    /// data which is read from the file directly instead of through [Cache::get]
+    pub fn path(&self, key: &str) -> Option<PathBuf> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        inner.entries.get_mut(key)?.last_used = clock;
+        Some(self.entry_path(key))
+    }
+
+    /// The folder the cache is kept in
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    /// Returns `true` if there is an entry for `key` in the cache
+    pub fn contains(&self, key: &str) -> bool {
+        self.inner.lock().unwrap().entries.contains_key(key)
+    }
+
+    /// Insert `data` into the cache under `key`, evicting older entries
+    /// if needed. Data larger than the whole budget is not cached, and
+    /// neither is the data it replaces.
+    pub fn insert(&self, key: &str, data: &[u8]) -> Result<(), CacheError> {
+        let max_size = *self.max_size.lock().unwrap();
+        if data.len() as u64 > max_size {
+            self.remove(key)?;
+            return Ok(());
+        }
+        ensure_space(&self.folder, data.len() as u64, *self.reserve.lock().unwrap())?;
+
+        let path = self.entry_path(key);
+        let mut writer_name = path.clone().into_os_string();
+        writer_name.push(format!(".{}.tmp", self.writes.fetch_add(1, Ordering::Relaxed)));
+        if let Err(error) = fs::write(&writer_name, data) {
+            let _ = fs::remove_file(&writer_name);
+            return Err(error.into());
+        }
+
+        // Renamed while locked, so the entry always matches the file
+        // when the same key is inserted at once
+        let mut inner = self.inner.lock().unwrap();
+        if let Err(error) = fs::rename(&writer_name, path) {
+            let _ = fs::remove_file(&writer_name);
+            return Err(error.into());
+        }
+        inner.clock += 1;
+        let entry = CacheEntry {
+            size: data.len() as u64,
+            last_used: inner.clock,
+        };
+        inner.size += entry.size;
+        if let Some(old) = inner.entries.insert(key.to_string(), entry) {
+            inner.size -= old.size;
+        }
+
+        self.evict(&mut inner, max_size, Some(key));
+        Ok(())
+    }
+
+    /// Remove the entry for `key`, returning the number of bytes reclaimed
+    pub fn remove(&self, key: &str) -> Result<u64, io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.remove(key) {
+            Some(entry) => {
+                inner.size -= entry.size;
+                fs::remove_file(self.entry_path(key))?;
+                Ok(entry.size)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Remove every entry in the cache, returning the number of bytes reclaimed
+    pub fn clear(&self) -> Result<u64, io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut reclaimed = 0;
+        for (key, entry) in inner.entries.drain() {
+            match fs::remove_file(self.entry_path(&key)) {
+                Ok(_) => reclaimed += entry.size,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                Err(error) => return Err(error),
+            }
+        }
+        inner.size = 0;
+        Ok(reclaimed)
+    }
+
+    /// The total size of the cached data in bytes
+    pub fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size
+    }
+
+    /// The maximum size of the cache in bytes
+    pub fn max_size(&self) -> u64 {
+        *self.max_size.lock().unwrap()
+    }
+
+    /// Change the budget of the cache, evicting entries if
+    /// it is now over budget
+    pub fn set_max_size(&self, max_size: u64) {
+        *self.max_size.lock().unwrap() = max_size;
+        self.evict(&mut self.inner.lock().unwrap(), max_size, None);
+    }
+
+    /// Change how many bytes must be left free on the disk after inserting
+    pub fn set_reserve(&self, reserve: u64) {
+        *self.reserve.lock().unwrap() = reserve;
+    }
+
+    /// Remove the least recently used entries until the cache fits within
+    /// `max_size`, never removing the entry for `keep`
+    fn evict(&self, inner: &mut CacheInner, max_size: u64, keep: Option<&str>) {
+        while inner.size > max_size {
+            let oldest = inner
+                .entries
+                .iter()
+                .filter(|(key, _)| Some(key.as_str()) != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            let oldest = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            let entry = inner.entries.remove(&oldest).unwrap();
+            inner.size -= entry.size;
+            let _ = fs::remove_file(self.entry_path(&oldest));
+        }
+    }
+}
+
+/// Every cache used by the player, each with its own budget
+#[derive(Debug)]
+pub struct Caches {
+    pub art: Cache,
+    pub waveform: Cache,
+    /// Songs from remote libraries downloaded to be played offline
+    pub offline: Cache,
+}
+
+impl Caches {
+    /// Open all caches within the folder given in the config
+    pub fn open(config: &ConfigCaches) -> Result<Self, io::Error> {
+        Ok(Caches {
+            art: Cache::open(config.folder.join("art"), config.art)?,
+            waveform: Cache::open(config.folder.join("waveform"), config.waveform)?,
+            offline: Cache::open(config.folder.join("offline"), config.offline)?,
+        })
+    }
+
+    /// Apply new budgets from the config, evicting entries as needed
+    pub fn set_limits(&self, config: &ConfigCaches) {
+        self.art.set_max_size(config.art);
+        self.waveform.set_max_size(config.waveform);
+        self.offline.set_max_size(config.offline);
+    }
+
+    /// Leave at least `reserve` bytes free on the disk when inserting into
+    /// any of the caches
+    pub fn set_reserve(&self, reserve: u64) {
+        self.art.set_reserve(reserve);
+        self.waveform.set_reserve(reserve);
+        self.offline.set_reserve(reserve);
+    }
+
+    /// The total size of all caches in bytes
+    pub fn size(&self) -> u64 {
+        self.art.size() + self.waveform.size() + self.offline.size()
+    }
+
+    /// Clear every cache, returning the total number of bytes reclaimed
+    pub fn clear_caches(&self) -> Result<u64, io::Error> {
+        Ok(self.art.clear()? + self.waveform.clear()? + self.offline.clear()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cache, CacheError};
+
+    #[test]
+    fn lru_eviction() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache = Cache::open(folder.path(), 10).unwrap();
+
+        cache.insert("a", &[0; 4]).unwrap();
+        cache.insert("b", &[0; 4]).unwrap();
+        assert!(cache.get("a").is_some());
+
+        // "b" is the least recently used now, so it should be evicted
+        cache.insert("c", &[0; 4]).unwrap();
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+        assert_eq!(cache.size(), 8);
+
+        // Too large to ever fit, which also drops what it replaces
+        cache.insert("d", &[0; 11]).unwrap();
+        assert!(!cache.contains("d"));
+        cache.insert("a", &[0; 11]).unwrap();
+        assert!(cache.get("a").is_none());
+        cache.insert("a", &[0; 4]).unwrap();
+
+        // Nothing is written which would fill the disk
+        cache.set_reserve(u64::MAX);
+        assert!(matches!(cache.insert("e", &[0; 4]), Err(CacheError::DiskSpace(_))));
+        assert!(!cache.contains("e"));
+        cache.set_reserve(0);
+
+        // Entries should be found again after reopening
+        drop(cache);
+        let cache = Cache::open(folder.path(), 10).unwrap();
+        assert_eq!(cache.size(), 8);
+
+        assert_eq!(cache.clear().unwrap(), 8);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache = Cache::open(folder.path(), 1024).unwrap();
+
+        // Keys which only differ in their extension don't share a temporary file
+        std::thread::scope(|scope| {
+            for (key, byte) in [("cover.png", 1), ("cover.jpg", 2)] {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        cache.insert(key, &[byte; 16]).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.get("cover.png").unwrap(), [1; 16]);
+        assert_eq!(cache.get("cover.jpg").unwrap(), [2; 16]);
+        assert_eq!(cache.size(), 32);
+        assert_eq!(std::fs::read_dir(folder.path()).unwrap().count(), 2);
+    }
+}
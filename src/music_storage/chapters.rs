@@ -0,0 +1,309 @@
+//! Reading of the chapters embedded in audiobooks and other long files,
+//! from the Nero `chpl` atom of MP4/M4B files and the `CHAP` frames of ID3v2
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A chapter of a file, such as a chapter of an audiobook
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+    /// The end of the chapter, `None` if it lasts until the end of the file
+    pub end: Option<Duration>,
+}
+
+/// Read the chapters of a file, returning no chapters if it doesn't have any.
+///
+/// Only MP4 files with Nero chapters and files with ID3v2 chapters are
+/// supported, QuickTime chapter tracks are not read.
+pub fn read_chapters<P: ?Sized + AsRef<Path>>(path: &P) -> Result<Vec<Chapter>, Box<dyn Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    if file.read_exact(&mut magic).is_err() {
+        return Ok(Vec::new());
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut chapters = if &magic[..3] == b"ID3" {
+        id3_chapters(&mut file)?
+    } else if &magic[4..8] == b"ftyp" {
+        mp4_chapters(&mut file)?
+    } else {
+        Vec::new()
+    };
+
+    // Chapters which don't say where they end, end where the next one starts
+    chapters.sort_by_key(|chapter| chapter.start);
+    for i in 1..chapters.len() {
+        if chapters[i - 1].end.is_none() {
+            chapters[i - 1].end = Some(chapters[i].start);
+        }
+    }
+    Ok(chapters)
+}
+
+/// Find the chapter which contains `position`, along with its index
+pub fn chapter_at(chapters: &[Chapter], position: Duration) -> Option<(usize, &Chapter)> {
+    chapters
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, chapter)| chapter.start <= position)
+}
+
+/// Read the header of an MP4 atom, returning its type and the size of its contents
+fn atom_header<R: Read + Seek>(reader: &mut R) -> Option<([u8; 4], u64)> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).ok()?;
+    let kind = [header[4], header[5], header[6], header[7]];
+
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        // The atom lasts until the end of the file
+        0 => {
+            let position = reader.stream_position().ok()?;
+            reader.seek(SeekFrom::End(0)).ok()? - position
+        }
+        1 => {
+            let mut large = [0; 8];
+            reader.read_exact(&mut large).ok()?;
+            u64::from_be_bytes(large).checked_sub(16)?
+        }
+        size => (size as u64).checked_sub(8)?,
+    };
+    Some((kind, size))
+}
+
+/// Find the atom at `path` within `size` bytes of the reader, returning the size of its contents
+fn find_atom<R: Read + Seek>(reader: &mut R, mut size: u64, path: &[&[u8; 4]]) -> Option<u64> {
+    let (target, rest) = path.split_first()?;
+    while size >= 8 {
+        let start = reader.stream_position().ok()?;
+        let (kind, atom_size) = atom_header(reader)?;
+        let header_size = reader.stream_position().ok()? - start;
+
+        if &kind == *target {
+            return match rest.is_empty() {
+                true => Some(atom_size),
+                false => find_atom(reader, atom_size, rest),
+            };
+        }
+        reader.seek(SeekFrom::Current(atom_size as i64)).ok()?;
+        size = size.checked_sub(header_size + atom_size)?;
+    }
+    None
+}
+
+fn mp4_chapters<R: Read + Seek>(reader: &mut R) -> Result<Vec<Chapter>, Box<dyn Error>> {
+    let size = match find_atom(reader, u64::MAX, &[b"moov", b"udta", b"chpl"]) {
+        Some(size) => size,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut chpl = vec![0; size as usize];
+    reader.read_exact(&mut chpl)?;
+
+    // A version and flags, four reserved bytes from version 1, then the number of chapters
+    let mut offset = match chpl.first() {
+        Some(1) => 8,
+        Some(_) => 4,
+        None => return Ok(Vec::new()),
+    };
+    let count = *chpl.get(offset).ok_or("chpl atom is too short")?;
+    offset += 1;
+
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let header = chpl.get(offset..offset + 9).ok_or("chpl atom is too short")?;
+        // Chapter starts are in units of 100 nanoseconds
+        let start = u64::from_be_bytes(header[..8].try_into()?);
+        let title_len = header[8] as usize;
+        offset += 9;
+
+        let title = chpl.get(offset..offset + title_len).ok_or("chpl atom is too short")?;
+        offset += title_len;
+        chapters.push(Chapter {
+            title: String::from_utf8_lossy(title).to_string(),
+            start: Duration::from_nanos(start.saturating_mul(100)),
+            end: None,
+        });
+    }
+    Ok(chapters)
+}
+
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f))
+}
+
+/// Split the frames out of an ID3v2 tag, giving the ID and contents of each
+fn id3_frames(mut data: &[u8], major: u8) -> Vec<(&[u8], &[u8])> {
+    let mut frames = Vec::new();
+    while data.len() >= 10 && data[0] != 0 {
+        let size = match major {
+            4 => synchsafe(&data[4..8]),
+            _ => u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize,
+        };
+        let body = match data.get(10..10 + size) {
+            Some(body) => body,
+            None => break,
+        };
+        frames.push((&data[..4], body));
+        data = &data[10 + size..];
+    }
+    frames
+}
+
+/// Decode the text of an ID3v2 text frame, such as `TIT2`
+fn id3_text(frame: &[u8]) -> String {
+    let (encoding, text) = match frame.split_first() {
+        Some(split) => split,
+        None => return String::new(),
+    };
+
+    let text = match encoding {
+        // UTF-16, with a byte order mark or big endian
+        1 | 2 => {
+            let little_endian = *encoding == 1 && text.starts_with(&[0xff, 0xfe]);
+            let text = match *encoding == 1 && text.len() >= 2 {
+                true => &text[2..],
+                false => text,
+            };
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| match little_endian {
+                    true => u16::from_le_bytes([pair[0], pair[1]]),
+                    false => u16::from_be_bytes([pair[0], pair[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).to_string(),
+        // ISO-8859-1 maps directly onto the first code points of unicode
+        _ => text.iter().map(|byte| *byte as char).collect(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+fn id3_chapters<R: Read + Seek>(reader: &mut R) -> Result<Vec<Chapter>, Box<dyn Error>> {
+    let mut header = [0; 10];
+    reader.read_exact(&mut header)?;
+    let major = header[3];
+    let mut tag = vec![0; synchsafe(&header[6..10])];
+    reader.read_exact(&mut tag)?;
+
+    // Skip over the extended header
+    let mut data = &tag[..];
+    if header[5] & 0x40 != 0 && data.len() >= 4 {
+        let size = match major {
+            4 => synchsafe(&data[..4]),
+            _ => u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize + 4,
+        };
+        data = data.get(size..).unwrap_or_default();
+    }
+
+    let mut chapters = Vec::new();
+    for (id, body) in id3_frames(data, major) {
+        if id != b"CHAP" {
+            continue;
+        }
+
+        // An element ID, then the start and end times in milliseconds, then byte offsets
+        let id_end = match body.iter().position(|byte| *byte == 0) {
+            Some(end) => end + 1,
+            None => continue,
+        };
+        let times = match body.get(id_end..id_end + 16) {
+            Some(times) => times,
+            None => continue,
+        };
+        let start = u32::from_be_bytes(times[..4].try_into()?);
+        let end = u32::from_be_bytes(times[4..8].try_into()?);
+
+        let title = id3_frames(&body[id_end + 16..], major)
+            .into_iter()
+            .find(|(id, _)| id == b"TIT2")
+            .map(|(_, text)| id3_text(text))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body[..id_end - 1]).to_string());
+
+        chapters.push(Chapter {
+            title,
+            start: Duration::from_millis(start as u64),
+            end: Some(Duration::from_millis(end as u64)),
+        });
+    }
+    Ok(chapters)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use super::{chapter_at, read_chapters};
+
+    fn atom(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut atom = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(contents);
+        atom
+    }
+
+    #[test]
+    fn mp4_chapters() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "Intro"), (600_000_000, "Chapter 1")] {
+            chpl.extend_from_slice(&start.to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend_from_slice(title.as_bytes());
+        }
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&atom(b"ftyp", b"M4B \0\0\0\0")).unwrap();
+        file.write_all(&atom(b"mdat", &[0; 16])).unwrap();
+        let udta = atom(b"udta", &atom(b"chpl", &chpl));
+        file.write_all(&atom(b"moov", &[atom(b"mvhd", &[0; 4]), udta].concat())).unwrap();
+
+        let chapters = read_chapters(file.path()).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].title, "Chapter 1");
+        assert_eq!(chapters[1].start, Duration::from_secs(60));
+        assert_eq!(chapters[0].end, Some(Duration::from_secs(60)));
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(90)).unwrap().0, 1);
+    }
+
+    #[test]
+    fn id3_chapters() {
+        let mut tit2 = b"TIT2".to_vec();
+        tit2.extend_from_slice(&8u32.to_be_bytes());
+        tit2.extend_from_slice(&[0, 0, 3]);
+        tit2.extend_from_slice(b"Opening");
+
+        let mut chap = b"ch0\0".to_vec();
+        for time in [0u32, 30_000, u32::MAX, u32::MAX] {
+            chap.extend_from_slice(&time.to_be_bytes());
+        }
+        chap.extend_from_slice(&tit2);
+
+        let mut frames = b"CHAP".to_vec();
+        frames.extend_from_slice(&(chap.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend_from_slice(&chap);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"ID3\x03\0\0").unwrap();
+        // The tag size is synchsafe, which is the same for sizes under 128
+        file.write_all(&(frames.len() as u32).to_be_bytes()).unwrap();
+        file.write_all(&frames).unwrap();
+
+        let chapters = read_chapters(file.path()).unwrap();
+        assert_eq!(chapters.len(), 1);
+        assert_eq!(chapters[0].title, "Opening");
+        assert_eq!(chapters[0].end, Some(Duration::from_secs(30)));
+    }
+}
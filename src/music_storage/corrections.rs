@@ -0,0 +1,271 @@
+//! Suggested fixes to the tags of songs, found by lookups such as
+//! fingerprinting, which are kept for the user to review instead of
+//! being applied straight away. Fixes can also replace album art, with
+//! covers found through [ArtProvider](super::art::ArtProvider)s
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::art::save_cover;
+use super::library::{MusicLibrary, Tag};
+use crate::config::Config;
+
+#[derive(Error, Debug)]
+pub enum CorrectionError {
+    #[error("no suggested fix with that id")]
+    NotFound,
+    #[error("the song is no longer in the library")]
+    SongNotFound,
+    /// The tag was changed since the fix was suggested
+    #[error("{0:?} has changed since the fix was suggested")]
+    Stale(Tag),
+    #[error("failed to replace the album art: {0}")]
+    Art(String),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// A change to a single tag, `None` being no value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub tag: Tag,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A suggested fix to the tags of one song
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Correction {
+    pub id: Uuid,
+    pub song: Uuid,
+    /// Where the fix came from, such as `musicbrainz`
+    pub source: String,
+    /// How sure the source is that the fix is right, from `0` to `1`
+    pub confidence: f32,
+    pub changes: Vec<FieldChange>,
+    /// The URL of a cover to replace the album art of the song with
+    #[serde(default)]
+    pub art: Option<String>,
+    #[serde(with = "ts_milliseconds")]
+    pub suggested: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Corrections {
+    pub pending: Vec<Correction>,
+}
+
+impl Corrections {
+    /// The location of the suggested fixes, which are stored beside the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.path.with_file_name("corrections.json")
+    }
+
+    /// Suggest new values for tags of a song, where a value of `None`
+    /// removes the tag. Only tags which would change are kept, and any
+    /// earlier fix for the song from the same source is replaced.
+    ///
+    /// Returns the id of the fix, or `None` if nothing would change.
+    pub fn suggest(
+        &mut self,
+        library: &MusicLibrary,
+        song: &Uuid,
+        source: &str,
+        confidence: f32,
+        tags: BTreeMap<Tag, Option<String>>,
+    ) -> Result<Option<Uuid>, CorrectionError> {
+        let (current, _) = library.query_uuid(song).ok_or(CorrectionError::SongNotFound)?;
+        let changes: Vec<FieldChange> = tags
+            .into_iter()
+            .map(|(tag, new)| FieldChange {
+                old: current.get_tag(&tag).cloned(),
+                tag,
+                new,
+            })
+            .filter(|change| change.old != change.new)
+            .collect();
+
+        self.pending
+            .retain(|fix| !(fix.song == *song && fix.source == source && fix.art.is_none()));
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.push(*song, source, confidence, changes, None)))
+    }
+
+    /// Suggest replacing the album art of a song with the cover at `url`,
+    /// replacing any earlier cover for the song from the same source
+    pub fn suggest_art(
+        &mut self,
+        library: &MusicLibrary,
+        song: &Uuid,
+        source: &str,
+        confidence: f32,
+        url: String,
+    ) -> Result<Uuid, CorrectionError> {
+        library.query_uuid(song).ok_or(CorrectionError::SongNotFound)?;
+        self.pending
+            .retain(|fix| !(fix.song == *song && fix.source == source && fix.art.is_some()));
+        Ok(self.push(*song, source, confidence, Vec::new(), Some(url)))
+    }
+
+    fn push(&mut self, song: Uuid, source: &str, confidence: f32, changes: Vec<FieldChange>, art: Option<String>) -> Uuid {
+        let id = Uuid::new_v4();
+        self.pending.push(Correction {
+            id,
+            song,
+            source: source.to_string(),
+            confidence: confidence.clamp(0.0, 1.0),
+            changes,
+            art,
+            suggested: Utc::now(),
+        });
+        id
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&Correction> {
+        self.pending.iter().find(|fix| &fix.id == id)
+    }
+
+    /// Every fix for a song
+    pub fn for_song(&self, song: &Uuid) -> Vec<&Correction> {
+        self.pending.iter().filter(|fix| &fix.song == song).collect()
+    }
+
+    /// Every fix at or above `min_confidence`, the most confident first
+    pub fn confident(&self, min_confidence: f32) -> Vec<&Correction> {
+        let mut fixes: Vec<&Correction> = self
+            .pending
+            .iter()
+            .filter(|fix| fix.confidence >= min_confidence)
+            .collect();
+        fixes.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        fixes
+    }
+
+    /// Apply a fix to the song in the library and remove it from the list.
+    ///
+    /// The fix is not applied if any of its tags were changed since it was
+    /// suggested, and it is kept so the user can look at it again.
+    pub fn accept(&mut self, id: &Uuid, library: &mut MusicLibrary) -> Result<(), CorrectionError> {
+        let index = self.pending.iter().position(|fix| &fix.id == id).ok_or(CorrectionError::NotFound)?;
+        let fix = &self.pending[index];
+        let song = library
+            .library
+            .iter_mut()
+            .find(|song| song.uuid == fix.song)
+            .ok_or(CorrectionError::SongNotFound)?;
+
+        if let Some(change) = fix.changes.iter().find(|change| song.get_tag(&change.tag) != change.old.as_ref()) {
+            return Err(CorrectionError::Stale(change.tag.clone()));
+        }
+        if let Some(url) = &fix.art {
+            save_cover(song, url).map_err(|error| CorrectionError::Art(error.to_string()))?;
+        }
+        for change in &fix.changes {
+            match &change.new {
+                Some(value) => song.set_tag(change.tag.clone(), value.clone()),
+                None => song.remove_tag(&change.tag),
+            }
+        }
+        self.pending.remove(index);
+        Ok(())
+    }
+
+    /// Accept every fix at or above `min_confidence`, returning the number
+    /// which were applied. Stale fixes are skipped.
+    pub fn accept_confident(&mut self, min_confidence: f32, library: &mut MusicLibrary) -> usize {
+        let ids: Vec<Uuid> = self.confident(min_confidence).iter().map(|fix| fix.id).collect();
+        ids.iter().filter(|id| self.accept(id, library).is_ok()).count()
+    }
+
+    /// Throw away a fix, returning it if it existed
+    pub fn reject(&mut self, id: &Uuid) -> Option<Correction> {
+        let index = self.pending.iter().position(|fix| &fix.id == id)?;
+        Some(self.pending.remove(index))
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), CorrectionError> {
+        let mut writer = path.to_path_buf();
+        writer.set_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&writer)?;
+        let corrections = serde_json::to_string_pretty(self)?;
+
+        file.write_all(corrections.as_bytes())?;
+        fs::rename(writer, path)?;
+        Ok(())
+    }
+
+    /// Read the suggested fixes, returning none if the file doesn't exist yet
+    pub fn read_file(path: &Path) -> Result<Self, CorrectionError> {
+        if !path.exists() {
+            return Ok(Corrections::default());
+        }
+
+        let mut file: File = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::{CorrectionError, Corrections};
+    use crate::music_storage::library::{test::test_song, MusicLibrary, Tag};
+
+    #[test]
+    fn suggested_fixes() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut library = MusicLibrary::from_path(&folder.path().join("library.dlib")).unwrap();
+        let song = test_song("Smells Like Teen Sprit", "Nirvana", Duration::from_secs(301));
+        let other = test_song("Lithium", "Nirvana", Duration::from_secs(257));
+        let (uuid, other_uuid) = (song.uuid, other.uuid);
+        library.library.extend([song, other]);
+
+        let mut corrections = Corrections::default();
+        let tags = BTreeMap::from([
+            (Tag::Title, Some("Smells Like Teen Spirit".to_string())),
+            (Tag::Artist, Some("Nirvana".to_string())),
+            (Tag::Album, Some("Nevermind".to_string())),
+        ]);
+        let fix = corrections.suggest(&library, &uuid, "musicbrainz", 0.95, tags).unwrap().unwrap();
+        // Only the tags which change are kept
+        assert_eq!(corrections.get(&fix).unwrap().changes.len(), 2);
+
+        let tags = BTreeMap::from([(Tag::Album, Some("Nevermind".to_string()))]);
+        corrections.suggest(&library, &other_uuid, "musicbrainz", 0.4, tags).unwrap();
+        let unchanged = BTreeMap::from([(Tag::Artist, Some("Nirvana".to_string()))]);
+        assert!(corrections.suggest(&library, &other_uuid, "acoustid", 0.9, unchanged).unwrap().is_none());
+
+        assert_eq!(corrections.accept_confident(0.9, &mut library), 1);
+        assert_eq!(library.library[0].get_tag(&Tag::Title).unwrap(), "Smells Like Teen Spirit");
+        assert!(library.library[1].get_tag(&Tag::Album).is_none());
+        assert_eq!(corrections.pending.len(), 1);
+
+        // A fix for a tag the user has since edited isn't applied
+        let id = corrections.pending[0].id;
+        library.library[1].set_tag(Tag::Album, "In Utero".to_string());
+        assert!(matches!(corrections.accept(&id, &mut library), Err(CorrectionError::Stale(Tag::Album))));
+        assert!(corrections.reject(&id).is_some());
+        assert!(corrections.pending.is_empty());
+    }
+}
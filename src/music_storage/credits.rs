@@ -0,0 +1,162 @@
+//! The people credited on a song other than its artist, such as remixers
+//! and producers, read from `TIPL`/`IPLS` frames and Vorbis comments
+//!
+//! Each role is stored in its own [Tag], with several names separated by
+//! [CREDIT_SEPARATOR], so the library can be browsed by any of them
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag};
+use super::utils::normalize;
+
+/// What goes between the names in a credit tag
+pub const CREDIT_SEPARATOR: &str = "; ";
+
+/// The words which come before featured artists in artist tags, with the
+/// spaces around them
+const FEATURING: &[&str] = &[" feat. ", " feat ", " ft. ", " featuring ", " (feat. ", " (ft. ", " (featuring "];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CreditRole {
+    Performer,
+    Remixer,
+    Producer,
+    /// Artists who feature on the song, from a `FEATURING` tag or after
+    /// `feat.` in the artist
+    Featured,
+    /// Whoever mixed the songs of a DJ-mix together
+    DjMixer,
+}
+
+impl CreditRole {
+    pub const ALL: [CreditRole; 5] = [
+        CreditRole::Performer,
+        CreditRole::Remixer,
+        CreditRole::Producer,
+        CreditRole::Featured,
+        CreditRole::DjMixer,
+    ];
+
+    /// The tag the role is stored in
+    pub fn tag(&self) -> Tag {
+        match self {
+            CreditRole::Performer => Tag::Artist,
+            CreditRole::Remixer => Tag::Remixer,
+            CreditRole::Producer => Tag::Producer,
+            CreditRole::Featured => Tag::Featured,
+            CreditRole::DjMixer => Tag::DjMixer,
+        }
+    }
+}
+
+/// Split a credit tag into its names
+pub fn credit_names(value: &str) -> Vec<String> {
+    value
+        .split(CREDIT_SEPARATOR.trim())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Add `name` to the credit tag `value`, unless it's already there
+pub(super) fn add_credit(value: &mut String, name: &str) {
+    if credit_names(value).iter().any(|credited| normalize(credited) == normalize(name)) {
+        return;
+    }
+    if !value.is_empty() {
+        value.push_str(CREDIT_SEPARATOR);
+    }
+    value.push_str(name.trim());
+}
+
+/// The artists featured in an artist tag such as `Artist feat. A & B`
+pub fn featured_in(artist: &str) -> Vec<String> {
+    // Only ASCII is lowered, so the indices still match the artist
+    let lower = artist.to_ascii_lowercase();
+    let Some((index, word)) = FEATURING.iter().filter_map(|word| Some((lower.find(word)?, word))).min() else {
+        return Vec::new();
+    };
+    let featured = artist[index + word.len()..].trim_end_matches(')');
+    featured
+        .split([',', '&'])
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Song {
+    /// The names credited with `role` on the song
+    pub fn credits(&self, role: CreditRole) -> Vec<String> {
+        let mut names = self.get_tag(&role.tag()).map(|value| credit_names(value)).unwrap_or_default();
+        if role == CreditRole::Featured && names.is_empty() {
+            names = self.get_tag(&Tag::Artist).map(|artist| featured_in(artist)).unwrap_or_default();
+        }
+        names
+    }
+}
+
+impl MusicLibrary {
+    /// Everyone credited with `role` in the library, along with the songs
+    /// they're credited on. Names which only differ in case or accents
+    /// are counted as the same person.
+    pub fn credited(&self, role: CreditRole) -> BTreeMap<String, Vec<Uuid>> {
+        let mut names: BTreeMap<String, String> = BTreeMap::new();
+        let mut credited: BTreeMap<String, Vec<Uuid>> = BTreeMap::new();
+        for song in &self.library {
+            for name in song.credits(role) {
+                let name = names.entry(normalize(&name)).or_insert(name).clone();
+                credited.entry(name).or_default().push(song.uuid);
+            }
+        }
+        credited
+    }
+
+    /// The songs `name` is credited with `role` on
+    pub fn songs_credited(&self, role: CreditRole, name: &str) -> Vec<&Song> {
+        let name = normalize(name);
+        self.library
+            .iter()
+            .filter(|song| song.credits(role).iter().any(|credited| normalize(credited) == name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{add_credit, featured_in, CreditRole};
+    use crate::music_storage::library::{test::test_song, MusicLibrary, Tag};
+
+    #[test]
+    fn credit_roles() {
+        assert_eq!(featured_in("Daft Punk feat. Pharrell Williams & Nile Rodgers"), ["Pharrell Williams", "Nile Rodgers"]);
+        assert_eq!(featured_in("Artist (ft. Guest)"), ["Guest"]);
+        assert!(featured_in("Feather").is_empty());
+
+        let mut remixers = String::new();
+        add_credit(&mut remixers, "Kaskade");
+        add_credit(&mut remixers, "deadmau5");
+        add_credit(&mut remixers, "Deadmau5");
+        assert_eq!(remixers, "Kaskade; deadmau5");
+
+        let mut library = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        let mut first = test_song("Ghosts n Stuff", "deadmau5 feat. Rob Swire", Duration::from_secs(300));
+        first.set_tag(Tag::Remixer, remixers);
+        let mut second = test_song("Raise Your Weapon", "deadmau5", Duration::from_secs(400));
+        second.set_tag(Tag::Remixer, "Kaskade".to_string());
+        second.set_tag(Tag::Featured, "Greta Svabo Bech".to_string());
+        library.library = vec![first, second];
+
+        let remixers = library.credited(CreditRole::Remixer);
+        assert_eq!(remixers.keys().collect::<Vec<_>>(), ["Kaskade", "deadmau5"]);
+        assert_eq!(remixers["Kaskade"].len(), 2);
+        assert_eq!(library.songs_credited(CreditRole::Featured, "rob swire").len(), 1);
+        assert_eq!(library.songs_credited(CreditRole::Featured, "Greta Svabo Bech").len(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+//! A parser for CUE sheets, both standalone files and those embedded in
+//! the tags of a file, which turns them into ranges of virtual tracks
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// CUE sheets measure time in CD frames, of which there are 75 per second
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// The characters Windows-1252 puts in `0x80..=0x9F`, where ISO-8859-1 has control codes
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+#[derive(Error, Debug)]
+pub enum CueError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("the cue sheet has no tracks")]
+    NoTracks,
+}
+
+/// A single track of a CUE sheet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    /// The index of the file in [CueSheet::files] which the track starts in
+    pub file: usize,
+    /// The type of the track, such as `AUDIO`
+    pub kind: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    /// Where the gap before the track starts (`INDEX 00`), and the
+    /// index of the file it is in, which can be the previous file
+    pub gap: Option<(usize, Duration)>,
+    /// Where the track starts (`INDEX 01`)
+    pub start: Duration,
+    /// Silence which should be played before the track, and is not in the file
+    pub pregap: Option<Duration>,
+    /// Silence which should be played after the track, and is not in the file
+    pub postgap: Option<Duration>,
+}
+
+/// The range of a file which a track covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CueRange {
+    /// The index of the track in [CueSheet::tracks]
+    pub track: usize,
+    /// The index of the file in [CueSheet::files]
+    pub file: usize,
+    /// The index of the track within its file
+    pub index: usize,
+    pub start: Duration,
+    /// The end of the track, `None` if it lasts until the end of the file
+    pub end: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub catalog: Option<String>,
+    /// `REM` comments, such as `DATE` and `GENRE`
+    pub comments: BTreeMap<String, String>,
+    /// The files the sheet refers to, relative to its location
+    pub files: Vec<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    /// Read a CUE sheet from a file, see [decode] for the supported encodings
+    pub fn read_file<P: ?Sized + AsRef<Path>>(path: &P) -> Result<Self, CueError> {
+        let contents = decode(&fs::read(path)?);
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, CueError> {
+        let mut sheet = CueSheet::default();
+        let mut track: Option<CueTrack> = None;
+
+        for (number, line) in contents.lines().enumerate() {
+            let syntax = |message: &str| CueError::Syntax {
+                line: number + 1,
+                message: message.to_string(),
+            };
+
+            let words = split_line(line);
+            let (command, args) = match words.split_first() {
+                Some((command, args)) => (command.to_ascii_uppercase(), args),
+                None => continue,
+            };
+            let arg = |i: usize| args.get(i).cloned().ok_or_else(|| syntax("missing argument"));
+
+            match (command.as_str(), &mut track) {
+                ("REM", _) => {
+                    if let Some((key, value)) = args.split_first() {
+                        sheet.comments.insert(key.to_ascii_uppercase(), value.join(" "));
+                    }
+                }
+                ("FILE", _) => {
+                    sheet.files.push(arg(0)?);
+                }
+                ("TRACK", _) => {
+                    if let Some(finished) = track.take() {
+                        sheet.tracks.push(finished);
+                    }
+                    if sheet.files.is_empty() {
+                        return Err(syntax("TRACK before any FILE"));
+                    }
+                    track = Some(CueTrack {
+                        number: arg(0)?.parse().map_err(|_| syntax("invalid track number"))?,
+                        file: sheet.files.len() - 1,
+                        kind: arg(1).unwrap_or_else(|_| String::from("AUDIO")).to_ascii_uppercase(),
+                        ..Default::default()
+                    });
+                }
+                ("INDEX", Some(track)) => {
+                    let index: u32 = arg(0)?.parse().map_err(|_| syntax("invalid index number"))?;
+                    let time = parse_msf(&arg(1)?).ok_or_else(|| syntax("invalid time"))?;
+                    let file = sheet.files.len() - 1;
+                    match index {
+                        0 => track.gap = Some((file, time)),
+                        1 => {
+                            track.file = file;
+                            track.start = time;
+                        }
+                        _ => (),
+                    }
+                }
+                ("PREGAP", Some(track)) => {
+                    track.pregap = Some(parse_msf(&arg(0)?).ok_or_else(|| syntax("invalid time"))?)
+                }
+                ("POSTGAP", Some(track)) => {
+                    track.postgap = Some(parse_msf(&arg(0)?).ok_or_else(|| syntax("invalid time"))?)
+                }
+                ("TITLE", Some(track)) => track.title = Some(arg(0)?),
+                ("PERFORMER", Some(track)) => track.performer = Some(arg(0)?),
+                ("SONGWRITER", Some(track)) => track.songwriter = Some(arg(0)?),
+                ("ISRC", Some(track)) => track.isrc = Some(arg(0)?),
+                ("TITLE", None) => sheet.title = Some(arg(0)?),
+                ("PERFORMER", None) => sheet.performer = Some(arg(0)?),
+                ("SONGWRITER", None) => sheet.songwriter = Some(arg(0)?),
+                ("CATALOG", None) => sheet.catalog = Some(arg(0)?),
+                _ => (),
+            }
+        }
+
+        if let Some(finished) = track.take() {
+            sheet.tracks.push(finished);
+        }
+        if sheet.tracks.is_empty() {
+            return Err(CueError::NoTracks);
+        }
+        Ok(sheet)
+    }
+
+    /// The range of its file which each audio track covers.
+    ///
+    /// Each track starts at its `INDEX 01`, and the gap before the next track
+    /// is kept at the end of the track before it, as a CD player would play
+    /// it. Audio before the first track of a file is kept at its start.
+    pub fn ranges(&self) -> Vec<CueRange> {
+        let audio: Vec<(usize, &CueTrack)> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| track.kind == "AUDIO")
+            .collect();
+
+        let mut ranges: Vec<CueRange> = Vec::new();
+        for (i, (index, track)) in audio.iter().enumerate() {
+            let first_in_file = ranges.last().is_none_or(|last| last.file != track.file);
+            let start = match first_in_file {
+                true => Duration::ZERO,
+                false => track.start,
+            };
+            let end = audio
+                .get(i + 1)
+                .filter(|(_, next)| next.file == track.file)
+                .map(|(_, next)| next.start);
+
+            ranges.push(CueRange {
+                track: *index,
+                file: track.file,
+                index: match first_in_file {
+                    true => 0,
+                    false => ranges.last().map_or(0, |last| last.index + 1),
+                },
+                start,
+                end,
+            });
+        }
+        ranges
+    }
+}
+
+/// Decode the bytes of a CUE sheet. UTF-8 and UTF-16 with a byte order mark,
+/// and UTF-8 without one are read as such, anything else is read as Windows-1252
+/// which is what most older rippers wrote.
+pub fn decode(bytes: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], little_endian: bool| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|pair| match little_endian {
+                true => u16::from_le_bytes([pair[0], pair[1]]),
+                false => u16::from_be_bytes([pair[0], pair[1]]),
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    if let Some(rest) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return String::from_utf8_lossy(rest).to_string();
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        return utf16(rest, true);
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        return utf16(rest, false);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes
+            .iter()
+            .map(|byte| match byte {
+                0x80..=0x9f => WINDOWS_1252[(byte - 0x80) as usize],
+                _ => *byte as char,
+            })
+            .collect(),
+    }
+}
+
+/// Parse a time of `minutes:seconds:frames`
+fn parse_msf(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.trim().parse::<u64>());
+    let (minutes, seconds, frames) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(m)), Some(Ok(s)), Some(Ok(f)), None) if s < 60 && f < FRAMES_PER_SECOND => (m, s, f),
+        _ => return None,
+    };
+
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Some(Duration::from_nanos(frames * 1_000_000_000 / FRAMES_PER_SECOND))
+}
+
+/// Split a line into words, keeping quoted strings together
+fn split_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+
+    for c in line.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{decode, CueSheet};
+
+    #[test]
+    fn multi_file_cue() {
+        let sheet = CueSheet::parse(
+            r#"REM GENRE "Progressive Rock"
+            REM DATE 1973
+            PERFORMER "Band"
+            TITLE "Album"
+            FILE "01 Intro.flac" WAVE
+              TRACK 01 AUDIO
+                TITLE "Intro"
+                INDEX 00 00:00:00
+                INDEX 01 00:01:00
+              TRACK 02 AUDIO
+                TITLE "Second"
+                PERFORMER "Guest"
+                INDEX 00 02:00:00
+                INDEX 01 02:02:00
+              TRACK 03 AUDIO
+                TITLE "Third"
+                INDEX 00 04:00:00
+            FILE "02 Third.flac" WAVE
+                INDEX 01 00:00:00
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(sheet.title.as_deref(), Some("Album"));
+        assert_eq!(sheet.comments.get("GENRE").map(String::as_str), Some("Progressive Rock"));
+        assert_eq!(sheet.files.len(), 2);
+        assert_eq!(sheet.tracks[1].performer.as_deref(), Some("Guest"));
+
+        // The third track's gap is in the first file, but it starts in the second
+        assert_eq!(sheet.tracks[2].file, 1);
+        assert_eq!(sheet.tracks[2].gap, Some((0, Duration::from_secs(240))));
+
+        let ranges = sheet.ranges();
+        assert_eq!(ranges.len(), 3);
+        // Audio before the first index is kept, and gaps are kept on the track before
+        assert_eq!(ranges[0].start, Duration::ZERO);
+        assert_eq!(ranges[0].end, Some(Duration::from_secs(122)));
+        assert_eq!(ranges[1].end, None);
+        assert_eq!((ranges[2].file, ranges[2].index, ranges[2].start), (1, 0, Duration::ZERO));
+    }
+
+    #[test]
+    fn cue_encodings() {
+        assert_eq!(decode(b"TITLE \"Caf\xe9\""), "TITLE \"Café\"");
+        assert_eq!(decode(b"\xef\xbb\xbfTITLE \"\xe3\x81\x82\""), "TITLE \"あ\"");
+        assert_eq!(decode(&[0xff, 0xfe, b'A', 0]), "A");
+
+        let sheet = CueSheet::parse("FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 01:00:37").unwrap();
+        assert_eq!(sheet.tracks[0].start.as_millis(), 60_493);
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+    }
+}
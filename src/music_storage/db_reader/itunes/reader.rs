@@ -4,7 +4,8 @@ use quick_xml::events::Event;
 use quick_xml::reader::Reader;
 use uuid::Uuid;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
+use std::error::Error;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration as StdDur;
@@ -124,8 +125,16 @@ impl ExternalLibrary for ITunesLibrary {
         lib
     }
     fn to_songs(&self) -> Vec<crate::music_storage::library::Song> {
+        self.songs_by_id().into_iter().map(|(_, song)| song).collect()
+    }
+}
+
+impl ITunesLibrary {
+    /// The songs of the library along with their iTunes track IDs, which
+    /// playlists refer to them by
+    pub fn songs_by_id(&self) -> Vec<(i32, Song)> {
         let mut count = 0;
-        let mut bun: Vec<Song> = Vec::new();
+        let mut bun: Vec<(i32, Song)> = Vec::new();
         for track in &self.tracks {
             //grab "other" tags
             let mut tags_: BTreeMap<Tag, String> = BTreeMap::new();
@@ -197,7 +206,7 @@ impl ExternalLibrary for ITunesLibrary {
                 internal_tags,
             };
             // dbg!(&ny.tags);
-            bun.push(ny);
+            bun.push((track.id, ny));
         }
         println!("skipped: {}", count);
         bun
@@ -332,13 +341,162 @@ impl ITunesSong {
     }
 }
 
+/// A playlist from an iTunes library, listing its songs by track ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ITunesPlaylist {
+    pub name: String,
+    pub track_ids: Vec<i32>,
+}
+
+/// Read the playlists of an iTunes library file, leaving out the ones
+/// iTunes makes for itself, such as the whole library
+pub fn read_playlists(file: &Path) -> Result<Vec<ITunesPlaylist>, Box<dyn Error>> {
+    parse_playlists(&fs::read_to_string(file)?)
+}
+
+fn parse_playlists(xml: &str) -> Result<Vec<ITunesPlaylist>, Box<dyn Error>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let root = loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == b"dict" => break read_plist(&mut reader, b"dict")?,
+            Event::Eof => return Err("the library has no contents".into()),
+            _ => (),
+        }
+    };
+
+    let Some(PlistValue::Array(playlists)) = root.get("Playlists") else {
+        return Ok(Vec::new());
+    };
+    let mut found = Vec::new();
+    for playlist in playlists {
+        let built_in = ["Master", "Distinguished Kind", "Folder"]
+            .iter()
+            .any(|key| playlist.get(key).is_some_and(|value| *value != PlistValue::False));
+        if built_in {
+            continue;
+        }
+        let name = match playlist.get("Name") {
+            Some(PlistValue::Text(name)) => name.clone(),
+            _ => continue,
+        };
+        let track_ids = match playlist.get("Playlist Items") {
+            Some(PlistValue::Array(items)) => items
+                .iter()
+                .filter_map(|item| match item.get("Track ID") {
+                    Some(PlistValue::Text(id)) => id.parse().ok(),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        found.push(ITunesPlaylist { name, track_ids });
+    }
+    Ok(found)
+}
+
+/// A value in a property list. Numbers, dates, and data are all kept as text.
+#[derive(Debug, Clone, PartialEq)]
+enum PlistValue {
+    Dict(Vec<(String, PlistValue)>),
+    Array(Vec<PlistValue>),
+    Text(String),
+    True,
+    False,
+}
+
+impl PlistValue {
+    fn get(&self, key: &str) -> Option<&PlistValue> {
+        match self {
+            PlistValue::Dict(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Read the value started by the `tag` element, up to its end
+fn read_plist(reader: &mut Reader<&[u8]>, tag: &[u8]) -> Result<PlistValue, Box<dyn Error>> {
+    if tag != b"dict" && tag != b"array" {
+        return Ok(PlistValue::Text(read_plist_text(reader)?));
+    }
+
+    let mut entries = Vec::new();
+    let mut values = Vec::new();
+    let mut key = String::new();
+    loop {
+        let value = match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == b"key" => {
+                key = read_plist_text(reader)?;
+                continue;
+            }
+            Event::Start(e) => read_plist(reader, e.name().as_ref())?,
+            Event::Empty(e) => match e.name().as_ref() {
+                b"true" => PlistValue::True,
+                b"false" => PlistValue::False,
+                b"dict" => PlistValue::Dict(Vec::new()),
+                b"array" => PlistValue::Array(Vec::new()),
+                _ => PlistValue::Text(String::new()),
+            },
+            Event::End(_) if tag == b"dict" => return Ok(PlistValue::Dict(entries)),
+            Event::End(_) => return Ok(PlistValue::Array(values)),
+            Event::Eof => return Err("the library ended early".into()),
+            _ => continue,
+        };
+        match tag {
+            b"dict" => entries.push((std::mem::take(&mut key), value)),
+            _ => values.push(value),
+        }
+    }
+}
+
+/// Read the text of an element, up to its end
+fn read_plist_text(reader: &mut Reader<&[u8]>) -> Result<String, Box<dyn Error>> {
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Text(e) => text.push_str(&e.unescape()?),
+            Event::CData(e) => text.push_str(&String::from_utf8_lossy(&e)),
+            Event::End(_) => return Ok(text),
+            Event::Eof => return Err("the library ended early".into()),
+            _ => (),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{path::{Path, PathBuf}, sync::{Arc, RwLock}};
 
     use crate::{config::{Config, ConfigLibrary}, music_storage::{db_reader::extern_library::ExternalLibrary, library::MusicLibrary}};
 
-    use super::ITunesLibrary;
+    use super::{parse_playlists, ITunesLibrary, ITunesPlaylist};
+
+    #[test]
+    fn itunes_playlists() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <plist version="1.0"><dict>
+                <key>Tracks</key><dict>
+                    <key>1</key><dict><key>Track ID</key><integer>1</integer><key>Name</key><string>A &amp; B</string></dict>
+                </dict>
+                <key>Playlists</key><array>
+                    <dict><key>Name</key><string>Library</string><key>Master</key><true/>
+                        <key>Playlist Items</key><array><dict><key>Track ID</key><integer>1</integer></dict></array></dict>
+                    <dict><key>Name</key><string>Favourites</string><key>Smart Info</key><data>AQ==</data>
+                        <key>Playlist Items</key><array>
+                            <dict><key>Track ID</key><integer>7</integer></dict>
+                            <dict><key>Track ID</key><integer>1</integer></dict>
+                        </array></dict>
+                    <dict><key>Name</key><string>Empty</string><key>Visible</key><false/></dict>
+                </array>
+            </dict></plist>"#;
+        assert_eq!(
+            parse_playlists(xml).unwrap(),
+            [
+                ITunesPlaylist { name: "Favourites".to_string(), track_ids: vec![7, 1] },
+                ITunesPlaylist { name: "Empty".to_string(), track_ids: vec![] },
+            ]
+        );
+    }
 
     #[test]
     fn itunes_lib_test() {
@@ -0,0 +1,91 @@
+//! Decoding whole files into samples with GStreamer, for analysing them
+//! outside of playback
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use gst::{ClockTime, MessageView};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+/// The format the samples of a file are decoded to. Anything left as
+/// `None` is kept as it is in the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeFormat {
+    pub rate: Option<u32>,
+    pub channels: Option<u32>,
+}
+
+/// Decode a file as fast as possible, calling `on_samples` with the sample
+/// rate, the number of channels, and each piece of interleaved samples,
+/// where `1` is full scale
+pub fn decode_file<F>(path: &Path, format: DecodeFormat, on_samples: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(u32, usize, &[f64]) + Send + 'static,
+{
+    gst::init()?;
+    let uri = glib::filename_to_uri(path.canonicalize()?, None)?;
+    let mut caps = String::from("audio/x-raw,format=F64LE,layout=interleaved");
+    if let Some(rate) = format.rate {
+        caps.push_str(&format!(",rate={}", rate));
+    }
+    if let Some(channels) = format.channels {
+        caps.push_str(&format!(",channels={}", channels));
+    }
+    let pipeline = gst::parse_launch(&format!(
+        "uridecodebin uri=\"{}\" ! audioconvert ! audioresample ! {} ! fakesink name=sink sync=false",
+        uri, caps
+    ))?
+    .downcast::<gst::Pipeline>()
+    .map_err(|_| "the decoder is not a pipeline")?;
+
+    let pad = pipeline
+        .by_name("sink")
+        .and_then(|sink| sink.static_pad("sink"))
+        .ok_or("the decoder has no sink")?;
+    // The probe can be called from any thread, so the callback is behind a lock
+    let on_samples = Arc::new(Mutex::new(on_samples));
+    let decoded = Arc::new(AtomicBool::new(false));
+    let decoded_ = decoded.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(buffer) = info.buffer() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let caps = pad.current_caps();
+        let format = caps.as_ref().and_then(|caps| caps.structure(0)).and_then(|structure| {
+            Some((structure.get::<i32>("rate").ok()?, structure.get::<i32>("channels").ok()?))
+        });
+        if let (Some((rate, channels)), Ok(map)) = (format, buffer.map_readable()) {
+            let samples: Vec<f64> = map
+                .chunks_exact(8)
+                .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            (on_samples.lock().unwrap())(rate as u32, channels.max(1) as usize, &samples);
+            decoded_.store(true, Ordering::Relaxed);
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().ok_or("the decoder has no bus")?;
+    let mut result = Ok(());
+    for message in bus.iter_timed(ClockTime::NONE) {
+        match message.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(error) => {
+                result = Err(error.error().to_string());
+                break;
+            }
+            _ => (),
+        }
+    }
+    let _ = pipeline.set_state(gst::State::Null);
+    result?;
+
+    match decoded.load(Ordering::Relaxed) {
+        true => Ok(()),
+        false => Err("the file has no audio".into()),
+    }
+}
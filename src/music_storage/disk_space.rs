@@ -0,0 +1,97 @@
+//! Checks for free disk space before writing large files, such as
+//! downloads, device syncs, and transcodes, so they fail early instead
+//! of filling the disk
+
+use std::error::Error;
+use std::io;
+use std::path::{self, Path};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DiskSpaceError {
+    #[error("not enough disk space: {required} bytes needed, {available} bytes available with {reserve} bytes reserved")]
+    InsufficientSpace {
+        required: u64,
+        available: u64,
+        reserve: u64,
+    },
+    #[error("could not check free disk space: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl DiskSpaceError {
+    /// The [DiskSpaceError::InsufficientSpace] which caused `error`, if
+    /// running out of space is why it failed
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a DiskSpaceError> {
+        let mut error = Some(error);
+        while let Some(current) = error {
+            match current.downcast_ref::<DiskSpaceError>() {
+                Some(found @ DiskSpaceError::InsufficientSpace { .. }) => return Some(found),
+                _ => error = current.source(),
+            }
+        }
+        None
+    }
+}
+
+/// Returns the number of bytes available on the disk containing `target`.
+///
+/// `target` does not need to exist yet, the nearest existing parent is
+/// checked instead.
+pub fn available_space(target: &Path) -> Result<u64, io::Error> {
+    // Made absolute so the search stops at the root, even when the
+    // working directory was deleted
+    let target = path::absolute(target)?;
+    let existing = target
+        .ancestors()
+        .find(|parent| parent.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no part of the path exists"))?;
+
+    fs2::available_space(existing)
+}
+
+/// Ensure that writing `required` bytes to `target` would still leave
+/// at least `reserve` bytes free on the disk
+pub fn ensure_space(target: &Path, required: u64, reserve: u64) -> Result<(), DiskSpaceError> {
+    let available = available_space(target)?;
+
+    if available < required.saturating_add(reserve) {
+        return Err(DiskSpaceError::InsufficientSpace {
+            required,
+            available,
+            reserve,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::{available_space, ensure_space, DiskSpaceError};
+
+    #[test]
+    fn space_guard() {
+        let folder = tempfile::tempdir().unwrap();
+        let target = folder.path().join("not/yet/created.mp3");
+
+        assert!(ensure_space(&target, 0, 0).is_ok());
+        assert!(matches!(
+            ensure_space(&target, u64::MAX, 0),
+            Err(DiskSpaceError::InsufficientSpace { .. })
+        ));
+
+        // Found through errors which wrap it
+        let error: Box<dyn Error> = ensure_space(&target, u64::MAX, 0).unwrap_err().into();
+        assert!(DiskSpaceError::find(error.as_ref()).is_some());
+        let error: Box<dyn Error> = "something else".into();
+        assert!(DiskSpaceError::find(error.as_ref()).is_none());
+
+        // Paths which don't exist anywhere are checked from the root
+        assert!(available_space(&folder.path().join("missing")).is_ok());
+        assert!(available_space(std::path::Path::new("relative/missing")).is_ok());
+    }
+}
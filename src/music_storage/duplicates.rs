@@ -0,0 +1,101 @@
+//! Finding the songs which are the same recording, such as a song on both
+//! an album and a compilation, or in both FLAC and MP3
+//!
+//! Songs are the same recording if they have the same MusicBrainz
+//! recording, or the same title and artist and about the same length.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag};
+use super::utils::normalize;
+
+/// How different the lengths of two songs can be for them to be the same
+/// recording
+const DURATION_TOLERANCE: Duration = Duration::from_secs(3);
+
+/// What songs are compared by, before their lengths
+fn recording_key(song: &Song) -> Option<String> {
+    if let Some(recording) = song.get_tag(&Tag::MusicBrainzRecordingId) {
+        return Some(format!("mbid:{}", recording));
+    }
+    let title = normalize(song.get_tag(&Tag::Title)?);
+    let artist = song.get_tag(&Tag::Artist).map(|artist| normalize(artist)).unwrap_or_default();
+    Some(format!("{}:{}", artist, title))
+}
+
+/// The group of each of the `songs`, in order, where songs in the same
+/// group are the same recording. Songs without a title are in a group of
+/// their own.
+pub fn duplicate_groups(songs: &[&Song]) -> Vec<usize> {
+    let mut by_key: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, song) in songs.iter().enumerate() {
+        if let Some(key) = recording_key(song) {
+            by_key.entry(key).or_default().push(index);
+        }
+    }
+
+    let mut groups: Vec<usize> = (0..songs.len()).collect();
+    for (key, mut indexes) in by_key {
+        // Recordings matched by MusicBrainz are the same whatever their length
+        if !key.starts_with("mbid:") {
+            indexes.sort_by_key(|&index| songs[index].duration);
+        }
+        for pair in indexes.windows(2) {
+            let (previous, index) = (pair[0], pair[1]);
+            if key.starts_with("mbid:") || songs[index].duration.abs_diff(songs[previous].duration) <= DURATION_TOLERANCE {
+                groups[index] = groups[previous];
+            }
+        }
+    }
+    groups
+}
+
+impl MusicLibrary {
+    /// The songs in the library which are the same recording as another
+    /// song, in groups of the [Uuid]s of each recording
+    pub fn duplicates(&self) -> Vec<Vec<Uuid>> {
+        let songs: Vec<&Song> = self.library.iter().collect();
+        let mut grouped: HashMap<usize, Vec<Uuid>> = HashMap::new();
+        for (song, group) in songs.iter().zip(duplicate_groups(&songs)) {
+            grouped.entry(group).or_default().push(song.uuid);
+        }
+        grouped.into_values().filter(|group| group.len() > 1).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::duplicate_groups;
+    use crate::music_storage::library::{test::test_song, Tag};
+    use crate::music_storage::memory_store::MemoryStore;
+
+    #[test]
+    fn duplicate_recordings() {
+        let mut songs = vec![
+            test_song("Song", "Artist", Duration::from_secs(200)),
+            test_song("song!", "ARTIST", Duration::from_secs(202)),
+            test_song("Song", "Artist", Duration::from_secs(320)),
+            test_song("Other", "Artist", Duration::from_secs(200)),
+            test_song("Another", "Artist", Duration::from_secs(100)),
+            test_song("Another (Remaster)", "Artist", Duration::from_secs(110)),
+        ];
+        for song in &mut songs[4..] {
+            song.set_tag(Tag::MusicBrainzRecordingId, "d1f2".to_string());
+        }
+
+        let groups = duplicate_groups(&songs.iter().collect::<Vec<_>>());
+        assert_eq!(groups[0], groups[1]);
+        // Too long to be the same recording, such as a live version
+        assert_ne!(groups[0], groups[2]);
+        assert_ne!(groups[0], groups[3]);
+        assert_eq!(groups[4], groups[5]);
+
+        let library = MemoryStore::with_songs(songs).into_library();
+        assert_eq!(library.duplicates().len(), 2);
+    }
+}
@@ -0,0 +1,337 @@
+//! Measuring the exact duration of songs whose headers get it wrong, such
+//! as VBR MP3s without a Xing header, by counting their frames or decoding
+//! them in full
+//!
+//! Songs which have been measured are marked with
+//! [InternalTag::ExactDuration] so they aren't measured again. The last
+//! track of a cue sheet ends where its file does, so it's fixed as well.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rayon::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::decode::{decode_file, DecodeFormat};
+use super::library::{InternalTag, MusicLibrary, Song, URI};
+
+/// How different a measured duration can be from the stored one before
+/// it's counted as corrected, about the length of a few MP3 frames
+const DURATION_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// The bitrates of MPEG-1 layers 1, 2, and 3, then MPEG-2 layer 1, and
+/// MPEG-2 layers 2 and 3, in kbps
+const BITRATES: [[u32; 14]; 5] = [
+    [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+    [32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+    [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameHeader {
+    /// The length of the frame in bytes, including the header
+    length: usize,
+    samples: u32,
+    rate: u32,
+}
+
+/// Read the header of an MPEG audio frame from the start of `bytes`
+fn frame_header(bytes: &[u8]) -> Option<FrameHeader> {
+    let [first, second, third, _] = *bytes.get(..4)? else {
+        return None;
+    };
+    if first != 0xFF || second & 0xE0 != 0xE0 {
+        return None;
+    }
+    // 3 is MPEG-1, 2 is MPEG-2, and 0 is MPEG-2.5
+    let version = (second >> 3) & 0b11;
+    let layer = match (second >> 1) & 0b11 {
+        0b11 => 1,
+        0b10 => 2,
+        0b01 => 3,
+        _ => return None,
+    };
+    let bitrate_index = (third >> 4) as usize;
+    let rate_index = ((third >> 2) & 0b11) as usize;
+    if version == 1 || !(1..15).contains(&bitrate_index) || rate_index == 3 {
+        return None;
+    }
+
+    let table = match (version, layer) {
+        (3, layer) => layer - 1,
+        (_, 1) => 3,
+        _ => 4,
+    };
+    let bitrate = BITRATES[table][bitrate_index - 1] * 1000;
+    let rate = [44100, 48000, 32000][rate_index] >> (3 - version.max(1));
+    let padding = ((third >> 1) & 1) as u32;
+    let (samples, length) = match (layer, version) {
+        (1, _) => (384, (12 * bitrate / rate + padding) * 4),
+        (2, _) | (3, 3) => (1152, 144 * bitrate / rate + padding),
+        _ => (576, 72 * bitrate / rate + padding),
+    };
+    Some(FrameHeader { length: length as usize, samples, rate })
+}
+
+/// Whether `bytes` is the start of a tag after the audio, or the end of
+/// the file
+fn is_audio_end(bytes: &[u8]) -> bool {
+    bytes.is_empty() || [&b"TAG"[..], b"APETAGEX", b"LYRICS"].iter().any(|tag| bytes.starts_with(tag))
+}
+
+/// The length of the ID3v2 tag at the start of an MP3, if it has one
+fn id3v2_length(data: &[u8]) -> usize {
+    match data.get(..10) {
+        Some(header) if header.starts_with(b"ID3") => {
+            let size = header[6..10].iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7F));
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+/// The duration of the MP3 in `data`, found by counting every frame. The
+/// Xing or VBRI frame at the start of VBR files has no audio, so it isn't
+/// counted. Returns `None` if there are no frames.
+pub fn mp3_duration(data: &[u8]) -> Option<Duration> {
+    let mut position = id3v2_length(data);
+    let mut samples = 0u64;
+    let mut rate = None;
+    let mut first = true;
+    // Where the last frame which was counted ends
+    let mut synced = None;
+    while position + 4 <= data.len() {
+        let header = match frame_header(&data[position..]) {
+            // A frame found by searching only counts if the next one
+            // follows it, so audio which happens to look like a header
+            // isn't counted
+            Some(header) if header.length > 4 && (synced == Some(position) || {
+                let next = &data[(position + header.length).min(data.len())..];
+                is_audio_end(next) || frame_header(next).is_some()
+            }) => header,
+            _ => {
+                if rate.is_some() && is_audio_end(&data[position..]) {
+                    break;
+                }
+                position += 1;
+                continue;
+            }
+        };
+
+        let frame = &data[position..(position + header.length).min(data.len())];
+        let is_info = first && frame.windows(4).take(48).any(|tag| [&b"Xing"[..], b"Info", b"VBRI"].contains(&tag));
+        if !is_info {
+            samples += header.samples as u64;
+            rate.get_or_insert(header.rate);
+        }
+        first = false;
+        position += header.length;
+        synced = Some(position);
+    }
+    rate.map(|rate| Duration::from_secs_f64(samples as f64 / rate as f64))
+}
+
+/// The duration of a file, found by decoding all of it
+pub fn decoded_duration(path: &Path) -> Result<Duration, Box<dyn Error>> {
+    let frames: Arc<Mutex<(u64, u32)>> = Arc::new(Mutex::new((0, 0)));
+    let frames_ = frames.clone();
+    decode_file(path, DecodeFormat::default(), move |rate, channels, samples| {
+        let mut frames = frames_.lock().unwrap();
+        frames.0 += (samples.len() / channels) as u64;
+        frames.1 = rate;
+    })?;
+    let (frames, rate) = *frames.lock().unwrap();
+    match rate {
+        0 => Err("the file has no audio".into()),
+        rate => Ok(Duration::from_secs_f64(frames as f64 / rate as f64)),
+    }
+}
+
+/// The exact duration of the file at `path`, counting the frames of MP3s
+/// and decoding everything else
+pub fn exact_duration(path: &Path) -> Result<Duration, Box<dyn Error>> {
+    if is_mp3(path) {
+        if let Some(duration) = mp3_duration(&fs::read(path)?) {
+            return Ok(duration);
+        }
+    }
+    decoded_duration(path)
+}
+
+fn is_mp3(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("mp3"))
+}
+
+/// The results of [MusicLibrary::correct_durations]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DurationScan {
+    /// Each song whose duration changed, with its old and new duration
+    pub corrected: Vec<(Uuid, Duration, Duration)>,
+    /// Songs which couldn't be measured, and why
+    pub failed: Vec<(Uuid, String)>,
+}
+
+impl Song {
+    /// Whether the duration of the song was measured from its audio
+    pub fn has_exact_duration(&self) -> bool {
+        self.internal_tags.contains(&InternalTag::ExactDuration)
+    }
+
+    /// Whether the song is a local MP3 whose duration hasn't been measured,
+    /// which is the kind of file whose headers are most often wrong
+    pub fn needs_exact_duration(&self) -> bool {
+        let local_mp3 = match self.location.first() {
+            Some(URI::Local(path) | URI::Cue { location: path, .. }) => is_mp3(path),
+            _ => false,
+        };
+        local_mp3 && !self.has_exact_duration()
+    }
+}
+
+impl MusicLibrary {
+    /// Measure the exact duration of every song which
+    /// [needs it](Song::needs_exact_duration), or every local song which
+    /// hasn't been measured if `all_formats` is set, and store it in the
+    /// library
+    pub fn correct_durations(&mut self, all_formats: bool) -> DurationScan {
+        // The tracks of a cue sheet share a file, which is only measured once
+        let mut files: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+        for (index, song) in self.library.iter().enumerate() {
+            let measure = match all_formats {
+                true => !song.has_exact_duration(),
+                false => song.needs_exact_duration(),
+            };
+            match song.location.first() {
+                Some(URI::Local(path) | URI::Cue { location: path, .. }) if measure => {
+                    files.entry(path.clone()).or_default().push(index)
+                }
+                _ => (),
+            }
+        }
+
+        let measured: Vec<_> = files
+            .into_par_iter()
+            .map(|(path, indices)| (exact_duration(&path).map_err(|error| error.to_string()), indices))
+            .collect();
+
+        let mut scan = DurationScan::default();
+        for (duration, indices) in measured {
+            let duration = match duration {
+                Ok(duration) => duration,
+                Err(error) => {
+                    scan.failed.extend(indices.iter().map(|index| (self.library[*index].uuid, error.clone())));
+                    continue;
+                }
+            };
+            let last_start = indices
+                .iter()
+                .filter_map(|index| self.library[*index].location[0].start().ok().copied())
+                .max();
+            for index in indices {
+                let song = &mut self.library[index];
+                let old = song.duration;
+                match &mut song.location[0] {
+                    URI::Cue { start, end, .. } if Some(*start) == last_start => {
+                        *end = duration;
+                        song.duration = duration.saturating_sub(*start);
+                    }
+                    URI::Cue { .. } => (),
+                    _ => song.duration = duration,
+                }
+                if song.duration.abs_diff(old) > DURATION_TOLERANCE {
+                    scan.corrected.push((song.uuid, old, song.duration));
+                }
+                song.internal_tags.push(InternalTag::ExactDuration);
+            }
+        }
+        scan
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::mp3_duration;
+    use crate::music_storage::library::{test::test_song, MusicLibrary, URI};
+
+    /// An MP3 of empty MPEG-1 layer 3 frames at 44.1kHz, with a Xing frame
+    /// first and each of `bitrates` after it, given by their index
+    fn vbr_mp3(bitrates: &[u8]) -> Vec<u8> {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x05".to_vec();
+        data.extend([0; 5]);
+        for (index, bitrate) in [9].iter().chain(bitrates).enumerate() {
+            let header = frame_header_bytes(*bitrate);
+            let length = super::frame_header(&header).unwrap().length;
+            let mut frame = vec![0; length];
+            frame[..4].copy_from_slice(&header);
+            if index == 0 {
+                frame[36..40].copy_from_slice(b"Xing");
+            }
+            data.extend(frame);
+        }
+        data.extend(b"TAG");
+        data.extend([0; 125]);
+        data
+    }
+
+    fn frame_header_bytes(bitrate: u8) -> [u8; 4] {
+        [0xFF, 0xFB, bitrate << 4, 0x00]
+    }
+
+    #[test]
+    fn exact_durations() {
+        // Frames of 128, 320, and 32 kbps, which a header would get wrong
+        let bitrates: Vec<u8> = (0..300).map(|frame| [9, 14, 1][frame % 3]).collect();
+        let data = vbr_mp3(&bitrates);
+        let expected = Duration::from_secs_f64(300.0 * 1152.0 / 44100.0);
+        assert_eq!(mp3_duration(&data), Some(expected));
+        // Noise between frames is skipped over, here after the tag, the
+        // Xing frame, and three frames
+        let mut noisy = data.clone();
+        noisy.splice(1997..1997, [0xFF, 0x00, 0xFF, 0xFB]);
+        assert_eq!(mp3_duration(&noisy), Some(expected));
+        assert_eq!(mp3_duration(b"not an mp3"), None);
+
+        let folder = tempfile::tempdir().unwrap();
+        let (single, sheet) = (folder.path().join("single.mp3"), folder.path().join("album.mp3"));
+        std::fs::write(&single, &data).unwrap();
+        std::fs::write(&sheet, &data).unwrap();
+        let mut library = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        let mut song = test_song("Single", "Artist", Duration::from_secs(20));
+        song.location = vec![URI::Local(single)];
+        library.library.push(song);
+        for (start, end) in [(0, 4), (4, 20)] {
+            let mut track = test_song("Track", "Artist", Duration::from_secs(end - start));
+            track.location = vec![URI::Cue {
+                location: sheet.clone(),
+                index: start as usize,
+                start: Duration::from_secs(start),
+                end: Duration::from_secs(end),
+            }];
+            library.library.push(track);
+        }
+        let mut flac = test_song("Lossless", "Artist", Duration::from_secs(20));
+        flac.location = vec![URI::Local(PathBuf::from("/music/song.flac"))];
+        library.library.push(flac);
+
+        let scan = library.correct_durations(false);
+        assert_eq!(scan.corrected.len(), 2);
+        assert!(scan.failed.is_empty());
+        assert_eq!(library.library[0].duration, expected);
+        assert_eq!(library.library[1].duration, Duration::from_secs(4));
+        assert_eq!(*library.library[2].location[0].end().unwrap(), expected);
+        assert!(!library.library[3].has_exact_duration());
+        // Songs are only measured once
+        assert!(library.correct_durations(false).corrected.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! Albums released more than once, such as remasters and deluxe editions,
+//! grouped together into one album with each release as an edition
+//!
+//! Every edition can still be played, but only the preferred one is used
+//! when songs are picked automatically, so shuffling the library doesn't
+//! play the same song from three editions
+
+use std::collections::{BTreeMap, HashSet};
+
+use uuid::Uuid;
+
+use super::library::{Album, MusicLibrary, Song, Tag};
+use super::utils::normalize;
+use crate::config::library_settings::LibrarySettings;
+
+/// Words in brackets after an album title which mark an edition
+const EDITION_WORDS: &[&str] = &[
+    "remaster",
+    "deluxe",
+    "expanded",
+    "edition",
+    "anniversary",
+    "reissue",
+    "bonus",
+    "special",
+    "collector",
+    "legacy",
+];
+
+/// One album with all of its editions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumEditions {
+    /// The key preferred editions are stored under, see
+    /// [LibrarySettings::preferred_editions]
+    pub key: String,
+    /// The title without any edition, such as `Abbey Road` for
+    /// `Abbey Road (2019 Remaster)`
+    pub title: String,
+    pub artist: Option<String>,
+    /// The titles of each edition, the original first if there is one
+    pub editions: Vec<String>,
+}
+
+impl AlbumEditions {
+    /// The title of the edition to play, which is the one picked in the
+    /// `settings` or otherwise the original
+    pub fn preferred<'a>(&'a self, settings: &'a LibrarySettings) -> &'a str {
+        settings
+            .preferred_editions
+            .get(&self.key)
+            .filter(|title| self.editions.contains(title))
+            .unwrap_or(&self.editions[0])
+    }
+}
+
+/// The title of an album without any edition in brackets after it
+pub fn base_title(title: &str) -> String {
+    let mut base = title.trim();
+    while let Some(close) = base.chars().last().filter(|c| *c == ')' || *c == ']') {
+        let open = if close == ')' { '(' } else { '[' };
+        let Some(start) = base.rfind(open) else { break };
+        let marker = base[start..].to_lowercase();
+        if !EDITION_WORDS.iter().any(|word| marker.contains(word)) {
+            break;
+        }
+        base = base[..start].trim_end_matches([' ', '-']);
+    }
+    base.to_string()
+}
+
+impl MusicLibrary {
+    /// Every album, with the albums which are editions of each other
+    /// grouped together. Editions are found by their MusicBrainz release
+    /// group, or by their titles and artists.
+    pub fn album_editions(&self) -> Vec<AlbumEditions> {
+        let mut grouped: BTreeMap<String, AlbumEditions> = BTreeMap::new();
+        for (title, album) in self.albums() {
+            let key = edition_key(self, &album);
+            let editions = grouped.entry(key.clone()).or_insert_with(|| AlbumEditions {
+                key,
+                title: base_title(&title),
+                artist: album.artist().clone(),
+                editions: Vec::new(),
+            });
+            editions.editions.push(title);
+        }
+
+        let mut grouped: Vec<AlbumEditions> = grouped.into_values().collect();
+        for album in &mut grouped {
+            // The original comes first, then the editions by title
+            album.editions.sort_by_key(|title| (base_title(title) != *title, title.clone()));
+        }
+        grouped
+    }
+
+    /// The songs which aren't on an edition other than the preferred one
+    pub fn preferred_songs(&self, settings: &LibrarySettings) -> Vec<&Song> {
+        let hidden: HashSet<String> = self
+            .album_editions()
+            .iter()
+            .flat_map(|album| {
+                let preferred = album.preferred(settings);
+                album.editions.iter().filter(move |title| *title != preferred).cloned()
+            })
+            .collect();
+        self.library
+            .iter()
+            .filter(|song| song.get_tag(&Tag::Album).is_none_or(|album| !hidden.contains(album)))
+            .collect()
+    }
+
+    /// The UUIDs of [MusicLibrary::preferred_songs]
+    pub fn preferred_uuids(&self, settings: &LibrarySettings) -> Vec<Uuid> {
+        self.preferred_songs(settings).iter().map(|song| song.uuid).collect()
+    }
+}
+
+/// The key an album's editions are grouped by
+fn edition_key(library: &MusicLibrary, album: &Album) -> String {
+    let release_group = album
+        .track(*album.discs().keys().next().unwrap_or(&1), 0)
+        .and_then(|(_, uuid)| library.query_uuid(uuid))
+        .and_then(|(song, _)| song.get_tag(&Tag::MusicBrainzReleaseGroupId).cloned());
+    match release_group {
+        Some(id) => id,
+        None => format!(
+            "{}:{}",
+            normalize(album.artist().as_deref().unwrap_or_default()),
+            normalize(&base_title(album.title()))
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::base_title;
+    use crate::config::library_settings::LibrarySettings;
+    use crate::music_storage::library::{test::test_song, Tag};
+    use crate::music_storage::memory_store::MemoryStore;
+
+    #[test]
+    fn album_editions() {
+        assert_eq!(base_title("Abbey Road (2019 Remaster)"), "Abbey Road");
+        assert_eq!(base_title("Discovery [Deluxe Edition] (Remastered)"), "Discovery");
+        assert_eq!(base_title("Under Pressure (Live)"), "Under Pressure (Live)");
+
+        let mut songs = Vec::new();
+        for (title, album) in [("Come Together", "Abbey Road"), ("Come Together", "Abbey Road (2019 Remaster)"), ("Help!", "Help!")] {
+            let mut song = test_song(title, "The Beatles", Duration::from_secs(200));
+            song.set_tag(Tag::Album, album.to_string());
+            song.set_tag(Tag::AlbumArtist, "The Beatles".to_string());
+            songs.push(song);
+        }
+        let store = MemoryStore::with_songs(songs);
+        let library = store.library();
+
+        let albums = library.album_editions();
+        assert_eq!(albums.len(), 2);
+        let abbey_road = albums.iter().find(|album| album.title == "Abbey Road").unwrap();
+        assert_eq!(abbey_road.editions, ["Abbey Road", "Abbey Road (2019 Remaster)"]);
+
+        let mut settings = LibrarySettings::default();
+        assert_eq!(abbey_road.preferred(&settings), "Abbey Road");
+        assert_eq!(library.preferred_songs(&settings).len(), 2);
+        settings.preferred_editions.insert(abbey_road.key.clone(), "Abbey Road (2019 Remaster)".to_string());
+        let preferred = library.preferred_songs(&settings);
+        assert_eq!(preferred.len(), 2);
+        assert!(preferred.iter().any(|song| song.get_tag(&Tag::Album).unwrap().contains("Remaster")));
+    }
+}
@@ -0,0 +1,368 @@
+//! Chromaprint fingerprints of songs, which are used to find duplicates
+//! in the library and to look songs up on [AcoustID](https://acoustid.org)
+//!
+//! Fingerprints are computed with `fpcalc`, which must be installed
+//! for songs to be fingerprinted.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use base64::{engine::general_purpose, Engine as _};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::corrections::{CorrectionError, Corrections};
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+const ACOUSTID_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+/// The Chromaprint algorithm used by `fpcalc` by default
+const ALGORITHM: u8 = 1;
+
+/// How far apart, in items, two fingerprints are compared at
+const MAX_OFFSET: usize = 80;
+
+#[derive(Error, Debug)]
+pub enum FingerprintError {
+    #[error("failed to run fpcalc: {0}")]
+    Fpcalc(#[from] std::io::Error),
+    #[error("fpcalc failed: {0}")]
+    FpcalcFailed(String),
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("AcoustID returned an error: {0}")]
+    AcoustId(String),
+    #[error("the song has not been fingerprinted")]
+    NoFingerprint,
+    #[error("{0}")]
+    Correction(#[from] CorrectionError),
+}
+
+/// The raw Chromaprint fingerprint of a song
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The length of the audio which was fingerprinted, in seconds
+    pub duration: u32,
+    pub raw: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+struct FpcalcOutput {
+    duration: f64,
+    fingerprint: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// Fingerprint an audio file with `fpcalc`
+    pub fn compute(path: &Path) -> Result<Self, FingerprintError> {
+        let output = Command::new("fpcalc").arg("-raw").arg("-json").arg(path).output()?;
+        if !output.status.success() {
+            return Err(FingerprintError::FpcalcFailed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        let output: FpcalcOutput = serde_json::from_slice(&output.stdout)?;
+        Ok(Fingerprint {
+            duration: output.duration.round() as u32,
+            raw: output.fingerprint,
+        })
+    }
+
+    /// The fingerprint compressed the same way as Chromaprint does, which
+    /// is the form AcoustID expects
+    pub fn compressed(&self) -> String {
+        // The position of each changed bit relative to the last one, with
+        // a 0 after each item
+        let mut bits = Vec::new();
+        let mut last = 0;
+        for &item in &self.raw {
+            let mut value = item ^ last;
+            last = item;
+            let (mut bit, mut last_bit) = (1, 0);
+            while value != 0 {
+                if value & 1 != 0 {
+                    bits.push(bit - last_bit);
+                    last_bit = bit;
+                }
+                value >>= 1;
+                bit += 1;
+            }
+            bits.push(0);
+        }
+
+        let size = self.raw.len();
+        let mut output = vec![ALGORITHM, (size >> 16) as u8, (size >> 8) as u8, size as u8];
+        // Positions of 7 or more are stored in a second, wider array
+        let normal: Vec<u8> = bits.iter().map(|bit| (*bit).min(7)).collect();
+        let exceptional: Vec<u8> = bits.iter().filter(|bit| **bit >= 7).map(|bit| bit - 7).collect();
+        output.extend(pack(&normal, 3));
+        output.extend(pack(&exceptional, 5));
+        general_purpose::URL_SAFE_NO_PAD.encode(output)
+    }
+
+    /// How alike two fingerprints are, from `0` to `1`, at the offset where
+    /// they line up best. Unrelated songs are around `0.5`.
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        let compare = |a: &[u32], b: &[u32]| {
+            let length = a.len().min(b.len());
+            if length == 0 {
+                return 0.0;
+            }
+            let errors: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+            1.0 - errors as f32 / (length * 32) as f32
+        };
+
+        let offsets = MAX_OFFSET.min(self.raw.len()).min(other.raw.len());
+        (0..offsets)
+            .flat_map(|offset| {
+                [
+                    compare(&self.raw[offset..], &other.raw),
+                    compare(&self.raw, &other.raw[offset..]),
+                ]
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Pack values into bytes, `width` bits at a time starting from the
+/// lowest bit
+fn pack(values: &[u8], width: usize) -> Vec<u8> {
+    let mut packed = vec![0u8; (values.len() * width).div_ceil(8)];
+    for (i, value) in values.iter().enumerate() {
+        for bit in 0..width {
+            if value >> bit & 1 != 0 {
+                let position = i * width + bit;
+                packed[position / 8] |= 1 << (position % 8);
+            }
+        }
+    }
+    packed
+}
+
+impl MusicLibrary {
+    /// Fingerprint every local song which hasn't been yet, returning the
+    /// number of songs which were fingerprinted
+    pub fn fingerprint_songs(&mut self) -> usize {
+        self.library
+            .par_iter_mut()
+            .filter(|song| song.fingerprint().is_none())
+            .map(|song| {
+                let path = match song.location.first() {
+                    Some(URI::Local(path)) => path,
+                    _ => return 0,
+                };
+                match Fingerprint::compute(path) {
+                    Ok(fingerprint) => {
+                        song.set_fingerprint(fingerprint);
+                        1
+                    }
+                    Err(error) => {
+                        println!("Failed to fingerprint {:?}: {}", path, error);
+                        0
+                    }
+                }
+            })
+            .sum()
+    }
+
+    /// Find pairs of songs which sound the same, at least `min_similarity`
+    /// alike, comparing only songs of about the same length
+    pub fn fingerprint_duplicates(&self, min_similarity: f32) -> Vec<(Uuid, Uuid, f32)> {
+        let mut songs: Vec<(&Song, &Fingerprint)> = self
+            .library
+            .iter()
+            .filter_map(|song| Some((song, song.fingerprint()?)))
+            .collect();
+        songs.sort_by_key(|(_, fingerprint)| fingerprint.duration);
+
+        (0..songs.len())
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let (song, fingerprint) = songs[i];
+                songs[i + 1..]
+                    .iter()
+                    .take_while(move |(_, other)| other.duration - fingerprint.duration <= 5)
+                    .filter_map(move |(other_song, other)| {
+                        let similarity = fingerprint.similarity(other);
+                        (similarity >= min_similarity).then_some((song.uuid, other_song.uuid, similarity))
+                    })
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    error: Option<LookupError>,
+}
+
+#[derive(Deserialize)]
+struct LookupError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct LookupResult {
+    score: f32,
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+    id: String,
+    title: Option<String>,
+    #[serde(default)]
+    artists: Vec<Artist>,
+    #[serde(default)]
+    releasegroups: Vec<ReleaseGroup>,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ReleaseGroup {
+    title: String,
+}
+
+/// A recording which AcoustID matched a fingerprint to
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcoustIdMatch {
+    /// How well the fingerprint matched, from `0` to `1`
+    pub score: f32,
+    /// The MusicBrainz ID of the recording
+    pub recording_id: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl AcoustIdMatch {
+    /// The tags to suggest for a song from this match. Existing albums
+    /// are kept, as a recording can be on many of them.
+    fn tags(&self, song: &Song) -> BTreeMap<Tag, Option<String>> {
+        let mut tags = BTreeMap::new();
+        tags.insert(Tag::MusicBrainzRecordingId, Some(self.recording_id.clone()));
+        if let Some(title) = &self.title {
+            tags.insert(Tag::Title, Some(title.clone()));
+        }
+        if let Some(artist) = &self.artist {
+            tags.insert(Tag::Artist, Some(artist.clone()));
+        }
+        if let (None, Some(album)) = (song.get_tag(&Tag::Album), &self.album) {
+            tags.insert(Tag::Album, Some(album.clone()));
+        }
+        tags
+    }
+}
+
+/// A client for the AcoustID lookup API
+#[derive(Debug, Clone)]
+pub struct AcoustId {
+    api_key: String,
+}
+
+impl AcoustId {
+    pub fn new(api_key: String) -> Self {
+        AcoustId { api_key }
+    }
+
+    /// Look up the recordings a fingerprint matches, the best match first
+    pub fn lookup(&self, fingerprint: &Fingerprint) -> Result<Vec<AcoustIdMatch>, FingerprintError> {
+        let response: LookupResponse = attohttpc::get(ACOUSTID_URL)
+            .param("client", &self.api_key)
+            .param("meta", "recordings releasegroups")
+            .param("duration", fingerprint.duration)
+            .param("fingerprint", fingerprint.compressed())
+            .send()?
+            .json()?;
+        if response.status != "ok" {
+            let message = response.error.map_or(response.status, |error| error.message);
+            return Err(FingerprintError::AcoustId(message));
+        }
+
+        let mut matches: Vec<AcoustIdMatch> = response
+            .results
+            .into_iter()
+            .flat_map(|result| {
+                result.recordings.into_iter().map(move |recording| AcoustIdMatch {
+                    score: result.score,
+                    recording_id: recording.id,
+                    title: recording.title,
+                    artist: (!recording.artists.is_empty()).then(|| {
+                        recording
+                            .artists
+                            .iter()
+                            .map(|artist| artist.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }),
+                    album: recording.releasegroups.into_iter().next().map(|group| group.title),
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(matches)
+    }
+
+    /// Look up a fingerprinted song and suggest its best match as a fix,
+    /// returning the id of the fix if anything would change
+    pub fn suggest_fix(
+        &self,
+        library: &MusicLibrary,
+        uuid: &Uuid,
+        corrections: &mut Corrections,
+    ) -> Result<Option<Uuid>, FingerprintError> {
+        let (song, _) = library.query_uuid(uuid).ok_or(CorrectionError::SongNotFound)?;
+        let fingerprint = song.fingerprint().ok_or(FingerprintError::NoFingerprint)?;
+        let best = match self.lookup(fingerprint)?.into_iter().next() {
+            Some(best) => best,
+            None => return Ok(None),
+        };
+        Ok(corrections.suggest(library, uuid, "acoustid", best.score, best.tags(song))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use base64::{engine::general_purpose, Engine as _};
+
+    use super::Fingerprint;
+
+    #[test]
+    fn fingerprint_similarity() {
+        let raw: Vec<u32> = (0..200u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let fingerprint = Fingerprint { duration: 20, raw: raw.clone() };
+
+        // The same audio starting a little later still matches
+        let shifted = Fingerprint { duration: 20, raw: raw[10..].to_vec() };
+        assert_eq!(fingerprint.similarity(&shifted), 1.0);
+        assert_eq!(shifted.similarity(&fingerprint), 1.0);
+
+        let other = Fingerprint {
+            duration: 20,
+            raw: raw.iter().map(|item| item.rotate_left(7) ^ 0x5555_5555).collect(),
+        };
+        assert!(fingerprint.similarity(&other) < 0.7);
+
+        // The header holds the algorithm and the number of items
+        let compressed = general_purpose::URL_SAFE_NO_PAD.decode(fingerprint.compressed()).unwrap();
+        assert_eq!(compressed[..4], [1, 0, 0, 200]);
+        let single = Fingerprint { duration: 1, raw: vec![0b1001] };
+        // Bits 1 and 4 are set, which are 1 and 3 apart, then the end of the item
+        let compressed = general_purpose::URL_SAFE_NO_PAD.decode(single.compressed()).unwrap();
+        assert_eq!(compressed, [1, 0, 0, 1, 0b11_001, 0]);
+    }
+}
@@ -0,0 +1,342 @@
+//! A remote library provider for the music libraries of a Jellyfin
+//! server, which reports playback back to the server so the play
+//! state stays in sync with other clients
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::library::{Service, Song, Tag, URI};
+use super::remote::{remote_song, PlaybackReport, RemoteLibrary};
+
+const CLIENT_NAME: &str = "dmp-core";
+const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Jellyfin measures time in ticks of 100 nanoseconds
+const TICKS_PER_SECOND: u64 = 10_000_000;
+
+/// The session and settings for a Jellyfin server, stored in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JellyfinConfig {
+    /// The base URL of the server, such as `https://jellyfin.example.com`
+    pub url: String,
+    pub user_id: String,
+    /// The access token given by the server when logging in
+    pub token: String,
+    /// Identifies this device to the server, so its sessions are kept apart
+    pub device_id: String,
+    /// Ask the server to transcode streams to this container, such as `opus`
+    pub transcode_format: Option<String>,
+    /// The maximum bitrate of streams in kbps, `None` for no limit
+    pub max_bit_rate: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum JellyfinError {
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("not logged in to the server")]
+    NotLoggedIn,
+}
+
+/// An item as it is returned by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct JellyfinItem {
+    pub id: String,
+    pub name: String,
+    pub album: Option<String>,
+    pub album_id: Option<String>,
+    pub album_artist: Option<String>,
+    pub artists: Vec<String>,
+    pub genres: Vec<String>,
+    pub index_number: Option<u32>,
+    pub parent_index_number: Option<u32>,
+    pub production_year: Option<u32>,
+    /// The duration in ticks
+    pub run_time_ticks: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct ItemsResponse {
+    items: Vec<JellyfinItem>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct AuthUser {
+    id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct AuthResponse {
+    user: AuthUser,
+    access_token: String,
+}
+
+/// A client for a single Jellyfin server
+#[derive(Debug, Clone)]
+pub struct JellyfinClient {
+    config: JellyfinConfig,
+}
+
+impl JellyfinClient {
+    pub fn new(config: JellyfinConfig) -> Self {
+        JellyfinClient { config }
+    }
+
+    pub fn config(&self) -> &JellyfinConfig {
+        &self.config
+    }
+
+    /// Log in to a server with a username and password, returning a
+    /// config holding the new session which can be saved
+    pub fn login(url: &str, username: &str, password: &str) -> Result<JellyfinConfig, JellyfinError> {
+        let mut config = JellyfinConfig {
+            url: url.trim_end_matches('/').to_string(),
+            device_id: Uuid::new_v4().simple().to_string(),
+            ..Default::default()
+        };
+
+        let response: AuthResponse = attohttpc::post(format!("{}/Users/AuthenticateByName", config.url))
+            .header("Authorization", Self::authorization(&config))
+            .json(&json!({ "Username": username, "Pw": password }))?
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        config.user_id = response.user.id;
+        config.token = response.access_token;
+        Ok(config)
+    }
+
+    /// The `Authorization` header which identifies this client and session
+    fn authorization(config: &JellyfinConfig) -> String {
+        let mut header = format!(
+            "MediaBrowser Client=\"{}\", Device=\"{}\", DeviceId=\"{}\", Version=\"{}\"",
+            CLIENT_NAME, CLIENT_NAME, config.device_id, CLIENT_VERSION
+        );
+        if !config.token.is_empty() {
+            header.push_str(&format!(", Token=\"{}\"", config.token));
+        }
+        header
+    }
+
+    fn url(&self, path: &str) -> Result<String, JellyfinError> {
+        if self.config.token.is_empty() {
+            return Err(JellyfinError::NotLoggedIn);
+        }
+        Ok(format!("{}{}", self.config.url.trim_end_matches('/'), path))
+    }
+
+    /// Get the items of the user matching the query parameters
+    fn items(&self, params: &[(&str, String)]) -> Result<Vec<JellyfinItem>, JellyfinError> {
+        let mut url = self.url(&format!("/Users/{}/Items?Recursive=true", self.config.user_id))?;
+        for (key, value) in params {
+            url.push_str(&format!("&{}={}", key, urlencoding::encode(value)));
+        }
+
+        let response: ItemsResponse = attohttpc::get(url)
+            .header("Authorization", Self::authorization(&self.config))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(response.items)
+    }
+
+    /// Post a JSON body to the server, ignoring the response
+    fn post(&self, path: &str, body: serde_json::Value) -> Result<(), JellyfinError> {
+        attohttpc::post(self.url(path)?)
+            .header("Authorization", Self::authorization(&self.config))
+            .json(&body)?
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Search the songs of the server
+    pub fn search(&self, query: &str, count: u32, offset: u32) -> Result<Vec<Song>, JellyfinError> {
+        let items = self.items(&[
+            ("IncludeItemTypes", String::from("Audio")),
+            ("SearchTerm", query.to_string()),
+            ("StartIndex", offset.to_string()),
+            ("Limit", count.to_string()),
+        ])?;
+        Ok(items.iter().map(|item| self.to_song(item)).collect())
+    }
+
+    /// List the albums on the server alphabetically, without their songs
+    pub fn albums(&self, count: u32, offset: u32) -> Result<Vec<JellyfinItem>, JellyfinError> {
+        self.items(&[
+            ("IncludeItemTypes", String::from("MusicAlbum")),
+            ("SortBy", String::from("SortName")),
+            ("StartIndex", offset.to_string()),
+            ("Limit", count.to_string()),
+        ])
+    }
+
+    /// Get the songs of the album with the given item ID
+    pub fn album_songs(&self, id: &str) -> Result<Vec<Song>, JellyfinError> {
+        let items = self.items(&[
+            ("IncludeItemTypes", String::from("Audio")),
+            ("ParentId", id.to_string()),
+            ("SortBy", String::from("ParentIndexNumber,IndexNumber")),
+        ])?;
+        Ok(items.iter().map(|item| self.to_song(item)).collect())
+    }
+
+    /// The item ID of a song, if the [URI] points to one
+    pub fn item_id(uri: &URI) -> Option<String> {
+        match uri {
+            URI::Remote(Service::Jellyfin, id) => Some(id.clone()),
+            _ => None,
+        }
+    }
+
+    /// The URL to stream a song from, with the transcoding settings applied
+    pub fn stream_url(&self, id: &str) -> Result<String, JellyfinError> {
+        let mut url = self.url(&format!("/Audio/{}/stream?api_key={}", id, self.config.token))?;
+        match (&self.config.transcode_format, self.config.max_bit_rate) {
+            (None, None) => url.push_str("&static=true"),
+            (format, max_bit_rate) => {
+                if let Some(format) = format {
+                    url.push_str(&format!("&container={}", urlencoding::encode(format)));
+                }
+                if let Some(max_bit_rate) = max_bit_rate {
+                    url.push_str(&format!("&audioBitRate={}", max_bit_rate * 1000));
+                }
+            }
+        }
+        Ok(url)
+    }
+
+    /// The URL of the primary image of an item
+    pub fn image_url(&self, id: &str) -> Result<String, JellyfinError> {
+        self.url(&format!("/Items/{}/Images/Primary?api_key={}", id, self.config.token))
+    }
+
+    /// Convert an item from the server into a [Song], with a location
+    /// of its item ID so no credentials are stored in the library
+    pub fn to_song(&self, item: &JellyfinItem) -> Song {
+        let ticks = item.run_time_ticks.unwrap_or(0);
+        let artist = match item.artists.is_empty() {
+            true => None,
+            false => Some(item.artists.join(", ")),
+        };
+
+        remote_song(
+            URI::Remote(Service::Jellyfin, item.id.clone()),
+            Duration::from_nanos(ticks.saturating_mul(100)),
+            vec![
+                (Tag::Title, Some(item.name.clone())),
+                (Tag::Album, item.album.clone()),
+                (Tag::Artist, artist),
+                (Tag::AlbumArtist, item.album_artist.clone()),
+                (Tag::Genre, item.genres.first().cloned()),
+                (Tag::Track, item.index_number.map(|t| t.to_string())),
+                (Tag::Disk, item.parent_index_number.map(|d| d.to_string())),
+                (Tag::Key("YEAR".to_string()), item.production_year.map(|y| y.to_string())),
+            ],
+            Some(URI::Remote(
+                Service::Jellyfin,
+                item.album_id.clone().unwrap_or_else(|| item.id.clone()),
+            )),
+        )
+    }
+
+    /// Mark an item as played by the user
+    pub fn mark_played(&self, id: &str) -> Result<(), JellyfinError> {
+        self.post(
+            &format!("/Users/{}/PlayedItems/{}", self.config.user_id, id),
+            json!({}),
+        )
+    }
+}
+
+impl RemoteLibrary for JellyfinClient {
+    fn resolve(&self, uri: &URI) -> Option<Result<URI, Box<dyn Error>>> {
+        let id = Self::item_id(uri)?;
+        Some(match self.stream_url(&id) {
+            Ok(url) => Ok(URI::Remote(Service::None, url)),
+            Err(error) => Err(error.into()),
+        })
+    }
+
+    fn report_playback(
+        &self,
+        uri: &URI,
+        report: PlaybackReport,
+        position: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = match Self::item_id(uri) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let ticks = position.as_secs() * TICKS_PER_SECOND
+            + position.subsec_nanos() as u64 / 100;
+        match report {
+            PlaybackReport::Started => self.post(
+                "/Sessions/Playing",
+                json!({ "ItemId": id, "PositionTicks": ticks, "CanSeek": true }),
+            )?,
+            PlaybackReport::Progress | PlaybackReport::Paused => self.post(
+                "/Sessions/Playing/Progress",
+                json!({
+                    "ItemId": id,
+                    "PositionTicks": ticks,
+                    "IsPaused": report == PlaybackReport::Paused,
+                }),
+            )?,
+            PlaybackReport::Stopped { finished } => {
+                self.post(
+                    "/Sessions/Playing/Stopped",
+                    json!({ "ItemId": id, "PositionTicks": ticks }),
+                )?;
+                if finished {
+                    self.mark_played(&id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ItemsResponse, JellyfinClient, JellyfinConfig};
+    use crate::music_storage::library::{Service, Tag, URI};
+
+    #[test]
+    fn jellyfin_songs() {
+        let client = JellyfinClient::new(JellyfinConfig {
+            url: String::from("https://jellyfin.example.com/"),
+            user_id: String::from("user"),
+            token: String::from("token"),
+            ..Default::default()
+        });
+        assert_eq!(
+            client.stream_url("42").unwrap(),
+            "https://jellyfin.example.com/Audio/42/stream?api_key=token&static=true"
+        );
+
+        let json = r#"{"Items": [{"Id": "42", "Name": "Song", "Album": "Album",
+            "AlbumId": "7", "Artists": ["A", "B"], "IndexNumber": 3,
+            "RunTimeTicks": 1800000000}], "TotalRecordCount": 1}"#;
+        let response: ItemsResponse = serde_json::from_str(json).unwrap();
+
+        let song = client.to_song(&response.items[0]);
+        assert_eq!(song.location[0], URI::Remote(Service::Jellyfin, String::from("42")));
+        assert_eq!(song.get_tag(&Tag::Artist), Some(&String::from("A, B")));
+        assert_eq!(song.get_tag(&Tag::Track), Some(&String::from("3")));
+        assert_eq!(song.duration.as_secs(), 180);
+        assert_eq!(JellyfinClient::item_id(&song.location[0]), Some(String::from("42")));
+    }
+}
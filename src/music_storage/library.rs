@@ -1,11 +1,22 @@
+use super::chapters::{read_chapters, Chapter};
+use super::credits::add_credit;
+use super::cue::CueSheet;
+use super::fingerprint::Fingerprint;
+use super::lyrics::Lyrics;
+use super::path_remap::PathRemap;
+use super::release_type::ReleaseType;
+use super::relocate::FileIdentity;
+use super::silence::SilenceTrim;
 use super::playlist::PlaylistFolder;
 // Crate things
-use super::utils::{find_images, normalize, read_file, write_file};
-use crate::config::Config;
+use super::library_format::{library_exists, read_library, write_library};
+use super::utils::{find_images, normalize};
+use crate::config::library_settings::LibrarySettings;
+use crate::config::{Config, ConfigLibrary, LibraryRoot};
 
 use std::cmp::Ordering;
 // Various std things
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::ops::ControlFlow::{Break, Continue};
 use std::vec::IntoIter;
@@ -14,12 +25,13 @@ use std::vec::IntoIter;
 use file_format::{FileFormat, Kind};
 use glib::filename_to_uri;
 
-use lofty::{AudioFile, ItemKey, ItemValue, ParseOptions, Probe, TagType, TaggedFileExt};
-use rcue::parser::parse_from_file;
+use lofty::id3::v2::Popularimeter;
+use lofty::{AudioFile, ItemKey, ItemValue, ParseOptions, Probe, TagExt, TagItem, TagType, TaggedFileExt};
 use std::fs;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 use walkdir::WalkDir;
+use crossbeam_channel::Sender;
 
 // Time
 use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
@@ -31,6 +43,7 @@ use serde::{Deserialize, Serialize};
 
 // Fun parallel stuff
 use rayon::prelude::*;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::{Arc, Mutex, RwLock};
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -62,6 +75,27 @@ pub enum Tag {
     Disk,
     Key(String),
     Field(String),
+    /// The date the song was released, such as `2011-03-27` or `2011`
+    Date,
+    MusicBrainzRecordingId,
+    MusicBrainzReleaseId,
+    MusicBrainzReleaseGroupId,
+    MusicBrainzArtistId,
+    MusicBrainzAlbumArtistId,
+    /// The tempo of the song in beats per minute
+    Bpm,
+    /// The musical key of the song, such as `Am`
+    InitialKey,
+    /// The people credited with a role on the song, see
+    /// [credits](super::credits)
+    Remixer,
+    Producer,
+    Featured,
+    DjMixer,
+    /// Such as `album` or `album; live`, see [ReleaseType]
+    ReleaseType,
+    /// Whether the song was ripped accurately, see [rip_log](super::rip_log)
+    RipStatus,
 }
 
 impl ToString for Tag {
@@ -77,6 +111,20 @@ impl ToString for Tag {
             Self::Disk => "DiscNumber".into(),
             Self::Key(key) => key.into(),
             Self::Field(f) => f.into(),
+            Self::Date => "RecordingDate".into(),
+            Self::MusicBrainzRecordingId => "MusicBrainzRecordingId".into(),
+            Self::MusicBrainzReleaseId => "MusicBrainzReleaseId".into(),
+            Self::MusicBrainzReleaseGroupId => "MusicBrainzReleaseGroupId".into(),
+            Self::MusicBrainzArtistId => "MusicBrainzArtistId".into(),
+            Self::MusicBrainzAlbumArtistId => "MusicBrainzReleaseArtistId".into(),
+            Self::Bpm => "Bpm".into(),
+            Self::InitialKey => "InitialKey".into(),
+            Self::Remixer => "Remixer".into(),
+            Self::Producer => "Producer".into(),
+            Self::Featured => "Featured".into(),
+            Self::DjMixer => "MixDj".into(),
+            Self::ReleaseType => "ReleaseType".into(),
+            Self::RipStatus => "RipStatus".into(),
         }
     }
 }
@@ -95,6 +143,8 @@ pub enum Field {
     LastPlayed(DateTime<Utc>),
     DateAdded(DateTime<Utc>),
     DateModified(DateTime<Utc>),
+    Labels(Vec<String>),
+    Notes(String),
 }
 
 impl ToString for Field {
@@ -114,6 +164,8 @@ impl ToString for Field {
             Self::LastPlayed(last) => last.to_rfc2822(),
             Self::DateAdded(added) => added.to_rfc2822(),
             Self::DateModified(modified) => modified.to_rfc2822(),
+            Self::Labels(labels) => labels.join(", "),
+            Self::Notes(notes) => notes.clone(),
         }
     }
 }
@@ -126,6 +178,27 @@ pub enum InternalTag {
     SongLink(Uuid, SongType),
     // Volume Adjustment from -100% to 100%
     VolumeAdjustment(i8),
+    /// A user-defined label, such as "workout", which is stored only in the library
+    Label(String),
+    /// Free-form notes about the song, stored only in the library
+    Note(String),
+    /// Free-form notes about the album of the song, kept on each of its songs
+    AlbumNote(String),
+    /// A label given to the album of the song, kept on each of its songs
+    /// and given to songs added to the album later
+    AlbumLabel(String),
+    /// The chapters found in the file, such as those of an audiobook
+    Chapters(Vec<Chapter>),
+    /// The size and hash of the file, used to find it again if it is moved
+    FileIdentity(FileIdentity),
+    /// The Chromaprint fingerprint of the song
+    Fingerprint(Fingerprint),
+    /// The lyrics of the song, from its tags, an `.lrc` file, or online
+    Lyrics(Lyrics),
+    /// The duration was measured from the audio instead of the headers of the file
+    ExactDuration,
+    /// Where the sound of the song starts and ends, see [silence](super::silence)
+    SilenceTrim(SilenceTrim),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -143,6 +216,10 @@ pub enum DoNotTrack {
     LibreFM,
     MusicBrainz,
     Discord,
+    /// Play counts and the listening history
+    History,
+    /// Reporting plays to any service, such as the scrobbles of remote libraries
+    Scrobbling,
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -154,6 +231,8 @@ pub enum SongType {
     Instrumental,
     Remix,
     Custom(String),
+    /// An audiobook, which is resumed from its bookmark rather than the start
+    Audiobook,
 }
 
 /// Stores information about a single song
@@ -207,6 +286,14 @@ impl Song {
             "duration" => Some(Field::Duration(self.duration)),
             "play_time" => Some(Field::PlayTime(self.play_time)),
             "format" => self.format.map(Field::Format),
+            "labels" => Some(Field::Labels(self.labels().into_iter().cloned().collect())),
+            "notes" => {
+                let notes: Vec<&String> = self.notes().into_iter().chain(self.album_notes()).collect();
+                match notes.is_empty() {
+                    true => None,
+                    false => Some(Field::Notes(notes.into_iter().cloned().collect::<Vec<_>>().join("\n"))),
+                }
+            }
             _ => todo!(), // Other field types are not yet supported
         }
     }
@@ -221,6 +308,225 @@ impl Song {
         self.tags.remove(target_key);
     }
 
+    /// Gets the user-defined labels of the song
+    pub fn labels(&self) -> Vec<&String> {
+        self.internal_tags
+            .iter()
+            .filter_map(|tag| match tag {
+                InternalTag::Label(label) | InternalTag::AlbumLabel(label) => Some(label),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Gets the labels of the album the song is on
+    pub fn album_labels(&self) -> Vec<&String> {
+        self.internal_tags
+            .iter()
+            .filter_map(|tag| match tag {
+                InternalTag::AlbumLabel(label) => Some(label),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the song has a label, ignoring case
+    pub fn has_label(&self, label: &str) -> bool {
+        let label = label.trim().to_lowercase();
+        self.labels().iter().any(|l| l.to_lowercase() == label)
+    }
+
+    /// Adds a label to the song, returning `false` if it already had it
+    pub fn add_label(&mut self, label: &str) -> bool {
+        let label = label.trim();
+        if label.is_empty() || self.has_label(label) {
+            return false;
+        }
+        self.internal_tags.push(InternalTag::Label(label.to_string()));
+        true
+    }
+
+    /// Adds a label to the album of the song, returning `false` if the
+    /// song already had it
+    pub fn add_album_label(&mut self, label: &str) -> bool {
+        let label = label.trim();
+        if label.is_empty() {
+            return false;
+        }
+        let had = self.has_label(label);
+        self.remove_label(label);
+        self.internal_tags.push(InternalTag::AlbumLabel(label.to_string()));
+        !had
+    }
+
+    /// Removes a label from the song, whether it was given to the song or
+    /// its album, returning `false` if it didn't have it
+    pub fn remove_label(&mut self, label: &str) -> bool {
+        let label = label.trim().to_lowercase();
+        let len = self.internal_tags.len();
+        self.internal_tags.retain(|tag| {
+            !matches!(tag, InternalTag::Label(l) | InternalTag::AlbumLabel(l) if l.to_lowercase() == label)
+        });
+        self.internal_tags.len() != len
+    }
+
+    /// Removes a label given to the album of the song, returning `false`
+    /// if it didn't have it
+    pub fn remove_album_label(&mut self, label: &str) -> bool {
+        let label = label.trim().to_lowercase();
+        let len = self.internal_tags.len();
+        self.internal_tags
+            .retain(|tag| !matches!(tag, InternalTag::AlbumLabel(l) if l.to_lowercase() == label));
+        self.internal_tags.len() != len
+    }
+
+    /// Gets the notes of the song
+    pub fn notes(&self) -> Option<&String> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::Note(notes) => Some(notes),
+            _ => None,
+        })
+    }
+
+    /// Gets the notes of the album the song is on
+    pub fn album_notes(&self) -> Option<&String> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::AlbumNote(notes) => Some(notes),
+            _ => None,
+        })
+    }
+
+    /// Sets the notes of the song, removing them if `notes` is `None` or blank
+    pub fn set_notes(&mut self, notes: Option<&str>) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::Note(_)));
+        if let Some(notes) = notes.map(str::trim).filter(|n| !n.is_empty()) {
+            self.internal_tags.push(InternalTag::Note(notes.to_string()));
+        }
+    }
+
+    /// Sets the album notes of the song, removing them if `notes` is `None`
+    /// or blank, returning `false` if they were already the same
+    pub fn set_album_notes(&mut self, notes: Option<&str>) -> bool {
+        let notes = notes.map(str::trim).filter(|n| !n.is_empty());
+        if self.album_notes().map(String::as_str) == notes {
+            return false;
+        }
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::AlbumNote(_)));
+        if let Some(notes) = notes {
+            self.internal_tags.push(InternalTag::AlbumNote(notes.to_string()));
+        }
+        true
+    }
+
+    /// Gets how much louder or quieter the song is played, from `-100`%
+    /// to `100`%
+    pub fn volume_adjustment(&self) -> i8 {
+        self.internal_tags
+            .iter()
+            .find_map(|tag| match tag {
+                InternalTag::VolumeAdjustment(adjustment) => Some(*adjustment),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// Sets how much louder or quieter the song is played, removing the
+    /// adjustment if it's `0`
+    pub fn set_volume_adjustment(&mut self, adjustment: i8) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::VolumeAdjustment(_)));
+        if adjustment != 0 {
+            self.internal_tags.push(InternalTag::VolumeAdjustment(adjustment.clamp(-100, 100)));
+        }
+    }
+
+    /// Gets the type of the song, such as [SongType::Audiobook]
+    pub fn song_type(&self) -> SongType {
+        self.internal_tags
+            .iter()
+            .find_map(|tag| match tag {
+                InternalTag::SongType(song_type) => Some(song_type.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sets the type of the song
+    pub fn set_song_type(&mut self, song_type: SongType) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::SongType(_)));
+        if song_type != SongType::Main {
+            self.internal_tags.push(InternalTag::SongType(song_type));
+        }
+    }
+
+    pub fn is_audiobook(&self) -> bool {
+        self.song_type() == SongType::Audiobook
+    }
+
+    /// Gets the chapters of the song, which are read when it is added
+    pub fn chapters(&self) -> &[Chapter] {
+        self.internal_tags
+            .iter()
+            .find_map(|tag| match tag {
+                InternalTag::Chapters(chapters) => Some(chapters.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Gets the identity of the song's file, recorded when it was added
+    pub fn file_identity(&self) -> Option<&FileIdentity> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::FileIdentity(identity) => Some(identity),
+            _ => None,
+        })
+    }
+
+    pub fn set_file_identity(&mut self, identity: FileIdentity) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::FileIdentity(_)));
+        self.internal_tags.push(InternalTag::FileIdentity(identity));
+    }
+
+    /// Whether plays of the song are tracked in the way given by `kind`
+    pub fn is_tracked(&self, kind: &DoNotTrack) -> bool {
+        !self.internal_tags.iter().any(|tag| matches!(tag, InternalTag::DoNotTrack(k) if k == kind))
+    }
+
+    /// Sets whether plays of the song are tracked in the way given by `kind`
+    pub fn set_tracked(&mut self, kind: DoNotTrack, tracked: bool) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::DoNotTrack(k) if *k == kind));
+        if !tracked {
+            self.internal_tags.push(InternalTag::DoNotTrack(kind));
+        }
+    }
+
+    /// Gets the fingerprint of the song, if it has been fingerprinted
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::Fingerprint(fingerprint) => Some(fingerprint),
+            _ => None,
+        })
+    }
+
+    pub fn set_fingerprint(&mut self, fingerprint: Fingerprint) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::Fingerprint(_)));
+        self.internal_tags.push(InternalTag::Fingerprint(fingerprint));
+    }
+
+    pub fn lyrics(&self) -> Option<&Lyrics> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::Lyrics(lyrics) => Some(lyrics),
+            _ => None,
+        })
+    }
+
+    /// Sets the lyrics of the song, removing them if `lyrics` is `None`
+    pub fn set_lyrics(&mut self, lyrics: Option<Lyrics>) {
+        self.internal_tags.retain(|tag| !matches!(tag, InternalTag::Lyrics(_)));
+        if let Some(lyrics) = lyrics {
+            self.internal_tags.push(InternalTag::Lyrics(lyrics));
+        }
+    }
+
     /// Creates a `Song` from a music file
     pub fn from_file<P: ?Sized + AsRef<Path>>(target_file: &P) -> Result<Self, Box<dyn Error>> {
         let normal_options = ParseOptions::new().parsing_mode(lofty::ParsingMode::Relaxed);
@@ -259,6 +565,29 @@ impl Song {
                 ItemKey::Comment => Tag::Comment,
                 ItemKey::AlbumTitle => Tag::Album,
                 ItemKey::DiscNumber => Tag::Disk,
+                ItemKey::RecordingDate => Tag::Date,
+                ItemKey::MusicBrainzRecordingId => Tag::MusicBrainzRecordingId,
+                ItemKey::MusicBrainzReleaseId => Tag::MusicBrainzReleaseId,
+                ItemKey::MusicBrainzReleaseGroupId => Tag::MusicBrainzReleaseGroupId,
+                ItemKey::MusicBrainzArtistId => Tag::MusicBrainzArtistId,
+                ItemKey::MusicBrainzReleaseArtistId => Tag::MusicBrainzAlbumArtistId,
+                ItemKey::Bpm | ItemKey::IntegerBpm => Tag::Bpm,
+                ItemKey::InitialKey => Tag::InitialKey,
+                ItemKey::Remixer => Tag::Remixer,
+                ItemKey::Producer => Tag::Producer,
+                ItemKey::MixDj => Tag::DjMixer,
+                ItemKey::Unknown(unknown)
+                    if unknown.eq_ignore_ascii_case("RELEASETYPE")
+                        || unknown.eq_ignore_ascii_case("MUSICBRAINZ_ALBUMTYPE")
+                        || unknown.ends_with("MusicBrainz Album Type") =>
+                {
+                    Tag::ReleaseType
+                }
+                ItemKey::Unknown(unknown)
+                    if unknown.eq_ignore_ascii_case("FEATURING") || unknown.eq_ignore_ascii_case("FEATURED_ARTIST") =>
+                {
+                    Tag::Featured
+                }
                 ItemKey::Unknown(unknown)
                     if unknown == "ACOUSTID_FINGERPRINT" || unknown == "Acoustid Fingerprint" =>
                 {
@@ -274,7 +603,15 @@ impl Song {
                 ItemValue::Binary(bin) => format!("BIN#{}", general_purpose::STANDARD.encode(bin)),
             };
 
-            tags.insert(key, value);
+            // Credits can be repeated, once for each person
+            match key {
+                Tag::Remixer | Tag::Producer | Tag::Featured | Tag::DjMixer => {
+                    add_credit(tags.entry(key).or_default(), &value)
+                }
+                key => {
+                    tags.insert(key, value);
+                }
+            }
         }
 
         // Get all the album artwork information from the file
@@ -298,9 +635,24 @@ impl Song {
         // TODO: Fix error handling
         let binding = fs::canonicalize(target_file).unwrap();
 
-        // TODO: Handle creation of internal tag: Song Type and Song Links
-        let internal_tags = { Vec::new() };
-        let new_song = Song {
+        // TODO: Handle creation of internal tag: Song Links
+        let mut internal_tags = Vec::new();
+        let chapters = read_chapters(target_file).unwrap_or_default();
+        let is_m4b = target_file
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("m4b"));
+        let genre = tags.get(&Tag::Genre).map(|genre| genre.to_lowercase());
+        if is_m4b || genre.is_some_and(|genre| genre.contains("audiobook")) {
+            internal_tags.push(InternalTag::SongType(SongType::Audiobook));
+        }
+        if !chapters.is_empty() {
+            internal_tags.push(InternalTag::Chapters(chapters));
+        }
+        if let Ok(identity) = FileIdentity::read(&binding) {
+            internal_tags.push(InternalTag::FileIdentity(identity));
+        }
+        let mut new_song = Song {
             location: vec![URI::Local(binding)],
             uuid: Uuid::new_v4(),
             plays: 0,
@@ -318,131 +670,132 @@ impl Song {
             album_art,
             internal_tags,
         };
+        if let Some(lyrics) = Lyrics::read(&new_song) {
+            new_song.set_lyrics(Some(lyrics));
+        }
         Ok(new_song)
     }
 
     /// creates a `Vec<Song>` from a cue file
     pub fn from_cue(cuesheet: &Path) -> Result<Vec<(Self, PathBuf)>, Box<dyn Error>> {
-        let mut tracks = Vec::new();
+        let sheet = CueSheet::read_file(cuesheet)?;
 
-        let cue_data = parse_from_file(&cuesheet.to_string_lossy(), false).unwrap();
+        let parent_dir = cuesheet.parent().expect("The file has no parent path??");
+        let files: Vec<PathBuf> = sheet.files.iter().map(|file| parent_dir.join(file)).collect();
+        Self::from_cue_sheet(&sheet, &files, None)
+    }
 
-        // Get album level information
-        let album_title = &cue_data.title;
-        let album_artist = &cue_data.performer;
+    /// Creates a `Vec<Song>` from a cue sheet embedded in the `CUESHEET`
+    /// tag of a file, such as a FLAC image of a whole CD. The tags and art
+    /// of `song` are used for any which the cue sheet doesn't have.
+    ///
+    /// Returns `None` if the song has no embedded cue sheet.
+    pub fn from_embedded_cue(song: &Song) -> Option<Result<Vec<Self>, Box<dyn Error>>> {
+        let contents = song.get_tag(&Tag::Key(String::from("CUESHEET")))?;
+        let path = match song.location.first() {
+            Some(URI::Local(path)) => path.clone(),
+            _ => return None,
+        };
 
-        let parent_dir = cuesheet.parent().expect("The file has no parent path??");
-        for file in cue_data.files.iter() {
-            let audio_location = &parent_dir.join(file.file.clone());
+        let songs = CueSheet::parse(contents).map_err(|e| e.into()).and_then(|sheet| {
+            // The sheet's files all refer to the file it is embedded in
+            let files = vec![path; sheet.files.len()];
+            Self::from_cue_sheet(&sheet, &files, Some(song))
+        });
+        Some(songs.map(|tracks| tracks.into_iter().map(|(song, _)| song).collect()))
+    }
 
+    /// Create the virtual tracks of a cue sheet, where `files` are the
+    /// locations of each of the sheet's files
+    fn from_cue_sheet(
+        sheet: &CueSheet,
+        files: &[PathBuf],
+        base: Option<&Song>,
+    ) -> Result<Vec<(Self, PathBuf)>, Box<dyn Error>> {
+        let mut tracks = Vec::new();
+        let mut durations: BTreeMap<usize, Duration> = BTreeMap::new();
+
+        for range in sheet.ranges() {
+            let track = &sheet.tracks[range.track];
+            let audio_location = &files[range.file];
             if !audio_location.exists() {
                 continue;
             }
 
-            let next_track = file.tracks.clone();
-            let mut next_track = next_track.iter().skip(1);
-            for (i, track) in file.tracks.iter().enumerate() {
-                // Get the track timing information
-                let pregap = match track.pregap {
-                    Some(pregap) => pregap,
-                    None => Duration::from_secs(0),
-                };
-                let postgap = match track.postgap {
-                    Some(postgap) => postgap,
-                    None => Duration::from_secs(0),
-                };
-
-                let mut start;
-                if track.indices.len() > 1 {
-                    start = track.indices[1].1;
-                } else {
-                    start = track.indices[0].1;
-                }
-                if !start.is_zero() {
-                    start -= pregap;
-                }
-
-                let duration = match next_track.next() {
-                    Some(future) => match future.indices.first() {
-                        Some(val) => val.1 - start,
-                        None => Duration::from_secs(0),
-                    },
-                    None => match lofty::read_from_path(audio_location) {
-                        Ok(tagged_file) => tagged_file.properties().duration() - start,
-
-                        Err(_) => match Probe::open(audio_location)?.read() {
-                            Ok(tagged_file) => tagged_file.properties().duration() - start,
+            let end = match range.end {
+                Some(end) => end,
+                None => *durations.entry(range.file).or_insert_with(|| {
+                    match Probe::open(audio_location).and_then(|probe| probe.read()) {
+                        Ok(tagged_file) => tagged_file.properties().duration(),
+                        Err(_) => Duration::from_secs(0),
+                    }
+                }),
+            };
+            let duration = end.saturating_sub(range.start);
 
-                            Err(_) => Duration::from_secs(0),
-                        },
-                    },
-                };
-                let end = start + duration + postgap;
+            // Get the format as a string
+            let format: Option<FileFormat> = match FileFormat::from_file(audio_location) {
+                Ok(fmt) => Some(fmt),
+                Err(_) => None,
+            };
 
-                // Get the format as a string
-                let format: Option<FileFormat> = match FileFormat::from_file(audio_location) {
-                    Ok(fmt) => Some(fmt),
-                    Err(_) => None,
-                };
+            // Start from the tags of the whole file, then the sheet, then the track
+            let mut tags: BTreeMap<Tag, String> = base.map(|song| song.tags.clone()).unwrap_or_default();
+            tags.remove(&Tag::Key(String::from("CUESHEET")));
+            if let Some(title) = &sheet.title {
+                tags.insert(Tag::Album, title.clone());
+            }
+            if let Some(artist) = &sheet.performer {
+                tags.insert(Tag::AlbumArtist, artist.clone());
+                tags.insert(Tag::Artist, artist.clone());
+            }
+            if let Some(genre) = sheet.comments.get("GENRE") {
+                tags.insert(Tag::Genre, genre.clone());
+            }
+            if let Some(date) = sheet.comments.get("DATE") {
+                tags.insert(Tag::Key(String::from("YEAR")), date.clone());
+            }
+            tags.insert(Tag::Track, track.number.to_string());
+            let title = match (&track.title, &track.isrc) {
+                (Some(title), _) => title.clone(),
+                (None, Some(isrc)) => isrc.clone(),
+                (None, None) => format!("{} - {}", track.number, sheet.files[range.file]),
+            };
+            tags.insert(Tag::Title, title);
+            if let Some(artist) = &track.performer {
+                tags.insert(Tag::Artist, artist.clone());
+            }
 
-                // Get some useful tags
-                let mut tags: BTreeMap<Tag, String> = BTreeMap::new();
-                match album_title {
-                    Some(title) => {
-                        tags.insert(Tag::Album, title.clone());
-                    }
-                    None => (),
-                }
-                match album_artist {
-                    Some(artist) => {
-                        tags.insert(Tag::Artist, artist.clone());
-                    }
-                    None => (),
-                }
-                tags.insert(Tag::Track, track.no.parse().unwrap_or((i + 1).to_string()));
-                match track.title.clone() {
-                    Some(title) => tags.insert(Tag::Title, title),
-                    None => match track.isrc.clone() {
-                        Some(title) => tags.insert(Tag::Title, title),
-                        None => {
-                            let namestr = format!("{} - {}", i, file.file.clone());
-                            tags.insert(Tag::Title, namestr)
-                        }
-                    },
-                };
-                match track.performer.clone() {
-                    Some(artist) => tags.insert(Tag::Artist, artist),
-                    None => None,
-                };
+            // Find images around the music file that can be used
+            let album_art = match base {
+                Some(song) => song.album_art.clone(),
+                None => find_images(&audio_location.to_path_buf()).unwrap(),
+            };
 
-                // Find images around the music file that can be used
-                let album_art = find_images(&audio_location.to_path_buf()).unwrap();
-
-                let new_song = Song {
-                    location: vec![URI::Cue {
-                        location: audio_location.clone(),
-                        index: i,
-                        start,
-                        end,
-                    }],
-                    uuid: Uuid::new_v4(),
-                    plays: 0,
-                    skips: 0,
-                    favorited: false,
-                    banned: None,
-                    rating: None,
-                    format,
-                    duration,
-                    play_time: Duration::from_secs(0),
-                    last_played: None,
-                    date_added: Some(chrono::offset::Utc::now()),
-                    date_modified: Some(chrono::offset::Utc::now()),
-                    tags,
-                    album_art,
-                    internal_tags: Vec::new(),
-                };
-                tracks.push((new_song, audio_location.clone()));
-            }
+            let new_song = Song {
+                location: vec![URI::Cue {
+                    location: audio_location.clone(),
+                    index: range.index,
+                    start: range.start,
+                    end,
+                }],
+                uuid: Uuid::new_v4(),
+                plays: 0,
+                skips: 0,
+                favorited: false,
+                banned: None,
+                rating: None,
+                format,
+                duration,
+                play_time: Duration::from_secs(0),
+                last_played: None,
+                date_added: Some(chrono::offset::Utc::now()),
+                date_modified: Some(chrono::offset::Utc::now()),
+                tags,
+                album_art,
+                internal_tags: Vec::new(),
+            };
+            tracks.push((new_song, audio_location.clone()));
         }
         Ok(tracks)
     }
@@ -473,9 +826,117 @@ impl Song {
             None => Err("No valid URIs for this song".into()),
         }
     }
+
+    /// Record that the song was listened to for `listened`, counting it as
+    /// a play if enough of it was heard, or a skip otherwise. Returns `true`
+    /// if it was counted as a play.
+    ///
+    /// A play is counted once half of the song, or four minutes of it,
+    /// has been heard.
+    pub fn record_listen(&mut self, listened: Duration) -> bool {
+        let threshold = (self.duration / 2).min(Duration::from_secs(240));
+        let played = listened >= threshold;
+
+        if played {
+            self.plays += 1;
+            self.last_played = Some(chrono::offset::Utc::now());
+        } else {
+            self.skips += 1;
+        }
+        self.play_time += listened;
+
+        played
+    }
+
+    /// Sets the rating of the song, from 0 to 100. Larger values
+    /// are clamped to 100, and `None` removes the rating.
+    pub fn set_rating(&mut self, rating: Option<u8>) {
+        self.rating = rating.map(|rating| rating.min(100));
+    }
+
+    /// Writes the song's rating back into the tags of its file.
+    ///
+    /// ID3v2 tags get a `POPM` frame, MP4 files the `rate` atom,
+    /// and all other formats an `FMPS_RATING` item.
+    pub fn write_rating(&self) -> Result<(), Box<dyn Error>> {
+        let location = self.primary_uri()?.0.as_path()?;
+
+        let normal_options = ParseOptions::new().parsing_mode(lofty::ParsingMode::Relaxed);
+        let mut tagged_file = Probe::open(location)?.options(normal_options).read()?;
+
+        let tag_type = tagged_file.primary_tag_type();
+        if tagged_file.tag(tag_type).is_none() {
+            tagged_file.insert_tag(lofty::Tag::new(tag_type));
+        }
+        let tag = tagged_file.tag_mut(tag_type).unwrap();
+
+        let key = match tag_type {
+            TagType::Id3v2 | TagType::Mp4Ilst => ItemKey::Popularimeter,
+            _ => ItemKey::Unknown("FMPS_RATING".to_string()),
+        };
+        tag.remove_key(&key);
+
+        if let Some(rating) = self.rating {
+            let value = match tag_type {
+                TagType::Id3v2 => ItemValue::Binary(
+                    Popularimeter {
+                        email: String::from("dmp-core"),
+                        rating: (rating as u16 * 255 / 100) as u8,
+                        counter: self.plays.max(0) as u64,
+                    }
+                    .as_bytes(),
+                ),
+                TagType::Mp4Ilst => ItemValue::Text(rating.to_string()),
+                _ => ItemValue::Text(format!("{:.2}", rating as f32 / 100.0)),
+            };
+            tag.insert_unchecked(TagItem::new(key, value));
+        }
+
+        tag.save_to_path(location)?;
+        Ok(())
+    }
+
+    /// Reads the raw data of the album art at `index` in the song's
+    /// `album_art` list, returning the bytes along with their MIME type
+    #[allow(clippy::type_complexity)]
+    pub fn read_art(&self, index: usize) -> Result<Option<(Vec<u8>, String)>, Box<dyn Error>> {
+        let art = match self.album_art.get(index) {
+            Some(art) => art,
+            None => return Ok(None),
+        };
+
+        match art {
+            AlbumArt::Embedded(i) => {
+                let normal_options = ParseOptions::new().parsing_mode(lofty::ParsingMode::Relaxed);
+                let location = self.primary_uri()?.0.path();
+                let tagged_file = Probe::open(location)?.options(normal_options).read()?;
+
+                let tag = match tagged_file.primary_tag() {
+                    Some(primary_tag) => primary_tag,
+                    None => match tagged_file.first_tag() {
+                        Some(first_tag) => first_tag,
+                        None => return Ok(None),
+                    },
+                };
+
+                Ok(tag.pictures().get(*i).map(|picture| {
+                    let mime = match picture.mime_type() {
+                        Some(mime) => mime.as_str().to_string(),
+                        None => FileFormat::from_bytes(picture.data()).media_type().to_string(),
+                    };
+                    (picture.data().to_vec(), mime)
+                }))
+            }
+            AlbumArt::External(uri) => {
+                let path = uri.as_path()?;
+                let mime = FileFormat::from_file(path)?.media_type().to_string();
+                Ok(Some((fs::read(path)?, mime)))
+            }
+        }
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum URI {
     Local(PathBuf),
     Cue {
@@ -488,6 +949,22 @@ pub enum URI {
 }
 
 impl URI {
+    /// Create a URI for an HTTP or HTTPS internet radio stream
+    pub fn http_stream<S: Into<String>>(url: S) -> Result<Self, Box<dyn Error>> {
+        let url = url.into();
+        let lower = url.to_ascii_lowercase();
+        if !(lower.starts_with("http://") || lower.starts_with("https://")) {
+            return Err(format!("\"{}\" is not an HTTP stream", url).into());
+        }
+        Ok(URI::Remote(Service::InternetRadio, url))
+    }
+
+    /// Whether this URI is a live stream, which has no duration
+    /// and never finishes on its own
+    pub fn is_stream(&self) -> bool {
+        matches!(self, URI::Remote(Service::InternetRadio, _))
+    }
+
     pub fn index(&self) -> Result<&usize, Box<dyn Error>> {
         match self {
             URI::Local(_) => Err("\"Local\" has no stored index".into()),
@@ -553,6 +1030,64 @@ impl URI {
             URI::Remote(_, _loc) => Ok(true), // TODO: Investigate a way to do this?
         }
     }
+
+    /// Rewrite the path of a local URI with the first matching rule
+    /// in `remap`, returning `true` if the path was changed
+    pub fn remap(&mut self, remap: &PathRemap) -> bool {
+        let location = match self.local_path_mut() {
+            Some(location) => location,
+            None => return false,
+        };
+
+        match remap.remap(&location.to_string_lossy()) {
+            Some(new) if &new != location => {
+                *location = new;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Make the path of a local URI relative to `root`, returning `true`
+    /// if the path was changed. Paths outside of `root` are left as-is.
+    pub fn make_relative(&mut self, root: &Path) -> bool {
+        let location = match self.local_path_mut() {
+            Some(location) => location,
+            None => return false,
+        };
+
+        match location.strip_prefix(root) {
+            Ok(relative) if location.is_absolute() => {
+                *location = relative.to_path_buf();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Resolve a relative local path against `root`, returning `true`
+    /// if the path was changed
+    pub fn make_absolute(&mut self, root: &Path) -> bool {
+        let location = match self.local_path_mut() {
+            Some(location) => location,
+            None => return false,
+        };
+
+        if location.is_relative() {
+            *location = root.join(&location);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn local_path_mut(&mut self) -> Option<&mut PathBuf> {
+        match self {
+            URI::Local(location) => Some(location),
+            URI::Cue { location, .. } => Some(location),
+            URI::Remote(_, _) => None,
+        }
+    }
 }
 
 impl ToString for URI {
@@ -566,12 +1101,52 @@ impl ToString for URI {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Service {
     InternetRadio,
     Spotify,
     Youtube,
     None,
+    /// A song on a Subsonic server, stored by its ID on the server
+    Subsonic,
+    /// A song on a Jellyfin server, stored by its item ID
+    Jellyfin,
+    /// A song on a Plex server, stored by its rating key
+    Plex,
+}
+
+/// Which album a song is on, by its title and album artist, so albums
+/// which share a title are kept apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AlbumKey {
+    pub title: String,
+    pub artist: Option<String>,
+}
+
+impl AlbumKey {
+    pub fn new(title: &str, artist: Option<&str>) -> Self {
+        AlbumKey {
+            title: title.to_string(),
+            artist: artist.map(str::to_string),
+        }
+    }
+
+    /// The album `song` is on, if it has an album tag
+    pub fn of(song: &Song) -> Option<Self> {
+        let title = song.get_tag(&Tag::Album)?;
+        Some(AlbumKey::new(title, song.get_tag(&Tag::AlbumArtist).map(String::as_str)))
+    }
+
+    /// Whether `song` is on this album
+    pub fn contains(&self, song: &Song) -> bool {
+        song.get_tag(&Tag::Album) == Some(&self.title) && song.get_tag(&Tag::AlbumArtist) == self.artist.as_ref()
+    }
+}
+
+impl From<&Album> for AlbumKey {
+    fn from(album: &Album) -> Self {
+        AlbumKey::new(album.title(), album.artist().as_deref())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -580,6 +1155,9 @@ pub struct Album {
     artist: Option<String>,
     cover: Option<AlbumArt>,
     discs: BTreeMap<u16, Vec<(u16, Uuid)>>,
+    musicbrainz_id: Option<String>,
+    artist_musicbrainz_id: Option<String>,
+    release_type: ReleaseType,
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -602,6 +1180,22 @@ impl Album {
     pub fn discs(&self) -> &BTreeMap<u16, Vec<(u16, Uuid)>> {
         &self.discs
     }
+
+    /// Returns the MusicBrainz ID of the release, if any of its songs have one
+    pub fn musicbrainz_id(&self) -> &Option<String> {
+        &self.musicbrainz_id
+    }
+
+    /// Returns the MusicBrainz ID of the Album Artist, if any of its songs have one
+    pub fn artist_musicbrainz_id(&self) -> &Option<String> {
+        &self.artist_musicbrainz_id
+    }
+
+    /// Returns the type of release, from the tags of its songs, or guessed
+    /// from its title and length if none of them have one
+    pub fn release_type(&self) -> ReleaseType {
+        self.release_type
+    }
     /// Returns the specified track at `index` from the album, returning
     /// an error if the track index is out of range
     pub fn track(&self, disc: u16, index: usize) -> Option<&(u16, Uuid)> {
@@ -671,26 +1265,51 @@ impl AlbumTrack {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How far through a scan started with [MusicLibrary::scan_folder_progress] is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanProgress {
+    /// The number of files which have been read
+    pub scanned: usize,
+    /// The number of files which will be read
+    pub total: usize,
+    /// The file which was just read
+    pub current_path: PathBuf,
+    /// The number of files which couldn't be read
+    pub errors: usize,
+}
+
+/// What a file found while scanning turned out to contain
+enum ScannedFile {
+    Songs(Vec<Song>),
+    Cue(Vec<(Song, PathBuf)>),
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MusicLibrary {
     pub name: String,
     pub uuid: Uuid,
     pub library: Vec<Song>,
     pub playlists: PlaylistFolder,
     pub backup_songs: Vec<Song>, // maybe move this to the config instead?
+    /// Songs whose root folder is currently unavailable, such as
+    /// an unplugged drive
+    #[serde(skip)]
+    offline: HashSet<Uuid>,
 }
 
 impl MusicLibrary {
     const BLOCKED_EXTENSIONS: &'static [&'static str] = &["vob", "log", "txt", "sf2"];
 
     /// Create a new library from a name and [Uuid]
-    fn new(name: String, uuid: Uuid) -> Self {
+    pub(super) fn new(name: String, uuid: Uuid) -> Self {
         MusicLibrary {
             name,
             uuid,
             library: Vec::new(),
             playlists: PlaylistFolder::default(),
             backup_songs: Vec::new(),
+            offline: HashSet::new(),
         }
     }
 
@@ -699,13 +1318,16 @@ impl MusicLibrary {
     /// If the database file already exists, return the [MusicLibrary], otherwise create
     /// the database first. This needs to be run before anything else to retrieve
     /// the [MusicLibrary] Vec
+    ///
+    /// Any relative paths in the database are resolved against the
+    /// folder containing it, see [MusicLibrary::save_relative]
     pub fn init(path: PathBuf, uuid: Uuid) -> Result<Self, Box<dyn Error>> {
-        let library: MusicLibrary = match path.exists() {
-            true => read_file(path)?,
+        let library: MusicLibrary = match library_exists(&path) {
+            true => Self::load(&path)?,
             false => {
                 // If the library does not exist, re-create it
                 let lib = MusicLibrary::new(String::new(), uuid);
-                write_file(&lib, path)?;
+                write_library(&lib, &path)?;
                 lib
             }
         };
@@ -715,22 +1337,31 @@ impl MusicLibrary {
     //#[cfg(debug_assertions)] // We probably wouldn't want to use this for real, but maybe it would have some utility?
     pub fn from_path<P: ?Sized + AsRef<Path>>(path: &P) -> Result<Self, Box<dyn Error>> {
         let path: PathBuf = path.as_ref().to_path_buf();
-        let library: MusicLibrary = match path.exists() {
-            true => read_file(path)?,
+        let library: MusicLibrary = match library_exists(&path) {
+            true => Self::load(&path)?,
             false => {
                 let lib = MusicLibrary::new(String::new(), Uuid::new_v4());
-                write_file(&lib, path)?;
+                write_library(&lib, &path)?;
                 lib
             }
         };
         Ok(library)
     }
 
+    /// Read the database at `path`, migrating it if it was saved by an
+    /// older version, see [library_format](super::library_format)
+    pub fn load<P: ?Sized + AsRef<Path>>(path: &P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut lib = read_library(path)?;
+        lib.make_absolute(&Self::library_root(path));
+        Ok(lib)
+    }
+
     /// Serializes the database out to the file specified in the config
     pub fn save_path<P: ?Sized + AsRef<Path>>(&self, path: &P) -> Result<(), Box<dyn Error>> {
         let path = path.as_ref();
         match path.try_exists() {
-            Ok(_) => write_file(self, path)?,
+            Ok(_) => write_library(self, path)?,
             Err(error) => return Err(error.into()),
         }
 
@@ -740,13 +1371,44 @@ impl MusicLibrary {
     /// Serializes the database out to the file specified in the config
     pub fn save(&self, path: PathBuf) -> Result<(), Box<dyn Error>> {
         match path.try_exists() {
-            Ok(_) => write_file(self, path)?,
+            Ok(_) => write_library(self, &path)?,
             Err(error) => return Err(error.into()),
         }
 
         Ok(())
     }
 
+    /// Serializes the database out to `path`, with every path inside of
+    /// the folder containing the database stored relative to it
+    pub fn save_relative(&self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut relative = self.clone();
+        relative.make_relative(&Self::library_root(&path));
+        relative.save(path)
+    }
+
+    /// Serializes the database out to the path of `library`, storing
+    /// relative paths if the library is set to use them. Read-only
+    /// libraries aren't written.
+    pub fn save_config(&self, library: &ConfigLibrary) -> Result<(), Box<dyn Error>> {
+        if library.settings.read_only {
+            return Err(format!("the library {:?} is read-only", library.name).into());
+        }
+        match library.relative_paths {
+            true => self.save_relative(library.path.clone()),
+            false => self.save(library.path.clone()),
+        }
+    }
+
+    /// The folder which relative paths in the database at `path` are
+    /// relative to
+    fn library_root(path: &Path) -> PathBuf {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf())
+    }
+
     /// Returns the library size in number of tracks
     pub fn len_tracks(&self) -> usize {
         self.library.len()
@@ -821,59 +1483,113 @@ impl MusicLibrary {
 
     /// Finds all the audio files within a specified folder
     pub fn scan_folder<P: ?Sized + AsRef<Path>>(&mut self, target_path: &P) -> Result<i32, Box<dyn std::error::Error>> {
-        let mut total = 0;
-        let mut errors = 0;
-        for target_file in WalkDir::new(target_path)
+        self.scan_folder_progress(target_path, None, &AtomicBool::new(false))
+    }
+
+    /// Finds all the audio files within a specified folder, reading their
+    /// tags on every core and sending a [ScanProgress] after each file.
+    ///
+    /// Setting `cancel` stops the scan, the songs which were already read
+    /// are still added. Returns the number of songs which were added.
+    pub fn scan_folder_progress<P: ?Sized + AsRef<Path>>(
+        &mut self,
+        target_path: &P,
+        progress: Option<&Sender<ScanProgress>>,
+        cancel: &AtomicBool,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        self.scan_folder_with(target_path, &LibrarySettings::default(), progress, cancel)
+    }
+
+    /// The same as [MusicLibrary::scan_folder_progress], leaving out the
+    /// files and folders which the `settings` exclude
+    pub fn scan_folder_with<P: ?Sized + AsRef<Path>>(
+        &mut self,
+        target_path: &P,
+        settings: &LibrarySettings,
+        progress: Option<&Sender<ScanProgress>>,
+        cancel: &AtomicBool,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        let root = target_path.as_ref();
+
+        // Find the files which aren't already in the db
+        let files: Vec<PathBuf> = WalkDir::new(target_path)
             .follow_links(true)
             .into_iter()
+            .filter_entry(|entry| !settings.excludes(root, entry.path()))
             .filter_map(|e| e.ok())
-        {
-            let path = target_file.path();
+            .map(|entry| entry.into_path())
+            .filter(|path| path.is_file() && self.query_uri(&URI::Local(path.clone())).is_none())
+            .collect();
 
-            // Ensure the target is a file and not a directory,
-            // if it isn't a file, skip this loop
-            if !path.is_file() {
-                continue;
-            }
+        let total = files.len();
+        let scanned = AtomicUsize::new(0);
+        let errors = AtomicUsize::new(0);
+        let results: Vec<ScannedFile> = files
+            .par_iter()
+            .filter_map(|path| {
+                if cancel.load(atomic::Ordering::Relaxed) {
+                    return None;
+                }
 
-            // Check if the file path is already in the db
-            if self.query_uri(&URI::Local(path.to_path_buf())).is_some() {
-                continue;
-            }
+                let result = Self::scan_file(path);
+                if let Err(error) = &result {
+                    errors.fetch_add(1, atomic::Ordering::Relaxed);
+                    println!("{:?}: {}", path, error);
+                }
+                if let Some(progress) = progress {
+                    let _ = progress.send(ScanProgress {
+                        scanned: scanned.fetch_add(1, atomic::Ordering::Relaxed) + 1,
+                        total,
+                        current_path: path.clone(),
+                        errors: errors.load(atomic::Ordering::Relaxed),
+                    });
+                }
+                result.ok()
+            })
+            .collect();
 
-            let format = FileFormat::from_file(path)?;
-            let extension = match path.extension() {
-                Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
-                None => String::new(),
+        // Adding songs checks for duplicates, so it is done one at a time
+        let mut added = 0;
+        for result in results {
+            added += match result {
+                ScannedFile::Songs(songs) => songs
+                    .into_iter()
+                    .map(|song| self.add_song(song))
+                    .filter(Result::is_ok)
+                    .count() as i32,
+                ScannedFile::Cue(tracks) => self.add_cue_tracks(tracks),
+                ScannedFile::Skipped => 0,
             };
-
-            // If it's a normal file, add it to the database
-            // if it's a cuesheet, do a bunch of fancy stuff
-            if (format.kind() == Kind::Audio || format.kind() == Kind::Video)
-                && !Self::BLOCKED_EXTENSIONS.contains(&extension.as_str())
-            {
-                match self.add_file(target_file.path()) {
-                    Ok(_) => total += 1,
-                    Err(_error) => {
-                        errors += 1;
-                        println!("{:?}: {}", target_file.file_name(), _error)
-                    } // TODO: Handle more of these errors
-                };
-            } else if extension == "cue" {
-                total += match self.add_cuesheet(target_file.path()) {
-                    Ok(added) => added,
-                    Err(_error) => {
-                        errors += 1;
-                        println!("{:?}: {}", target_file.file_name(), _error);
-                        0
-                    }
-                }
-            }
         }
 
-        println!("Total scanning errors: {}", errors);
+        println!("Total scanning errors: {}", errors.into_inner());
 
-        Ok(total)
+        Ok(added)
+    }
+
+    /// Read the songs in a file found while scanning
+    fn scan_file(path: &Path) -> Result<ScannedFile, Box<dyn Error>> {
+        let format = FileFormat::from_file(path)?;
+        let extension = match path.extension() {
+            Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+            None => String::new(),
+        };
+
+        // If it's a normal file, add it to the database
+        // if it's a cuesheet, do a bunch of fancy stuff
+        if (format.kind() == Kind::Audio || format.kind() == Kind::Video)
+            && !Self::BLOCKED_EXTENSIONS.contains(&extension.as_str())
+        {
+            let song = Song::from_file(path)?;
+            match Song::from_embedded_cue(&song) {
+                Some(tracks) => Ok(ScannedFile::Songs(tracks?)),
+                None => Ok(ScannedFile::Songs(vec![song])),
+            }
+        } else if extension == "cue" {
+            Ok(ScannedFile::Cue(Song::from_cue(path)?))
+        } else {
+            Ok(ScannedFile::Skipped)
+        }
     }
 
     pub fn remove_missing(&mut self) {
@@ -892,8 +1608,165 @@ impl MusicLibrary {
         }
     }
 
+    /// Scan every root folder which is available and set to be scanned,
+    /// returning the total number of songs added
+    pub fn scan_roots(&mut self, roots: &[LibraryRoot]) -> Result<i32, Box<dyn Error>> {
+        let mut total = 0;
+        for root in roots {
+            if !root.scan {
+                continue;
+            }
+            if !root.is_available() {
+                println!("Skipping unavailable root {:?}", root.path);
+                continue;
+            }
+            total += self.scan_folder(&root.path)?;
+        }
+        Ok(total)
+    }
+
+    /// Scan the roots of `library` which are watched for changes, using
+    /// its settings. Returns the total number of songs added.
+    pub fn scan_watched(&mut self, library: &ConfigLibrary) -> Result<i32, Box<dyn Error>> {
+        let mut total = 0;
+        for root in library.watched_roots() {
+            if !root.scan || !root.is_available() {
+                continue;
+            }
+            total += self.scan_folder_with(&root.path, &library.settings, None, &AtomicBool::new(false))?;
+        }
+        Ok(total)
+    }
+
+    /// Remove songs whose files are missing, ignoring any songs inside
+    /// of roots which are currently unavailable
+    pub fn remove_missing_in(&mut self, roots: &[LibraryRoot]) {
+        let unavailable: Vec<&LibraryRoot> =
+            roots.iter().filter(|root| !root.is_available()).collect();
+
+        let target_removals: Vec<URI> = self
+            .library
+            .par_iter()
+            .flat_map_iter(|song| song.location.iter())
+            .filter(|location| {
+                let path = match location {
+                    URI::Remote(_, _) => return false,
+                    _ => location.path(),
+                };
+                !unavailable.iter().any(|root| root.contains(&path))
+                    && !location.exists().unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+
+        for location in target_removals {
+            let _ = self.remove_uri(&location);
+        }
+    }
+
+    /// Returns all songs which are stored inside of a root
+    /// folder that is currently unavailable
+    pub fn unavailable_songs(&self, roots: &[LibraryRoot]) -> Vec<&Song> {
+        let unavailable: Vec<&LibraryRoot> =
+            roots.iter().filter(|root| !root.is_available()).collect();
+        if unavailable.is_empty() {
+            return Vec::new();
+        }
+
+        self.library
+            .iter()
+            .filter(|song| {
+                song.location.iter().any(|location| match location {
+                    URI::Remote(_, _) => false,
+                    _ => unavailable.iter().any(|root| root.contains(&location.path())),
+                })
+            })
+            .collect()
+    }
+
+    /// Mark the songs inside of unavailable roots as offline, and bring
+    /// songs back online once their root is available again, without
+    /// rescanning them. Returns the number of songs which changed state.
+    pub fn refresh_offline(&mut self, roots: &[LibraryRoot]) -> usize {
+        let offline: HashSet<Uuid> = self
+            .unavailable_songs(roots)
+            .iter()
+            .map(|song| song.uuid)
+            .collect();
+
+        let changed = self.offline.symmetric_difference(&offline).count();
+        self.offline = offline;
+        changed
+    }
+
+    /// Whether the song with the given [Uuid] is stored on a root
+    /// which is currently unavailable
+    pub fn is_offline(&self, uuid: &Uuid) -> bool {
+        self.offline.contains(uuid)
+    }
+
+    /// Returns all of the songs which are currently offline
+    pub fn offline_songs(&self) -> Vec<&Song> {
+        self.library
+            .iter()
+            .filter(|song| self.offline.contains(&song.uuid))
+            .collect()
+    }
+
+    /// Rewrite the locations and album art paths of every song with
+    /// `remap`, returning the number of songs which were changed
+    pub fn apply_remap(&mut self, remap: &PathRemap) -> usize {
+        if remap.is_empty() {
+            return 0;
+        }
+
+        self.map_uris(|uri| uri.remap(remap))
+    }
+
+    /// Make every song's paths which are inside of `root` relative to it,
+    /// returning the number of songs which were changed
+    pub fn make_relative(&mut self, root: &Path) -> usize {
+        self.map_uris(|uri| uri.make_relative(root))
+    }
+
+    /// Resolve every song's relative paths against `root`, returning the
+    /// number of songs which were changed
+    pub fn make_absolute(&mut self, root: &Path) -> usize {
+        self.map_uris(|uri| uri.make_absolute(root))
+    }
+
+    /// Run `f` on the locations and external album art of every song,
+    /// returning the number of songs for which it returned `true`
+    fn map_uris<F: Fn(&mut URI) -> bool + Sync>(&mut self, f: F) -> usize {
+        self.library
+            .par_iter_mut()
+            .chain(self.backup_songs.par_iter_mut())
+            .map(|song| {
+                let mut changed = false;
+                for location in &mut song.location {
+                    changed |= f(location);
+                }
+                for art in &mut song.album_art {
+                    if let AlbumArt::External(uri) = art {
+                        changed |= f(uri);
+                    }
+                }
+                changed as usize
+            })
+            .sum()
+    }
+
     pub fn add_file(&mut self, target_file: &Path) -> Result<(), Box<dyn Error>> {
         let new_song = Song::from_file(target_file)?;
+
+        // Files with an embedded cue sheet are added as their virtual tracks
+        if let Some(tracks) = Song::from_embedded_cue(&new_song) {
+            for track in tracks? {
+                let _ = self.add_song(track);
+            }
+            return Ok(());
+        }
+
         match self.add_song(new_song) {
             Ok(_) => (),
             Err(_) => {
@@ -906,6 +1779,12 @@ impl MusicLibrary {
 
     pub fn add_cuesheet(&mut self, cuesheet: &Path) -> Result<i32, Box<dyn Error>> {
         let tracks = Song::from_cue(cuesheet)?;
+        Ok(self.add_cue_tracks(tracks))
+    }
+
+    /// Add the tracks of a cue sheet, replacing the files they are
+    /// part of, returning the number of tracks added
+    fn add_cue_tracks(&mut self, tracks: Vec<(Song, PathBuf)>) -> i32 {
         let mut tracks_added = tracks.len() as i32;
 
         for (new_song, location) in tracks {
@@ -921,7 +1800,7 @@ impl MusicLibrary {
                 }
             };
         }
-        Ok(tracks_added)
+        tracks_added
     }
 
     pub fn add_song(&mut self, new_song: Song) -> Result<(), Box<dyn Error>> {
@@ -938,10 +1817,32 @@ impl MusicLibrary {
         }
 
         self.library.push(new_song);
+        self.inherit_album_tags(self.library.len() - 1);
 
         Ok(())
     }
 
+    /// Give the song at `index` the labels and notes of its album, from the
+    /// songs which were on it before
+    fn inherit_album_tags(&mut self, index: usize) {
+        let Some(album) = AlbumKey::of(&self.library[index]) else {
+            return;
+        };
+        let Some(sibling) = self.library.iter().enumerate().find(|(i, song)| *i != index && album.contains(song)) else {
+            return;
+        };
+        let labels: Vec<String> = sibling.1.album_labels().into_iter().cloned().collect();
+        let notes = sibling.1.album_notes().cloned();
+
+        let song = &mut self.library[index];
+        for label in labels {
+            song.add_album_label(&label);
+        }
+        if notes.is_some() {
+            song.set_album_notes(notes.as_deref());
+        }
+    }
+
     /// Removes a song indexed by URI, returning the position removed
     pub fn remove_uri(&mut self, target_uri: &URI) -> Result<usize, Box<dyn Error>> {
         let location = match self.query_uri(target_uri) {
@@ -1098,6 +1999,8 @@ impl MusicLibrary {
         let mut paths = BTreeMap::new();
 
         let mut albums: BTreeMap<String, Album> = BTreeMap::new();
+        // The tagged release type and total length of each album
+        let mut release_types: BTreeMap<String, (Option<ReleaseType>, Duration)> = BTreeMap::new();
         for song in &self.library {
             let album_title = match song.get_tag(&Tag::Album) {
                 Some(title) => title.clone(),
@@ -1111,26 +2014,40 @@ impl MusicLibrary {
                 .parse::<u16>()
                 .unwrap_or(1);
 
+            let (tagged, duration) = release_types.entry(album_title.clone()).or_default();
+            if tagged.is_none() {
+                *tagged = song.get_tag(&Tag::ReleaseType).and_then(|release| release.parse().ok());
+            }
+            *duration += song.duration;
+
             match albums.get_mut(&album_title) {
                 // If the album is in the list, add the track to the appropriate disc within the album
-                Some(album) => match album.discs.get_mut(&disc_num) {
-                    Some(disc) => disc.push((
-                        song.get_tag(&Tag::Track)
-                            .unwrap_or(&String::new())
-                            .parse::<u16>()
-                            .unwrap_or_default(),
-                        song.uuid
-                    )),
-                    None => {
-                        album.discs.insert(disc_num, vec![(
+                Some(album) => {
+                    if album.musicbrainz_id.is_none() {
+                        album.musicbrainz_id = song.get_tag(&Tag::MusicBrainzReleaseId).cloned();
+                    }
+                    if album.artist_musicbrainz_id.is_none() {
+                        album.artist_musicbrainz_id = song.get_tag(&Tag::MusicBrainzAlbumArtistId).cloned();
+                    }
+                    match album.discs.get_mut(&disc_num) {
+                        Some(disc) => disc.push((
                             song.get_tag(&Tag::Track)
                                 .unwrap_or(&String::new())
                                 .parse::<u16>()
                                 .unwrap_or_default(),
                             song.uuid
-                        )]);
+                        )),
+                        None => {
+                            album.discs.insert(disc_num, vec![(
+                                song.get_tag(&Tag::Track)
+                                    .unwrap_or(&String::new())
+                                    .parse::<u16>()
+                                    .unwrap_or_default(),
+                                song.uuid
+                            )]);
+                        }
                     }
-                },
+                }
                 // If the album is not in the list, make it new one and add it
                 None => {
                     let album_art = song.album_art.first();
@@ -1147,12 +2064,24 @@ impl MusicLibrary {
                                 song.uuid
                             )])]),
                         cover: album_art.cloned(),
+                        musicbrainz_id: song.get_tag(&Tag::MusicBrainzReleaseId).cloned(),
+                        artist_musicbrainz_id: song.get_tag(&Tag::MusicBrainzAlbumArtistId).cloned(),
+                        release_type: ReleaseType::default(),
                     };
                     albums.insert(album_title, new_album);
                 }
 
             }
-            paths.insert(song.uuid, song.primary_uri().unwrap());
+            // Songs which can't be found are still sorted by where they were
+            let uri = song.primary_uri().map(|(uri, _)| uri).ok().or(song.location.first());
+            if let Some(uri) = uri {
+                paths.insert(song.uuid, uri);
+            }
+        }
+
+        for (title, album) in albums.iter_mut() {
+            let (tagged, duration) = release_types[title];
+            album.release_type = tagged.unwrap_or_else(|| ReleaseType::classify(title, album.len(), duration));
         }
 
         // Sort the tracks in each disk in each album
@@ -1168,12 +2097,12 @@ impl MusicLibrary {
                         num_a.cmp(&num_b)
                     } else {
                         // If parsing doesn't succeed, compare the locations
-                        let a = match paths.get_key_value(&a.1) {
-                            Some((_, (uri, _))) => uri,
+                        let a = match paths.get(&a.1) {
+                            Some(uri) => uri,
                             None => return Ordering::Equal
                         };
-                        let b = match paths.get_key_value(&b.1) {
-                            Some((_, (uri, _))) => uri,
+                        let b = match paths.get(&b.1) {
+                            Some(uri) => uri,
                             None => return Ordering::Equal
                         };
 
@@ -1187,6 +2116,14 @@ impl MusicLibrary {
         albums
     }
 
+    /// Generates the albums of the given release types, such as to show
+    /// albums only and hide singles
+    pub fn albums_of_type(&self, types: &[ReleaseType]) -> BTreeMap<String, Album> {
+        let mut albums = self.albums();
+        albums.retain(|_, album| types.contains(&album.release_type));
+        albums
+    }
+
     /// Queries a list of albums by title
     pub fn query_albums(
         &self,
@@ -1208,17 +2145,257 @@ impl MusicLibrary {
 
         Ok(albums)
     }
+
+    /// Record a listen of the song with the given [Uuid], see [Song::record_listen].
+    ///
+    /// Returns `None` if the song is not in the library.
+    pub fn record_listen(&mut self, uuid: &Uuid, listened: Duration) -> Option<bool> {
+        let (_, i) = self.query_uuid(uuid)?;
+        Some(self.library[i].record_listen(listened))
+    }
+
+    /// Returns up to `limit` of the most played songs, most played first
+    pub fn most_played(&self, limit: usize) -> Vec<&Song> {
+        let mut songs: Vec<&Song> = self.library.iter().filter(|song| song.plays > 0).collect();
+        songs.par_sort_by(|a, b| b.plays.cmp(&a.plays));
+        songs.truncate(limit);
+        songs
+    }
+
+    /// Returns up to `limit` of the most recently played songs, latest first
+    pub fn recently_played(&self, limit: usize) -> Vec<&Song> {
+        let mut songs: Vec<&Song> = self
+            .library
+            .iter()
+            .filter(|song| song.last_played.is_some())
+            .collect();
+        songs.par_sort_by(|a, b| b.last_played.cmp(&a.last_played));
+        songs.truncate(limit);
+        songs
+    }
+
+    /// Returns up to `limit` artists along with the total number of plays
+    /// of their songs, most played first
+    pub fn most_played_artists(&self, limit: usize) -> Vec<(String, i32)> {
+        let mut artists: BTreeMap<&String, i32> = BTreeMap::new();
+        for song in &self.library {
+            if song.plays == 0 {
+                continue;
+            }
+            if let Some(artist) = song.get_tag(&Tag::Artist) {
+                *artists.entry(artist).or_default() += song.plays;
+            }
+        }
+
+        let mut artists: Vec<(String, i32)> = artists
+            .into_iter()
+            .map(|(artist, plays)| (artist.clone(), plays))
+            .collect();
+        artists.sort_by_key(|(_, plays)| std::cmp::Reverse(*plays));
+        artists.truncate(limit);
+        artists
+    }
+
+    /// Set the rating of the song with the given [Uuid], see [Song::set_rating].
+    ///
+    /// If `write_tags` is set, the rating is also written to the song's file.
+    pub fn set_rating(
+        &mut self,
+        uuid: &Uuid,
+        rating: Option<u8>,
+        write_tags: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let i = match self.query_uuid(uuid) {
+            Some((_, i)) => i,
+            None => return Err("UUID not in database".into()),
+        };
+
+        self.library[i].set_rating(rating);
+        if write_tags {
+            self.library[i].write_rating()?;
+        }
+        Ok(())
+    }
+
+    /// Toggle whether the song with the given [Uuid] is a favorite,
+    /// returning the new state, or `None` if the song is not in the library
+    pub fn toggle_favorite(&mut self, uuid: &Uuid) -> Option<bool> {
+        let (_, i) = self.query_uuid(uuid)?;
+        let song = &mut self.library[i];
+        song.favorited = !song.favorited;
+        Some(song.favorited)
+    }
+
+    /// Returns all of the favorited songs
+    pub fn favorites(&self) -> Vec<&Song> {
+        self.library.iter().filter(|song| song.favorited).collect()
+    }
+
+    /// Returns all songs rated `min_rating` or higher, highest rated first
+    pub fn rated_at_least(&self, min_rating: u8) -> Vec<&Song> {
+        let mut songs: Vec<&Song> = self
+            .library
+            .iter()
+            .filter(|song| song.rating.is_some_and(|rating| rating >= min_rating))
+            .collect();
+        songs.par_sort_by(|a, b| b.rating.cmp(&a.rating));
+        songs
+    }
+
+    /// Add a label to the song with the given [Uuid], see [Song::add_label].
+    ///
+    /// Returns `None` if the song is not in the library.
+    pub fn add_label(&mut self, uuid: &Uuid, label: &str) -> Option<bool> {
+        let (_, i) = self.query_uuid(uuid)?;
+        Some(self.library[i].add_label(label))
+    }
+
+    /// Remove a label from the song with the given [Uuid], see [Song::remove_label].
+    ///
+    /// Returns `None` if the song is not in the library.
+    pub fn remove_label(&mut self, uuid: &Uuid, label: &str) -> Option<bool> {
+        let (_, i) = self.query_uuid(uuid)?;
+        Some(self.library[i].remove_label(label))
+    }
+
+    /// Add a label to every song of an album, along with songs added to it
+    /// later, returning the number of songs changed
+    pub fn add_album_label(&mut self, album: &AlbumKey, label: &str) -> usize {
+        self.library
+            .iter_mut()
+            .filter(|song| album.contains(song))
+            .map(|song| song.add_album_label(label) as usize)
+            .sum()
+    }
+
+    /// Remove a label from every song of an album, returning the number of songs changed
+    pub fn remove_album_label(&mut self, album: &AlbumKey, label: &str) -> usize {
+        self.library
+            .iter_mut()
+            .filter(|song| album.contains(song))
+            .map(|song| song.remove_label(label) as usize)
+            .sum()
+    }
+
+    /// Returns the labels which every song of an album has
+    pub fn album_labels(&self, album: &AlbumKey) -> Vec<String> {
+        let mut songs = self.library.iter().filter(|song| album.contains(song));
+
+        let first = match songs.next() {
+            Some(song) => song,
+            None => return Vec::new(),
+        };
+        let mut labels: Vec<String> = first.labels().into_iter().cloned().collect();
+        for song in songs {
+            labels.retain(|label| song.has_label(label));
+        }
+        labels
+    }
+
+    /// Returns every label in the library, along with the number of songs which have it
+    pub fn labels(&self) -> BTreeMap<String, usize> {
+        let mut labels = BTreeMap::new();
+        for song in &self.library {
+            for label in song.labels() {
+                *labels.entry(label.clone()).or_default() += 1;
+            }
+        }
+        labels
+    }
+
+    /// Returns all of the songs with a label
+    pub fn with_label(&self, label: &str) -> Vec<&Song> {
+        self.library.iter().filter(|song| song.has_label(label)).collect()
+    }
+
+    /// Set the notes of the song with the given [Uuid], see [Song::set_notes].
+    ///
+    /// Returns `false` if the song is not in the library.
+    pub fn set_notes(&mut self, uuid: &Uuid, notes: Option<&str>) -> bool {
+        match self.query_uuid(uuid) {
+            Some((_, i)) => {
+                self.library[i].set_notes(notes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set whether plays of a song are tracked in the way given by `kind`,
+    /// returning `false` if the song isn't in the library
+    pub fn set_tracked(&mut self, uuid: &Uuid, kind: DoNotTrack, tracked: bool) -> bool {
+        match self.query_uuid(uuid) {
+            Some((_, i)) => {
+                self.library[i].set_tracked(kind, tracked);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set the notes of an album on every one of its songs, along with
+    /// songs added to it later, returning the number of songs changed
+    pub fn set_album_notes(&mut self, album: &AlbumKey, notes: Option<&str>) -> usize {
+        self.library
+            .iter_mut()
+            .filter(|song| album.contains(song))
+            .map(|song| song.set_album_notes(notes) as usize)
+            .sum()
+    }
+
+    /// Set the volume adjustment of the song with the given [Uuid], see
+    /// [Song::set_volume_adjustment].
+    ///
+    /// Returns `false` if the song is not in the library.
+    pub fn set_volume_adjustment(&mut self, uuid: &Uuid, adjustment: i8) -> bool {
+        match self.query_uuid(uuid) {
+            Some((_, i)) => {
+                self.library[i].set_volume_adjustment(adjustment);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set the volume adjustment of every song of an album, see
+    /// [Song::set_volume_adjustment], returning the number of songs changed
+    pub fn set_album_volume_adjustment(&mut self, album: &AlbumKey, adjustment: i8) -> usize {
+        self.library
+            .iter_mut()
+            .filter(|song| album.contains(song))
+            .map(|song| song.set_volume_adjustment(adjustment))
+            .count()
+    }
+
+    /// Returns all of the songs which are audiobooks
+    pub fn audiobooks(&self) -> Vec<&Song> {
+        self.library.iter().filter(|song| song.is_audiobook()).collect()
+    }
+
+    /// Returns the notes of an album, if any of its songs have them
+    pub fn album_notes(&self, album: &AlbumKey) -> Option<&String> {
+        self.library
+            .iter()
+            .filter(|song| album.contains(song))
+            .find_map(|song| song.album_notes())
+    }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use std::{
+        collections::BTreeMap,
         path::PathBuf,
-        sync::{Arc, RwLock},
+        sync::{atomic::AtomicBool, Arc, RwLock},
+        time::Duration,
     };
 
+    use uuid::Uuid;
+
     use crate::{config::{tests::new_config_lib, Config}, music_storage::library::MusicLibrary};
 
+    use super::{AlbumKey, LibraryRoot, PathRemap, ScanProgress, Song, Tag, URI};
+
     #[test]
     fn library_init() {
         let config = Config::read_file(PathBuf::from("test_config/config_test.json")).unwrap();
@@ -1226,4 +2403,314 @@ mod test {
         let a = MusicLibrary::init(config.libraries.get_default().unwrap().path.clone(), target_uuid).unwrap();
         dbg!(a);
     }
+
+    /// Create a song which exists only in memory, for tests which
+    /// don't touch the filesystem
+    pub fn test_song(title: &str, artist: &str, duration: Duration) -> Song {
+        Song {
+            location: vec![URI::Local(PathBuf::from(format!("/music/{}.flac", title)))],
+            uuid: Uuid::new_v4(),
+            plays: 0,
+            skips: 0,
+            favorited: false,
+            banned: None,
+            rating: None,
+            format: None,
+            duration,
+            play_time: Duration::from_secs(0),
+            last_played: None,
+            date_added: None,
+            date_modified: None,
+            album_art: Vec::new(),
+            tags: BTreeMap::from([
+                (Tag::Title, title.to_string()),
+                (Tag::Artist, artist.to_string()),
+            ]),
+            internal_tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn play_statistics() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let a = test_song("a", "Artist 1", Duration::from_secs(200));
+        let b = test_song("b", "Artist 2", Duration::from_secs(600));
+        let (a_uuid, b_uuid) = (a.uuid, b.uuid);
+        lib.library.push(a);
+        lib.library.push(b);
+
+        // Half of a short song counts as a play
+        assert_eq!(lib.record_listen(&a_uuid, Duration::from_secs(100)), Some(true));
+        assert_eq!(lib.record_listen(&a_uuid, Duration::from_secs(10)), Some(false));
+        // Four minutes of a long song counts as a play
+        assert_eq!(lib.record_listen(&b_uuid, Duration::from_secs(240)), Some(true));
+        assert_eq!(lib.record_listen(&b_uuid, Duration::from_secs(240)), Some(true));
+        assert_eq!(lib.record_listen(&Uuid::new_v4(), Duration::from_secs(1)), None);
+
+        let (a, _) = lib.query_uuid(&a_uuid).unwrap();
+        assert_eq!((a.plays, a.skips), (1, 1));
+        assert_eq!(a.play_time, Duration::from_secs(110));
+
+        assert_eq!(lib.most_played(1)[0].uuid, b_uuid);
+        assert_eq!(lib.recently_played(10).len(), 2);
+        assert_eq!(
+            lib.most_played_artists(10),
+            vec![("Artist 2".to_string(), 2), ("Artist 1".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn ratings_and_favorites() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let a = test_song("a", "Artist", Duration::from_secs(1));
+        let b = test_song("b", "Artist", Duration::from_secs(1));
+        let (a_uuid, b_uuid) = (a.uuid, b.uuid);
+        lib.library.push(a);
+        lib.library.push(b);
+
+        lib.set_rating(&a_uuid, Some(60), false).unwrap();
+        lib.set_rating(&b_uuid, Some(250), false).unwrap();
+        assert!(lib.set_rating(&Uuid::new_v4(), Some(10), false).is_err());
+        assert_eq!(lib.query_uuid(&b_uuid).unwrap().0.rating, Some(100));
+
+        let rated: Vec<Uuid> = lib.rated_at_least(50).iter().map(|song| song.uuid).collect();
+        assert_eq!(rated, vec![b_uuid, a_uuid]);
+
+        assert_eq!(lib.toggle_favorite(&a_uuid), Some(true));
+        assert_eq!(lib.favorites().len(), 1);
+        assert_eq!(lib.toggle_favorite(&a_uuid), Some(false));
+        assert_eq!(lib.toggle_favorite(&Uuid::new_v4()), None);
+
+        // Ratings and favorites can be searched as fields
+        lib.toggle_favorite(&b_uuid);
+        let found = lib
+            .query_tracks(&String::from("true"), &vec![Tag::Field("favorited".to_string())], &vec![])
+            .unwrap();
+        assert_eq!(found[0].uuid, b_uuid);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        lib.library.push(test_song("a", "Artist", Duration::from_secs(1)));
+        let mut outside = test_song("b", "Artist", Duration::from_secs(1));
+        outside.location = vec![URI::Local(PathBuf::from("/elsewhere/b.flac"))];
+        lib.library.push(outside);
+
+        assert_eq!(lib.make_relative(&PathBuf::from("/music")), 1);
+        assert_eq!(lib.library[0].location[0], URI::Local(PathBuf::from("a.flac")));
+        assert_eq!(lib.library[1].location[0], URI::Local(PathBuf::from("/elsewhere/b.flac")));
+
+        assert_eq!(lib.make_absolute(&PathBuf::from("/mnt/music")), 1);
+        assert_eq!(lib.library[0].location[0], URI::Local(PathBuf::from("/mnt/music/a.flac")));
+    }
+
+    #[test]
+    fn unavailable_roots() {
+        let available = std::env::temp_dir();
+        let roots = vec![
+            LibraryRoot::new(PathBuf::from("/music")),
+            LibraryRoot::new(available.clone()),
+        ];
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let a = test_song("a", "Artist", Duration::from_secs(1));
+        let a_uuid = a.uuid;
+        lib.library.push(a);
+        let mut b = test_song("b", "Artist", Duration::from_secs(1));
+        b.location = vec![URI::Local(available.join("dmp-missing-song.flac"))];
+        lib.library.push(b);
+
+        assert!(!roots[0].is_available());
+        let unavailable: Vec<Uuid> = lib.unavailable_songs(&roots).iter().map(|s| s.uuid).collect();
+        assert_eq!(unavailable, vec![a_uuid]);
+
+        // Only missing songs from available roots are removed
+        lib.remove_missing_in(&roots);
+        assert_eq!(lib.library.len(), 1);
+        assert_eq!(lib.library[0].uuid, a_uuid);
+
+        // Nor from the empty mount point an unplugged drive leaves behind
+        let mount_point = tempfile::tempdir().unwrap();
+        lib.library[0].location = vec![URI::Local(mount_point.path().join("a.flac"))];
+        lib.remove_missing_in(&[LibraryRoot::new(mount_point.path().to_path_buf())]);
+        assert_eq!(lib.library.len(), 1);
+    }
+
+    #[test]
+    fn offline_songs() {
+        let roots = vec![LibraryRoot::new(PathBuf::from("/music"))];
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let a = test_song("a", "Artist", Duration::from_secs(1));
+        let a_uuid = a.uuid;
+        lib.library.push(a);
+
+        assert_eq!(lib.refresh_offline(&roots), 1);
+        assert!(lib.is_offline(&a_uuid));
+        assert_eq!(lib.offline_songs().len(), 1);
+        // Nothing changes while the root stays unavailable
+        assert_eq!(lib.refresh_offline(&roots), 0);
+
+        // The root is no longer part of the library, so the song is back
+        assert_eq!(lib.refresh_offline(&[]), 1);
+        assert!(!lib.is_offline(&a_uuid));
+    }
+
+    #[test]
+    fn song_labels() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        // Songs are only added with files which exist
+        let folder = tempfile::tempdir().unwrap();
+        let file = |title: &str| {
+            let path = folder.path().join(format!("{title}.flac"));
+            std::fs::write(&path, b"").unwrap();
+            vec![URI::Local(path)]
+        };
+        for title in ["a", "b", "c"] {
+            let mut song = test_song(title, "Artist", Duration::from_secs(1));
+            song.location = file(title);
+            if title != "c" {
+                song.set_tag(Tag::Album, String::from("Album"));
+            }
+            lib.library.push(song);
+        }
+        let (a_uuid, c_uuid) = (lib.library[0].uuid, lib.library[2].uuid);
+        let album = AlbumKey::new("Album", None);
+
+        // A song already labelled isn't counted as changed
+        assert_eq!(lib.add_label(&a_uuid, "vinyl-owned"), Some(true));
+        assert_eq!(lib.add_album_label(&album, "vinyl-owned"), 1);
+        assert_eq!(lib.add_label(&c_uuid, "Workout"), Some(true));
+        assert_eq!(lib.add_label(&c_uuid, " workout "), Some(false));
+        assert_eq!(lib.add_label(&Uuid::new_v4(), "workout"), None);
+
+        assert_eq!(lib.album_labels(&album), vec![String::from("vinyl-owned")]);
+        assert_eq!(lib.with_label("WORKOUT").len(), 1);
+        assert_eq!(lib.labels().get("vinyl-owned"), Some(&2));
+
+        // Songs added to the album later get its labels, but not those of
+        // another album with the same title
+        for (title, album_artist) in [("d", None), ("e", Some("Band"))] {
+            let mut song = test_song(title, "Artist", Duration::from_secs(1));
+            song.location = file(title);
+            song.set_tag(Tag::Album, String::from("Album"));
+            if let Some(album_artist) = album_artist {
+                song.set_tag(Tag::AlbumArtist, album_artist.to_string());
+            }
+            lib.add_song(song).unwrap();
+        }
+        assert_eq!(lib.labels().get("vinyl-owned"), Some(&3));
+        assert!(lib.album_labels(&AlbumKey::new("Album", Some("Band"))).is_empty());
+
+        // Labels can be searched as a field
+        let found = lib
+            .query_tracks(&String::from("workout"), &vec![Tag::Field("labels".to_string())], &vec![])
+            .unwrap();
+        assert_eq!(found[0].uuid, c_uuid);
+
+        assert_eq!(lib.remove_label(&c_uuid, "workout"), Some(true));
+        assert!(lib.with_label("workout").is_empty());
+    }
+
+    #[test]
+    fn embedded_cue() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut image = test_song("Image", "Artist", Duration::from_secs(300));
+        image.location = vec![URI::Local(file.path().to_path_buf())];
+        image.set_tag(Tag::Genre, String::from("Jazz"));
+        image.set_tag(
+            Tag::Key(String::from("CUESHEET")),
+            String::from(
+                "TITLE \"Live\"\nFILE \"image.wav\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"One\"\n    INDEX 01 00:00:00\n  \
+                TRACK 02 AUDIO\n    TITLE \"Two\"\n    INDEX 00 01:58:00\n    INDEX 01 02:00:00\n",
+            ),
+        );
+
+        let tracks = Song::from_embedded_cue(&image).unwrap().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].get_tag(&Tag::Title), Some(&String::from("One")));
+        assert_eq!(tracks[0].get_tag(&Tag::Genre), Some(&String::from("Jazz")));
+        assert_eq!(tracks[0].duration, Duration::from_secs(120));
+        assert!(tracks[1].get_tag(&Tag::Key(String::from("CUESHEET"))).is_none());
+        match &tracks[1].location[0] {
+            URI::Cue { index, start, .. } => assert_eq!((*index, *start), (1, Duration::from_secs(120))),
+            uri => panic!("expected a cue track, got {:?}", uri),
+        }
+
+        assert!(Song::from_embedded_cue(&test_song("a", "Artist", Duration::from_secs(1))).is_none());
+    }
+
+    #[test]
+    fn song_notes() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        for title in ["a", "b"] {
+            let mut song = test_song(title, "Artist", Duration::from_secs(1));
+            song.set_tag(Tag::Album, String::from("Album"));
+            lib.library.push(song);
+        }
+        let a_uuid = lib.library[0].uuid;
+
+        assert!(lib.set_notes(&a_uuid, Some("skip the intro")));
+        assert!(!lib.set_notes(&Uuid::new_v4(), Some("missing")));
+        let album = AlbumKey::new("Album", None);
+        assert_eq!(lib.set_album_notes(&album, Some("from the 2019 remaster")), 2);
+        assert_eq!(lib.set_album_notes(&album, Some(" from the 2019 remaster ")), 0);
+        assert_eq!(lib.album_notes(&album), Some(&String::from("from the 2019 remaster")));
+        assert_eq!(lib.album_notes(&AlbumKey::new("Album", Some("Band"))), None);
+
+        // Both the song and album notes are searchable
+        let notes = vec![Tag::Field("notes".to_string())];
+        let found = lib.query_tracks(&String::from("intro"), &notes, &vec![]).unwrap();
+        assert_eq!(found.len(), 1);
+        let found = lib.query_tracks(&String::from("remaster"), &notes, &vec![Tag::Title]).unwrap();
+        assert_eq!(found.len(), 2);
+
+        // Notes are kept when the song is exported
+        let json = serde_json::to_string(&lib.library[0]).unwrap();
+        assert!(json.contains("skip the intro"));
+
+        lib.set_notes(&a_uuid, Some("  "));
+        assert_eq!(lib.library[0].notes(), None);
+    }
+
+    #[test]
+    fn library_remap() {
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let mut a = test_song("a", "Artist", Duration::from_secs(1));
+        a.location = vec![URI::Local(PathBuf::from("D:\\Music\\a.flac"))];
+        lib.library.push(a);
+        lib.library.push(test_song("b", "Artist", Duration::from_secs(1)));
+
+        let mut remap = PathRemap::new();
+        remap.add_rule("D:\\Music", "/mnt/music");
+
+        assert_eq!(lib.apply_remap(&remap), 1);
+        assert_eq!(lib.library[0].location[0], URI::Local(PathBuf::from("/mnt/music/a.flac")));
+        // Applying the rules again does nothing
+        assert_eq!(lib.apply_remap(&remap), 0);
+    }
+
+    #[test]
+    fn scan_progress() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::write(folder.path().join("notes.txt"), "not music").unwrap();
+        std::fs::write(folder.path().join("cover.txt"), "still not music").unwrap();
+        std::fs::write(folder.path().join("broken.cue"), "FILE \"missing.flac\" WAVE\n").unwrap();
+
+        let mut lib = MusicLibrary::new(String::new(), Uuid::new_v4());
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let added = lib.scan_folder_progress(folder.path(), Some(&tx), &AtomicBool::new(false)).unwrap();
+        assert_eq!(added, 0);
+
+        let updates: Vec<ScanProgress> = rx.try_iter().collect();
+        assert_eq!(updates.len(), 3);
+        assert!(updates.iter().all(|update| update.total == 3));
+        assert_eq!(updates.iter().map(|update| update.scanned).max(), Some(3));
+        assert_eq!(updates.iter().map(|update| update.errors).max(), Some(1));
+
+        // A cancelled scan reads nothing more
+        lib.scan_folder_progress(folder.path(), Some(&tx), &AtomicBool::new(true)).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
 }
@@ -0,0 +1,545 @@
+//! The on-disk format of a [MusicLibrary]: a short header giving the
+//! version of the format, followed by the [bincode] encoded library.
+//!
+//! Bincode is not self-describing, so a library saved with different
+//! fields can't be read directly. Whenever the layout of [MusicLibrary]
+//! (or anything it contains) changes, [LIBRARY_VERSION] is increased and
+//! a migration is added to [MIGRATIONS] which turns the previous version
+//! into the new one, by decoding a frozen copy of the old structs and
+//! encoding a frozen copy of the new ones. Old files then load cleanly by running every
+//! migration between their version and the current one.
+//!
+//! Saves never overwrite the library in place. The new file is written
+//! and synced under a temporary name, and the last few saves are kept as
+//! backups which are loaded instead if the library file is corrupt.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::library::MusicLibrary;
+
+/// The bytes every versioned library file starts with
+const MAGIC: &[u8; 4] = b"DMPL";
+
+/// The version of the format written by [write_library]
+pub const LIBRARY_VERSION: u32 = 2;
+
+/// How many previous saves of the library are kept
+pub const LIBRARY_BACKUPS: usize = 3;
+
+#[derive(Error, Debug)]
+pub enum LibraryFormatError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to decode library: {0}")]
+    Decode(#[from] bincode::error::DecodeError),
+    #[error("failed to encode library: {0}")]
+    Encode(#[from] bincode::error::EncodeError),
+    #[error("library is version {0}, which is newer than this version of the player supports")]
+    TooNew(u32),
+}
+
+/// Turns the encoded library from one version of the format into the next
+type Migration = fn(Vec<u8>) -> Result<Vec<u8>, LibraryFormatError>;
+
+/// Every migration in order, where the migration at index `n` upgrades
+/// version `n` to version `n + 1`
+const MIGRATIONS: &[Migration] = &[
+    // Version 0 is the same library without a header
+    Ok,
+    playlist_folder_uuids,
+];
+
+/// The layout of version 1, before playlist folders had their own UUIDs.
+///
+/// These are copies of the structs as they were, so that changing the
+/// library later doesn't change how old files are read. Enums keep their
+/// variants in the same order, which is all bincode stores of them.
+mod v1 {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+    use file_format::FileFormat;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct MusicLibrary {
+        pub name: String,
+        pub uuid: Uuid,
+        pub library: Vec<Song>,
+        pub playlists: PlaylistFolder,
+        pub backup_songs: Vec<Song>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PlaylistFolder {
+        pub name: String,
+        pub items: Vec<PlaylistFolderItem>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum PlaylistFolderItem {
+        Folder(PlaylistFolder),
+        List(Playlist),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Playlist {
+        pub uuid: Uuid,
+        pub title: String,
+        pub cover: Option<AlbumArt>,
+        pub tracks: Vec<Uuid>,
+        pub sort_order: SortOrder,
+        pub play_count: i32,
+        pub play_time: Duration,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum SortOrder {
+        Manual,
+        Tag(Vec<Tag>),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Song {
+        pub location: Vec<Uri>,
+        pub uuid: Uuid,
+        pub plays: i32,
+        pub skips: i32,
+        pub favorited: bool,
+        pub banned: Option<BannedType>,
+        pub rating: Option<u8>,
+        pub format: Option<FileFormat>,
+        pub duration: Duration,
+        pub play_time: Duration,
+        #[serde(with = "ts_milliseconds_option")]
+        pub last_played: Option<DateTime<Utc>>,
+        #[serde(with = "ts_milliseconds_option")]
+        pub date_added: Option<DateTime<Utc>>,
+        #[serde(with = "ts_milliseconds_option")]
+        pub date_modified: Option<DateTime<Utc>>,
+        pub album_art: Vec<AlbumArt>,
+        pub tags: BTreeMap<Tag, String>,
+        pub internal_tags: Vec<InternalTag>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum Uri {
+        Local(PathBuf),
+        Cue {
+            location: PathBuf,
+            index: usize,
+            start: Duration,
+            end: Duration,
+        },
+        Remote(Service, String),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum Service {
+        InternetRadio,
+        Spotify,
+        Youtube,
+        None,
+        Subsonic,
+        Jellyfin,
+        Plex,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum BannedType {
+        Shuffle,
+        All,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum AlbumArt {
+        Embedded(usize),
+        External(Uri),
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Tag {
+        Title,
+        Album,
+        Artist,
+        AlbumArtist,
+        Genre,
+        Comment,
+        Track,
+        Disk,
+        Key(String),
+        Field(String),
+        Date,
+        MusicBrainzRecordingId,
+        MusicBrainzReleaseId,
+        MusicBrainzReleaseGroupId,
+        MusicBrainzArtistId,
+        MusicBrainzAlbumArtistId,
+        Bpm,
+        InitialKey,
+        Remixer,
+        Producer,
+        Featured,
+        DjMixer,
+        ReleaseType,
+        RipStatus,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum InternalTag {
+        DoNotTrack(DoNotTrack),
+        SongType(SongType),
+        SongLink(Uuid, SongType),
+        VolumeAdjustment(i8),
+        Label(String),
+        Note(String),
+        AlbumNote(String),
+        Chapters(Vec<Chapter>),
+        FileIdentity(FileIdentity),
+        Fingerprint(Fingerprint),
+        Lyrics(Lyrics),
+        ExactDuration,
+        SilenceTrim(SilenceTrim),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum DoNotTrack {
+        LastFM,
+        LibreFM,
+        MusicBrainz,
+        Discord,
+        History,
+        Scrobbling,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum SongType {
+        Main,
+        Instrumental,
+        Remix,
+        Custom(String),
+        Audiobook,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Chapter {
+        pub title: String,
+        pub start: Duration,
+        pub end: Option<Duration>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct FileIdentity {
+        pub size: u64,
+        pub hash: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Fingerprint {
+        pub duration: u32,
+        pub raw: Vec<u32>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum Lyrics {
+        Unsynced(String),
+        Synced(Vec<LyricLine>),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct LyricLine {
+        pub time: Duration,
+        pub text: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct SilenceTrim {
+        pub start: Duration,
+        pub end: Duration,
+    }
+}
+
+/// The layout of version 2, where only playlist folders changed, so the
+/// songs and playlists of [v1] are the same
+mod v2 {
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use super::v1::{Playlist, Song};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct MusicLibrary {
+        pub name: String,
+        pub uuid: Uuid,
+        pub library: Vec<Song>,
+        pub playlists: PlaylistFolder,
+        pub backup_songs: Vec<Song>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PlaylistFolder {
+        pub uuid: Uuid,
+        pub name: String,
+        pub items: Vec<PlaylistFolderItem>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum PlaylistFolderItem {
+        Folder(PlaylistFolder),
+        List(Playlist),
+    }
+}
+
+/// Give every playlist folder a UUID of its own
+fn playlist_folder_uuids(payload: Vec<u8>) -> Result<Vec<u8>, LibraryFormatError> {
+    fn convert(old: v1::PlaylistFolder) -> v2::PlaylistFolder {
+        let items = old
+            .items
+            .into_iter()
+            .map(|item| match item {
+                v1::PlaylistFolderItem::Folder(inner) => v2::PlaylistFolderItem::Folder(convert(inner)),
+                v1::PlaylistFolderItem::List(playlist) => v2::PlaylistFolderItem::List(playlist),
+            })
+            .collect();
+        v2::PlaylistFolder { uuid: Uuid::new_v4(), name: old.name, items }
+    }
+
+    let (old, _): (v1::MusicLibrary, _) = bincode::serde::decode_from_slice(&payload, config())?;
+    let library = v2::MusicLibrary {
+        name: old.name,
+        uuid: old.uuid,
+        library: old.library,
+        playlists: convert(old.playlists),
+        backup_songs: old.backup_songs,
+    };
+    Ok(bincode::serde::encode_to_vec(&library, config())?)
+}
+
+/// The [bincode] configuration every library is encoded with
+pub(super) fn config() -> impl bincode::config::Config {
+    bincode::config::standard()
+        .with_little_endian()
+        .with_variable_int_encoding()
+}
+
+/// Split a library file into its version and the encoded library.
+/// Files without a header were written before the format was versioned.
+fn split_header(data: &[u8]) -> (u32, &[u8]) {
+    match data.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let version = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            (version, &rest[4..])
+        }
+        _ => (0, data),
+    }
+}
+
+/// Decode a library file of any version, migrating it to the current one
+pub fn decode_library(data: &[u8]) -> Result<MusicLibrary, LibraryFormatError> {
+    let (version, payload) = split_header(data);
+    if version > LIBRARY_VERSION {
+        return Err(LibraryFormatError::TooNew(version));
+    }
+
+    let mut payload = payload.to_vec();
+    for migration in &MIGRATIONS[version as usize..] {
+        payload = migration(payload)?;
+    }
+
+    let (library, _) = bincode::serde::decode_from_slice(&payload, config())?;
+    Ok(library)
+}
+
+/// Encode a library with the header of the current version
+pub fn encode_library(library: &MusicLibrary) -> Result<Vec<u8>, LibraryFormatError> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&LIBRARY_VERSION.to_le_bytes());
+    data.extend(bincode::serde::encode_to_vec(library, config())?);
+    Ok(data)
+}
+
+/// The path of the `n`th most recent backup of the library at `path`,
+/// starting from 1
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak{}", n));
+    path.with_file_name(name)
+}
+
+/// Whether there is a library at `path`, or a backup to recover it from
+pub(super) fn library_exists(path: &Path) -> bool {
+    path.exists() || (1..=LIBRARY_BACKUPS).any(|n| backup_path(path, n).exists())
+}
+
+/// Read a library file written by any version of the player.
+///
+/// If the file is missing or corrupt, the most recent backup which can be
+/// read is restored in its place, and the corrupt file is kept beside it.
+pub(super) fn read_library(path: &Path) -> Result<MusicLibrary, LibraryFormatError> {
+    let error = match fs::read(path).map_err(LibraryFormatError::from).and_then(|data| decode_library(&data)) {
+        Ok(library) => return Ok(library),
+        // The file is fine, this version just can't read it
+        Err(error @ LibraryFormatError::TooNew(_)) => return Err(error),
+        Err(error) => error,
+    };
+
+    for n in 1..=LIBRARY_BACKUPS {
+        let backup = backup_path(path, n);
+        let library = match fs::read(&backup).map_err(LibraryFormatError::from).and_then(|data| decode_library(&data)) {
+            Ok(library) => library,
+            Err(_) => continue,
+        };
+
+        println!("Library {} could not be read ({}), recovering from {}", path.display(), error, backup.display());
+        if path.exists() {
+            let mut corrupt = path.as_os_str().to_os_string();
+            corrupt.push(".corrupt");
+            fs::rename(path, corrupt)?;
+        }
+        fs::copy(&backup, path)?;
+        return Ok(library);
+    }
+    Err(error)
+}
+
+/// Write out a library in the current format. The old file is only
+/// replaced once the new one is safely on the disk, and is kept as the
+/// most recent backup.
+pub(super) fn write_library(library: &MusicLibrary, path: &Path) -> Result<(), LibraryFormatError> {
+    let data = encode_library(library)?;
+
+    let mut writer_name = PathBuf::from(path);
+    writer_name.set_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&writer_name)?);
+    writer.write_all(&data)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    // Shift the backups along, dropping the oldest
+    for n in (1..LIBRARY_BACKUPS).rev() {
+        let backup = backup_path(path, n);
+        if backup.exists() {
+            fs::rename(&backup, backup_path(path, n + 1))?;
+        }
+    }
+    if LIBRARY_BACKUPS > 0 && path.exists() {
+        fs::rename(path, backup_path(path, 1))?;
+    }
+    fs::rename(writer_name, path)?;
+
+    // Make sure the renames themselves survive a crash
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        backup_path, decode_library, encode_library, read_library, v1, write_library, LibraryFormatError,
+        LIBRARY_BACKUPS, LIBRARY_VERSION, MAGIC,
+    };
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::music_storage::library::{MusicLibrary, Tag};
+
+    #[test]
+    fn library_versions() {
+        let folder = tempfile::tempdir().unwrap();
+        let library = MusicLibrary::from_path(&folder.path().join("library.dlib")).unwrap();
+
+        // Libraries saved before the header existed still load
+        let song = v1::Song {
+            location: vec![v1::Uri::Local("song.flac".into())],
+            uuid: Uuid::new_v4(),
+            plays: 3,
+            skips: 0,
+            favorited: true,
+            banned: None,
+            rating: Some(4),
+            format: None,
+            duration: Duration::from_secs(180),
+            play_time: Duration::ZERO,
+            last_played: None,
+            date_added: Some(chrono::Utc::now()),
+            date_modified: None,
+            album_art: vec![v1::AlbumArt::Embedded(0)],
+            tags: BTreeMap::from([(v1::Tag::Title, "Song".to_string())]),
+            internal_tags: vec![v1::InternalTag::Label("workout".to_string())],
+        };
+        let playlist = v1::Playlist {
+            uuid: Uuid::new_v4(),
+            title: "Playlist".to_string(),
+            cover: None,
+            tracks: vec![song.uuid],
+            sort_order: v1::SortOrder::Manual,
+            play_count: 0,
+            play_time: Duration::ZERO,
+        };
+        let old = v1::MusicLibrary {
+            name: library.name.clone(),
+            uuid: library.uuid,
+            library: vec![song],
+            playlists: v1::PlaylistFolder {
+                name: "Folder".to_string(),
+                items: vec![v1::PlaylistFolderItem::List(playlist)],
+            },
+            backup_songs: Vec::new(),
+        };
+        let legacy = bincode::serde::encode_to_vec(&old, super::config()).unwrap();
+        let migrated = decode_library(&legacy).unwrap();
+        assert_eq!(migrated.uuid, library.uuid);
+        assert_eq!(migrated.playlists.name(), "Folder");
+        assert_eq!(migrated.playlists.items().len(), 1);
+        assert_eq!(migrated.library[0].get_tag(&Tag::Title).map(String::as_str), Some("Song"));
+        assert_eq!(migrated.library[0].labels().len(), 1);
+
+        // Version 1 gives its playlist folders UUIDs
+        let mut version_1 = MAGIC.to_vec();
+        version_1.extend_from_slice(&1u32.to_le_bytes());
+        version_1.extend_from_slice(&legacy);
+        assert_eq!(decode_library(&version_1).unwrap().playlists.name(), "Folder");
+
+        let current = encode_library(&library).unwrap();
+        assert!(current.starts_with(MAGIC));
+        assert_eq!(decode_library(&current).unwrap().uuid, library.uuid);
+
+        let mut future = current.clone();
+        future[4..8].copy_from_slice(&(LIBRARY_VERSION + 1).to_le_bytes());
+        assert!(matches!(decode_library(&future), Err(LibraryFormatError::TooNew(_))));
+    }
+
+    #[test]
+    fn library_recovery() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("library.dlib");
+        let mut library = MusicLibrary::from_path(&path).unwrap();
+
+        for name in ["first", "second", "third", "fourth", "fifth"] {
+            library.name = name.to_string();
+            write_library(&library, &path).unwrap();
+        }
+        assert!(backup_path(&path, LIBRARY_BACKUPS).exists());
+        assert!(!backup_path(&path, LIBRARY_BACKUPS + 1).exists());
+
+        // A save which died part way through leaves a truncated file
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+        assert_eq!(read_library(&path).unwrap().name, "fourth");
+        assert_eq!(read_library(&path).unwrap().name, "fourth");
+        assert!(folder.path().join("library.dlib.corrupt").exists());
+    }
+}
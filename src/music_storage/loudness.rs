@@ -0,0 +1,360 @@
+//! Measuring the loudness of songs to work out their ReplayGain, so songs
+//! without ReplayGain tags can still be normalized
+//!
+//! Loudness is measured as in EBU R128, and gains are given against the
+//! ReplayGain 2.0 reference of -18 LUFS
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use lofty::{ItemKey, ItemValue, ParseOptions, Probe, TagExt, TagItem, TaggedFileExt};
+use rayon::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::decode::{decode_file, DecodeFormat};
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// The loudness every song is brought to by ReplayGain 2.0
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Blocks quieter than this are never counted, as they are silence
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this far below the loudness of the rest aren't counted
+const RELATIVE_GATE: f64 = -10.0;
+
+/// A second order IIR filter
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+/// The two stages of the K-weighting filter, for a sample rate
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    // The high shelf which models the head
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    // The high pass which ignores the lowest frequencies
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+/// Measures the loudness of audio as it is given interleaved samples
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    /// The number of frames in 100ms, the step between blocks
+    step: usize,
+    /// The frames and weighted energy of the step being filled
+    frames: usize,
+    energy: f64,
+    /// The energy of every 100ms step so far
+    steps: Vec<f64>,
+    /// The energy of each 400ms block, which overlap by 300ms
+    blocks: Vec<f64>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        // The LFE channel of 5.1 audio isn't counted, and the surrounds count more
+        let weights = (0..channels)
+            .map(|channel| match (channels, channel) {
+                (6, 3) => 0.0,
+                (6, 4 | 5) => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+        LoudnessMeter {
+            channels,
+            filters: vec![k_weighting(rate as f64); channels],
+            weights,
+            step: (rate as usize / 10).max(1),
+            frames: 0,
+            energy: 0.0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    /// Measure interleaved samples, where `1` is full scale
+    pub fn add_samples(&mut self, samples: &[f64]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.abs());
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(*sample));
+                self.energy += self.weights[channel] * weighted * weighted;
+            }
+
+            self.frames += 1;
+            if self.frames == self.step {
+                self.steps.push(self.energy / self.step as f64);
+                self.frames = 0;
+                self.energy = 0.0;
+                if self.steps.len() >= 4 {
+                    let block = self.steps[self.steps.len() - 4..].iter().sum::<f64>() / 4.0;
+                    self.blocks.push(block);
+                }
+            }
+        }
+    }
+
+    /// The highest sample so far, where `1` is full scale
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+
+    /// The integrated loudness in LUFS, `None` if it is all silence
+    pub fn loudness(&self) -> Option<f64> {
+        gated_loudness(self.blocks.iter().copied())
+    }
+
+    /// The loudness of several meters together, such as the songs of an album
+    pub fn combined_loudness(meters: &[&LoudnessMeter]) -> Option<f64> {
+        gated_loudness(meters.iter().flat_map(|meter| meter.blocks.iter().copied()))
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// The loudness of the blocks which pass both gates
+fn gated_loudness(blocks: impl Iterator<Item = f64> + Clone) -> Option<f64> {
+    let mean = |blocks: &mut dyn Iterator<Item = f64>| {
+        let (sum, count) = blocks.fold((0.0, 0usize), |(sum, count), block| (sum + block, count + 1));
+        (count > 0).then(|| sum / count as f64)
+    };
+    let loud = blocks.filter(|block| energy_to_loudness(*block) > ABSOLUTE_GATE);
+    let relative_gate = energy_to_loudness(mean(&mut loud.clone())?) + RELATIVE_GATE;
+    let gated = mean(&mut loud.filter(|block| energy_to_loudness(*block) > relative_gate))?;
+    Some(energy_to_loudness(gated))
+}
+
+/// Decode a file and measure its loudness
+pub fn measure_file(path: &Path) -> Result<LoudnessMeter, Box<dyn Error>> {
+    let meter: Arc<Mutex<Option<LoudnessMeter>>> = Arc::new(Mutex::new(None));
+    let meter_ = meter.clone();
+    decode_file(path, DecodeFormat::default(), move |rate, channels, samples| {
+        meter_
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| LoudnessMeter::new(rate, channels))
+            .add_samples(samples);
+    })?;
+    let meter = meter.lock().unwrap().take();
+    Ok(meter.ok_or("the file has no audio")?)
+}
+
+/// The gain to bring audio of a loudness to [REFERENCE_LOUDNESS], in dB
+pub fn gain_for(loudness: f64) -> f64 {
+    REFERENCE_LOUDNESS - loudness
+}
+
+/// The results of [MusicLibrary::scan_replay_gain]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayGainScan {
+    pub scanned: Vec<Uuid>,
+    /// Songs which couldn't be measured, and why
+    pub failed: Vec<(Uuid, String)>,
+}
+
+impl Song {
+    fn has_replay_gain(&self) -> bool {
+        self.get_tag(&Tag::Key("ReplayGainTrackGain".to_string())).is_some()
+    }
+
+    /// Write the ReplayGain stored in the library into the tags of the file
+    pub fn write_replay_gain(&self) -> Result<(), Box<dyn Error>> {
+        let location = self.primary_uri()?.0.as_path()?;
+
+        let normal_options = ParseOptions::new().parsing_mode(lofty::ParsingMode::Relaxed);
+        let mut tagged_file = Probe::open(location)?.options(normal_options).read()?;
+
+        let tag_type = tagged_file.primary_tag_type();
+        if tagged_file.tag(tag_type).is_none() {
+            tagged_file.insert_tag(lofty::Tag::new(tag_type));
+        }
+        let tag = tagged_file.tag_mut(tag_type).unwrap();
+
+        let keys = [
+            ItemKey::ReplayGainTrackGain,
+            ItemKey::ReplayGainTrackPeak,
+            ItemKey::ReplayGainAlbumGain,
+            ItemKey::ReplayGainAlbumPeak,
+        ];
+        for key in keys {
+            tag.remove_key(&key);
+            if let Some(value) = self.get_tag(&Tag::Key(format!("{:?}", key))) {
+                tag.insert(TagItem::new(key, ItemValue::Text(value.clone())));
+            }
+        }
+
+        tag.save_to_path(location)?;
+        Ok(())
+    }
+}
+
+impl MusicLibrary {
+    /// Measure the loudness of songs and store their ReplayGain in the
+    /// library, where the player finds it the same as ReplayGain tags.
+    ///
+    /// Every album with a song which has no ReplayGain is measured, or every
+    /// album if `rescan` is set, as album gain depends on all of its songs.
+    /// With `write_tags` the ReplayGain is written into the files too.
+    pub fn scan_replay_gain(&mut self, rescan: bool, write_tags: bool) -> ReplayGainScan {
+        // Songs without an album are measured alone
+        let mut albums: HashMap<Option<(String, String)>, Vec<usize>> = HashMap::new();
+        let mut singles = Vec::new();
+        for (index, song) in self.library.iter().enumerate() {
+            // The tracks of a cue sheet share a file, so they can't be measured alone
+            if !matches!(song.location.first(), Some(URI::Local(_))) {
+                continue;
+            }
+            let album = song.get_tag(&Tag::Album).map(|album| {
+                let artist = song.get_tag(&Tag::AlbumArtist).or(song.get_tag(&Tag::Artist));
+                (artist.cloned().unwrap_or_default(), album.clone())
+            });
+            match album {
+                Some(_) => albums.entry(album).or_default().push(index),
+                None => singles.push(vec![index]),
+            }
+        }
+        let groups: Vec<(bool, Vec<usize>)> = albums
+            .into_values()
+            .map(|indices| (true, indices))
+            .chain(singles.into_iter().map(|indices| (false, indices)))
+            .filter(|(_, indices)| rescan || indices.iter().any(|i| !self.library[*i].has_replay_gain()))
+            .collect();
+
+        let library = &self.library;
+        let measured: Vec<_> = groups
+            .into_par_iter()
+            .map(|(is_album, indices)| {
+                let meters: Vec<_> = indices
+                    .into_iter()
+                    .map(|index| {
+                        let meter = library[index]
+                            .primary_uri()
+                            .and_then(|(uri, _)| measure_file(&uri.path()))
+                            .map_err(|error| error.to_string());
+                        (index, meter)
+                    })
+                    .collect();
+                (is_album, meters)
+            })
+            .collect();
+
+        let mut scan = ReplayGainScan::default();
+        for (is_album, meters) in measured {
+            let album = is_album.then(|| {
+                let measured: Vec<&LoudnessMeter> = meters.iter().filter_map(|(_, meter)| meter.as_ref().ok()).collect();
+                let peak = measured.iter().map(|meter| meter.peak()).fold(0.0, f64::max);
+                LoudnessMeter::combined_loudness(&measured).map(|loudness| (gain_for(loudness), peak))
+            });
+
+            for (index, meter) in meters {
+                let song = &mut self.library[index];
+                let meter = match meter {
+                    Ok(meter) => meter,
+                    Err(error) => {
+                        scan.failed.push((song.uuid, error));
+                        continue;
+                    }
+                };
+                let Some(loudness) = meter.loudness() else {
+                    scan.failed.push((song.uuid, "the song is silent".to_string()));
+                    continue;
+                };
+
+                let mut set = |key: &str, value: String| song.set_tag(Tag::Key(key.to_string()), value);
+                set("ReplayGainTrackGain", format!("{:.2} dB", gain_for(loudness)));
+                set("ReplayGainTrackPeak", format!("{:.6}", meter.peak()));
+                if let Some(Some((gain, peak))) = album {
+                    set("ReplayGainAlbumGain", format!("{:.2} dB", gain));
+                    set("ReplayGainAlbumPeak", format!("{:.6}", peak));
+                }
+
+                if write_tags {
+                    if let Err(error) = song.write_replay_gain() {
+                        println!("Failed to write the ReplayGain of {}: {}", song.uuid, error);
+                    }
+                }
+                scan.scanned.push(song.uuid);
+            }
+        }
+        scan
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gain_for, LoudnessMeter};
+
+    /// Interleaved stereo samples of a sine wave
+    fn sine(rate: u32, frequency: f64, amplitude: f64, seconds: f64) -> Vec<f64> {
+        let frames = (rate as f64 * seconds) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (2.0 * std::f64::consts::PI * frequency * i as f64 / rate as f64).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ebu_loudness() {
+        // A 1kHz sine at -23dBFS in both channels measures -23 LUFS
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.add_samples(&sine(48000, 1000.0, 10f64.powf(-23.0 / 20.0), 20.0));
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness - -23.0).abs() < 0.1, "{loudness}");
+        assert!((gain_for(loudness) - 5.0).abs() < 0.1);
+        assert!((meter.peak() - 10f64.powf(-23.0 / 20.0)).abs() < 0.001);
+
+        // Quiet parts of a song don't drag down its loudness
+        let mut quiet = LoudnessMeter::new(48000, 2);
+        quiet.add_samples(&sine(48000, 1000.0, 10f64.powf(-23.0 / 20.0), 10.0));
+        quiet.add_samples(&sine(48000, 1000.0, 10f64.powf(-60.0 / 20.0), 10.0));
+        assert!((quiet.loudness().unwrap() - -23.0).abs() < 0.2);
+
+        let mut silence = LoudnessMeter::new(44100, 2);
+        silence.add_samples(&vec![0.0; 44100 * 2]);
+        assert_eq!(silence.loudness(), None);
+        assert!(LoudnessMeter::combined_loudness(&[&meter, &silence]).is_some());
+    }
+}
@@ -0,0 +1,332 @@
+//! Lyrics of songs, read from the lyrics tags of a file or from an `.lrc`
+//! file beside it, and optionally fetched from online providers
+//!
+//! Synchronized lyrics give the time each line is sung at, so the current
+//! line can be shown karaoke style while the song plays
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine as _};
+use lofty::id3::v2::{SynchronizedText, TimestampFormat};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// A line of synchronized lyrics
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LyricLine {
+    /// When the line starts, from the start of the song
+    pub time: Duration,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Lyrics {
+    /// Lyrics without any timing
+    Unsynced(String),
+    /// Lines of lyrics and the times they are sung at, in order
+    Synced(Vec<LyricLine>),
+}
+
+impl Lyrics {
+    /// Parse the contents of an `.lrc` file. Files without any timed lines
+    /// are read as unsynced lyrics.
+    pub fn parse_lrc(contents: &str) -> Option<Self> {
+        let mut offset: i64 = 0;
+        let mut lines = Vec::new();
+        let mut plain = Vec::new();
+
+        for line in contents.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            while let Some(tag) = rest.strip_prefix('[') {
+                let Some(end) = tag.find(']') else { break };
+                let (inside, after) = (&tag[..end], &tag[end + 1..]);
+                if let Some(time) = parse_timestamp(inside) {
+                    times.push(time);
+                } else if let Some(("offset", value)) = inside.split_once(':') {
+                    // A positive offset makes the lyrics appear sooner
+                    offset = value.trim().parse().unwrap_or(0);
+                }
+                rest = after;
+            }
+
+            let text = strip_word_times(rest.trim());
+            if times.is_empty() {
+                // Lines which were only metadata tags are dropped
+                if rest.len() == line.trim().len() {
+                    plain.push(text);
+                }
+                continue;
+            }
+            for time in times {
+                lines.push(LyricLine {
+                    time,
+                    text: text.clone(),
+                });
+            }
+        }
+
+        if lines.is_empty() {
+            let text = plain.join("\n").trim().to_string();
+            return (!text.is_empty()).then_some(Lyrics::Unsynced(text));
+        }
+        for line in &mut lines {
+            let millis = (line.time.as_millis() as i64 - offset).max(0);
+            line.time = Duration::from_millis(millis as u64);
+        }
+        lines.sort_by_key(|line| line.time);
+        Some(Lyrics::Synced(lines))
+    }
+
+    /// Read the contents of an ID3v2 `SYLT` frame. Lyrics timed by MPEG
+    /// frames can't be read.
+    pub fn from_sylt(data: &[u8]) -> Option<Self> {
+        let sylt = SynchronizedText::parse(data).ok()?;
+        if sylt.timestamp_format != TimestampFormat::MS || sylt.content.is_empty() {
+            return None;
+        }
+        let mut lines: Vec<LyricLine> = sylt
+            .content
+            .into_iter()
+            .map(|(time, text)| LyricLine {
+                time: Duration::from_millis(time as u64),
+                text: text.trim().to_string(),
+            })
+            .collect();
+        lines.sort_by_key(|line| line.time);
+        Some(Lyrics::Synced(lines))
+    }
+
+    /// Find the lyrics of a song, from an `.lrc` file with the same name
+    /// as it, then its synchronized lyrics tag, then its lyrics tag
+    pub fn read(song: &Song) -> Option<Self> {
+        // The tracks of a cue sheet share a file, so an .lrc can't be for one of them
+        if let Some(URI::Local(path)) = song.location.first() {
+            let lrc = fs::read_to_string(path.with_extension("lrc"));
+            if let Some(lyrics) = lrc.ok().and_then(|lrc| Self::parse_lrc(&lrc)) {
+                return Some(lyrics);
+            }
+        }
+
+        let sylt = song
+            .get_tag(&Tag::Key("SYLT".to_string()))
+            .and_then(|value| value.strip_prefix("BIN#"))
+            .and_then(|value| general_purpose::STANDARD.decode(value).ok());
+        if let Some(lyrics) = sylt.and_then(|data| Self::from_sylt(&data)) {
+            return Some(lyrics);
+        }
+
+        let text = song.get_tag(&Tag::Key("Lyrics".to_string()))?;
+        // Some taggers put synchronized lyrics in the plain lyrics tag
+        Self::parse_lrc(text)
+    }
+
+    pub fn is_synced(&self) -> bool {
+        matches!(self, Lyrics::Synced(_))
+    }
+
+    /// The lyrics without any timing
+    pub fn text(&self) -> String {
+        match self {
+            Lyrics::Unsynced(text) => text.clone(),
+            Lyrics::Synced(lines) => lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>().join("\n"),
+        }
+    }
+
+    /// The index of the line being sung at `position`, and the line itself.
+    /// Unsynced lyrics have no current line.
+    pub fn line_at(&self, position: Duration) -> Option<(usize, &LyricLine)> {
+        let Lyrics::Synced(lines) = self else {
+            return None;
+        };
+        // The number of lines which have started by now
+        let started = lines.partition_point(|line| line.time <= position);
+        let index = started.checked_sub(1)?;
+        Some((index, &lines[index]))
+    }
+
+    /// Write the lyrics as an `.lrc` file
+    pub fn write_lrc(&self, path: &Path) -> Result<(), std::io::Error> {
+        let contents = match self {
+            Lyrics::Unsynced(text) => text.clone(),
+            Lyrics::Synced(lines) => lines
+                .iter()
+                .map(|line| {
+                    let (minutes, seconds) = (line.time.as_secs() / 60, line.time.as_secs() % 60);
+                    let hundredths = line.time.subsec_millis() / 10;
+                    format!("[{:02}:{:02}.{:02}]{}\n", minutes, seconds, hundredths, line.text)
+                })
+                .collect(),
+        };
+        fs::write(path, contents)
+    }
+}
+
+/// Parse an `mm:ss.xx` timestamp, where the fraction is optional
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (minutes, seconds) = timestamp.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    // Some files separate the fraction with a second colon
+    let seconds = seconds.replacen(':', ".", 1);
+    let seconds: f64 = seconds.trim().parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    Some(Duration::from_secs(minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+/// Remove the `<mm:ss.xx>` word timings of enhanced LRC files
+fn strip_word_times(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else { break };
+        if parse_timestamp(&rest[start + 1..start + end]).is_none() {
+            stripped.push_str(&rest[..start + end + 1]);
+        } else {
+            stripped.push_str(&rest[..start]);
+        }
+        rest = &rest[start + end + 1..];
+    }
+    stripped.push_str(rest);
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A service which lyrics can be looked up from
+pub trait LyricsProvider {
+    fn name(&self) -> &str;
+
+    /// Look up the lyrics of a song, preferring synchronized ones
+    fn find_lyrics(&self, song: &Song) -> Result<Option<Lyrics>, Box<dyn Error>>;
+}
+
+/// Looks up lyrics from [LRCLIB](https://lrclib.net)
+#[derive(Debug, Clone)]
+pub struct LrcLib {
+    user_agent: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LrcLibLyrics {
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+impl LrcLib {
+    const API_URL: &'static str = "https://lrclib.net/api/get";
+
+    /// Create a provider which identifies itself with `user_agent`
+    pub fn new(user_agent: String) -> Self {
+        LrcLib { user_agent }
+    }
+}
+
+impl LyricsProvider for LrcLib {
+    fn name(&self) -> &str {
+        "lrclib"
+    }
+
+    fn find_lyrics(&self, song: &Song) -> Result<Option<Lyrics>, Box<dyn Error>> {
+        let (Some(title), Some(artist)) = (song.get_tag(&Tag::Title), song.get_tag(&Tag::Artist)) else {
+            return Ok(None);
+        };
+        let mut request = attohttpc::get(Self::API_URL)
+            .header("User-Agent", &self.user_agent)
+            .param("track_name", title)
+            .param("artist_name", artist)
+            .param("duration", song.duration.as_secs());
+        if let Some(album) = song.get_tag(&Tag::Album) {
+            request = request.param("album_name", album);
+        }
+
+        let response = request.send()?;
+        if response.status() == attohttpc::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let found: LrcLibLyrics = response.error_for_status()?.json()?;
+        let synced = found.synced_lyrics.as_deref().and_then(Lyrics::parse_lrc);
+        Ok(synced.or(found.plain_lyrics.and_then(|text| {
+            let text = text.trim().to_string();
+            (!text.is_empty()).then_some(Lyrics::Unsynced(text))
+        })))
+    }
+}
+
+impl MusicLibrary {
+    /// Read the lyrics of every song which doesn't have any stored from
+    /// its tags and `.lrc` files. Returns the number of songs updated.
+    pub fn read_lyrics(&mut self) -> usize {
+        let mut found = 0;
+        for song in self.library.iter_mut().filter(|song| song.lyrics().is_none()) {
+            if let Some(lyrics) = Lyrics::read(song) {
+                song.set_lyrics(Some(lyrics));
+                found += 1;
+            }
+        }
+        found
+    }
+
+    /// Look up the lyrics of a song from a provider, storing them if they
+    /// were found. Synchronized lyrics are never replaced by unsynced ones.
+    pub fn fetch_lyrics(&mut self, uuid: &Uuid, provider: &dyn LyricsProvider) -> Result<bool, Box<dyn Error>> {
+        let (song, index) = self.query_uuid(uuid).ok_or("the song is not in the library")?;
+        let lyrics = match provider.find_lyrics(song)? {
+            Some(lyrics) => lyrics,
+            None => return Ok(false),
+        };
+        if !lyrics.is_synced() && song.lyrics().is_some_and(Lyrics::is_synced) {
+            return Ok(false);
+        }
+        self.library[index].set_lyrics(Some(lyrics));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{parse_timestamp, LyricLine, Lyrics};
+
+    #[test]
+    fn parse_lrc() {
+        assert_eq!(parse_timestamp("01:02.50"), Some(Duration::from_millis(62_500)));
+        assert_eq!(parse_timestamp("00:05"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_timestamp("ar:Artist"), None);
+
+        let lyrics = Lyrics::parse_lrc(
+            "[ar:Artist]\n[ti:Song]\n[offset:500]\n\
+             [00:10.00]First <00:10.50>line\n\
+             [00:20.00][00:40.00]Chorus\n\
+             [00:30.00]Second line\n",
+        )
+        .unwrap();
+        let line = |secs: f64, text: &str| LyricLine {
+            time: Duration::from_secs_f64(secs),
+            text: text.to_string(),
+        };
+        assert_eq!(
+            lyrics,
+            Lyrics::Synced(vec![
+                line(9.5, "First line"),
+                line(19.5, "Chorus"),
+                line(29.5, "Second line"),
+                line(39.5, "Chorus"),
+            ])
+        );
+
+        assert_eq!(lyrics.line_at(Duration::from_secs(5)), None);
+        assert_eq!(lyrics.line_at(Duration::from_secs(25)).unwrap().0, 1);
+        assert_eq!(lyrics.line_at(Duration::from_secs(100)).unwrap().1.text, "Chorus");
+
+        let plain = Lyrics::parse_lrc("Just some\nwords").unwrap();
+        assert_eq!(plain, Lyrics::Unsynced("Just some\nwords".to_string()));
+        assert_eq!(plain.line_at(Duration::from_secs(1)), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! A [LibraryStore] which only lives in memory and never touches the
+//! filesystem, for tests of code which works on a library
+//!
+//! The songs are kept in a [MusicLibrary], so playlists, smart playlists,
+//! and searches can be run against [MemoryStore::library] just like a
+//! library read from disk.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+use super::playlist::PlaylistFolder;
+use super::store::LibraryStore;
+
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    library: MusicLibrary,
+    /// The index of each song in the library by its [Uuid]
+    index: HashMap<Uuid, usize>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new(MusicLibrary::new(String::new(), Uuid::new_v4()))
+    }
+}
+
+impl MemoryStore {
+    /// A store holding the songs of `library`
+    pub fn new(library: MusicLibrary) -> Self {
+        let mut store = MemoryStore { library, index: HashMap::new() };
+        store.reindex();
+        store
+    }
+
+    /// A store holding `songs`, in order
+    pub fn with_songs(songs: Vec<Song>) -> Self {
+        let mut store = MemoryStore::default();
+        store.library.library = songs;
+        store.reindex();
+        store
+    }
+
+    /// The library the songs are in, to use anywhere a [MusicLibrary] is needed
+    pub fn library(&self) -> &MusicLibrary {
+        &self.library
+    }
+
+    pub fn playlists_mut(&mut self) -> &mut PlaylistFolder {
+        &mut self.library.playlists
+    }
+
+    pub fn into_library(self) -> MusicLibrary {
+        self.library
+    }
+
+    fn reindex(&mut self) {
+        self.index = self.library.library.iter().enumerate().map(|(i, song)| (song.uuid, i)).collect();
+    }
+}
+
+impl LibraryStore for MemoryStore {
+    fn len(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.library.library.len())
+    }
+
+    fn get(&self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        Ok(self.index.get(uuid).map(|&i| self.library.library[i].clone()))
+    }
+
+    fn get_uri(&self, uri: &URI) -> Result<Option<Song>, Box<dyn Error>> {
+        Ok(self.library.library.iter().find(|song| song.location.contains(uri)).cloned())
+    }
+
+    fn insert(&mut self, song: Song) -> Result<(), Box<dyn Error>> {
+        match self.index.get(&song.uuid) {
+            Some(&i) => self.library.library[i] = song,
+            None => {
+                self.index.insert(song.uuid, self.library.library.len());
+                self.library.library.push(song);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        let Some(i) = self.index.remove(uuid) else {
+            return Ok(None);
+        };
+        let song = self.library.library.remove(i);
+        // Every song after the removed one moved down by one
+        for index in self.index.values_mut().filter(|index| **index > i) {
+            *index -= 1;
+        }
+        Ok(Some(song))
+    }
+
+    fn with_tag(&self, tag: &Tag, value: &str) -> Result<Vec<Song>, Box<dyn Error>> {
+        self.library.with_tag(tag, value)
+    }
+
+    fn page(&self, offset: usize, limit: usize) -> Result<Vec<Song>, Box<dyn Error>> {
+        self.library.page(offset, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::MemoryStore;
+    use crate::music_storage::library::{test::test_song, Tag, URI};
+    use crate::music_storage::smart_playlist::{SmartPlaylist, SmartRule};
+    use crate::music_storage::store::LibraryStore;
+
+    #[test]
+    fn memory_store() {
+        let mut store = MemoryStore::default();
+        let mut songs = Vec::new();
+        for (track, title) in ["One", "Two", "Three"].iter().enumerate() {
+            let mut song = test_song(title, "Artist", Duration::from_secs(200));
+            // None of the files exist, and nothing needs them to
+            song.location = vec![URI::Local(PathBuf::from(format!("/nowhere/{}.flac", title)))];
+            song.set_tag(Tag::Album, "Album".to_string());
+            song.set_tag(Tag::Track, (track + 1).to_string());
+            songs.push(song.clone());
+            store.insert(song).unwrap();
+        }
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(store.get(&songs[1].uuid).unwrap(), Some(songs[1].clone()));
+        assert_eq!(store.get_uri(&songs[2].location[0]).unwrap(), Some(songs[2].clone()));
+        assert_eq!(store.page(1, 5).unwrap().len(), 2);
+
+        assert_eq!(store.remove(&songs[0].uuid).unwrap(), Some(songs[0].clone()));
+        assert_eq!(store.get(&songs[2].uuid).unwrap(), Some(songs[2].clone()));
+        assert_eq!(store.with_tag(&Tag::Album, "Album").unwrap().len(), 2);
+
+        let library = store.library();
+        assert_eq!(library.albums()["Album"].len(), 2);
+        let playlist = SmartPlaylist::new(
+            "Threes".to_string(),
+            vec![SmartRule::Contains { tag: Tag::Title, value: "three".to_string() }],
+        );
+        assert_eq!(playlist.songs(library).len(), 1);
+    }
+}
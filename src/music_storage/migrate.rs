@@ -0,0 +1,343 @@
+//! One call migrations from other players, which import their songs,
+//! merge them with songs already in the library, bring their playlists
+//! across, and share album art between the songs of each album
+//!
+//! Each step sends a [MigrationProgress] so a single progress bar can
+//! follow the whole migration
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hash;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Sender};
+use uuid::Uuid;
+
+use super::db_reader::extern_library::ExternalLibrary;
+use super::db_reader::itunes::reader::{read_playlists, ITunesLibrary};
+use super::library::{AlbumArt, MusicLibrary, ScanProgress, Song, Tag};
+use super::path_remap::PathRemap;
+use super::playlist::Playlist;
+use super::playlist_import::import_playlist_from;
+use super::utils::normalize;
+
+/// How different the durations of two songs can be for them to be the
+/// same recording
+const DUPLICATE_TOLERANCE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStage {
+    /// Reading the files of the songs
+    Scanning,
+    /// Adding the songs to the library
+    Songs,
+    Playlists,
+    Art,
+}
+
+/// How far through a migration is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub stage: MigrationStage,
+    /// The number of items of this stage which are done
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MigrationOptions {
+    /// Merge songs which are already in the library at another location,
+    /// adding up their plays, rather than adding them again
+    pub merge_duplicates: bool,
+    pub playlists: bool,
+    /// Give songs without art the art of the other songs of their album
+    pub share_art: bool,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        MigrationOptions {
+            merge_duplicates: true,
+            playlists: true,
+            share_art: true,
+        }
+    }
+}
+
+/// What a migration did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub added: usize,
+    /// Songs which were merged into songs already in the library
+    pub merged: usize,
+    /// Songs which were already in the library at the same location
+    pub skipped: usize,
+    pub playlists: usize,
+    /// Playlist entries which weren't found in the library
+    pub unmatched: usize,
+    /// Songs which were given art from the rest of their album
+    pub art: usize,
+}
+
+/// Migrate the songs and playlists of an iTunes library, from the XML
+/// file exported by iTunes
+pub fn migrate_from_itunes(
+    xml: &Path,
+    library: &mut MusicLibrary,
+    options: &MigrationOptions,
+    progress: Option<&Sender<MigrationProgress>>,
+) -> Result<MigrationReport, Box<dyn Error>> {
+    if !xml.is_file() {
+        return Err(format!("{:?} is not an iTunes library", xml).into());
+    }
+    let mut report = MigrationReport::default();
+    let songs = ITunesLibrary::from_file(xml).songs_by_id();
+    let ids = add_songs(library, songs, options, &mut report, progress);
+
+    if options.playlists {
+        let playlists = read_playlists(xml)?
+            .into_iter()
+            .map(|playlist| (playlist.name, playlist.track_ids));
+        add_playlists(library, playlists.collect(), &ids, &mut report, progress);
+    }
+    if options.share_art {
+        report.art = share_art(library, &ids.into_values().collect::<Vec<_>>(), progress);
+    }
+    Ok(report)
+}
+
+/// Migrate the songs in an MPD music directory, along with the playlists
+/// in its playlist directory. Play counts and ratings kept in MPD's
+/// sticker database aren't migrated.
+pub fn migrate_from_mpd(
+    music_directory: &Path,
+    playlist_directory: Option<&Path>,
+    library: &mut MusicLibrary,
+    options: &MigrationOptions,
+    progress: Option<&Sender<MigrationProgress>>,
+) -> Result<MigrationReport, Box<dyn Error>> {
+    // The songs are read on their own first, so they can be merged with
+    // the ones in the library
+    let mut scanned = MusicLibrary::new(String::new(), Uuid::new_v4());
+    thread::scope(|scope| {
+        let (tx, rx) = unbounded::<ScanProgress>();
+        scope.spawn(move || {
+            for update in rx {
+                send(progress, MigrationStage::Scanning, update.scanned, update.total);
+            }
+        });
+        scanned.scan_folder_progress(music_directory, Some(&tx), &AtomicBool::new(false))
+    })?;
+
+    let mut report = MigrationReport::default();
+    let songs = scanned.library.iter().map(|song| (song.uuid, song.clone())).collect();
+    let ids = add_songs(library, songs, options, &mut report, progress);
+
+    if let (true, Some(directory)) = (options.playlists, playlist_directory) {
+        let mut playlists = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "m3u") {
+                continue;
+            }
+            // MPD playlists are relative to the music directory
+            match import_playlist_from(&path, music_directory, &scanned, &PathRemap::default()) {
+                Ok(import) => {
+                    report.unmatched += import.unmatched.len();
+                    playlists.push((import.playlist.title().clone(), import.playlist.tracks()));
+                }
+                Err(error) => println!("Failed to read {:?}: {}", path, error),
+            }
+        }
+        add_playlists(library, playlists, &ids, &mut report, progress);
+    }
+    if options.share_art {
+        report.art = share_art(library, &ids.into_values().collect::<Vec<_>>(), progress);
+    }
+    Ok(report)
+}
+
+fn send(progress: Option<&Sender<MigrationProgress>>, stage: MigrationStage, done: usize, total: usize) {
+    if let Some(progress) = progress {
+        let _ = progress.send(MigrationProgress { stage, done, total });
+    }
+}
+
+/// Add songs to the library, returning the song each of their keys ended
+/// up as, which may be a song that was already in the library
+fn add_songs<K: Hash + Eq>(
+    library: &mut MusicLibrary,
+    songs: Vec<(K, Song)>,
+    options: &MigrationOptions,
+    report: &mut MigrationReport,
+    progress: Option<&Sender<MigrationProgress>>,
+) -> HashMap<K, Uuid> {
+    let total = songs.len();
+    let mut ids = HashMap::new();
+    for (done, (key, song)) in songs.into_iter().enumerate() {
+        send(progress, MigrationStage::Songs, done + 1, total);
+
+        let existing = song.location.iter().find_map(|uri| library.query_uri(uri)).map(|(_, i)| i);
+        if let Some(index) = existing {
+            report.skipped += 1;
+            ids.insert(key, library.library[index].uuid);
+            continue;
+        }
+        if let Some(index) = options.merge_duplicates.then(|| find_duplicate(library, &song)).flatten() {
+            merge_song(&mut library.library[index], &song);
+            report.merged += 1;
+            ids.insert(key, library.library[index].uuid);
+            continue;
+        }
+
+        let uuid = song.uuid;
+        match library.add_song(song) {
+            Ok(()) => {
+                report.added += 1;
+                ids.insert(key, uuid);
+            }
+            Err(error) => println!("Failed to add a song: {}", error),
+        }
+    }
+    ids
+}
+
+/// The index of a song in the library which is the same recording as
+/// `song`, going by its title, artist, album, and duration
+fn find_duplicate(library: &MusicLibrary, song: &Song) -> Option<usize> {
+    let key = |song: &Song| [Tag::Title, Tag::Artist, Tag::Album].map(|tag| song.get_tag(&tag).map(|v| normalize(v)));
+    let wanted = key(song);
+    wanted[0].as_ref()?;
+    library
+        .library
+        .iter()
+        .position(|other| key(other) == wanted && other.duration.abs_diff(song.duration) <= DUPLICATE_TOLERANCE)
+}
+
+/// Combine the listening stats of `from` into `into`
+fn merge_song(into: &mut Song, from: &Song) {
+    into.plays += from.plays;
+    into.skips += from.skips;
+    into.play_time += from.play_time;
+    into.favorited |= from.favorited;
+    into.rating = into.rating.or(from.rating);
+    into.last_played = into.last_played.max(from.last_played);
+    into.date_added = match (into.date_added, from.date_added) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+}
+
+/// Add playlists, given as their title and the keys of their songs
+fn add_playlists<K: Hash + Eq>(
+    library: &mut MusicLibrary,
+    playlists: Vec<(String, Vec<K>)>,
+    ids: &HashMap<K, Uuid>,
+    report: &mut MigrationReport,
+    progress: Option<&Sender<MigrationProgress>>,
+) {
+    let total = playlists.len();
+    for (done, (title, keys)) in playlists.into_iter().enumerate() {
+        send(progress, MigrationStage::Playlists, done + 1, total);
+        let tracks: Vec<Uuid> = keys.iter().filter_map(|key| ids.get(key).copied()).collect();
+        report.unmatched += keys.len() - tracks.len();
+
+        let mut playlist = Playlist::new();
+        playlist.set_title(title);
+        playlist.set_tracks(tracks);
+        library.playlists.add_playlist(playlist);
+        report.playlists += 1;
+    }
+}
+
+/// Give each of `songs` which has no art the art files of another song
+/// from the same album, returning the number of songs given art.
+/// Embedded art belongs to its file, so only art files are shared.
+fn share_art(library: &mut MusicLibrary, songs: &[Uuid], progress: Option<&Sender<MigrationProgress>>) -> usize {
+    let album = |song: &Song| {
+        let artist = song.get_tag(&Tag::AlbumArtist).or(song.get_tag(&Tag::Artist));
+        Some((normalize(song.get_tag(&Tag::Album)?), artist.map(|artist| normalize(artist))))
+    };
+    let mut covers: HashMap<_, Vec<AlbumArt>> = HashMap::new();
+    for song in &library.library {
+        let files: Vec<AlbumArt> = song.album_art.iter().filter(|art| art.uri().is_some()).cloned().collect();
+        if let (Some(album), false) = (album(song), files.is_empty()) {
+            covers.entry(album).or_insert(files);
+        }
+    }
+
+    let mut shared = 0;
+    for (done, uuid) in songs.iter().enumerate() {
+        send(progress, MigrationStage::Art, done + 1, songs.len());
+        let Some((_, index)) = library.query_uuid(uuid) else {
+            continue;
+        };
+        let song = &mut library.library[index];
+        if !song.album_art.is_empty() {
+            continue;
+        }
+        if let Some(files) = album(song).and_then(|album| covers.get(&album)) {
+            song.album_art = files.clone();
+            shared += 1;
+        }
+    }
+    shared
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crossbeam_channel::unbounded;
+
+    use super::{add_playlists, add_songs, share_art, MigrationOptions, MigrationReport, MigrationStage};
+    use crate::music_storage::library::{test::test_song, AlbumArt, MusicLibrary, Tag, URI};
+
+    #[test]
+    fn migrate_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = MusicLibrary::from_path(&dir.path().join("library.dlib")).unwrap();
+        // Songs are only added if their file exists
+        let song = |title: &str, seconds| {
+            let mut song = test_song(title, "Artist", Duration::from_secs(seconds));
+            song.set_tag(Tag::Album, "Album".to_string());
+            let path = dir.path().join(format!("{}.flac", title));
+            std::fs::write(&path, b"").unwrap();
+            song.location = vec![URI::Local(path)];
+            song
+        };
+        let mut existing = song("One", 200);
+        existing.album_art = vec![AlbumArt::External(URI::Local(PathBuf::from("/music/cover.jpg")))];
+        existing.plays = 2;
+        library.library.push(existing.clone());
+
+        // The same song from another player, a song at a path already in the library, and a new song
+        let mut copy = song("one (copy)", 201);
+        copy.set_tag(Tag::Title, "one".to_string());
+        copy.plays = 5;
+        copy.favorited = true;
+        let new = song("Two", 100);
+        let songs = vec![(1, copy), (2, existing.clone()), (3, new.clone())];
+
+        let (tx, rx) = unbounded();
+        let mut report = MigrationReport::default();
+        let ids = add_songs(&mut library, songs, &MigrationOptions::default(), &mut report, Some(&tx));
+        assert_eq!((report.added, report.merged, report.skipped), (1, 1, 1));
+        assert_eq!(library.library.len(), 2);
+        assert_eq!(library.library[0].plays, 7);
+        assert!(library.library[0].favorited);
+        assert_eq!(ids[&1], existing.uuid);
+        assert_eq!(rx.try_iter().last().unwrap().done, 3);
+
+        add_playlists(&mut library, vec![("Mix".to_string(), vec![3, 1, 9])], &ids, &mut report, Some(&tx));
+        assert_eq!((report.playlists, report.unmatched), (1, 1));
+
+        assert_eq!(share_art(&mut library, &[new.uuid], Some(&tx)), 1);
+        assert_eq!(library.query_uuid(&new.uuid).unwrap().0.album_art, existing.album_art);
+        assert_eq!(rx.try_iter().last().unwrap().stage, MigrationStage::Art);
+    }
+}
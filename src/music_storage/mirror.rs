@@ -0,0 +1,295 @@
+//! A local copy of the catalog of a remote library, such as on a
+//! [Subsonic](super::subsonic) or [Jellyfin](super::jellyfin) server, so
+//! it can be browsed and searched while the server can't be reached
+//!
+//! Only the metadata is kept, the songs are still streamed from the
+//! server. Each song keeps its [Uuid] between syncs so playlists and the
+//! history which refer to it stay valid.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::jellyfin::JellyfinClient;
+use super::library::{Song, Tag, URI};
+use super::subsonic::SubsonicClient;
+use super::utils::{normalize, read_file, write_file};
+
+/// How many albums are asked for at once when fetching a catalog
+const CATALOG_PAGE: u32 = 500;
+
+/// A server whose whole catalog can be fetched
+pub trait RemoteCatalog {
+    /// Check that the server can be reached
+    fn ping(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Every song on the server
+    fn catalog(&self) -> Result<Vec<Song>, Box<dyn Error>>;
+}
+
+impl RemoteCatalog for SubsonicClient {
+    fn ping(&self) -> Result<(), Box<dyn Error>> {
+        Ok(SubsonicClient::ping(self)?)
+    }
+
+    fn catalog(&self) -> Result<Vec<Song>, Box<dyn Error>> {
+        let mut songs = Vec::new();
+        for offset in (0..).step_by(CATALOG_PAGE as usize) {
+            let albums = self.albums(CATALOG_PAGE, offset)?;
+            for album in &albums {
+                songs.extend(self.album_songs(&album.id)?);
+            }
+            if albums.len() < CATALOG_PAGE as usize {
+                break;
+            }
+        }
+        Ok(songs)
+    }
+}
+
+impl RemoteCatalog for JellyfinClient {
+    fn ping(&self) -> Result<(), Box<dyn Error>> {
+        self.albums(1, 0)?;
+        Ok(())
+    }
+
+    fn catalog(&self) -> Result<Vec<Song>, Box<dyn Error>> {
+        let mut songs = Vec::new();
+        for offset in (0..).step_by(CATALOG_PAGE as usize) {
+            let albums = self.albums(CATALOG_PAGE, offset)?;
+            for album in &albums {
+                songs.extend(self.album_songs(&album.id)?);
+            }
+            if albums.len() < CATALOG_PAGE as usize {
+                break;
+            }
+        }
+        Ok(songs)
+    }
+}
+
+/// Whether a song in a [RemoteMirror] can be played right now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Online,
+    /// The song can be browsed, but the server can't be reached to play it
+    Offline,
+}
+
+/// What changed when a [RemoteMirror] was synced with its server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MirrorReport {
+    pub added: usize,
+    pub removed: usize,
+    pub updated: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MirrorFile {
+    songs: Vec<Song>,
+    synced: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct RemoteMirror {
+    path: PathBuf,
+    songs: Vec<Song>,
+    synced: Option<DateTime<Utc>>,
+    online: bool,
+}
+
+impl RemoteMirror {
+    /// Open the mirror kept at `path`, which is empty if it doesn't exist
+    /// yet. It's offline until it has been [checked](RemoteMirror::check).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let file: MirrorFile = match path.exists() {
+            true => read_file(path.clone())?,
+            false => MirrorFile::default(),
+        };
+        Ok(RemoteMirror {
+            path,
+            songs: file.songs,
+            synced: file.synced,
+            online: false,
+        })
+    }
+
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+
+    /// When the mirror was last synced with the server
+    pub fn synced(&self) -> Option<DateTime<Utc>> {
+        self.synced
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
+    /// Whether the song at `uri` can be played, or `None` if it isn't in
+    /// the mirror
+    pub fn availability(&self, uri: &URI) -> Option<Availability> {
+        self.songs.iter().find(|song| song.location.contains(uri))?;
+        Some(match self.online {
+            true => Availability::Online,
+            false => Availability::Offline,
+        })
+    }
+
+    /// The songs whose title, artist, or album contain `query`
+    pub fn search(&self, query: &str) -> Vec<&Song> {
+        let query = normalize(query);
+        self.songs
+            .iter()
+            .filter(|song| {
+                [Tag::Title, Tag::Artist, Tag::Album]
+                    .iter()
+                    .filter_map(|tag| song.get_tag(tag))
+                    .any(|value| normalize(value).contains(&query))
+            })
+            .collect()
+    }
+
+    /// See whether the server can be reached, syncing the mirror if it
+    /// has come back online or has never been synced. Returns what changed
+    /// if it was synced.
+    pub fn check(&mut self, remote: &dyn RemoteCatalog) -> Result<Option<MirrorReport>, Box<dyn Error>> {
+        if let Err(error) = remote.ping() {
+            println!("The remote library is offline: {}", error);
+            self.online = false;
+            return Ok(None);
+        }
+        match self.online && self.synced.is_some() {
+            true => Ok(None),
+            false => self.sync(remote).map(Some),
+        }
+    }
+
+    /// Replace the mirror with the catalog of the server and save it. If
+    /// the catalog can't be fetched the mirror is left as it was, and
+    /// marked offline.
+    pub fn sync(&mut self, remote: &dyn RemoteCatalog) -> Result<MirrorReport, Box<dyn Error>> {
+        let catalog = match remote.catalog() {
+            Ok(catalog) => catalog,
+            Err(error) => {
+                self.online = false;
+                return Err(error);
+            }
+        };
+        let report = self.reconcile(catalog);
+        self.synced = Some(Utc::now());
+        self.online = true;
+        self.save()?;
+        Ok(report)
+    }
+
+    /// Replace the songs with `catalog`, keeping the [Uuid] and play
+    /// counts of the songs which were already there
+    fn reconcile(&mut self, mut catalog: Vec<Song>) -> MirrorReport {
+        let mut previous: HashMap<URI, Song> = self
+            .songs
+            .drain(..)
+            .filter_map(|song| Some((song.location.first()?.clone(), song)))
+            .collect();
+
+        let mut report = MirrorReport::default();
+        for song in &mut catalog {
+            let Some(old) = song.location.first().and_then(|uri| previous.remove(uri)) else {
+                report.added += 1;
+                continue;
+            };
+            if old.tags != song.tags || old.duration != song.duration {
+                report.updated += 1;
+            }
+            song.uuid = old.uuid;
+            song.plays = old.plays;
+            song.skips = old.skips;
+            song.favorited = old.favorited;
+            song.rating = old.rating;
+            song.last_played = old.last_played;
+            song.date_added = old.date_added;
+        }
+        report.removed = previous.len();
+        self.songs = catalog;
+        report
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let file = MirrorFile {
+            songs: self.songs.clone(),
+            synced: self.synced,
+        };
+        write_file(file, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::time::Duration;
+
+    use super::{Availability, MirrorReport, RemoteCatalog, RemoteMirror};
+    use crate::music_storage::library::{test::test_song, Service, Song, URI};
+
+    struct FakeServer {
+        songs: RefCell<Vec<Song>>,
+        online: RefCell<bool>,
+    }
+
+    impl RemoteCatalog for FakeServer {
+        fn ping(&self) -> Result<(), Box<dyn Error>> {
+            match *self.online.borrow() {
+                true => Ok(()),
+                false => Err("unreachable".into()),
+            }
+        }
+
+        fn catalog(&self) -> Result<Vec<Song>, Box<dyn Error>> {
+            self.ping()?;
+            Ok(self.songs.borrow().clone())
+        }
+    }
+
+    fn remote(title: &str, id: &str) -> Song {
+        let mut song = test_song(title, "Artist", Duration::from_secs(180));
+        song.location = vec![URI::Remote(Service::Subsonic, id.to_string())];
+        song
+    }
+
+    #[test]
+    fn remote_mirror() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("mirror");
+        let server = FakeServer {
+            songs: RefCell::new(vec![remote("One", "1"), remote("Two", "2")]),
+            online: RefCell::new(true),
+        };
+
+        let mut mirror = RemoteMirror::open(&path).unwrap();
+        assert_eq!(mirror.check(&server).unwrap(), Some(MirrorReport { added: 2, ..Default::default() }));
+        assert_eq!(mirror.availability(&server.songs.borrow()[0].location[0]), Some(Availability::Online));
+        // Syncing only happens when the server comes back
+        assert_eq!(mirror.check(&server).unwrap(), None);
+        let uuid = mirror.songs()[0].uuid;
+
+        *server.online.borrow_mut() = false;
+        assert_eq!(mirror.check(&server).unwrap(), None);
+        let mut mirror = RemoteMirror::open(&path).unwrap();
+        assert_eq!(mirror.search("two").len(), 1);
+        assert_eq!(mirror.availability(&mirror.songs()[0].location[0]), Some(Availability::Offline));
+
+        // The server changed while it was offline
+        server.songs.borrow_mut().remove(1);
+        server.songs.borrow_mut().push(remote("Three", "3"));
+        *server.online.borrow_mut() = true;
+        assert_eq!(mirror.check(&server).unwrap(), Some(MirrorReport { added: 1, removed: 1, updated: 0 }));
+        assert_eq!(mirror.songs()[0].uuid, uuid);
+        assert!(mirror.is_online());
+    }
+}
@@ -0,0 +1,276 @@
+//! A client for the [MusicBrainz](https://musicbrainz.org) API, used to
+//! fill in missing metadata of songs from their MusicBrainz IDs
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Tag};
+use super::release_type::ReleaseType;
+use super::utils::normalize;
+
+const API_URL: &str = "https://musicbrainz.org/ws/2";
+
+/// MusicBrainz allows one request a second from each client
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum MusicBrainzError {
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("the song is not in the library")]
+    SongNotFound,
+    #[error("the song has no MusicBrainz recording or release ID to look up")]
+    NoIdentifiers,
+    #[error("the release doesn't have the song on it")]
+    NotOnRelease,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtistCredit {
+    pub name: String,
+    #[serde(default)]
+    pub joinphrase: String,
+    pub artist: Artist,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseGroup {
+    pub id: String,
+    /// Such as `Album` or `Single`
+    #[serde(rename = "primary-type")]
+    pub primary_type: Option<String>,
+    /// Such as `Live` or `Remix`
+    #[serde(rename = "secondary-types", default)]
+    pub secondary_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Recording {
+    pub id: String,
+    pub title: String,
+    #[serde(rename = "artist-credit", default)]
+    pub artist_credit: Vec<ArtistCredit>,
+    /// The releases the recording is on, if they were asked for
+    #[serde(default)]
+    pub releases: Vec<Release>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Track {
+    pub position: u32,
+    pub title: String,
+    pub recording: Option<Recording>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Medium {
+    pub position: u32,
+    #[serde(default)]
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub id: String,
+    pub title: String,
+    pub date: Option<String>,
+    #[serde(rename = "artist-credit", default)]
+    pub artist_credit: Vec<ArtistCredit>,
+    #[serde(rename = "release-group")]
+    pub release_group: Option<ReleaseGroup>,
+    #[serde(default)]
+    pub media: Vec<Medium>,
+}
+
+/// The names of every artist in a credit, joined the way MusicBrainz
+/// displays them
+pub fn credited_name(credit: &[ArtistCredit]) -> Option<String> {
+    if credit.is_empty() {
+        return None;
+    }
+    Some(credit.iter().map(|credit| format!("{}{}", credit.name, credit.joinphrase)).collect())
+}
+
+impl Release {
+    /// Find a track on the release by its recording, or by its title if
+    /// there is no recording ID, returning the disc number with it
+    pub fn find_track(&self, recording_id: Option<&str>, title: Option<&str>) -> Option<(u32, &Track)> {
+        let title = title.map(normalize);
+        self.media.iter().find_map(|medium| {
+            let track = medium.tracks.iter().find(|track| match recording_id {
+                Some(id) => track.recording.as_ref().is_some_and(|recording| recording.id == id),
+                None => title.as_ref().is_some_and(|title| &normalize(&track.title) == title),
+            })?;
+            Some((medium.position, track))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MusicBrainz {
+    user_agent: String,
+    last_request: Mutex<Option<Instant>>,
+}
+
+impl MusicBrainz {
+    /// Create a client which identifies itself with `user_agent`, which
+    /// MusicBrainz requires to include a way to contact the application
+    pub fn new(user_agent: String) -> Self {
+        MusicBrainz {
+            user_agent,
+            last_request: Mutex::new(None),
+        }
+    }
+
+    fn get<T: for<'de> Deserialize<'de>>(&self, path: &str, inc: &str) -> Result<T, MusicBrainzError> {
+        // Wait until the rate limit allows another request
+        {
+            let mut last_request = self.last_request.lock().unwrap();
+            if let Some(elapsed) = last_request.map(|last| last.elapsed()) {
+                if elapsed < REQUEST_INTERVAL {
+                    sleep(REQUEST_INTERVAL - elapsed);
+                }
+            }
+            *last_request = Some(Instant::now());
+        }
+
+        Ok(attohttpc::get(format!("{}/{}", API_URL, path))
+            .header("User-Agent", &self.user_agent)
+            .param("inc", inc)
+            .param("fmt", "json")
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    pub fn recording(&self, id: &str) -> Result<Recording, MusicBrainzError> {
+        self.get(&format!("recording/{}", id), "artist-credits releases")
+    }
+
+    pub fn release(&self, id: &str) -> Result<Release, MusicBrainzError> {
+        self.get(&format!("release/{}", id), "artist-credits recordings release-groups")
+    }
+}
+
+impl MusicLibrary {
+    /// Fill in the missing tags of a song from MusicBrainz, looking it up
+    /// by its release, or by its recording if it has no release. Tags which
+    /// are already set are never changed.
+    ///
+    /// Returns the tags which were filled in.
+    pub fn enrich_metadata(&mut self, uuid: &Uuid, client: &MusicBrainz) -> Result<Vec<Tag>, MusicBrainzError> {
+        let (song, index) = self.query_uuid(uuid).ok_or(MusicBrainzError::SongNotFound)?;
+        let recording_id = song.get_tag(&Tag::MusicBrainzRecordingId).cloned();
+        let title = song.get_tag(&Tag::Title).cloned();
+
+        let release_id = match (song.get_tag(&Tag::MusicBrainzReleaseId), &recording_id) {
+            (Some(release), _) => release.clone(),
+            (None, Some(recording)) => {
+                // Prefer the release with the same title as the song's album
+                let album = song.get_tag(&Tag::Album).map(|album| normalize(album));
+                let releases = client.recording(recording)?.releases;
+                releases
+                    .iter()
+                    .find(|release| Some(normalize(&release.title)) == album)
+                    .or(releases.first())
+                    .map(|release| release.id.clone())
+                    .ok_or(MusicBrainzError::NotOnRelease)?
+            }
+            (None, None) => return Err(MusicBrainzError::NoIdentifiers),
+        };
+
+        let release = client.release(&release_id)?;
+        let (disc, track) = release
+            .find_track(recording_id.as_deref(), title.as_deref())
+            .ok_or(MusicBrainzError::NotOnRelease)?;
+
+        let mut found = BTreeMap::new();
+        found.insert(Tag::MusicBrainzReleaseId, Some(release.id.clone()));
+        found.insert(Tag::Album, Some(release.title.clone()));
+        found.insert(Tag::Date, release.date.clone());
+        found.insert(Tag::AlbumArtist, credited_name(&release.artist_credit));
+        found.insert(
+            Tag::MusicBrainzAlbumArtistId,
+            release.artist_credit.first().map(|credit| credit.artist.id.clone()),
+        );
+        found.insert(
+            Tag::MusicBrainzReleaseGroupId,
+            release.release_group.as_ref().map(|group| group.id.clone()),
+        );
+        found.insert(
+            Tag::ReleaseType,
+            release.release_group.as_ref().and_then(|group| {
+                let release_type = ReleaseType::from_musicbrainz(group.primary_type.as_deref(), &group.secondary_types);
+                release_type.map(|release_type| release_type.to_string())
+            }),
+        );
+        found.insert(Tag::Disk, Some(disc.to_string()));
+        found.insert(Tag::Track, Some(track.position.to_string()));
+        found.insert(Tag::Title, Some(track.title.clone()));
+        if let Some(recording) = &track.recording {
+            found.insert(Tag::MusicBrainzRecordingId, Some(recording.id.clone()));
+            found.insert(Tag::Artist, credited_name(&recording.artist_credit));
+            found.insert(
+                Tag::MusicBrainzArtistId,
+                recording.artist_credit.first().map(|credit| credit.artist.id.clone()),
+            );
+        }
+
+        let song = &mut self.library[index];
+        let mut filled = Vec::new();
+        for (tag, value) in found {
+            if let (None, Some(value)) = (song.get_tag(&tag), value) {
+                song.set_tag(tag.clone(), value);
+                filled.push(tag);
+            }
+        }
+        Ok(filled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{credited_name, Release};
+
+    #[test]
+    fn musicbrainz_release() {
+        let release: Release = serde_json::from_str(
+            r#"{
+                "id": "b84ee12a-09ef-421b-82de-0441a926375b",
+                "title": "Nevermind",
+                "date": "1991-09-24",
+                "artist-credit": [{"name": "Nirvana", "joinphrase": "", "artist": {"id": "5b11f4ce", "name": "Nirvana"}}],
+                "release-group": {"id": "1b022e01", "primary-type": "Album"},
+                "media": [
+                    {"position": 1, "tracks": [
+                        {"position": 1, "title": "Smells Like Teen Spirit", "recording": {"id": "aaa", "title": "Smells Like Teen Spirit"}},
+                        {"position": 2, "title": "In Bloom", "recording": {"id": "bbb", "title": "In Bloom"}}
+                    ]},
+                    {"position": 2, "tracks": [
+                        {"position": 1, "title": "Lithium (live)", "recording": {"id": "ccc", "title": "Lithium"}}
+                    ]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(credited_name(&release.artist_credit).unwrap(), "Nirvana");
+        let (disc, track) = release.find_track(Some("ccc"), None).unwrap();
+        assert_eq!((disc, track.position), (2, 1));
+        let (disc, track) = release.find_track(None, Some("in bloom")).unwrap();
+        assert_eq!((disc, track.position), (1, 2));
+        assert!(release.find_track(Some("ddd"), Some("In Bloom")).is_none());
+    }
+}
@@ -0,0 +1,175 @@
+//! Downloading songs from remote libraries so they can be played offline
+//!
+//! Downloads are kept in the offline [Cache], which has its own budget and
+//! evicts the songs played least recently. [OfflineCopies] goes before the
+//! remote libraries, so a song which was downloaded is played from the
+//! cache instead of being streamed.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use super::cache::{Cache, Caches};
+use super::library::{MusicLibrary, Service, Song, Tag, URI};
+use super::remote::{resolve_uri, PlaybackReport, RemoteLibrary};
+
+/// Something to download for offline playback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OfflineItem {
+    /// Every song on the album with this title
+    Album(String),
+    Playlist(Uuid),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadState {
+    Queued,
+    Downloading,
+    Done,
+    Failed(String),
+}
+
+/// Sent to [Controller](crate::music_controller::controller::Controller)
+/// listeners as each song of an [OfflineItem] is downloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfflineEvent {
+    pub uuid: Uuid,
+    pub state: DownloadState,
+}
+
+/// The key a song at `uri` is kept under in the offline cache, or `None`
+/// if it isn't a song from a remote library
+pub fn offline_key(uri: &URI) -> Option<String> {
+    match uri {
+        URI::Remote(service @ (Service::Subsonic | Service::Jellyfin | Service::Plex), id) => {
+            Some(format!("{:?}:{}", service, id))
+        }
+        _ => None,
+    }
+}
+
+/// Whether the song at `uri` has been downloaded
+pub fn is_downloaded(cache: &Cache, uri: &URI) -> bool {
+    offline_key(uri).is_some_and(|key| cache.contains(&key))
+}
+
+/// The songs of `item` which are from a remote library
+pub fn item_songs(library: &MusicLibrary, item: &OfflineItem) -> Vec<Song> {
+    let songs: Vec<Song> = match item {
+        OfflineItem::Album(title) => library
+            .library
+            .iter()
+            .filter(|song| song.get_tag(&Tag::Album) == Some(title))
+            .cloned()
+            .collect(),
+        OfflineItem::Playlist(uuid) => match library.playlists.playlist(uuid) {
+            Some(playlist) => playlist
+                .tracks()
+                .iter()
+                .filter_map(|uuid| library.query_uuid(uuid).map(|(song, _)| song.clone()))
+                .collect(),
+            None => Vec::new(),
+        },
+    };
+    songs
+        .into_iter()
+        .filter(|song| song.location.first().and_then(offline_key).is_some())
+        .collect()
+}
+
+/// Download `song` into the offline `cache`, which refuses it if it would
+/// use up the reserved disk space. Songs which were already downloaded are
+/// skipped.
+pub fn download(
+    cache: &Cache,
+    remotes: &[Box<dyn RemoteLibrary>],
+    song: &Song,
+) -> Result<(), Box<dyn Error>> {
+    let uri = song.location.first().ok_or("the song has no location")?;
+    let key = offline_key(uri).ok_or("the song isn't from a remote library")?;
+    if cache.contains(&key) {
+        return Ok(());
+    }
+
+    let url = match resolve_uri(remotes, uri)? {
+        URI::Remote(_, url) => url,
+        _ => return Err("the song can't be streamed".into()),
+    };
+    let data = attohttpc::get(url).send()?.error_for_status()?.bytes()?;
+    cache.insert(&key, &data)?;
+    Ok(())
+}
+
+/// Plays songs which were downloaded from the offline cache
+pub struct OfflineCopies {
+    caches: Arc<Caches>,
+}
+
+impl OfflineCopies {
+    pub fn new(caches: Arc<Caches>) -> Self {
+        OfflineCopies { caches }
+    }
+}
+
+impl RemoteLibrary for OfflineCopies {
+    fn resolve(&self, uri: &URI) -> Option<Result<URI, Box<dyn Error>>> {
+        let path = self.caches.offline.path(&offline_key(uri)?)?;
+        Some(Ok(URI::Local(path)))
+    }
+
+    fn report_playback(&self, _uri: &URI, _report: PlaybackReport, _position: Duration) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{download, is_downloaded, item_songs, offline_key, OfflineCopies, OfflineItem};
+    use crate::config::ConfigCaches;
+    use crate::music_storage::cache::Caches;
+    use crate::music_storage::library::{test::test_song, MusicLibrary, Service, Tag, URI};
+    use crate::music_storage::playlist::Playlist;
+    use crate::music_storage::remote::{resolve_uri, RemoteLibrary};
+
+    #[test]
+    fn offline_copies() {
+        let folder = tempfile::tempdir().unwrap();
+        let caches = Arc::new(Caches::open(&ConfigCaches { folder: folder.path().to_path_buf(), ..Default::default() }).unwrap());
+
+        let mut library = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        for (id, album) in [("1", "Album"), ("2", "Album"), ("3", "Other")] {
+            let mut song = test_song(id, "Artist", Duration::from_secs(180));
+            song.location = vec![URI::Remote(Service::Jellyfin, id.to_string())];
+            song.set_tag(Tag::Album, album.to_string());
+            library.library.push(song);
+        }
+        let mut local = test_song("Local", "Artist", Duration::from_secs(180));
+        local.set_tag(Tag::Album, "Album".to_string());
+        library.library.push(local);
+        let mut playlist = Playlist::new();
+        playlist.set_tracks(vec![library.library[2].uuid]);
+        let playlist_uuid = *playlist.uuid();
+        library.playlists.add_playlist(playlist);
+
+        // Local files never need downloading
+        assert_eq!(item_songs(&library, &OfflineItem::Album("Album".to_string())).len(), 2);
+        assert_eq!(item_songs(&library, &OfflineItem::Playlist(playlist_uuid)).len(), 1);
+
+        let uri = library.library[0].location[0].clone();
+        let key = offline_key(&uri).unwrap();
+        let remotes: Vec<Box<dyn RemoteLibrary>> = vec![Box::new(OfflineCopies::new(caches.clone()))];
+        assert_eq!(resolve_uri(&remotes, &uri).unwrap(), uri);
+
+        caches.offline.insert(&key, b"audio").unwrap();
+        assert!(is_downloaded(&caches.offline, &uri));
+        assert_eq!(resolve_uri(&remotes, &uri).unwrap(), URI::Local(caches.offline.path(&key).unwrap()));
+        // Nothing is fetched for a song which is already downloaded
+        assert!(download(&caches.offline, &remotes, &library.library[0]).is_ok());
+        assert!(download(&caches.offline, &remotes, &library.library[3]).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Rewriting of path prefixes, for resolving paths which were
+//! written on another machine or under a different mount point
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// A single rule, replacing the prefix `from` with `to`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemapRule {
+    pub from: String,
+    pub to: String,
+}
+
+/// A list of [RemapRule]s, the first matching rule is applied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRemap {
+    pub rules: Vec<RemapRule>,
+}
+
+/// Turn all path separators into `/` so paths from Windows and Unix
+/// machines can be compared
+fn normalize_separators(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+impl PathRemap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a rule replacing the prefix `from` with `to`
+    pub fn add_rule<F: Into<String>, T: Into<String>>(&mut self, from: F, to: T) {
+        self.rules.push(RemapRule {
+            from: from.into(),
+            to: to.into(),
+        });
+    }
+
+    /// Remove the rule at `index`, returning it if it existed
+    pub fn remove_rule(&mut self, index: usize) -> Option<RemapRule> {
+        if index < self.rules.len() {
+            Some(self.rules.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply the first matching rule to `path`, returning `None`
+    /// if no rule matches
+    pub fn remap(&self, path: &str) -> Option<PathBuf> {
+        let normalized = normalize_separators(path);
+
+        for rule in &self.rules {
+            let from = normalize_separators(&rule.from);
+            let from = from.trim_end_matches('/');
+
+            let rest = match normalized.strip_prefix(from) {
+                // Only match whole path components
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                _ => continue,
+            };
+
+            let to = rule.to.trim_end_matches(['/', '\\']);
+            let mut remapped = PathBuf::from(to);
+            for component in rest.split('/').filter(|c| !c.is_empty()) {
+                remapped.push(component);
+            }
+            return Some(remapped);
+        }
+
+        None
+    }
+
+    /// Apply the first matching rule to `path`, or return it as-is
+    pub fn apply(&self, path: &str) -> PathBuf {
+        match self.remap(path) {
+            Some(remapped) => remapped,
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Apply the rules to a [Path]
+    pub fn apply_path(&self, path: &Path) -> PathBuf {
+        self.apply(&path.to_string_lossy())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::PathRemap;
+
+    #[test]
+    fn remap_prefix() {
+        let mut remap = PathRemap::new();
+        remap.add_rule("C:\\Users\\dango\\Music", "/home/dango/Music");
+        remap.add_rule("/mnt/old", "/mnt/new/");
+
+        assert_eq!(
+            remap.apply("C:\\Users\\dango\\Music\\Album\\01.flac"),
+            PathBuf::from("/home/dango/Music/Album/01.flac")
+        );
+        assert_eq!(remap.apply("/mnt/old/a.mp3"), PathBuf::from("/mnt/new/a.mp3"));
+        // Partial component matches are not remapped
+        assert_eq!(remap.remap("/mnt/older/a.mp3"), None);
+        assert_eq!(remap.apply("/other/a.mp3"), PathBuf::from("/other/a.mp3"));
+    }
+}
@@ -10,7 +10,9 @@ use std::time::Duration;
 
 // use chrono::Duration;
 use super::library::{AlbumArt, MusicLibrary, Song, Tag, URI};
+use super::path_remap::PathRemap;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use uuid::Uuid;
 
 use m3u8_rs::{MediaPlaylist, MediaPlaylistType, MediaSegment, Playlist as List2};
@@ -24,10 +26,18 @@ pub enum SortOrder {
     Tag(Vec<Tag>),
 }
 
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PlaylistFolderError {
+    #[error("no playlist or folder has that UUID")]
+    NotFound,
+    #[error("a folder can't be moved into itself or its own folders")]
+    IntoItself,
+}
+
 nest! {
     #[derive(Debug, Clone, Deserialize, Serialize)]*
-    #[derive(Default)]
     pub struct PlaylistFolder {
+        uuid: Uuid,
         name: String,
         items: Vec<
             pub enum PlaylistFolderItem {
@@ -38,6 +48,168 @@ nest! {
     }
 }
 
+impl PlaylistFolderItem {
+    pub fn uuid(&self) -> &Uuid {
+        match self {
+            PlaylistFolderItem::Folder(folder) => &folder.uuid,
+            PlaylistFolderItem::List(playlist) => &playlist.uuid,
+        }
+    }
+
+    /// The name of the folder or the title of the playlist
+    pub fn name(&self) -> &String {
+        match self {
+            PlaylistFolderItem::Folder(folder) => &folder.name,
+            PlaylistFolderItem::List(playlist) => &playlist.title,
+        }
+    }
+}
+
+impl PlaylistFolder {
+    pub fn new(name: &str) -> Self {
+        PlaylistFolder {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn items(&self) -> &Vec<PlaylistFolderItem> {
+        &self.items
+    }
+
+    /// Add a playlist to the end of this folder
+    pub fn add_playlist(&mut self, playlist: Playlist) {
+        self.items.push(PlaylistFolderItem::List(playlist));
+    }
+
+    /// Add a folder to the end of this folder
+    pub fn add_folder(&mut self, folder: PlaylistFolder) {
+        self.items.push(PlaylistFolderItem::Folder(folder));
+    }
+
+    /// Every playlist in this folder and the folders inside of it
+    pub fn playlists_mut(&mut self) -> Vec<&mut Playlist> {
+        self.items
+            .iter_mut()
+            .flat_map(|item| match item {
+                PlaylistFolderItem::Folder(folder) => folder.playlists_mut(),
+                PlaylistFolderItem::List(playlist) => vec![playlist],
+            })
+            .collect()
+    }
+
+    /// Find the playlist with the given [Uuid] in this folder or the
+    /// folders inside of it
+    pub fn playlist(&self, uuid: &Uuid) -> Option<&Playlist> {
+        self.items.iter().find_map(|item| match item {
+            PlaylistFolderItem::Folder(folder) => folder.playlist(uuid),
+            PlaylistFolderItem::List(playlist) => (&playlist.uuid == uuid).then_some(playlist),
+        })
+    }
+
+    /// Find the folder with the given [Uuid], which may be this one
+    pub fn folder(&self, uuid: &Uuid) -> Option<&PlaylistFolder> {
+        if &self.uuid == uuid {
+            return Some(self);
+        }
+        self.items.iter().find_map(|item| match item {
+            PlaylistFolderItem::Folder(folder) => folder.folder(uuid),
+            PlaylistFolderItem::List(_) => None,
+        })
+    }
+
+    pub fn folder_mut(&mut self, uuid: &Uuid) -> Option<&mut PlaylistFolder> {
+        if &self.uuid == uuid {
+            return Some(self);
+        }
+        self.items.iter_mut().find_map(|item| match item {
+            PlaylistFolderItem::Folder(folder) => folder.folder_mut(uuid),
+            PlaylistFolderItem::List(_) => None,
+        })
+    }
+
+    /// The names of the folders leading to the playlist or folder with
+    /// the given [Uuid], starting from inside this one
+    pub fn path_to(&self, uuid: &Uuid) -> Option<Vec<&String>> {
+        self.items.iter().find_map(|item| match item {
+            _ if item.uuid() == uuid => Some(Vec::new()),
+            PlaylistFolderItem::Folder(folder) => {
+                let mut path = folder.path_to(uuid)?;
+                path.insert(0, &folder.name);
+                Some(path)
+            }
+            PlaylistFolderItem::List(_) => None,
+        })
+    }
+
+    /// Rename the playlist or folder with the given [Uuid], anywhere in
+    /// this folder
+    pub fn rename(&mut self, uuid: &Uuid, name: String) -> Result<(), PlaylistFolderError> {
+        if let Some(folder) = self.folder_mut(uuid) {
+            folder.name = name;
+            return Ok(());
+        }
+        let playlist = self
+            .playlists_mut()
+            .into_iter()
+            .find(|playlist| &playlist.uuid == uuid)
+            .ok_or(PlaylistFolderError::NotFound)?;
+        playlist.title = name;
+        Ok(())
+    }
+
+    /// Take the playlist or folder with the given [Uuid] out of this
+    /// folder, or out of whichever folder inside of it it's in
+    pub fn remove(&mut self, uuid: &Uuid) -> Option<PlaylistFolderItem> {
+        if let Some(index) = self.items.iter().position(|item| item.uuid() == uuid) {
+            return Some(self.items.remove(index));
+        }
+        self.items.iter_mut().find_map(|item| match item {
+            PlaylistFolderItem::Folder(folder) => folder.remove(uuid),
+            PlaylistFolderItem::List(_) => None,
+        })
+    }
+
+    /// Move the playlist or folder with the given [Uuid] into the folder
+    /// `to`, at `index` within it, or to the end if that's `None`
+    pub fn move_item(&mut self, uuid: &Uuid, to: &Uuid, index: Option<usize>) -> Result<(), PlaylistFolderError> {
+        if self.path_to(uuid).is_none() || self.folder(to).is_none() {
+            return Err(PlaylistFolderError::NotFound);
+        }
+        if self.folder(uuid).is_some_and(|folder| folder.folder(to).is_some()) {
+            return Err(PlaylistFolderError::IntoItself);
+        }
+
+        let item = self.remove(uuid).ok_or(PlaylistFolderError::NotFound)?;
+        let folder = self.folder_mut(to).ok_or(PlaylistFolderError::NotFound)?;
+        let index = index.unwrap_or(folder.items.len()).min(folder.items.len());
+        folder.items.insert(index, item);
+        Ok(())
+    }
+}
+
+impl Default for PlaylistFolder {
+    fn default() -> Self {
+        PlaylistFolder {
+            uuid: Uuid::new_v4(),
+            name: String::default(),
+            items: Vec::default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Playlist {
     uuid: Uuid,
@@ -59,10 +231,18 @@ impl Playlist {
         self.play_time
     }
 
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
     pub fn title(&self) -> &String {
         &self.title
     }
 
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
     pub fn cover(&self) -> Option<&AlbumArt> {
         match &self.cover {
             Some(e) => Some(e),
@@ -169,6 +349,16 @@ impl Playlist {
     pub fn from_m3u8(
         path: &str,
         lib: Arc<RwLock<MusicLibrary>>,
+    ) -> Result<Playlist, Box<dyn Error>> {
+        Self::from_m3u8_remapped(path, lib, &PathRemap::new())
+    }
+
+    /// Read an m3u8 playlist, rewriting the path of each entry with
+    /// `remap` before it is looked up in the library
+    pub fn from_m3u8_remapped(
+        path: &str,
+        lib: Arc<RwLock<MusicLibrary>>,
+        remap: &PathRemap,
     ) -> Result<Playlist, Box<dyn Error>> {
         let mut file = match File::open(path) {
             Ok(file) => file,
@@ -191,7 +381,7 @@ impl Playlist {
             List2::MediaPlaylist(playlist_) => {
                 let mut uuids = Vec::new();
                 for seg in playlist_.segments {
-                    let path_ = PathBuf::from(seg.uri.to_owned());
+                    let path_ = remap.apply(&seg.uri);
                     let mut lib = lib.write().unwrap();
 
                     let uuid = if let Some((song, _)) = lib.query_uri(&URI::Local(path_.clone())) {
@@ -340,6 +530,34 @@ mod test_super {
         dbg!(playlist)
     }
 
+    #[test]
+    fn playlist_folders() {
+        let mut root = PlaylistFolder::default();
+        let mut rock = PlaylistFolder::new("Rock");
+        let mut favourites = Playlist::new();
+        favourites.set_title("Favourites".to_string());
+        let (playlist, rock_uuid) = (favourites.uuid, rock.uuid);
+        rock.add_playlist(favourites);
+        let jazz = PlaylistFolder::new("Jazz");
+        let jazz_uuid = jazz.uuid;
+        root.add_folder(rock);
+        root.add_folder(jazz);
+        assert_eq!(root.path_to(&playlist), Some(vec![&"Rock".to_string()]));
+
+        root.move_item(&playlist, &jazz_uuid, None).unwrap();
+        root.rename(&jazz_uuid, "Smooth Jazz".to_string()).unwrap();
+        assert_eq!(root.path_to(&playlist), Some(vec![&"Smooth Jazz".to_string()]));
+        assert!(root.folder(&rock_uuid).unwrap().items().is_empty());
+
+        // Folders can't end up inside themselves
+        root.move_item(&rock_uuid, &jazz_uuid, Some(0)).unwrap();
+        assert_eq!(root.move_item(&jazz_uuid, &rock_uuid, None), Err(PlaylistFolderError::IntoItself));
+        assert_eq!(root.move_item(&Uuid::new_v4(), &rock_uuid, None), Err(PlaylistFolderError::NotFound));
+        assert_eq!(root.folder(&jazz_uuid).unwrap().items()[0].name(), "Rock");
+        assert!(root.remove(&playlist).is_some());
+        assert!(root.playlist(&playlist).is_none());
+    }
+
     #[test]
     fn out_queue_sort() {
         let (_, lib) = read_config_lib();
@@ -0,0 +1,289 @@
+//! Bulk import of playlists exported from other players, resolving
+//! their entries against the songs already in a [MusicLibrary]
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use super::library::{MusicLibrary, URI};
+use super::path_remap::PathRemap;
+use super::playlist::Playlist;
+
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8", "pls", "xspf"];
+
+/// A playlist which has been read and resolved, but not yet
+/// added to the library
+#[derive(Debug, Clone)]
+pub struct PlaylistImport {
+    pub source: PathBuf,
+    pub playlist: Playlist,
+    pub entries: usize,
+    pub unmatched: Vec<String>,
+}
+
+impl PlaylistImport {
+    /// The number of entries which were found in the library
+    pub fn matched(&self) -> usize {
+        self.entries - self.unmatched.len()
+    }
+
+    /// The fraction of entries which were found in the library, from `0` to `1`
+    pub fn match_rate(&self) -> f32 {
+        if self.entries == 0 {
+            return 0.0;
+        }
+        self.matched() as f32 / self.entries as f32
+    }
+}
+
+/// The results of scanning a folder of playlists, which can be reviewed
+/// before being committed to the library
+#[derive(Debug, Clone, Default)]
+pub struct BulkImport {
+    pub imports: Vec<PlaylistImport>,
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl BulkImport {
+    /// Find every m3u, pls, and xspf playlist within `folder` and resolve
+    /// their entries against `library`, applying `remap` to each entry first
+    pub fn scan<P: ?Sized + AsRef<Path>>(
+        folder: &P,
+        library: &MusicLibrary,
+        remap: &PathRemap,
+    ) -> Self {
+        let mut import = BulkImport::default();
+
+        for target_file in WalkDir::new(folder)
+            .follow_links(true)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let path = target_file.path();
+            let extension = match path.extension() {
+                Some(ext) => ext.to_string_lossy().to_ascii_lowercase(),
+                None => continue,
+            };
+            if !path.is_file() || !PLAYLIST_EXTENSIONS.contains(&extension.as_str()) {
+                continue;
+            }
+
+            match import_playlist(path, library, remap) {
+                Ok(playlist) => import.imports.push(playlist),
+                Err(error) => import.errors.push((path.to_path_buf(), error.to_string())),
+            }
+        }
+
+        import
+    }
+
+    /// Add every playlist with a match rate of at least `min_match_rate`
+    /// to the library, returning the number of playlists added
+    pub fn commit(self, library: &mut MusicLibrary, min_match_rate: f32) -> usize {
+        let mut added = 0;
+        for import in self.imports {
+            if import.entries == 0 || import.match_rate() < min_match_rate {
+                continue;
+            }
+            library.playlists.add_playlist(import.playlist);
+            added += 1;
+        }
+        added
+    }
+}
+
+/// Read a single playlist file and resolve its entries against `library`
+pub fn import_playlist(
+    path: &Path,
+    library: &MusicLibrary,
+    remap: &PathRemap,
+) -> Result<PlaylistImport, Box<dyn Error>> {
+    import_playlist_from(path, path.parent().unwrap_or(Path::new("")), library, remap)
+}
+
+/// Read a single playlist file whose relative entries are relative to
+/// `base` rather than the folder it is in, such as the playlists of MPD
+pub fn import_playlist_from(
+    path: &Path,
+    base: &Path,
+    library: &MusicLibrary,
+    remap: &PathRemap,
+) -> Result<PlaylistImport, Box<dyn Error>> {
+    let contents = fs::read(path)?;
+    let contents = String::from_utf8_lossy(&contents);
+
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let entries = match extension.as_str() {
+        "pls" => parse_pls(&contents),
+        "xspf" => parse_xspf(&contents)?,
+        _ => parse_m3u(&contents),
+    };
+
+    let mut tracks = Vec::new();
+    let mut unmatched = Vec::new();
+    for entry in &entries {
+        match resolve_entry(entry, base, remap).and_then(|path| find_song(&path, library)) {
+            Some(uuid) => tracks.push(uuid),
+            None => unmatched.push(entry.clone()),
+        }
+    }
+
+    let mut playlist = Playlist::new();
+    playlist.set_title(
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    );
+    playlist.set_tracks(tracks);
+
+    Ok(PlaylistImport {
+        source: path.to_path_buf(),
+        playlist,
+        entries: entries.len(),
+        unmatched,
+    })
+}
+
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim().trim_start_matches('\u{feff}'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn parse_pls(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            if key.trim().to_ascii_lowercase().starts_with("file") {
+                Some(value.trim().to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_xspf(contents: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut reader = Reader::from_str(contents);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut in_location = false;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) if e.name().as_ref() == b"location" => in_location = true,
+            Event::End(e) if e.name().as_ref() == b"location" => in_location = false,
+            Event::Text(e) if in_location => entries.push(e.unescape()?.to_string()),
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(entries)
+}
+
+/// Turn a playlist entry into a local path, returns `None` for
+/// entries which point to remote locations
+fn resolve_entry(entry: &str, base: &Path, remap: &PathRemap) -> Option<PathBuf> {
+    let entry = match entry.strip_prefix("file://") {
+        Some(path) => {
+            let path = urlencoding::decode(path).ok()?.into_owned();
+            // Windows file URIs look like `file:///C:/Music`
+            match path.strip_prefix('/') {
+                Some(stripped) if stripped.chars().nth(1) == Some(':') => stripped.to_string(),
+                _ => path,
+            }
+        }
+        None if entry.contains("://") => return None,
+        None => entry.to_string(),
+    };
+
+    let mut path = remap.apply(&entry);
+    if path.is_relative() {
+        path = base.join(path);
+    }
+
+    Some(fs::canonicalize(&path).unwrap_or(path))
+}
+
+fn find_song(path: &Path, library: &MusicLibrary) -> Option<Uuid> {
+    library
+        .query_uri(&URI::Local(path.to_path_buf()))
+        .map(|(song, _)| song.uuid)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::BulkImport;
+    use crate::music_storage::library::{test::test_song, MusicLibrary, URI};
+    use crate::music_storage::path_remap::PathRemap;
+
+    #[test]
+    fn bulk_import() {
+        let folder = tempfile::tempdir().unwrap();
+        let music = folder.path().join("music");
+
+        let mut library = MusicLibrary::from_path(&folder.path().join("library")).unwrap();
+        for name in ["a", "b", "c"] {
+            let mut song = test_song(name, "Artist", Duration::from_secs(100));
+            song.location = vec![URI::Local(music.join(format!("{}.flac", name)))];
+            library.library.push(song);
+        }
+
+        fs::write(
+            folder.path().join("relative.m3u"),
+            "#EXTM3U\n#EXTINF:100,a\nmusic/a.flac\nmusic/missing.flac\n",
+        )
+        .unwrap();
+        fs::write(
+            folder.path().join("remapped.pls"),
+            "[playlist]\nFile1=D:\\Music\\b.flac\nFile2=D:\\Music\\c.flac\nNumberOfEntries=2\n",
+        )
+        .unwrap();
+        fs::write(
+            folder.path().join("uri.xspf"),
+            format!(
+                "<playlist><trackList><track><location>file://{}</location></track>\
+                 <track><location>http://example.com/stream</location></track></trackList></playlist>",
+                music.join("c.flac").to_string_lossy()
+            ),
+        )
+        .unwrap();
+
+        let mut remap = PathRemap::new();
+        remap.add_rule("D:\\Music", music.to_string_lossy());
+
+        let import = BulkImport::scan(folder.path(), &library, &remap);
+        assert!(import.errors.is_empty());
+        assert_eq!(import.imports.len(), 3);
+
+        for playlist in &import.imports {
+            let rate = playlist.match_rate();
+            match playlist.playlist.title().as_str() {
+                "relative" | "uri" => assert_eq!(rate, 0.5),
+                "remapped" => assert_eq!(rate, 1.0),
+                _ => unreachable!(),
+            }
+        }
+
+        assert_eq!(import.commit(&mut library, 0.75), 1);
+        assert_eq!(library.playlists.items().len(), 1);
+    }
+}
@@ -0,0 +1,330 @@
+//! A remote library provider for the music sections of a Plex
+//! server, which reports playback back to the server so the play
+//! state stays in sync with other clients
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::library::{Service, Song, Tag, URI};
+use super::remote::{remote_song, PlaybackReport, RemoteLibrary};
+
+const PRODUCT_NAME: &str = "dmp-core";
+
+/// The type number Plex uses for tracks when listing a section
+const TRACK_TYPE: u32 = 10;
+
+/// The token and settings for a Plex server, stored in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlexConfig {
+    /// The base URL of the server, such as `http://192.168.1.2:32400`
+    pub url: String,
+    pub token: String,
+    /// Identifies this device to the server, so its sessions are kept apart
+    pub client_id: String,
+    /// The key of the music section to browse, `None` to search every section
+    pub section: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum PlexError {
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("no token is set")]
+    NoToken,
+    #[error("the server sent an invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+/// A section of the server, such as a music library
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlexSection {
+    pub key: String,
+    pub title: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// A track as it is returned by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PlexTrack {
+    pub rating_key: String,
+    pub title: String,
+    /// The album of the track
+    pub parent_title: Option<String>,
+    /// The album artist of the track
+    pub grandparent_title: Option<String>,
+    /// The artist of the track, if it differs from the album artist
+    pub original_title: Option<String>,
+    pub index: Option<u32>,
+    pub parent_index: Option<u32>,
+    pub parent_year: Option<u32>,
+    /// The duration in milliseconds
+    pub duration: Option<u64>,
+    pub parent_rating_key: Option<String>,
+    #[serde(rename = "Media")]
+    pub media: Vec<PlexMedia>,
+    #[serde(rename = "Genre")]
+    pub genre: Vec<PlexTag>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlexMedia {
+    #[serde(rename = "Part")]
+    pub part: Vec<PlexPart>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlexPart {
+    /// The path of the file on the server, relative to its URL
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlexTag {
+    pub tag: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MediaContainer {
+    #[serde(rename = "Metadata")]
+    metadata: Vec<PlexTrack>,
+    #[serde(rename = "Directory")]
+    directory: Vec<PlexSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(rename = "MediaContainer")]
+    container: MediaContainer,
+}
+
+/// A client for a single Plex server
+#[derive(Debug, Clone)]
+pub struct PlexClient {
+    config: PlexConfig,
+}
+
+impl PlexClient {
+    pub fn new(config: PlexConfig) -> Self {
+        PlexClient { config }
+    }
+
+    pub fn config(&self) -> &PlexConfig {
+        &self.config
+    }
+
+    fn url(&self, path: &str) -> Result<String, PlexError> {
+        if self.config.token.is_empty() {
+            return Err(PlexError::NoToken);
+        }
+        Ok(format!("{}{}", self.config.url.trim_end_matches('/'), path))
+    }
+
+    /// Make a request to the server with the headers identifying this client
+    fn get(&self, path: &str) -> Result<attohttpc::Response, PlexError> {
+        Ok(attohttpc::get(self.url(path)?)
+            .header("Accept", "application/json")
+            .header("X-Plex-Token", self.config.token.as_str())
+            .header("X-Plex-Client-Identifier", self.config.client_id.as_str())
+            .header("X-Plex-Product", PRODUCT_NAME)
+            .send()?
+            .error_for_status()?)
+    }
+
+    fn container(&self, path: &str) -> Result<MediaContainer, PlexError> {
+        let response: Response = self.get(path)?.json()?;
+        Ok(response.container)
+    }
+
+    /// List the music sections of the server
+    pub fn sections(&self) -> Result<Vec<PlexSection>, PlexError> {
+        let sections = self.container("/library/sections")?.directory;
+        Ok(sections.into_iter().filter(|section| section.kind == "artist").collect())
+    }
+
+    /// List the tracks of the configured section
+    pub fn tracks(&self, count: u32, offset: u32) -> Result<Vec<Song>, PlexError> {
+        let section = self
+            .config
+            .section
+            .as_ref()
+            .ok_or_else(|| PlexError::InvalidResponse("no section is set".into()))?;
+
+        let container = self.container(&format!(
+            "/library/sections/{}/all?type={}&X-Plex-Container-Start={}&X-Plex-Container-Size={}",
+            section, TRACK_TYPE, offset, count
+        ))?;
+        Ok(container.metadata.iter().map(|track| self.to_song(track)).collect())
+    }
+
+    /// Search the tracks of the configured section, or every section if none is set
+    pub fn search(&self, query: &str) -> Result<Vec<Song>, PlexError> {
+        let path = match &self.config.section {
+            Some(section) => format!("/library/sections/{}/search", section),
+            None => String::from("/search"),
+        };
+
+        let container = self.container(&format!(
+            "{}?type={}&query={}",
+            path,
+            TRACK_TYPE,
+            urlencoding::encode(query)
+        ))?;
+        Ok(container.metadata.iter().map(|track| self.to_song(track)).collect())
+    }
+
+    /// Get the tracks of the album with the given rating key
+    pub fn album_songs(&self, rating_key: &str) -> Result<Vec<Song>, PlexError> {
+        let container = self.container(&format!("/library/metadata/{}/children", rating_key))?;
+        Ok(container.metadata.iter().map(|track| self.to_song(track)).collect())
+    }
+
+    /// The rating key of a track, if the [URI] points to one
+    pub fn rating_key(uri: &URI) -> Option<String> {
+        match uri {
+            URI::Remote(Service::Plex, key) => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// The URL to stream a track from, which plays the file directly
+    pub fn stream_url(&self, track: &PlexTrack) -> Result<String, PlexError> {
+        let part = track
+            .media
+            .iter()
+            .flat_map(|media| &media.part)
+            .next()
+            .ok_or_else(|| PlexError::InvalidResponse("track has no media".into()))?;
+
+        self.url(&format!("{}?X-Plex-Token={}", part.key, self.config.token))
+    }
+
+    /// Look up a track by its rating key and get the URL to stream it from
+    fn resolve_key(&self, rating_key: &str) -> Result<String, PlexError> {
+        let container = self.container(&format!("/library/metadata/{}", rating_key))?;
+        let track = container
+            .metadata
+            .first()
+            .ok_or_else(|| PlexError::InvalidResponse("missing track".into()))?;
+        self.stream_url(track)
+    }
+
+    /// Convert a track from the server into a [Song], with a location
+    /// of its rating key so no credentials are stored in the library
+    pub fn to_song(&self, track: &PlexTrack) -> Song {
+        remote_song(
+            URI::Remote(Service::Plex, track.rating_key.clone()),
+            Duration::from_millis(track.duration.unwrap_or(0)),
+            vec![
+                (Tag::Title, Some(track.title.clone())),
+                (Tag::Album, track.parent_title.clone()),
+                (
+                    Tag::Artist,
+                    track.original_title.clone().or(track.grandparent_title.clone()),
+                ),
+                (Tag::AlbumArtist, track.grandparent_title.clone()),
+                (Tag::Genre, track.genre.first().map(|genre| genre.tag.clone())),
+                (Tag::Track, track.index.map(|t| t.to_string())),
+                (Tag::Disk, track.parent_index.map(|d| d.to_string())),
+                (Tag::Key("YEAR".to_string()), track.parent_year.map(|y| y.to_string())),
+            ],
+            track
+                .parent_rating_key
+                .as_ref()
+                .map(|key| URI::Remote(Service::Plex, key.clone())),
+        )
+    }
+
+    /// Mark a track as played
+    pub fn scrobble(&self, rating_key: &str) -> Result<(), PlexError> {
+        self.get(&format!(
+            "/:/scrobble?key={}&identifier=com.plexapp.plugins.library",
+            rating_key
+        ))?;
+        Ok(())
+    }
+}
+
+impl RemoteLibrary for PlexClient {
+    fn resolve(&self, uri: &URI) -> Option<Result<URI, Box<dyn Error>>> {
+        let rating_key = Self::rating_key(uri)?;
+        Some(match self.resolve_key(&rating_key) {
+            Ok(url) => Ok(URI::Remote(Service::None, url)),
+            Err(error) => Err(error.into()),
+        })
+    }
+
+    fn report_playback(
+        &self,
+        uri: &URI,
+        report: PlaybackReport,
+        position: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let rating_key = match Self::rating_key(uri) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let state = match report {
+            PlaybackReport::Started | PlaybackReport::Progress => "playing",
+            PlaybackReport::Paused => "paused",
+            PlaybackReport::Stopped { .. } => "stopped",
+        };
+        self.get(&format!(
+            "/:/timeline?ratingKey={}&key={}&state={}&time={}",
+            rating_key,
+            urlencoding::encode(&format!("/library/metadata/{}", rating_key)),
+            state,
+            position.as_millis()
+        ))?;
+
+        if report == (PlaybackReport::Stopped { finished: true }) {
+            self.scrobble(&rating_key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PlexClient, PlexConfig, Response};
+    use crate::music_storage::library::{Service, Tag, URI};
+
+    #[test]
+    fn plex_songs() {
+        let client = PlexClient::new(PlexConfig {
+            url: String::from("http://plex.local:32400/"),
+            token: String::from("token"),
+            ..Default::default()
+        });
+
+        let json = r#"{"MediaContainer": {"size": 1, "Metadata": [{"ratingKey": "42",
+            "title": "Song", "parentTitle": "Album", "grandparentTitle": "Artist",
+            "index": 3, "duration": 180000, "parentRatingKey": "7",
+            "Media": [{"Part": [{"key": "/library/parts/1/file.flac"}]}]}]}}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        let track = &response.container.metadata[0];
+
+        assert_eq!(
+            client.stream_url(track).unwrap(),
+            "http://plex.local:32400/library/parts/1/file.flac?X-Plex-Token=token"
+        );
+
+        let song = client.to_song(track);
+        assert_eq!(song.location[0], URI::Remote(Service::Plex, String::from("42")));
+        assert_eq!(song.get_tag(&Tag::Artist), Some(&String::from("Artist")));
+        assert_eq!(song.get_tag(&Tag::Album), Some(&String::from("Album")));
+        assert_eq!(song.duration.as_secs(), 180);
+        assert_eq!(PlexClient::rating_key(&song.location[0]), Some(String::from("42")));
+    }
+}
@@ -0,0 +1,619 @@
+//! Podcast subscriptions, which are kept apart from the music library.
+//! Feeds can be RSS or Atom, and the playback position and played
+//! state of each episode is tracked so it can be resumed later.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+use super::disk_space::{ensure_space, DiskSpaceError};
+use super::library::{Service, URI};
+
+/// The fraction of an episode which must be listened to for it to be marked as played
+pub const PLAYED_THRESHOLD: f64 = 0.95;
+
+#[derive(Error, Debug)]
+pub enum PodcastError {
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("invalid feed: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the feed has no episodes or title")]
+    EmptyFeed,
+    #[error("podcast or episode not found")]
+    NotFound,
+    #[error("{0}")]
+    DiskSpace(#[from] DiskSpaceError),
+}
+
+/// A single episode of a podcast
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Episode {
+    /// The unique ID of the episode given by the feed, or its URL if it has none
+    pub guid: String,
+    pub title: String,
+    pub description: Option<String>,
+    /// The URL of the episode's audio
+    pub url: String,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub published: Option<DateTime<Utc>>,
+    pub duration: Option<Duration>,
+    /// How far into the episode playback has reached
+    pub position: Duration,
+    pub played: bool,
+    /// Where the episode has been downloaded to, if it has been
+    pub download: Option<PathBuf>,
+}
+
+impl Episode {
+    /// The [URI] to play the episode from, which is the download if
+    /// it still exists, otherwise the episode is streamed
+    pub fn uri(&self) -> URI {
+        match &self.download {
+            Some(path) if path.exists() => URI::Local(path.clone()),
+            _ => URI::Remote(Service::None, self.url.clone()),
+        }
+    }
+
+    /// Whether the episode is played from `uri`, either streamed or downloaded
+    pub fn is_at(&self, uri: &URI) -> bool {
+        match uri {
+            URI::Remote(_, url) => url == &self.url,
+            URI::Local(path) => self.download.as_ref() == Some(path),
+            _ => false,
+        }
+    }
+
+    /// Update how far into the episode playback has reached, marking it as
+    /// played once [PLAYED_THRESHOLD] of it has been listened to. Returns
+    /// `true` if the episode was newly marked as played.
+    pub fn set_position(&mut self, position: Duration, duration: Option<Duration>) -> bool {
+        self.position = position;
+        if let Some(duration) = duration.filter(|d| !d.is_zero()) {
+            self.duration = Some(duration);
+        }
+
+        let reached = self
+            .duration
+            .is_some_and(|d| position.as_secs_f64() >= d.as_secs_f64() * PLAYED_THRESHOLD);
+        if reached && !self.played {
+            self.played = true;
+            return true;
+        }
+        false
+    }
+
+    /// Mark the episode as played or unplayed, which resets its position
+    pub fn set_played(&mut self, played: bool) {
+        self.played = played;
+        self.position = Duration::ZERO;
+    }
+
+    /// The position to resume playback from, `None` if it should start
+    /// from the beginning
+    pub fn resume_position(&self) -> Option<Duration> {
+        match self.played || self.position.is_zero() {
+            true => None,
+            false => Some(self.position),
+        }
+    }
+
+    /// Download the episode into `folder`, leaving at least `reserve` bytes
+    /// free on the disk, and return the path it was saved to
+    pub fn download(&mut self, folder: &Path, reserve: u64) -> Result<PathBuf, PodcastError> {
+        let path = self.download_path(folder);
+        download_to(&self.url, &path, reserve)?;
+        self.download = Some(path.clone());
+        Ok(path)
+    }
+
+    /// Where the episode is saved to when it is downloaded into `folder`
+    pub fn download_path(&self, folder: &Path) -> PathBuf {
+        let extension = self
+            .url
+            .split(['?', '#'])
+            .next()
+            .and_then(|url| url.rsplit('/').next())
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_string())
+            .unwrap_or_else(|| String::from("mp3"));
+        folder.join(format!("{:x}.{}", md5::compute(&self.guid), extension))
+    }
+
+    /// Delete the downloaded copy of the episode, so it is streamed again
+    pub fn remove_download(&mut self) -> Result<(), PodcastError> {
+        if let Some(path) = self.download.take() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A podcast which has been subscribed to
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Podcast {
+    pub uuid: Uuid,
+    pub title: String,
+    pub description: Option<String>,
+    pub feed_url: String,
+    pub image: Option<String>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub last_refreshed: Option<DateTime<Utc>>,
+    /// The episodes of the podcast, newest first
+    pub episodes: Vec<Episode>,
+}
+
+impl Podcast {
+    /// Fetch the feed at `feed_url` and create a podcast from it
+    pub fn from_url(feed_url: &str) -> Result<Self, PodcastError> {
+        let mut podcast = Podcast {
+            uuid: Uuid::new_v4(),
+            feed_url: feed_url.to_string(),
+            ..Default::default()
+        };
+        podcast.refresh()?;
+        Ok(podcast)
+    }
+
+    /// Fetch the feed again, adding any new episodes. Episodes which are
+    /// already known keep their position and played state.
+    ///
+    /// Returns the number of new episodes.
+    pub fn refresh(&mut self) -> Result<usize, PodcastError> {
+        let contents = fetch_feed(&self.feed_url)?;
+        self.refresh_from(&contents)
+    }
+
+    /// Refresh the podcast from the `contents` of its feed, which were
+    /// fetched with [fetch_feed], returning the number of new episodes
+    pub fn refresh_from(&mut self, contents: &str) -> Result<usize, PodcastError> {
+        let added = self.update_from_feed(contents)?;
+        self.last_refreshed = Some(Utc::now());
+        Ok(added)
+    }
+
+    /// Update the podcast from the contents of its feed, returning the
+    /// number of new episodes
+    pub fn update_from_feed(&mut self, contents: &str) -> Result<usize, PodcastError> {
+        let feed = parse_feed(contents)?;
+        if feed.title.is_empty() && feed.episodes.is_empty() {
+            return Err(PodcastError::EmptyFeed);
+        }
+
+        if !feed.title.is_empty() {
+            self.title = feed.title;
+        }
+        self.description = feed.description.or(self.description.take());
+        self.image = feed.image.or(self.image.take());
+
+        let mut added = 0;
+        for mut episode in feed.episodes {
+            match self.episodes.iter_mut().find(|e| e.guid == episode.guid) {
+                Some(known) => {
+                    // Keep the playback state, but pick up any edits to the episode
+                    episode.position = known.position;
+                    episode.played = known.played;
+                    episode.download = known.download.take();
+                    episode.duration = episode.duration.or(known.duration);
+                    *known = episode;
+                }
+                None => {
+                    self.episodes.push(episode);
+                    added += 1;
+                }
+            }
+        }
+
+        self.episodes.sort_by_key(|episode| std::cmp::Reverse(episode.published));
+        Ok(added)
+    }
+
+    pub fn episode(&self, guid: &str) -> Option<&Episode> {
+        self.episodes.iter().find(|episode| episode.guid == guid)
+    }
+
+    pub fn episode_mut(&mut self, guid: &str) -> Option<&mut Episode> {
+        self.episodes.iter_mut().find(|episode| episode.guid == guid)
+    }
+
+    /// The episodes which have not been played yet, newest first
+    pub fn unplayed(&self) -> Vec<&Episode> {
+        self.episodes.iter().filter(|episode| !episode.played).collect()
+    }
+}
+
+/// Every podcast which has been subscribed to, stored beside the config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Podcasts {
+    pub podcasts: Vec<Podcast>,
+}
+
+impl Podcasts {
+    /// The location of the podcasts file, which is stored beside the config
+    pub fn path(config: &Config) -> PathBuf {
+        config.path.with_file_name("podcasts.json")
+    }
+
+    /// The folder episodes are downloaded to
+    pub fn download_folder(config: &Config) -> PathBuf {
+        config.path.with_file_name("podcasts")
+    }
+
+    /// Subscribe to the feed at `feed_url`, returning the [Uuid] of the podcast.
+    /// Subscribing to a feed twice returns the existing podcast.
+    pub fn subscribe(&mut self, feed_url: &str) -> Result<Uuid, PodcastError> {
+        if let Some(podcast) = self.podcasts.iter().find(|p| p.feed_url == feed_url) {
+            return Ok(podcast.uuid);
+        }
+
+        Ok(self.add(Podcast::from_url(feed_url)?))
+    }
+
+    /// Add a podcast made with [Podcast::from_url], returning its [Uuid],
+    /// or the [Uuid] of the podcast with the same feed if there already is one
+    pub fn add(&mut self, podcast: Podcast) -> Uuid {
+        if let Some(known) = self.podcasts.iter().find(|p| p.feed_url == podcast.feed_url) {
+            return known.uuid;
+        }
+        let uuid = podcast.uuid;
+        self.podcasts.push(podcast);
+        uuid
+    }
+
+    /// Unsubscribe from a podcast, deleting any episodes which were downloaded
+    pub fn unsubscribe(&mut self, uuid: &Uuid) -> Result<Option<Podcast>, PodcastError> {
+        let index = match self.podcasts.iter().position(|p| &p.uuid == uuid) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let mut podcast = self.podcasts.remove(index);
+        for episode in &mut podcast.episodes {
+            episode.remove_download()?;
+        }
+        Ok(Some(podcast))
+    }
+
+    /// Refresh every podcast, returning the number of new episodes of each.
+    /// A podcast which fails to refresh does not stop the others.
+    pub fn refresh_all(&mut self) -> Vec<(Uuid, Result<usize, PodcastError>)> {
+        self.podcasts
+            .iter_mut()
+            .map(|podcast| (podcast.uuid, podcast.refresh()))
+            .collect()
+    }
+
+    pub fn podcast(&self, uuid: &Uuid) -> Option<&Podcast> {
+        self.podcasts.iter().find(|podcast| &podcast.uuid == uuid)
+    }
+
+    pub fn podcast_mut(&mut self, uuid: &Uuid) -> Option<&mut Podcast> {
+        self.podcasts.iter_mut().find(|podcast| &podcast.uuid == uuid)
+    }
+
+    /// Find the episode which is played from `uri`, and its podcast
+    pub fn episode_at(&self, uri: &URI) -> Option<(&Podcast, &Episode)> {
+        self.podcasts.iter().find_map(|podcast| {
+            let episode = podcast.episodes.iter().find(|episode| episode.is_at(uri))?;
+            Some((podcast, episode))
+        })
+    }
+
+    /// Find the episode which is played from `uri`
+    pub fn episode_at_mut(&mut self, uri: &URI) -> Option<&mut Episode> {
+        self.podcasts
+            .iter_mut()
+            .flat_map(|podcast| podcast.episodes.iter_mut())
+            .find(|episode| episode.is_at(uri))
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), PodcastError> {
+        let mut writer = path.to_path_buf();
+        writer.set_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&writer)?;
+        let podcasts = serde_json::to_string_pretty(self)?;
+
+        file.write_all(podcasts.as_bytes())?;
+        fs::rename(writer, path)?;
+        Ok(())
+    }
+
+    /// Read the podcasts file, returning no podcasts if it doesn't exist yet
+    pub fn read_file(path: &Path) -> Result<Self, PodcastError> {
+        if !path.exists() {
+            return Ok(Podcasts::default());
+        }
+
+        let mut file = File::open(path)?;
+        let mut bun = String::new();
+        file.read_to_string(&mut bun)?;
+        Ok(serde_json::from_str::<Podcasts>(&bun)?)
+    }
+}
+
+/// Fetch the contents of the feed at `feed_url`
+pub fn fetch_feed(feed_url: &str) -> Result<String, PodcastError> {
+    Ok(attohttpc::get(feed_url).send()?.error_for_status()?.text()?)
+}
+
+/// Download the audio at `url` to `path`, leaving at least `reserve` bytes
+/// free on the disk. Nothing is written if the server says the file is too
+/// big, and a download which runs out of space partway is removed.
+pub fn download_to(url: &str, path: &Path, reserve: u64) -> Result<(), PodcastError> {
+    if let Some(folder) = path.parent() {
+        fs::create_dir_all(folder)?;
+    }
+    let response = attohttpc::get(url).send()?.error_for_status()?;
+    let length = response
+        .headers()
+        .get(attohttpc::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    ensure_space(path, length, reserve)?;
+
+    let mut writer = path.to_path_buf();
+    writer.set_extension("tmp");
+    if let Err(error) = response.write_to(File::create(&writer)?) {
+        let _ = fs::remove_file(&writer);
+        return Err(error.into());
+    }
+    fs::rename(writer, path)?;
+    Ok(())
+}
+
+/// The parts of an RSS or Atom feed which are used
+#[derive(Debug, Default)]
+struct Feed {
+    title: String,
+    description: Option<String>,
+    image: Option<String>,
+    episodes: Vec<Episode>,
+}
+
+/// Parse an RSS or Atom feed. Entries without any audio are skipped.
+fn parse_feed(contents: &str) -> Result<Feed, PodcastError> {
+    let mut reader = Reader::from_str(contents);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut feed = Feed::default();
+    let mut stack: Vec<String> = Vec::new();
+    let mut episode: Option<Episode> = None;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                if name == "item" || name == "entry" {
+                    episode = Some(Episode::default());
+                }
+                read_attributes(&e, &name, &mut feed, &mut episode)?;
+                stack.push(name);
+            }
+            Event::Empty(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                read_attributes(&e, &name, &mut feed, &mut episode)?;
+            }
+            Event::End(e) => {
+                stack.pop();
+                let name = e.name();
+                if name.as_ref() == b"item" || name.as_ref() == b"entry" {
+                    if let Some(mut finished) = episode.take() {
+                        if finished.guid.is_empty() {
+                            finished.guid = finished.url.clone();
+                        }
+                        if !finished.url.is_empty() {
+                            feed.episodes.push(finished);
+                        }
+                    }
+                }
+            }
+            Event::Text(e) => read_text(&stack, e.unescape()?.to_string(), &mut feed, &mut episode),
+            Event::CData(e) => {
+                let text = String::from_utf8_lossy(&e.into_inner()).to_string();
+                read_text(&stack, text, &mut feed, &mut episode)
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(feed)
+}
+
+fn read_attributes(
+    e: &BytesStart,
+    name: &str,
+    feed: &mut Feed,
+    episode: &mut Option<Episode>,
+) -> Result<(), PodcastError> {
+    let attribute = |key: &str| -> Result<Option<String>, PodcastError> {
+        Ok(match e.try_get_attribute(key)? {
+            Some(value) => Some(value.unescape_value()?.to_string()),
+            None => None,
+        })
+    };
+
+    match (name, episode) {
+        ("enclosure", Some(episode)) => {
+            if let Some(url) = attribute("url")? {
+                episode.url = url;
+            }
+        }
+        // Atom links the audio with a `rel="enclosure"` link
+        ("link", Some(episode)) if attribute("rel")?.as_deref() == Some("enclosure") => {
+            if let Some(url) = attribute("href")? {
+                episode.url = url;
+            }
+        }
+        ("itunes:image", None) => feed.image = attribute("href")?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn read_text(stack: &[String], text: String, feed: &mut Feed, episode: &mut Option<Episode>) {
+    let name = match stack.last() {
+        Some(name) => name.as_str(),
+        None => return,
+    };
+
+    match episode {
+        Some(episode) => match name {
+            "title" => episode.title = text,
+            "guid" | "id" => episode.guid = text,
+            "description" | "summary" | "itunes:summary" => {
+                episode.description.get_or_insert(text);
+            }
+            "pubDate" => {
+                episode.published = DateTime::parse_from_rfc2822(&text)
+                    .ok()
+                    .map(|date| date.with_timezone(&Utc))
+            }
+            "published" | "updated" if episode.published.is_none() || name == "published" => {
+                episode.published = DateTime::parse_from_rfc3339(&text)
+                    .ok()
+                    .map(|date| date.with_timezone(&Utc))
+            }
+            "itunes:duration" => episode.duration = parse_duration(&text),
+            _ => (),
+        },
+        // Images have a title too, which isn't the title of the feed
+        None if stack.iter().any(|name| name == "image") => {
+            if name == "url" {
+                feed.image.get_or_insert(text);
+            }
+        }
+        None => match name {
+            "title" if feed.title.is_empty() => feed.title = text,
+            "description" | "subtitle" => {
+                feed.description.get_or_insert(text);
+            }
+            _ => (),
+        },
+    }
+}
+
+/// Parse a duration of seconds, `MM:SS`, or `HH:MM:SS`
+fn parse_duration(text: &str) -> Option<Duration> {
+    let mut seconds = 0;
+    for part in text.trim().split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::{parse_duration, Podcast, Podcasts};
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+        <rss xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel>
+            <title>Dango Cast</title>
+            <image><title>Not the title</title><url>https://example.com/cover.jpg</url></image>
+            <item>
+                <title>Episode 1</title>
+                <guid>ep-1</guid>
+                <pubDate>Mon, 01 Jan 2024 00:00:00 +0000</pubDate>
+                <itunes:duration>1:00:00</itunes:duration>
+                <enclosure url="https://example.com/ep1.mp3" type="audio/mpeg" />
+            </item>
+            <item>
+                <title>Episode 2</title>
+                <description><![CDATA[<p>The second one</p>]]></description>
+                <pubDate>Mon, 08 Jan 2024 00:00:00 +0000</pubDate>
+                <enclosure url="https://example.com/ep2.mp3" type="audio/mpeg" />
+            </item>
+            <item><title>Text only post</title></item>
+        </channel></rss>"#;
+
+    #[test]
+    fn podcast_feed() {
+        let mut podcast = Podcast::default();
+        assert_eq!(podcast.update_from_feed(RSS).unwrap(), 2);
+        assert_eq!(podcast.title, "Dango Cast");
+        assert_eq!(podcast.image.as_deref(), Some("https://example.com/cover.jpg"));
+
+        // Newest first, and an episode without a GUID uses its URL
+        assert_eq!(podcast.episodes[0].guid, "https://example.com/ep2.mp3");
+        assert_eq!(podcast.episodes[0].description.as_deref(), Some("<p>The second one</p>"));
+        assert_eq!(podcast.episodes[1].duration, Some(Duration::from_secs(3600)));
+
+        // Playback state survives a refresh
+        let episode = podcast.episode_mut("ep-1").unwrap();
+        assert!(!episode.set_position(Duration::from_secs(1800), None));
+        assert_eq!(episode.resume_position(), Some(Duration::from_secs(1800)));
+        assert!(episode.set_position(Duration::from_secs(3500), None));
+        assert!(episode.played);
+
+        assert_eq!(podcast.refresh_from(RSS).unwrap(), 0);
+        assert!(podcast.last_refreshed.is_some());
+        assert!(podcast.episode("ep-1").unwrap().played);
+        assert_eq!(podcast.unplayed().len(), 1);
+
+        let path = podcast.episodes[0].download_path(Path::new("downloads"));
+        assert_eq!(path.extension().unwrap(), "mp3");
+
+        // Subscribing to the same feed twice gives the same podcast
+        podcast.feed_url = "https://example.com/feed".to_string();
+        let mut podcasts = Podcasts::default();
+        let uuid = podcasts.add(podcast.clone());
+        podcast.uuid = Uuid::new_v4();
+        assert_eq!(podcasts.add(podcast), uuid);
+        assert_eq!(podcasts.podcasts.len(), 1);
+    }
+
+    #[test]
+    fn atom_feed() {
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <title>Atom Cast</title>
+            <entry>
+                <id>urn:uuid:1</id>
+                <title>First</title>
+                <updated>2024-01-01T00:00:00Z</updated>
+                <link rel="alternate" href="https://example.com/first" />
+                <link rel="enclosure" href="https://example.com/first.opus" />
+            </entry>
+        </feed>"#;
+
+        let mut podcast = Podcast::default();
+        assert_eq!(podcast.update_from_feed(atom).unwrap(), 1);
+        assert_eq!(podcast.title, "Atom Cast");
+        assert_eq!(podcast.episodes[0].guid, "urn:uuid:1");
+        assert_eq!(podcast.episodes[0].url, "https://example.com/first.opus");
+        assert!(podcast.episodes[0].published.is_some());
+
+        assert_eq!(parse_duration("62:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("45"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("soon"), None);
+    }
+}
@@ -0,0 +1,118 @@
+//! What kind of release an album is, such as an EP or a live album,
+//! taken from its tags or MusicBrainz and guessed for untagged releases
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Releases shorter than this can be singles or EPs
+const SHORT_RELEASE: Duration = Duration::from_secs(30 * 60);
+/// The most tracks a single has
+const SINGLE_TRACKS: usize = 3;
+/// The most tracks an EP has
+const EP_TRACKS: usize = 6;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReleaseType {
+    #[default]
+    Album,
+    Ep,
+    Single,
+    Live,
+    Remix,
+}
+
+impl ReleaseType {
+    /// Guess the type of a release without one tagged, from its title,
+    /// number of tracks, and total length
+    pub fn classify(title: &str, tracks: usize, duration: Duration) -> Self {
+        let title = title.to_lowercase();
+        let hints = |words: &[&str]| words.iter().any(|word| title.contains(word));
+        if hints(&["(live", "[live", " live at ", " live in "]) || title.ends_with(" live") {
+            ReleaseType::Live
+        } else if hints(&["remixes", "(remix", "[remix", " remixed"]) {
+            ReleaseType::Remix
+        } else if hints(&["(ep)", "[ep]"]) || title.ends_with(" ep") {
+            ReleaseType::Ep
+        } else if duration < SHORT_RELEASE && tracks <= SINGLE_TRACKS {
+            ReleaseType::Single
+        } else if duration < SHORT_RELEASE && tracks <= EP_TRACKS {
+            ReleaseType::Ep
+        } else {
+            ReleaseType::Album
+        }
+    }
+
+    /// The type of a MusicBrainz release group, where the secondary types
+    /// of live and remix releases take precedence over the primary type
+    pub fn from_musicbrainz(primary: Option<&str>, secondary: &[String]) -> Option<Self> {
+        let types: Vec<&str> = secondary.iter().map(String::as_str).chain(primary).collect();
+        types.join("; ").parse().ok()
+    }
+}
+
+impl FromStr for ReleaseType {
+    type Err = String;
+
+    /// Parse a release type tag, such as `album`, `EP`, or `album; live`
+    /// as written by MusicBrainz Picard
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let types: Vec<String> = value.split([';', '/', ',']).map(|part| part.trim().to_lowercase()).collect();
+        let has = |kind: &str| types.iter().any(|part| part == kind);
+        if has("live") {
+            Ok(ReleaseType::Live)
+        } else if has("remix") {
+            Ok(ReleaseType::Remix)
+        } else if has("single") {
+            Ok(ReleaseType::Single)
+        } else if has("ep") {
+            Ok(ReleaseType::Ep)
+        } else if has("album") || has("compilation") || has("soundtrack") {
+            Ok(ReleaseType::Album)
+        } else {
+            Err(format!("Unknown release type: {}", value))
+        }
+    }
+}
+
+impl fmt::Display for ReleaseType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ReleaseType::Album => "album",
+            ReleaseType::Ep => "ep",
+            ReleaseType::Single => "single",
+            ReleaseType::Live => "live",
+            ReleaseType::Remix => "remix",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ReleaseType;
+
+    #[test]
+    fn release_types() {
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        assert_eq!(ReleaseType::classify("One More Time", 2, minutes(9)), ReleaseType::Single);
+        assert_eq!(ReleaseType::classify("Four Tet", 5, minutes(25)), ReleaseType::Ep);
+        assert_eq!(ReleaseType::classify("Long Single", 2, minutes(40)), ReleaseType::Album);
+        assert_eq!(ReleaseType::classify("Alive 2007 (Live)", 12, minutes(70)), ReleaseType::Live);
+        assert_eq!(ReleaseType::classify("Daft Club: The Remixes", 15, minutes(70)), ReleaseType::Remix);
+        assert_eq!(ReleaseType::classify("Homework EP", 8, minutes(40)), ReleaseType::Ep);
+        assert_eq!(ReleaseType::classify("Discovery", 14, minutes(61)), ReleaseType::Album);
+
+        assert_eq!("album; live".parse(), Ok(ReleaseType::Live));
+        assert_eq!("EP".parse(), Ok(ReleaseType::Ep));
+        assert!("bootleg".parse::<ReleaseType>().is_err());
+        assert_eq!(
+            ReleaseType::from_musicbrainz(Some("Album"), &["Remix".to_string()]),
+            Some(ReleaseType::Remix)
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Finding songs whose files have been moved, so they can be pointed at
+//! their new location instead of being removed along with their play
+//! counts and playlist membership
+//!
+//! Files are matched by name, then by the [FileIdentity] recorded when
+//! the song was added, within the root folders of the library
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use super::library::{AlbumArt, InternalTag, MusicLibrary, URI};
+use crate::config::LibraryRoot;
+
+/// The number of bytes from the start of a file which are hashed
+const HASHED_BYTES: u64 = 64 * 1024;
+
+/// The size and hash of a file, which stay the same when it is moved
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FileIdentity {
+    pub size: u64,
+    /// The MD5 hash of the start of the file
+    pub hash: String,
+}
+
+impl FileIdentity {
+    pub fn read(path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut start = Vec::new();
+        file.take(HASHED_BYTES).read_to_end(&mut start)?;
+        Ok(FileIdentity {
+            size,
+            hash: format!("{:x}", md5::compute(&start)),
+        })
+    }
+}
+
+/// A song which was found at a new location
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Relocation {
+    pub song: Uuid,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RelocationReport {
+    pub relocated: Vec<Relocation>,
+    /// Songs with a missing file which couldn't be found
+    pub not_found: Vec<Uuid>,
+}
+
+/// The files in the roots of a library which aren't used by any song
+struct Candidates {
+    by_name: HashMap<OsString, Vec<(PathBuf, u64)>>,
+    by_size: HashMap<u64, Vec<PathBuf>>,
+    claimed: HashSet<PathBuf>,
+}
+
+impl Candidates {
+    fn find(roots: &[&LibraryRoot], known: &HashSet<PathBuf>) -> Self {
+        let mut candidates = Candidates {
+            by_name: HashMap::new(),
+            by_size: HashMap::new(),
+            claimed: HashSet::new(),
+        };
+        let files = roots
+            .iter()
+            .flat_map(|root| WalkDir::new(&root.path).follow_links(true))
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && !known.contains(entry.path()));
+
+        for entry in files {
+            let size = match entry.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            let path = entry.into_path();
+            if let Some(name) = path.file_name() {
+                candidates
+                    .by_name
+                    .entry(name.to_os_string())
+                    .or_default()
+                    .push((path.clone(), size));
+            }
+            candidates.by_size.entry(size).or_default().push(path);
+        }
+        candidates
+    }
+
+    /// Find the new location of a missing file. With an identity the file
+    /// must match it, otherwise the name must be unique.
+    fn locate(&mut self, missing: &Path, identity: Option<&FileIdentity>) -> Option<PathBuf> {
+        let named: Vec<&PathBuf> = missing
+            .file_name()
+            .and_then(|name| self.by_name.get(name))
+            .into_iter()
+            .flatten()
+            .filter(|(path, size)| !self.claimed.contains(path) && identity.is_none_or(|id| id.size == *size))
+            .map(|(path, _)| path)
+            .collect();
+
+        let found = match identity {
+            None if named.len() == 1 => Some(named[0].clone()),
+            None => None,
+            Some(identity) => {
+                let same_size = self.by_size.get(&identity.size).into_iter().flatten();
+                // Files with the same name are checked first, then any file
+                // of the same size in case it was renamed too
+                named
+                    .into_iter()
+                    .chain(same_size)
+                    .filter(|path| !self.claimed.contains(*path))
+                    .find(|path| FileIdentity::read(path).is_ok_and(|found| &found == identity))
+                    .cloned()
+            }
+        };
+        if let Some(path) = &found {
+            self.claimed.insert(path.clone());
+        }
+        found
+    }
+}
+
+impl MusicLibrary {
+    /// Record the [FileIdentity] of every local song which doesn't have one,
+    /// such as songs added before identities were recorded. Returns the
+    /// number of songs which were updated.
+    pub fn record_identities(&mut self) -> usize {
+        let mut recorded = 0;
+        for song in &mut self.library {
+            if song.file_identity().is_some() {
+                continue;
+            }
+            if let Some(URI::Local(path)) = song.location.first() {
+                if let Ok(identity) = FileIdentity::read(path) {
+                    song.set_file_identity(identity);
+                    recorded += 1;
+                }
+            }
+        }
+        recorded
+    }
+
+    /// Look for the files of songs which no longer exist inside of the
+    /// available `roots`, and update the songs to point to where they were
+    /// found. Songs inside of unavailable roots are left alone.
+    pub fn relocate_missing(&mut self, roots: &[LibraryRoot]) -> RelocationReport {
+        let (available, unavailable): (Vec<&LibraryRoot>, Vec<&LibraryRoot>) =
+            roots.iter().partition(|root| root.is_available());
+        let mut report = RelocationReport::default();
+
+        let is_missing = |path: &Path| !path.exists() && !unavailable.iter().any(|root| root.contains(path));
+        let mut known = HashSet::new();
+        let mut missing = false;
+        for location in self.library.iter().flat_map(|song| &song.location) {
+            if let URI::Remote(_, _) = location {
+                continue;
+            }
+            let path = location.path();
+            missing |= is_missing(&path);
+            known.insert(path);
+        }
+        if !missing {
+            return report;
+        }
+
+        let mut candidates = Candidates::find(&available, &known);
+        // Every track of a cue sheet shares a file, so it is only looked for once
+        let mut moved: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+
+        for song in &mut self.library {
+            // The song can't be borrowed whole while its locations are changed
+            let identity = song.internal_tags.iter().find_map(|tag| match tag {
+                InternalTag::FileIdentity(identity) => Some(identity),
+                _ => None,
+            });
+            let mut relocated = false;
+            let mut lost = false;
+            for location in &mut song.location {
+                let path = match location {
+                    URI::Local(path) | URI::Cue { location: path, .. } => path,
+                    URI::Remote(_, _) => continue,
+                };
+                if !is_missing(path) {
+                    continue;
+                }
+                let found = moved
+                    .entry(path.clone())
+                    .or_insert_with(|| candidates.locate(path, identity))
+                    .clone();
+                match found {
+                    Some(new_path) => {
+                        report.relocated.push(Relocation {
+                            song: song.uuid,
+                            from: path.clone(),
+                            to: new_path.clone(),
+                        });
+                        *path = new_path;
+                        relocated = true;
+                    }
+                    None => lost = true,
+                }
+            }
+
+            if relocated {
+                relocate_art(&mut song.album_art, &report.relocated);
+            }
+            if lost {
+                report.not_found.push(song.uuid);
+            }
+        }
+        report
+    }
+}
+
+/// Move missing external art along with the song, if a file with the same
+/// name is in the folder the song was moved to
+fn relocate_art(album_art: &mut [AlbumArt], relocated: &[Relocation]) {
+    for art in album_art {
+        let path = match art {
+            AlbumArt::External(URI::Local(path)) if !path.exists() => path,
+            _ => continue,
+        };
+        let (Some(folder), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+
+        let new_path = relocated
+            .iter()
+            .filter(|relocation| relocation.from.parent() == Some(folder))
+            .filter_map(|relocation| Some(relocation.to.parent()?.join(name)))
+            .find(|new_path| new_path.exists());
+        if let Some(new_path) = new_path {
+            *path = new_path;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::config::LibraryRoot;
+    use crate::music_storage::library::{test::test_song, MusicLibrary, URI};
+
+    #[test]
+    fn relocate_missing() {
+        let folder = tempfile::tempdir().unwrap();
+        let root = folder.path().join("music");
+        fs::create_dir_all(root.join("old")).unwrap();
+        fs::create_dir_all(root.join("new")).unwrap();
+
+        let mut library = MusicLibrary::from_path(&folder.path().join("library.dlib")).unwrap();
+        let mut songs = Vec::new();
+        for name in ["moved", "renamed", "deleted"] {
+            let path = root.join("old").join(format!("{name}.flac"));
+            fs::write(&path, format!("the audio of {name}")).unwrap();
+            let mut song = test_song(name, "Artist", Duration::from_secs(60));
+            song.location = vec![URI::Local(path)];
+            song.plays = 3;
+            songs.push(song);
+        }
+        library.library = songs;
+        assert_eq!(library.record_identities(), 3);
+
+        fs::rename(root.join("old/moved.flac"), root.join("new/moved.flac")).unwrap();
+        fs::rename(root.join("old/renamed.flac"), root.join("new/01 renamed.flac")).unwrap();
+        fs::remove_file(root.join("old/deleted.flac")).unwrap();
+        // A different file with the same name as the deleted one
+        fs::write(root.join("new/deleted.flac"), "something else").unwrap();
+
+        let report = library.relocate_missing(&[LibraryRoot::new(root.clone())]);
+        assert_eq!(report.relocated.len(), 2);
+        assert_eq!(report.not_found, vec![library.library[2].uuid]);
+        assert_eq!(library.library[0].location, vec![URI::Local(root.join("new/moved.flac"))]);
+        assert_eq!(library.library[1].location, vec![URI::Local(root.join("new/01 renamed.flac"))]);
+        assert_eq!(library.library[1].plays, 3);
+    }
+}
@@ -0,0 +1,96 @@
+//! Shared parts of the remote library providers, such as
+//! [Subsonic](super::subsonic), [Jellyfin](super::jellyfin),
+//! and [Plex](super::plex)
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use super::library::{AlbumArt, Song, Tag, URI};
+
+/// The state of playback which is reported to a remote server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackReport {
+    Started,
+    Progress,
+    Paused,
+    /// Playback stopped, `finished` is set if the song was listened
+    /// to enough to count as played
+    Stopped { finished: bool },
+}
+
+/// A server which songs in the library can be streamed from
+pub trait RemoteLibrary: Send + Sync {
+    /// Turn a [URI] of a song on the server into one which the player can
+    /// stream. Returns `None` for URIs which are not from this server.
+    fn resolve(&self, uri: &URI) -> Option<Result<URI, Box<dyn Error>>>;
+
+    /// Tell the server about the playback of a song, so that its play state
+    /// stays in sync with other clients. URIs which are not from this server
+    /// are ignored.
+    fn report_playback(
+        &self,
+        uri: &URI,
+        report: PlaybackReport,
+        position: Duration,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// Turn a [URI] into one which can be played using the first remote library
+/// that recognizes it, other URIs are returned as-is
+pub fn resolve_uri(remotes: &[Box<dyn RemoteLibrary>], uri: &URI) -> Result<URI, Box<dyn Error>> {
+    for remote in remotes {
+        if let Some(resolved) = remote.resolve(uri) {
+            return resolved;
+        }
+    }
+    Ok(uri.clone())
+}
+
+/// Report playback to every remote library, printing any errors
+pub fn report_playback(
+    remotes: &[Box<dyn RemoteLibrary>],
+    uri: &URI,
+    report: PlaybackReport,
+    position: Duration,
+) {
+    for remote in remotes {
+        if let Err(error) = remote.report_playback(uri, report, position) {
+            println!("Failed to report playback: {}", error);
+        }
+    }
+}
+
+/// Create a [Song] from the metadata given by a remote server
+pub(super) fn remote_song(
+    location: URI,
+    duration: Duration,
+    tags: Vec<(Tag, Option<String>)>,
+    album_art: Option<URI>,
+) -> Song {
+    let tags: BTreeMap<Tag, String> = tags
+        .into_iter()
+        .filter_map(|(tag, value)| value.map(|value| (tag, value)))
+        .collect();
+
+    Song {
+        location: vec![location],
+        uuid: Uuid::new_v4(),
+        plays: 0,
+        skips: 0,
+        favorited: false,
+        banned: None,
+        rating: None,
+        format: None,
+        duration,
+        play_time: Duration::from_secs(0),
+        last_played: None,
+        date_added: Some(chrono::offset::Utc::now()),
+        date_modified: Some(chrono::offset::Utc::now()),
+        album_art: album_art.map(AlbumArt::External).into_iter().collect(),
+        tags,
+        internal_tags: Vec::new(),
+    }
+}
@@ -0,0 +1,392 @@
+//! Rip logs written by Exact Audio Copy, X Lossless Decoder, and CUETools
+//! next to ripped albums, and whether they say each track was ripped
+//! accurately according to AccurateRip
+//!
+//! The result is kept in [Tag::RipStatus], so songs can be filtered by it
+//! like any other tag, such as with a [SmartRule::Is](super::smart_playlist::SmartRule::Is)
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// The extensions of files which can be rip logs
+const LOG_EXTENSIONS: &[&str] = &["log", "accurip"];
+
+#[derive(Error, Debug)]
+pub enum RipLogError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a rip log from a known ripper")]
+    Unknown,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RipStatus {
+    /// AccurateRip matched the track
+    Accurate,
+    /// The ripper found errors, or AccurateRip didn't match the track
+    Suspicious,
+    /// The track isn't in the AccurateRip database, or there's no log
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ripper {
+    Eac,
+    Xld,
+    CueTools,
+}
+
+/// What a rip log says about one track
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrackRip {
+    pub status: RipStatus,
+    /// How many other rips AccurateRip matched
+    pub confidence: Option<u32>,
+    pub crc: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RipLog {
+    pub ripper: Ripper,
+    /// Each track in the log by its number
+    pub tracks: BTreeMap<u16, TrackRip>,
+}
+
+impl RipLog {
+    /// Read a log, which EAC writes in UTF-16
+    pub fn read(path: &Path) -> Result<Self, RipLogError> {
+        Self::parse(&decode(&fs::read(path)?))
+    }
+
+    pub fn parse(log: &str) -> Result<Self, RipLogError> {
+        let ripper = if log.contains("Exact Audio Copy") {
+            Ripper::Eac
+        } else if log.contains("X Lossless Decoder") {
+            Ripper::Xld
+        } else if log.contains("CUETools") || log.contains("[AccurateRip ID:") {
+            Ripper::CueTools
+        } else {
+            return Err(RipLogError::Unknown);
+        };
+
+        let mut tracks: BTreeMap<u16, TrackRip> = BTreeMap::new();
+        let mut current = None;
+        for line in log.lines().map(str::trim) {
+            // `Track  1` starts a track, and EAC's summary has lines such
+            // as `Track  1  accurately ripped (confidence 5)` after them
+            let numbered = match ripper {
+                Ripper::CueTools => leading_number(line),
+                _ => line.strip_prefix("Track").and_then(leading_number),
+            };
+            let text = match numbered {
+                Some((number, rest)) => {
+                    current = Some(number);
+                    tracks.entry(number).or_default();
+                    rest
+                }
+                None => line,
+            };
+            if let Some(track) = current.and_then(|number| tracks.get_mut(&number)) {
+                read_line(track, text);
+            }
+        }
+        Ok(RipLog { ripper, tracks })
+    }
+}
+
+/// Split a line into the number at its start and the rest of it
+fn leading_number(line: &str) -> Option<(u16, &str)> {
+    let line = line.trim_start();
+    let end = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len());
+    let number = line[..end].parse().ok()?;
+    Some((number, line[end..].trim()))
+}
+
+/// Update a track with what a line of its section of the log says
+fn read_line(track: &mut TrackRip, line: &str) {
+    let lower = line.to_lowercase();
+    let suspicious = [
+        "suspicious position",
+        "may not be accurate",
+        "cannot be verified as accurate",
+        "no match",
+        "differs",
+        "copy aborted",
+    ];
+    if suspicious.iter().any(|hint| lower.contains(hint)) {
+        track.status = RipStatus::Suspicious;
+    } else if lower.contains("accurately ripped") && track.status != RipStatus::Suspicious {
+        track.status = RipStatus::Accurate;
+    } else if let Some(errors) = lower.strip_prefix("read error").or_else(|| lower.strip_prefix("damaged sector count")) {
+        // XLD lists how many errors it read, which should be none
+        if errors.trim_start_matches([' ', ':']).parse::<u32>().is_ok_and(|errors| errors > 0) {
+            track.status = RipStatus::Suspicious;
+        }
+    }
+
+    if let Some(confidence) = lower.split("confidence").nth(1) {
+        track.confidence = leading_number(confidence.trim_start_matches([' ', '('])).map(|(number, _)| number.into());
+    } else if lower.contains("crc") && !lower.contains("test") && track.crc.is_none() {
+        // `Copy CRC 1A2B3C4D` from EAC, and `CRC32 hash : 1A2B3C4D` from XLD
+        let crc = line.split([' ', ':']).rfind(|word| !word.is_empty()).unwrap_or_default();
+        if crc.len() == 8 && crc.chars().all(|c| c.is_ascii_hexdigit()) {
+            track.crc = Some(crc.to_uppercase());
+        }
+    } else if let Some(confidence) = line.strip_suffix("Accurately ripped").and_then(|rest| rest.rsplit_once('(')) {
+        // CUETools writes the confidence first, as `(05/10) Accurately ripped`
+        track.confidence = leading_number(confidence.1).map(|(number, _)| number.into());
+    }
+}
+
+/// Read the text of a log, which may be UTF-16 with a byte order mark
+fn decode(bytes: &[u8]) -> String {
+    match bytes {
+        [0xFF, 0xFE, rest @ ..] => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        [0xFE, 0xFF, rest @ ..] => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8_lossy(rest).to_string(),
+        bytes => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
+impl FromStr for RipStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "accurate" => Ok(RipStatus::Accurate),
+            "suspicious" => Ok(RipStatus::Suspicious),
+            "unknown" => Ok(RipStatus::Unknown),
+            _ => Err(format!("Unknown rip status: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for RipStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RipStatus::Accurate => "accurate",
+            RipStatus::Suspicious => "suspicious",
+            RipStatus::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How well the tracks of an album were ripped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlbumQuality {
+    pub album: String,
+    pub artist: Option<String>,
+    pub accurate: usize,
+    pub suspicious: usize,
+    pub unknown: usize,
+}
+
+impl AlbumQuality {
+    pub fn tracks(&self) -> usize {
+        self.accurate + self.suspicious + self.unknown
+    }
+
+    /// Suspicious if any track is, and only accurate if every track is
+    pub fn status(&self) -> RipStatus {
+        if self.suspicious > 0 {
+            RipStatus::Suspicious
+        } else if self.accurate == self.tracks() {
+            RipStatus::Accurate
+        } else {
+            RipStatus::Unknown
+        }
+    }
+}
+
+impl Song {
+    pub fn rip_status(&self) -> RipStatus {
+        self.get_tag(&Tag::RipStatus).and_then(|status| status.parse().ok()).unwrap_or_default()
+    }
+}
+
+impl MusicLibrary {
+    /// Read the rip logs in the folders of local songs, and tag each song
+    /// with what its log says. Folders with a log for each disc are
+    /// matched to discs in the order of their file names. Returns how many
+    /// songs were tagged.
+    pub fn verify_rips(&mut self) -> usize {
+        let mut folders: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+        for (i, song) in self.library.iter().enumerate() {
+            let folder = song.location.iter().find_map(|uri| match uri {
+                URI::Local(path) | URI::Cue { location: path, .. } => path.parent().map(Path::to_path_buf),
+                URI::Remote(..) => None,
+            });
+            if let Some(folder) = folder {
+                folders.entry(folder).or_default().push(i);
+            }
+        }
+
+        let mut tagged = 0;
+        for (folder, songs) in folders {
+            let logs = folder_logs(&folder);
+            for i in songs {
+                let song = &mut self.library[i];
+                let number = |tag: &Tag| -> Option<u16> { song.get_tag(tag)?.split('/').next()?.trim().parse().ok() };
+                let log = match logs.len() {
+                    0 => continue,
+                    1 => &logs[0],
+                    _ => match logs.get(usize::from(number(&Tag::Disk).unwrap_or(1)).saturating_sub(1)) {
+                        Some(log) => log,
+                        None => continue,
+                    },
+                };
+                let Some(rip) = number(&Tag::Track).and_then(|track| log.tracks.get(&track)) else {
+                    continue;
+                };
+                song.set_tag(Tag::RipStatus, rip.status.to_string());
+                tagged += 1;
+            }
+        }
+        tagged
+    }
+
+    /// How well each album in the library was ripped, by album title
+    pub fn album_quality(&self) -> Vec<AlbumQuality> {
+        let mut albums: BTreeMap<String, AlbumQuality> = BTreeMap::new();
+        for song in &self.library {
+            let Some(title) = song.get_tag(&Tag::Album) else { continue };
+            let album = albums.entry(title.clone()).or_insert_with(|| AlbumQuality {
+                album: title.clone(),
+                artist: song.get_tag(&Tag::AlbumArtist).or(song.get_tag(&Tag::Artist)).cloned(),
+                accurate: 0,
+                suspicious: 0,
+                unknown: 0,
+            });
+            match song.rip_status() {
+                RipStatus::Accurate => album.accurate += 1,
+                RipStatus::Suspicious => album.suspicious += 1,
+                RipStatus::Unknown => album.unknown += 1,
+            }
+        }
+        albums.into_values().collect()
+    }
+}
+
+/// The rip logs in a folder, in order of their file names
+fn folder_logs(folder: &Path) -> Vec<RipLog> {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+            extension.is_some_and(|ext| LOG_EXTENSIONS.contains(&ext.as_str()))
+        })
+        .collect();
+    paths.sort();
+    paths.iter().filter_map(|path| RipLog::read(path).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{RipLog, RipStatus, Ripper};
+    use crate::music_storage::library::{test::test_song, MusicLibrary, Tag, URI};
+
+    const EAC_LOG: &str = "Exact Audio Copy V1.0 beta 3 from 29. August 2011
+
+Track  1
+
+     Filename C:\\Rips\\01 - One.wav
+
+     Peak level 98.8 %
+     Copy CRC 1A2B3C4D
+     Accurately ripped (confidence 5)  [12345678]  (AR v2)
+     Copy OK
+
+Track  2
+
+     Filename C:\\Rips\\02 - Two.wav
+
+     Suspicious position 0:02:20
+     Copy CRC 0BADF00D
+     Copy finished
+
+Track  3
+
+     Copy CRC 00C0FFEE
+     Track not present in AccurateRip database
+     Copy OK
+";
+
+    const XLD_LOG: &str = "X Lossless Decoder version 20121222 (145.0)
+
+Track 01
+    Filename : /Rips/01 One.flac
+    CRC32 hash               : 1A2B3C4D
+        ->Accurately ripped (v1+v2, confidence 5+3/8)
+    Statistics
+        Read error                           : 0
+
+Track 02
+    CRC32 hash               : 0BADF00D
+        ->Rip may not be accurate.
+    Statistics
+        Read error                           : 2
+";
+
+    #[test]
+    fn rip_logs() {
+        let log = RipLog::parse(EAC_LOG).unwrap();
+        assert_eq!(log.ripper, Ripper::Eac);
+        assert_eq!(log.tracks.len(), 3);
+        assert_eq!(log.tracks[&1].status, RipStatus::Accurate);
+        assert_eq!(log.tracks[&1].confidence, Some(5));
+        assert_eq!(log.tracks[&1].crc.as_deref(), Some("1A2B3C4D"));
+        assert_eq!(log.tracks[&2].status, RipStatus::Suspicious);
+        assert_eq!(log.tracks[&3].status, RipStatus::Unknown);
+
+        let log = RipLog::parse(XLD_LOG).unwrap();
+        assert_eq!(log.ripper, Ripper::Xld);
+        assert_eq!(log.tracks[&1].status, RipStatus::Accurate);
+        assert_eq!(log.tracks[&1].confidence, Some(5));
+        assert_eq!(log.tracks[&2].status, RipStatus::Suspicious);
+        assert!(RipLog::parse("just some notes").is_err());
+
+        // EAC writes its logs in UTF-16
+        let folder = tempfile::tempdir().unwrap();
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(EAC_LOG.encode_utf16().flat_map(u16::to_le_bytes));
+        std::fs::write(folder.path().join("Album.log"), bytes).unwrap();
+
+        let mut library = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        for track in 1..=4 {
+            let mut song = test_song(&format!("Track {}", track), "Artist", Duration::from_secs(200));
+            song.location = vec![URI::Local(folder.path().join(format!("{:02}.flac", track)))];
+            song.set_tag(Tag::Album, "Album".to_string());
+            song.set_tag(Tag::Track, format!("{}/4", track));
+            library.library.push(song);
+        }
+        assert_eq!(library.verify_rips(), 3);
+        assert_eq!(library.library[0].rip_status(), RipStatus::Accurate);
+        assert_eq!(library.library[3].get_tag(&Tag::RipStatus), None);
+
+        let report = library.album_quality();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].accurate, report[0].suspicious, report[0].unknown), (1, 1, 2));
+        assert_eq!(report[0].status(), RipStatus::Suspicious);
+    }
+}
@@ -0,0 +1,188 @@
+//! Finding the silence at the start and end of songs, so it can be skipped
+//! for tighter playback between tracks
+//!
+//! Where the sound starts and ends is stored as [InternalTag::SilenceTrim].
+//! Only local files are measured, as the tracks of a cue sheet usually run
+//! into each other.
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::decode::{decode_file, DecodeFormat};
+use super::library::{InternalTag, MusicLibrary, Song, URI};
+
+/// How loud the audio can be while still counting as silence, in dBFS
+pub const SILENCE_THRESHOLD: f64 = -60.0;
+
+/// Silence shorter than this is left alone, it's part of the song
+const MIN_SILENCE: Duration = Duration::from_millis(250);
+
+/// How much of the silence is kept next to the sound, so quiet fades
+/// aren't cut off
+const TRIM_MARGIN: Duration = Duration::from_millis(50);
+
+/// Where the sound of a song starts and ends, with silence before and after
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SilenceTrim {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// Finds the [SilenceTrim] of a song from samples as they're decoded
+#[derive(Debug, Default, Clone)]
+pub struct SilenceDetector {
+    rate: u32,
+    frames: u64,
+    /// The first and last frames which were louder than the threshold
+    sound: Option<(u64, u64)>,
+}
+
+impl SilenceDetector {
+    pub fn new() -> Self {
+        SilenceDetector::default()
+    }
+
+    /// Add interleaved `samples` with `channels` channels at `rate`, where
+    /// `1` is full scale
+    pub fn add_samples(&mut self, rate: u32, channels: usize, samples: &[f64]) {
+        let threshold = 10f64.powf(SILENCE_THRESHOLD / 20.0);
+        self.rate = rate;
+        for frame in samples.chunks(channels.max(1)) {
+            if frame.iter().any(|sample| sample.abs() > threshold) {
+                let first = self.sound.map_or(self.frames, |(first, _)| first);
+                self.sound = Some((first, self.frames));
+            }
+            self.frames += 1;
+        }
+    }
+
+    /// Where the sound starts and ends, which is the whole song if it's
+    /// silent the whole way through
+    pub fn finish(&self) -> SilenceTrim {
+        let time = |frames: u64| Duration::from_secs_f64(frames as f64 / self.rate.max(1) as f64);
+        let length = time(self.frames);
+        let Some((first, last)) = self.sound else {
+            return SilenceTrim { start: Duration::ZERO, end: length };
+        };
+
+        let start = time(first).saturating_sub(TRIM_MARGIN);
+        let end = (time(last + 1) + TRIM_MARGIN).min(length);
+        SilenceTrim {
+            start: if start < MIN_SILENCE { Duration::ZERO } else { start },
+            end: if length - end < MIN_SILENCE { length } else { end },
+        }
+    }
+}
+
+/// Decode a file to find the silence at its start and end
+pub fn measure_silence(path: &Path) -> Result<SilenceTrim, Box<dyn Error>> {
+    let detector = Arc::new(Mutex::new(SilenceDetector::new()));
+    let detector_ = detector.clone();
+    decode_file(path, DecodeFormat::default(), move |rate, channels, samples| {
+        detector_.lock().unwrap().add_samples(rate, channels, samples);
+    })?;
+    let trim = detector.lock().unwrap().finish();
+    Ok(trim)
+}
+
+/// The results of [MusicLibrary::scan_silence]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SilenceScan {
+    /// Each song with silence to skip, and where its sound is
+    pub trimmed: Vec<(Uuid, SilenceTrim)>,
+    /// Songs which couldn't be measured, and why
+    pub failed: Vec<(Uuid, String)>,
+}
+
+impl Song {
+    /// Where the sound of the song starts and ends, if it has been measured
+    pub fn silence_trim(&self) -> Option<SilenceTrim> {
+        self.internal_tags.iter().find_map(|tag| match tag {
+            InternalTag::SilenceTrim(trim) => Some(*trim),
+            _ => None,
+        })
+    }
+
+    /// The part of the song to play with the silence skipped, if it has any
+    pub fn trimmed_range(&self) -> Option<(Duration, Duration)> {
+        let trim = self.silence_trim()?;
+        let trimmed = trim.start > Duration::ZERO || trim.end < self.duration;
+        (trimmed && trim.start < trim.end).then_some((trim.start, trim.end))
+    }
+}
+
+impl MusicLibrary {
+    /// Find the silence at the start and end of every local song, or only
+    /// those which haven't been measured unless `rescan` is set
+    pub fn scan_silence(&mut self, rescan: bool) -> SilenceScan {
+        let songs: Vec<(usize, &Path)> = self
+            .library
+            .iter()
+            .enumerate()
+            .filter(|(_, song)| rescan || song.silence_trim().is_none())
+            .filter_map(|(index, song)| match song.location.first() {
+                Some(URI::Local(path)) => Some((index, path.as_path())),
+                _ => None,
+            })
+            .collect();
+
+        let measured: Vec<_> = songs
+            .into_par_iter()
+            .map(|(index, path)| (index, measure_silence(path).map_err(|error| error.to_string())))
+            .collect();
+
+        let mut scan = SilenceScan::default();
+        for (index, trim) in measured {
+            let song = &mut self.library[index];
+            match trim {
+                Ok(trim) => {
+                    song.internal_tags.retain(|tag| !matches!(tag, InternalTag::SilenceTrim(_)));
+                    song.internal_tags.push(InternalTag::SilenceTrim(trim));
+                    if song.trimmed_range().is_some() {
+                        scan.trimmed.push((song.uuid, trim));
+                    }
+                }
+                Err(error) => scan.failed.push((song.uuid, error)),
+            }
+        }
+        scan
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{SilenceDetector, SilenceTrim};
+    use crate::music_storage::library::{test::test_song, InternalTag};
+
+    #[test]
+    fn silence_trim() {
+        // Two seconds of silence, a second of sound, then a tenth of a
+        // second of silence which is too short to skip
+        let rate = 1000;
+        let mut detector = SilenceDetector::new();
+        detector.add_samples(rate, 2, &[0.0001; 4000]);
+        detector.add_samples(rate, 2, &[0.5, -0.5].repeat(1000));
+        detector.add_samples(rate, 2, &[0.0; 200]);
+        let trim = detector.finish();
+        assert_eq!(trim, SilenceTrim { start: Duration::from_millis(1950), end: Duration::from_millis(3100) });
+
+        let mut song = test_song("Song", "Artist", Duration::from_millis(3100));
+        assert_eq!(song.trimmed_range(), None);
+        song.internal_tags.push(InternalTag::SilenceTrim(trim));
+        assert_eq!(song.trimmed_range(), Some((trim.start, trim.end)));
+
+        // Songs which are silent throughout are left alone
+        let mut silent = SilenceDetector::new();
+        silent.add_samples(rate, 1, &[0.0; 500]);
+        let trim = silent.finish();
+        assert_eq!(trim, SilenceTrim { start: Duration::ZERO, end: Duration::from_millis(500) });
+    }
+}
@@ -0,0 +1,154 @@
+//! Playlists which hold every song in the library matching a set of
+//! rules, such as songs with a BPM between 120 and 128
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::analysis::MusicalKey;
+use super::library::{MusicLibrary, Song, Tag};
+use super::release_type::ReleaseType;
+use super::utils::normalize;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SmartRule {
+    /// The tag contains the text, ignoring case and accents
+    Contains { tag: Tag, value: String },
+    /// The tag is the text, ignoring case and accents. Keys are compared
+    /// as keys, so `A minor` is `Am`.
+    Is { tag: Tag, value: String },
+    /// The tag is a number from `min` to `max`, inclusive
+    Between { tag: Tag, min: f64, max: f64 },
+    /// The song is on a release of one of the types
+    ReleaseType(Vec<ReleaseType>),
+}
+
+impl SmartRule {
+    /// Whether the song matches the rule. Songs without a release type
+    /// tagged only match [SmartRule::ReleaseType] when the type of their
+    /// album can be guessed, see [SmartPlaylist::songs].
+    pub fn matches(&self, song: &Song) -> bool {
+        self.matches_in(song, &BTreeMap::new())
+    }
+
+    /// Whether the song matches the rule, using the `release_types` of the
+    /// albums in the library for songs without one tagged
+    fn matches_in(&self, song: &Song, release_types: &BTreeMap<String, ReleaseType>) -> bool {
+        match self {
+            SmartRule::Contains { tag, value } => song
+                .get_tag(tag)
+                .is_some_and(|found| normalize(found).contains(&normalize(value))),
+            SmartRule::Is { tag: Tag::InitialKey, value } => {
+                let key = song.get_tag(&Tag::InitialKey).and_then(|key| key.parse::<MusicalKey>().ok());
+                key.is_some_and(|key| value.parse::<MusicalKey>() == Ok(key))
+            }
+            SmartRule::Is { tag, value } => song.get_tag(tag).is_some_and(|found| normalize(found) == normalize(value)),
+            SmartRule::Between { tag, min, max } => {
+                // Numbers may have a unit after them, such as `128 BPM`
+                let number = song
+                    .get_tag(tag)
+                    .and_then(|found| found.split_whitespace().next()?.parse::<f64>().ok());
+                number.is_some_and(|number| (*min..=*max).contains(&number))
+            }
+            SmartRule::ReleaseType(types) => {
+                let tagged = song.get_tag(&Tag::ReleaseType).and_then(|release| release.parse().ok());
+                let release_type = tagged.or_else(|| Some(*release_types.get(song.get_tag(&Tag::Album)?)?));
+                release_type.is_some_and(|release_type| types.contains(&release_type))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub uuid: Uuid,
+    pub title: String,
+    pub rules: Vec<SmartRule>,
+    /// Whether songs have to match every rule, otherwise matching any is enough
+    pub match_all: bool,
+    /// The most songs the playlist can have
+    pub limit: Option<usize>,
+}
+
+impl SmartPlaylist {
+    pub fn new(title: String, rules: Vec<SmartRule>) -> Self {
+        SmartPlaylist {
+            uuid: Uuid::new_v4(),
+            title,
+            rules,
+            match_all: true,
+            limit: None,
+        }
+    }
+
+    pub fn matches(&self, song: &Song) -> bool {
+        self.matches_in(song, &BTreeMap::new())
+    }
+
+    fn matches_in(&self, song: &Song, release_types: &BTreeMap<String, ReleaseType>) -> bool {
+        match self.match_all {
+            true => self.rules.iter().all(|rule| rule.matches_in(song, release_types)),
+            false => self.rules.iter().any(|rule| rule.matches_in(song, release_types)),
+        }
+    }
+
+    /// The songs of the library which are in the playlist, in library order
+    pub fn songs<'a>(&self, library: &'a MusicLibrary) -> Vec<&'a Song> {
+        // Working out the albums is slow, so it's only done when needed
+        let release_types = match self.rules.iter().any(|rule| matches!(rule, SmartRule::ReleaseType(_))) {
+            true => library.albums().into_iter().map(|(title, album)| (title, album.release_type())).collect(),
+            false => BTreeMap::new(),
+        };
+        library
+            .library
+            .iter()
+            .filter(|song| self.matches_in(song, &release_types))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{SmartPlaylist, SmartRule};
+    use crate::music_storage::release_type::ReleaseType;
+    use crate::music_storage::library::{test::test_song, Tag};
+
+    #[test]
+    fn smart_playlist_rules() {
+        let songs: Vec<_> = [("Warm Up", "100", "C"), ("Run", "124", "Am"), ("Sprint", "128 BPM", "F#m")]
+            .iter()
+            .map(|(title, bpm, key)| {
+                let mut song = test_song(title, "Artist", Duration::from_secs(60));
+                song.set_tag(Tag::Bpm, bpm.to_string());
+                song.set_tag(Tag::InitialKey, key.to_string());
+                song
+            })
+            .collect();
+
+        let mut playlist = SmartPlaylist::new(
+            "Workout".to_string(),
+            vec![SmartRule::Between { tag: Tag::Bpm, min: 120.0, max: 128.0 }],
+        );
+        let titles = |playlist: &SmartPlaylist| -> Vec<String> {
+            let matching = songs.iter().filter(|song| playlist.matches(song));
+            matching.map(|song| song.get_tag(&Tag::Title).unwrap().clone()).collect()
+        };
+        assert_eq!(titles(&playlist), ["Run", "Sprint"]);
+
+        playlist.rules.push(SmartRule::Is { tag: Tag::InitialKey, value: "A minor".to_string() });
+        assert_eq!(titles(&playlist), ["Run"]);
+        playlist.match_all = false;
+        playlist.rules.push(SmartRule::Contains { tag: Tag::Title, value: "warm".to_string() });
+        assert_eq!(titles(&playlist), ["Warm Up", "Run", "Sprint"]);
+
+        let mut song = songs[0].clone();
+        song.set_tag(Tag::ReleaseType, "album; live".to_string());
+        let live = SmartRule::ReleaseType(vec![ReleaseType::Live]);
+        assert!(live.matches(&song));
+        assert!(!live.matches(&songs[1]));
+    }
+}
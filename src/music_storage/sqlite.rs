@@ -0,0 +1,249 @@
+//! A [LibraryStore] kept in an SQLite database, for libraries too large
+//! to comfortably hold in memory.
+//!
+//! Each song is stored whole as [bincode], alongside indexed tables of
+//! its tags and locations which are used to look songs up. The database
+//! is opened in WAL mode, so any number of [SqliteStore]s can read from
+//! it while another writes.
+//!
+//! The songs are encoded like those of a library file, so the database
+//! keeps the [LIBRARY_VERSION] they were written with as its
+//! `user_version`.
+
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+use super::library_format::{self, LIBRARY_VERSION};
+use super::store::LibraryStore;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS songs (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tags (
+        song INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tags_value ON tags(tag, value);
+    CREATE TABLE IF NOT EXISTS locations (
+        song INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        uri TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS locations_uri ON locations(uri);
+";
+
+/// How long to wait for another connection to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The oldest [LIBRARY_VERSION] whose songs are encoded the same as the
+/// current one. Databases older than this have to be imported again.
+const SONGS_SINCE: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum SqliteStoreError {
+    #[error("database is version {0}, which is newer than this version of the player supports")]
+    TooNew(u32),
+    #[error("database is version {0}, whose songs can't be read anymore, import the library again")]
+    Outdated(u32),
+}
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        Self::prepare(&connection)?;
+        Ok(SqliteStore { connection })
+    }
+
+    /// Open a database which only lasts as long as the store
+    pub fn open_in_memory() -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open_in_memory()?;
+        Self::prepare(&connection)?;
+        Ok(SqliteStore { connection })
+    }
+
+    /// Create the tables, and check the songs can be read
+    fn prepare(connection: &Connection) -> Result<(), Box<dyn Error>> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+
+        // Databases from before the version was kept have the songs of version 1
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let version = version.max(1);
+        if version > LIBRARY_VERSION {
+            return Err(SqliteStoreError::TooNew(version).into());
+        }
+        if version < SONGS_SINCE {
+            return Err(SqliteStoreError::Outdated(version).into());
+        }
+        connection.pragma_update(None, "user_version", LIBRARY_VERSION)?;
+        Ok(())
+    }
+
+    /// Copy every song of a [MusicLibrary] into the database
+    pub fn import(&mut self, library: &MusicLibrary) -> Result<(), Box<dyn Error>> {
+        self.insert_many(library.library.clone())
+    }
+
+    fn decode(data: Vec<u8>) -> Result<Song, Box<dyn Error>> {
+        let (song, _) = bincode::serde::decode_from_slice(&data, library_format::config())?;
+        Ok(song)
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Song>, Box<dyn Error>> {
+        let mut statement = self.connection.prepare_cached(sql)?;
+        let rows = statement.query_map(params, |row| row.get::<_, Vec<u8>>(0))?;
+        rows.map(|data| Self::decode(data?)).collect()
+    }
+
+    fn insert_into(connection: &Connection, song: &Song) -> Result<(), Box<dyn Error>> {
+        let data = bincode::serde::encode_to_vec(song, library_format::config())?;
+        // Deleting the old row deletes its tags and locations with it
+        connection.execute("DELETE FROM songs WHERE uuid = ?1", [song.uuid.to_string()])?;
+        connection.execute(
+            "INSERT INTO songs (uuid, data) VALUES (?1, ?2)",
+            params![song.uuid.to_string(), data],
+        )?;
+        let id = connection.last_insert_rowid();
+
+        let mut tags = connection.prepare_cached("INSERT INTO tags (song, tag, value) VALUES (?1, ?2, ?3)")?;
+        for (tag, value) in &song.tags {
+            tags.execute(params![id, tag.to_string(), value])?;
+        }
+        let mut locations = connection.prepare_cached("INSERT INTO locations (song, uri) VALUES (?1, ?2)")?;
+        for location in &song.location {
+            locations.execute(params![id, location.to_string()])?;
+        }
+        Ok(())
+    }
+}
+
+impl LibraryStore for SqliteStore {
+    fn len(&self) -> Result<usize, Box<dyn Error>> {
+        let count: i64 = self.connection.query_row("SELECT COUNT(*) FROM songs", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn get(&self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        let data: Option<Vec<u8>> = self
+            .connection
+            .query_row("SELECT data FROM songs WHERE uuid = ?1", [uuid.to_string()], |row| row.get(0))
+            .optional()?;
+        data.map(Self::decode).transpose()
+    }
+
+    fn get_uri(&self, uri: &URI) -> Result<Option<Song>, Box<dyn Error>> {
+        let songs = self.query(
+            "SELECT data FROM songs JOIN locations ON locations.song = songs.id WHERE uri = ?1 LIMIT 1",
+            [uri.to_string()],
+        )?;
+        Ok(songs.into_iter().next())
+    }
+
+    fn insert(&mut self, song: Song) -> Result<(), Box<dyn Error>> {
+        let transaction = self.connection.transaction()?;
+        Self::insert_into(&transaction, &song)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn insert_many(&mut self, songs: Vec<Song>) -> Result<(), Box<dyn Error>> {
+        let transaction = self.connection.transaction()?;
+        for song in &songs {
+            Self::insert_into(&transaction, song)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        let song = self.get(uuid)?;
+        self.connection.execute("DELETE FROM songs WHERE uuid = ?1", [uuid.to_string()])?;
+        Ok(song)
+    }
+
+    fn with_tag(&self, tag: &Tag, value: &str) -> Result<Vec<Song>, Box<dyn Error>> {
+        self.query(
+            "SELECT DISTINCT data FROM songs JOIN tags ON tags.song = songs.id
+             WHERE tag = ?1 AND value = ?2 ORDER BY songs.id",
+            params![tag.to_string(), value],
+        )
+    }
+
+    fn page(&self, offset: usize, limit: usize) -> Result<Vec<Song>, Box<dyn Error>> {
+        self.query(
+            "SELECT data FROM songs ORDER BY id LIMIT ?1 OFFSET ?2",
+            params![limit as i64, offset as i64],
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use super::{SqliteStore, SqliteStoreError};
+    use crate::music_storage::library::{test::test_song, Tag};
+    use crate::music_storage::library_format::LIBRARY_VERSION;
+    use crate::music_storage::store::LibraryStore;
+
+    #[test]
+    fn sqlite_store() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let first = test_song("First", "Artist", Duration::from_secs(60));
+        let second = test_song("Second", "Other", Duration::from_secs(90));
+        store.insert_many(vec![first.clone(), second.clone()]).unwrap();
+        assert_eq!(store.len().unwrap(), 2);
+
+        assert_eq!(store.get(&second.uuid).unwrap(), Some(second.clone()));
+        assert_eq!(store.get_uri(&first.location[0]).unwrap(), Some(first.clone()));
+        assert_eq!(store.with_tag(&Tag::Artist, "Other").unwrap(), vec![second.clone()]);
+        assert_eq!(store.page(1, 10).unwrap(), vec![second.clone()]);
+
+        // Replacing a song also replaces its tags
+        let mut renamed = first.clone();
+        renamed.set_tag(Tag::Artist, "Other".to_string());
+        store.insert(renamed).unwrap();
+        assert_eq!(store.len().unwrap(), 2);
+        assert_eq!(store.with_tag(&Tag::Artist, "Other").unwrap().len(), 2);
+        assert!(store.with_tag(&Tag::Artist, "Artist").unwrap().is_empty());
+
+        assert_eq!(store.remove(&second.uuid).unwrap(), Some(second));
+        assert_eq!(store.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn sqlite_version() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("library.db");
+        drop(SqliteStore::open(&path).unwrap());
+
+        let connection = Connection::open(&path).unwrap();
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, LIBRARY_VERSION);
+
+        // Songs written by a newer version can't be decoded
+        connection.pragma_update(None, "user_version", LIBRARY_VERSION + 1).unwrap();
+        drop(connection);
+        let error = SqliteStore::open(&path).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SqliteStoreError::TooNew(_))));
+    }
+}
@@ -0,0 +1,91 @@
+//! A common interface over the ways songs can be stored, so very large
+//! libraries can be kept in a database instead of entirely in memory
+
+use std::error::Error;
+
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Song, Tag, URI};
+
+/// Storage for the songs of a library.
+///
+/// [MusicLibrary] keeps every song in memory and saves them to a flat file,
+/// while other stores such as `SqliteStore` (with the `sqlite` feature) load
+/// songs only as they are asked for.
+pub trait LibraryStore {
+    /// The number of songs in the store
+    fn len(&self) -> Result<usize, Box<dyn Error>>;
+
+    fn is_empty(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Get the song with the given [Uuid]
+    fn get(&self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>>;
+
+    /// Get the song stored at `uri`
+    fn get_uri(&self, uri: &URI) -> Result<Option<Song>, Box<dyn Error>>;
+
+    /// Add a song, replacing any song with the same [Uuid]
+    fn insert(&mut self, song: Song) -> Result<(), Box<dyn Error>>;
+
+    /// Add many songs at once, which some stores can do much faster
+    fn insert_many(&mut self, songs: Vec<Song>) -> Result<(), Box<dyn Error>> {
+        for song in songs {
+            self.insert(song)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the song with the given [Uuid], returning it if it existed
+    fn remove(&mut self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>>;
+
+    /// Every song where `tag` is exactly `value`
+    fn with_tag(&self, tag: &Tag, value: &str) -> Result<Vec<Song>, Box<dyn Error>>;
+
+    /// Up to `limit` songs starting from `offset`, in the order they were added
+    fn page(&self, offset: usize, limit: usize) -> Result<Vec<Song>, Box<dyn Error>>;
+}
+
+impl LibraryStore for MusicLibrary {
+    fn len(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.library.len())
+    }
+
+    fn get(&self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        Ok(self.query_uuid(uuid).map(|(song, _)| song.clone()))
+    }
+
+    fn get_uri(&self, uri: &URI) -> Result<Option<Song>, Box<dyn Error>> {
+        Ok(self.query_uri(uri).map(|(song, _)| song.clone()))
+    }
+
+    fn insert(&mut self, song: Song) -> Result<(), Box<dyn Error>> {
+        match self.library.iter_mut().find(|existing| existing.uuid == song.uuid) {
+            Some(existing) => *existing = song,
+            None => self.library.push(song),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Result<Option<Song>, Box<dyn Error>> {
+        Ok(self
+            .library
+            .iter()
+            .position(|song| &song.uuid == uuid)
+            .map(|index| self.library.remove(index)))
+    }
+
+    fn with_tag(&self, tag: &Tag, value: &str) -> Result<Vec<Song>, Box<dyn Error>> {
+        Ok(self
+            .library
+            .iter()
+            .filter(|song| song.get_tag(tag).is_some_and(|v| v == value))
+            .cloned()
+            .collect())
+    }
+
+    fn page(&self, offset: usize, limit: usize) -> Result<Vec<Song>, Box<dyn Error>> {
+        Ok(self.library.iter().skip(offset).take(limit).cloned().collect())
+    }
+}
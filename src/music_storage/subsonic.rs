@@ -0,0 +1,449 @@
+//! A remote library provider which speaks the Subsonic and
+//! OpenSubsonic APIs, allowing songs on a Subsonic server to be
+//! browsed, added to a [MusicLibrary], and streamed
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::library::{MusicLibrary, Service, Song, Tag, URI};
+use super::playlist::Playlist;
+use super::remote::{remote_song, PlaybackReport, RemoteLibrary};
+
+/// The version of the Subsonic API which requests are made with
+const API_VERSION: &str = "1.16.1";
+const CLIENT_NAME: &str = "dmp-core";
+
+/// The credentials and settings for a Subsonic server, stored in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubsonicConfig {
+    /// The base URL of the server, such as `https://music.example.com`
+    pub url: String,
+    pub username: String,
+    pub password: Option<String>,
+    /// An OpenSubsonic API key, used instead of the password if set
+    pub api_key: Option<String>,
+    /// Ask the server to transcode streams to this format, such as `opus`
+    pub transcode_format: Option<String>,
+    /// The maximum bitrate of streams in kbps, `None` for no limit
+    pub max_bit_rate: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum SubsonicError {
+    #[error("request failed: {0}")]
+    Http(#[from] attohttpc::Error),
+    #[error("server error {code}: {message}")]
+    Api { code: i32, message: String },
+    #[error("the server sent an invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("no password or API key is set")]
+    NoCredentials,
+}
+
+/// A song as it is returned by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SubsonicSong {
+    pub id: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub display_album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    /// The duration in seconds
+    pub duration: Option<u64>,
+    pub cover_art: Option<String>,
+}
+
+/// An album as it is returned by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SubsonicAlbum {
+    pub id: String,
+    pub name: String,
+    pub artist: Option<String>,
+    pub year: Option<u32>,
+    pub song_count: u32,
+    pub cover_art: Option<String>,
+    pub song: Vec<SubsonicSong>,
+}
+
+/// A playlist as it is returned by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SubsonicPlaylist {
+    pub id: String,
+    pub name: String,
+    pub song_count: u32,
+    pub entry: Vec<SubsonicSong>,
+}
+
+/// The contents of every response, only the parts which are used are parsed
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ResponseBody {
+    status: String,
+    error: Option<ResponseError>,
+    search_result3: Option<SearchResult>,
+    album_list2: Option<AlbumList>,
+    album: Option<SubsonicAlbum>,
+    playlists: Option<PlaylistList>,
+    playlist: Option<SubsonicPlaylist>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ResponseError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchResult {
+    song: Vec<SubsonicSong>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AlbumList {
+    album: Vec<SubsonicAlbum>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PlaylistList {
+    playlist: Vec<SubsonicPlaylist>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(rename = "subsonic-response")]
+    body: ResponseBody,
+}
+
+/// A client for a single Subsonic server
+#[derive(Debug, Clone)]
+pub struct SubsonicClient {
+    config: SubsonicConfig,
+}
+
+impl SubsonicClient {
+    pub fn new(config: SubsonicConfig) -> Self {
+        SubsonicClient { config }
+    }
+
+    pub fn config(&self) -> &SubsonicConfig {
+        &self.config
+    }
+
+    /// Build the URL of an API method, including the authentication parameters
+    fn method_url(&self, method: &str, params: &[(&str, String)]) -> Result<String, SubsonicError> {
+        let mut url = format!(
+            "{}/rest/{}?v={}&c={}&f=json",
+            self.config.url.trim_end_matches('/'),
+            method,
+            API_VERSION,
+            CLIENT_NAME
+        );
+
+        match (&self.config.api_key, &self.config.password) {
+            (Some(api_key), _) => url.push_str(&format!("&apiKey={}", urlencoding::encode(api_key))),
+            (None, Some(password)) => {
+                let salt = Uuid::new_v4().simple().to_string();
+                let token = md5::compute(format!("{}{}", password, salt));
+                url.push_str(&format!(
+                    "&u={}&t={:x}&s={}",
+                    urlencoding::encode(&self.config.username),
+                    token,
+                    salt
+                ));
+            }
+            (None, None) => return Err(SubsonicError::NoCredentials),
+        }
+
+        for (key, value) in params {
+            url.push_str(&format!("&{}={}", key, urlencoding::encode(value)));
+        }
+        Ok(url)
+    }
+
+    /// Call an API method, returning the body of the response
+    fn call(&self, method: &str, params: &[(&str, String)]) -> Result<ResponseBody, SubsonicError> {
+        let url = self.method_url(method, params)?;
+        let response: Response = attohttpc::get(url).send()?.error_for_status()?.json()?;
+
+        if response.body.status != "ok" {
+            return Err(match response.body.error {
+                Some(error) => SubsonicError::Api {
+                    code: error.code,
+                    message: error.message,
+                },
+                None => SubsonicError::InvalidResponse(format!("status {}", response.body.status)),
+            });
+        }
+        Ok(response.body)
+    }
+
+    /// Check that the server can be reached and the credentials are valid
+    pub fn ping(&self) -> Result<(), SubsonicError> {
+        self.call("ping", &[])?;
+        Ok(())
+    }
+
+    /// Search the songs of the server
+    pub fn search(&self, query: &str, count: u32, offset: u32) -> Result<Vec<Song>, SubsonicError> {
+        let body = self.call(
+            "search3",
+            &[
+                ("query", query.to_string()),
+                ("songCount", count.to_string()),
+                ("songOffset", offset.to_string()),
+                ("albumCount", String::from("0")),
+                ("artistCount", String::from("0")),
+            ],
+        )?;
+
+        let result = body.search_result3.unwrap_or_default();
+        Ok(result.song.iter().map(|song| self.to_song(song)).collect())
+    }
+
+    /// List the albums on the server alphabetically, without their songs
+    pub fn albums(&self, size: u32, offset: u32) -> Result<Vec<SubsonicAlbum>, SubsonicError> {
+        let body = self.call(
+            "getAlbumList2",
+            &[
+                ("type", String::from("alphabeticalByName")),
+                ("size", size.to_string()),
+                ("offset", offset.to_string()),
+            ],
+        )?;
+        Ok(body.album_list2.unwrap_or_default().album)
+    }
+
+    /// Get the songs of the album with the given server ID
+    pub fn album_songs(&self, id: &str) -> Result<Vec<Song>, SubsonicError> {
+        let body = self.call("getAlbum", &[("id", id.to_string())])?;
+        let album = body
+            .album
+            .ok_or_else(|| SubsonicError::InvalidResponse("missing album".into()))?;
+        Ok(album.song.iter().map(|song| self.to_song(song)).collect())
+    }
+
+    /// List the playlists on the server, without their songs
+    pub fn playlists(&self) -> Result<Vec<SubsonicPlaylist>, SubsonicError> {
+        let body = self.call("getPlaylists", &[])?;
+        Ok(body.playlists.unwrap_or_default().playlist)
+    }
+
+    /// Get the playlist with the given server ID, along with its songs
+    pub fn playlist(&self, id: &str) -> Result<SubsonicPlaylist, SubsonicError> {
+        let body = self.call("getPlaylist", &[("id", id.to_string())])?;
+        body.playlist
+            .ok_or_else(|| SubsonicError::InvalidResponse("missing playlist".into()))
+    }
+
+    /// Create a playlist on the server, returning its ID
+    pub fn create_playlist(&self, name: &str, song_ids: &[String]) -> Result<String, SubsonicError> {
+        let mut params = vec![("name", name.to_string())];
+        params.extend(song_ids.iter().map(|id| ("songId", id.clone())));
+
+        let body = self.call("createPlaylist", &params)?;
+        match body.playlist {
+            Some(playlist) => Ok(playlist.id),
+            None => Err(SubsonicError::InvalidResponse("missing playlist".into())),
+        }
+    }
+
+    /// Append songs to the end of a playlist on the server
+    pub fn add_to_playlist(&self, id: &str, song_ids: &[String]) -> Result<(), SubsonicError> {
+        if song_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut params = vec![("playlistId", id.to_string())];
+        params.extend(song_ids.iter().map(|id| ("songIdToAdd", id.clone())));
+        self.call("updatePlaylist", &params)?;
+        Ok(())
+    }
+
+    /// Merge a local playlist with a playlist on the server, so each contains
+    /// the songs of both. Songs from the server which are not yet in the
+    /// library are added to it.
+    ///
+    /// Returns the number of songs added to the local and remote playlists.
+    pub fn sync_playlist(
+        &self,
+        local: &mut Playlist,
+        library: &mut MusicLibrary,
+        remote_id: &str,
+    ) -> Result<(usize, usize), SubsonicError> {
+        let remote = self.playlist(remote_id)?;
+        let remote_ids: HashSet<&String> = remote.entry.iter().map(|song| &song.id).collect();
+
+        // Find which local songs the server is missing, before adding to the playlist
+        let local_tracks = local.tracks();
+        let mut to_remote = Vec::new();
+        for uuid in &local_tracks {
+            if let Some((song, _)) = library.query_uuid(uuid) {
+                let id = song.location.iter().find_map(Self::song_id);
+                if let Some(id) = id {
+                    if !remote_ids.contains(&id) && !to_remote.contains(&id) {
+                        to_remote.push(id);
+                    }
+                }
+            }
+        }
+
+        let mut added_local = 0;
+        for entry in &remote.entry {
+            let uri = URI::Remote(Service::Subsonic, entry.id.clone());
+            let uuid = match library.query_uri(&uri) {
+                Some((song, _)) => song.uuid,
+                None => {
+                    let song = self.to_song(entry);
+                    let uuid = song.uuid;
+                    library.library.push(song);
+                    uuid
+                }
+            };
+
+            if !local_tracks.contains(&uuid) {
+                local.add_track(uuid);
+                added_local += 1;
+            }
+        }
+
+        self.add_to_playlist(remote_id, &to_remote)?;
+        Ok((added_local, to_remote.len()))
+    }
+
+    /// The server ID of a song, if the [URI] points to one
+    pub fn song_id(uri: &URI) -> Option<String> {
+        match uri {
+            URI::Remote(Service::Subsonic, id) => Some(id.clone()),
+            _ => None,
+        }
+    }
+
+    /// The URL to stream a song from, with the transcoding settings applied
+    pub fn stream_url(&self, id: &str) -> Result<String, SubsonicError> {
+        let mut params = vec![("id", id.to_string())];
+        if let Some(format) = &self.config.transcode_format {
+            params.push(("format", format.clone()));
+        }
+        if let Some(max_bit_rate) = self.config.max_bit_rate {
+            params.push(("maxBitRate", max_bit_rate.to_string()));
+        }
+        self.method_url("stream", &params)
+    }
+
+    /// The URL of a piece of cover art
+    pub fn cover_art_url(&self, id: &str) -> Result<String, SubsonicError> {
+        self.method_url("getCoverArt", &[("id", id.to_string())])
+    }
+
+    /// Convert a song from the server into a [Song], with a location
+    /// of its server ID so no credentials are stored in the library
+    pub fn to_song(&self, song: &SubsonicSong) -> Song {
+        remote_song(
+            URI::Remote(Service::Subsonic, song.id.clone()),
+            Duration::from_secs(song.duration.unwrap_or(0)),
+            vec![
+                (Tag::Title, Some(song.title.clone())),
+                (Tag::Album, song.album.clone()),
+                (Tag::Artist, song.artist.clone()),
+                (Tag::AlbumArtist, song.display_album_artist.clone()),
+                (Tag::Genre, song.genre.clone()),
+                (Tag::Track, song.track.map(|t| t.to_string())),
+                (Tag::Disk, song.disc_number.map(|d| d.to_string())),
+                (Tag::Key("YEAR".to_string()), song.year.map(|y| y.to_string())),
+            ],
+            song.cover_art
+                .as_ref()
+                .map(|id| URI::Remote(Service::Subsonic, id.clone())),
+        )
+    }
+
+    /// Tell the server a song is playing, or has been played if `submission` is set
+    pub fn scrobble(&self, id: &str, submission: bool) -> Result<(), SubsonicError> {
+        self.call(
+            "scrobble",
+            &[("id", id.to_string()), ("submission", submission.to_string())],
+        )?;
+        Ok(())
+    }
+}
+
+impl RemoteLibrary for SubsonicClient {
+    fn resolve(&self, uri: &URI) -> Option<Result<URI, Box<dyn Error>>> {
+        let id = Self::song_id(uri)?;
+        Some(match self.stream_url(&id) {
+            Ok(url) => Ok(URI::Remote(Service::None, url)),
+            Err(error) => Err(error.into()),
+        })
+    }
+
+    fn report_playback(
+        &self,
+        uri: &URI,
+        report: PlaybackReport,
+        _position: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = match Self::song_id(uri) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        match report {
+            PlaybackReport::Started => self.scrobble(&id, false)?,
+            PlaybackReport::Stopped { finished: true } => self.scrobble(&id, true)?,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Response, SubsonicClient, SubsonicConfig};
+    use crate::music_storage::library::{Service, Tag, URI};
+
+    #[test]
+    fn subsonic_songs() {
+        let client = SubsonicClient::new(SubsonicConfig {
+            url: String::from("https://music.example.com/"),
+            username: String::from("dango"),
+            password: Some(String::from("hunter2")),
+            transcode_format: Some(String::from("opus")),
+            max_bit_rate: Some(128),
+            ..Default::default()
+        });
+
+        let url = client.stream_url("42").unwrap();
+        assert!(url.starts_with("https://music.example.com/rest/stream?"));
+        assert!(url.contains("&u=dango&t="));
+        assert!(url.ends_with("&id=42&format=opus&maxBitRate=128"));
+
+        let json = r#"{"subsonic-response": {"status": "ok", "version": "1.16.1",
+            "searchResult3": {"song": [{"id": "42", "title": "Song", "artist": "Artist",
+            "track": 3, "duration": 180, "coverArt": "al-1"}]}}}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+        let songs = response.body.search_result3.unwrap().song;
+
+        let song = client.to_song(&songs[0]);
+        assert_eq!(song.location[0], URI::Remote(Service::Subsonic, String::from("42")));
+        assert_eq!(song.get_tag(&Tag::Track), Some(&String::from("3")));
+        assert_eq!(song.duration.as_secs(), 180);
+        assert_eq!(SubsonicClient::song_id(&song.location[0]), Some(String::from("42")));
+    }
+}
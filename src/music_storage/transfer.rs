@@ -0,0 +1,196 @@
+//! Moving songs from one library to another, along with everything which
+//! refers to them
+//!
+//! Songs keep their [Uuid] unless the other library already uses it, in
+//! which case they're given a new one and the listening history is
+//! pointed at it. Album art is cached by where it was downloaded from, so
+//! the cache is shared by every library and doesn't need changing.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use uuid::Uuid;
+
+use crate::config::ConfigLibrary;
+use crate::music_controller::history::History;
+
+use super::library::{AlbumKey, MusicLibrary};
+
+/// What to move between libraries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transfer {
+    Track(Uuid),
+    /// Every song on the album
+    Album(AlbumKey),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransferReport {
+    /// The [Uuid] of each song moved in the library it came from, and in
+    /// the library it went to
+    pub moved: Vec<(Uuid, Uuid)>,
+    /// Songs which the other library already had at the same location,
+    /// which were merged into the song already there
+    pub merged: usize,
+    /// Entries for the moved songs taken out of the playlists of the
+    /// library they came from
+    pub playlist_entries: usize,
+    /// Plays in the history moved over to a new [Uuid]
+    pub history_entries: usize,
+}
+
+impl TransferReport {
+    /// The songs which have a different [Uuid] in the library they went to
+    pub fn remapped(&self) -> HashMap<Uuid, Uuid> {
+        self.moved.iter().filter(|(from, to)| from != to).copied().collect()
+    }
+}
+
+/// Move songs from `from` to `to`, only changing the libraries in memory.
+/// See [transfer_and_save] to save them as well.
+pub fn transfer(from: &mut MusicLibrary, to: &mut MusicLibrary, item: &Transfer) -> TransferReport {
+    let moving: Vec<Uuid> = from
+        .library
+        .iter()
+        .filter(|song| match item {
+            Transfer::Track(uuid) => song.uuid == *uuid,
+            Transfer::Album(album) => album.contains(song),
+        })
+        .map(|song| song.uuid)
+        .collect();
+
+    let mut report = TransferReport::default();
+    for uuid in &moving {
+        let index = from.library.iter().position(|song| song.uuid == *uuid).unwrap();
+        let mut song = from.library.remove(index);
+
+        let existing = to.library.iter().find(|other| other.location.iter().any(|uri| song.location.contains(uri)));
+        if let Some(existing) = existing {
+            report.moved.push((*uuid, existing.uuid));
+            report.merged += 1;
+            continue;
+        }
+        if to.query_uuid(&song.uuid).is_some() {
+            song.uuid = Uuid::new_v4();
+        }
+        report.moved.push((*uuid, song.uuid));
+        to.library.push(song);
+    }
+
+    // Playlists can only hold songs of their own library
+    for playlist in from.playlists.playlists_mut() {
+        let tracks = playlist.tracks();
+        let kept: Vec<Uuid> = tracks.iter().filter(|uuid| !moving.contains(uuid)).copied().collect();
+        report.playlist_entries += tracks.len() - kept.len();
+        if kept.len() != tracks.len() {
+            playlist.set_tracks(kept);
+        }
+    }
+    report
+}
+
+/// Move songs between two libraries from the config, saving both of them
+/// and updating the `history`. If any of it fails, everything is put back
+/// the way it was, in memory and on disk.
+pub fn transfer_and_save(
+    from: &mut MusicLibrary,
+    from_config: &ConfigLibrary,
+    to: &mut MusicLibrary,
+    to_config: &ConfigLibrary,
+    item: &Transfer,
+    history: &History,
+) -> Result<TransferReport, Box<dyn Error>> {
+    for config in [from_config, to_config] {
+        if config.settings.read_only {
+            return Err(format!("the library {:?} is read-only", config.name).into());
+        }
+    }
+
+    let (from_before, to_before) = (from.clone(), to.clone());
+    let mut report = transfer(from, to, item);
+    let result = to.save_config(to_config).and_then(|_| from.save_config(from_config)).and_then(|_| {
+        report.history_entries = history.remap(&report.remapped())?;
+        Ok(())
+    });
+
+    if let Err(error) = result {
+        // Whichever library was saved is saved again as it was before
+        for (library, config) in [(&from_before, from_config), (&to_before, to_config)] {
+            if let Err(rollback) = library.save_config(config) {
+                println!("Failed to restore the library {:?}: {}", config.name, rollback);
+            }
+        }
+        *from = from_before;
+        *to = to_before;
+        return Err(error);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{transfer, transfer_and_save, Transfer};
+    use crate::config::ConfigLibrary;
+    use crate::music_controller::history::{History, HistoryEntry};
+    use crate::music_storage::library::{test::test_song, AlbumKey, MusicLibrary, Tag, URI};
+    use crate::music_storage::playlist::Playlist;
+
+    #[test]
+    fn library_transfer() {
+        let mut from = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        let mut to = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        for (title, track) in [("One", 1), ("Two", 2), ("Other", 1)] {
+            let mut song = test_song(title, "Artist", Duration::from_secs(200));
+            song.location = vec![URI::Local(PathBuf::from(format!("/music/{}.flac", title)))];
+            // Another artist's album of the same name stays behind
+            song.set_tag(Tag::Album, "Album".to_string());
+            if title == "Other" {
+                song.set_tag(Tag::AlbumArtist, "Band".to_string());
+            }
+            song.set_tag(Tag::Track, track.to_string());
+            from.library.push(song);
+        }
+        // The other library already has a song with the same UUID
+        let mut taken = test_song("Taken", "Artist", Duration::from_secs(200));
+        taken.uuid = from.library[1].uuid;
+        to.library.push(taken);
+
+        let mut playlist = Playlist::new();
+        playlist.set_tracks(from.library.iter().map(|song| song.uuid).collect());
+        from.playlists.add_playlist(playlist);
+
+        let folder = tempfile::tempdir().unwrap();
+        let history = History::new(folder.path().join("history.jsonl"));
+        history.record(&HistoryEntry::new(&from.library[1], Duration::from_secs(200))).unwrap();
+        let from_config = ConfigLibrary::new(folder.path().join("from.dlib"), "From".to_string(), None);
+        let mut to_config = ConfigLibrary::new(folder.path().join("to.dlib"), "To".to_string(), None);
+
+        // Nothing changes when either library can't be saved
+        to_config.settings.read_only = true;
+        let album = Transfer::Album(AlbumKey::new("Album", None));
+        assert!(transfer_and_save(&mut from, &from_config, &mut to, &to_config, &album, &history).is_err());
+        assert_eq!((from.library.len(), to.library.len()), (3, 1));
+
+        to_config.settings.read_only = false;
+        let (first, second) = (from.library[0].uuid, from.library[1].uuid);
+        let report = transfer_and_save(&mut from, &from_config, &mut to, &to_config, &album, &history).unwrap();
+        assert_eq!(report.moved.len(), 2);
+        assert_eq!(report.moved[0], (first, first));
+        assert_ne!(report.moved[1].1, second);
+        assert_eq!(report.playlist_entries, 2);
+        assert_eq!(report.history_entries, 1);
+        assert_eq!(history.entries().unwrap()[0].uuid, report.moved[1].1);
+        assert_eq!((from.library.len(), to.library.len()), (1, 3));
+        assert!(from_config.path.exists() && to_config.path.exists());
+
+        // Moving a song back to where it already is merges it
+        let mut to_again = MusicLibrary::new(String::new(), uuid::Uuid::new_v4());
+        to_again.library.push(to.library[1].clone());
+        let report = transfer(&mut to, &mut to_again, &Transfer::Track(first));
+        assert_eq!(report.merged, 1);
+        assert_eq!(to_again.library.len(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+//! Overviews of how loud songs are across their length, for drawing
+//! seekbars like SoundCloud's
+//!
+//! Waveforms are made by decoding the whole song, or from the
+//! [PCM tap](crate::music_player::player::Player::pcm_frames) while it
+//! plays, and are kept in the waveform [Cache] so each song is only
+//! decoded once. Each point is stored as a byte, as that's as fine as a
+//! seekbar can show.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::cache::{Cache, CacheError};
+use super::decode::{decode_file, DecodeFormat};
+use super::library::{Song, URI};
+
+/// How many points waveforms have, unless asked for otherwise
+pub const WAVEFORM_POINTS: usize = 1000;
+
+/// How long each block of samples is, which waveforms are made from
+const BLOCK_LENGTH: Duration = Duration::from_millis(10);
+
+/// The peak of each part of a song, from `0` to `1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waveform {
+    pub peaks: Vec<f32>,
+}
+
+impl Waveform {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.peaks.iter().map(|peak| (peak.clamp(0.0, 1.0) * 255.0).round() as u8).collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Waveform {
+            peaks: bytes.iter().map(|byte| *byte as f32 / 255.0).collect(),
+        }
+    }
+}
+
+/// Makes a [Waveform] from samples as they're decoded, without knowing how
+/// long the song is ahead of time
+#[derive(Debug, Default, Clone)]
+pub struct WaveformBuilder {
+    /// The peak of each [BLOCK_LENGTH] of the song
+    blocks: Vec<f32>,
+    /// How many frames are in the last block so far
+    frames: usize,
+}
+
+impl WaveformBuilder {
+    pub fn new() -> Self {
+        WaveformBuilder::default()
+    }
+
+    /// Add interleaved `samples` with `channels` channels at `rate`, where
+    /// `1` is full scale
+    pub fn add_samples<S: Copy + Into<f64>>(&mut self, rate: u32, channels: usize, samples: &[S]) {
+        let block_frames = ((rate as f64 * BLOCK_LENGTH.as_secs_f64()) as usize).max(1);
+        for frame in samples.chunks(channels.max(1)) {
+            if self.frames == 0 {
+                self.blocks.push(0.0);
+            }
+            let peak = frame.iter().map(|sample| (*sample).into().abs() as f32).fold(0.0, f32::max);
+            let block = self.blocks.last_mut().unwrap();
+            *block = block.max(peak);
+            self.frames = (self.frames + 1) % block_frames;
+        }
+    }
+
+    /// The waveform of `points` of the part of the song from `start` to
+    /// `end`, or to the end of what was added if it's `None`
+    pub fn finish(&self, points: usize, start: Duration, end: Option<Duration>) -> Waveform {
+        let block = |time: Duration| ((time.as_secs_f64() / BLOCK_LENGTH.as_secs_f64()) as usize).min(self.blocks.len());
+        let blocks = &self.blocks[block(start)..end.map_or(self.blocks.len(), block).max(block(start))];
+        if blocks.is_empty() || points == 0 {
+            return Waveform { peaks: vec![0.0; points] };
+        }
+
+        // Each point is the loudest of the blocks it covers
+        let peaks = (0..points)
+            .map(|point| {
+                let first = point * blocks.len() / points;
+                let last = ((point + 1) * blocks.len() / points).max(first + 1).min(blocks.len());
+                blocks[first..last].iter().copied().fold(0.0, f32::max)
+            })
+            .collect();
+        Waveform { peaks }
+    }
+}
+
+/// The key a song's waveform of `points` is kept under in the cache
+fn waveform_key(song: &Song, points: usize) -> String {
+    format!("{}:{}", song.uuid, points)
+}
+
+/// The waveform of `points` of a local song, from the `cache` if it was
+/// made before, or by decoding the song and caching it
+pub fn song_waveform(cache: &Cache, song: &Song, points: usize) -> Result<Waveform, Box<dyn Error>> {
+    let key = waveform_key(song, points);
+    if let Some(bytes) = cache.get(&key) {
+        return Ok(Waveform::from_bytes(&bytes));
+    }
+
+    // The tracks of a cue sheet are only part of their file
+    let (path, start, end) = match song.primary_uri()?.0 {
+        URI::Local(path) => (path, Duration::ZERO, None),
+        URI::Cue { location, start, end, .. } => (location, *start, Some(*end)),
+        URI::Remote(..) => return Err("only local songs have a waveform".into()),
+    };
+    let builder = Arc::new(Mutex::new(WaveformBuilder::new()));
+    let builder_ = builder.clone();
+    decode_file(path, DecodeFormat::default(), move |rate, channels, samples| {
+        builder_.lock().unwrap().add_samples(rate, channels, samples);
+    })?;
+    let waveform = builder.lock().unwrap().finish(points, start, end);
+    cache.insert(&key, &waveform.to_bytes())?;
+    Ok(waveform)
+}
+
+/// Keep a waveform made some other way, such as from the PCM tap, so
+/// [song_waveform] doesn't need to decode the song
+pub fn cache_waveform(cache: &Cache, song: &Song, waveform: &Waveform) -> Result<(), CacheError> {
+    cache.insert(&waveform_key(song, waveform.peaks.len()), &waveform.to_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{cache_waveform, song_waveform, Waveform, WaveformBuilder};
+    use crate::music_storage::cache::Cache;
+    use crate::music_storage::library::test::test_song;
+
+    #[test]
+    fn waveform_overview() {
+        // A second of stereo at half volume, then a second at full volume
+        let rate = 1000;
+        let mut builder = WaveformBuilder::new();
+        builder.add_samples(rate, 2, &[0.5f32, -0.25].repeat(rate as usize));
+        builder.add_samples(rate, 2, &[0.1f64, -1.0].repeat(rate as usize));
+
+        let waveform = builder.finish(4, Duration::ZERO, None);
+        assert_eq!(waveform.peaks, vec![0.5, 0.5, 1.0, 1.0]);
+        // Only the part of the file which is the track, as with cue sheets
+        let part = builder.finish(2, Duration::from_millis(500), Some(Duration::from_secs(1)));
+        assert_eq!(part.peaks, vec![0.5, 0.5]);
+        assert_eq!(builder.finish(3, Duration::from_secs(5), None).peaks, vec![0.0; 3]);
+
+        // Cached waveforms are used instead of decoding the song
+        let folder = tempfile::tempdir().unwrap();
+        let cache = Cache::open(folder.path(), 1024 * 1024).unwrap();
+        let song = test_song("Song", "Artist", Duration::from_secs(2));
+        cache_waveform(&cache, &song, &waveform).unwrap();
+        let cached = song_waveform(&cache, &song, 4).unwrap();
+        assert_eq!(cached, Waveform { peaks: vec![128.0 / 255.0, 128.0 / 255.0, 1.0, 1.0] });
+        assert!(song_waveform(&cache, &song, 10).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! The stable surface of the core for frontends
+//!
+//! ```no_run
+//! use dmp_core::prelude::*;
+//!
+//! fn now_playing(controller: &Controller<GStreamer>) -> Option<String> {
+//!     let uri = controller.dump_state().ok()?.now_playing?.uri;
+//!     let library = controller.library();
+//!     let (song, _) = library.query_uri(&uri)?;
+//!     song.get_tag(&Tag::Title).cloned()
+//! }
+//! ```
+//!
+//! Everything here follows semver, so a frontend which only uses the
+//! prelude keeps building between minor versions. The modules behind it,
+//! such as [music_player](crate::music_player) and
+//! [music_storage](crate::music_storage), are still public for anything
+//! the prelude doesn't cover, but can change in any release. The state the
+//! [Controller] keeps is only reached through its methods.
+
+pub use crate::config::{Config, ConfigError, ConfigEvent};
+pub use crate::error::{DmpError, DmpResult};
+pub use crate::music_controller::controller::{Controller, ControllerError, PlayerLocation};
+pub use crate::music_controller::events::ControllerEvent;
+pub use crate::music_controller::modes::{Normalization, PlaybackModes, RepeatMode};
+pub use crate::music_controller::queue::{QueueEvent, QueueSong, QueueSource};
+pub use crate::music_controller::snapshot::{NowPlaying, QueueItemSnapshot, StateSnapshot};
+pub use crate::music_player::gstreamer::GStreamer;
+pub use crate::music_player::player::{Player, PlayerCommand, PlayerError, StreamInfo};
+pub use crate::music_storage::library::{Album, AlbumKey, MusicLibrary, Song, Tag, URI};
+pub use crate::music_storage::playlist::{Playlist, SortOrder};
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Frontends which read events over the remote API get them as JSON,
+    /// so the format is as much a part of the prelude as the types
+    #[test]
+    fn stable_event_format() {
+        let tick = ControllerEvent::PositionTick {
+            position: Duration::from_millis(1500),
+            duration: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&tick).unwrap(),
+            json!({ "event": "PositionTick", "data": { "position": { "secs": 1, "nanos": 500_000_000 }, "duration": null } })
+        );
+        assert_eq!(serde_json::to_value(ControllerEvent::Paused).unwrap(), json!({ "event": "Paused" }));
+        assert_eq!(
+            serde_json::to_value(ControllerEvent::VolumeChanged(0.5)).unwrap(),
+            json!({ "event": "VolumeChanged", "data": 0.5 })
+        );
+        assert_eq!(
+            serde_json::to_value(ControllerEvent::DeviceRemoved { device: "Headphones".to_string(), paused: true }).unwrap(),
+            json!({ "event": "DeviceRemoved", "data": { "device": "Headphones", "paused": true } })
+        );
+    }
+}